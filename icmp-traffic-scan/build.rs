@@ -0,0 +1,3 @@
+fn main() {
+    shared_schema::build_info::emit();
+}
@@ -0,0 +1,262 @@
+// fping 方式のバッチ ping エンジン（PING_ENGINE=batch）
+//
+// ターゲットごとに `ping` プロセスを起動する代わりに、アドレスファミリごとに 1 つの ICMP ソケットから
+// 全ターゲットへ echo request を間隔を空けて順に送り、応答を identifier / sequence と送信元で照合する。
+// 送信の合間にも受信済みの応答を読み出すため、数千ターゲット / 秒でもプロセス起動のコストがかからない。
+// PROBE_BIND で送信元を指定したインターフェースのラベルには、そのデバイス / アドレスに結び付けたソケットを別に開く。
+// ソケットの開き方とパケットの組み立てはネイティブエンジン（icmp.rs）と共通。
+// ペイロードは ECHO_PAYLOAD_PATTERN で埋め、応答のペイロードを照合する（payload.rs）。
+
+use crate::bind::{Binding, Bindings};
+use crate::icmp::{echo_request, parse_echo_reply, IcmpSocket};
+use crate::payload::{EchoPayload, Reply};
+use socket2::SockAddr;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::mem::MaybeUninit;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+// 1 回分のバッチで送ったプローブ
+struct Pending {
+    target: IpAddr,
+    interface: String,
+    // 送ったソケットの送信元（sources の番号）
+    source: usize,
+    sent: Instant,
+}
+
+// 1 つの送信元のアドレスファミリごとのソケット
+struct Source {
+    v4: Option<IcmpSocket>,
+    v6: Option<IcmpSocket>,
+}
+
+impl Source {
+    fn open(binding: Option<&Binding>) -> Self {
+        let open = |v6: bool| {
+            if !binding.is_none_or(|binding| binding.supports(v6)) {
+                return None;
+            }
+            let icmp = IcmpSocket::open(v6)?;
+            if let Some(binding) = binding {
+                if let Err(e) = binding.apply(&icmp.socket, v6) {
+                    warn!(
+                        "Failed to bind the ICMP{} socket to {:?}: {}",
+                        if v6 { "v6" } else { "" },
+                        binding,
+                        e
+                    );
+                    return None;
+                }
+            }
+            Some(icmp)
+        };
+        Self {
+            v4: open(false),
+            v6: open(true),
+        }
+    }
+
+    fn socket_for(&self, target: IpAddr) -> Option<&IcmpSocket> {
+        match target {
+            IpAddr::V4(_) => self.v4.as_ref(),
+            IpAddr::V6(_) => self.v6.as_ref(),
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!("v4 {}, v6 {}", describe(&self.v4), describe(&self.v6))
+    }
+}
+
+pub struct BatchPinger {
+    // 先頭は送信元を指定していないラベルのターゲット用（経路はカーネルに任せる）
+    sources: Vec<Source>,
+    // PROBE_BIND のラベル -> sources の番号
+    bound: HashMap<String, usize>,
+    identifier: u16,
+    // 送信間隔（BATCH_PING_INTERVAL_US）
+    interval: Duration,
+    // 最後の送信から応答を待つ時間（BATCH_PING_TIMEOUT_MS）
+    timeout: Duration,
+    // 周期をまたいで sequence を進め、前の周期の遅れた応答と取り違えない
+    next_sequence: Mutex<u16>,
+    payload: EchoPayload,
+}
+
+impl BatchPinger {
+    // PING_ENGINE=batch のときのみ。ソケットを開けなければ None（`ping` コマンドを使う）
+    pub fn from_env(payload: EchoPayload, bindings: &Bindings) -> Option<Self> {
+        let engine = shared_config::var("PING_ENGINE").unwrap_or_default();
+        if engine.trim() != "batch" {
+            return None;
+        }
+        let interval = Duration::from_micros(
+            shared_config::var("BATCH_PING_INTERVAL_US")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(250),
+        );
+        let timeout = Duration::from_millis(
+            shared_config::var("BATCH_PING_TIMEOUT_MS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(1000),
+        );
+        let default = Source::open(None);
+        if default.v4.is_none() && default.v6.is_none() {
+            warn!("No ICMP socket available, falling back to the ping command");
+            return None;
+        }
+        info!(
+            "Batch ping engine: interval {:?}, timeout {:?}, {}",
+            interval,
+            timeout,
+            default.describe()
+        );
+        let mut sources = vec![default];
+        let mut bound = HashMap::new();
+        for (interface, binding) in bindings.iter() {
+            let source = Source::open(Some(binding));
+            info!("Batch ping engine for {}: {}", interface, source.describe());
+            bound.insert(interface.clone(), sources.len());
+            sources.push(source);
+        }
+        Some(Self {
+            sources,
+            bound,
+            identifier: std::process::id() as u16,
+            interval,
+            timeout,
+            next_sequence: Mutex::new(0),
+            payload,
+        })
+    }
+
+    // (ターゲット, インターフェースのラベル) ごとに、そのラベルの送信元から echo request を 1 回ずつ送り、
+    // 応答のあった組の結果を返す。送信元を指定したラベルでそのアドレスファミリのソケットが無ければ送らない。
+    // ブロッキングするので spawn_blocking から呼ぶ
+    pub fn probe(&self, targets: &[(IpAddr, String)]) -> HashMap<(IpAddr, String), Reply> {
+        let mut replies = HashMap::new();
+        // 1 周期に 1 バッチだけ
+        let mut next_sequence = self.next_sequence.lock().unwrap();
+        let mut pending: HashMap<u16, Pending> = HashMap::new();
+
+        // sequence は 16 bit なので 1 バッチは 65535 ターゲットまで
+        for (target, interface) in targets.iter().take(u16::MAX as usize) {
+            let target = *target;
+            let source = self.bound.get(interface).copied().unwrap_or(0);
+            let Some(icmp) = self.sources[source].socket_for(target) else {
+                continue;
+            };
+            let sequence = *next_sequence;
+            *next_sequence = next_sequence.wrapping_add(1);
+            let packet = echo_request(
+                target.is_ipv6(),
+                self.identifier,
+                sequence,
+                self.payload.bytes(),
+            );
+            let address = SockAddr::from(SocketAddr::new(target, 0));
+            match icmp.socket.send_to(&packet, &address) {
+                Ok(_) => {
+                    pending.insert(
+                        sequence,
+                        Pending {
+                            target,
+                            interface: interface.clone(),
+                            source,
+                            sent: Instant::now(),
+                        },
+                    );
+                }
+                Err(e) => warn!(
+                    "Failed to send echo request to {} on {}: {}",
+                    target, interface, e
+                ),
+            }
+            // 送信の合間に届いている応答を読み出す
+            self.drain(&mut pending, &mut replies);
+            if !self.interval.is_zero() {
+                std::thread::sleep(self.interval);
+            }
+        }
+
+        // 最後の送信からタイムアウトまで残りの応答を待つ
+        let deadline = Instant::now() + self.timeout;
+        while !pending.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            self.drain(&mut pending, &mut replies);
+            std::thread::sleep((deadline - now).min(Duration::from_millis(1)));
+        }
+        replies
+    }
+
+    // 受信キューにある応答をすべて読み、pending と照合する
+    fn drain(
+        &self,
+        pending: &mut HashMap<u16, Pending>,
+        replies: &mut HashMap<(IpAddr, String), Reply>,
+    ) {
+        let mut buffer = [MaybeUninit::<u8>::uninit(); 1500];
+        let sockets = self.sources.iter().enumerate().flat_map(|(index, source)| {
+            [(false, &source.v4), (true, &source.v6)]
+                .into_iter()
+                .filter_map(move |(v6, icmp)| icmp.as_ref().map(|icmp| (index, v6, icmp)))
+        });
+        for (index, v6, icmp) in sockets {
+            loop {
+                let (len, from) = match icmp.socket.recv_from(&mut buffer) {
+                    Ok(received) => received,
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        warn!("Failed to receive ICMP: {}", e);
+                        break;
+                    }
+                };
+                let received = Instant::now();
+                // SAFETY: recv_from は先頭 len バイトを初期化している
+                let data: &[u8] =
+                    unsafe { std::slice::from_raw_parts(buffer.as_ptr().cast(), len) };
+                let Some((identifier, sequence, payload)) = parse_echo_reply(v6, icmp.raw, data)
+                else {
+                    continue;
+                };
+                // 非特権ソケットではカーネルが identifier を書き換え、自分宛ての応答だけを渡す
+                if icmp.raw && identifier != self.identifier {
+                    continue;
+                }
+                let Some(from) = from.as_socket().map(|address| address.ip()) else {
+                    continue;
+                };
+                // raw ソケットは他の送信元の応答も受け取るので、送ったソケットで受けたものだけを使う
+                if pending
+                    .get(&sequence)
+                    .is_some_and(|probe| probe.target == from && probe.source == index)
+                {
+                    let probe = pending.remove(&sequence).unwrap();
+                    let reply = if self.payload.matches(payload) {
+                        Reply::Valid(received.duration_since(probe.sent).as_secs_f64() * 1000.0)
+                    } else {
+                        Reply::Corrupted
+                    };
+                    replies.insert((probe.target, probe.interface), reply);
+                }
+            }
+        }
+    }
+}
+
+fn describe(socket: &Option<IcmpSocket>) -> &'static str {
+    match socket {
+        Some(IcmpSocket { raw: true, .. }) => "raw",
+        Some(IcmpSocket { raw: false, .. }) => "unprivileged",
+        None => "unavailable",
+    }
+}
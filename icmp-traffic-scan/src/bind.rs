@@ -0,0 +1,135 @@
+// インターフェースのラベルごとの送信元（PROBE_BIND）
+//
+// rtt_icmp_dump の interface は localPacketDump-rs から受け取った WAN のラベル（wan0 / wan1）だが、
+// 送信元を決めなければ echo request はカーネルが選んだ経路で出ていき、どのラベルでも同じ RTT になる。
+// PROBE_BIND="wan0=eth0,wan1=ppp0" のようにラベルごとにデバイス（SO_BINDTODEVICE）か送信元アドレスを指定すると、
+// そのラベルの測定はその WAN から送る。同じラベルを IPv4 と IPv6 のアドレスで 2 回書いてもよい。
+// 設定の無いラベルは従来どおり経路に任せる。
+
+use socket2::{SockAddr, Socket};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Default)]
+pub struct Binding {
+    device: Option<String>,
+    v4: Option<IpAddr>,
+    v6: Option<IpAddr>,
+}
+
+impl Binding {
+    fn address(&self, v6: bool) -> Option<IpAddr> {
+        if v6 {
+            self.v6
+        } else {
+            self.v4
+        }
+    }
+
+    // このアドレスファミリのターゲットをこの送信元から測れるか
+    pub fn supports(&self, v6: bool) -> bool {
+        self.device.is_some() || self.address(v6).is_some()
+    }
+
+    // ソケットをデバイスと送信元アドレスに結び付ける
+    pub fn apply(&self, socket: &Socket, v6: bool) -> std::io::Result<()> {
+        if let Some(device) = &self.device {
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            socket.bind_device(Some(device.as_bytes()))?;
+            #[cfg(not(any(target_os = "linux", target_os = "android")))]
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!(
+                    "binding to {} needs SO_BINDTODEVICE (Linux), use a source address",
+                    device
+                ),
+            ));
+        }
+        if let Some(address) = self.address(v6) {
+            socket.bind(&SockAddr::from(SocketAddr::new(address, 0)))?;
+        }
+        Ok(())
+    }
+
+    // `ping` コマンドに渡す送信元の指定
+    pub fn ping_args(&self, v6: bool) -> Vec<String> {
+        let mut args = Vec::new();
+        #[cfg(target_os = "linux")]
+        if let Some(source) = self
+            .device
+            .clone()
+            .or_else(|| self.address(v6).map(|address| address.to_string()))
+        {
+            args.push("-I".to_string());
+            args.push(source);
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            if let Some(device) = &self.device {
+                args.push("-b".to_string());
+                args.push(device.clone());
+            }
+            if let Some(address) = self.address(v6) {
+                args.push("-S".to_string());
+                args.push(address.to_string());
+            }
+        }
+        args
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Bindings {
+    by_interface: HashMap<String, Binding>,
+}
+
+impl Bindings {
+    pub fn from_env() -> Self {
+        let mut by_interface: HashMap<String, Binding> = HashMap::new();
+        let value = shared_config::var("PROBE_BIND").unwrap_or_default();
+        for entry in value.split(',').map(str::trim).filter(|v| !v.is_empty()) {
+            let Some((interface, source)) = entry
+                .split_once('=')
+                .map(|(interface, source)| (interface.trim(), source.trim()))
+                .filter(|(interface, source)| !interface.is_empty() && !source.is_empty())
+            else {
+                warn!(
+                    "Ignoring PROBE_BIND entry {} (expected interface=device or interface=address)",
+                    entry
+                );
+                continue;
+            };
+            let binding = by_interface.entry(interface.to_string()).or_default();
+            match source.parse::<IpAddr>() {
+                Ok(address @ IpAddr::V4(_)) => binding.v4 = Some(address),
+                Ok(address @ IpAddr::V6(_)) => binding.v6 = Some(address),
+                Err(_) => binding.device = Some(source.to_string()),
+            }
+        }
+        for (interface, binding) in &by_interface {
+            info!(
+                "Probes for {} are sent from device {}, IPv4 {}, IPv6 {}",
+                interface,
+                binding.device.as_deref().unwrap_or("-"),
+                binding
+                    .v4
+                    .map(|address| address.to_string())
+                    .unwrap_or_else(|| "-".to_string()),
+                binding
+                    .v6
+                    .map(|address| address.to_string())
+                    .unwrap_or_else(|| "-".to_string())
+            );
+        }
+        Self { by_interface }
+    }
+
+    pub fn get(&self, interface: &str) -> Option<&Binding> {
+        self.by_interface.get(interface)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Binding)> {
+        self.by_interface.iter()
+    }
+}
@@ -0,0 +1,120 @@
+// 同じターゲットのインターフェース間の RTT 比較
+//
+// 同じリモート IP に wan0 と wan1 の両方の RTT がある場合に、その差と RTT の小さい方の
+// インターフェースを求める。WAN の選択側は PromQL で系列を突き合わせずにそのまま使える。
+// 片方の測定が途絶えた値と比べないよう、古い RTT は比較に使わない。
+// 優先インターフェースは差が COMPARE_MARGIN_MS を超えたときだけ切り替え、ばたつかせない。
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// ターゲット 1 つ分の比較結果
+#[derive(Debug, Clone)]
+pub struct TargetComparison {
+    pub remote_ip: String,
+    // (interface, other_interface, interface の RTT - other_interface の RTT)
+    pub deltas: Vec<(String, String, f64)>,
+    pub preferred: String,
+}
+
+// リモート IP -> インターフェース -> (直近の RTT, 測定時刻)
+type LatestRtt = HashMap<String, BTreeMap<String, (f64, Instant)>>;
+
+pub struct InterfaceComparison {
+    // これより古い RTT は比較に使わない（COMPARE_MAX_AGE_SECS）
+    max_age: Duration,
+    // 優先インターフェースを切り替える RTT の差（COMPARE_MARGIN_MS）
+    margin_ms: f64,
+    latest: Mutex<LatestRtt>,
+    // リモート IP -> 現在の優先インターフェース
+    preferred: Mutex<HashMap<String, String>>,
+}
+
+impl InterfaceComparison {
+    pub fn from_env() -> Self {
+        let max_age_secs = shared_config::var("COMPARE_MAX_AGE_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(60);
+        let margin_ms = shared_config::var("COMPARE_MARGIN_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(2.0);
+        Self {
+            max_age: Duration::from_secs(max_age_secs),
+            margin_ms,
+            latest: Mutex::new(HashMap::new()),
+            preferred: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn record(&self, remote_ip: &str, interface: &str, rtt_ms: f64) {
+        self.latest
+            .lock()
+            .unwrap()
+            .entry(remote_ip.to_string())
+            .or_default()
+            .insert(interface.to_string(), (rtt_ms, Instant::now()));
+    }
+
+    // 2 つ以上のインターフェースで新しい RTT があるターゲットを比較する
+    pub fn compare(&self) -> Vec<TargetComparison> {
+        let mut latest = self.latest.lock().unwrap();
+        let mut preferred = self.preferred.lock().unwrap();
+        latest.retain(|_, interfaces| {
+            interfaces.retain(|_, (_, measured)| measured.elapsed() <= self.max_age);
+            !interfaces.is_empty()
+        });
+
+        let mut comparisons = Vec::new();
+        for (remote_ip, interfaces) in latest.iter() {
+            if interfaces.len() < 2 {
+                continue;
+            }
+            let rtts: Vec<(&String, f64)> = interfaces
+                .iter()
+                .map(|(interface, (rtt_ms, _))| (interface, *rtt_ms))
+                .collect();
+
+            // インターフェース名の順に組を作る（wan0 - wan1）
+            let mut deltas = Vec::new();
+            for (i, (interface, rtt_ms)) in rtts.iter().enumerate() {
+                for (other, other_rtt_ms) in &rtts[i + 1..] {
+                    deltas.push((
+                        interface.to_string(),
+                        other.to_string(),
+                        rtt_ms - other_rtt_ms,
+                    ));
+                }
+            }
+
+            let (best, best_rtt_ms) = rtts
+                .iter()
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+                .map(|(interface, rtt_ms)| (interface.to_string(), *rtt_ms))
+                .unwrap();
+            // 今の優先インターフェースがまだ測定されていれば、差がマージンを超えるまで維持する
+            let current_rtt_ms = preferred
+                .get(remote_ip)
+                .and_then(|current| interfaces.get(current).map(|(rtt_ms, _)| *rtt_ms));
+            let keep = current_rtt_ms.is_some_and(|rtt_ms| rtt_ms - best_rtt_ms <= self.margin_ms);
+            if !keep {
+                preferred.insert(remote_ip.clone(), best);
+            }
+
+            comparisons.push(TargetComparison {
+                remote_ip: remote_ip.clone(),
+                deltas,
+                preferred: preferred[remote_ip].clone(),
+            });
+        }
+        // 比較できなくなったターゲットの優先インターフェースは忘れる
+        preferred.retain(|remote_ip, _| {
+            latest
+                .get(remote_ip)
+                .is_some_and(|interfaces| interfaces.len() >= 2)
+        });
+        comparisons
+    }
+}
@@ -0,0 +1,116 @@
+// ターゲットごとの日次 RTT（最小 / 最大 / 平均）
+//
+// Prometheus の保持期間に頼らず ISP との SLA の話ができるよう、日ごとの集計を
+// ファイルに保存し、/daily で JSON として返す。日付はローカル時刻で区切る。
+
+use anyhow::Result;
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DailyRtt {
+    pub min_ms: f64,
+    pub max_ms: f64,
+    pub avg_ms: f64,
+    pub count: u64,
+}
+
+impl DailyRtt {
+    fn new(rtt_ms: f64) -> Self {
+        Self {
+            min_ms: rtt_ms,
+            max_ms: rtt_ms,
+            avg_ms: rtt_ms,
+            count: 1,
+        }
+    }
+
+    fn add(&mut self, rtt_ms: f64) {
+        self.min_ms = self.min_ms.min(rtt_ms);
+        self.max_ms = self.max_ms.max(rtt_ms);
+        self.count += 1;
+        self.avg_ms += (rtt_ms - self.avg_ms) / self.count as f64;
+    }
+}
+
+// 日付（YYYY-MM-DD）-> インターフェース -> リモート IP -> 集計
+type DailyReport = BTreeMap<String, BTreeMap<String, BTreeMap<String, DailyRtt>>>;
+
+pub struct DailyStats {
+    path: PathBuf,
+    // この日数より古い集計は削除する
+    retention_days: i64,
+    report: Mutex<DailyReport>,
+}
+
+impl DailyStats {
+    // DAILY_STATS_FILE から前回の集計を読み込む（無ければ空から始める）
+    pub fn from_env() -> Self {
+        let path = PathBuf::from(
+            shared_config::var("DAILY_STATS_FILE").unwrap_or_else(|_| "daily_rtt.json".to_string()),
+        );
+        let retention_days = shared_config::var("DAILY_RETENTION_DAYS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(31);
+
+        let report = match std::fs::read_to_string(&path) {
+            Ok(content) => match serde_json::from_str::<DailyReport>(&content) {
+                Ok(report) => {
+                    info!("Loaded {} days of RTT stats from {:?}", report.len(), path);
+                    report
+                }
+                Err(e) => {
+                    warn!("Failed to parse {:?}, starting empty: {}", path, e);
+                    DailyReport::new()
+                }
+            },
+            Err(_) => DailyReport::new(),
+        };
+
+        Self {
+            path,
+            retention_days,
+            report: Mutex::new(report),
+        }
+    }
+
+    pub fn record(&self, remote_ip: &str, interface: &str, rtt_ms: f64) {
+        let today = Local::now().format("%Y-%m-%d").to_string();
+        let mut report = self.report.lock().unwrap();
+        report
+            .entry(today)
+            .or_default()
+            .entry(interface.to_string())
+            .or_default()
+            .entry(remote_ip.to_string())
+            .and_modify(|stats| stats.add(rtt_ms))
+            .or_insert_with(|| DailyRtt::new(rtt_ms));
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        let report = self.report.lock().unwrap();
+        Ok(serde_json::to_string(&*report)?)
+    }
+
+    // 保持期間を過ぎた日を削除してファイルに書き出す
+    pub fn save(&self) -> Result<()> {
+        let content = {
+            let mut report = self.report.lock().unwrap();
+            let oldest = (Local::now() - chrono::Duration::days(self.retention_days))
+                .format("%Y-%m-%d")
+                .to_string();
+            report.retain(|date, _| *date >= oldest);
+            serde_json::to_string_pretty(&*report)?
+        };
+        // 書き込み途中で落ちても前回のファイルが壊れないよう一時ファイル経由で置き換える
+        let tmp = self.path.with_extension("json.tmp");
+        std::fs::write(&tmp, content)?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
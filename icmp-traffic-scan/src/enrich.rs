@@ -0,0 +1,251 @@
+// 測定対象の逆引きホスト名と GeoIP（ASN / 国）情報の付与
+//
+// 結果はキャッシュし、rtt_icmp_target_info{remote_ip, hostname, asn, as_org, country} として
+// 公開する。ダッシュボード側では remote_ip で join して「Cloudflare 宛て」などにまとめられる。
+
+use crate::MetricsCollector;
+#[cfg(feature = "geoip")]
+use maxminddb::{geoip2, Reader};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task;
+#[cfg(feature = "geoip")]
+use tracing::info;
+use tracing::{error, warn};
+
+// 逆引き / GeoIP の結果を再取得するまでの期間（ENRICH_CACHE_TTL_SECS の既定）
+const DEFAULT_CACHE_TTL_SECS: u64 = 3600;
+// キャッシュするターゲットの上限（ENRICH_CACHE_MAX の既定）
+const DEFAULT_CACHE_MAX: usize = 10000;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TargetInfo {
+    pub hostname: String,
+    pub asn: String,
+    pub as_org: String,
+    pub country: String,
+}
+
+struct CacheEntry {
+    // None の間は取得中
+    info: Option<TargetInfo>,
+    refreshed_at: Instant,
+    // 最後に測定されたとき。TTL のあいだ測定されなかったターゲットは捨てる
+    used_at: Instant,
+}
+
+pub struct Enricher {
+    rdns: bool,
+    geoip: Option<GeoIp>,
+    // 再取得までの期間で、測定されなくなったターゲットを捨てるまでの期間でもある
+    cache_ttl: Duration,
+    cache_max: usize,
+    cache: Mutex<HashMap<String, CacheEntry>>,
+}
+
+impl Enricher {
+    // ENRICH_RDNS / GEOIP_ASN_DB / GEOIP_COUNTRY_DB のいずれも設定されていなければ無効
+    pub fn from_env() -> Option<Self> {
+        let rdns = matches!(
+            shared_config::var("ENRICH_RDNS").as_deref(),
+            Ok("1") | Ok("true")
+        );
+        let geoip = GeoIp::open();
+
+        if !rdns && geoip.is_none() {
+            return None;
+        }
+
+        let cache_ttl_secs = shared_config::var("ENRICH_CACHE_TTL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&secs| secs > 0)
+            .unwrap_or(DEFAULT_CACHE_TTL_SECS);
+        let cache_max = shared_config::var("ENRICH_CACHE_MAX")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&max| max > 0)
+            .unwrap_or(DEFAULT_CACHE_MAX);
+
+        Some(Self {
+            rdns,
+            geoip,
+            cache_ttl: Duration::from_secs(cache_ttl_secs),
+            cache_max,
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // キャッシュが無いか古い場合のみ、バックグラウンドで情報を取得して info メトリクスを更新する
+    pub fn enrich(self: &Arc<Self>, ip: &str, metrics: &Arc<MetricsCollector>) {
+        let previous = {
+            let mut cache = self.cache.lock().unwrap();
+            let now = Instant::now();
+            match cache.get_mut(ip) {
+                Some(entry) if entry.refreshed_at.elapsed() < self.cache_ttl => {
+                    entry.used_at = now;
+                    return;
+                }
+                Some(entry) => {
+                    entry.refreshed_at = now;
+                    entry.used_at = now;
+                    entry.info.clone()
+                }
+                None => {
+                    self.evict(&mut cache, metrics);
+                    cache.insert(
+                        ip.to_string(),
+                        CacheEntry {
+                            info: None,
+                            refreshed_at: now,
+                            used_at: now,
+                        },
+                    );
+                    None
+                }
+            }
+        };
+
+        let enricher = Arc::clone(self);
+        let metrics = Arc::clone(metrics);
+        let ip = ip.to_string();
+        task::spawn(async move {
+            let info = enricher.resolve(&ip).await;
+            // 取得中に捨てられたターゲットの系列は作らない
+            if let Some(entry) = enricher.cache.lock().unwrap().get_mut(&ip) {
+                metrics.set_target_info(&ip, previous.as_ref(), &info);
+                entry.info = Some(info);
+            }
+        });
+    }
+
+    // TTL のあいだ測定されなかったターゲットを捨て、それでも上限に達していれば
+    // 最後に測定されたのが最も古いものから捨てる（info メトリクスの系列も消す）
+    fn evict(&self, cache: &mut HashMap<String, CacheEntry>, metrics: &MetricsCollector) {
+        cache.retain(|ip, entry| {
+            if entry.used_at.elapsed() < self.cache_ttl {
+                return true;
+            }
+            if let Some(info) = &entry.info {
+                metrics.remove_target_info(ip, info);
+            }
+            false
+        });
+        while cache.len() >= self.cache_max {
+            let Some(oldest) = cache
+                .iter()
+                .min_by_key(|(_, entry)| entry.used_at)
+                .map(|(ip, _)| ip.clone())
+            else {
+                break;
+            };
+            if let Some(CacheEntry {
+                info: Some(info), ..
+            }) = cache.remove(&oldest)
+            {
+                metrics.remove_target_info(&oldest, &info);
+            }
+        }
+    }
+
+    async fn resolve(&self, ip: &str) -> TargetInfo {
+        let mut info = TargetInfo::default();
+        let addr: IpAddr = match ip.parse() {
+            Ok(addr) => addr,
+            Err(_) => return info,
+        };
+
+        if self.rdns {
+            // getnameinfo はブロッキングするので専用スレッドで実行
+            match task::spawn_blocking(move || dns_lookup::lookup_addr(&addr)).await {
+                Ok(Ok(hostname)) if hostname != ip => info.hostname = hostname,
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => warn!("Reverse DNS lookup failed for {}: {}", ip, e),
+                Err(e) => warn!("Reverse DNS task failed for {}: {}", ip, e),
+            }
+        }
+
+        if let Some(geoip) = &self.geoip {
+            geoip.lookup(addr, &mut info);
+        }
+
+        info
+    }
+}
+
+#[cfg(feature = "geoip")]
+struct GeoIp {
+    asn_db: Option<Reader<Vec<u8>>>,
+    country_db: Option<Reader<Vec<u8>>>,
+}
+
+#[cfg(feature = "geoip")]
+impl GeoIp {
+    fn open() -> Option<Self> {
+        let asn_db = open_db("GEOIP_ASN_DB");
+        let country_db = open_db("GEOIP_COUNTRY_DB");
+        if asn_db.is_none() && country_db.is_none() {
+            return None;
+        }
+        Some(Self { asn_db, country_db })
+    }
+
+    fn lookup(&self, addr: IpAddr, info: &mut TargetInfo) {
+        if let Some(db) = &self.asn_db {
+            if let Ok(asn) = db.lookup::<geoip2::Asn>(addr) {
+                if let Some(number) = asn.autonomous_system_number {
+                    info.asn = format!("AS{}", number);
+                }
+                info.as_org = asn
+                    .autonomous_system_organization
+                    .unwrap_or_default()
+                    .to_string();
+            }
+        }
+
+        if let Some(db) = &self.country_db {
+            if let Ok(country) = db.lookup::<geoip2::Country>(addr) {
+                info.country = country
+                    .country
+                    .and_then(|c| c.iso_code)
+                    .unwrap_or_default()
+                    .to_string();
+            }
+        }
+    }
+}
+
+// geoip feature 無しのビルドでは DB を開けないので、設定されていればエラーを出して無効にする
+#[cfg(not(feature = "geoip"))]
+struct GeoIp;
+
+#[cfg(not(feature = "geoip"))]
+impl GeoIp {
+    fn open() -> Option<Self> {
+        for env_name in ["GEOIP_ASN_DB", "GEOIP_COUNTRY_DB"] {
+            if shared_config::var(env_name).is_ok() {
+                error!("{} is ignored: built without the geoip feature", env_name);
+            }
+        }
+        None
+    }
+
+    fn lookup(&self, _addr: IpAddr, _info: &mut TargetInfo) {}
+}
+
+#[cfg(feature = "geoip")]
+fn open_db(env_name: &str) -> Option<Reader<Vec<u8>>> {
+    let path = shared_config::var(env_name).ok()?;
+    match Reader::open_readfile(&path) {
+        Ok(reader) => {
+            info!("Loaded GeoIP database {} from {}", env_name, path);
+            Some(reader)
+        }
+        Err(e) => {
+            error!("Failed to open {} ({}): {}", env_name, path, e);
+            None
+        }
+    }
+}
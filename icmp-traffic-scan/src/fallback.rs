@@ -0,0 +1,183 @@
+// ICMP に応答しないターゲットの TCP / UDP での測定（FALLBACK_PROBE）
+//
+// CDN やゲーム機の多くは ICMP を落とすため、echo request では RTT が出ない。FALLBACK_PROBE="tcp,udp" のように
+// 設定すると、1 周期の echo request に 1 つも応答が無かったターゲットを書いた順の方法で測り直し、
+// 最初に測れた値を rtt_icmp_dump{probe_type="tcp"} / {probe_type="udp"} として公開する。
+// - tcp: FALLBACK_TCP_PORT（既定 443）へ接続し、接続の確立か RST までの時間
+// - udp: FALLBACK_UDP_PORT（既定 33434）へデータグラムを送り、応答か ICMP port unreachable までの時間
+// 送信元は echo request と同じく PROBE_BIND に従う。
+
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpSocket, UdpSocket};
+use tokio::time::timeout;
+use tracing::{debug, info, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeType {
+    Icmp,
+    Tcp,
+    Udp,
+}
+
+impl ProbeType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProbeType::Icmp => "icmp",
+            ProbeType::Tcp => "tcp",
+            ProbeType::Udp => "udp",
+        }
+    }
+}
+
+pub struct FallbackProbe {
+    // 試す順
+    order: Vec<ProbeType>,
+    tcp_port: u16,
+    udp_port: u16,
+    timeout: Duration,
+}
+
+impl FallbackProbe {
+    // FALLBACK_PROBE が設定されていない場合は無効
+    pub fn from_env(timeout: Duration) -> Option<Self> {
+        let value = shared_config::var("FALLBACK_PROBE").unwrap_or_default();
+        let mut order = Vec::new();
+        for name in value.split(',').map(|v| v.trim().to_ascii_lowercase()) {
+            let probe_type = match name.as_str() {
+                "" => continue,
+                "tcp" => ProbeType::Tcp,
+                "udp" => ProbeType::Udp,
+                _ => {
+                    warn!(
+                        "Ignoring FALLBACK_PROBE entry {} (expected tcp or udp)",
+                        name
+                    );
+                    continue;
+                }
+            };
+            if !order.contains(&probe_type) {
+                order.push(probe_type);
+            }
+        }
+        if order.is_empty() {
+            return None;
+        }
+        let port = |name: &str, default: u16| {
+            shared_config::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        };
+        let probe = Self {
+            order,
+            tcp_port: port("FALLBACK_TCP_PORT", 443),
+            udp_port: port("FALLBACK_UDP_PORT", 33434),
+            timeout,
+        };
+        info!(
+            "Targets without echo replies are probed with {} (TCP port {}, UDP port {})",
+            probe
+                .order
+                .iter()
+                .map(ProbeType::as_str)
+                .collect::<Vec<_>>()
+                .join(", "),
+            probe.tcp_port,
+            probe.udp_port
+        );
+        Some(probe)
+    }
+
+    // 設定した順に試し、最初に測れた方法と RTT（ミリ秒）を返す
+    pub async fn probe(&self, target_ip: &str, interface: &str) -> Option<(ProbeType, f64)> {
+        let target: IpAddr = target_ip.parse().ok()?;
+        for probe_type in &self.order {
+            crate::ratelimit::acquire(1).await;
+            let result = match probe_type {
+                ProbeType::Tcp => {
+                    self.tcp(SocketAddr::new(target, self.tcp_port), interface)
+                        .await
+                }
+                _ => {
+                    self.udp(SocketAddr::new(target, self.udp_port), interface)
+                        .await
+                }
+            };
+            match result {
+                Ok(Some(rtt)) => return Some((*probe_type, rtt)),
+                Ok(None) => {}
+                Err(e) => debug!(
+                    "{} probe to {} on {} failed: {}",
+                    probe_type.as_str(),
+                    target_ip,
+                    interface,
+                    e
+                ),
+            }
+        }
+        None
+    }
+
+    // 接続の確立でも RST でも、相手から返ってきた時点で RTT とする
+    async fn tcp(&self, target: SocketAddr, interface: &str) -> std::io::Result<Option<f64>> {
+        let Some(socket) = bound_socket(target, Type::STREAM, Protocol::TCP, interface)? else {
+            return Ok(None);
+        };
+        let socket = TcpSocket::from_std_stream(socket.into());
+        let sent = Instant::now();
+        match timeout(self.timeout, socket.connect(target)).await {
+            Err(_) => Ok(None),
+            Ok(Ok(_)) => Ok(Some(elapsed_ms(sent))),
+            Ok(Err(e)) if e.kind() == ErrorKind::ConnectionRefused => Ok(Some(elapsed_ms(sent))),
+            Ok(Err(e)) => Err(e),
+        }
+    }
+
+    // 応答のデータグラムでも port unreachable（接続済みソケットの ECONNREFUSED）でも RTT とする
+    async fn udp(&self, target: SocketAddr, interface: &str) -> std::io::Result<Option<f64>> {
+        let Some(socket) = bound_socket(target, Type::DGRAM, Protocol::UDP, interface)? else {
+            return Ok(None);
+        };
+        let socket = UdpSocket::from_std(socket.into())?;
+        socket.connect(target).await?;
+        let sent = Instant::now();
+        socket.send(b"traffic-scan").await?;
+        let mut buf = [0u8; 512];
+        match timeout(self.timeout, socket.recv(&mut buf)).await {
+            Err(_) => Ok(None),
+            Ok(Ok(_)) => Ok(Some(elapsed_ms(sent))),
+            Ok(Err(e)) if e.kind() == ErrorKind::ConnectionRefused => Ok(Some(elapsed_ms(sent))),
+            Ok(Err(e)) => Err(e),
+        }
+    }
+}
+
+// interface の PROBE_BIND の送信元に結び付けたノンブロッキングのソケット
+// （その送信元からこのアドレスファミリへ送れなければ None）
+fn bound_socket(
+    target: SocketAddr,
+    kind: Type,
+    protocol: Protocol,
+    interface: &str,
+) -> std::io::Result<Option<Socket>> {
+    let v6 = target.is_ipv6();
+    let socket = Socket::new(Domain::for_address(target), kind, Some(protocol))?;
+    if let Some(binding) = crate::BINDINGS
+        .get()
+        .and_then(|bindings| bindings.get(interface))
+    {
+        if !binding.supports(v6) {
+            return Ok(None);
+        }
+        binding.apply(&socket, v6)?;
+    }
+    socket.set_nonblocking(true)?;
+    Ok(Some(socket))
+}
+
+fn elapsed_ms(since: Instant) -> f64 {
+    since.elapsed().as_secs_f64() * 1000.0
+}
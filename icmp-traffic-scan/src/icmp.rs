@@ -0,0 +1,507 @@
+// ICMP echo の送受信（PING_ENGINE=native、既定）
+//
+// ターゲットごとに `ping` プロセスを起動して出力を読む代わりに、アドレスファミリごとに 1 つの ICMP ソケットを
+// 開いたまま使い、echo request を identifier / sequence 付きで送る。受信はファミリごとのタスクが行い、
+// sequence と送信元で照合して、待っているプローブに受信時刻ごと渡す。時刻はソケットから読んだ直後に取るので、
+// `ping` の出力の丸めやロケールに左右されずマイクロ秒単位で測れる。
+// 同時に応答を待つプローブは PING_CONCURRENCY まで。IPv6 のターゲットには ICMPv6 の echo を送る。
+// PROBE_BIND で送信元を指定したインターフェースのラベルには、そのデバイス / アドレスに結び付けたソケットを別に開く。
+// バースト測定の PMTU 確認のように DF を立てて送るプローブには、初めて使うときに DF 付きのソケットを別に開く。
+// ソケットの開き方とパケットの組み立てはバッチエンジン（batch.rs）と共通。
+
+use crate::bind::{Binding, Bindings};
+use crate::payload::{EchoPayload, Reply};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::collections::HashMap;
+use std::mem::MaybeUninit;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::io::unix::AsyncFd;
+use tokio::sync::{oneshot, Semaphore};
+use tracing::{debug, info, warn};
+
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+
+// 最大のペイロード（1472 バイト）に ICMP ヘッダとオプション付きの IPv4 ヘッダを足しても収まる大きさ
+const RECEIVE_BUFFER_SIZE: usize = 2048;
+
+pub struct IcmpSocket {
+    pub socket: Socket,
+    // raw ソケットは IPv4 ヘッダ付きで、他プロセス宛ての ICMP も受信する
+    pub raw: bool,
+}
+
+impl IcmpSocket {
+    // 非特権の ICMP ソケット（net.ipv4.ping_group_range）を優先し、使えなければ raw ソケットを開く
+    pub fn open(v6: bool) -> Option<Self> {
+        let (domain, protocol) = if v6 {
+            (Domain::IPV6, Protocol::ICMPV6)
+        } else {
+            (Domain::IPV4, Protocol::ICMPV4)
+        };
+        let (socket, raw) = match Socket::new(domain, Type::DGRAM, Some(protocol)) {
+            Ok(socket) => (socket, false),
+            Err(_) => match Socket::new(domain, Type::RAW, Some(protocol)) {
+                Ok(socket) => (socket, true),
+                Err(e) => {
+                    warn!(
+                        "Cannot open an ICMP{} socket: {}",
+                        if v6 { "v6" } else { "" },
+                        e
+                    );
+                    return None;
+                }
+            },
+        };
+        // 応答が一斉に返ってきても溢れないよう受信バッファを広げる
+        let _ = socket.set_recv_buffer_size(1 << 20);
+        // 受信はポーリング（バッチエンジン）か tokio の readiness（ネイティブエンジン）で待つ
+        if let Err(e) = socket.set_nonblocking(true) {
+            warn!("Failed to make the ICMP socket non-blocking: {}", e);
+            return None;
+        }
+        Some(Self { socket, raw })
+    }
+}
+
+pub fn echo_request(v6: bool, identifier: u16, sequence: u16, payload: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0u8; 8];
+    packet.extend_from_slice(payload);
+    packet[0] = if v6 {
+        ICMPV6_ECHO_REQUEST
+    } else {
+        ICMP_ECHO_REQUEST
+    };
+    packet[4..6].copy_from_slice(&identifier.to_be_bytes());
+    packet[6..8].copy_from_slice(&sequence.to_be_bytes());
+    // ICMPv6 のチェックサムは疑似ヘッダを含むためカーネルが計算する
+    if !v6 {
+        let checksum = checksum(&packet);
+        packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+    }
+    packet
+}
+
+// echo reply なら (identifier, sequence, ペイロード)
+pub fn parse_echo_reply(v6: bool, raw: bool, data: &[u8]) -> Option<(u16, u16, &[u8])> {
+    // IPv4 の raw ソケットは IP ヘッダ付きで受信する
+    let icmp = if raw && !v6 {
+        let header_len = usize::from(*data.first()? & 0x0f) * 4;
+        data.get(header_len..)?
+    } else {
+        data
+    };
+    let reply = if v6 {
+        ICMPV6_ECHO_REPLY
+    } else {
+        ICMP_ECHO_REPLY
+    };
+    if icmp.len() < 8 || icmp[0] != reply {
+        return None;
+    }
+    Some((
+        u16::from_be_bytes([icmp[4], icmp[5]]),
+        u16::from_be_bytes([icmp[6], icmp[7]]),
+        &icmp[8..],
+    ))
+}
+
+// RFC 1071 のインターネットチェックサム
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|pair| u32::from(u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)])))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+// 経路の途中で分割させない（PMTU の確認用）。大きすぎるパケットは送信時に EMSGSIZE になる
+#[cfg(target_os = "linux")]
+fn set_dont_fragment(socket: &Socket, v6: bool) -> std::io::Result<()> {
+    let (level, name, value) = if v6 {
+        (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_DO,
+        )
+    } else {
+        (
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_DO,
+        )
+    };
+    setsockopt_int(socket, level, name, value)
+}
+
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+fn set_dont_fragment(socket: &Socket, v6: bool) -> std::io::Result<()> {
+    let (level, name) = if v6 {
+        (libc::IPPROTO_IPV6, libc::IPV6_DONTFRAG)
+    } else {
+        (libc::IPPROTO_IP, libc::IP_DONTFRAG)
+    };
+    setsockopt_int(socket, level, name, 1)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "freebsd")))]
+fn set_dont_fragment(_socket: &Socket, _v6: bool) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "setting DF is not supported on this platform",
+    ))
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd"))]
+fn setsockopt_int(
+    socket: &Socket,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+    // SAFETY: value は呼び出しの間有効な c_int
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            (&value as *const libc::c_int).cast(),
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+// 応答を待っているプローブ
+struct Waiting {
+    target: IpAddr,
+    // 受信時刻と応答のペイロード
+    reply: oneshot::Sender<(Instant, Vec<u8>)>,
+}
+
+// アドレスファミリごとのソケットと、sequence ごとの待ち
+struct Channel {
+    fd: AsyncFd<Socket>,
+    v6: bool,
+    raw: bool,
+    waiting: Mutex<HashMap<u16, Waiting>>,
+}
+
+impl Channel {
+    fn open(v6: bool, binding: Option<&Binding>, dont_fragment: bool) -> Option<Self> {
+        let icmp = IcmpSocket::open(v6)?;
+        if dont_fragment {
+            if let Err(e) = set_dont_fragment(&icmp.socket, v6) {
+                warn!(
+                    "Failed to set DF on the ICMP{} socket: {}",
+                    if v6 { "v6" } else { "" },
+                    e
+                );
+                return None;
+            }
+        }
+        if let Some(binding) = binding {
+            if let Err(e) = binding.apply(&icmp.socket, v6) {
+                warn!(
+                    "Failed to bind the ICMP{} socket to {:?}: {}",
+                    if v6 { "v6" } else { "" },
+                    binding,
+                    e
+                );
+                return None;
+            }
+        }
+        let fd = match AsyncFd::new(icmp.socket) {
+            Ok(fd) => fd,
+            Err(e) => {
+                warn!("Failed to register the ICMP socket with the runtime: {}", e);
+                return None;
+            }
+        };
+        Some(Self {
+            fd,
+            v6,
+            raw: icmp.raw,
+            waiting: Mutex::new(HashMap::new()),
+        })
+    }
+
+    async fn send_to(&self, packet: &[u8], target: IpAddr) -> std::io::Result<usize> {
+        let address = SockAddr::from(SocketAddr::new(target, 0));
+        loop {
+            let mut guard = self.fd.writable().await?;
+            if let Ok(result) = guard.try_io(|fd| fd.get_ref().send_to(packet, &address)) {
+                return result;
+            }
+        }
+    }
+
+    // 応答を読み続け、sequence と送信元が一致したプローブに渡す
+    async fn receive(self: Arc<Self>, identifier: u16) {
+        let mut buffer = [MaybeUninit::<u8>::uninit(); RECEIVE_BUFFER_SIZE];
+        loop {
+            let mut guard = match self.fd.readable().await {
+                Ok(guard) => guard,
+                Err(e) => {
+                    warn!("ICMP receive loop stopped: {}", e);
+                    return;
+                }
+            };
+            let (len, from) = match guard.try_io(|fd| fd.get_ref().recv_from(&mut buffer)) {
+                Ok(Ok(received)) => received,
+                Ok(Err(e)) => {
+                    warn!("Failed to receive ICMP: {}", e);
+                    continue;
+                }
+                // readiness が古かった
+                Err(_) => continue,
+            };
+            let received = Instant::now();
+            // SAFETY: recv_from は先頭 len バイトを初期化している
+            let data: &[u8] = unsafe { std::slice::from_raw_parts(buffer.as_ptr().cast(), len) };
+            let Some((reply_identifier, sequence, payload)) =
+                parse_echo_reply(self.v6, self.raw, data)
+            else {
+                continue;
+            };
+            // 非特権ソケットではカーネルが identifier を書き換え、自分宛ての応答だけを渡す
+            if self.raw && reply_identifier != identifier {
+                continue;
+            }
+            let Some(source) = from.as_socket().map(|address| address.ip()) else {
+                continue;
+            };
+            let mut waiting = self.waiting.lock().unwrap();
+            if waiting
+                .get(&sequence)
+                .is_some_and(|probe| probe.target == source)
+            {
+                let probe = waiting.remove(&sequence).unwrap();
+                let _ = probe.reply.send((received, payload.to_vec()));
+            }
+        }
+    }
+}
+
+// 1 つの送信元のアドレスファミリごとのソケット
+#[derive(Default)]
+struct Sockets {
+    v4: Option<Arc<Channel>>,
+    v6: Option<Arc<Channel>>,
+    binding: Option<Binding>,
+    // DF 付きのソケット（初めて使うときに開く）
+    v4_dont_fragment: OnceLock<Option<Arc<Channel>>>,
+    v6_dont_fragment: OnceLock<Option<Arc<Channel>>>,
+}
+
+impl Sockets {
+    fn open(binding: Option<&Binding>) -> Self {
+        let open = |v6: bool| {
+            binding
+                .is_none_or(|binding| binding.supports(v6))
+                .then(|| Channel::open(v6, binding, false).map(Arc::new))
+                .flatten()
+        };
+        Self {
+            v4: open(false),
+            v6: open(true),
+            binding: binding.cloned(),
+            ..Default::default()
+        }
+    }
+
+    fn channel(
+        &self,
+        target: IpAddr,
+        dont_fragment: bool,
+        identifier: u16,
+    ) -> Option<&Arc<Channel>> {
+        let v6 = target.is_ipv6();
+        if !dont_fragment {
+            return if v6 {
+                self.v6.as_ref()
+            } else {
+                self.v4.as_ref()
+            };
+        }
+        let cell = if v6 {
+            &self.v6_dont_fragment
+        } else {
+            &self.v4_dont_fragment
+        };
+        cell.get_or_init(|| {
+            let binding = self.binding.as_ref();
+            let channel = binding
+                .is_none_or(|binding| binding.supports(v6))
+                .then(|| Channel::open(v6, binding, true).map(Arc::new))
+                .flatten()?;
+            tokio::spawn(Arc::clone(&channel).receive(identifier));
+            Some(channel)
+        })
+        .as_ref()
+    }
+
+    fn channels(&self) -> impl Iterator<Item = &Arc<Channel>> {
+        self.v4.iter().chain(self.v6.iter())
+    }
+
+    fn describe(&self) -> String {
+        let kind = |channel: &Option<Arc<Channel>>| match channel {
+            Some(channel) if channel.raw => "raw",
+            Some(_) => "unprivileged",
+            None => "unavailable",
+        };
+        format!("v4 {}, v6 {}", kind(&self.v4), kind(&self.v6))
+    }
+}
+
+pub struct Pinger {
+    // 送信元を指定していないラベルのターゲット用（経路はカーネルに任せる）
+    default: Sockets,
+    // PROBE_BIND のラベルごと
+    bound: HashMap<String, Sockets>,
+    identifier: u16,
+    // 応答を待つ時間（--timeout / PING_TIMEOUT_MS）
+    timeout: Duration,
+    // 同時に応答を待つプローブの数（--concurrency / PING_CONCURRENCY）
+    limit: Semaphore,
+    next_sequence: AtomicU16,
+    payload: EchoPayload,
+}
+
+impl Pinger {
+    // PING_ENGINE=command なら None。ソケットを開けなければ None（`ping` コマンドを使う）。
+    // timeout / concurrency は --timeout / --concurrency（PING_TIMEOUT_MS / PING_CONCURRENCY）。
+    // 受信タスクを起動するので tokio のランタイムの中で呼ぶ
+    pub fn from_env(
+        payload: EchoPayload,
+        bindings: &Bindings,
+        timeout: Duration,
+        concurrency: usize,
+    ) -> Option<Self> {
+        let engine = shared_config::var("PING_ENGINE").unwrap_or_default();
+        if engine.trim() == "command" {
+            return None;
+        }
+        let default = Sockets::open(None);
+        if default.v4.is_none() && default.v6.is_none() {
+            warn!("No ICMP socket available, falling back to the ping command");
+            return None;
+        }
+        let bound: HashMap<String, Sockets> = bindings
+            .iter()
+            .map(|(interface, binding)| (interface.clone(), Sockets::open(Some(binding))))
+            .collect();
+        // raw ソケットは同じプロセスのバッチエンジンの応答も受け取るので identifier を分ける
+        let identifier = (std::process::id() as u16) ^ 0x8000;
+        for channel in default
+            .channels()
+            .chain(bound.values().flat_map(Sockets::channels))
+        {
+            tokio::spawn(Arc::clone(channel).receive(identifier));
+        }
+        info!(
+            "Native ping engine: timeout {:?}, concurrency {}, {}",
+            timeout,
+            concurrency,
+            default.describe()
+        );
+        for (interface, sockets) in &bound {
+            info!(
+                "Native ping engine for {}: {}",
+                interface,
+                sockets.describe()
+            );
+        }
+        Some(Self {
+            default,
+            bound,
+            identifier,
+            timeout,
+            limit: Semaphore::new(concurrency),
+            next_sequence: AtomicU16::new(0),
+            payload,
+        })
+    }
+
+    // interface の送信元から echo request を 1 回送り、タイムアウトまでに届いた応答を返す。
+    // 送信元を指定したラベルでそのアドレスファミリのソケットが無ければ測らない
+    pub async fn ping(&self, target: IpAddr, interface: &str) -> Option<Reply> {
+        let (rtt, payload) = self
+            .exchange(target, interface, self.payload.bytes(), false)
+            .await?;
+        Some(if self.payload.matches(&payload) {
+            Reply::Valid(rtt)
+        } else {
+            Reply::Corrupted
+        })
+    }
+
+    // payload をそのまま送る ping（バースト測定）。dont_fragment なら DF を立てて送り、
+    // 経路の MTU を超えていれば応答は無い。応答の RTT（ミリ秒）を返し、ペイロードは検証しない
+    pub async fn ping_with(
+        &self,
+        target: IpAddr,
+        interface: &str,
+        payload: &[u8],
+        dont_fragment: bool,
+    ) -> Option<f64> {
+        self.exchange(target, interface, payload, dont_fragment)
+            .await
+            .map(|(rtt, _)| rtt)
+    }
+
+    // echo request を送って応答を待ち、(RTT ミリ秒, 応答のペイロード) を返す
+    async fn exchange(
+        &self,
+        target: IpAddr,
+        interface: &str,
+        payload: &[u8],
+        dont_fragment: bool,
+    ) -> Option<(f64, Vec<u8>)> {
+        let sockets = self.bound.get(interface).unwrap_or(&self.default);
+        let Some(channel) = sockets.channel(target, dont_fragment, self.identifier) else {
+            debug!("No ICMP socket to reach {} from {}", target, interface);
+            return None;
+        };
+        let _permit = self.limit.acquire().await.ok()?;
+        let sequence = self.next_sequence.fetch_add(1, Ordering::Relaxed);
+        let (reply, received) = oneshot::channel();
+        channel
+            .waiting
+            .lock()
+            .unwrap()
+            .insert(sequence, Waiting { target, reply });
+
+        let packet = echo_request(target.is_ipv6(), self.identifier, sequence, payload);
+        let sent = Instant::now();
+        if let Err(e) = channel.send_to(&packet, target).await {
+            // DF 付きで経路の MTU を超えたパケットは送れない（EMSGSIZE）
+            if dont_fragment {
+                debug!("Echo request to {} not sent with DF: {}", target, e);
+            } else {
+                warn!("Failed to send echo request to {}: {}", target, e);
+            }
+            channel.waiting.lock().unwrap().remove(&sequence);
+            return None;
+        }
+        let result = tokio::time::timeout(self.timeout, received).await;
+        // タイムアウトしたプローブの待ちを片付ける（応答済みなら既に無い）
+        channel.waiting.lock().unwrap().remove(&sequence);
+        let (at, payload) = result.ok()?.ok()?;
+        Some((at.duration_since(sent).as_secs_f64() * 1000.0, payload))
+    }
+}
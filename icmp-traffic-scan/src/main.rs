@@ -1,11 +1,100 @@
+mod batch;
+mod bind;
+mod compare;
+mod daily;
+mod enrich;
+mod fallback;
+mod icmp;
+mod payload;
+mod ratelimit;
+mod round;
+mod scheduler;
+mod spike;
+mod state;
+mod targets;
+
 use anyhow::Result;
-use prometheus::{Encoder, GaugeVec, Registry, TextEncoder};
-use serde_json::Value;
-use std::sync::Arc;
+use clap::Parser;
+use fallback::ProbeType;
+use payload::Reply;
+use prometheus::{GaugeVec, HistogramOpts, HistogramVec, IntCounterVec, Registry};
+use round::{Round, RoundConfig};
+use serde::Serialize;
+use shared_http::HttpClient;
+use shared_schema::schedule::Schedule;
+use shared_schema::{
+    build_info, pipeline, LABEL_INTERFACE, LABEL_LOCAL_IP, LABEL_REMOTE_IP, LABEL_REMOTE_PREFIX,
+};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, OnceLock};
 use std::time::Duration;
 use tokio::task;
 use tokio::time::sleep;
-use tracing::{error, info};
+use tracing::{debug, error, info, warn};
+use traffic_scan_core::server::{self, StatusCode};
+use traffic_scan_core::{MetricsServer, PrometheusClient};
+
+// コマンドラインが環境変数より、環境変数が --config の設定ファイルより優先する
+#[derive(Parser, Debug)]
+#[command(
+    version,
+    about = "ICMP RTT exporter for the remotes seen by localPacketDump-rs"
+)]
+struct Args {
+    /// Prometheus to read localPacketDump-rs traffic from [default: http://localhost:9090]
+    #[arg(long, env = "PROMETHEUS_URL", value_name = "URL")]
+    prometheus_url: Option<String>,
+
+    /// localPacketDump-rs /targets to read probe targets from, Prometheus is the fallback
+    #[arg(long, env = "TARGETS_URL", value_name = "URL")]
+    targets_url: Option<String>,
+
+    /// Port of the metrics server on 127.0.0.1 [default: 59123]
+    #[arg(long, env = "LISTEN_PORT", value_name = "PORT")]
+    listen_port: Option<u16>,
+
+    /// Seconds between probe cycles [default: 1]
+    #[arg(long, env = "PROBE_INTERVAL_SECS", value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    interval: Option<u64>,
+
+    /// Remotes with this many bytes or fewer in a window are not probed [default: 100]
+    #[arg(long, env = "MIN_BYTES", value_name = "BYTES")]
+    min_bytes: Option<u64>,
+
+    /// Milliseconds to wait for each echo reply [default: 1000]
+    #[arg(long, env = "PING_TIMEOUT_MS", value_name = "MS")]
+    timeout: Option<u64>,
+
+    /// Echo requests waiting for a reply at the same time [default: 256]
+    #[arg(long, env = "PING_CONCURRENCY", value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    concurrency: Option<u64>,
+
+    /// Read settings from the icmp-traffic-scan table of a shared TOML/YAML file
+    #[arg(long, value_name = "PATH")]
+    config: Option<String>,
+
+    /// Synthesize Prometheus responses and ping results instead of measuring
+    #[arg(long)]
+    simulate: bool,
+}
+
+// コマンドラインと環境変数に無ければ設定ファイルの値、それも無ければ default
+fn setting<T: FromStr>(value: Option<T>, name: &str, default: T) -> T {
+    value
+        .or_else(|| {
+            let raw = shared_config::var(name).ok()?;
+            match raw.trim().parse() {
+                Ok(value) => Some(value),
+                Err(_) => {
+                    warn!("Ignoring invalid {} in the config file: {}", name, raw);
+                    None
+                }
+            }
+        })
+        .unwrap_or(default)
+}
 
 #[derive(Debug, Clone)]
 struct RemoteIpMetric {
@@ -15,8 +104,108 @@ struct RemoteIpMetric {
     bytes: u64,
 }
 
+// 取得したバイト数の元になった localPacketDump-rs のウィンドウ
+#[derive(Debug, Clone, Copy, Default)]
+struct InputWindow {
+    sequence: Option<f64>,
+    // 確定時刻（Unix エポック秒）
+    closed: Option<f64>,
+}
+
+// --simulate のとき、Prometheus の応答と ping の結果をこのシナリオから合成する
+static SIMULATION: OnceLock<Arc<shared_sim::Scenario>> = OnceLock::new();
+
+// echo request のペイロード（ECHO_PAYLOAD_PATTERN / ECHO_PAYLOAD_SIZE）
+static ECHO_PAYLOAD: OnceLock<payload::EchoPayload> = OnceLock::new();
+
+// インターフェースのラベルごとの送信元（PROBE_BIND）
+static BINDINGS: OnceLock<bind::Bindings> = OnceLock::new();
+
+// ICMP ソケットで ping するエンジン（開けなかったとき、PING_ENGINE=command のときは未設定で `ping` コマンドを使う）
+static PINGER: OnceLock<icmp::Pinger> = OnceLock::new();
+
+// `ping` コマンドで測るときの待ち時間と同時に起動するプロセスの上限（PINGER が未設定のとき）
+static PING_COMMAND: OnceLock<PingCommand> = OnceLock::new();
+
+struct PingCommand {
+    // PING_TIMEOUT_MS（プロセスの起動を含めてこの時間で打ち切る）
+    timeout: Duration,
+    // PING_CONCURRENCY
+    limit: tokio::sync::Semaphore,
+}
+
+// 全体の送信レートの上限（PROBE_RATE_LIMIT、0 なら未設定）
+static RATE_LIMIT: OnceLock<ratelimit::RateLimiter> = OnceLock::new();
+
+// echo request に応答しないターゲットの TCP / UDP での測定（FALLBACK_PROBE）
+static FALLBACK: OnceLock<fallback::FallbackProbe> = OnceLock::new();
+
+// インターフェースごとの idle / loaded RTT とバッファブロート評価
+#[derive(Debug, Clone, Default, Serialize)]
+struct BufferbloatState {
+    idle_rtt_ms: Option<f64>,
+    loaded_rtt_ms: Option<f64>,
+    ratio: Option<f64>,
+    grade: Option<char>,
+}
+
+// loaded / idle の RTT 比から A〜F の評価を求める
+fn bufferbloat_grade(ratio: f64) -> char {
+    if ratio < 1.5 {
+        'A'
+    } else if ratio < 2.0 {
+        'B'
+    } else if ratio < 3.0 {
+        'C'
+    } else if ratio < 5.0 {
+        'D'
+    } else {
+        'F'
+    }
+}
+
+// Gauge で公開する評価値（A=4, B=3, C=2, D=1, F=0）
+fn bufferbloat_score(grade: char) -> f64 {
+    match grade {
+        'A' => 4.0,
+        'B' => 3.0,
+        'C' => 2.0,
+        'D' => 1.0,
+        _ => 0.0,
+    }
+}
+
 struct MetricsCollector {
     rtt_gauge: GaugeVec,
+    // PROBE_COUNT 回の echo request の最小 / 平均 / 最大 RTT、ジッター、ロス率
+    round_rtt_min_gauge: GaugeVec,
+    round_rtt_avg_gauge: GaugeVec,
+    round_rtt_max_gauge: GaugeVec,
+    round_jitter_gauge: GaugeVec,
+    round_loss_gauge: GaugeVec,
+    interface_rtt_gauge: GaugeVec,
+    interface_load_rtt_gauge: GaugeVec,
+    bufferbloat_ratio_gauge: GaugeVec,
+    bufferbloat_grade_gauge: GaugeVec,
+    bufferbloat: Mutex<HashMap<String, BufferbloatState>>,
+    burst_rtt_gauge: GaugeVec,
+    burst_loss_gauge: GaugeVec,
+    burst_pmtu_gauge: GaugeVec,
+    target_info_gauge: GaugeVec,
+    interface_delta_gauge: GaugeVec,
+    preferred_interface_gauge: GaugeVec,
+    // 同じターゲットのインターフェース間の RTT 比較
+    comparison: compare::InterfaceComparison,
+    probe_targets_gauge: GaugeVec,
+    echo_replies_counter: IntCounterVec,
+    fallback_counter: IntCounterVec,
+    stale_gauge: GaugeVec,
+    lag_histogram: HistogramVec,
+    input_sequence_gauge: GaugeVec,
+    // ターゲットごとの日次 RTT（ファイルに保存）
+    daily: daily::DailyStats,
+    // 再起動時に復元する直近の RTT / ロス率（ファイルに保存）
+    gauge_state: state::GaugeState,
     registry: Registry,
 }
 
@@ -27,219 +216,1097 @@ impl MetricsCollector {
         let rtt_gauge = GaugeVec::new(
             prometheus::Opts::new(
                 "rtt_icmp_dump",
-                "RTT measured via ICMP ping in milliseconds",
+                "RTT measured via ICMP ping (or the TCP / UDP fallback in probe_type) in milliseconds",
+            ),
+            &["remote_ip", "interface", "data_type", "probe_type"],
+        )?;
+
+        // 1 周期に同じターゲットへ送った PROBE_COUNT 回の echo request の集計
+        let round_gauge = |name: &str, help: &str| {
+            GaugeVec::new(
+                prometheus::Opts::new(name, help),
+                &[LABEL_REMOTE_IP, LABEL_INTERFACE],
+            )
+        };
+        let round_rtt_min_gauge = round_gauge(
+            "icmp_rtt_min",
+            "Minimum RTT of the probes sent to the target in the last cycle in milliseconds",
+        )?;
+        let round_rtt_avg_gauge = round_gauge(
+            "icmp_rtt_avg",
+            "Average RTT of the probes sent to the target in the last cycle in milliseconds",
+        )?;
+        let round_rtt_max_gauge = round_gauge(
+            "icmp_rtt_max",
+            "Maximum RTT of the probes sent to the target in the last cycle in milliseconds",
+        )?;
+        let round_jitter_gauge = round_gauge(
+            "icmp_jitter",
+            "Mean absolute deviation of the RTTs in the last cycle in milliseconds",
+        )?;
+        let round_loss_gauge = round_gauge(
+            "icmp_loss_ratio",
+            "Ratio of probes sent to the target in the last cycle that got no reply",
+        )?;
+
+        // インターフェースごとの集約 RTT（全ターゲットの中央値と p95）
+        let interface_rtt_gauge = GaugeVec::new(
+            prometheus::Opts::new(
+                "rtt_icmp_interface",
+                "RTT quantiles across all probed targets per interface in milliseconds",
+            ),
+            &["interface", "quantile"],
+        )?;
+
+        // 通信量が多い周期（loaded）と少ない周期（idle）で分けたインターフェースごとの RTT 中央値
+        let interface_load_rtt_gauge = GaugeVec::new(
+            prometheus::Opts::new(
+                "rtt_icmp_interface_load",
+                "Median RTT per interface in milliseconds, split by idle and loaded traffic windows",
+            ),
+            &["interface", "load"],
+        )?;
+
+        // バッファブロート評価（loaded / idle の RTT 比と A〜F の評価値）
+        let bufferbloat_ratio_gauge = GaugeVec::new(
+            prometheus::Opts::new(
+                "rtt_icmp_bufferbloat_ratio",
+                "Ratio of loaded to idle median RTT per interface",
+            ),
+            &["interface"],
+        )?;
+        let bufferbloat_grade_gauge = GaugeVec::new(
+            prometheus::Opts::new(
+                "rtt_icmp_bufferbloat_grade",
+                "Bufferbloat grade per interface (A=4, B=3, C=2, D=1, F=0)",
+            ),
+            &["interface"],
+        )?;
+
+        // 静かな時間帯のバースト測定結果（毎秒の測定とは別に保持する）
+        let burst_rtt_gauge = GaugeVec::new(
+            prometheus::Opts::new(
+                "rtt_icmp_burst",
+                "RTT statistics from scheduled burst tests in milliseconds",
+            ),
+            &["remote_ip", "interface", "stat"],
+        )?;
+        let burst_loss_gauge = GaugeVec::new(
+            prometheus::Opts::new(
+                "rtt_icmp_burst_loss_ratio",
+                "Packet loss ratio from scheduled burst tests",
+            ),
+            &["remote_ip", "interface"],
+        )?;
+        let burst_pmtu_gauge = GaugeVec::new(
+            prometheus::Opts::new(
+                "rtt_icmp_burst_pmtu_ok",
+                "Whether a 1500-byte packet with DF set reached the target (1) or not (0)",
+            ),
+            &["remote_ip", "interface"],
+        )?;
+
+        // 測定対象の逆引きホスト名 / ASN / 国（値は常に 1）
+        let target_info_gauge = GaugeVec::new(
+            prometheus::Opts::new(
+                "rtt_icmp_target_info",
+                "Reverse DNS and GeoIP information of probed targets",
             ),
-            &["remote_ip", "interface", "data_type"],
+            &["remote_ip", "hostname", "asn", "as_org", "country"],
         )?;
 
+        // 同じターゲットのインターフェース間の RTT の差と、RTT の小さい方のインターフェース（値は常に 1）
+        let interface_delta_gauge = GaugeVec::new(
+            prometheus::Opts::new(
+                "rtt_icmp_interface_delta",
+                "RTT on interface minus RTT on other_interface for the same target in milliseconds",
+            ),
+            &["remote_ip", "interface", "other_interface"],
+        )?;
+        let preferred_interface_gauge = GaugeVec::new(
+            prometheus::Opts::new(
+                "rtt_icmp_preferred_interface",
+                "Interface with the lower RTT for targets measured on several interfaces",
+            ),
+            &["remote_ip", "interface"],
+        )?;
+
+        // 予算内で ping したターゲット数と次周期以降に回したターゲット数
+        let probe_targets_gauge = GaugeVec::new(
+            prometheus::Opts::new(
+                "rtt_icmp_probe_targets",
+                "Number of targets probed or deferred by the probe budget in the last cycle",
+            ),
+            &["state"],
+        )?;
+
+        // ペイロードが一致した応答（ok）と壊れていた応答（corrupted）の数
+        let echo_replies_counter = IntCounterVec::new(
+            prometheus::Opts::new(
+                "rtt_icmp_echo_replies_total",
+                "Echo replies per interface by payload validation result (ok, corrupted)",
+            ),
+            &["interface", "result"],
+        )?;
+
+        // echo request に応答しなかったターゲットを測り直せた方法（tcp / udp、どれも応答しなければ none）
+        let fallback_counter = IntCounterVec::new(
+            prometheus::Opts::new(
+                "rtt_icmp_fallback_probes_total",
+                "Targets without echo replies re-probed per interface by the method that answered (tcp, udp, none)",
+            ),
+            &["interface", "probe_type"],
+        )?;
+
+        // 前回の起動から復元し、まだ新しく測定されていない値（値は常に 1）
+        let stale_gauge = GaugeVec::new(
+            prometheus::Opts::new(
+                "rtt_icmp_stale",
+                "Values restored from the previous run that have not been re-measured yet",
+            ),
+            &["metric", "remote_ip", "interface", "data_type"],
+        )?;
+
+        // 使ったウィンドウの確定から ping を始めるまでの遅延
+        let lag_histogram = HistogramVec::new(
+            HistogramOpts::new(pipeline::LAG_METRIC, pipeline::LAG_HELP)
+                .buckets(pipeline::LAG_BUCKETS.to_vec()),
+            &["stage"],
+        )?;
+        let input_sequence_gauge = GaugeVec::new(
+            prometheus::Opts::new(
+                pipeline::INPUT_SEQUENCE_METRIC,
+                pipeline::INPUT_SEQUENCE_HELP,
+            ),
+            &["stage"],
+        )?;
+
+        // 動いているビルドのバージョンなど（値は常に 1）
+        server::register_build_info(&registry, &build_info!(), None)?;
         registry.register(Box::new(rtt_gauge.clone()))?;
+        registry.register(Box::new(round_rtt_min_gauge.clone()))?;
+        registry.register(Box::new(round_rtt_avg_gauge.clone()))?;
+        registry.register(Box::new(round_rtt_max_gauge.clone()))?;
+        registry.register(Box::new(round_jitter_gauge.clone()))?;
+        registry.register(Box::new(round_loss_gauge.clone()))?;
+        registry.register(Box::new(interface_rtt_gauge.clone()))?;
+        registry.register(Box::new(interface_load_rtt_gauge.clone()))?;
+        registry.register(Box::new(bufferbloat_ratio_gauge.clone()))?;
+        registry.register(Box::new(bufferbloat_grade_gauge.clone()))?;
+        registry.register(Box::new(burst_rtt_gauge.clone()))?;
+        registry.register(Box::new(burst_loss_gauge.clone()))?;
+        registry.register(Box::new(burst_pmtu_gauge.clone()))?;
+        registry.register(Box::new(target_info_gauge.clone()))?;
+        registry.register(Box::new(interface_delta_gauge.clone()))?;
+        registry.register(Box::new(preferred_interface_gauge.clone()))?;
+        registry.register(Box::new(probe_targets_gauge.clone()))?;
+        registry.register(Box::new(echo_replies_counter.clone()))?;
+        registry.register(Box::new(fallback_counter.clone()))?;
+        registry.register(Box::new(stale_gauge.clone()))?;
+        registry.register(Box::new(lag_histogram.clone()))?;
+        registry.register(Box::new(input_sequence_gauge.clone()))?;
+
+        let gauge_state = state::GaugeState::from_env();
+        let (restored_rtt, restored_loss) = gauge_state.load();
+        for saved in restored_rtt {
+            rtt_gauge
+                .with_label_values(&[
+                    &saved.remote_ip,
+                    &saved.interface,
+                    &saved.data_type,
+                    &saved.probe_type,
+                ])
+                .set(saved.rtt_ms);
+            stale_gauge
+                .with_label_values(&[
+                    "rtt_icmp_dump",
+                    &saved.remote_ip,
+                    &saved.interface,
+                    &saved.data_type,
+                ])
+                .set(1.0);
+        }
+        for saved in restored_loss {
+            burst_loss_gauge
+                .with_label_values(&[&saved.remote_ip, &saved.interface])
+                .set(saved.loss_ratio);
+            stale_gauge
+                .with_label_values(&[
+                    "rtt_icmp_burst_loss_ratio",
+                    &saved.remote_ip,
+                    &saved.interface,
+                    "",
+                ])
+                .set(1.0);
+        }
 
         Ok(MetricsCollector {
             rtt_gauge,
+            round_rtt_min_gauge,
+            round_rtt_avg_gauge,
+            round_rtt_max_gauge,
+            round_jitter_gauge,
+            round_loss_gauge,
+            interface_rtt_gauge,
+            interface_load_rtt_gauge,
+            bufferbloat_ratio_gauge,
+            bufferbloat_grade_gauge,
+            bufferbloat: Mutex::new(HashMap::new()),
+            burst_rtt_gauge,
+            burst_loss_gauge,
+            burst_pmtu_gauge,
+            target_info_gauge,
+            interface_delta_gauge,
+            preferred_interface_gauge,
+            comparison: compare::InterfaceComparison::from_env(),
+            probe_targets_gauge,
+            echo_replies_counter,
+            fallback_counter,
+            stale_gauge,
+            lag_histogram,
+            input_sequence_gauge,
+            daily: daily::DailyStats::from_env(),
+            gauge_state,
             registry,
         })
     }
 
-    fn set_rtt(&self, remote_ip: &str, interface: &str, data_type: &str, rtt_ms: f64) {
+    // 測定の方法が変わったら前の方法の系列は消す
+    fn set_rtt(
+        &self,
+        remote_ip: &str,
+        interface: &str,
+        data_type: &str,
+        probe_type: ProbeType,
+        rtt_ms: f64,
+    ) {
+        for other in [ProbeType::Icmp, ProbeType::Tcp, ProbeType::Udp] {
+            if other != probe_type {
+                let _ = self.rtt_gauge.remove_label_values(&[
+                    remote_ip,
+                    interface,
+                    data_type,
+                    other.as_str(),
+                ]);
+            }
+        }
         self.rtt_gauge
-            .with_label_values(&[remote_ip, interface, data_type])
+            .with_label_values(&[remote_ip, interface, data_type, probe_type.as_str()])
             .set(rtt_ms);
+        self.daily.record(remote_ip, interface, rtt_ms);
+        self.comparison.record(remote_ip, interface, rtt_ms);
+        self.gauge_state
+            .record_rtt(remote_ip, interface, data_type, probe_type.as_str(), rtt_ms);
+        let _ = self.stale_gauge.remove_label_values(&[
+            "rtt_icmp_dump",
+            remote_ip,
+            interface,
+            data_type,
+        ]);
     }
 
-    fn gather_metrics(&self) -> Result<String> {
-        let encoder = TextEncoder::new();
-        let metric_families = self.registry.gather();
-        let mut buffer = vec![];
-        encoder.encode(&metric_families, &mut buffer)?;
-        Ok(String::from_utf8(buffer)?)
+    // 1 周期分の echo request の結果を公開する。応答が 1 つも無ければ RTT の系列は消す
+    fn set_round(&self, remote_ip: &str, interface: &str, round: &Round) {
+        let labels = [remote_ip, interface];
+        self.round_loss_gauge
+            .with_label_values(&labels)
+            .set(round.loss_ratio());
+        let gauges = [
+            &self.round_rtt_min_gauge,
+            &self.round_rtt_avg_gauge,
+            &self.round_rtt_max_gauge,
+            &self.round_jitter_gauge,
+        ];
+        match round.stats() {
+            Some(stats) => {
+                for (gauge, value) in
+                    gauges
+                        .iter()
+                        .zip([stats.min, stats.avg, stats.max, stats.jitter])
+                {
+                    gauge.with_label_values(&labels).set(value);
+                }
+            }
+            None => {
+                for gauge in gauges {
+                    let _ = gauge.remove_label_values(&labels);
+                }
+            }
+        }
     }
-}
 
-async fn fetch_prometheus_metrics(prometheus_url: &str) -> Result<Vec<RemoteIpMetric>> {
-    let client = reqwest::Client::new();
+    // 応答を数え、ペイロードが一致していれば RTT を返す
+    fn record_reply(&self, remote_ip: &str, interface: &str, reply: Reply) -> Option<f64> {
+        match reply {
+            Reply::Valid(rtt_ms) => {
+                self.echo_replies_counter
+                    .with_label_values(&[interface, "ok"])
+                    .inc();
+                Some(rtt_ms)
+            }
+            Reply::Corrupted => {
+                self.echo_replies_counter
+                    .with_label_values(&[interface, "corrupted"])
+                    .inc();
+                warn!(
+                    "Corrupted echo reply from {} on {}: payload does not match",
+                    remote_ip, interface
+                );
+                None
+            }
+        }
+    }
 
-    // Prometheus クエリ - localpacketdump ジョブのメトリクスを取得
-    let query =
-        r#"{job="localpacketdump-rs",__name__!~".*scrape.*",__name__!="up",__name__!~".*total.*"}"#;
-    let url = format!(
-        "{}api/v1/query?query={}",
-        prometheus_url,
-        urlencoding::encode(query)
-    );
+    fn count_fallback(&self, interface: &str, probe_type: &str) {
+        self.fallback_counter
+            .with_label_values(&[interface, probe_type])
+            .inc();
+    }
 
-    let response = client.get(&url).send().await?;
-    let json: Value = response.json().await?;
-
-    let mut metrics_list: Vec<RemoteIpMetric> = Vec::new();
-
-    if let Some(result) = json["data"]["result"].as_array() {
-        for item in result {
-            if let (Some(metric), Some(value)) =
-                (item["metric"].as_object(), item["value"].as_array())
-            {
-                let remote_ip = metric
-                    .get("remote_ip")
-                    .and_then(|v| v.as_str())
-                    .unwrap_or("unknown")
-                    .to_string();
-
-                let interface = metric
-                    .get("interface")
-                    .and_then(|v| v.as_str())
-                    .unwrap_or("unknown")
-                    .to_string();
-
-                let metric_name = metric
-                    .get("__name__")
-                    .and_then(|v| v.as_str())
-                    .unwrap_or("unknown");
-
-                let metric_value: u64 = value
-                    .get(1)
-                    .and_then(|v| v.as_str())
-                    .and_then(|s| s.parse().ok())
-                    .unwrap_or(0);
-
-                let data_type = match metric_name {
-                    "download_bytes" => "download",
-                    "upload_bytes" => "upload",
-                    _ => continue,
-                };
+    fn set_interface_rtt(&self, interface: &str, quantile: &str, rtt_ms: f64) {
+        self.interface_rtt_gauge
+            .with_label_values(&[interface, quantile])
+            .set(rtt_ms);
+    }
 
-                // データ量が100バイト以下の場合はスキップ
-                if metric_value <= 100 {
-                    continue;
-                }
+    fn set_interface_load_rtt(&self, interface: &str, load: &str, rtt_ms: f64) {
+        self.interface_load_rtt_gauge
+            .with_label_values(&[interface, load])
+            .set(rtt_ms);
 
-                metrics_list.push(RemoteIpMetric {
-                    ip: remote_ip,
-                    interface,
-                    data_type: data_type.to_string(),
-                    bytes: metric_value,
-                });
+        // idle と loaded の両方が揃ったらバッファブロート評価を更新
+        let mut bufferbloat = self.bufferbloat.lock().unwrap();
+        let state = bufferbloat.entry(interface.to_string()).or_default();
+        match load {
+            "loaded" => state.loaded_rtt_ms = Some(rtt_ms),
+            _ => state.idle_rtt_ms = Some(rtt_ms),
+        }
+        if let (Some(idle), Some(loaded)) = (state.idle_rtt_ms, state.loaded_rtt_ms) {
+            if idle > 0.0 {
+                let ratio = loaded / idle;
+                let grade = bufferbloat_grade(ratio);
+                state.ratio = Some(ratio);
+                state.grade = Some(grade);
+                self.bufferbloat_ratio_gauge
+                    .with_label_values(&[interface])
+                    .set(ratio);
+                self.bufferbloat_grade_gauge
+                    .with_label_values(&[interface])
+                    .set(bufferbloat_score(grade));
+            }
+        }
+    }
+
+    fn set_burst_rtt(&self, remote_ip: &str, interface: &str, stat: &str, rtt_ms: f64) {
+        self.burst_rtt_gauge
+            .with_label_values(&[remote_ip, interface, stat])
+            .set(rtt_ms);
+    }
+
+    fn set_burst_loss(&self, remote_ip: &str, interface: &str, loss_ratio: f64) {
+        self.burst_loss_gauge
+            .with_label_values(&[remote_ip, interface])
+            .set(loss_ratio);
+        self.gauge_state
+            .record_loss(remote_ip, interface, loss_ratio);
+        let _ = self.stale_gauge.remove_label_values(&[
+            "rtt_icmp_burst_loss_ratio",
+            remote_ip,
+            interface,
+            "",
+        ]);
+    }
+
+    fn set_burst_pmtu_ok(&self, remote_ip: &str, interface: &str, ok: bool) {
+        self.burst_pmtu_gauge
+            .with_label_values(&[remote_ip, interface])
+            .set(if ok { 1.0 } else { 0.0 });
+    }
+
+    fn set_target_info(
+        &self,
+        remote_ip: &str,
+        previous: Option<&enrich::TargetInfo>,
+        info: &enrich::TargetInfo,
+    ) {
+        // 情報が変わった場合は古い系列を削除
+        if let Some(previous) = previous.filter(|previous| *previous != info) {
+            self.remove_target_info(remote_ip, previous);
+        }
+        self.target_info_gauge
+            .with_label_values(&[
+                remote_ip,
+                &info.hostname,
+                &info.asn,
+                &info.as_org,
+                &info.country,
+            ])
+            .set(1.0);
+    }
+
+    fn remove_target_info(&self, remote_ip: &str, info: &enrich::TargetInfo) {
+        let _ = self.target_info_gauge.remove_label_values(&[
+            remote_ip,
+            &info.hostname,
+            &info.asn,
+            &info.as_org,
+            &info.country,
+        ]);
+    }
+
+    // 比較できるターゲットの系列だけを残して差と優先インターフェースを更新
+    fn update_interface_comparison(&self) {
+        let comparisons = self.comparison.compare();
+        self.interface_delta_gauge.reset();
+        self.preferred_interface_gauge.reset();
+        for comparison in comparisons {
+            for (interface, other_interface, delta_ms) in &comparison.deltas {
+                self.interface_delta_gauge
+                    .with_label_values(&[&comparison.remote_ip, interface, other_interface])
+                    .set(*delta_ms);
             }
+            self.preferred_interface_gauge
+                .with_label_values(&[&comparison.remote_ip, &comparison.preferred])
+                .set(1.0);
         }
     }
 
-    Ok(metrics_list)
+    fn set_probe_targets(&self, probed: usize, deferred: usize) {
+        self.probe_targets_gauge
+            .with_label_values(&["probed"])
+            .set(probed as f64);
+        self.probe_targets_gauge
+            .with_label_values(&["deferred"])
+            .set(deferred as f64);
+    }
+
+    // ping を始める時点で、使ったウィンドウがどれだけ古いか
+    fn observe_input_window(&self, window: InputWindow) {
+        if let Some(closed) = window.closed {
+            self.lag_histogram
+                .with_label_values(&[pipeline::STAGE_PROBE])
+                .observe(pipeline::lag_seconds(closed));
+        }
+        if let Some(sequence) = window.sequence {
+            self.input_sequence_gauge
+                .with_label_values(&[pipeline::STAGE_PROBE])
+                .set(sequence);
+        }
+    }
+
+    fn bufferbloat_json(&self) -> Result<String> {
+        let bufferbloat = self.bufferbloat.lock().unwrap();
+        Ok(serde_json::to_string(&*bufferbloat)?)
+    }
+}
+
+async fn fetch_prometheus_metrics(
+    prometheus: &PrometheusClient,
+    job: &str,
+    min_bytes: u64,
+) -> Result<(Vec<RemoteIpMetric>, InputWindow)> {
+    // Prometheus クエリ - localPacketDump-rs のジョブ（LOCALPACKETDUMP_JOB）のメトリクスを取得
+    let query = format!(
+        r#"{{job="{}",__name__!~".*scrape.*",__name__!="up",__name__!~".*total.*"}}"#,
+        job
+    );
+    let samples = prometheus.query(&query).await?;
+
+    let mut window = InputWindow::default();
+    // PERSPECTIVE=both や PROTOCOL_LABELS では 1 つのアドレスに系列が複数あるので合計する
+    let mut bytes: BTreeMap<(String, String, &str), u64> = BTreeMap::new();
+    let mut unusable = 0;
+
+    for sample in samples {
+        let metric_name = sample.name().unwrap_or("unknown");
+
+        // ウィンドウの番号と確定時刻は同じジョブの別のメトリクスで届く
+        if metric_name == pipeline::WINDOW_SEQUENCE_METRIC {
+            window.sequence = Some(sample.value);
+            continue;
+        }
+        if metric_name == pipeline::WINDOW_CLOSED_METRIC {
+            window.closed = Some(sample.value);
+            continue;
+        }
+
+        let data_type = match metric_name {
+            "download_bytes" => "download",
+            "upload_bytes" => "upload",
+            _ => continue,
+        };
+
+        let (Some(address), Some(interface)) =
+            (probe_address(&sample), sample.label(LABEL_INTERFACE))
+        else {
+            unusable += 1;
+            continue;
+        };
+        *bytes
+            .entry((address.to_string(), interface.to_string(), data_type))
+            .or_default() += sample.value as u64;
+    }
+    if unusable > 0 {
+        debug!(
+            "Skipped {} series without a single address or interface to probe",
+            unusable
+        );
+    }
+
+    // データ量が MIN_BYTES（デフォルト 100 バイト）以下の場合はスキップ
+    let metrics_list = bytes
+        .into_iter()
+        .filter(|(_, bytes)| *bytes > min_bytes)
+        .map(|((ip, interface, data_type), bytes)| RemoteIpMetric {
+            ip,
+            interface,
+            data_type: data_type.to_string(),
+            bytes,
+        })
+        .collect();
+
+    Ok((metrics_list, window))
+}
+
+// 系列の ping するアドレス。remote_ip、1 アドレスだけの remote_prefix（AGGREGATE_PREFIX_V4=32 など）、
+// local_ip（PERSPECTIVE=local）の順に使う。まとめたプレフィックスには ping できるアドレスが無い
+fn probe_address(sample: &traffic_scan_core::Sample) -> Option<IpAddr> {
+    if let Some(remote_ip) = sample.label(LABEL_REMOTE_IP) {
+        return remote_ip.parse().ok();
+    }
+    if let Some(prefix) = sample.label(LABEL_REMOTE_PREFIX) {
+        let (address, len) = prefix.split_once('/')?;
+        let address: IpAddr = address.parse().ok()?;
+        let host_len = if address.is_ipv4() { 32 } else { 128 };
+        return (len.parse::<u8>().ok()? == host_len).then_some(address);
+    }
+    sample.label(LABEL_LOCAL_IP)?.parse().ok()
+}
+
+// localPacketDump-rs から取得できなかった周期は Prometheus に問い合わせる
+async fn fetch_targets(
+    source: Option<&targets::TargetSource>,
+    prometheus: &PrometheusClient,
+    job: &str,
+    min_bytes: u64,
+) -> Result<(Vec<RemoteIpMetric>, InputWindow)> {
+    if let Some(source) = source {
+        match source.fetch(min_bytes).await {
+            Ok(fetched) => return Ok(fetched),
+            Err(e) => warn!(
+                "Failed to fetch targets from {}, falling back to Prometheus: {}",
+                source.url(),
+                e
+            ),
+        }
+    }
+    fetch_prometheus_metrics(prometheus, job, min_bytes).await
+}
+
+async fn measure_icmp_rtt(target_ip: &str, interface: &str) -> Option<f64> {
+    match measure_icmp_reply(target_ip, interface).await? {
+        Reply::Valid(rtt) => Some(rtt),
+        Reply::Corrupted => None,
+    }
 }
 
-async fn measure_icmp_rtt(target_ip: &str) -> Option<f64> {
-    use std::process::Command;
+// interface のラベルに PROBE_BIND の送信元があれば、その WAN から送る
+async fn measure_icmp_reply(target_ip: &str, interface: &str) -> Option<Reply> {
+    if let Some(scenario) = SIMULATION.get() {
+        return scenario.sample_rtt().map(Reply::Valid);
+    }
+    ratelimit::acquire(1).await;
 
-    // macOS では `ping` コマンドを使用（1回のみ、1秒のタイムアウト）
-    let output = Command::new("ping")
-        .arg("-c")
-        .arg("1")
+    if let Some(pinger) = PINGER.get() {
+        let Ok(target) = target_ip.parse::<IpAddr>() else {
+            warn!("Skipping ping to {}: not an IP address", target_ip);
+            return None;
+        };
+        return pinger.ping(target, interface).await;
+    }
+
+    // ICMP ソケットを使えなければ `ping` コマンドを 1 回だけ使用（PING_TIMEOUT_MS で打ち切る）
+    let ping_command = PING_COMMAND.get()?;
+    let _permit = ping_command.limit.acquire().await.ok()?;
+    let mut command = tokio::process::Command::new("ping");
+    command.arg("-c").arg("1").kill_on_drop(true);
+    // -W は Linux（iputils）では秒、macOS / BSD ではミリ秒
+    #[cfg(target_os = "linux")]
+    command.arg("-W").arg(
+        ping_command
+            .timeout
+            .as_secs_f64()
+            .ceil()
+            .max(1.0)
+            .to_string(),
+    );
+    #[cfg(not(target_os = "linux"))]
+    command
         .arg("-W")
-        .arg("1000")
-        .arg(target_ip)
-        .output();
-
-    match output {
-        Ok(out) => {
-            let stdout = String::from_utf8_lossy(&out.stdout);
-            // "time=42.123 ms" の形式を抽出
-            for line in stdout.lines() {
-                if let Some(start) = line.find("time=") {
-                    let rest = &line[start + 5..];
-                    if let Some(end) = rest.find(" ms") {
-                        if let Ok(rtt) = rest[..end].parse::<f64>() {
-                            return Some(rtt);
-                        }
-                    }
-                }
-            }
-            None
+        .arg(ping_command.timeout.as_millis().max(1).to_string());
+    if let Some(payload) = ECHO_PAYLOAD.get() {
+        command.args(payload.ping_args());
+    }
+    if let Some(binding) = BINDINGS.get().and_then(|bindings| bindings.get(interface)) {
+        let v6 = target_ip.contains(':');
+        if !binding.supports(v6) {
+            return None;
         }
-        Err(e) => {
+        command.args(binding.ping_args(v6));
+    }
+    command.arg(target_ip);
+
+    let out = match tokio::time::timeout(ping_command.timeout, command.output()).await {
+        Ok(Ok(out)) => out,
+        Ok(Err(e)) => {
             error!("Failed to run ping: {}", e);
-            None
+            return None;
         }
+        // 打ち切ったプロセスは kill_on_drop で終了させる
+        Err(_) => return None,
+    };
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    // `ping` はペイロードが -p のパターンと一致しないと "wrong data byte" を出す
+    if stdout.contains("wrong data byte") {
+        return Some(Reply::Corrupted);
     }
+    // "time=42.123 ms" の形式を抽出
+    for line in stdout.lines() {
+        if let Some(start) = line.find("time=") {
+            let rest = &line[start + 5..];
+            if let Some(end) = rest.find(" ms") {
+                if let Ok(rtt) = rest[..end].parse::<f64>() {
+                    return Some(Reply::Valid(rtt));
+                }
+            }
+        }
+    }
+    None
 }
 
 async fn ping_and_update_metrics(
     metrics: Arc<MetricsCollector>,
     remote_metrics: Vec<RemoteIpMetric>,
+    probe_targets: Vec<RemoteIpMetric>,
+    loaded_bytes_threshold: u64,
+    enricher: Option<Arc<enrich::Enricher>>,
+    pinger: Option<Arc<batch::BatchPinger>>,
+    round: RoundConfig,
 ) {
-    // 各メトリクスに対して並列で ICMP ping を実行
-    let handles: Vec<_> = remote_metrics
-        .iter()
-        .map(|metric| {
-            let ip = metric.ip.clone();
-            let interface = metric.interface.clone();
-            let data_type = metric.data_type.clone();
-            let metrics = Arc::clone(&metrics);
+    // インターフェースごとの通信量を合計し、閾値を超えていれば loaded とみなす
+    let mut bytes_by_interface: HashMap<String, u64> = HashMap::new();
+    for metric in &remote_metrics {
+        *bytes_by_interface
+            .entry(metric.interface.clone())
+            .or_insert(0) += metric.bytes;
+    }
+
+    // 予算内で選ばれたターゲットに対して ICMP ping を実行
+    let measured = match pinger.filter(|_| SIMULATION.get().is_none()) {
+        Some(pinger) => batch_ping(&metrics, &probe_targets, pinger, enricher, round).await,
+        None => spawn_pings(&metrics, &probe_targets, enricher, round).await,
+    };
+    let mut rtts_by_interface: HashMap<String, Vec<f64>> = HashMap::new();
+    for (interface, rtt) in measured {
+        rtts_by_interface.entry(interface).or_default().push(rtt);
+    }
+
+    // インターフェースごとの中央値と p95 を公開
+    for (interface, mut rtts) in rtts_by_interface {
+        rtts.sort_by(|a, b| a.total_cmp(b));
+        let median = percentile(&rtts, 0.5);
+        let p95 = percentile(&rtts, 0.95);
+        metrics.set_interface_rtt(&interface, "0.5", median);
+        metrics.set_interface_rtt(&interface, "0.95", p95);
+
+        let interface_bytes = bytes_by_interface.get(&interface).copied().unwrap_or(0);
+        let load = if interface_bytes > loaded_bytes_threshold {
+            "loaded"
+        } else {
+            "idle"
+        };
+        metrics.set_interface_load_rtt(&interface, load, median);
+
+        info!(
+            "Interface {} RTT over {} targets ({}, {} bytes/s): median={:.2}ms p95={:.2}ms",
+            interface,
+            rtts.len(),
+            load,
+            interface_bytes,
+            median,
+            p95
+        );
+    }
+
+    metrics.update_interface_comparison();
+}
+
+// 1 ターゲットへの echo request の応答を数えて 1 周期分にまとめる
+fn collect_round(
+    metrics: &MetricsCollector,
+    remote_ip: &str,
+    interface: &str,
+    replies: impl IntoIterator<Item = Option<Reply>>,
+) -> Round {
+    let mut round = Round::default();
+    for reply in replies {
+        round.sent += 1;
+        let Some(reply) = reply else {
+            continue;
+        };
+        round.replied += 1;
+        if let Some(rtt) = metrics.record_reply(remote_ip, interface, reply) {
+            round.rtts.push(rtt);
+        }
+    }
+    round
+}
+
+// 選ばれたターゲットを (ip, interface) ごとにまとめ、測定のきっかけになった data_type を並べる
+fn group_targets(probe_targets: &[RemoteIpMetric]) -> BTreeMap<(String, String), Vec<String>> {
+    let mut groups: BTreeMap<(String, String), Vec<String>> = BTreeMap::new();
+    for metric in probe_targets {
+        groups
+            .entry((metric.ip.clone(), metric.interface.clone()))
+            .or_default()
+            .push(metric.data_type.clone());
+    }
+    groups
+}
+
+// echo request に 1 つも応答しなかったターゲットを FALLBACK_PROBE の方法で測り直す
+async fn measure_fallback(
+    metrics: &MetricsCollector,
+    ip: &str,
+    interface: &str,
+    data_types: &[String],
+) {
+    let Some(fallback) = FALLBACK.get() else {
+        return;
+    };
+    let Some((probe_type, rtt)) = fallback.probe(ip, interface).await else {
+        metrics.count_fallback(interface, "none");
+        debug!(
+            "No reply from {} on {} to any fallback probe",
+            ip, interface
+        );
+        return;
+    };
+    metrics.count_fallback(interface, probe_type.as_str());
+    for data_type in data_types {
+        metrics.set_rtt(ip, interface, data_type, probe_type, rtt);
+    }
+    info!(
+        "Measured RTT to {} on {} ({}) over {} without echo replies: {:.2}ms",
+        ip,
+        interface,
+        data_types.join("/"),
+        probe_type.as_str(),
+        rtt
+    );
+}
+
+// ターゲットごとにタスクを起動して並列で ping し、(interface, 平均 RTT) を返す
+// （download / upload の両方で選ばれた (ip, interface) には 1 回だけ送る。同時に応答を待つ数は PING_CONCURRENCY で抑える）
+async fn spawn_pings(
+    metrics: &Arc<MetricsCollector>,
+    probe_targets: &[RemoteIpMetric],
+    enricher: Option<Arc<enrich::Enricher>>,
+    round_config: RoundConfig,
+) -> Vec<(String, f64)> {
+    let handles: Vec<_> = group_targets(probe_targets)
+        .into_iter()
+        .map(|((ip, interface), data_types)| {
+            let metrics = Arc::clone(metrics);
+            let enricher = enricher.clone();
 
             task::spawn(async move {
-                if let Some(rtt) = measure_icmp_rtt(&ip).await {
-                    metrics.set_rtt(&ip, &interface, &data_type, rtt);
+                if let Some(enricher) = &enricher {
+                    enricher.enrich(&ip, &metrics);
+                }
+                let replies = round_config.run(&ip, &interface).await;
+                let round = collect_round(&metrics, &ip, &interface, replies);
+                metrics.set_round(&ip, &interface, &round);
+                let Some(stats) = round.stats() else {
+                    // ペイロードの壊れた応答があれば ICMP は届いているので測り直さない
+                    if round.replied == 0 {
+                        measure_fallback(&metrics, &ip, &interface, &data_types).await;
+                    }
+                    return None;
+                };
+                for data_type in &data_types {
+                    metrics.set_rtt(&ip, &interface, data_type, ProbeType::Icmp, stats.avg);
+                }
+                let data_type = data_types.join("/");
+                if round.sent > 1 {
+                    info!(
+                        "Measured RTT to {} on {} ({}): avg={:.2}ms min={:.2}ms max={:.2}ms jitter={:.2}ms loss={:.0}%",
+                        ip,
+                        interface,
+                        data_type,
+                        stats.avg,
+                        stats.min,
+                        stats.max,
+                        stats.jitter,
+                        round.loss_ratio() * 100.0
+                    );
+                } else {
                     info!(
                         "Measured RTT to {} on {} ({}): {:.2}ms",
-                        ip, interface, data_type, rtt
+                        ip, interface, data_type, stats.avg
                     );
                 }
+                Some((interface, stats.avg))
             })
         })
         .collect();
 
     // すべてのタスクが完了するまで待つ
+    let mut measured = Vec::new();
     for handle in handles {
-        let _ = handle.await;
+        if let Ok(Some(result)) = handle.await {
+            measured.push(result);
+        }
     }
+    measured
 }
 
-async fn run_http_server(metrics: Arc<MetricsCollector>, port: u16) -> Result<()> {
-    use hyper::service::{make_service_fn, service_fn};
-    use hyper::{Body, Request, Response, Server, StatusCode};
-
-    let metrics_clone = Arc::clone(&metrics);
-
-    let make_svc = make_service_fn(move |_conn| {
-        let metrics = Arc::clone(&metrics_clone);
-        async move {
-            Ok::<_, hyper::Error>(service_fn(move |_req: Request<Body>| {
-                let metrics = Arc::clone(&metrics);
-                async move {
-                    match metrics.gather_metrics() {
-                        Ok(body) => Ok::<_, hyper::Error>(
-                            Response::builder()
-                                .status(StatusCode::OK)
-                                .header("Content-Type", "text/plain; version=0.0.4")
-                                .body(Body::from(body))
-                                .unwrap(),
-                        ),
-                        Err(_) => Ok(Response::builder()
-                            .status(StatusCode::INTERNAL_SERVER_ERROR)
-                            .body(Body::from("Error gathering metrics"))
-                            .unwrap()),
-                    }
-                }
-            }))
+// バッチエンジンで全ターゲットをラベルごとの送信元のソケットからまとめて測定し、(interface, 平均 RTT) を返す
+// （PROBE_COUNT 回のバッチを PROBE_SPACING_MS 空けて続けて送る）
+async fn batch_ping(
+    metrics: &Arc<MetricsCollector>,
+    probe_targets: &[RemoteIpMetric],
+    pinger: Arc<batch::BatchPinger>,
+    enricher: Option<Arc<enrich::Enricher>>,
+    round_config: RoundConfig,
+) -> Vec<(String, f64)> {
+    if let Some(enricher) = &enricher {
+        for metric in probe_targets {
+            enricher.enrich(&metric.ip, metrics);
         }
-    });
+    }
+    // 同じ (IP, interface) が download / upload の両方で選ばれても 1 回だけ送る
+    let targets: Arc<Vec<(IpAddr, String)>> = Arc::new(
+        probe_targets
+            .iter()
+            .filter_map(|metric| Some((metric.ip.parse().ok()?, metric.interface.clone())))
+            .collect::<BTreeSet<(IpAddr, String)>>()
+            .into_iter()
+            .collect(),
+    );
+    let sent = targets.len();
+    let mut batches = Vec::with_capacity(round_config.count);
+    for i in 0..round_config.count {
+        if i > 0 {
+            sleep(round_config.spacing).await;
+        }
+        ratelimit::acquire(sent as u32).await;
+        let (pinger, targets) = (Arc::clone(&pinger), Arc::clone(&targets));
+        match task::spawn_blocking(move || pinger.probe(&targets)).await {
+            Ok(replies) => batches.push(replies),
+            Err(e) => {
+                error!("Batch ping failed: {}", e);
+                return Vec::new();
+            }
+        }
+    }
+    let replied = batches
+        .iter()
+        .flat_map(|replies| replies.keys())
+        .collect::<BTreeSet<_>>()
+        .len();
+    info!("Batch ping: {} of {} targets replied", replied, sent);
 
-    let addr = ([127, 0, 0, 1], port).into();
-    let server = Server::bind(&addr).serve(make_svc);
+    // 応答は (IP, interface) ごとに 1 回だけ数える
+    let mut rounds: HashMap<(IpAddr, String), Round> = HashMap::new();
+    let mut measured = Vec::new();
+    let mut unanswered: Vec<((String, String), Vec<String>)> = Vec::new();
+    for ((ip, interface), data_types) in group_targets(probe_targets) {
+        let Ok(address) = ip.parse::<IpAddr>() else {
+            continue;
+        };
+        let key = (address, interface.clone());
+        let round = rounds.entry(key.clone()).or_insert_with(|| {
+            let replies = batches.iter().map(|replies| replies.get(&key).copied());
+            collect_round(metrics, &ip, &interface, replies)
+        });
+        metrics.set_round(&ip, &interface, round);
+        let Some(stats) = round.stats() else {
+            if round.replied == 0 {
+                unanswered.push(((ip, interface), data_types));
+            }
+            continue;
+        };
+        for data_type in &data_types {
+            metrics.set_rtt(&ip, &interface, data_type, ProbeType::Icmp, stats.avg);
+        }
+        debug!(
+            "Measured RTT to {} on {} ({}): {:.2}ms",
+            ip,
+            interface,
+            data_types.join("/"),
+            stats.avg
+        );
+        measured.push((interface, stats.avg));
+    }
 
-    info!("Metrics server listening on http://{}", addr);
-    server.await?;
+    // 応答の無かったターゲットは並行して TCP / UDP で測り直す
+    if FALLBACK.get().is_some() {
+        let fallbacks: Vec<_> = unanswered
+            .into_iter()
+            .map(|((ip, interface), data_types)| {
+                let metrics = Arc::clone(metrics);
+                task::spawn(async move {
+                    measure_fallback(&metrics, &ip, &interface, &data_types).await;
+                })
+            })
+            .collect();
+        for fallback in fallbacks {
+            let _ = fallback.await;
+        }
+    }
+    measured
+}
+
+// ソート済みの値から nearest-rank 方式でパーセンタイルを求める
+fn percentile(sorted: &[f64], q: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = (q * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+async fn run_http_server(metrics: Arc<MetricsCollector>, port: u16) -> Result<()> {
+    let json = |result: Result<String>| match result {
+        Ok(body) => server::json_response(body),
+        Err(_) => {
+            server::error_response(StatusCode::INTERNAL_SERVER_ERROR, "Error gathering metrics")
+        }
+    };
+    let bufferbloat_metrics = Arc::clone(&metrics);
+    MetricsServer::new(([127, 0, 0, 1], port), metrics.registry.clone())
+        // METRICS_AUTH_* / METRICS_TLS_* が設定されていれば認証と HTTPS を有効にする
+        .with_env_security()
+        .map_err(anyhow::Error::msg)?
+        // /bufferbloat はインターフェースごとの評価を JSON で返す
+        .route("/bufferbloat", move |_| {
+            json(bufferbloat_metrics.bufferbloat_json())
+        })
+        // /daily はターゲットごとの日次 RTT を JSON で返す
+        .route("/daily", move |_| json(metrics.daily.to_json()))
+        // /buildinfo は動いているビルドの情報を JSON で返す
+        .build_info(build_info!())
+        .serve()
+        .await?;
 
     Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let args = Args::parse();
+
     // ログ初期化
     tracing_subscriber::fmt()
         .with_max_level(tracing::Level::INFO)
         .init();
 
-    let prometheus_url = "http://localhost:9090/";
-    let exporter_port = 59123;
+    // --config traffic-scan.toml（環境変数が優先）
+    if let Err(e) = shared_config::init(shared_config::ICMP_TRAFFIC_SCAN) {
+        error!("{}", e);
+        std::process::exit(2);
+    }
+
+    let prometheus_url = setting(
+        args.prometheus_url,
+        "PROMETHEUS_URL",
+        "http://localhost:9090".to_string(),
+    );
+    let targets_url = setting(args.targets_url, "TARGETS_URL", String::new());
+    // Prometheus で localPacketDump-rs をスクレイプしているジョブの名前
+    let job = shared_config::var("LOCALPACKETDUMP_JOB")
+        .ok()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .unwrap_or_else(|| "localpacketdump-rs".to_string());
+    let exporter_port: u16 = setting(args.listen_port, "LISTEN_PORT", 59123);
+    let probe_interval =
+        Duration::from_secs(setting(args.interval, "PROBE_INTERVAL_SECS", 1).max(1));
+    let min_bytes: u64 = setting(args.min_bytes, "MIN_BYTES", 100);
+    let ping_timeout = Duration::from_millis(setting(args.timeout, "PING_TIMEOUT_MS", 1000));
+    let ping_concurrency = setting(args.concurrency, "PING_CONCURRENCY", 256).max(1) as usize;
+    info!(
+        "Prometheus {}, metrics on port {}, probing remotes above {} bytes",
+        prometheus_url, exporter_port, min_bytes
+    );
+    // 周期の開始を壁時計の境界から PROBE_PHASE_MS だけずらす（tcp-traffic-scan の --phase と重ねない）
+    let probe_phase = Duration::from_millis(
+        shared_config::var("PROBE_PHASE_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0),
+    );
+    let schedule = Schedule::new(probe_interval, probe_phase);
+    info!(
+        "Probe cycle every {:?} at phase {:?}",
+        probe_interval, probe_phase
+    );
+    // この通信量（バイト/秒）を超えるインターフェースの測定は loaded として扱う
+    let loaded_bytes_threshold: u64 = shared_config::var("LOADED_BYTES_THRESHOLD")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(1_250_000);
+
+    if shared_sim::enabled() {
+        let scenario = SIMULATION.get_or_init(|| Arc::new(shared_sim::Scenario::from_env()));
+        info!("Simulating Prometheus and ping: {}", scenario.summary());
+    }
 
     let metrics = Arc::new(MetricsCollector::new()?);
+    let http_client = Arc::new(HttpClient::from_env());
+    let mut prometheus =
+        PrometheusClient::new(&prometheus_url, Arc::clone(&http_client)).with_env_auth();
+    if let Some(scenario) = SIMULATION.get() {
+        prometheus = prometheus.simulated(Arc::clone(scenario));
+    }
+    // TARGETS_URL があれば localPacketDump-rs から直接、無ければ Prometheus から測定対象を取得
+    let target_source = targets::TargetSource::new(targets_url, Arc::clone(&http_client));
+
+    // 1 周期あたりの ping 数の上限と、通信量に応じたターゲットの選択
+    let mut scheduler = scheduler::ProbeScheduler::from_env();
+
+    // 逆引き / GeoIP による測定対象の情報付与（設定されている場合のみ）
+    let enricher = enrich::Enricher::from_env().map(Arc::new);
+
+    // PING_ENGINE=batch なら送信元ごとのソケットから全ターゲットへまとめて ping する
+    let echo_payload = ECHO_PAYLOAD.get_or_init(payload::EchoPayload::from_env);
+    let bindings = BINDINGS.get_or_init(bind::Bindings::from_env);
+    let pinger = batch::BatchPinger::from_env(echo_payload.clone(), bindings).map(Arc::new);
+    // それ以外（急増時の即時測定を含む）はターゲットごとに ICMP ソケットから ping する
+    // 1 周期に 1 ターゲットへ送る echo request の数と間隔
+    let round_config = RoundConfig::from_env();
+    if SIMULATION.get().is_none() {
+        if let Some(limiter) = ratelimit::RateLimiter::from_env() {
+            let _ = RATE_LIMIT.set(limiter);
+        }
+        if let Some(fallback) = fallback::FallbackProbe::from_env(ping_timeout) {
+            let _ = FALLBACK.set(fallback);
+        }
+        if let Some(native) = icmp::Pinger::from_env(
+            echo_payload.clone(),
+            bindings,
+            ping_timeout,
+            ping_concurrency,
+        ) {
+            let _ = PINGER.set(native);
+        } else {
+            let _ = PING_COMMAND.set(PingCommand {
+                timeout: ping_timeout,
+                limit: tokio::sync::Semaphore::new(ping_concurrency),
+            });
+        }
+    }
 
     // HTTP サーバーをバックグラウンドで起動
     let server_metrics = Arc::clone(&metrics);
@@ -249,13 +1316,53 @@ async fn main() -> Result<()> {
         }
     });
 
+    // 日次 RTT と直近の RTT / ロス率を 1 分ごとにファイルへ保存
+    let daily_metrics = Arc::clone(&metrics);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            if let Err(e) = daily_metrics.daily.save() {
+                error!("Failed to save daily RTT stats: {}", e);
+            }
+            if let Err(e) = daily_metrics.gauge_state.save() {
+                error!("Failed to save gauge state: {}", e);
+            }
+        }
+    });
+
+    // 静かな時間帯のバースト測定（QUIET_HOURS が設定されている場合のみ）
+    let burst_targets: Arc<Mutex<Vec<RemoteIpMetric>>> = Arc::new(Mutex::new(Vec::new()));
+    if let Some(burst_config) = round::BurstConfig::from_env() {
+        let burst_metrics = Arc::clone(&metrics);
+        let targets = Arc::clone(&burst_targets);
+        tokio::spawn(async move {
+            round::run_burst_scheduler(burst_config, burst_metrics, targets).await;
+        });
+    }
+
+    // 通信量の急増を検知したら即座に測定（SPIKE_WINDOW_URL が設定されている場合のみ）
+    if let Some(spike_config) = spike::SpikeConfig::from_env() {
+        let spike_client = Arc::clone(&http_client);
+        let spike_metrics = Arc::clone(&metrics);
+        tokio::spawn(async move {
+            spike::run_spike_watcher(spike_config, spike_client, spike_metrics).await;
+        });
+    }
+
     // メインループ：定期的に Prometheus からデータを取得して ICMP ping を実行
+    // 1 周期ごとに systemd の watchdog へ知らせ、ループが止まったら再起動させる
+    let mut watchdog = traffic_scan_core::systemd::Watchdog::from_env();
     loop {
-        match fetch_prometheus_metrics(prometheus_url).await {
-            Ok(remote_metrics) => {
+        watchdog.pet();
+        // 周期（既定で 1 秒、Prometheus のスクレイプ間隔に合わせる）の境界 + 位相まで待つ
+        sleep(schedule.until_next()).await;
+        match fetch_targets(target_source.as_ref(), &prometheus, &job, min_bytes).await {
+            Ok((remote_metrics, input_window)) => {
                 info!(
-                    "Fetched {} metrics from Prometheus (filtered by >100 bytes)",
-                    remote_metrics.len()
+                    "Fetched {} metrics (filtered by >{} bytes)",
+                    remote_metrics.len(),
+                    min_bytes
                 );
                 for metric in &remote_metrics {
                     info!(
@@ -264,15 +1371,40 @@ async fn main() -> Result<()> {
                     );
                 }
 
+                // バースト測定用に直近のターゲットを共有
+                *burst_targets.lock().unwrap() = remote_metrics.clone();
+
+                // download / upload の両方に現れた (ip, interface) は 1 つのターゲットとして数える
+                let probe_targets = scheduler.select(&remote_metrics);
+                let found = scheduler::count_targets(&remote_metrics);
+                let probed = scheduler::count_targets(&probe_targets);
+                metrics.set_probe_targets(probed, found - probed);
+                if probed < found {
+                    info!(
+                        "Probing {} of {} targets (budget {} targets/s)",
+                        probed,
+                        found,
+                        scheduler.budget()
+                    );
+                }
+
+                metrics.observe_input_window(input_window);
+
                 // ICMP ping を実行してメトリクスを更新
-                ping_and_update_metrics(Arc::clone(&metrics), remote_metrics).await;
+                ping_and_update_metrics(
+                    Arc::clone(&metrics),
+                    remote_metrics,
+                    probe_targets,
+                    loaded_bytes_threshold,
+                    enricher.clone(),
+                    pinger.clone(),
+                    round_config,
+                )
+                .await;
             }
             Err(e) => {
                 error!("Failed to fetch Prometheus metrics: {}", e);
             }
         }
-
-        // スクレイプ間隔は 1 秒（Prometheus の設定に合わせる）
-        sleep(Duration::from_secs(1)).await;
     }
 }
@@ -0,0 +1,104 @@
+// echo request のペイロードと応答の検証
+//
+// ECHO_PAYLOAD_PATTERN（16 進、"a5" や "ff00ff00"）を設定すると、ペイロードをそのパターンの繰り返しで埋め、
+// 応答のペイロードをバイト単位で比較する。パケットを落とさずに中身だけ壊すモデムを見つけるためのもので、
+// 一致しない応答は RTT に使わず rtt_icmp_echo_replies_total{result="corrupted"} に数える。
+
+use tracing::{info, warn};
+
+// `ping` と同じペイロード長
+const DEFAULT_SIZE: usize = 56;
+// IPv4 で 1500 バイトに収まる最大
+const MAX_SIZE: usize = 1472;
+// `ping -p` が受け付けるパターン長
+const MAX_PATTERN_LEN: usize = 16;
+
+// 応答の判定結果
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reply {
+    // RTT（ミリ秒）
+    Valid(f64),
+    // ペイロードが送ったものと一致しない
+    Corrupted,
+}
+
+#[derive(Debug, Clone)]
+pub struct EchoPayload {
+    // None ならゼロ埋めで検証しない
+    pattern: Option<Vec<u8>>,
+    bytes: Vec<u8>,
+}
+
+impl EchoPayload {
+    pub fn from_env() -> Self {
+        let size = shared_config::var("ECHO_PAYLOAD_SIZE")
+            .ok()
+            .and_then(|v| v.trim().parse::<usize>().ok())
+            .unwrap_or(DEFAULT_SIZE)
+            .min(MAX_SIZE);
+        let pattern = shared_config::var("ECHO_PAYLOAD_PATTERN")
+            .ok()
+            .filter(|v| !v.trim().is_empty())
+            .and_then(|v| match parse_hex(v.trim()) {
+                Some(pattern) => Some(pattern),
+                None => {
+                    warn!(
+                        "Invalid ECHO_PAYLOAD_PATTERN {}: expected 1 to {} hex bytes, replies are not validated",
+                        v, MAX_PATTERN_LEN
+                    );
+                    None
+                }
+            });
+        let bytes = match &pattern {
+            Some(pattern) => pattern.iter().copied().cycle().take(size).collect(),
+            None => vec![0; size],
+        };
+        let payload = Self { pattern, bytes };
+        if let Some(pattern) = &payload.pattern {
+            info!(
+                "Validating {}-byte echo payloads filled with {}",
+                size,
+                hex(pattern)
+            );
+        }
+        payload
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn is_validated(&self) -> bool {
+        self.pattern.is_some()
+    }
+
+    // 受け取ったペイロードが送ったものと同じか（検証しないときは常に true）
+    pub fn matches(&self, received: &[u8]) -> bool {
+        !self.is_validated() || received == self.bytes.as_slice()
+    }
+
+    // `ping` コマンドに渡す -s / -p
+    pub fn ping_args(&self) -> Vec<String> {
+        let mut args = vec!["-s".to_string(), self.bytes.len().to_string()];
+        if let Some(pattern) = &self.pattern {
+            args.push("-p".to_string());
+            args.push(hex(pattern));
+        }
+        args
+    }
+}
+
+fn parse_hex(value: &str) -> Option<Vec<u8>> {
+    let value = value.strip_prefix("0x").unwrap_or(value);
+    if value.is_empty() || !value.len().is_multiple_of(2) || value.len() / 2 > MAX_PATTERN_LEN {
+        return None;
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,79 @@
+// 全体の送信レートの上限（PROBE_RATE_LIMIT）
+//
+// PROBE_BUDGET_PER_SEC はターゲットの数を抑えるだけで、PROBE_COUNT や急増時の即時測定、
+// バースト測定、TCP / UDP の測定を重ねると 1 秒に送るパケットはその何倍にもなる。
+// すべての echo request と TCP / UDP のプローブは送る前にこのトークンバケットからトークンを取り、
+// 足りなければ補充されるまで待つ（足りない分は前借りし、後から取る送信が待つ）。
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+use tracing::info;
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+pub struct RateLimiter {
+    // 1 秒あたりに補充するトークン（= パケット）
+    rate: f64,
+    // 溜められるトークンの上限
+    burst: f64,
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    // PROBE_RATE_LIMIT=0 なら無効
+    pub fn from_env() -> Option<Self> {
+        let rate = shared_config::var("PROBE_RATE_LIMIT")
+            .ok()
+            .and_then(|v| v.parse::<f64>().ok())
+            .unwrap_or(500.0);
+        if rate <= 0.0 {
+            info!("Probe rate limit disabled");
+            return None;
+        }
+        let burst = shared_config::var("PROBE_RATE_BURST")
+            .ok()
+            .and_then(|v| v.parse::<f64>().ok())
+            .filter(|burst| *burst >= 1.0)
+            .unwrap_or(rate);
+        info!("Probe rate limit: {} packets/s (burst {})", rate, burst);
+        Some(Self {
+            rate,
+            burst,
+            bucket: Mutex::new(Bucket {
+                tokens: burst,
+                refilled: Instant::now(),
+            }),
+        })
+    }
+
+    // packets 個のパケットを送れるようになるまで待つ
+    pub async fn acquire(&self, packets: u32) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+            bucket.refilled = now;
+            bucket.tokens -= packets as f64;
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / self.rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+        if !wait.is_zero() {
+            sleep(wait).await;
+        }
+    }
+}
+
+// 設定されていれば全体のレートに従って待つ
+pub async fn acquire(packets: u32) {
+    if let Some(limiter) = crate::RATE_LIMIT.get() {
+        limiter.acquire(packets).await;
+    }
+}
@@ -0,0 +1,381 @@
+// 同じターゲットへ間隔をずらして送る複数の echo request
+//
+// 毎周期の測定（PROBE_COUNT）と、静かな時間帯（QUIET_HOURS）にだけ実行する重めのバースト測定は
+// どちらも「count 回の echo request を間隔をずらして送り、最小 / 平均 / 最大 RTT とロス率にまとめる」ので、
+// 送信（spaced）と集計（Round）をこのモジュールで共有する。
+//
+// 毎周期: 1 回の ping では RTT がばらつき、ロスも分からない。周期ごとに PROBE_COUNT 回の echo request を
+// PROBE_SPACING_MS ずつずらして送り、最小 / 平均 / 最大 RTT、ジッター（平均からの平均絶対偏差）、
+// ロス率を icmp_rtt_min / icmp_rtt_avg / icmp_rtt_max / icmp_jitter / icmp_loss_ratio として公開する。
+// rtt_icmp_dump には平均を入れる（PROBE_COUNT=1 なら従来どおり 1 回の RTT）。
+//
+// バースト: 毎秒のメインループとは独立したタスクで動作し、結果は rtt_icmp_burst 系の
+// 別メトリクスとして公開する。echo request はネイティブエンジン（icmp::Pinger）の
+// ソケットから送り、ICMP ソケットを使えないときだけ `ping` コマンドを使う。
+
+use crate::payload::Reply;
+use crate::{MetricsCollector, RemoteIpMetric};
+use chrono::{Local, NaiveTime};
+use std::collections::HashSet;
+use std::future::Future;
+use std::net::IpAddr;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::{self, JoinSet};
+use tokio::time::sleep;
+use tracing::{error, info, warn};
+
+// 1 周期に 1 ターゲットへ送る echo request の上限
+const MAX_COUNT: usize = 100;
+
+// PMTU 確認に使うペイロードサイズ（1500 - IP ヘッダ 20 / 40 - ICMP ヘッダ 8）
+const PMTU_PAYLOAD_SIZE_V4: u32 = 1472;
+const PMTU_PAYLOAD_SIZE_V6: u32 = 1452;
+
+// バーストの送信間隔（`ping -i 0.2` と同じ）
+const BURST_SPACING: Duration = Duration::from_millis(200);
+
+// probe を count 回、spacing ずつずらして並行に実行し、結果を返す（順序は完了順）
+async fn spaced<T, F, Fut>(count: usize, spacing: Duration, probe: F) -> Vec<T>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = T> + Send + 'static,
+    T: Default + Send + 'static,
+{
+    let mut probes = JoinSet::new();
+    for i in 0..count {
+        let delay = spacing * i as u32;
+        let probe = probe();
+        probes.spawn(async move {
+            sleep(delay).await;
+            probe.await
+        });
+    }
+    let mut results = Vec::with_capacity(count);
+    while let Some(result) = probes.join_next().await {
+        results.push(result.unwrap_or_default());
+    }
+    results
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RoundConfig {
+    pub count: usize,
+    // echo request の送信間隔
+    pub spacing: Duration,
+}
+
+impl RoundConfig {
+    pub fn from_env() -> Self {
+        let count = shared_config::var("PROBE_COUNT")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(1)
+            .clamp(1, MAX_COUNT);
+        let spacing = Duration::from_millis(
+            shared_config::var("PROBE_SPACING_MS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(20),
+        );
+        if count > 1 {
+            info!("Sending {} probes per target {:?} apart", count, spacing);
+        }
+        Self { count, spacing }
+    }
+
+    // count 回の echo request を spacing ずつずらして並行に送り、応答（無ければ None）を返す
+    pub async fn run(&self, ip: &str, interface: &str) -> Vec<Option<Reply>> {
+        spaced(self.count, self.spacing, || {
+            let (ip, interface) = (ip.to_string(), interface.to_string());
+            async move { crate::measure_icmp_reply(&ip, &interface).await }
+        })
+        .await
+    }
+}
+
+// 1 周期分の結果
+#[derive(Debug, Clone, Default)]
+pub struct Round {
+    pub sent: usize,
+    // ペイロードが壊れていた応答も含む
+    pub replied: usize,
+    // ペイロードが一致した応答の RTT（ミリ秒）
+    pub rtts: Vec<f64>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RttStats {
+    pub min: f64,
+    pub avg: f64,
+    pub max: f64,
+    // 平均からの平均絶対偏差
+    pub jitter: f64,
+}
+
+impl Round {
+    pub fn loss_ratio(&self) -> f64 {
+        if self.sent == 0 {
+            return 0.0;
+        }
+        1.0 - self.replied as f64 / self.sent as f64
+    }
+
+    // RTT が 1 つも無ければ None
+    pub fn stats(&self) -> Option<RttStats> {
+        if self.rtts.is_empty() {
+            return None;
+        }
+        let n = self.rtts.len() as f64;
+        let avg = self.rtts.iter().sum::<f64>() / n;
+        Some(RttStats {
+            min: self.rtts.iter().copied().fold(f64::INFINITY, f64::min),
+            avg,
+            max: self.rtts.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+            jitter: self.rtts.iter().map(|rtt| (rtt - avg).abs()).sum::<f64>() / n,
+        })
+    }
+}
+
+// "HH:MM-HH:MM" 形式の時間帯（日付をまたいでもよい）
+#[derive(Debug, Clone, Copy)]
+pub struct QuietWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl QuietWindow {
+    pub fn parse(s: &str) -> Option<Self> {
+        let (start, end) = s.split_once('-')?;
+        let start = NaiveTime::parse_from_str(start.trim(), "%H:%M").ok()?;
+        let end = NaiveTime::parse_from_str(end.trim(), "%H:%M").ok()?;
+        Some(Self { start, end })
+    }
+
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BurstConfig {
+    window: QuietWindow,
+    // バースト測定の実行間隔
+    interval: Duration,
+    // 1 ターゲットあたりの ping 回数
+    count: u32,
+    // ping のペイロードサイズ（バイト）
+    payload_size: u32,
+}
+
+impl BurstConfig {
+    // QUIET_HOURS が設定されていない場合はスケジューラを無効にする
+    pub fn from_env() -> Option<Self> {
+        let quiet_hours = shared_config::var("QUIET_HOURS").ok()?;
+        let window = match QuietWindow::parse(&quiet_hours) {
+            Some(window) => window,
+            None => {
+                error!(
+                    "Invalid QUIET_HOURS {} (expected HH:MM-HH:MM), burst tests disabled",
+                    quiet_hours
+                );
+                return None;
+            }
+        };
+
+        let env_u64 = |name: &str, default: u64| {
+            shared_config::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        };
+
+        // 0 回ではロス率が求まらない
+        let count = env_u64("BURST_COUNT", 20) as u32;
+        if count == 0 {
+            error!("BURST_COUNT must be at least 1, burst tests disabled");
+            return None;
+        }
+
+        Some(Self {
+            window,
+            interval: Duration::from_secs(env_u64("BURST_INTERVAL_SECS", 900)),
+            count,
+            payload_size: env_u64("BURST_PAYLOAD_SIZE", 1200) as u32,
+        })
+    }
+}
+
+// 静かな時間帯に入っている間、interval ごとに直近のターゲットへバースト測定を行う
+pub async fn run_burst_scheduler(
+    config: BurstConfig,
+    metrics: Arc<MetricsCollector>,
+    targets: Arc<Mutex<Vec<RemoteIpMetric>>>,
+) {
+    info!(
+        "Burst tests scheduled between {}-{} every {:?}",
+        config.window.start.format("%H:%M"),
+        config.window.end.format("%H:%M"),
+        config.interval
+    );
+
+    let mut interval = tokio::time::interval(config.interval);
+    loop {
+        interval.tick().await;
+
+        if !config.window.contains(Local::now().time()) {
+            continue;
+        }
+
+        // download / upload で重複しているターゲットは 1 回だけ測定する
+        let unique_targets: HashSet<(String, String)> = targets
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|metric| (metric.ip.clone(), metric.interface.clone()))
+            .collect();
+
+        info!("Running burst tests for {} targets", unique_targets.len());
+
+        // 軽量ループに影響しないよう、ターゲットは 1 つずつ順番に測定する
+        for (ip, interface) in unique_targets {
+            // count 回の ping と PMTU の確認の 1 回
+            crate::ratelimit::acquire(config.count + 1).await;
+            let round = burst(&ip, &interface, config.count, config.payload_size, false).await;
+            let pmtu_payload_size = if ip.contains(':') {
+                PMTU_PAYLOAD_SIZE_V6
+            } else {
+                PMTU_PAYLOAD_SIZE_V4
+            };
+            let pmtu_ok = burst(&ip, &interface, 1, pmtu_payload_size, true)
+                .await
+                .replied
+                > 0;
+
+            metrics.set_burst_loss(&ip, &interface, round.loss_ratio());
+            metrics.set_burst_pmtu_ok(&ip, &interface, pmtu_ok);
+
+            if let Some(stats) = round.stats() {
+                metrics.set_burst_rtt(&ip, &interface, "min", stats.min);
+                metrics.set_burst_rtt(&ip, &interface, "avg", stats.avg);
+                metrics.set_burst_rtt(&ip, &interface, "max", stats.max);
+                info!(
+                    "Burst to {} on {}: min={:.2}ms avg={:.2}ms max={:.2}ms loss={:.0}% pmtu_ok={}",
+                    ip,
+                    interface,
+                    stats.min,
+                    stats.avg,
+                    stats.max,
+                    round.loss_ratio() * 100.0,
+                    pmtu_ok
+                );
+            } else {
+                warn!("Burst to {} on {}: no replies", ip, interface);
+            }
+        }
+    }
+}
+
+// count 回の echo request を BURST_SPACING ずつずらして送り、1 回分の結果にまとめる
+async fn burst(
+    target_ip: &str,
+    interface: &str,
+    count: u32,
+    payload_size: u32,
+    dont_fragment: bool,
+) -> Round {
+    let rtts = if let Some(scenario) = crate::SIMULATION.get() {
+        (0..count).filter_map(|_| scenario.sample_rtt()).collect()
+    } else if let Some(pinger) = crate::PINGER.get() {
+        let Ok(target) = target_ip.parse::<IpAddr>() else {
+            warn!("Skipping burst to {}: not an IP address", target_ip);
+            return Round::default();
+        };
+        let payload = Arc::new(vec![0u8; payload_size as usize]);
+        spaced(count as usize, BURST_SPACING, || {
+            let (interface, payload) = (interface.to_string(), Arc::clone(&payload));
+            async move {
+                pinger
+                    .ping_with(target, &interface, &payload, dont_fragment)
+                    .await
+            }
+        })
+        .await
+        .into_iter()
+        .flatten()
+        .collect()
+    } else {
+        let (target_ip, interface) = (target_ip.to_string(), interface.to_string());
+        task::spawn_blocking(move || {
+            run_ping(&target_ip, &interface, count, payload_size, dont_fragment)
+        })
+        .await
+        .unwrap_or_default()
+    };
+    Round {
+        sent: count as usize,
+        replied: rtts.len(),
+        rtts,
+    }
+}
+
+// ICMP ソケットを使えないとき: ping を count 回実行し、応答のあった RTT（ミリ秒）を返す
+// （interface に PROBE_BIND の送信元があればそこから送る）
+fn run_ping(
+    target_ip: &str,
+    interface: &str,
+    count: u32,
+    payload_size: u32,
+    dont_fragment: bool,
+) -> Vec<f64> {
+    let mut command = Command::new("ping");
+    command
+        .arg("-c")
+        .arg(count.to_string())
+        .arg("-i")
+        .arg("0.2")
+        .arg("-s")
+        .arg(payload_size.to_string());
+
+    if let Some(binding) = crate::BINDINGS
+        .get()
+        .and_then(|bindings| bindings.get(interface))
+    {
+        let v6 = target_ip.contains(':');
+        if !binding.supports(v6) {
+            return Vec::new();
+        }
+        command.args(binding.ping_args(v6));
+    }
+
+    if dont_fragment {
+        #[cfg(target_os = "linux")]
+        command.arg("-M").arg("do");
+        #[cfg(not(target_os = "linux"))]
+        command.arg("-D");
+    }
+
+    let output = match command.arg(target_ip).output() {
+        Ok(output) => output,
+        Err(e) => {
+            error!("Failed to run ping: {}", e);
+            return Vec::new();
+        }
+    };
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    stdout
+        .lines()
+        .filter_map(|line| {
+            let start = line.find("time=")?;
+            let rest = &line[start + 5..];
+            let end = rest.find(" ms")?;
+            rest[..end].parse::<f64>().ok()
+        })
+        .collect()
+}
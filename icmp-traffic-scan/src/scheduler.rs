@@ -0,0 +1,97 @@
+// 毎秒の ping 対象を全体の予算内に収めるスケジューラ
+//
+// ターゲットごとに通信量に比例したクレジットを毎周期加算し、クレジットが 1 以上の
+// ターゲットから多い順に予算分だけ選ぶ（重み付き公平スケジューリング）。
+// 通信量の多いターゲットほど頻繁に測定され、数百の IP が見つかっても
+// 1 周期あたりに測定するターゲットが PROBE_BUDGET_PER_SEC を超えることはない。
+// download / upload の両方に現れた (ip, interface) は 1 つのターゲットとして数え、まとめて選ぶ。
+
+use crate::RemoteIpMetric;
+use std::collections::{HashMap, HashSet};
+use tracing::info;
+
+// 長く待たされたターゲットがまとめて選ばれ続けないようクレジットに上限を設ける
+const MAX_CREDIT: f64 = 2.0;
+
+// (ip, interface)
+type TargetKey = (String, String);
+
+pub struct ProbeScheduler {
+    // 1 周期（約 1 秒）あたりに測定するターゲットの上限
+    budget: usize,
+    credits: HashMap<TargetKey, f64>,
+}
+
+impl ProbeScheduler {
+    pub fn from_env() -> Self {
+        let budget = shared_config::var("PROBE_BUDGET_PER_SEC")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(50);
+        info!("Probe budget: {} probes/s", budget);
+        Self {
+            budget,
+            credits: HashMap::new(),
+        }
+    }
+
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    // 今周期に ping するターゲットを選ぶ（同じ (ip, interface) の download / upload は揃って選ばれる）
+    pub fn select(&mut self, targets: &[RemoteIpMetric]) -> Vec<RemoteIpMetric> {
+        // (ip, interface) ごとに通信量を合計する（順序は最初に現れた順）
+        let mut keys: Vec<TargetKey> = Vec::new();
+        let mut bytes: HashMap<TargetKey, u64> = HashMap::new();
+        for target in targets {
+            let key = target_key(target);
+            if !bytes.contains_key(&key) {
+                keys.push(key.clone());
+            }
+            *bytes.entry(key).or_insert(0) += target.bytes;
+        }
+
+        // 通信が無くなったターゲットのクレジットは捨てる
+        self.credits.retain(|key, _| bytes.contains_key(key));
+
+        if keys.len() <= self.budget {
+            return targets.to_vec();
+        }
+
+        // 予算を通信量の比で配分してクレジットに加算（新しいターゲットは最初の周期で選ばれる）
+        let total_bytes: u64 = bytes.values().sum::<u64>().max(1);
+        for key in &keys {
+            let share = self.budget as f64 * bytes[key] as f64 / total_bytes as f64;
+            let credit = self.credits.entry(key.clone()).or_insert(1.0 - share);
+            *credit = (*credit + share).min(MAX_CREDIT);
+        }
+
+        let mut candidates: Vec<(&TargetKey, f64)> = keys
+            .iter()
+            .map(|key| (key, self.credits[key]))
+            .filter(|(_, credit)| *credit >= 1.0)
+            .collect();
+        candidates.sort_by(|a, b| b.1.total_cmp(&a.1));
+        candidates.truncate(self.budget);
+
+        let selected: HashSet<&TargetKey> = candidates.into_iter().map(|(key, _)| key).collect();
+        for key in &selected {
+            *self.credits.get_mut(*key).unwrap() -= 1.0;
+        }
+        targets
+            .iter()
+            .filter(|target| selected.contains(&target_key(target)))
+            .cloned()
+            .collect()
+    }
+}
+
+// download / upload を区別しないターゲットの数
+pub fn count_targets(targets: &[RemoteIpMetric]) -> usize {
+    targets.iter().map(target_key).collect::<HashSet<_>>().len()
+}
+
+fn target_key(target: &RemoteIpMetric) -> TargetKey {
+    (target.ip.clone(), target.interface.clone())
+}
@@ -0,0 +1,148 @@
+// 通信量の急増をきっかけにした即時測定
+//
+// Prometheus 経由では急増から ping まで数秒遅れ、負荷がかかっている間の RTT を
+// 取り逃がす。localPacketDump-rs の /window.json を直接ポーリングし、閾値を超えた
+// 瞬間のリモート IP だけをすぐに測定する（rtt_icmp_dump{data_type="spike"}）。
+
+use crate::fallback::ProbeType;
+use crate::MetricsCollector;
+use serde::Deserialize;
+use serde_json::Value;
+use shared_http::HttpClient;
+use shared_schema::{
+    LABEL_INTERFACE, LABEL_REMOTE_IP, SCHEMA_VERSION_HEADER, WINDOW_SCHEMA_VERSION,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task;
+use tracing::{info, warn};
+
+#[derive(Debug, Clone)]
+pub struct SpikeConfig {
+    window_url: String,
+    // この通信量（バイト/秒）を下から超えたリモートを測定する
+    threshold_bytes: u64,
+    poll_interval: Duration,
+    // 同じリモートを続けて測定しない期間
+    cooldown: Duration,
+}
+
+impl SpikeConfig {
+    // SPIKE_WINDOW_URL が設定されていない場合は無効
+    pub fn from_env() -> Option<Self> {
+        let window_url = shared_config::var("SPIKE_WINDOW_URL")
+            .ok()
+            .filter(|url| !url.is_empty())?;
+        let env_u64 = |name: &str, default: u64| {
+            shared_config::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        };
+        Some(Self {
+            window_url,
+            threshold_bytes: env_u64("SPIKE_THRESHOLD_BYTES", 1_250_000),
+            poll_interval: Duration::from_millis(env_u64("SPIKE_POLL_MS", 500)),
+            cooldown: Duration::from_secs(env_u64("SPIKE_COOLDOWN_SECS", 10)),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct WindowSnapshot {
+    sequence: u64,
+    // remote_ip / interface などのラベルと download_bytes / upload_bytes
+    entries: Vec<HashMap<String, Value>>,
+}
+
+pub async fn run_spike_watcher(
+    config: SpikeConfig,
+    client: Arc<HttpClient>,
+    metrics: Arc<MetricsCollector>,
+) {
+    info!(
+        "Watching {} for traffic spikes above {} bytes/s",
+        config.window_url, config.threshold_bytes
+    );
+
+    let mut last_sequence = 0;
+    // (remote_ip, interface) -> 直前のウィンドウの通信量
+    let mut previous: HashMap<(String, String), u64> = HashMap::new();
+    let mut last_probe: HashMap<String, Instant> = HashMap::new();
+    let mut interval = tokio::time::interval(config.poll_interval);
+
+    loop {
+        interval.tick().await;
+        let snapshot = match fetch_window(&client, &config.window_url).await {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                warn!("Failed to fetch {}: {}", config.window_url, e);
+                continue;
+            }
+        };
+        // 同じウィンドウを二度評価しない
+        if snapshot.sequence == last_sequence {
+            continue;
+        }
+        last_sequence = snapshot.sequence;
+
+        let mut current: HashMap<(String, String), u64> = HashMap::new();
+        for entry in &snapshot.entries {
+            let (Some(remote_ip), Some(interface)) = (
+                entry.get(LABEL_REMOTE_IP).and_then(Value::as_str),
+                entry.get(LABEL_INTERFACE).and_then(Value::as_str),
+            ) else {
+                continue;
+            };
+            let bytes = ["download_bytes", "upload_bytes"]
+                .iter()
+                .filter_map(|field| entry.get(*field).and_then(Value::as_u64))
+                .sum::<u64>();
+            // local_ip / protocol ラベルで分かれている場合はリモートごとに合算する
+            *current
+                .entry((remote_ip.to_string(), interface.to_string()))
+                .or_insert(0) += bytes;
+        }
+
+        for ((remote_ip, interface), bytes) in &current {
+            let before = previous
+                .get(&(remote_ip.clone(), interface.clone()))
+                .copied()
+                .unwrap_or(0);
+            if *bytes <= config.threshold_bytes || before > config.threshold_bytes {
+                continue;
+            }
+            if last_probe
+                .get(remote_ip)
+                .is_some_and(|at| at.elapsed() < config.cooldown)
+            {
+                continue;
+            }
+            last_probe.insert(remote_ip.clone(), Instant::now());
+
+            let metrics = Arc::clone(&metrics);
+            let (remote_ip, interface, bytes) = (remote_ip.clone(), interface.clone(), *bytes);
+            task::spawn(async move {
+                if let Some(rtt) = crate::measure_icmp_rtt(&remote_ip, &interface).await {
+                    metrics.set_rtt(&remote_ip, &interface, "spike", ProbeType::Icmp, rtt);
+                    info!(
+                        "Traffic spike to {} on {} ({} bytes/s): RTT {:.2}ms",
+                        remote_ip, interface, bytes, rtt
+                    );
+                }
+            });
+        }
+
+        last_probe.retain(|_, at| at.elapsed() < config.cooldown);
+        previous = current;
+    }
+}
+
+async fn fetch_window(client: &HttpClient, url: &str) -> anyhow::Result<WindowSnapshot> {
+    let request = client
+        .get(url)
+        .header(SCHEMA_VERSION_HEADER, WINDOW_SCHEMA_VERSION.to_string());
+    let response = client.send(request).await?.error_for_status()?;
+    Ok(response.json::<WindowSnapshot>().await?)
+}
@@ -0,0 +1,212 @@
+// 直近の RTT / ロス率の保存と復元
+//
+// 再起動すると rtt_icmp_dump が最初の ping まで空になり、毎秒参照している
+// throughput-dump の計算が途切れる。最後の値をファイルに保存しておき、起動時に
+// 復元する。復元した値は新しく測定されるまで rtt_icmp_stale=1 として区別する。
+
+use anyhow::Result;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedRtt {
+    pub remote_ip: String,
+    pub interface: String,
+    pub data_type: String,
+    // 古いファイルには無いので icmp とみなす
+    #[serde(default = "icmp")]
+    pub probe_type: String,
+    pub rtt_ms: f64,
+    // 測定時刻（UNIX 秒）
+    pub measured_at: i64,
+}
+
+fn icmp() -> String {
+    "icmp".to_string()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedLoss {
+    pub remote_ip: String,
+    pub interface: String,
+    pub loss_ratio: f64,
+    pub measured_at: i64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SavedGauges {
+    rtt: Vec<SavedRtt>,
+    loss: Vec<SavedLoss>,
+}
+
+#[derive(Default)]
+struct Latest {
+    // (remote_ip, interface, data_type) -> (RTT, 測定時刻, probe_type)
+    rtt: HashMap<(String, String, String), (f64, i64, String)>,
+    // (remote_ip, interface) -> (ロス率, 測定時刻)
+    loss: HashMap<(String, String), (f64, i64)>,
+}
+
+pub struct GaugeState {
+    path: PathBuf,
+    // これより古い値は復元しない
+    max_age_secs: i64,
+    latest: Mutex<Latest>,
+}
+
+impl GaugeState {
+    pub fn from_env() -> Self {
+        // 既定は作業ディレクトリの gauge_state.json。systemd などで起動するときは絶対パスを指定する
+        let path = PathBuf::from(
+            shared_config::var("STATE_FILE")
+                .ok()
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+                .unwrap_or_else(|| "gauge_state.json".to_string()),
+        );
+        info!("Gauge state file: {:?}", path);
+        let max_age_secs = shared_config::var("GAUGE_STATE_MAX_AGE_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(600);
+        Self {
+            path,
+            max_age_secs,
+            latest: Mutex::new(Latest::default()),
+        }
+    }
+
+    // 前回保存した値のうち古すぎないものを読み込む（無ければ空）
+    pub fn load(&self) -> (Vec<SavedRtt>, Vec<SavedLoss>) {
+        let saved = match std::fs::read_to_string(&self.path) {
+            Ok(content) => match serde_json::from_str::<SavedGauges>(&content) {
+                Ok(saved) => saved,
+                Err(e) => {
+                    warn!("Failed to parse {:?}, not restoring: {}", self.path, e);
+                    return (Vec::new(), Vec::new());
+                }
+            },
+            Err(_) => return (Vec::new(), Vec::new()),
+        };
+
+        let oldest = Utc::now().timestamp() - self.max_age_secs;
+        let rtt: Vec<SavedRtt> = saved
+            .rtt
+            .into_iter()
+            .filter(|saved| saved.measured_at >= oldest)
+            .collect();
+        let loss: Vec<SavedLoss> = saved
+            .loss
+            .into_iter()
+            .filter(|saved| saved.measured_at >= oldest)
+            .collect();
+        info!(
+            "Restored {} RTT and {} loss values from {:?}",
+            rtt.len(),
+            loss.len(),
+            self.path
+        );
+
+        // 復元した値も次回の保存に含める（測定時刻はそのまま）
+        let mut latest = self.latest.lock().unwrap();
+        for saved in &rtt {
+            latest.rtt.insert(
+                (
+                    saved.remote_ip.clone(),
+                    saved.interface.clone(),
+                    saved.data_type.clone(),
+                ),
+                (saved.rtt_ms, saved.measured_at, saved.probe_type.clone()),
+            );
+        }
+        for saved in &loss {
+            latest.loss.insert(
+                (saved.remote_ip.clone(), saved.interface.clone()),
+                (saved.loss_ratio, saved.measured_at),
+            );
+        }
+        (rtt, loss)
+    }
+
+    pub fn record_rtt(
+        &self,
+        remote_ip: &str,
+        interface: &str,
+        data_type: &str,
+        probe_type: &str,
+        rtt_ms: f64,
+    ) {
+        self.latest.lock().unwrap().rtt.insert(
+            (
+                remote_ip.to_string(),
+                interface.to_string(),
+                data_type.to_string(),
+            ),
+            (rtt_ms, Utc::now().timestamp(), probe_type.to_string()),
+        );
+    }
+
+    pub fn record_loss(&self, remote_ip: &str, interface: &str, loss_ratio: f64) {
+        self.latest.lock().unwrap().loss.insert(
+            (remote_ip.to_string(), interface.to_string()),
+            (loss_ratio, Utc::now().timestamp()),
+        );
+    }
+
+    // 復元しても使われない古い値を除いてファイルに書き出す
+    pub fn save(&self) -> Result<()> {
+        let content = {
+            let mut latest = self.latest.lock().unwrap();
+            let oldest = Utc::now().timestamp() - self.max_age_secs;
+            latest
+                .rtt
+                .retain(|_, (_, measured_at, _)| *measured_at >= oldest);
+            latest
+                .loss
+                .retain(|_, (_, measured_at)| *measured_at >= oldest);
+            let saved = SavedGauges {
+                rtt: latest
+                    .rtt
+                    .iter()
+                    .map(
+                        |((remote_ip, interface, data_type), (rtt_ms, measured_at, probe_type))| {
+                            SavedRtt {
+                                remote_ip: remote_ip.clone(),
+                                interface: interface.clone(),
+                                data_type: data_type.clone(),
+                                probe_type: probe_type.clone(),
+                                rtt_ms: *rtt_ms,
+                                measured_at: *measured_at,
+                            }
+                        },
+                    )
+                    .collect(),
+                loss: latest
+                    .loss
+                    .iter()
+                    .map(
+                        |((remote_ip, interface), (loss_ratio, measured_at))| SavedLoss {
+                            remote_ip: remote_ip.clone(),
+                            interface: interface.clone(),
+                            loss_ratio: *loss_ratio,
+                            measured_at: *measured_at,
+                        },
+                    )
+                    .collect(),
+            };
+            serde_json::to_string(&saved)?
+        };
+        // STATE_FILE に指定したディレクトリがまだ無ければ作る
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        let tmp = self.path.with_extension("json.tmp");
+        std::fs::write(&tmp, content)?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
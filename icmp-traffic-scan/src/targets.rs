@@ -0,0 +1,86 @@
+// localPacketDump-rs から直接測定対象を取得する（TARGETS_URL）
+//
+// Prometheus 経由ではスクレイプ間隔の分だけ測定対象が遅れ、Prometheus が止まると測定も止まる。
+// TARGETS_URL に localPacketDump-rs の /targets を設定すると、最後に確定したウィンドウの
+// (remote_ip, interface) ごとの通信量を直接受け取って測定する。取得に失敗した周期は
+// 従来どおり Prometheus のクエリで測定対象を決める。
+
+use crate::{InputWindow, RemoteIpMetric};
+use serde::Deserialize;
+use shared_http::HttpClient;
+use std::sync::Arc;
+use tracing::info;
+
+#[derive(Debug, Deserialize)]
+struct TargetsPage {
+    sequence: u64,
+    timestamp_ms: i64,
+    targets: Vec<Target>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Target {
+    remote_ip: String,
+    interface: String,
+    download_bytes: u64,
+    upload_bytes: u64,
+}
+
+pub struct TargetSource {
+    url: String,
+    client: Arc<HttpClient>,
+}
+
+impl TargetSource {
+    // URL が空なら無効（Prometheus だけを使う）
+    pub fn new(url: String, client: Arc<HttpClient>) -> Option<Self> {
+        if url.trim().is_empty() {
+            return None;
+        }
+        info!(
+            "Fetching probe targets from {} (Prometheus as fallback)",
+            url
+        );
+        Some(Self { url, client })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    // Prometheus から取得した場合と同じく、download / upload ごとに min_bytes を超えたものを返す
+    pub async fn fetch(
+        &self,
+        min_bytes: u64,
+    ) -> anyhow::Result<(Vec<RemoteIpMetric>, InputWindow)> {
+        let request = self
+            .client
+            .get(&self.url)
+            .query(&[("min_bytes", min_bytes)]);
+        let response = self.client.send(request).await?.error_for_status()?;
+        let page = response.json::<TargetsPage>().await?;
+
+        let mut metrics_list = Vec::new();
+        for target in page.targets {
+            for (data_type, bytes) in [
+                ("download", target.download_bytes),
+                ("upload", target.upload_bytes),
+            ] {
+                if bytes <= min_bytes {
+                    continue;
+                }
+                metrics_list.push(RemoteIpMetric {
+                    ip: target.remote_ip.clone(),
+                    interface: target.interface.clone(),
+                    data_type: data_type.to_string(),
+                    bytes,
+                });
+            }
+        }
+        let window = InputWindow {
+            sequence: Some(page.sequence as f64),
+            closed: Some(page.timestamp_ms as f64 / 1000.0),
+        };
+        Ok((metrics_list, window))
+    }
+}
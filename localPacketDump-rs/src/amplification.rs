@@ -0,0 +1,246 @@
+// UDP flood / amplification detection
+//
+// Reflection attacks show up as large inbound UDP from well-known amplifier ports
+// (NTP, DNS, SSDP) that no local device asked for. Outbound requests are remembered
+// for a short time; inbound bytes without a matching request are summed per remote
+// over each 1-second window and flagged when they exceed the threshold.
+// Later IPv4 fragments carry no UDP header, so only a datagram's first fragment is counted.
+// Spoofed floods can come from any address, so the remote_ip label is capped at
+// AMPLIFICATION_MAX_REMOTES addresses; further remotes are counted as "other".
+
+use crate::capture::{CapturedPacket, Transport};
+use crate::network;
+use dashmap::DashMap;
+use prometheus::{IntCounterVec, Registry};
+use serde::Serialize;
+use shared_http::HttpClient;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+// How long an outbound request makes replies from that remote port legitimate
+const REQUEST_MATCH_WINDOW: Duration = Duration::from_secs(30);
+// remote_ip label of suspects beyond AMPLIFICATION_MAX_REMOTES
+const OTHER_REMOTES: &str = "other";
+
+#[derive(Debug, Serialize)]
+struct AmplificationAlert {
+    remote_ip: String,
+    port: u16,
+    service: &'static str,
+    bytes: u64,
+    timestamp: String,
+}
+
+pub struct AmplificationDetector {
+    // Source ports treated as amplifiers (AMPLIFICATION_PORTS)
+    ports: Vec<u16>,
+    // Unsolicited inbound bytes per second that mark a remote as suspicious
+    threshold_bytes: u64,
+    // Optional webhook notified on suspicion (AMPLIFICATION_WEBHOOK_URL)
+    webhook_url: Option<String>,
+    // Minimum interval between webhook alerts for the same remote
+    alert_cooldown: Duration,
+    // Last outbound request per (local_ip, remote_ip, remote_port)
+    outbound_requests: DashMap<(String, String, u16), Instant>,
+    // Unsolicited inbound bytes in the current window per (remote_ip, source port)
+    window_unsolicited_bytes: DashMap<(String, u16), u64>,
+    // Last webhook alert per remote IP
+    last_alert: DashMap<String, Instant>,
+    // Number of windows in which a remote exceeded the threshold
+    suspect_counter: IntCounterVec,
+    // Remotes with their own remote_ip label, at most max_remotes
+    labeled_remotes: Mutex<HashSet<String>>,
+    max_remotes: usize,
+    http: Arc<HttpClient>,
+}
+
+impl AmplificationDetector {
+    pub fn new(registry: &Registry, http: Arc<HttpClient>) -> Self {
+        let ports: Vec<u16> = network::var("AMPLIFICATION_PORTS")
+            .unwrap_or_else(|_| "53,123,1900".to_string())
+            .split(',')
+            .filter_map(|port| match port.trim().parse::<u16>() {
+                Ok(port) => Some(port),
+                Err(e) => {
+                    error!("Failed to parse amplification port {}: {}", port, e);
+                    None
+                }
+            })
+            .collect();
+        let threshold_bytes = network::var("AMPLIFICATION_THRESHOLD_BYTES")
+            .ok()
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .unwrap_or(1_000_000);
+        let webhook_url = network::var("AMPLIFICATION_WEBHOOK_URL")
+            .ok()
+            .filter(|url| !url.is_empty());
+        let alert_cooldown = Duration::from_secs(
+            network::var("AMPLIFICATION_ALERT_COOLDOWN_SECS")
+                .ok()
+                .and_then(|v| v.trim().parse::<u64>().ok())
+                .unwrap_or(300),
+        );
+        let max_remotes = network::var("AMPLIFICATION_MAX_REMOTES")
+            .ok()
+            .and_then(|v| v.trim().parse::<usize>().ok())
+            .unwrap_or(1000);
+
+        info!(
+            "Amplification detection on ports {:?}, threshold {} bytes/s, webhook {}",
+            ports,
+            threshold_bytes,
+            webhook_url.as_deref().unwrap_or("disabled")
+        );
+
+        let suspect_counter = IntCounterVec::new(
+            prometheus::Opts::new(
+                "udp_amplification_suspect_total",
+                "Seconds in which unsolicited inbound UDP from an amplifier port exceeded the threshold",
+            )
+            .const_label("job", "localpacketdump"),
+            &["remote_ip", "service"],
+        )
+        .expect("failed to create udp_amplification_suspect_total counter");
+        registry
+            .register(Box::new(suspect_counter.clone()))
+            .expect("failed to register udp_amplification_suspect_total counter");
+
+        Self {
+            ports,
+            threshold_bytes,
+            webhook_url,
+            alert_cooldown,
+            outbound_requests: DashMap::new(),
+            window_unsolicited_bytes: DashMap::new(),
+            last_alert: DashMap::new(),
+            suspect_counter,
+            labeled_remotes: Mutex::new(HashSet::new()),
+            max_remotes,
+            http,
+        }
+    }
+
+    fn is_amplifier_port(&self, port: u16) -> bool {
+        self.ports.contains(&port)
+    }
+
+    // A local device's request to an amplifier port. Sampling must not skip these, or the
+    // replies would look unsolicited
+    pub fn is_request(&self, packet: &CapturedPacket, from_local: bool) -> bool {
+        match packet.transport {
+            Transport::Udp { dst_port, .. } => from_local && self.is_amplifier_port(dst_port),
+            _ => false,
+        }
+    }
+
+    // Local device sent a UDP datagram to a remote
+    pub fn record_outbound(&self, local_ip: &str, remote_ip: &str, remote_port: u16) {
+        if !self.is_amplifier_port(remote_port) {
+            return;
+        }
+        self.outbound_requests.insert(
+            (local_ip.to_string(), remote_ip.to_string(), remote_port),
+            Instant::now(),
+        );
+    }
+
+    // Remote sent a UDP datagram to a local device
+    pub fn record_inbound(&self, remote_ip: &str, remote_port: u16, local_ip: &str, bytes: u64) {
+        if !self.is_amplifier_port(remote_port) {
+            return;
+        }
+        let key = (local_ip.to_string(), remote_ip.to_string(), remote_port);
+        let solicited = self
+            .outbound_requests
+            .get(&key)
+            .map(|sent| sent.elapsed() < REQUEST_MATCH_WINDOW)
+            .unwrap_or(false);
+        if solicited {
+            return;
+        }
+        self.window_unsolicited_bytes
+            .entry((remote_ip.to_string(), remote_port))
+            .and_modify(|v| *v += bytes)
+            .or_insert(bytes);
+    }
+
+    // Evaluate the last 1-second window, then reset it
+    pub fn evaluate_and_reset(&self) {
+        let mut per_remote: HashMap<(String, u16), u64> = HashMap::new();
+        for entry in self.window_unsolicited_bytes.iter() {
+            per_remote.insert(entry.key().clone(), *entry.value());
+        }
+        self.window_unsolicited_bytes.clear();
+
+        for ((remote_ip, port), bytes) in per_remote {
+            if bytes < self.threshold_bytes {
+                continue;
+            }
+            let service = service_name(port);
+            warn!(
+                "Possible UDP amplification from {} ({}:{}): {} unsolicited bytes in the last second",
+                remote_ip, service, port, bytes
+            );
+            self.suspect_counter
+                .with_label_values(&[self.remote_label(&remote_ip), service])
+                .inc();
+            self.send_alert(remote_ip, port, bytes);
+        }
+
+        // Forget requests that can no longer match a reply, and alerts past their cooldown
+        self.outbound_requests
+            .retain(|_, sent| sent.elapsed() < REQUEST_MATCH_WINDOW);
+        self.last_alert
+            .retain(|_, sent| sent.elapsed() < self.alert_cooldown);
+    }
+
+    // The remote's own address while fewer than max_remotes have one, "other" after that
+    fn remote_label<'a>(&self, remote_ip: &'a str) -> &'a str {
+        let mut labeled = self.labeled_remotes.lock().unwrap();
+        if labeled.contains(remote_ip) {
+            return remote_ip;
+        }
+        if labeled.len() < self.max_remotes {
+            labeled.insert(remote_ip.to_string());
+            return remote_ip;
+        }
+        OTHER_REMOTES
+    }
+
+    fn send_alert(&self, remote_ip: String, port: u16, bytes: u64) {
+        let Some(url) = self.webhook_url.clone() else {
+            return;
+        };
+        if let Some(last) = self.last_alert.get(&remote_ip) {
+            if last.elapsed() < self.alert_cooldown {
+                return;
+            }
+        }
+        self.last_alert.insert(remote_ip.clone(), Instant::now());
+
+        let alert = AmplificationAlert {
+            remote_ip,
+            port,
+            service: service_name(port),
+            bytes,
+            timestamp: chrono::Utc::now().to_rfc3339(),
+        };
+        let http = self.http.clone();
+        tokio::spawn(async move {
+            if let Err(e) = http.send(http.post(&url).json(&alert)).await {
+                warn!("Failed to send amplification alert to {}: {}", url, e);
+            }
+        });
+    }
+}
+
+fn service_name(port: u16) -> &'static str {
+    match port {
+        53 => "dns",
+        123 => "ntp",
+        1900 => "ssdp",
+        _ => "other",
+    }
+}
@@ -0,0 +1,378 @@
+// EWMA baselines and traffic anomaly alerts (ANOMALY_DETECTION)
+//
+// Flags remotes whose per-second download or upload rate is far above their own history
+// without writing PromQL. Each remote keeps an exponentially weighted mean and variance
+// per direction with a half-life of ANOMALY_HALF_LIFE_SECS; a window's deviation score is
+// (rate - mean) / stddev. A remote is flagged once the score stays at or above
+// ANOMALY_THRESHOLD with at least ANOMALY_MIN_BYTES per second for ANOMALY_FOR_WINDOWS
+// windows, and cleared when the score drops below half the threshold. Remotes never seen
+// before start from the average baseline of the remotes already tracked, so a new
+// destination is flagged when it is far above what remotes usually carry rather than
+// merely above zero; the very first remotes start from zero and are covered by
+// ANOMALY_WARMUP_SECS.
+//
+// Only flagged remotes get per-remote series, keeping cardinality bounded. The webhook
+// (ANOMALY_WEBHOOK_URL) receives one JSON POST when an anomaly fires and one when it resolves.
+
+use crate::network;
+use dashmap::DashMap;
+use prometheus::{GaugeVec, IntCounterVec, IntGauge, Registry};
+use serde::Serialize;
+use shared_http::HttpClient;
+use shared_schema::LABEL_REMOTE_IP;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+const DIRECTIONS: [&str; 2] = ["download", "upload"];
+
+// Baselines of remotes idle this long have decayed to nothing and are dropped
+const IDLE_EVICT_HALF_LIVES: f64 = 8.0;
+
+#[derive(Debug, Clone, Default)]
+struct Baseline {
+    // Bytes per second
+    mean: f64,
+    variance: f64,
+    // Consecutive windows at or above the threshold
+    above: u32,
+    flagged: bool,
+    idle: Duration,
+}
+
+impl Baseline {
+    fn stddev(&self, floor: f64) -> f64 {
+        self.variance.sqrt().max(floor)
+    }
+
+    // Exponentially weighted mean and variance (West 1979, incremental form)
+    fn update(&mut self, rate: f64, alpha: f64) {
+        let diff = rate - self.mean;
+        let increment = alpha * diff;
+        self.mean += increment;
+        self.variance = (1.0 - alpha) * (self.variance + diff * increment);
+    }
+}
+
+// Starting baseline per direction for new remotes: the mean and variance averaged over the
+// tracked remotes
+fn population_baselines(baselines: &HashMap<(String, usize), Baseline>) -> [Baseline; 2] {
+    let mut seeds: [Baseline; 2] = Default::default();
+    let mut counts = [0usize; 2];
+    for ((_, direction), baseline) in baselines {
+        seeds[*direction].mean += baseline.mean;
+        seeds[*direction].variance += baseline.variance;
+        counts[*direction] += 1;
+    }
+    for (seed, count) in seeds.iter_mut().zip(counts) {
+        if count > 0 {
+            seed.mean /= count as f64;
+            seed.variance /= count as f64;
+        }
+    }
+    seeds
+}
+
+#[derive(Debug, Serialize)]
+struct AnomalyAlert {
+    // "firing" or "resolved"
+    state: &'static str,
+    remote_ip: String,
+    direction: &'static str,
+    bytes_per_second: f64,
+    baseline_bytes_per_second: f64,
+    stddev_bytes_per_second: f64,
+    score: f64,
+    timestamp: String,
+}
+
+pub struct AnomalyDetector {
+    half_life: Duration,
+    threshold: f64,
+    // Rates below this are never flagged, whatever their score (ANOMALY_MIN_BYTES)
+    min_bytes: f64,
+    // Lower bound of the stddev, so a flat baseline does not turn jitter into huge scores
+    min_stddev: f64,
+    for_windows: u32,
+    // No remote is flagged until the detector has seen this much traffic history
+    warmup: Duration,
+    max_remotes: usize,
+    webhook_url: Option<String>,
+    started: Instant,
+    // (download, upload) bytes per remote in the current window
+    window: DashMap<String, (u64, u64)>,
+    // (remote_ip, direction index) -> baseline
+    baselines: Mutex<HashMap<(String, usize), Baseline>>,
+    flagged: GaugeVec,
+    score: GaugeVec,
+    baseline_bytes: GaugeVec,
+    max_score: GaugeVec,
+    alerts: IntCounterVec,
+    tracked: IntGauge,
+    http: Arc<HttpClient>,
+}
+
+impl AnomalyDetector {
+    // None unless ANOMALY_DETECTION is enabled
+    pub fn from_env(registry: &Registry, http: Arc<HttpClient>) -> Option<Self> {
+        let enabled = matches!(
+            network::var("ANOMALY_DETECTION")
+                .map(|v| v.trim().to_ascii_lowercase())
+                .as_deref(),
+            Ok("1") | Ok("true")
+        );
+        if !enabled {
+            return None;
+        }
+        let number = |name: &str, default: f64| {
+            network::var(name)
+                .ok()
+                .and_then(|v| v.trim().parse::<f64>().ok())
+                .filter(|v| v.is_finite() && *v >= 0.0)
+                .unwrap_or(default)
+        };
+        let half_life = Duration::from_secs_f64(number("ANOMALY_HALF_LIFE_SECS", 3600.0).max(1.0));
+        let threshold = number("ANOMALY_THRESHOLD", 6.0).max(0.1);
+        let min_bytes = number("ANOMALY_MIN_BYTES", 1_250_000.0);
+        let min_stddev = number("ANOMALY_MIN_STDDEV_BYTES", 12_500.0).max(1.0);
+        let for_windows = (number("ANOMALY_FOR_WINDOWS", 3.0) as u32).max(1);
+        let warmup = Duration::from_secs_f64(number("ANOMALY_WARMUP_SECS", 600.0));
+        let max_remotes = number("ANOMALY_MAX_REMOTES", 10000.0) as usize;
+        let webhook_url = network::var("ANOMALY_WEBHOOK_URL")
+            .ok()
+            .filter(|url| !url.is_empty());
+        info!(
+            "Anomaly detection: half-life {:?}, score >= {} and >= {} bytes/s for {} windows, webhook {}",
+            half_life,
+            threshold,
+            min_bytes,
+            for_windows,
+            webhook_url.as_deref().unwrap_or("disabled")
+        );
+
+        let gauge = |name: &str, help: &str, labels: &[&str]| {
+            let gauge = GaugeVec::new(
+                prometheus::Opts::new(name, help).const_label("job", "localpacketdump"),
+                labels,
+            )
+            .unwrap_or_else(|e| panic!("failed to create {} gauge: {}", name, e));
+            registry
+                .register(Box::new(gauge.clone()))
+                .unwrap_or_else(|e| panic!("failed to register {} gauge: {}", name, e));
+            gauge
+        };
+        let flagged = gauge(
+            "traffic_anomaly",
+            "1 while a remote's rate is flagged as anomalous against its EWMA baseline",
+            &[LABEL_REMOTE_IP, "direction"],
+        );
+        let score = gauge(
+            "traffic_anomaly_score",
+            "Deviation score (standard deviations above the EWMA baseline) of flagged remotes",
+            &[LABEL_REMOTE_IP, "direction"],
+        );
+        let baseline_bytes = gauge(
+            "traffic_anomaly_baseline_bytes",
+            "EWMA baseline in bytes per second of flagged remotes",
+            &[LABEL_REMOTE_IP, "direction"],
+        );
+        let max_score = gauge(
+            "traffic_anomaly_max_score",
+            "Highest deviation score over all remotes in the last window",
+            &["direction"],
+        );
+        let alerts = IntCounterVec::new(
+            prometheus::Opts::new(
+                "traffic_anomaly_alerts_total",
+                "Anomalies that started firing",
+            )
+            .const_label("job", "localpacketdump"),
+            &["direction"],
+        )
+        .expect("failed to create traffic_anomaly_alerts_total counter");
+        registry
+            .register(Box::new(alerts.clone()))
+            .expect("failed to register traffic_anomaly_alerts_total counter");
+        let tracked = IntGauge::with_opts(
+            prometheus::Opts::new(
+                "traffic_anomaly_tracked_remotes",
+                "Remotes with an EWMA baseline",
+            )
+            .const_label("job", "localpacketdump"),
+        )
+        .expect("failed to create traffic_anomaly_tracked_remotes gauge");
+        registry
+            .register(Box::new(tracked.clone()))
+            .expect("failed to register traffic_anomaly_tracked_remotes gauge");
+
+        Some(Self {
+            half_life,
+            threshold,
+            min_bytes,
+            min_stddev,
+            for_windows,
+            warmup,
+            max_remotes,
+            webhook_url,
+            started: Instant::now(),
+            window: DashMap::new(),
+            baselines: Mutex::new(HashMap::new()),
+            flagged,
+            score,
+            baseline_bytes,
+            max_score,
+            alerts,
+            tracked,
+            http,
+        })
+    }
+
+    pub fn record(&self, remote_ip: &str, bytes: u64, download: bool) {
+        let mut entry = self.window.entry(remote_ip.to_string()).or_default();
+        if download {
+            entry.0 += bytes;
+        } else {
+            entry.1 += bytes;
+        }
+    }
+
+    // Score the closed window against the baselines, then fold it into them
+    pub fn evaluate_and_reset(&self, duration: Duration) {
+        let window: HashMap<String, (u64, u64)> = self
+            .window
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        self.window.clear();
+        let seconds = duration.as_secs_f64();
+        if seconds <= 0.0 {
+            return;
+        }
+        let alpha = 1.0 - (-seconds * std::f64::consts::LN_2 / self.half_life.as_secs_f64()).exp();
+        let warmed_up = self.started.elapsed() >= self.warmup;
+        let evict_after = self.half_life.mul_f64(IDLE_EVICT_HALF_LIVES);
+
+        let mut baselines = self.baselines.lock().unwrap();
+        let seeds = population_baselines(&baselines);
+        for remote_ip in window.keys() {
+            for (direction, seed) in seeds.iter().enumerate() {
+                let key = (remote_ip.clone(), direction);
+                if baselines.len() >= self.max_remotes * DIRECTIONS.len()
+                    && !baselines.contains_key(&key)
+                {
+                    continue;
+                }
+                baselines.entry(key).or_insert_with(|| seed.clone());
+            }
+        }
+
+        let mut max_score = [0.0f64; 2];
+        let mut evicted = Vec::new();
+        for ((remote_ip, direction), baseline) in baselines.iter_mut() {
+            let bytes = window
+                .get(remote_ip)
+                .map(|(download, upload)| if *direction == 0 { *download } else { *upload })
+                .unwrap_or(0);
+            let rate = bytes as f64 / seconds;
+            let stddev = baseline.stddev(self.min_stddev);
+            let score = (rate - baseline.mean) / stddev;
+            max_score[*direction] = max_score[*direction].max(score);
+            let labels = [remote_ip.as_str(), DIRECTIONS[*direction]];
+
+            if warmed_up && score >= self.threshold && rate >= self.min_bytes {
+                baseline.above += 1;
+            } else {
+                baseline.above = 0;
+            }
+            if !baseline.flagged && baseline.above >= self.for_windows {
+                baseline.flagged = true;
+                warn!(
+                    "Traffic anomaly: {} {} at {:.0} bytes/s, baseline {:.0} bytes/s (score {:.1})",
+                    remote_ip, DIRECTIONS[*direction], rate, baseline.mean, score
+                );
+                self.alerts
+                    .with_label_values(&[DIRECTIONS[*direction]])
+                    .inc();
+                self.send_alert(
+                    "firing", remote_ip, *direction, rate, baseline, stddev, score,
+                );
+            } else if baseline.flagged && score < self.threshold / 2.0 {
+                baseline.flagged = false;
+                info!(
+                    "Traffic anomaly resolved: {} {} at {:.0} bytes/s",
+                    remote_ip, DIRECTIONS[*direction], rate
+                );
+                self.send_alert(
+                    "resolved", remote_ip, *direction, rate, baseline, stddev, score,
+                );
+                let _ = self.flagged.remove_label_values(&labels);
+                let _ = self.score.remove_label_values(&labels);
+                let _ = self.baseline_bytes.remove_label_values(&labels);
+            }
+            if baseline.flagged {
+                self.flagged.with_label_values(&labels).set(1.0);
+                self.score.with_label_values(&labels).set(score);
+                self.baseline_bytes
+                    .with_label_values(&labels)
+                    .set(baseline.mean);
+            }
+
+            baseline.update(rate, alpha);
+            if bytes > 0 {
+                baseline.idle = Duration::ZERO;
+            } else {
+                baseline.idle += duration;
+                if baseline.idle >= evict_after && !baseline.flagged {
+                    evicted.push((remote_ip.clone(), *direction));
+                }
+            }
+        }
+        for key in evicted {
+            baselines.remove(&key);
+        }
+        for (direction, score) in max_score.iter().enumerate() {
+            self.max_score
+                .with_label_values(&[DIRECTIONS[direction]])
+                .set(*score);
+        }
+        self.tracked.set(
+            baselines
+                .keys()
+                .filter(|(_, direction)| *direction == 0)
+                .count() as i64,
+        );
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn send_alert(
+        &self,
+        state: &'static str,
+        remote_ip: &str,
+        direction: usize,
+        rate: f64,
+        baseline: &Baseline,
+        stddev: f64,
+        score: f64,
+    ) {
+        let Some(url) = self.webhook_url.clone() else {
+            return;
+        };
+        let alert = AnomalyAlert {
+            state,
+            remote_ip: remote_ip.to_string(),
+            direction: DIRECTIONS[direction],
+            bytes_per_second: rate,
+            baseline_bytes_per_second: baseline.mean,
+            stddev_bytes_per_second: stddev,
+            score,
+            timestamp: chrono::Utc::now().to_rfc3339(),
+        };
+        let http = self.http.clone();
+        tokio::spawn(async move {
+            if let Err(e) = http.send(http.post(&url).json(&alert)).await {
+                warn!("Failed to send anomaly alert to {}: {}", url, e);
+            }
+        });
+    }
+}
@@ -0,0 +1,92 @@
+// Sub-second burst detection
+//
+// A microburst can saturate the link for a few hundred milliseconds and still average
+// out over a one-second window. Bytes are also counted in 100ms slots per interface and
+// direction, and the busiest slot of each window is exported as a per-second rate.
+
+use dashmap::DashMap;
+use prometheus::{IntGaugeVec, Registry};
+use std::time::{Duration, Instant};
+
+const SLOT: Duration = Duration::from_millis(100);
+const SLOTS_PER_SECOND: u64 = 1000 / SLOT.as_millis() as u64;
+
+#[derive(Default)]
+struct SlotState {
+    // Slot the bytes below belong to
+    slot: u64,
+    bytes: u64,
+    // Busiest finished slot in the current window
+    max_bytes: u64,
+}
+
+pub struct BurstTracker {
+    started: Instant,
+    // (interface, direction) -> slot state
+    slots: DashMap<(String, &'static str), SlotState>,
+    max_rate_gauge: IntGaugeVec,
+}
+
+impl BurstTracker {
+    pub fn new(registry: &Registry) -> Self {
+        let max_rate_gauge = IntGaugeVec::new(
+            prometheus::Opts::new(
+                "max_subsecond_rate",
+                "Highest 100ms rate within the last second, in bytes per second",
+            )
+            .const_label("job", "localpacketdump"),
+            &["interface", "direction"],
+        )
+        .expect("failed to create max_subsecond_rate gauge");
+        registry
+            .register(Box::new(max_rate_gauge.clone()))
+            .expect("failed to register max_subsecond_rate gauge");
+
+        Self {
+            started: Instant::now(),
+            slots: DashMap::new(),
+            max_rate_gauge,
+        }
+    }
+
+    fn current_slot(&self) -> u64 {
+        (self.started.elapsed().as_millis() / SLOT.as_millis()) as u64
+    }
+
+    pub fn record(&self, interface: &str, download: bool, bytes: u64) {
+        let direction = if download { "download" } else { "upload" };
+        let slot = self.current_slot();
+        let mut state = self
+            .slots
+            .entry((interface.to_string(), direction))
+            .or_default();
+        if state.slot != slot {
+            state.max_bytes = state.max_bytes.max(state.bytes);
+            state.slot = slot;
+            state.bytes = 0;
+        }
+        state.bytes += bytes;
+    }
+
+    // Publish the busiest slot of the window; interfaces seen before keep reporting 0
+    pub fn publish_and_reset(&self) {
+        let slot = self.current_slot();
+        for mut entry in self.slots.iter_mut() {
+            let (interface, direction) = entry.key().clone();
+            let state = entry.value_mut();
+            // The slot still in progress is counted by the next window
+            let max_bytes = if state.slot == slot {
+                state.max_bytes
+            } else {
+                state.max_bytes.max(state.bytes)
+            };
+            self.max_rate_gauge
+                .with_label_values(&[&interface, direction])
+                .set((max_bytes * SLOTS_PER_SECOND) as i64);
+            state.max_bytes = 0;
+            if state.slot != slot {
+                state.bytes = 0;
+            }
+        }
+    }
+}
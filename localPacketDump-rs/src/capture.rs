@@ -0,0 +1,538 @@
+// Frame parsing on the capture thread
+//
+// The capture thread only decodes the headers it needs into a small owned record and
+// hands it over a bounded channel; label lookups and accounting happen on the consumer
+// thread, so a slow consumer shows up as dropped packets instead of a stalled socket.
+
+use crate::fingerprint::SynSignature;
+use crate::transition::Transition;
+use crate::tunnel::{Inner, Tunnels};
+use pnet::packet::ethernet::{EtherType, EtherTypes, EthernetPacket};
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::ipv4::{Ipv4Flags, Ipv4Packet};
+use pnet::packet::ipv6::Ipv6Packet;
+use pnet::packet::tcp::{TcpFlags, TcpPacket};
+use pnet::packet::udp::UdpPacket;
+use pnet::packet::vlan::VlanPacket;
+use pnet::packet::Packet;
+use std::net::IpAddr;
+
+// Transport header fields used by flow tracking, amplification detection, TCP
+// connection quality and multicast membership tracking
+#[derive(Debug, Clone)]
+pub enum Transport {
+    Tcp {
+        src_port: u16,
+        dst_port: u16,
+        // Raw TCP flags (TcpFlags)
+        flags: u8,
+        seq: u32,
+        // Segment payload length in bytes
+        payload_len: u32,
+    },
+    Udp {
+        src_port: u16,
+        dst_port: u16,
+    },
+    // IGMP / MLD membership report or leave
+    Membership(Vec<GroupChange>),
+    Other,
+}
+
+// One group in a membership report
+#[derive(Debug, Clone, Copy)]
+pub struct GroupChange {
+    pub group: IpAddr,
+    // false for a leave (IGMPv2 leave, MLDv1 done, or an IGMPv3 / MLDv2 record
+    // switching to include mode with no sources)
+    pub joined: bool,
+}
+
+// 802.1Q tags of a frame; `inner` is only set for QinQ
+#[derive(Debug, Clone, Copy)]
+pub struct VlanTags {
+    pub outer: u16,
+    pub inner: Option<u16>,
+}
+
+impl VlanTags {
+    // Value of the vlan label: "100", or "100.20" for QinQ
+    pub fn label(&self) -> String {
+        match self.inner {
+            Some(inner) => format!("{}.{}", self.outer, inner),
+            None => self.outer.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CapturedPacket {
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    pub protocol: IpNextHeaderProtocol,
+    pub transport: Transport,
+    pub vlan: Option<VlanTags>,
+    // DSCP of the accounted IP header (the inner one for tunnels)
+    pub dscp: u8,
+    // Header fields of a connection's first SYN, for OS fingerprinting
+    pub syn: Option<SynSignature>,
+    // Length of the IP packet
+    pub bytes: u64,
+    // Packets this one stands for; more than 1 when sampled, with bytes scaled alike
+    pub packets: u64,
+}
+
+// Nested tunnels decapsulated at most
+const MAX_TUNNEL_DEPTH: u8 = 2;
+
+// Header decoding state shared by nested tunnel layers
+struct Decoder<'a> {
+    transition: &'a Transition,
+    tunnels: &'a Tunnels,
+}
+
+pub fn parse_frame(
+    frame: &[u8],
+    transition: &Transition,
+    tunnels: &Tunnels,
+) -> Option<CapturedPacket> {
+    let eth = EthernetPacket::new(frame)?;
+    let (ethertype, payload, vlan) = strip_vlan_tags(eth.get_ethertype(), eth.payload())?;
+    let decoder = Decoder {
+        transition,
+        tunnels,
+    };
+    let mut packet = decoder.parse_ip(ethertype, payload, 0)?;
+    if let Some(syn) = &mut packet.syn {
+        syn.mac.get_or_insert(eth.get_source());
+    }
+    // The outer tag is the one that identifies the segment on this interface
+    packet.vlan = vlan;
+    Some(packet)
+}
+
+// Skip up to two VLAN tags (802.1Q, or 802.1ad / legacy 0x9100 QinQ)
+fn strip_vlan_tags(
+    mut ethertype: EtherType,
+    mut payload: &[u8],
+) -> Option<(EtherType, &[u8], Option<VlanTags>)> {
+    let mut tags: Vec<u16> = Vec::new();
+    while tags.len() < 2
+        && matches!(
+            ethertype,
+            EtherTypes::Vlan | EtherTypes::PBridge | EtherTypes::QinQ
+        )
+    {
+        let vlan = VlanPacket::new(payload)?;
+        tags.push(vlan.get_vlan_identifier());
+        ethertype = vlan.get_ethertype();
+        payload = &payload[VlanPacket::minimum_packet_size()..];
+    }
+    let vlan = tags.first().map(|outer| VlanTags {
+        outer: *outer,
+        inner: tags.get(1).copied(),
+    });
+    Some((ethertype, payload, vlan))
+}
+
+impl Decoder<'_> {
+    fn parse_ip(&self, ethertype: EtherType, payload: &[u8], depth: u8) -> Option<CapturedPacket> {
+        match ethertype {
+            EtherTypes::Ipv4 => self.parse_ipv4(payload, depth),
+            EtherTypes::Ipv6 => {
+                let ipv6 = Ipv6Packet::new(payload)?;
+                // DS-Lite softwire: account the inner IPv4 packet
+                if ipv6.get_next_header() == IpNextHeaderProtocols::Ipv4
+                    && self.transition.dslite_decapsulate
+                {
+                    self.transition.count_dslite();
+                    return self.parse_ipv4(ipv6.payload(), depth);
+                }
+                // MLD always follows a Hop-by-Hop header carrying the router alert
+                let (protocol, payload) = skip_hop_by_hop(ipv6.get_next_header(), ipv6.payload());
+                if let Some(inner) = self.decapsulate(protocol, payload, depth) {
+                    return Some(inner);
+                }
+                Some(CapturedPacket {
+                    src: IpAddr::V6(ipv6.get_source()),
+                    dst: IpAddr::V6(ipv6.get_destination()),
+                    protocol,
+                    transport: parse_transport(protocol, payload),
+                    bytes: ipv6.packet().len() as u64,
+                    packets: 1,
+                    vlan: None,
+                    dscp: ipv6.get_traffic_class() >> 2,
+                    syn: syn_signature(protocol, payload, 6, ipv6.get_hop_limit(), false),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn parse_ipv4(&self, data: &[u8], depth: u8) -> Option<CapturedPacket> {
+        let ipv4 = Ipv4Packet::new(data)?;
+        let protocol = ipv4.get_next_level_protocol();
+        // Only the first fragment starts with the transport header; reading one from a later
+        // fragment would turn payload bytes into ports and flags
+        let first_fragment = ipv4.get_fragment_offset() == 0;
+        if first_fragment {
+            if let Some(inner) = self.decapsulate(protocol, ipv4.payload(), depth) {
+                return Some(inner);
+            }
+        }
+        Some(CapturedPacket {
+            src: IpAddr::V4(ipv4.get_source()),
+            dst: IpAddr::V4(ipv4.get_destination()),
+            protocol,
+            transport: if first_fragment {
+                parse_transport(protocol, ipv4.payload())
+            } else {
+                Transport::Other
+            },
+            bytes: ipv4.packet().len() as u64,
+            packets: 1,
+            vlan: None,
+            dscp: ipv4.get_dscp(),
+            syn: first_fragment
+                .then(|| {
+                    syn_signature(
+                        protocol,
+                        ipv4.payload(),
+                        4,
+                        ipv4.get_ttl(),
+                        ipv4.get_flags() & Ipv4Flags::DontFragment != 0,
+                    )
+                })
+                .flatten(),
+        })
+    }
+
+    // Inner packet of a GRE / VXLAN tunnel; None accounts the outer packet instead
+    fn decapsulate(
+        &self,
+        protocol: IpNextHeaderProtocol,
+        payload: &[u8],
+        depth: u8,
+    ) -> Option<CapturedPacket> {
+        if depth >= MAX_TUNNEL_DEPTH {
+            return None;
+        }
+        match self.tunnels.decapsulate(protocol, payload)? {
+            Inner::Ip(ethertype, inner) => self.parse_ip(ethertype, inner, depth + 1),
+            Inner::Ethernet(frame) => {
+                let eth = EthernetPacket::new(frame)?;
+                let (ethertype, inner, _) = strip_vlan_tags(eth.get_ethertype(), eth.payload())?;
+                let mut packet = self.parse_ip(ethertype, inner, depth + 1)?;
+                // The outer frame comes from the tunnel endpoint, not the device
+                if let Some(syn) = &mut packet.syn {
+                    syn.mac.get_or_insert(eth.get_source());
+                }
+                Some(packet)
+            }
+        }
+    }
+}
+
+// Only a SYN without ACK shows the sender's defaults
+fn syn_signature(
+    protocol: IpNextHeaderProtocol,
+    payload: &[u8],
+    ip_version: u8,
+    ttl: u8,
+    df: bool,
+) -> Option<SynSignature> {
+    if protocol != IpNextHeaderProtocols::Tcp {
+        return None;
+    }
+    let tcp = TcpPacket::new(payload)?;
+    (tcp.get_flags() & (TcpFlags::SYN | TcpFlags::ACK) == TcpFlags::SYN)
+        .then(|| SynSignature::parse(ip_version, ttl, df, &tcp))
+}
+
+fn parse_transport(protocol: IpNextHeaderProtocol, payload: &[u8]) -> Transport {
+    match protocol {
+        IpNextHeaderProtocols::Tcp => match TcpPacket::new(payload) {
+            Some(tcp) => Transport::Tcp {
+                src_port: tcp.get_source(),
+                dst_port: tcp.get_destination(),
+                flags: tcp.get_flags(),
+                seq: tcp.get_sequence(),
+                payload_len: tcp.payload().len() as u32,
+            },
+            None => Transport::Other,
+        },
+        IpNextHeaderProtocols::Udp => match UdpPacket::new(payload) {
+            Some(udp) => Transport::Udp {
+                src_port: udp.get_source(),
+                dst_port: udp.get_destination(),
+            },
+            None => Transport::Other,
+        },
+        IpNextHeaderProtocols::Igmp => parse_igmp(payload).unwrap_or(Transport::Other),
+        IpNextHeaderProtocols::Icmpv6 => parse_mld(payload).unwrap_or(Transport::Other),
+        _ => Transport::Other,
+    }
+}
+
+fn skip_hop_by_hop(
+    protocol: IpNextHeaderProtocol,
+    payload: &[u8],
+) -> (IpNextHeaderProtocol, &[u8]) {
+    if protocol != IpNextHeaderProtocols::Hopopt || payload.len() < 2 {
+        return (protocol, payload);
+    }
+    let len = (payload[1] as usize + 1) * 8;
+    match payload.get(len..) {
+        Some(rest) => (IpNextHeaderProtocol(payload[0]), rest),
+        None => (protocol, payload),
+    }
+}
+
+// IGMPv1/v2 reports, IGMPv2 leaves and IGMPv3 reports; queries are ignored
+fn parse_igmp(payload: &[u8]) -> Option<Transport> {
+    let group_at = |offset: usize| -> Option<IpAddr> {
+        let octets: [u8; 4] = payload.get(offset..offset + 4)?.try_into().ok()?;
+        Some(IpAddr::from(octets))
+    };
+    let changes = match *payload.first()? {
+        0x12 | 0x16 => vec![GroupChange {
+            group: group_at(4)?,
+            joined: true,
+        }],
+        0x17 => vec![GroupChange {
+            group: group_at(4)?,
+            joined: false,
+        }],
+        0x22 => parse_v3_records(payload, 4, group_at),
+        _ => return None,
+    };
+    (!changes.is_empty()).then_some(Transport::Membership(changes))
+}
+
+// MLDv1 reports and dones, and MLDv2 reports; queries and other ICMPv6 are ignored
+fn parse_mld(payload: &[u8]) -> Option<Transport> {
+    let group_at = |offset: usize| -> Option<IpAddr> {
+        let octets: [u8; 16] = payload.get(offset..offset + 16)?.try_into().ok()?;
+        Some(IpAddr::from(octets))
+    };
+    let changes = match *payload.first()? {
+        131 => vec![GroupChange {
+            group: group_at(8)?,
+            joined: true,
+        }],
+        132 => vec![GroupChange {
+            group: group_at(8)?,
+            joined: false,
+        }],
+        143 => parse_v3_records(payload, 16, group_at),
+        _ => return None,
+    };
+    (!changes.is_empty()).then_some(Transport::Membership(changes))
+}
+
+// Group records of an IGMPv3 / MLDv2 report, which share a layout apart from the
+// address size: type, aux length (in 32-bit words), source count, group, sources, aux
+fn parse_v3_records(
+    payload: &[u8],
+    address_len: usize,
+    group_at: impl Fn(usize) -> Option<IpAddr>,
+) -> Vec<GroupChange> {
+    let mut changes = Vec::new();
+    let Some(count) = payload.get(6..8) else {
+        return changes;
+    };
+    let count = u16::from_be_bytes([count[0], count[1]]);
+    let mut offset = 8;
+    for _ in 0..count {
+        let Some(header) = payload.get(offset..offset + 4) else {
+            break;
+        };
+        let record_type = header[0];
+        let aux_len = header[1] as usize * 4;
+        let sources = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let Some(group) = group_at(offset + 4) else {
+            break;
+        };
+        let joined = match record_type {
+            // MODE_IS_EXCLUDE / CHANGE_TO_EXCLUDE_MODE: any-source join
+            2 | 4 => Some(true),
+            // MODE_IS_INCLUDE / CHANGE_TO_INCLUDE_MODE / ALLOW_NEW_SOURCES: a join
+            // for the listed sources, a leave when the list is empty
+            1 | 3 | 5 => Some(sources > 0),
+            // BLOCK_OLD_SOURCES leaves the rest of the membership as it was
+            _ => None,
+        };
+        if let Some(joined) = joined {
+            changes.push(GroupChange { group, joined });
+        }
+        offset += 4 + address_len + sources * address_len + aux_len;
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // VLAN tag with priority 5, so the identifier has to be masked out of the TCI
+    fn tag(vid: u16, next: EtherType) -> Vec<u8> {
+        let tci = (5 << 13) | vid;
+        [tci.to_be_bytes(), next.0.to_be_bytes()].concat()
+    }
+
+    fn strip(ethertype: EtherType, payload: &[u8]) -> Option<(EtherType, Vec<u8>, Option<String>)> {
+        let (ethertype, rest, vlan) = strip_vlan_tags(ethertype, payload)?;
+        Some((ethertype, rest.to_vec(), vlan.map(|tags| tags.label())))
+    }
+
+    #[test]
+    fn untagged_frame() {
+        let ip = [0x45, 0x00];
+        assert_eq!(
+            strip(EtherTypes::Ipv4, &ip),
+            Some((EtherTypes::Ipv4, ip.to_vec(), None))
+        );
+    }
+
+    #[test]
+    fn single_tag() {
+        let payload = [tag(100, EtherTypes::Ipv6), vec![0x60]].concat();
+        assert_eq!(
+            strip(EtherTypes::Vlan, &payload),
+            Some((EtherTypes::Ipv6, vec![0x60], Some("100".to_string())))
+        );
+    }
+
+    #[test]
+    fn qinq_outer_tag_first() {
+        let payload = [
+            tag(100, EtherTypes::Vlan),
+            tag(20, EtherTypes::Ipv4),
+            vec![0x45],
+        ]
+        .concat();
+        let expected = Some((EtherTypes::Ipv4, vec![0x45], Some("100.20".to_string())));
+        // 802.1ad and the legacy 0x9100 outer tag
+        assert_eq!(strip(EtherTypes::PBridge, &payload), expected);
+        assert_eq!(strip(EtherTypes::QinQ, &payload), expected);
+    }
+
+    #[test]
+    fn third_tag_left_in_place() {
+        let third = tag(3, EtherTypes::Ipv4);
+        let payload = [
+            tag(1, EtherTypes::Vlan),
+            tag(2, EtherTypes::Vlan),
+            third.clone(),
+        ]
+        .concat();
+        assert_eq!(
+            strip(EtherTypes::PBridge, &payload),
+            Some((EtherTypes::Vlan, third, Some("1.2".to_string())))
+        );
+    }
+
+    #[test]
+    fn truncated_tags() {
+        assert_eq!(strip(EtherTypes::Vlan, &[0x00, 0x64, 0x08]), None);
+        let inner_cut = [tag(100, EtherTypes::Vlan), vec![0x00, 0x14]].concat();
+        assert_eq!(strip(EtherTypes::PBridge, &inner_cut), None);
+        // A tag with nothing after it leaves an empty payload
+        assert_eq!(
+            strip(EtherTypes::Vlan, &tag(7, EtherTypes::Ipv4)),
+            Some((EtherTypes::Ipv4, Vec::new(), Some("7".to_string())))
+        );
+    }
+
+    fn memberships(transport: Option<Transport>) -> Vec<(IpAddr, bool)> {
+        match transport {
+            Some(Transport::Membership(changes)) => changes
+                .iter()
+                .map(|change| (change.group, change.joined))
+                .collect(),
+            other => panic!("not a membership report: {:?}", other),
+        }
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn igmp_v1_v2() {
+        let group = [239, 1, 2, 3];
+        let message = |kind: u8| [&[kind, 0, 0, 0][..], &group].concat();
+        assert_eq!(
+            memberships(parse_igmp(&message(0x12))),
+            [(ip("239.1.2.3"), true)]
+        );
+        assert_eq!(
+            memberships(parse_igmp(&message(0x16))),
+            [(ip("239.1.2.3"), true)]
+        );
+        assert_eq!(
+            memberships(parse_igmp(&message(0x17))),
+            [(ip("239.1.2.3"), false)]
+        );
+        // Queries are not membership changes
+        assert!(parse_igmp(&message(0x11)).is_none());
+        assert!(parse_igmp(&message(0x16)[..6]).is_none());
+    }
+
+    // IGMPv3 group record: type, aux length in words, source count, group, sources, aux data
+    fn record(kind: u8, group: [u8; 4], sources: &[[u8; 4]], aux_words: u8) -> Vec<u8> {
+        let mut record = vec![kind, aux_words];
+        record.extend((sources.len() as u16).to_be_bytes());
+        record.extend(group);
+        record.extend(sources.concat());
+        record.extend(vec![0xee; aux_words as usize * 4]);
+        record
+    }
+
+    fn v3_report(count: u16, records: &[Vec<u8>]) -> Vec<u8> {
+        let mut report = vec![0x22, 0, 0, 0, 0, 0];
+        report.extend(count.to_be_bytes());
+        report.extend(records.concat());
+        report
+    }
+
+    #[test]
+    fn igmp_v3_records_with_sources() {
+        let records = [
+            // MODE_IS_INCLUDE with sources and aux data: join
+            record(1, [232, 1, 1, 1], &[[10, 0, 0, 1], [10, 0, 0, 2]], 1),
+            // CHANGE_TO_INCLUDE_MODE with no sources: leave
+            record(3, [239, 2, 2, 2], &[], 0),
+            // BLOCK_OLD_SOURCES: skipped, but its sources still have to be stepped over
+            record(6, [239, 3, 3, 3], &[[10, 0, 0, 3]], 0),
+            // CHANGE_TO_EXCLUDE_MODE: any-source join
+            record(4, [239, 4, 4, 4], &[], 2),
+        ];
+        assert_eq!(
+            memberships(parse_igmp(&v3_report(4, &records))),
+            [
+                (ip("232.1.1.1"), true),
+                (ip("239.2.2.2"), false),
+                (ip("239.4.4.4"), true),
+            ]
+        );
+    }
+
+    #[test]
+    fn igmp_v3_truncated() {
+        let records = [
+            record(2, [239, 1, 1, 1], &[], 0),
+            record(2, [239, 2, 2, 2], &[], 0),
+        ];
+        // More records announced than present: the complete ones are kept
+        assert_eq!(
+            memberships(parse_igmp(&v3_report(3, &records))),
+            [(ip("239.1.1.1"), true), (ip("239.2.2.2"), true)]
+        );
+        // A record cut inside its group address
+        let cut = v3_report(1, &records[..1]);
+        assert!(parse_igmp(&cut[..cut.len() - 1]).is_none());
+        // No record count at all
+        assert!(parse_igmp(&[0x22, 0, 0, 0, 0, 0]).is_none());
+    }
+}
@@ -0,0 +1,233 @@
+// LAN / WAN cross-check of the same flows
+//
+// The byte gauges come from the LAN side only, so traffic the router drops (shaping,
+// firewall) or originates itself never shows up in them. CAPTURE_POINTS tags capture
+// interfaces with roles, e.g. "lan=eth2,wan0=eth0,wan1=eth1"; every WAN point is captured
+// as well and its bytes are compared per flow with the LAN packets that the status mapping
+// sends over that WAN (wan0 / wan1). NAT rewrites the local side, so a flow is keyed by its
+// remote address, remote port and protocol. Bytes seen on one side only end up in
+// capture_discrepancy_bytes, labeled with the capture point's interface.
+
+use crate::capture::{CapturedPacket, Transport};
+use crate::network;
+use dashmap::DashMap;
+use prometheus::{IntGaugeVec, Registry};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use tracing::{error, info};
+
+// Flows tracked per window and side at most; further flows are not compared
+const MAX_FLOWS_PER_WINDOW: usize = 65536;
+
+// (WAN role, remote address, remote port, IP protocol)
+type FlowKey = (String, IpAddr, u16, u8);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Lan,
+    Wan,
+}
+
+// A WAN capture point
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturePoint {
+    // wan0, wan1, ... as in the status mappings
+    pub role: String,
+    pub interface_name: String,
+}
+
+// (role, interface) pairs of CAPTURE_POINTS; roles are "lan" or start with "wan"
+fn points_from_env() -> Vec<(String, String)> {
+    network::var("CAPTURE_POINTS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| {
+            let Some((role, interface)) = entry.split_once('=') else {
+                error!(
+                    "Failed to parse capture point {}: expected ROLE=INTERFACE",
+                    entry
+                );
+                return None;
+            };
+            let (role, interface) = (role.trim(), interface.trim());
+            if role != "lan" && !role.starts_with("wan") {
+                error!("Unknown capture point role {}: expected lan or wanN", role);
+                return None;
+            }
+            Some((role.to_string(), interface.to_string()))
+        })
+        .collect()
+}
+
+// Interface of the lan capture point, which replaces INTERFACE_NAME when set
+pub fn lan_interface() -> Option<String> {
+    points_from_env()
+        .into_iter()
+        .find(|(role, _)| role == "lan")
+        .map(|(_, interface)| interface)
+}
+
+pub struct CrossCheck {
+    points: Vec<CapturePoint>,
+    // (download, upload) bytes per flow in the current window
+    lan: DashMap<FlowKey, (u64, u64)>,
+    wan: DashMap<FlowKey, (u64, u64)>,
+    observed_gauge: IntGaugeVec,
+    discrepancy_gauge: IntGaugeVec,
+}
+
+impl CrossCheck {
+    // None without any WAN capture point
+    pub fn from_env(registry: &Registry) -> Option<Self> {
+        let points: Vec<CapturePoint> = points_from_env()
+            .into_iter()
+            .filter(|(role, _)| role != "lan")
+            .map(|(role, interface_name)| CapturePoint {
+                role,
+                interface_name,
+            })
+            .collect();
+        if points.is_empty() {
+            return None;
+        }
+        for point in &points {
+            info!(
+                "Cross-checking {} against capture point {}",
+                point.role, point.interface_name
+            );
+        }
+
+        let observed_gauge = IntGaugeVec::new(
+            prometheus::Opts::new(
+                "capture_point_bytes",
+                "Bytes of the flows over a WAN seen at the LAN and at the WAN capture point over the last second",
+            )
+            .const_label("job", "localpacketdump"),
+            &["interface", "direction", "side"],
+        )
+        .expect("failed to create capture_point_bytes gauge");
+        let discrepancy_gauge = IntGaugeVec::new(
+            prometheus::Opts::new(
+                "capture_discrepancy_bytes",
+                "Bytes of the flows over a WAN seen only at the LAN (lan_only) or only at the WAN capture point (wan_only) over the last second",
+            )
+            .const_label("job", "localpacketdump"),
+            &["interface", "direction", "side"],
+        )
+        .expect("failed to create capture_discrepancy_bytes gauge");
+        registry
+            .register(Box::new(observed_gauge.clone()))
+            .expect("failed to register capture_point_bytes gauge");
+        registry
+            .register(Box::new(discrepancy_gauge.clone()))
+            .expect("failed to register capture_discrepancy_bytes gauge");
+
+        Some(Self {
+            points,
+            lan: DashMap::new(),
+            wan: DashMap::new(),
+            observed_gauge,
+            discrepancy_gauge,
+        })
+    }
+
+    pub fn points(&self) -> &[CapturePoint] {
+        &self.points
+    }
+
+    // `remote` is the address on the far side of the WAN; flows over WANs without a
+    // capture point are ignored
+    pub fn record(
+        &self,
+        side: Side,
+        role: &str,
+        remote: IpAddr,
+        packet: &CapturedPacket,
+        download: bool,
+    ) {
+        if !self.points.iter().any(|point| point.role == role) {
+            return;
+        }
+        let remote_port = match packet.transport {
+            Transport::Tcp {
+                src_port, dst_port, ..
+            }
+            | Transport::Udp { src_port, dst_port } => {
+                if download {
+                    src_port
+                } else {
+                    dst_port
+                }
+            }
+            _ => 0,
+        };
+        let flows = match side {
+            Side::Lan => &self.lan,
+            Side::Wan => &self.wan,
+        };
+        let key = (role.to_string(), remote, remote_port, packet.protocol.0);
+        if flows.len() >= MAX_FLOWS_PER_WINDOW && !flows.contains_key(&key) {
+            return;
+        }
+        let mut entry = flows.entry(key).or_default();
+        if download {
+            entry.0 += packet.bytes;
+        } else {
+            entry.1 += packet.bytes;
+        }
+    }
+
+    // Compare the window's flows and reset; called whenever the byte gauges are published
+    pub fn publish_and_reset(&self, scale: f64) {
+        let lan: Vec<(FlowKey, (u64, u64))> = self
+            .lan
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        self.lan.clear();
+        let mut wan: HashMap<FlowKey, (u64, u64)> = self
+            .wan
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        self.wan.clear();
+
+        for point in &self.points {
+            // [download, upload] of [lan, wan, lan_only, wan_only]
+            let mut totals = [[0u64; 4]; 2];
+            for (key, lan_bytes) in lan.iter().filter(|(key, _)| key.0 == point.role) {
+                let wan_bytes = wan.remove(key).unwrap_or_default();
+                add_flow(&mut totals, *lan_bytes, wan_bytes);
+            }
+            for (_, wan_bytes) in wan.iter().filter(|(key, _)| key.0 == point.role) {
+                add_flow(&mut totals, (0, 0), *wan_bytes);
+            }
+
+            for (direction, totals) in ["download", "upload"].iter().zip(totals) {
+                let scaled = |bytes: u64| (bytes as f64 * scale) as i64;
+                for (side, bytes) in [("lan", totals[0]), ("wan", totals[1])] {
+                    self.observed_gauge
+                        .with_label_values(&[&point.interface_name, direction, side])
+                        .set(scaled(bytes));
+                }
+                for (side, bytes) in [("lan_only", totals[2]), ("wan_only", totals[3])] {
+                    self.discrepancy_gauge
+                        .with_label_values(&[&point.interface_name, direction, side])
+                        .set(scaled(bytes));
+                }
+            }
+        }
+    }
+}
+
+// Adds one flow's (download, upload) bytes at each side
+fn add_flow(totals: &mut [[u64; 4]; 2], lan: (u64, u64), wan: (u64, u64)) {
+    for (totals, (lan, wan)) in totals.iter_mut().zip([(lan.0, wan.0), (lan.1, wan.1)]) {
+        totals[0] += lan;
+        totals[1] += wan;
+        totals[2] += lan.saturating_sub(wan);
+        totals[3] += wan.saturating_sub(lan);
+    }
+}
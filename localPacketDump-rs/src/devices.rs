@@ -0,0 +1,118 @@
+// Per-device traffic accounting
+//
+// download_bytes / upload_bytes answer "which remote is busy"; these gauges answer
+// "which local host is consuming bandwidth" without switching PERSPECTIVE, which
+// would change the labels icmp-traffic-scan and throughput-dump rely on.
+// Off unless DEVICE_METRICS is set, since it adds a series pair per device.
+// Idle devices follow IDLE_POLICY / IDLE_TTL_SECS like the byte gauges.
+
+use crate::idle::{IdleSeries, IdleSettings};
+use crate::network;
+use dashmap::DashMap;
+use prometheus::{IntGaugeVec, Registry};
+use shared_schema::{LABEL_INTERFACE, LABEL_LOCAL_IP};
+use tracing::info;
+
+pub struct Devices {
+    enabled: bool,
+    // (download, upload) bytes in the current window per [local_ip, interface]
+    window_bytes: DashMap<[String; 2], (u64, u64)>,
+    // Published devices and how long each has been idle
+    idle: IdleSeries<[String; 2]>,
+    download_gauge: IntGaugeVec,
+    upload_gauge: IntGaugeVec,
+}
+
+impl Devices {
+    pub fn new(registry: &Registry, idle: IdleSettings) -> Self {
+        let enabled = matches!(
+            network::var("DEVICE_METRICS")
+                .map(|v| v.trim().to_ascii_lowercase())
+                .as_deref(),
+            Ok("1") | Ok("true")
+        );
+        info!("Device metrics: {}", enabled);
+
+        let download_gauge = IntGaugeVec::new(
+            prometheus::Opts::new(
+                "device_download_bytes",
+                "Download bytes per local device over the last second",
+            )
+            .const_label("job", "localpacketdump"),
+            &[LABEL_LOCAL_IP, LABEL_INTERFACE],
+        )
+        .expect("failed to create device_download_bytes gauge");
+        let upload_gauge = IntGaugeVec::new(
+            prometheus::Opts::new(
+                "device_upload_bytes",
+                "Upload bytes per local device over the last second",
+            )
+            .const_label("job", "localpacketdump"),
+            &[LABEL_LOCAL_IP, LABEL_INTERFACE],
+        )
+        .expect("failed to create device_upload_bytes gauge");
+        if enabled {
+            registry
+                .register(Box::new(download_gauge.clone()))
+                .expect("failed to register device_download_bytes gauge");
+            registry
+                .register(Box::new(upload_gauge.clone()))
+                .expect("failed to register device_upload_bytes gauge");
+        }
+
+        Self {
+            enabled,
+            window_bytes: DashMap::new(),
+            idle: IdleSeries::new(idle),
+            download_gauge,
+            upload_gauge,
+        }
+    }
+
+    pub fn record(&self, local_ip: &str, interface: &str, download: bool, bytes: u64) {
+        if !self.enabled {
+            return;
+        }
+        let mut entry = self
+            .window_bytes
+            .entry([local_ip.to_string(), interface.to_string()])
+            .or_default();
+        if download {
+            entry.0 += bytes;
+        } else {
+            entry.1 += bytes;
+        }
+    }
+
+    // Publish the window; idle devices read 0 until IDLE_POLICY / IDLE_TTL_SECS removes them
+    pub fn publish_and_reset(&self, scale: f64) {
+        if !self.enabled {
+            return;
+        }
+        for entry in self.window_bytes.iter() {
+            let (download, upload) = *entry.value();
+            let labels = [entry.key()[0].as_str(), entry.key()[1].as_str()];
+            self.download_gauge
+                .with_label_values(&labels)
+                .set((download as f64 * scale) as i64);
+            self.upload_gauge
+                .with_label_values(&labels)
+                .set((upload as f64 * scale) as i64);
+            self.idle.seen(entry.key());
+        }
+        self.idle.sweep(
+            |key| self.window_bytes.contains_key(key),
+            |key| {
+                let labels = [key[0].as_str(), key[1].as_str()];
+                self.download_gauge.with_label_values(&labels).set(0);
+                self.upload_gauge.with_label_values(&labels).set(0);
+            },
+            |key| {
+                let labels = [key[0].as_str(), key[1].as_str()];
+                let _ = self.download_gauge.remove_label_values(&labels);
+                let _ = self.upload_gauge.remove_label_values(&labels);
+            },
+        );
+        self.window_bytes.clear();
+    }
+}
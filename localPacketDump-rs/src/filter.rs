@@ -0,0 +1,211 @@
+// Kernel capture filter (classic BPF) and CIDR deny-list
+//
+// A filter attached to the capture socket drops unwanted frames in the kernel, before
+// they are copied to userspace. pnet cannot compile pcap expressions, so CAPTURE_FILTER
+// is compiled with `tcpdump -ddd`; CAPTURE_FILTER_BPF takes that output directly for
+// hosts without tcpdump. DENY_CIDRS covers what is easier to express as address ranges.
+
+use crate::network;
+use ipnetwork::IpNetwork;
+use prometheus::{IntCounter, Registry};
+use shared_cidr::IpSet;
+use std::io;
+use std::net::IpAddr;
+use std::process::Command;
+use std::str::FromStr;
+use tracing::{error, info};
+
+// One classic BPF instruction (struct sock_filter)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BpfInstruction {
+    pub code: u16,
+    pub jt: u8,
+    pub jf: u8,
+    pub k: u32,
+}
+
+// Filter program from CAPTURE_FILTER / CAPTURE_FILTER_BPF, or None to capture everything
+pub fn program_from_env(interface_name: &str) -> Option<Vec<BpfInstruction>> {
+    if let Some(bpf) = network::var("CAPTURE_FILTER_BPF")
+        .ok()
+        .filter(|v| !v.trim().is_empty())
+    {
+        return match parse_ddd(&bpf) {
+            Ok(program) => {
+                info!(
+                    "Using precompiled capture filter ({} instructions)",
+                    program.len()
+                );
+                Some(program)
+            }
+            Err(e) => {
+                error!("Failed to parse CAPTURE_FILTER_BPF: {}", e);
+                None
+            }
+        };
+    }
+
+    let expression = network::var("CAPTURE_FILTER")
+        .ok()
+        .filter(|v| !v.trim().is_empty())?;
+    match compile(interface_name, &expression) {
+        Ok(program) => {
+            info!(
+                "Using capture filter '{}' ({} instructions)",
+                expression,
+                program.len()
+            );
+            Some(program)
+        }
+        Err(e) => {
+            error!("Failed to compile CAPTURE_FILTER '{}': {}", expression, e);
+            None
+        }
+    }
+}
+
+// Compile a pcap filter expression for the interface's link type
+fn compile(interface_name: &str, expression: &str) -> io::Result<Vec<BpfInstruction>> {
+    let output = Command::new("tcpdump")
+        .args(["-i", interface_name, "-ddd", expression])
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+    parse_ddd(&String::from_utf8_lossy(&output.stdout))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// `tcpdump -ddd` output: the instruction count, then "code jt jf k" per instruction.
+// Lines may also be separated by commas so the program fits in one variable.
+fn parse_ddd(s: &str) -> Result<Vec<BpfInstruction>, String> {
+    let mut lines = s
+        .split(['\n', ','])
+        .map(str::trim)
+        .filter(|line| !line.is_empty());
+    let count: usize = lines
+        .next()
+        .ok_or("empty program")?
+        .parse()
+        .map_err(|_| "first line must be the instruction count")?;
+
+    let program = lines
+        .map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [code, jt, jf, k] = fields[..] else {
+                return Err(format!("expected 'code jt jf k', got '{}'", line));
+            };
+            let invalid = |_| format!("invalid instruction '{}'", line);
+            Ok(BpfInstruction {
+                code: code.parse().map_err(invalid)?,
+                jt: jt.parse().map_err(invalid)?,
+                jf: jf.parse().map_err(invalid)?,
+                k: k.parse().map_err(invalid)?,
+            })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    if program.len() != count {
+        return Err(format!(
+            "expected {} instructions, got {}",
+            count,
+            program.len()
+        ));
+    }
+    Ok(program)
+}
+
+// Attach the program to a packet socket (SO_ATTACH_FILTER)
+#[cfg(target_os = "linux")]
+pub fn attach(fd: i32, program: &[BpfInstruction]) -> io::Result<()> {
+    let mut filter: Vec<libc::sock_filter> = program
+        .iter()
+        .map(|insn| libc::sock_filter {
+            code: insn.code,
+            jt: insn.jt,
+            jf: insn.jf,
+            k: insn.k,
+        })
+        .collect();
+    let prog = libc::sock_fprog {
+        len: filter.len() as libc::c_ushort,
+        filter: filter.as_mut_ptr(),
+    };
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ATTACH_FILTER,
+            &prog as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::sock_fprog>() as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn attach(_fd: i32, _program: &[BpfInstruction]) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "SO_ATTACH_FILTER is only supported on Linux",
+    ))
+}
+
+// Address ranges whose packets are ignored in either direction (DENY_CIDRS)
+pub struct DenyList {
+    networks: IpSet,
+    denied_packets: IntCounter,
+}
+
+impl DenyList {
+    pub fn new(registry: &Registry) -> Self {
+        let networks: IpSet = network::var("DENY_CIDRS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|cidr| !cidr.is_empty())
+            .filter_map(|cidr| match IpNetwork::from_str(cidr) {
+                Ok(net) => Some(net),
+                Err(e) => {
+                    error!("Failed to parse deny CIDR {}: {}", cidr, e);
+                    None
+                }
+            })
+            .collect();
+        if !networks.is_empty() {
+            let cidrs: Vec<&IpNetwork> = networks.iter().map(|(net, _)| net).collect();
+            info!("Ignoring traffic to and from {:?}", cidrs);
+        }
+
+        let denied_packets = IntCounter::with_opts(
+            prometheus::Opts::new(
+                "denied_packets_total",
+                "Captured packets ignored because an address is in DENY_CIDRS",
+            )
+            .const_label("job", "localpacketdump"),
+        )
+        .expect("failed to create denied_packets_total counter");
+        registry
+            .register(Box::new(denied_packets.clone()))
+            .expect("failed to register denied_packets_total counter");
+
+        Self {
+            networks,
+            denied_packets,
+        }
+    }
+
+    pub fn denies(&self, src: IpAddr, dst: IpAddr) -> bool {
+        let denied = self.networks.contains(src) || self.networks.contains(dst);
+        if denied {
+            self.denied_packets.inc();
+        }
+        denied
+    }
+}
@@ -0,0 +1,237 @@
+// Passive OS fingerprinting of local devices, served at /devices
+//
+// Every TCP stack fills the first SYN of a connection a little differently: initial TTL,
+// window size, which options are sent and in what order. Matching those p0f-style gives
+// a coarse OS class per local device, which helps to put a name to an unknown MAC address
+// on the network. Only SYNs sent by local devices are looked at and the newest signature
+// per device is kept. Off unless OS_FINGERPRINT is set.
+
+use crate::network;
+use dashmap::DashMap;
+use pnet::packet::tcp::TcpPacket;
+use pnet::util::MacAddr;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use tracing::info;
+
+// TCP options of a SYN kept for matching at most
+const MAX_OPTIONS: usize = 12;
+// Devices kept at most; devices unseen for DEVICE_RETENTION_MS make room for new ones
+const MAX_DEVICES: usize = 65536;
+const DEVICE_RETENTION_MS: i64 = 24 * 60 * 60 * 1000;
+
+// Layouts of the SYN options, as named in the signature
+const LAYOUT_APPLE: &str = "mss,nop,ws,nop,nop,ts,sok,eol";
+const LAYOUT_LINUX: &str = "mss,sok,ts,nop,ws";
+
+// Header fields of a SYN without ACK, taken on the capture thread
+#[derive(Debug, Clone, Copy)]
+pub struct SynSignature {
+    ip_version: u8,
+    ttl: u8,
+    df: bool,
+    window: u16,
+    mss: Option<u16>,
+    window_scale: Option<u8>,
+    // Option kinds in the order sent
+    options: [u8; MAX_OPTIONS],
+    option_count: u8,
+    // Source of the Ethernet frame that carried the SYN
+    pub mac: Option<MacAddr>,
+}
+
+impl SynSignature {
+    pub fn parse(ip_version: u8, ttl: u8, df: bool, tcp: &TcpPacket) -> Self {
+        let mut signature = Self {
+            ip_version,
+            ttl,
+            df,
+            window: tcp.get_window(),
+            mss: None,
+            window_scale: None,
+            options: [0; MAX_OPTIONS],
+            option_count: 0,
+            mac: None,
+        };
+        let raw = tcp.get_options_raw();
+        let mut offset = 0;
+        while offset < raw.len() && (signature.option_count as usize) < MAX_OPTIONS {
+            let kind = raw[offset];
+            signature.options[signature.option_count as usize] = kind;
+            signature.option_count += 1;
+            match kind {
+                // End of options
+                0 => break,
+                // No-operation padding
+                1 => offset += 1,
+                _ => {
+                    let Some(&len) = raw.get(offset + 1) else {
+                        break;
+                    };
+                    let Some(data) = raw.get(offset + 2..offset + len as usize) else {
+                        break;
+                    };
+                    match (kind, data) {
+                        (2, [high, low]) => signature.mss = Some(u16::from_be_bytes([*high, *low])),
+                        (3, [shift]) => signature.window_scale = Some(*shift),
+                        _ => {}
+                    }
+                    offset += (len as usize).max(2);
+                }
+            }
+        }
+        signature
+    }
+
+    fn options(&self) -> &[u8] {
+        &self.options[..self.option_count as usize]
+    }
+
+    // TTL the sender started with: the next common default at or above the one seen
+    fn initial_ttl(&self) -> u8 {
+        [32, 64, 128]
+            .into_iter()
+            .find(|&initial| self.ttl <= initial)
+            .unwrap_or(255)
+    }
+
+    fn layout(&self) -> String {
+        self.options()
+            .iter()
+            .map(|kind| match kind {
+                0 => "eol".to_string(),
+                1 => "nop".to_string(),
+                2 => "mss".to_string(),
+                3 => "ws".to_string(),
+                4 => "sok".to_string(),
+                8 => "ts".to_string(),
+                other => format!("?{}", other),
+            })
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    // ip version:initial ttl:df:window:options:mss:window scale, "-" for an absent value
+    fn describe(&self) -> String {
+        let or_dash = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+        format!(
+            "{}:{}:{}:{}:{}:{}:{}",
+            self.ip_version,
+            self.initial_ttl(),
+            if self.df { "df" } else { "-" },
+            self.window,
+            self.layout(),
+            or_dash(self.mss.map(|mss| mss.to_string())),
+            or_dash(self.window_scale.map(|shift| shift.to_string())),
+        )
+    }
+
+    fn classify(&self) -> OsClass {
+        let layout = self.layout();
+        match self.initial_ttl() {
+            128 => OsClass::Windows,
+            64 if layout.starts_with(LAYOUT_APPLE) => OsClass::IosMacos,
+            64 if layout == LAYOUT_LINUX => OsClass::AndroidLinux,
+            // Small embedded stacks send MSS at most and often start from 255
+            64 | 255 if self.option_count <= 1 => OsClass::Iot,
+            _ => OsClass::Unknown,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OsClass {
+    Windows,
+    // Android and other Linux; their SYNs cannot be told apart
+    AndroidLinux,
+    IosMacos,
+    // Embedded devices (lwIP and similar)
+    Iot,
+    Unknown,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Device {
+    pub local_ip: String,
+    pub mac: Option<String>,
+    pub os: OsClass,
+    pub signature: String,
+    pub interface: String,
+    pub first_seen_ms: i64,
+    pub last_seen_ms: i64,
+}
+
+// Query parameters of /devices
+#[derive(Debug, Default, Deserialize)]
+pub struct DevicesQuery {
+    // Only devices of this class
+    pub os: Option<OsClass>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DevicesPage {
+    // Sorted by address
+    pub devices: Vec<Device>,
+}
+
+pub struct Fingerprints {
+    devices: DashMap<String, Device>,
+}
+
+impl Fingerprints {
+    // None unless OS_FINGERPRINT is enabled
+    pub fn from_env() -> Option<Self> {
+        let enabled = matches!(
+            network::var("OS_FINGERPRINT")
+                .map(|v| v.trim().to_ascii_lowercase())
+                .as_deref(),
+            Ok("1") | Ok("true")
+        );
+        info!("OS fingerprinting: {}", enabled);
+        enabled.then(|| Self {
+            devices: DashMap::new(),
+        })
+    }
+
+    pub fn record(&self, local_ip: &str, interface: &str, syn: &SynSignature) {
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        if self.devices.len() >= MAX_DEVICES && !self.devices.contains_key(local_ip) {
+            self.devices
+                .retain(|_, device| now_ms - device.last_seen_ms <= DEVICE_RETENTION_MS);
+            if self.devices.len() >= MAX_DEVICES {
+                return;
+            }
+        }
+        let mut device = self
+            .devices
+            .entry(local_ip.to_string())
+            .or_insert_with(|| Device {
+                local_ip: local_ip.to_string(),
+                mac: None,
+                os: OsClass::Unknown,
+                signature: String::new(),
+                interface: String::new(),
+                first_seen_ms: now_ms,
+                last_seen_ms: now_ms,
+            });
+        if let Some(mac) = syn.mac {
+            device.mac = Some(mac.to_string());
+        }
+        device.os = syn.classify();
+        device.signature = syn.describe();
+        device.interface = interface.to_string();
+        device.last_seen_ms = now_ms;
+    }
+
+    pub fn query(&self, query: &DevicesQuery) -> DevicesPage {
+        let mut devices: Vec<Device> = self
+            .devices
+            .iter()
+            .filter(|device| query.os.is_none_or(|os| os == device.os))
+            .map(|device| device.value().clone())
+            .collect();
+        devices.sort_by_key(|device| device.local_ip.parse::<IpAddr>().ok());
+        DevicesPage { devices }
+    }
+}
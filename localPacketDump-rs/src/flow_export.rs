@@ -0,0 +1,513 @@
+// NetFlow v9 / IPFIX export
+//
+// NOC tooling consumes flow records rather than Prometheus. When FLOW_EXPORT_COLLECTOR is
+// set, every packet between a local device and a remote is accounted to its 5-tuple flow,
+// and finished flows (TCP FIN/RST, idle timeout) plus long-running ones (active timeout)
+// are sent to the collector over UDP. The Prometheus gauges are unaffected.
+//
+// While SAMPLING_THRESHOLD_PPS thins out packets, records carry the sampled packets and
+// bytes as they were seen together with SAMPLING_INTERVAL, so collectors scale them up
+// themselves. A flow whose sampling interval changes is exported and restarted, as one
+// record can only carry one interval.
+
+use crate::capture::{CapturedPacket, Transport};
+use crate::network;
+use dashmap::DashMap;
+use pnet::packet::tcp::TcpFlags;
+use prometheus::{IntCounter, IntCounterVec, IntGauge, Registry};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{error, info, warn};
+
+// Keep export packets below a typical path MTU
+const MAX_PACKET_SIZE: usize = 1400;
+const TEMPLATE_ID_V4: u16 = 256;
+const TEMPLATE_ID_V6: u16 = 257;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    NetflowV9,
+    Ipfix,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct FlowKey {
+    src: IpAddr,
+    dst: IpAddr,
+    src_port: u16,
+    dst_port: u16,
+    protocol: u8,
+}
+
+impl FlowKey {
+    // NAT64 flows mix families; both addresses then go into the IPv6 template
+    fn is_v6(&self) -> bool {
+        self.src.is_ipv6() || self.dst.is_ipv6()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Flow {
+    // Wall clock of the first and last packet, in milliseconds since the epoch
+    first_ms: u64,
+    last_ms: u64,
+    bytes: u64,
+    packets: u64,
+    // TCP flags seen over the flow, OR'ed together
+    tcp_flags: u8,
+    // Sent by a local device (egress) rather than received (ingress)
+    outbound: bool,
+    // Outer VLAN ID, 0 when untagged
+    vlan: u16,
+    // One in this many packets was counted (1 without sampling)
+    sampling_interval: u32,
+}
+
+pub struct FlowExporter {
+    collector: String,
+    protocol: Protocol,
+    // Export a flow that has been silent this long (FLOW_IDLE_TIMEOUT_SECS)
+    idle_timeout_ms: u64,
+    // Export a long-running flow this often (FLOW_ACTIVE_TIMEOUT_SECS)
+    active_timeout_ms: u64,
+    // Re-send the templates this often, since UDP collectors may miss them
+    template_interval: Duration,
+    // Observation domain / source ID in the export header (FLOW_EXPORT_DOMAIN_ID)
+    domain_id: u32,
+    // Upper bound on tracked flows (FLOW_EXPORT_MAX_FLOWS)
+    max_flows: usize,
+    flows: DashMap<FlowKey, Flow>,
+    // Parts of flows ended by a change of the sampling interval, sent on the next tick
+    split: Mutex<Vec<(FlowKey, Flow)>>,
+    // result="sent" / "failed"; flows of a tick that could not be sent are not retried
+    records_counter: IntCounterVec,
+    dropped_counter: IntCounter,
+    active_flows_gauge: IntGauge,
+}
+
+impl FlowExporter {
+    // None unless FLOW_EXPORT_COLLECTOR is set
+    pub fn from_env(registry: &Registry) -> Option<Self> {
+        let collector = network::var("FLOW_EXPORT_COLLECTOR")
+            .ok()
+            .filter(|v| !v.trim().is_empty())?;
+        let protocol = match network::var("FLOW_EXPORT_PROTOCOL")
+            .as_deref()
+            .map(str::trim)
+        {
+            Err(_) | Ok("") | Ok("netflow9") => Protocol::NetflowV9,
+            Ok("ipfix") => Protocol::Ipfix,
+            Ok(other) => {
+                error!("Unknown FLOW_EXPORT_PROTOCOL {}, using netflow9", other);
+                Protocol::NetflowV9
+            }
+        };
+        let secs = |name: &str, default: u64| {
+            network::var(name)
+                .ok()
+                .and_then(|v| v.trim().parse::<u64>().ok())
+                .unwrap_or(default)
+        };
+
+        info!("Exporting flows to {} as {:?}", collector.trim(), protocol);
+
+        let records_counter = IntCounterVec::new(
+            prometheus::Opts::new(
+                "flow_export_records_total",
+                "Flow records sent to, or failed to reach, the NetFlow / IPFIX collector",
+            )
+            .const_label("job", "localpacketdump"),
+            &["result"],
+        )
+        .expect("failed to create flow_export_records_total counter");
+        let dropped_counter = IntCounter::with_opts(
+            prometheus::Opts::new(
+                "flow_export_dropped_flows_total",
+                "New flows not tracked because FLOW_EXPORT_MAX_FLOWS was reached",
+            )
+            .const_label("job", "localpacketdump"),
+        )
+        .expect("failed to create flow_export_dropped_flows_total counter");
+        let active_flows_gauge = IntGauge::with_opts(
+            prometheus::Opts::new(
+                "flow_export_active_flows",
+                "Flows currently tracked for export",
+            )
+            .const_label("job", "localpacketdump"),
+        )
+        .expect("failed to create flow_export_active_flows gauge");
+        registry
+            .register(Box::new(records_counter.clone()))
+            .expect("failed to register flow_export_records_total counter");
+        registry
+            .register(Box::new(dropped_counter.clone()))
+            .expect("failed to register flow_export_dropped_flows_total counter");
+        registry
+            .register(Box::new(active_flows_gauge.clone()))
+            .expect("failed to register flow_export_active_flows gauge");
+
+        Some(Self {
+            collector: collector.trim().to_string(),
+            protocol,
+            idle_timeout_ms: secs("FLOW_IDLE_TIMEOUT_SECS", 15).max(1) * 1000,
+            active_timeout_ms: secs("FLOW_ACTIVE_TIMEOUT_SECS", 60).max(1) * 1000,
+            template_interval: Duration::from_secs(secs("FLOW_EXPORT_TEMPLATE_INTERVAL_SECS", 60)),
+            domain_id: secs("FLOW_EXPORT_DOMAIN_ID", 0) as u32,
+            max_flows: secs("FLOW_EXPORT_MAX_FLOWS", 65536) as usize,
+            flows: DashMap::new(),
+            split: Mutex::new(Vec::new()),
+            records_counter,
+            dropped_counter,
+            active_flows_gauge,
+        })
+    }
+
+    // Account a packet between a local device and a remote to its flow
+    pub fn record(&self, packet: &CapturedPacket, outbound: bool) {
+        let (src_port, dst_port, tcp_flags) = match packet.transport {
+            Transport::Tcp {
+                src_port,
+                dst_port,
+                flags,
+                ..
+            } => (src_port, dst_port, flags),
+            Transport::Udp { src_port, dst_port } => (src_port, dst_port, 0),
+            Transport::Membership(_) | Transport::Other => (0, 0, 0),
+        };
+        let key = FlowKey {
+            src: packet.src,
+            dst: packet.dst,
+            src_port,
+            dst_port,
+            protocol: packet.protocol.0,
+        };
+        let now = now_ms();
+        // Sampling scaled the packet up; export it as seen
+        let sampling_interval = packet.packets.max(1);
+        let bytes = packet.bytes / sampling_interval;
+
+        if let Some(mut flow) = self.flows.get_mut(&key) {
+            if flow.sampling_interval != sampling_interval as u32 {
+                if flow.packets > 0 {
+                    self.split.lock().unwrap().push((key, *flow));
+                }
+                flow.first_ms = now;
+                flow.bytes = 0;
+                flow.packets = 0;
+                flow.tcp_flags = 0;
+                flow.sampling_interval = sampling_interval as u32;
+            }
+            flow.last_ms = now;
+            flow.bytes += bytes;
+            flow.packets += 1;
+            flow.tcp_flags |= tcp_flags;
+            return;
+        }
+        if self.flows.len() >= self.max_flows {
+            self.dropped_counter.inc();
+            return;
+        }
+        self.flows.insert(
+            key,
+            Flow {
+                first_ms: now,
+                last_ms: now,
+                bytes,
+                packets: 1,
+                tcp_flags,
+                outbound,
+                vlan: packet.vlan.map(|tags| tags.outer).unwrap_or(0),
+                sampling_interval: sampling_interval as u32,
+            },
+        );
+    }
+
+    // Remove finished flows and restart long-running ones, returning the records to send
+    fn expire(&self) -> Vec<(FlowKey, Flow)> {
+        let now = now_ms();
+        let mut expired = std::mem::take(&mut *self.split.lock().unwrap());
+        self.flows.retain(|key, flow| {
+            let finished = flow.tcp_flags & (TcpFlags::FIN | TcpFlags::RST) != 0;
+            if finished || now.saturating_sub(flow.last_ms) >= self.idle_timeout_ms {
+                // Nothing new since the last active-timeout export
+                if flow.packets > 0 {
+                    expired.push((*key, *flow));
+                }
+                return false;
+            }
+            if now.saturating_sub(flow.first_ms) >= self.active_timeout_ms {
+                expired.push((*key, *flow));
+                flow.first_ms = now;
+                flow.bytes = 0;
+                flow.packets = 0;
+                flow.tcp_flags = 0;
+            }
+            true
+        });
+        self.active_flows_gauge.set(self.flows.len() as i64);
+        expired
+    }
+
+    // Export loop; runs on its own thread
+    pub fn run(&self) {
+        let started_ms = now_ms();
+        let mut socket: Option<UdpSocket> = None;
+        let mut last_template: Option<Instant> = None;
+        // NetFlow v9 counts export packets, IPFIX counts data records
+        let mut sequence: u32 = 0;
+
+        loop {
+            std::thread::sleep(Duration::from_secs(1));
+            let expired = self.expire();
+
+            if socket.is_none() {
+                match connect(&self.collector) {
+                    Ok(connected) => socket = Some(connected),
+                    Err(e) => {
+                        warn!("Failed to reach flow collector {}: {}", self.collector, e);
+                        self.count_records("failed", expired.len());
+                        continue;
+                    }
+                }
+            }
+            let Some(udp) = &socket else {
+                continue;
+            };
+
+            // (message, flow records in it)
+            let mut messages = Vec::new();
+            if last_template.is_none_or(|sent| sent.elapsed() >= self.template_interval) {
+                messages.push((self.template_message(started_ms, sequence), 0));
+                if self.protocol == Protocol::NetflowV9 {
+                    sequence = sequence.wrapping_add(1);
+                }
+                last_template = Some(Instant::now());
+            }
+            for v6 in [false, true] {
+                let records: Vec<&(FlowKey, Flow)> = expired
+                    .iter()
+                    .filter(|(key, _)| key.is_v6() == v6)
+                    .collect();
+                let per_message = (MAX_PACKET_SIZE - header_len(self.protocol) - 4)
+                    / record_len(self.protocol, v6);
+                for chunk in records.chunks(per_message) {
+                    messages.push((
+                        self.data_message(started_ms, sequence, v6, chunk),
+                        chunk.len(),
+                    ));
+                    sequence = match self.protocol {
+                        Protocol::NetflowV9 => sequence.wrapping_add(1),
+                        Protocol::Ipfix => sequence.wrapping_add(chunk.len() as u32),
+                    };
+                }
+            }
+
+            let mut sent = 0;
+            for (message, records) in messages {
+                if let Err(e) = udp.send(&message) {
+                    warn!("Failed to send flow export to {}: {}", self.collector, e);
+                    // Resolve and send the templates again on the next tick
+                    socket = None;
+                    last_template = None;
+                    break;
+                }
+                sent += records;
+            }
+            self.count_records("sent", sent);
+            self.count_records("failed", expired.len() - sent);
+        }
+    }
+
+    fn count_records(&self, result: &str, records: usize) {
+        if records > 0 {
+            self.records_counter
+                .with_label_values(&[result])
+                .inc_by(records as u64);
+        }
+    }
+
+    fn header(&self, started_ms: u64, sequence: u32, records: u16) -> Vec<u8> {
+        let now = now_ms();
+        let mut buf = Vec::with_capacity(MAX_PACKET_SIZE);
+        match self.protocol {
+            Protocol::NetflowV9 => {
+                buf.extend_from_slice(&9u16.to_be_bytes());
+                buf.extend_from_slice(&records.to_be_bytes());
+                buf.extend_from_slice(&(now.saturating_sub(started_ms) as u32).to_be_bytes());
+                buf.extend_from_slice(&((now / 1000) as u32).to_be_bytes());
+            }
+            Protocol::Ipfix => {
+                buf.extend_from_slice(&10u16.to_be_bytes());
+                // Message length, patched in finish()
+                buf.extend_from_slice(&0u16.to_be_bytes());
+                buf.extend_from_slice(&((now / 1000) as u32).to_be_bytes());
+            }
+        }
+        buf.extend_from_slice(&sequence.to_be_bytes());
+        buf.extend_from_slice(&self.domain_id.to_be_bytes());
+        buf
+    }
+
+    fn template_message(&self, started_ms: u64, sequence: u32) -> Vec<u8> {
+        let mut buf = self.header(started_ms, sequence, 2);
+        let set_start = buf.len();
+        let set_id: u16 = match self.protocol {
+            Protocol::NetflowV9 => 0,
+            Protocol::Ipfix => 2,
+        };
+        buf.extend_from_slice(&set_id.to_be_bytes());
+        buf.extend_from_slice(&0u16.to_be_bytes());
+        for (template_id, v6) in [(TEMPLATE_ID_V4, false), (TEMPLATE_ID_V6, true)] {
+            let fields = template_fields(self.protocol, v6);
+            buf.extend_from_slice(&template_id.to_be_bytes());
+            buf.extend_from_slice(&(fields.len() as u16).to_be_bytes());
+            for (field_type, length) in fields {
+                buf.extend_from_slice(&field_type.to_be_bytes());
+                buf.extend_from_slice(&length.to_be_bytes());
+            }
+        }
+        finish_set(&mut buf, set_start);
+        self.finish(buf)
+    }
+
+    fn data_message(
+        &self,
+        started_ms: u64,
+        sequence: u32,
+        v6: bool,
+        records: &[&(FlowKey, Flow)],
+    ) -> Vec<u8> {
+        let mut buf = self.header(started_ms, sequence, records.len() as u16);
+        let set_start = buf.len();
+        let set_id = if v6 { TEMPLATE_ID_V6 } else { TEMPLATE_ID_V4 };
+        buf.extend_from_slice(&set_id.to_be_bytes());
+        buf.extend_from_slice(&0u16.to_be_bytes());
+        for (key, flow) in records {
+            match (key.src, key.dst) {
+                (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                    buf.extend_from_slice(&src.octets());
+                    buf.extend_from_slice(&dst.octets());
+                }
+                (src, dst) => {
+                    buf.extend_from_slice(&ipv6_octets(src));
+                    buf.extend_from_slice(&ipv6_octets(dst));
+                }
+            }
+            buf.extend_from_slice(&key.src_port.to_be_bytes());
+            buf.extend_from_slice(&key.dst_port.to_be_bytes());
+            buf.push(key.protocol);
+            buf.push(flow.tcp_flags);
+            buf.extend_from_slice(&flow.bytes.to_be_bytes());
+            buf.extend_from_slice(&flow.packets.to_be_bytes());
+            buf.push(flow.outbound as u8);
+            buf.extend_from_slice(&flow.vlan.to_be_bytes());
+            buf.extend_from_slice(&flow.sampling_interval.to_be_bytes());
+            match self.protocol {
+                // FIRST_SWITCHED / LAST_SWITCHED are relative to the exporter's uptime
+                Protocol::NetflowV9 => {
+                    let uptime = |ms: u64| ms.saturating_sub(started_ms) as u32;
+                    buf.extend_from_slice(&uptime(flow.first_ms).to_be_bytes());
+                    buf.extend_from_slice(&uptime(flow.last_ms).to_be_bytes());
+                }
+                Protocol::Ipfix => {
+                    buf.extend_from_slice(&flow.first_ms.to_be_bytes());
+                    buf.extend_from_slice(&flow.last_ms.to_be_bytes());
+                }
+            }
+        }
+        finish_set(&mut buf, set_start);
+        self.finish(buf)
+    }
+
+    fn finish(&self, mut buf: Vec<u8>) -> Vec<u8> {
+        if self.protocol == Protocol::Ipfix {
+            let len = (buf.len() as u16).to_be_bytes();
+            buf[2..4].copy_from_slice(&len);
+        }
+        buf
+    }
+}
+
+// (field type, length); NetFlow v9 field types and IPFIX information element IDs agree
+// for everything except the timestamps
+fn template_fields(protocol: Protocol, v6: bool) -> Vec<(u16, u16)> {
+    let mut fields = if v6 {
+        // IPV6_SRC_ADDR, IPV6_DST_ADDR
+        vec![(27, 16), (28, 16)]
+    } else {
+        // IPV4_SRC_ADDR, IPV4_DST_ADDR
+        vec![(8, 4), (12, 4)]
+    };
+    // L4_SRC_PORT, L4_DST_PORT, PROTOCOL, TCP_FLAGS, IN_BYTES, IN_PKTS, DIRECTION, SRC_VLAN,
+    // SAMPLING_INTERVAL
+    fields.extend([
+        (7, 2),
+        (11, 2),
+        (4, 1),
+        (6, 1),
+        (1, 8),
+        (2, 8),
+        (61, 1),
+        (58, 2),
+        (34, 4),
+    ]);
+    match protocol {
+        // FIRST_SWITCHED, LAST_SWITCHED (uptime milliseconds)
+        Protocol::NetflowV9 => fields.extend([(22, 4), (21, 4)]),
+        // flowStartMilliseconds, flowEndMilliseconds
+        Protocol::Ipfix => fields.extend([(152, 8), (153, 8)]),
+    }
+    fields
+}
+
+fn record_len(protocol: Protocol, v6: bool) -> usize {
+    template_fields(protocol, v6)
+        .iter()
+        .map(|(_, length)| *length as usize)
+        .sum()
+}
+
+fn header_len(protocol: Protocol) -> usize {
+    match protocol {
+        Protocol::NetflowV9 => 20,
+        Protocol::Ipfix => 16,
+    }
+}
+
+// Pad the set to a 4-byte boundary and fill in its length
+fn finish_set(buf: &mut Vec<u8>, set_start: usize) {
+    while !(buf.len() - set_start).is_multiple_of(4) {
+        buf.push(0);
+    }
+    let len = ((buf.len() - set_start) as u16).to_be_bytes();
+    buf[set_start + 2..set_start + 4].copy_from_slice(&len);
+}
+
+fn ipv6_octets(addr: IpAddr) -> [u8; 16] {
+    match addr {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped().octets(),
+        IpAddr::V6(v6) => v6.octets(),
+    }
+}
+
+fn connect(collector: &str) -> std::io::Result<UdpSocket> {
+    let addr: SocketAddr = collector.to_socket_addrs()?.next().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::NotFound, "collector did not resolve")
+    })?;
+    let bind: SocketAddr = if addr.is_ipv4() {
+        "0.0.0.0:0".parse().unwrap()
+    } else {
+        "[::]:0".parse().unwrap()
+    };
+    let socket = UdpSocket::bind(bind)?;
+    socket.connect(addr)?;
+    Ok(socket)
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
@@ -0,0 +1,117 @@
+// Flow tracking for per-device connection rates
+//
+// A connection is counted when a local device opens it: an outbound TCP SYN, or the
+// first outbound UDP datagram of a 5-tuple that has been idle for UDP_FLOW_TIMEOUT.
+// A device suddenly opening thousands of connections per second is an early sign of
+// malware or a compromised IoT device.
+
+use dashmap::DashMap;
+use pnet::packet::tcp::TcpFlags;
+use prometheus::{IntGaugeVec, Registry};
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+// UDP has no handshake, so a flow is new when it has been silent this long
+const UDP_FLOW_TIMEOUT: Duration = Duration::from_secs(60);
+
+// (local_ip, local_port, remote_ip, remote_port)
+type UdpFlowKey = (String, u16, String, u16);
+
+pub struct FlowTracker {
+    // Last packet seen per UDP flow, in either direction
+    udp_flows: DashMap<UdpFlowKey, Instant>,
+    // Connections opened in the current 1-second window per local device
+    window_new_connections: DashMap<String, u64>,
+    // Every local device that has ever opened a connection
+    known_devices: DashMap<String, ()>,
+    new_connections_gauge: IntGaugeVec,
+}
+
+impl FlowTracker {
+    pub fn new(registry: &Registry) -> Self {
+        let new_connections_gauge = IntGaugeVec::new(
+            prometheus::Opts::new(
+                "device_new_connections",
+                "New connections opened per local device over the last second",
+            )
+            .const_label("job", "localpacketdump"),
+            &["local_ip"],
+        )
+        .expect("failed to create device_new_connections gauge");
+        registry
+            .register(Box::new(new_connections_gauge.clone()))
+            .expect("failed to register device_new_connections gauge");
+
+        Self {
+            udp_flows: DashMap::new(),
+            window_new_connections: DashMap::new(),
+            known_devices: DashMap::new(),
+            new_connections_gauge,
+        }
+    }
+
+    fn count_new_connections(&self, local_ip: &str, count: u64) {
+        self.window_new_connections
+            .entry(local_ip.to_string())
+            .and_modify(|v| *v += count)
+            .or_insert(count);
+    }
+
+    // Outbound TCP segment from a local device; only the initial SYN opens a connection.
+    // `packets` is the sampling scale: one sampled SYN stands for that many
+    pub fn record_tcp_outbound(&self, local_ip: &str, flags: u8, packets: u64) {
+        if flags & TcpFlags::SYN != 0 && flags & TcpFlags::ACK == 0 {
+            self.count_new_connections(local_ip, packets);
+        }
+    }
+
+    // UDP datagram between a local device and a remote
+    pub fn record_udp(
+        &self,
+        local_ip: &str,
+        local_port: u16,
+        remote_ip: &str,
+        remote_port: u16,
+        outbound: bool,
+    ) {
+        let key = (
+            local_ip.to_string(),
+            local_port,
+            remote_ip.to_string(),
+            remote_port,
+        );
+        let now = Instant::now();
+        let previous = self.udp_flows.insert(key, now);
+        let is_new = previous
+            .map(|seen| now.duration_since(seen) >= UDP_FLOW_TIMEOUT)
+            .unwrap_or(true);
+        // Not scaled by sampling: a flow of many packets is still seen once
+        if is_new && outbound {
+            self.count_new_connections(local_ip, 1);
+        }
+    }
+
+    // Publish the last 1-second window, then reset it
+    pub fn publish_and_reset(&self) {
+        let mut current: HashSet<String> = HashSet::new();
+        for entry in self.window_new_connections.iter() {
+            self.new_connections_gauge
+                .with_label_values(&[entry.key()])
+                .set(*entry.value() as i64);
+            self.known_devices.insert(entry.key().clone(), ());
+            current.insert(entry.key().clone());
+        }
+
+        for entry in self.known_devices.iter() {
+            if !current.contains(entry.key()) {
+                self.new_connections_gauge
+                    .with_label_values(&[entry.key()])
+                    .set(0);
+            }
+        }
+
+        self.window_new_connections.clear();
+        self.udp_flows
+            .retain(|_, seen| seen.elapsed() < UDP_FLOW_TIMEOUT);
+    }
+}
@@ -0,0 +1,280 @@
+// GeoIP / ASN labels from local MaxMind databases
+//
+// When GEOIP_COUNTRY_DB or GEOIP_ASN_DB points at a GeoLite2 (or GeoIP2) mmdb file,
+// download_bytes / upload_bytes get country and asn labels for the remote, so WAN
+// traffic can be broken down by provider without joining against another source.
+// The files are checked every GEOIP_RELOAD_SECS and reloaded when their modification
+// time changes, so a geoipupdate cron job takes effect without a restart.
+// Built without the geoip feature, the settings are reported and ignored.
+
+use crate::network;
+#[cfg(feature = "geoip")]
+use arc_swap::ArcSwapOption;
+#[cfg(feature = "geoip")]
+use dashmap::DashMap;
+#[cfg(feature = "geoip")]
+use maxminddb::Reader;
+#[cfg(feature = "geoip")]
+use prometheus::IntGaugeVec;
+use prometheus::Registry;
+#[cfg(feature = "geoip")]
+use serde::Deserialize;
+#[cfg(feature = "geoip")]
+use std::net::IpAddr;
+#[cfg(feature = "geoip")]
+use std::path::PathBuf;
+#[cfg(feature = "geoip")]
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+#[cfg(feature = "geoip")]
+use std::sync::Mutex;
+use std::time::Duration;
+#[cfg(feature = "geoip")]
+use std::time::SystemTime;
+use tracing::error;
+#[cfg(feature = "geoip")]
+use tracing::info;
+
+// Label value when a database is missing or has no record for the remote
+const UNKNOWN: &str = "unknown";
+// Remotes whose labels are cached at most; the cache is cleared when full
+#[cfg(feature = "geoip")]
+const CACHE_SIZE: usize = 65536;
+
+// Only the fields used for labels are decoded
+#[cfg(feature = "geoip")]
+#[derive(Deserialize)]
+struct CountryRecord<'a> {
+    #[serde(borrow)]
+    country: Option<IsoCode<'a>>,
+    // Anonymous proxies and satellite providers only have a registered country
+    #[serde(borrow)]
+    registered_country: Option<IsoCode<'a>>,
+}
+
+#[cfg(feature = "geoip")]
+#[derive(Deserialize)]
+struct IsoCode<'a> {
+    iso_code: Option<&'a str>,
+}
+
+#[cfg(feature = "geoip")]
+#[derive(Deserialize)]
+struct AsnRecord {
+    autonomous_system_number: Option<u32>,
+}
+
+// One mmdb file and the modification time it was loaded at
+#[cfg(feature = "geoip")]
+struct Database {
+    name: &'static str,
+    path: PathBuf,
+    reader: ArcSwapOption<Reader<Vec<u8>>>,
+    modified: Mutex<Option<SystemTime>>,
+    // Set once a missing file was reported, so the error is not repeated every check
+    missing: AtomicBool,
+}
+
+#[cfg(feature = "geoip")]
+impl Database {
+    fn from_env(name: &'static str, var: &str) -> Option<Self> {
+        let path = network::var(var)
+            .ok()
+            .filter(|path| !path.trim().is_empty())?;
+        Some(Self {
+            name,
+            path: PathBuf::from(path.trim()),
+            reader: ArcSwapOption::empty(),
+            modified: Mutex::new(None),
+            missing: AtomicBool::new(false),
+        })
+    }
+
+    // Load the file if it changed since the last load; true when a new reader is in place
+    fn reload(&self, build_gauge: &IntGaugeVec) -> bool {
+        let modified = match std::fs::metadata(&self.path).and_then(|m| m.modified()) {
+            Ok(modified) => modified,
+            Err(e) => {
+                if !self.missing.swap(true, Ordering::Relaxed) {
+                    error!("Cannot read GeoIP database {}: {}", self.path.display(), e);
+                }
+                return false;
+            }
+        };
+        self.missing.store(false, Ordering::Relaxed);
+        let mut loaded = self.modified.lock().unwrap();
+        if *loaded == Some(modified) {
+            return false;
+        }
+        match Reader::open_readfile(&self.path) {
+            Ok(reader) => {
+                info!(
+                    "Loaded GeoIP {} database {} ({})",
+                    self.name,
+                    self.path.display(),
+                    reader.metadata.database_type
+                );
+                build_gauge
+                    .with_label_values(&[self.name])
+                    .set(reader.metadata.build_epoch as i64);
+                self.reader.store(Some(Arc::new(reader)));
+                *loaded = Some(modified);
+                true
+            }
+            // The previous reader is kept; a file being replaced is retried on the next check
+            Err(e) => {
+                error!(
+                    "Failed to load GeoIP database {}: {}",
+                    self.path.display(),
+                    e
+                );
+                false
+            }
+        }
+    }
+}
+
+#[cfg(feature = "geoip")]
+pub struct GeoIp {
+    country: Option<Database>,
+    asn: Option<Database>,
+    // How often the files are checked for changes (GEOIP_RELOAD_SECS)
+    reload_interval: Duration,
+    // [country, asn] per remote IP
+    cache: DashMap<IpAddr, [Arc<str>; 2]>,
+    build_gauge: IntGaugeVec,
+}
+
+#[cfg(feature = "geoip")]
+impl GeoIp {
+    // None unless GEOIP_COUNTRY_DB or GEOIP_ASN_DB is set
+    pub fn from_env(registry: &Registry) -> Option<Self> {
+        let country = Database::from_env("country", "GEOIP_COUNTRY_DB");
+        let asn = Database::from_env("asn", "GEOIP_ASN_DB");
+        if country.is_none() && asn.is_none() {
+            info!("GeoIP labels: false");
+            return None;
+        }
+        let reload_interval = Duration::from_secs(
+            network::var("GEOIP_RELOAD_SECS")
+                .ok()
+                .and_then(|v| v.trim().parse::<u64>().ok())
+                .filter(|&secs| secs > 0)
+                .unwrap_or(300),
+        );
+
+        let build_gauge = IntGaugeVec::new(
+            prometheus::Opts::new(
+                "geoip_database_build_timestamp_seconds",
+                "Build time of the loaded GeoIP database",
+            )
+            .const_label("job", "localpacketdump"),
+            &["database"],
+        )
+        .expect("failed to create geoip_database_build_timestamp_seconds gauge");
+        registry
+            .register(Box::new(build_gauge.clone()))
+            .expect("failed to register geoip_database_build_timestamp_seconds gauge");
+
+        let geoip = Self {
+            country,
+            asn,
+            reload_interval,
+            cache: DashMap::new(),
+            build_gauge,
+        };
+        geoip.reload();
+        info!(
+            "GeoIP labels: true (checking for updates every {:?})",
+            reload_interval
+        );
+        Some(geoip)
+    }
+
+    // [country, asn] label values, e.g. ["JP", "2516"]
+    pub fn labels(&self, remote_ip: &str) -> [Arc<str>; 2] {
+        let Ok(ip) = remote_ip.parse::<IpAddr>() else {
+            return [UNKNOWN.into(), UNKNOWN.into()];
+        };
+        if let Some(labels) = self.cache.get(&ip) {
+            return labels.clone();
+        }
+        let labels = [self.country_of(ip), self.asn_of(ip)];
+        if self.cache.len() >= CACHE_SIZE {
+            self.cache.clear();
+        }
+        self.cache.insert(ip, labels.clone());
+        labels
+    }
+
+    fn country_of(&self, ip: IpAddr) -> Arc<str> {
+        let reader = self.country.as_ref().and_then(|db| db.reader.load_full());
+        let Some(reader) = reader else {
+            return UNKNOWN.into();
+        };
+        let code = match reader.lookup::<CountryRecord>(ip) {
+            Ok(record) => record
+                .country
+                .or(record.registered_country)
+                .and_then(|country| country.iso_code)
+                .map(Arc::from),
+            Err(_) => None,
+        };
+        code.unwrap_or_else(|| UNKNOWN.into())
+    }
+
+    fn asn_of(&self, ip: IpAddr) -> Arc<str> {
+        let reader = self.asn.as_ref().and_then(|db| db.reader.load_full());
+        let Some(reader) = reader else {
+            return UNKNOWN.into();
+        };
+        match reader.lookup::<AsnRecord>(ip) {
+            Ok(AsnRecord {
+                autonomous_system_number: Some(asn),
+            }) => asn.to_string().into(),
+            _ => UNKNOWN.into(),
+        }
+    }
+
+    // Reload changed files; cached labels are dropped when either database changed
+    pub fn reload(&self) {
+        let mut changed = false;
+        for db in [&self.country, &self.asn].into_iter().flatten() {
+            changed |= db.reload(&self.build_gauge);
+        }
+        if changed {
+            self.cache.clear();
+        }
+    }
+
+    pub fn reload_interval(&self) -> Duration {
+        self.reload_interval
+    }
+}
+
+// Without the geoip feature the databases cannot be read, so configured ones are reported
+// and the labels stay off
+#[cfg(not(feature = "geoip"))]
+pub struct GeoIp;
+
+#[cfg(not(feature = "geoip"))]
+impl GeoIp {
+    pub fn from_env(_registry: &Registry) -> Option<Self> {
+        for var in ["GEOIP_COUNTRY_DB", "GEOIP_ASN_DB"] {
+            if network::var(var).is_ok_and(|path| !path.trim().is_empty()) {
+                error!("{} is ignored: built without the geoip feature", var);
+            }
+        }
+        None
+    }
+
+    pub fn labels(&self, _remote_ip: &str) -> [Arc<str>; 2] {
+        [UNKNOWN.into(), UNKNOWN.into()]
+    }
+
+    pub fn reload(&self) {}
+
+    pub fn reload_interval(&self) -> Duration {
+        Duration::MAX
+    }
+}
@@ -0,0 +1,269 @@
+// Liveness and readiness for /healthz and /readyz
+//
+// When a datalink channel dies the exporter keeps serving the last gauges and nothing
+// notices. Every capture thread reports its state and the time of its last frame here.
+// /healthz fails when a capture is failing or an open capture has seen no frame for
+// HEALTH_MAX_PACKET_AGE_SECS, so a supervisor can restart a wedged capture; /readyz also
+// requires the status API to answer (fewer than HEALTH_STATUS_MAX_FAILURES consecutive
+// failed fetches), since packets are attributed to interface "unknown" without it.
+// The 1-second publish loop checks in too; a loop stuck for PUBLISH_STALL fails /healthz.
+// The systemd watchdog ignores packet age, so a quiet link does not get the service restarted;
+// it only needs the publish loops and the capture loops (which wake every second even on an
+// idle link) to keep running.
+
+use crate::network;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// The publish loop runs every second; allow for a slow remote_write or inventory tick
+const PUBLISH_STALL: Duration = Duration::from_secs(10);
+// Capture loops wake at least every second through their read or poll timeout
+const CAPTURE_STALL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "state", rename_all = "lowercase")]
+pub enum CaptureStatus {
+    Starting,
+    Open,
+    Paused,
+    // Retrying after the interface, channel or ring could not be used
+    Failed { error: String },
+    // Stopped by a reload or shutdown
+    Closed,
+}
+
+// One capture thread
+pub struct CaptureHealth {
+    // "lan", or the WAN role of a capture point
+    role: String,
+    interface: String,
+    // Ring worker index
+    worker: Option<usize>,
+    started: Instant,
+    state: Mutex<CaptureStatus>,
+    // Milliseconds since `started`, 0 before the first one
+    opened_ms: AtomicU64,
+    last_frame_ms: AtomicU64,
+    // Last pass of the capture loop, with or without a frame
+    polled_ms: AtomicU64,
+}
+
+impl CaptureHealth {
+    pub fn set(&self, state: CaptureStatus) {
+        if matches!(state, CaptureStatus::Open) {
+            self.opened_ms.store(self.now_ms(), Ordering::Relaxed);
+        }
+        *self.state.lock().unwrap() = state;
+    }
+
+    pub fn failed(&self, error: impl ToString) {
+        self.set(CaptureStatus::Failed {
+            error: error.to_string(),
+        });
+    }
+
+    // Called for every frame read, parsed or not
+    pub fn frame(&self) {
+        self.last_frame_ms.store(self.now_ms(), Ordering::Relaxed);
+    }
+
+    // Called on every pass of the capture loop, including read timeouts on an idle link
+    pub fn polled(&self) {
+        self.polled_ms.store(self.now_ms(), Ordering::Relaxed);
+    }
+
+    // An open capture whose loop has not come round for CAPTURE_STALL is stuck
+    fn stuck(&self) -> bool {
+        if !matches!(*self.state.lock().unwrap(), CaptureStatus::Open) {
+            return false;
+        }
+        let since = self
+            .polled_ms
+            .load(Ordering::Relaxed)
+            .max(self.opened_ms.load(Ordering::Relaxed));
+        Duration::from_millis(self.now_ms().saturating_sub(since)) > CAPTURE_STALL
+    }
+
+    fn now_ms(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64 + 1
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CaptureReport {
+    role: String,
+    interface: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    worker: Option<usize>,
+    #[serde(flatten)]
+    state: CaptureStatus,
+    // None when no frame has been read yet
+    last_frame_secs_ago: Option<f64>,
+    healthy: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StatusReport {
+    // A response is available, fetched or loaded from the cache
+    available: bool,
+    consecutive_failures: u32,
+    healthy: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HealthReport {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    network: Option<String>,
+    captures: Vec<CaptureReport>,
+    status: StatusReport,
+    // Since the publish loop last ran
+    publish_secs_ago: f64,
+    // Captures and the publish loop only; the status API does not make the process unhealthy
+    pub live: bool,
+    pub ready: bool,
+}
+
+pub struct Health {
+    // None when HEALTH_MAX_PACKET_AGE_SECS is 0, for links that may go quiet
+    max_packet_age: Option<Duration>,
+    status_max_failures: u32,
+    captures: Mutex<Vec<Arc<CaptureHealth>>>,
+    started: Instant,
+    // Milliseconds since `started`, 0 before the first publish
+    published_ms: AtomicU64,
+}
+
+impl Health {
+    pub fn from_env() -> Self {
+        let max_packet_age = network::var("HEALTH_MAX_PACKET_AGE_SECS")
+            .ok()
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .unwrap_or(60);
+        let status_max_failures = network::var("HEALTH_STATUS_MAX_FAILURES")
+            .ok()
+            .and_then(|v| v.trim().parse::<u32>().ok())
+            .unwrap_or(3)
+            .max(1);
+        Self {
+            max_packet_age: (max_packet_age > 0).then(|| Duration::from_secs(max_packet_age)),
+            status_max_failures,
+            captures: Mutex::new(Vec::new()),
+            started: Instant::now(),
+            published_ms: AtomicU64::new(0),
+        }
+    }
+
+    // Called at the end of every publish loop iteration
+    pub fn published(&self) {
+        let now_ms = self.started.elapsed().as_millis() as u64 + 1;
+        self.published_ms.store(now_ms, Ordering::Relaxed);
+    }
+
+    // Some capture has not opened its interface yet
+    pub fn starting(&self) -> bool {
+        self.captures
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|capture| matches!(*capture.state.lock().unwrap(), CaptureStatus::Starting))
+    }
+
+    // Called by every capture thread before it opens its interface
+    pub fn register(
+        &self,
+        role: &str,
+        interface: &str,
+        worker: Option<usize>,
+    ) -> Arc<CaptureHealth> {
+        let capture = Arc::new(CaptureHealth {
+            role: role.to_string(),
+            interface: interface.to_string(),
+            worker,
+            started: Instant::now(),
+            state: Mutex::new(CaptureStatus::Starting),
+            opened_ms: AtomicU64::new(0),
+            last_frame_ms: AtomicU64::new(0),
+            polled_ms: AtomicU64::new(0),
+        });
+        self.captures.lock().unwrap().push(capture.clone());
+        capture
+    }
+
+    pub fn report(
+        &self,
+        network: Option<&str>,
+        status_available: bool,
+        consecutive_failures: u32,
+    ) -> HealthReport {
+        let mut captures = self.captures.lock().unwrap();
+        // Threads of a previous capture end shortly after a reload
+        captures.retain(|capture| !matches!(*capture.state.lock().unwrap(), CaptureStatus::Closed));
+        let captures: Vec<CaptureReport> = captures
+            .iter()
+            .map(|capture| self.capture_report(capture))
+            .collect();
+        let status = StatusReport {
+            available: status_available,
+            consecutive_failures,
+            healthy: status_available && consecutive_failures < self.status_max_failures,
+        };
+        let publish_age = self.publish_age();
+        let live = captures.iter().all(|capture| capture.healthy) && publish_age <= PUBLISH_STALL;
+        HealthReport {
+            network: network.map(str::to_string),
+            ready: live && status.healthy,
+            live,
+            captures,
+            status,
+            publish_secs_ago: publish_age.as_secs_f64(),
+        }
+    }
+
+    // Whether the systemd watchdog may be petted: the publish loop and every capture loop
+    // keep running, however long the link has been quiet
+    pub fn watchdog_ok(&self) -> bool {
+        self.publish_age() <= PUBLISH_STALL
+            && !self
+                .captures
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|capture| capture.stuck())
+    }
+
+    // Counted from startup until the first publish
+    fn publish_age(&self) -> Duration {
+        let since = Duration::from_millis(self.published_ms.load(Ordering::Relaxed));
+        self.started.elapsed().saturating_sub(since)
+    }
+
+    fn capture_report(&self, capture: &CaptureHealth) -> CaptureReport {
+        let state = capture.state.lock().unwrap().clone();
+        let now_ms = capture.now_ms();
+        let age = |ms: u64| (ms > 0).then(|| Duration::from_millis(now_ms.saturating_sub(ms)));
+        let last_frame = age(capture.last_frame_ms.load(Ordering::Relaxed));
+        let healthy = match state {
+            CaptureStatus::Open => {
+                // Counted from when the capture was (re)opened until it reads a frame
+                let since = capture
+                    .last_frame_ms
+                    .load(Ordering::Relaxed)
+                    .max(capture.opened_ms.load(Ordering::Relaxed));
+                let quiet = age(since).unwrap_or_default();
+                self.max_packet_age.is_none_or(|max| quiet <= max)
+            }
+            CaptureStatus::Failed { .. } => false,
+            CaptureStatus::Starting | CaptureStatus::Paused | CaptureStatus::Closed => true,
+        };
+        CaptureReport {
+            role: capture.role.clone(),
+            interface: capture.interface.clone(),
+            worker: capture.worker,
+            state,
+            last_frame_secs_ago: last_frame.map(|age| age.as_secs_f64()),
+            healthy,
+        }
+    }
+}
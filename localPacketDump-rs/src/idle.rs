@@ -0,0 +1,144 @@
+// Idle label sets
+//
+// A label set that stops seeing traffic keeps being published as 0 or is dropped
+// according to IDLE_POLICY, and is dropped regardless once it has been idle for
+// IDLE_TTL_SECS. The byte gauges, the per-device gauges and the TCP quality gauges
+// all follow the same settings.
+
+use crate::network;
+use dashmap::DashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{error, info};
+
+// What happens to a label set once it stops seeing traffic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdlePolicy {
+    // Keep publishing 0 forever (default)
+    Zero,
+    // Publish 0 for this many idle windows, then drop the series
+    Expire(u32),
+    // Drop the series as soon as a window has no traffic for it
+    Absent,
+}
+
+impl IdlePolicy {
+    fn from_env() -> Self {
+        match network::var("IDLE_POLICY") {
+            Ok(v) => match v.trim().to_ascii_lowercase().as_str() {
+                "zero" => IdlePolicy::Zero,
+                "expire" => {
+                    let windows = network::var("IDLE_EXPIRE_WINDOWS")
+                        .ok()
+                        .and_then(|v| v.trim().parse::<u32>().ok())
+                        .unwrap_or(60);
+                    IdlePolicy::Expire(windows)
+                }
+                "absent" => IdlePolicy::Absent,
+                other => {
+                    error!("Unknown IDLE_POLICY {}, falling back to zero", other);
+                    IdlePolicy::Zero
+                }
+            },
+            Err(_) => IdlePolicy::Zero,
+        }
+    }
+
+    // Number of idle windows after which a series is dropped
+    fn max_idle_windows(&self) -> Option<u32> {
+        match self {
+            IdlePolicy::Zero => None,
+            IdlePolicy::Expire(windows) => Some(*windows),
+            IdlePolicy::Absent => Some(0),
+        }
+    }
+}
+
+// How long a published label set has gone without traffic
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IdleAge {
+    windows: u32,
+    // Summed window lengths, so scrape-driven windows age by wall time
+    elapsed: Duration,
+}
+
+// IDLE_POLICY and IDLE_TTL_SECS
+#[derive(Debug, Clone, Copy)]
+pub struct IdleSettings {
+    policy: IdlePolicy,
+    // Drop label sets idle for this long regardless of the policy
+    ttl: Option<Duration>,
+}
+
+impl IdleSettings {
+    pub fn from_env() -> Self {
+        let policy = IdlePolicy::from_env();
+        info!("Idle series policy: {:?}", policy);
+        let ttl = network::var("IDLE_TTL_SECS")
+            .ok()
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .filter(|&secs| secs > 0)
+            .map(Duration::from_secs);
+        if let Some(ttl) = ttl {
+            info!("Idle series TTL: {:?}", ttl);
+        }
+        Self { policy, ttl }
+    }
+
+    // Age an idle label set by one window of `elapsed`; true once it should be dropped
+    pub fn age(&self, age: &mut IdleAge, elapsed: Duration) -> bool {
+        age.windows += 1;
+        age.elapsed += elapsed;
+        let windows_exceeded = self
+            .policy
+            .max_idle_windows()
+            .is_some_and(|max| age.windows > max);
+        let ttl_exceeded = self.ttl.is_some_and(|ttl| age.elapsed >= ttl);
+        windows_exceeded || ttl_exceeded
+    }
+}
+
+// Label sets a tracker publishes, with how long each has been idle
+pub struct IdleSeries<K> {
+    settings: IdleSettings,
+    ages: DashMap<K, IdleAge>,
+    last_sweep: Mutex<Instant>,
+}
+
+impl<K: Eq + Hash + Clone> IdleSeries<K> {
+    pub fn new(settings: IdleSettings) -> Self {
+        Self {
+            settings,
+            ages: DashMap::new(),
+            last_sweep: Mutex::new(Instant::now()),
+        }
+    }
+
+    // The label set had traffic in this window
+    pub fn seen(&self, key: &K) {
+        self.ages.insert(key.clone(), IdleAge::default());
+    }
+
+    // Age the label sets without traffic in this window (`active` is false for them).
+    // `zero` is called for those still published, `drop` for those that just expired.
+    pub fn sweep(&self, active: impl Fn(&K) -> bool, zero: impl Fn(&K), drop: impl Fn(&K)) {
+        let elapsed = {
+            let mut last = self.last_sweep.lock().unwrap();
+            let elapsed = last.elapsed();
+            *last = Instant::now();
+            elapsed
+        };
+        self.ages.retain(|key, age| {
+            if active(key) {
+                return true;
+            }
+            if self.settings.age(age, elapsed) {
+                drop(key);
+                return false;
+            }
+            zero(key);
+            true
+        });
+    }
+}
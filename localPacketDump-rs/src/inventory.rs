@@ -0,0 +1,290 @@
+// Inventory of remote endpoints
+//
+// Keeps, per remote address, when it was first and last seen, the bytes exchanged with it
+// and the interfaces used, so an audit can answer "what has this network talked to".
+// The inventory outlives the byte gauges (which only describe the last second), is saved
+// to REMOTE_INVENTORY_FILE every minute and is served at /remotes.
+
+use crate::network;
+use dashmap::DashMap;
+use prometheus::{IntCounter, IntGauge, Registry};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicI64, Ordering};
+use tracing::{info, warn};
+
+// Largest page /remotes returns
+const MAX_PAGE_SIZE: usize = 1000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoteEntry {
+    // Unix epoch milliseconds, at window resolution
+    pub first_seen_ms: i64,
+    pub last_seen_ms: i64,
+    pub download_bytes: u64,
+    pub upload_bytes: u64,
+    pub interfaces: BTreeSet<String>,
+}
+
+// One remote in a /remotes page and in the saved file
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RemoteRecord {
+    pub remote_ip: String,
+    #[serde(flatten)]
+    pub entry: RemoteEntry,
+    #[serde(default)]
+    pub total_bytes: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RemotesPage {
+    // Remotes matching the filters, before paging
+    pub total: usize,
+    pub offset: usize,
+    pub limit: usize,
+    pub remotes: Vec<RemoteRecord>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SortKey {
+    // Most recently seen first (default)
+    #[default]
+    LastSeen,
+    // Most recently discovered first
+    FirstSeen,
+    // Most bytes first
+    Bytes,
+}
+
+// Query parameters of /remotes
+#[derive(Debug, Default, Deserialize)]
+pub struct RemotesQuery {
+    // Only remotes used over this interface
+    pub interface: Option<String>,
+    // Only remotes inside this CIDR, e.g. 203.0.113.0/24
+    pub prefix: Option<String>,
+    // Only remotes seen at or after this time (Unix epoch milliseconds)
+    pub seen_since_ms: Option<i64>,
+    // Only remotes first seen at or after this time (Unix epoch milliseconds)
+    pub new_since_ms: Option<i64>,
+    // Only remotes with at least this many bytes in total
+    pub min_bytes: Option<u64>,
+    #[serde(default)]
+    pub sort: SortKey,
+    pub offset: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+pub struct Inventory {
+    path: PathBuf,
+    entries: DashMap<String, RemoteEntry>,
+    // Remotes beyond this are not added
+    max_entries: usize,
+    // Entries not seen for this long are dropped when saving
+    retention_ms: i64,
+    // Time stamped on packets, advanced once per window instead of read per packet
+    now_ms: AtomicI64,
+    entries_gauge: IntGauge,
+    dropped: IntCounter,
+}
+
+impl Inventory {
+    // None unless REMOTE_INVENTORY is enabled
+    pub fn from_env(registry: &Registry) -> Option<Self> {
+        let enabled = matches!(
+            network::var("REMOTE_INVENTORY")
+                .map(|v| v.trim().to_ascii_lowercase())
+                .as_deref(),
+            Ok("1") | Ok("true")
+        );
+        if !enabled {
+            return None;
+        }
+        // One file per network so networks of the same process do not overwrite each other
+        let path = PathBuf::from(network::var("REMOTE_INVENTORY_FILE").unwrap_or_else(|_| {
+            match network::current() {
+                Some(name) => format!("remote_inventory-{}.json", name),
+                None => "remote_inventory.json".to_string(),
+            }
+        }));
+        let max_entries = network::var("REMOTE_INVENTORY_MAX_ENTRIES")
+            .ok()
+            .and_then(|v| v.trim().parse::<usize>().ok())
+            .unwrap_or(100_000);
+        let retention_days = network::var("REMOTE_INVENTORY_RETENTION_DAYS")
+            .ok()
+            .and_then(|v| v.trim().parse::<i64>().ok())
+            .unwrap_or(90);
+
+        let entries_gauge = IntGauge::with_opts(
+            prometheus::Opts::new(
+                "remote_inventory_entries",
+                "Remote endpoints in the inventory",
+            )
+            .const_label("job", "localpacketdump"),
+        )
+        .expect("failed to create remote_inventory_entries gauge");
+        registry
+            .register(Box::new(entries_gauge.clone()))
+            .expect("failed to register remote_inventory_entries gauge");
+        let dropped = IntCounter::with_opts(
+            prometheus::Opts::new(
+                "remote_inventory_dropped_total",
+                "Packets to new remotes not added because the inventory was full",
+            )
+            .const_label("job", "localpacketdump"),
+        )
+        .expect("failed to create remote_inventory_dropped_total counter");
+        registry
+            .register(Box::new(dropped.clone()))
+            .expect("failed to register remote_inventory_dropped_total counter");
+
+        let inventory = Self {
+            path,
+            entries: DashMap::new(),
+            max_entries,
+            retention_ms: retention_days * 24 * 60 * 60 * 1000,
+            now_ms: AtomicI64::new(chrono::Utc::now().timestamp_millis()),
+            entries_gauge,
+            dropped,
+        };
+        inventory.load();
+        Some(inventory)
+    }
+
+    fn load(&self) {
+        let content = match std::fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(_) => return,
+        };
+        match serde_json::from_str::<Vec<RemoteRecord>>(&content) {
+            Ok(saved) => {
+                for record in saved {
+                    self.entries.insert(record.remote_ip, record.entry);
+                }
+                self.entries_gauge.set(self.entries.len() as i64);
+                info!("Loaded {} remotes from {:?}", self.entries.len(), self.path);
+            }
+            Err(e) => warn!("Failed to parse {:?}, starting empty: {}", self.path, e),
+        }
+    }
+
+    // Bytes exchanged with a remote over an interface
+    pub fn record(&self, remote_ip: &str, interface: &str, bytes: u64, download: bool) {
+        let now_ms = self.now_ms.load(Ordering::Relaxed);
+        let add = |entry: &mut RemoteEntry| {
+            entry.last_seen_ms = now_ms;
+            if download {
+                entry.download_bytes += bytes;
+            } else {
+                entry.upload_bytes += bytes;
+            }
+            if !entry.interfaces.contains(interface) {
+                entry.interfaces.insert(interface.to_string());
+            }
+        };
+        if let Some(mut entry) = self.entries.get_mut(remote_ip) {
+            add(&mut entry);
+            return;
+        }
+        if self.entries.len() >= self.max_entries {
+            self.dropped.inc();
+            return;
+        }
+        let mut entry = self
+            .entries
+            .entry(remote_ip.to_string())
+            .or_insert_with(|| RemoteEntry {
+                first_seen_ms: now_ms,
+                last_seen_ms: now_ms,
+                download_bytes: 0,
+                upload_bytes: 0,
+                interfaces: BTreeSet::new(),
+            });
+        add(&mut entry);
+    }
+
+    // Called once per window
+    pub fn tick(&self) {
+        self.now_ms
+            .store(chrono::Utc::now().timestamp_millis(), Ordering::Relaxed);
+        self.entries_gauge.set(self.entries.len() as i64);
+    }
+
+    // Drop remotes past the retention and write the rest, replacing the file atomically
+    pub fn save(&self) -> std::io::Result<()> {
+        let oldest = chrono::Utc::now().timestamp_millis() - self.retention_ms;
+        self.entries.retain(|_, entry| entry.last_seen_ms >= oldest);
+        let records: Vec<RemoteRecord> = self
+            .entries
+            .iter()
+            .map(|entry| record(entry.key(), entry.value()))
+            .collect();
+        let content = serde_json::to_vec(&records)?;
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, content)?;
+        std::fs::rename(&tmp, &self.path)
+    }
+
+    pub fn query(&self, query: &RemotesQuery) -> Result<RemotesPage, String> {
+        let prefix = query
+            .prefix
+            .as_deref()
+            .map(|p| {
+                p.parse::<ipnetwork::IpNetwork>()
+                    .map_err(|_| format!("invalid prefix '{}'", p))
+            })
+            .transpose()?;
+
+        let mut matched: Vec<RemoteRecord> = self
+            .entries
+            .iter()
+            .filter(|entry| {
+                let (remote_ip, entry) = (entry.key(), entry.value());
+                query
+                    .interface
+                    .as_ref()
+                    .is_none_or(|i| entry.interfaces.contains(i))
+                    && prefix
+                        .is_none_or(|prefix| remote_ip.parse().is_ok_and(|ip| prefix.contains(ip)))
+                    && query
+                        .seen_since_ms
+                        .is_none_or(|since| entry.last_seen_ms >= since)
+                    && query
+                        .new_since_ms
+                        .is_none_or(|since| entry.first_seen_ms >= since)
+                    && query
+                        .min_bytes
+                        .is_none_or(|min| entry.download_bytes + entry.upload_bytes >= min)
+            })
+            .map(|entry| record(entry.key(), entry.value()))
+            .collect();
+
+        match query.sort {
+            SortKey::LastSeen => matched.sort_by_key(|r| std::cmp::Reverse(r.entry.last_seen_ms)),
+            SortKey::FirstSeen => matched.sort_by_key(|r| std::cmp::Reverse(r.entry.first_seen_ms)),
+            SortKey::Bytes => matched.sort_by_key(|r| std::cmp::Reverse(r.total_bytes)),
+        }
+
+        let total = matched.len();
+        let offset = query.offset.unwrap_or(0);
+        let limit = query.limit.unwrap_or(100).min(MAX_PAGE_SIZE);
+        let remotes = matched.into_iter().skip(offset).take(limit).collect();
+        Ok(RemotesPage {
+            total,
+            offset,
+            limit,
+            remotes,
+        })
+    }
+}
+
+fn record(remote_ip: &str, entry: &RemoteEntry) -> RemoteRecord {
+    RemoteRecord {
+        remote_ip: remote_ip.to_string(),
+        entry: entry.clone(),
+        total_bytes: entry.download_bytes + entry.upload_bytes,
+    }
+}
@@ -1,32 +1,437 @@
+mod amplification;
+mod anomaly;
+mod burst;
+mod capture;
+mod crosscheck;
+mod devices;
+mod filter;
+mod fingerprint;
+#[cfg(feature = "flow-export")]
+mod flow_export;
+mod flows;
+mod geoip;
+mod health;
+mod idle;
+mod inventory;
+mod multicast;
+mod network;
+mod persist;
+mod protocols;
+mod rdns;
+#[cfg(target_os = "linux")]
+mod ring;
+mod sampling;
+mod segments;
+mod serve;
+mod simulate;
+mod status;
+mod stream;
+mod targets;
+mod tcp_quality;
+mod top;
+mod transition;
+mod tunnel;
+
+use amplification::AmplificationDetector;
+use anomaly::AnomalyDetector;
+use arc_swap::{ArcSwap, ArcSwapOption};
 use axum::{response::IntoResponse, routing::get, Router};
+use burst::BurstTracker;
+use capture::{CapturedPacket, Transport};
+use crossbeam_channel::{Receiver, Sender, TrySendError};
+use crosscheck::{CrossCheck, Side};
 use dashmap::DashMap;
+use devices::Devices;
+use filter::{BpfInstruction, DenyList};
+use fingerprint::Fingerprints;
+#[cfg(feature = "flow-export")]
+use flow_export::FlowExporter;
+use flows::FlowTracker;
+use geoip::GeoIp;
+use health::{CaptureHealth, CaptureStatus, Health};
+use idle::{IdleAge, IdleSettings};
+use inventory::Inventory;
+use multicast::MulticastTracker;
+use persist::FlowStore;
 use pnet::datalink::{self, NetworkInterface};
-use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
-use pnet::packet::ipv4::Ipv4Packet;
-use pnet::packet::ipv6::Ipv6Packet;
-use pnet::packet::Packet;
-use prometheus::{Encoder, IntGaugeVec, Registry, TextEncoder};
-use serde::Deserialize;
-use std::collections::{HashMap, HashSet};
-use std::env;
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use prometheus::{
+    proto::MetricFamily, Gauge, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec,
+    IntGauge, IntGaugeVec, Registry,
+};
+use protocols::{ProtocolAction, ProtocolPolicy};
+use rdns::ReverseDns;
+use sampling::AdaptiveSampler;
+use segments::Segments;
+use serde::{Deserialize, Serialize};
+use shared_cidr::IpSet;
+use shared_http::HttpClient;
+use shared_schema::{
+    build_info, pipeline, BuildInfo, StatusResponse, LABELS_SCHEMA_VERSION, LABEL_ASN,
+    LABEL_COUNTRY, LABEL_DSCP_CLASS, LABEL_INTERFACE, LABEL_LOCAL_IP, LABEL_NETWORK,
+    LABEL_PROTOCOL, LABEL_REMOTE_IP, LABEL_REMOTE_PREFIX, LABEL_VLAN, SCHEMA_VERSION_HEADER,
+    SCHEMA_VERSION_QUERY, STATUS_SCHEMA_VERSION, WINDOW_SCHEMA_VERSION,
+};
+use status::StatusTracker;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::IntoFuture;
 use std::net::IpAddr;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+use stream::{LiveStream, StreamFilter};
+use tcp_quality::TcpQuality;
 use tokio::task;
 use tokio::time::Duration;
+use top::TopTalkers;
 use tracing::{error, info, warn};
+use traffic_scan_core::auth::constant_time_eq;
+use traffic_scan_core::{router, server, MetricFilter, RemoteWrite};
+use transition::Transition;
+use tunnel::Tunnels;
+
+// Capture thread tuning
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+struct CaptureTuning {
+    // CPU core to pin the capture thread to (CAPTURE_CPU)
+    cpu: Option<usize>,
+    // SO_BUSY_POLL timeout in microseconds for the capture socket (BUSY_POLL_USECS)
+    busy_poll_usecs: Option<u32>,
+    // How frames are read from the interface (CAPTURE_BACKEND)
+    backend: CaptureBackend,
+    // Number of ring capture threads sharing a fanout group (CAPTURE_WORKERS)
+    workers: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum CaptureBackend {
+    // TPACKET_V3 ring on Linux, pnet elsewhere or if the ring cannot be set up
+    #[default]
+    Auto,
+    // pnet's datalink channel, one recvfrom per frame
+    Pnet,
+}
+
+impl CaptureTuning {
+    fn from_env() -> Self {
+        let cpu = network::var("CAPTURE_CPU")
+            .ok()
+            .and_then(|v| match v.trim().parse::<usize>() {
+                Ok(cpu) => Some(cpu),
+                Err(e) => {
+                    error!("Failed to parse CAPTURE_CPU {}: {}", v, e);
+                    None
+                }
+            });
+        let busy_poll_usecs =
+            network::var("BUSY_POLL_USECS")
+                .ok()
+                .and_then(|v| match v.trim().parse::<u32>() {
+                    Ok(0) => None,
+                    Ok(usecs) => Some(usecs),
+                    Err(e) => {
+                        error!("Failed to parse BUSY_POLL_USECS {}: {}", v, e);
+                        None
+                    }
+                });
+        let backend = match network::var("CAPTURE_BACKEND").as_deref().map(str::trim) {
+            Err(_) | Ok("") | Ok("auto") => CaptureBackend::Auto,
+            Ok("pnet") => CaptureBackend::Pnet,
+            Ok(other) => {
+                error!("Unknown CAPTURE_BACKEND {}, using auto", other);
+                CaptureBackend::Auto
+            }
+        };
+        let workers = network::var("CAPTURE_WORKERS")
+            .ok()
+            .and_then(|v| match v.trim().parse::<usize>() {
+                Ok(workers) => Some(workers.max(1)),
+                Err(e) => {
+                    error!("Failed to parse CAPTURE_WORKERS {}: {}", v, e);
+                    None
+                }
+            })
+            .unwrap_or(1);
+        Self {
+            cpu,
+            busy_poll_usecs,
+            backend,
+            workers,
+        }
+    }
+}
+
+// Where and how packets are captured; the capture is restarted when a reload changes it
+#[derive(Debug, Clone, PartialEq)]
+struct CaptureSettings {
+    interface_name: String,
+    tuning: CaptureTuning,
+    filter: Option<Vec<BpfInstruction>>,
+}
+
+impl CaptureSettings {
+    fn from_env() -> Self {
+        // The lan role of CAPTURE_POINTS names the same interface
+        let interface_name = crosscheck::lan_interface()
+            .or_else(|| network::var("INTERFACE_NAME").ok())
+            .unwrap_or_else(|| "eth2".to_string());
+        let filter = filter::program_from_env(&interface_name);
+        Self {
+            interface_name,
+            tuning: CaptureTuning::from_env(),
+            filter,
+        }
+    }
+}
+
+// Parse local CIDR ranges from environment variable
+// Default is 10.40.0.0/20 - adjust based on your local network
+fn local_cidrs_from_env() -> IpSet {
+    let local_cidrs_str =
+        network::var("LOCAL_CIDRS").unwrap_or_else(|_| "10.40.0.0/20".to_string());
+    local_cidrs_str
+        .split(',')
+        .filter_map(|cidr| match ipnetwork::IpNetwork::from_str(cidr.trim()) {
+            Ok(net) => {
+                info!("Configured local CIDR: {}", net);
+                Some(net)
+            }
+            Err(e) => {
+                error!("Failed to parse local CIDR {}: {}", cidr, e);
+                None
+            }
+        })
+        .collect()
+}
+
+fn status_url_from_env() -> String {
+    network::var("STATUS_URL").unwrap_or_else(|_| "http://localhost:32599/status".to_string())
+}
+
+// Which address of a packet becomes the label of the byte gauges
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Perspective {
+    // Keyed by the remote IP (default, consumed by icmp-traffic-scan / throughput-dump)
+    Remote,
+    // Keyed by the local device IP
+    Local,
+    // Keyed by both the remote IP and the local device IP
+    Both,
+}
+
+impl Perspective {
+    fn from_env() -> Self {
+        match network::var("PERSPECTIVE") {
+            Ok(v) => match v.trim().to_ascii_lowercase().as_str() {
+                "remote" => Perspective::Remote,
+                "local" => Perspective::Local,
+                "both" => Perspective::Both,
+                other => {
+                    error!("Unknown PERSPECTIVE {}, falling back to remote", other);
+                    Perspective::Remote
+                }
+            },
+            Err(_) => Perspective::Remote,
+        }
+    }
+
+    // With `protocol` / `vlan` / `dscp` / `geo`, trailing protocol, vlan, dscp_class and
+    // country + asn labels are added (PROTOCOL_LABELS / VLAN_LABELS / DSCP_LABELS / GEOIP_*_DB)
+    fn label_names(&self, protocol: bool, vlan: bool, dscp: bool, geo: bool) -> Vec<&'static str> {
+        let mut names = match self {
+            Perspective::Remote => vec![LABEL_REMOTE_IP, LABEL_INTERFACE],
+            Perspective::Local => vec![LABEL_LOCAL_IP, LABEL_INTERFACE],
+            Perspective::Both => vec![LABEL_REMOTE_IP, LABEL_LOCAL_IP, LABEL_INTERFACE],
+        };
+        if protocol {
+            names.push(LABEL_PROTOCOL);
+        }
+        if vlan {
+            names.push(LABEL_VLAN);
+        }
+        if dscp {
+            names.push(LABEL_DSCP_CLASS);
+        }
+        if geo {
+            names.extend([LABEL_COUNTRY, LABEL_ASN]);
+        }
+        names
+    }
+
+    // Label values in the same order as label_names(); `optional` holds the enabled ones of
+    // protocol, vlan and dscp_class in that order
+    fn label_values(
+        &self,
+        remote_ip: &str,
+        local_ip: &str,
+        interface: String,
+        optional: &[&str],
+        geo: Option<[Arc<str>; 2]>,
+    ) -> Vec<String> {
+        let mut values = match self {
+            Perspective::Remote => vec![remote_ip.to_string(), interface],
+            Perspective::Local => vec![local_ip.to_string(), interface],
+            Perspective::Both => vec![remote_ip.to_string(), local_ip.to_string(), interface],
+        };
+        values.extend(optional.iter().map(|value| value.to_string()));
+        if let Some([country, asn]) = geo {
+            values.extend([country.to_string(), asn.to_string()]);
+        }
+        values
+    }
+}
+
+// Remote addresses bucketed into prefixes to bound the series count
+// (AGGREGATE_PREFIX_V4 / AGGREGATE_PREFIX_V6); off unless one of them is set
+#[derive(Debug, Clone, Copy, Default)]
+struct RemoteAggregation {
+    v4: Option<u8>,
+    v6: Option<u8>,
+}
+
+impl RemoteAggregation {
+    fn from_env() -> Self {
+        let prefix = |name: &str, max: u8| {
+            let value = network::var(name).ok()?;
+            match value.trim().parse::<u8>() {
+                Ok(len) if len <= max => Some(len),
+                _ => {
+                    error!("Invalid {} {}, not aggregating", name, value);
+                    None
+                }
+            }
+        };
+        Self {
+            v4: prefix("AGGREGATE_PREFIX_V4", 32),
+            v6: prefix("AGGREGATE_PREFIX_V6", 128),
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.v4.is_some() || self.v6.is_some()
+    }
+
+    // The remote label is remote_prefix instead of remote_ip while aggregating
+    fn relabel(&self, mut names: Vec<&'static str>) -> Vec<&'static str> {
+        if self.enabled() {
+            for name in names.iter_mut().filter(|name| **name == LABEL_REMOTE_IP) {
+                *name = LABEL_REMOTE_PREFIX;
+            }
+        }
+        names
+    }
+
+    // Prefix containing the remote, e.g. "203.0.113.0/24"; a family without a configured
+    // length keeps the full address (/32 or /128) so every value is a prefix
+    fn bucket(&self, remote_ip: &str) -> String {
+        let Ok(addr) = remote_ip.parse::<IpAddr>() else {
+            return remote_ip.to_string();
+        };
+        let len = match addr {
+            IpAddr::V4(_) => self.v4.unwrap_or(32),
+            IpAddr::V6(_) => self.v6.unwrap_or(128),
+        };
+        match ipnetwork::IpNetwork::new(addr, len) {
+            Ok(network) => format!("{}/{}", network.network(), len),
+            Err(_) => remote_ip.to_string(),
+        }
+    }
+}
+
+// How long shutdown waits for the processing thread to account the queued packets, and
+// for the HTTP connections to finish once the server stops
+const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long /metrics keeps serving the final window before the server stops
+// (SHUTDOWN_GRACE_SECS), so Prometheus can scrape it
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+// Value of the protocol label
+fn protocol_name(protocol: IpNextHeaderProtocol) -> &'static str {
+    match protocol {
+        IpNextHeaderProtocols::Tcp => "tcp",
+        IpNextHeaderProtocols::Udp => "udp",
+        IpNextHeaderProtocols::Icmp | IpNextHeaderProtocols::Icmpv6 => "icmp",
+        _ => "other",
+    }
+}
+
+// Value of the dscp_class label: the RFC 4594 / 8622 name, or DSCP<n> for other code points
+fn dscp_class(dscp: u8) -> Cow<'static, str> {
+    match dscp {
+        0 => "BE".into(),
+        1 => "LE".into(),
+        44 => "VA".into(),
+        46 => "EF".into(),
+        // AF11..AF43: class in the top three bits, drop precedence in the next two
+        10 | 12 | 14 | 18 | 20 | 22 | 26 | 28 | 30 | 34 | 36 | 38 => {
+            format!("AF{}{}", dscp >> 3, (dscp & 7) >> 1).into()
+        }
+        8 | 16 | 24 | 32 | 40 | 48 | 56 => format!("CS{}", dscp >> 3).into(),
+        _ => format!("DSCP{}", dscp).into(),
+    }
+}
+
+// When the byte window is closed and published
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WindowAlignment {
+    // Every second from process start (default)
+    Interval,
+    // On wall-clock second boundaries, so scrapes at a fixed offset see full windows
+    WallClock,
+    // Only when a scrape asks for it with ?snapshot=true; bytes are scaled to per-second
+    Scrape,
+}
+
+impl WindowAlignment {
+    fn from_env() -> Self {
+        match network::var("WINDOW_ALIGNMENT") {
+            Ok(v) => match v.trim().to_ascii_lowercase().as_str() {
+                "interval" => WindowAlignment::Interval,
+                "wallclock" => WindowAlignment::WallClock,
+                "scrape" => WindowAlignment::Scrape,
+                other => {
+                    error!(
+                        "Unknown WINDOW_ALIGNMENT {}, falling back to interval",
+                        other
+                    );
+                    WindowAlignment::Interval
+                }
+            },
+            Err(_) => WindowAlignment::Interval,
+        }
+    }
+}
 
-#[derive(Debug, Deserialize, Clone)]
-struct StatusConfig {
-    lan: String,
-    wan0: String,
-    wan1: String,
+// Time left until the next wall-clock second
+fn until_next_second() -> Duration {
+    let nanos = chrono::Utc::now().timestamp_subsec_nanos() as u64;
+    Duration::from_nanos(1_000_000_000u64.saturating_sub(nanos % 1_000_000_000))
 }
 
-#[derive(Debug, Deserialize, Clone)]
-struct StatusResponse {
-    config: StatusConfig,
-    mappings: HashMap<String, String>,
+// The last fully published window, served as /window.json
+#[derive(Debug, Clone, Serialize)]
+struct WindowSnapshot {
+    // Layout version of this payload (see shared-schema)
+    schema_version: u32,
+    // Increments by one for every published window
+    sequence: u64,
+    // When the window was closed (Unix epoch milliseconds)
+    timestamp_ms: i64,
+    // Length of the window; bytes are already scaled to per-second
+    duration_ms: u64,
+    entries: Vec<WindowEntry>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct WindowEntry {
+    // Same labels as the download_bytes / upload_bytes gauges
+    #[serde(flatten)]
+    labels: BTreeMap<&'static str, String>,
+    download_bytes: u64,
+    upload_bytes: u64,
 }
 
 #[derive(Clone)]
@@ -35,31 +440,187 @@ struct TrafficMetrics {
     download_bytes_gauge: Arc<IntGaugeVec>,
     // Gauge of upload bytes per second over the last second (outbound traffic to remote)
     upload_bytes_gauge: Arc<IntGaugeVec>,
-    // Bytes observed in the current 1-second window (download), keyed by (remote IP, interface)
-    window_download_bytes: Arc<DashMap<(String, String), u64>>,
-    // Bytes observed in the current 1-second window (upload), keyed by (remote IP, interface)
-    window_upload_bytes: Arc<DashMap<(String, String), u64>>,
-    // Track all (remote IP, interface) pairs ever seen
-    known_metrics: Arc<DashMap<(String, String), ()>>,
+    // Bytes accumulated since startup, labeled like the gauges, for rate() over any range
+    download_bytes_counter: IntCounterVec,
+    upload_bytes_counter: IntCounterVec,
+    // Gauge of download packets per second over the last second
+    download_packets_gauge: Arc<IntGaugeVec>,
+    // Gauge of upload packets per second over the last second
+    upload_packets_gauge: Arc<IntGaugeVec>,
+    // (bytes, packets) observed in the current 1-second window (download), keyed by label values
+    window_download: Arc<DashMap<Vec<String>, (u64, u64)>>,
+    // (bytes, packets) observed in the current 1-second window (upload), keyed by label values
+    window_upload: Arc<DashMap<Vec<String>, (u64, u64)>>,
+    // Packet size distribution per interface and direction (PACKET_SIZE_HISTOGRAM)
+    packet_sizes: Option<HistogramVec>,
+    // (download, upload) children of packet_sizes per interface
+    packet_size_handles: Arc<DashMap<String, [Histogram; 2]>>,
+    // Track all label value sets still published, with how long they have been idle
+    known_metrics: Arc<DashMap<Vec<String>, IdleAge>>,
+    // How long idle label sets keep being published (IDLE_POLICY / IDLE_TTL_SECS)
+    idle: IdleSettings,
+    // Label sets dropped for being idle
+    idle_series_evicted: IntCounter,
+    // Which address is used as the primary label
+    perspective: Perspective,
+    // Bucketing of remote addresses into prefixes for the byte gauges
+    aggregation: RemoteAggregation,
+    // Add the L4 protocol (tcp/udp/icmp/other) as a label (PROTOCOL_LABELS)
+    protocol_labels: bool,
+    // Add the 802.1Q VLAN ID as a label (VLAN_LABELS)
+    vlan_labels: bool,
+    // Add the DSCP class (EF, AF41, BE, ...) as a label (DSCP_LABELS)
+    dscp_labels: bool,
+    // Add the remote's country and AS number as labels (GEOIP_COUNTRY_DB / GEOIP_ASN_DB)
+    geoip: Option<Arc<GeoIp>>,
+    // How window boundaries are chosen
+    alignment: WindowAlignment,
+    // When the current window was opened (used to scale scrape-driven windows)
+    window_started: Arc<Mutex<Instant>>,
+    // Last fully published window for /window.json
+    last_window: Arc<RwLock<Option<WindowSnapshot>>>,
+    // Sequence and close time of the last window, so downstream tools can tell how old
+    // the bytes they read from Prometheus are
+    window_sequence_gauge: IntGauge,
+    window_closed_gauge: Gauge,
+    // Every published window for /stream clients (STREAM_MAX_CLIENTS)
+    live: Arc<LiveStream<WindowSnapshot>>,
     // Registry to gather and encode metrics
     registry: Arc<Registry>,
     // Local CIDR ranges (e.g., 10.40.0.0/20) - packets from/to these IPs are considered local
-    local_cidrs: Arc<Vec<ipnetwork::IpNetwork>>,
-    // Current status from the external service
-    status: Arc<tokio::sync::RwLock<Option<StatusResponse>>>,
-    // Status endpoint URL
-    status_url: String,
+    // Swapped in whole on reload
+    local_cidrs: Arc<ArcSwap<IpSet>>,
+    // Address ranges that are never accounted (DENY_CIDRS)
+    deny: Arc<DenyList>,
+    // Current status from the external service, swapped in whole so lookups never block
+    status: Arc<ArcSwapOption<StatusResponse>>,
+    // Status endpoint URL, swapped on reload
+    status_url: Arc<ArcSwap<String>>,
+    // Disk cache, refresh backoff and WAN move counting for the status API
+    status_tracker: Arc<StatusTracker>,
+    // Shared HTTP client for the status API and webhooks
+    http: Arc<HttpClient>,
+    // Pushes every window to REMOTE_WRITE_URL when Prometheus cannot scrape us
+    remote_write: Option<Arc<RemoteWrite>>,
+    // Families and series left out of /metrics and remote_write (METRICS_ALLOW / METRICS_DENY*)
+    metric_filter: Arc<MetricFilter>,
+    // Unsolicited inbound UDP from amplifier ports (NTP, DNS, SSDP)
+    amplification: Arc<AmplificationDetector>,
+    // Per-device new connection rate
+    flows: Arc<FlowTracker>,
+    // SYN / RST / retransmission rates per remote (TCP_QUALITY_METRICS)
+    tcp_quality: Arc<TcpQuality>,
+    // IGMP / MLD group membership and per-group traffic
+    multicast: Arc<MulticastTracker>,
+    // First/last seen and bytes per remote, served at /remotes (REMOTE_INVENTORY)
+    inventory: Option<Arc<Inventory>>,
+    // Bytes per remote over the last windows, served at /top (TOP_MAX_WINDOWS)
+    top: Option<Arc<TopTalkers>>,
+    // EWMA baselines per remote and traffic_anomaly alerts (ANOMALY_DETECTION)
+    anomaly: Option<Arc<AnomalyDetector>>,
+    // Per-flow comparison with the WAN capture points (CAPTURE_POINTS)
+    crosscheck: Option<Arc<CrossCheck>>,
+    // OS class of local devices from their TCP SYNs, served at /devices (OS_FINGERPRINT)
+    fingerprints: Option<Arc<Fingerprints>>,
+    // NetFlow v9 / IPFIX export, when FLOW_EXPORT_COLLECTOR is set
+    #[cfg(feature = "flow-export")]
+    flow_export: Option<Arc<FlowExporter>>,
+    // Per-second records appended to SQLite / Parquet files, when PERSIST_FORMAT is set
+    flow_store: Option<Arc<FlowStore>>,
+    // Busiest 100ms slot per interface and direction
+    bursts: Arc<BurstTracker>,
+    // Aggregate bytes per named local segment (SEGMENTS)
+    segments: Arc<Segments>,
+    // Whether ICMP, GRE, ESP and other non-TCP/UDP bytes are accounted, left out or
+    // accounted separately (ICMP_BYTES / GRE_BYTES / ESP_BYTES / OTHER_PROTOCOL_BYTES)
+    protocol_policy: Arc<ProtocolPolicy>,
+    // Bytes per local device and interface (DEVICE_METRICS)
+    devices: Arc<Devices>,
+    // NAT64 address translation and DS-Lite decapsulation
+    transition: Arc<Transition>,
+    // GRE / VXLAN decapsulation
+    tunnels: Arc<Tunnels>,
+    // 1-in-N sampling of queued packets under load (SAMPLING_THRESHOLD_PPS)
+    sampler: Arc<AdaptiveSampler>,
+    // Names of remote IPs through reverse DNS (REVERSE_DNS)
+    reverse_dns: Option<Arc<ReverseDns>>,
+    // Packets the capture thread could not queue because the consumer fell behind
+    capture_dropped: IntCounter,
+    // Packets the kernel dropped because a capture ring was full
+    capture_ring_dropped: IntCounter,
+    // Set through POST /control/capture; the capture socket is closed while paused
+    capture_paused: Arc<AtomicBool>,
+    capture_paused_gauge: IntGauge,
+    // State and last frame of every capture thread, for /healthz and /readyz
+    health: Arc<Health>,
+    // Bearer token for the control API (CONTROL_TOKEN); the API is disabled when unset
+    control_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum CaptureAction {
+    Pause,
+    Resume,
+}
+
+#[derive(Debug, Deserialize)]
+struct CaptureControl {
+    action: CaptureAction,
+}
+
+#[derive(Debug, Serialize)]
+struct CaptureState {
+    paused: bool,
 }
 
 impl TrafficMetrics {
     fn new(registry: Arc<Registry>) -> Self {
+        let perspective = Perspective::from_env();
+        info!("Labeling metrics from {:?} perspective", perspective);
+        let alignment = WindowAlignment::from_env();
+        info!("Aligning byte windows by {:?}", alignment);
+        let idle = IdleSettings::from_env();
+        let aggregation = RemoteAggregation::from_env();
+        if aggregation.enabled() {
+            info!("Aggregating remotes into prefixes: {:?}", aggregation);
+        }
+        // Opt-in: the extra label multiplies series and changes existing dashboards
+        let protocol_labels = matches!(
+            network::var("PROTOCOL_LABELS")
+                .map(|v| v.trim().to_ascii_lowercase())
+                .as_deref(),
+            Ok("1") | Ok("true")
+        );
+        info!("Protocol labels: {}", protocol_labels);
+        let vlan_labels = matches!(
+            network::var("VLAN_LABELS")
+                .map(|v| v.trim().to_ascii_lowercase())
+                .as_deref(),
+            Ok("1") | Ok("true")
+        );
+        info!("VLAN labels: {}", vlan_labels);
+        let dscp_labels = matches!(
+            network::var("DSCP_LABELS")
+                .map(|v| v.trim().to_ascii_lowercase())
+                .as_deref(),
+            Ok("1") | Ok("true")
+        );
+        info!("DSCP labels: {}", dscp_labels);
+        let geoip = GeoIp::from_env(&registry).map(Arc::new);
+
         let download_bytes_gauge = IntGaugeVec::new(
             prometheus::Opts::new(
                 "download_bytes",
                 "Download bytes per remote IP over the last second (inbound traffic)",
             )
             .const_label("job", "localpacketdump"),
-            &["remote_ip", "interface"],
+            &aggregation.relabel(perspective.label_names(
+                protocol_labels,
+                vlan_labels,
+                dscp_labels,
+                geoip.is_some(),
+            )),
         )
         .expect("failed to create download_bytes gauge");
 
@@ -69,7 +630,12 @@ impl TrafficMetrics {
                 "Upload bytes per remote IP over the last second (outbound traffic)",
             )
             .const_label("job", "localpacketdump"),
-            &["remote_ip", "interface"],
+            &aggregation.relabel(perspective.label_names(
+                protocol_labels,
+                vlan_labels,
+                dscp_labels,
+                geoip.is_some(),
+            )),
         )
         .expect("failed to create upload_bytes gauge");
 
@@ -80,178 +646,806 @@ impl TrafficMetrics {
             .register(Box::new(upload_bytes_gauge.clone()))
             .expect("failed to register upload_bytes gauge");
 
-        // Parse local CIDR ranges from environment variable
-        // Default is 10.40.0.0/20 - adjust based on your local network
-        let local_cidrs_str =
-            env::var("LOCAL_CIDRS").unwrap_or_else(|_| "10.40.0.0/20".to_string());
-        let local_cidrs: Vec<ipnetwork::IpNetwork> = local_cidrs_str
-            .split(',')
-            .filter_map(|cidr| match ipnetwork::IpNetwork::from_str(cidr.trim()) {
-                Ok(net) => {
-                    info!("Configured local CIDR: {}", net);
-                    Some(net)
-                }
-                Err(e) => {
-                    error!("Failed to parse local CIDR {}: {}", cidr, e);
-                    None
-                }
-            })
-            .collect();
+        let download_bytes_counter = IntCounterVec::new(
+            prometheus::Opts::new(
+                "download_bytes_total",
+                "Download bytes since startup, labeled like download_bytes",
+            )
+            .const_label("job", "localpacketdump"),
+            &aggregation.relabel(perspective.label_names(
+                protocol_labels,
+                vlan_labels,
+                dscp_labels,
+                geoip.is_some(),
+            )),
+        )
+        .expect("failed to create download_bytes_total counter");
+        let upload_bytes_counter = IntCounterVec::new(
+            prometheus::Opts::new(
+                "upload_bytes_total",
+                "Upload bytes since startup, labeled like upload_bytes",
+            )
+            .const_label("job", "localpacketdump"),
+            &aggregation.relabel(perspective.label_names(
+                protocol_labels,
+                vlan_labels,
+                dscp_labels,
+                geoip.is_some(),
+            )),
+        )
+        .expect("failed to create upload_bytes_total counter");
+        registry
+            .register(Box::new(download_bytes_counter.clone()))
+            .expect("failed to register download_bytes_total counter");
+        registry
+            .register(Box::new(upload_bytes_counter.clone()))
+            .expect("failed to register upload_bytes_total counter");
+
+        let download_packets_gauge = IntGaugeVec::new(
+            prometheus::Opts::new(
+                "download_packets",
+                "Download packets over the last second, labeled like download_bytes",
+            )
+            .const_label("job", "localpacketdump"),
+            &aggregation.relabel(perspective.label_names(
+                protocol_labels,
+                vlan_labels,
+                dscp_labels,
+                geoip.is_some(),
+            )),
+        )
+        .expect("failed to create download_packets gauge");
+        let upload_packets_gauge = IntGaugeVec::new(
+            prometheus::Opts::new(
+                "upload_packets",
+                "Upload packets over the last second, labeled like upload_bytes",
+            )
+            .const_label("job", "localpacketdump"),
+            &aggregation.relabel(perspective.label_names(
+                protocol_labels,
+                vlan_labels,
+                dscp_labels,
+                geoip.is_some(),
+            )),
+        )
+        .expect("failed to create upload_packets gauge");
+        registry
+            .register(Box::new(download_packets_gauge.clone()))
+            .expect("failed to register download_packets gauge");
+        registry
+            .register(Box::new(upload_packets_gauge.clone()))
+            .expect("failed to register upload_packets gauge");
+
+        // Opt-in: a dozen extra series per interface and direction
+        let packet_sizes = matches!(
+            network::var("PACKET_SIZE_HISTOGRAM")
+                .map(|v| v.trim().to_ascii_lowercase())
+                .as_deref(),
+            Ok("1") | Ok("true")
+        )
+        .then(|| {
+            let histogram = HistogramVec::new(
+                HistogramOpts::new("packet_size_bytes", "Size of accounted IP packets")
+                    .const_label("job", "localpacketdump")
+                    .buckets(vec![
+                        64.0, 128.0, 256.0, 512.0, 1024.0, 1280.0, 1500.0, 9000.0,
+                    ]),
+                &[LABEL_INTERFACE, "direction"],
+            )
+            .expect("failed to create packet_size_bytes histogram");
+            registry
+                .register(Box::new(histogram.clone()))
+                .expect("failed to register packet_size_bytes histogram");
+            histogram
+        });
+        info!("Packet size histogram: {}", packet_sizes.is_some());
 
-        let status_url =
-            env::var("STATUS_URL").unwrap_or_else(|_| "http://localhost:32599/status".to_string());
+        // Lets consumers and dashboards check which data contracts this build speaks
+        let schema_info = IntGaugeVec::new(
+            prometheus::Opts::new(
+                "traffic_scan_schema_info",
+                "Schema versions of the status API, window snapshot and byte gauge labels",
+            )
+            .const_label("job", "localpacketdump"),
+            &["status", "window", "labels"],
+        )
+        .expect("failed to create traffic_scan_schema_info gauge");
+        schema_info
+            .with_label_values(&[
+                &STATUS_SCHEMA_VERSION.to_string(),
+                &WINDOW_SCHEMA_VERSION.to_string(),
+                &LABELS_SCHEMA_VERSION.to_string(),
+            ])
+            .set(1);
+        registry
+            .register(Box::new(schema_info))
+            .expect("failed to register traffic_scan_schema_info gauge");
+
+        let window_sequence_gauge = IntGauge::with_opts(
+            prometheus::Opts::new(
+                pipeline::WINDOW_SEQUENCE_METRIC,
+                "Sequence of the last published byte window (as in /window.json)",
+            )
+            .const_label("job", "localpacketdump"),
+        )
+        .expect("failed to create window_sequence gauge");
+        let window_closed_gauge = Gauge::with_opts(
+            prometheus::Opts::new(
+                pipeline::WINDOW_CLOSED_METRIC,
+                "Unix time the last published byte window was closed",
+            )
+            .const_label("job", "localpacketdump"),
+        )
+        .expect("failed to create window_closed_timestamp_seconds gauge");
+        registry
+            .register(Box::new(window_sequence_gauge.clone()))
+            .expect("failed to register window_sequence gauge");
+        registry
+            .register(Box::new(window_closed_gauge.clone()))
+            .expect("failed to register window_closed_timestamp_seconds gauge");
+
+        let local_cidrs = local_cidrs_from_env();
+        let status_url = status_url_from_env();
+        let status_tracker = StatusTracker::from_env(&registry);
+
+        let http = Arc::new(HttpClient::from_lookup(network::var));
+        let amplification = AmplificationDetector::new(&registry, http.clone());
+        let remote_write =
+            RemoteWrite::from_lookup(&registry, http.clone(), network::var).map(Arc::new);
+        let metric_filter = Arc::new(MetricFilter::from_lookup(network::var));
+        let flows = FlowTracker::new(&registry);
+        let tcp_quality = TcpQuality::new(
+            &registry,
+            &aggregation.relabel(vec![LABEL_REMOTE_IP, LABEL_INTERFACE]),
+            idle,
+        );
+        let multicast = MulticastTracker::new(&registry);
+        let inventory = Inventory::from_env(&registry).map(Arc::new);
+        let top = TopTalkers::from_env(&registry).map(Arc::new);
+        let anomaly = AnomalyDetector::from_env(&registry, http.clone()).map(Arc::new);
+        let crosscheck = CrossCheck::from_env(&registry).map(Arc::new);
+        let fingerprints = Fingerprints::from_env().map(Arc::new);
+        let segments = Segments::new(&registry);
+        let protocol_policy = ProtocolPolicy::new(&registry);
+        let devices = Devices::new(&registry, idle);
+        let bursts = BurstTracker::new(&registry);
+
+        let capture_paused_gauge = IntGauge::with_opts(
+            prometheus::Opts::new(
+                "capture_paused",
+                "1 while packet capture is paused through the control API",
+            )
+            .const_label("job", "localpacketdump"),
+        )
+        .expect("failed to create capture_paused gauge");
+        registry
+            .register(Box::new(capture_paused_gauge.clone()))
+            .expect("failed to register capture_paused gauge");
+        let capture_dropped = IntCounter::with_opts(
+            prometheus::Opts::new(
+                "capture_dropped_packets_total",
+                "Captured packets dropped because the processing queue was full",
+            )
+            .const_label("job", "localpacketdump"),
+        )
+        .expect("failed to create capture_dropped_packets_total counter");
+        registry
+            .register(Box::new(capture_dropped.clone()))
+            .expect("failed to register capture_dropped_packets_total counter");
+        let idle_series_evicted = IntCounter::with_opts(
+            prometheus::Opts::new(
+                "idle_series_evicted_total",
+                "Label sets removed from download_bytes/upload_bytes after going idle",
+            )
+            .const_label("job", "localpacketdump"),
+        )
+        .expect("failed to create idle_series_evicted_total counter");
+        registry
+            .register(Box::new(idle_series_evicted.clone()))
+            .expect("failed to register idle_series_evicted_total counter");
+
+        let capture_ring_dropped = IntCounter::with_opts(
+            prometheus::Opts::new(
+                "capture_ring_dropped_packets_total",
+                "Packets dropped by the kernel because the capture ring was full",
+            )
+            .const_label("job", "localpacketdump"),
+        )
+        .expect("failed to create capture_ring_dropped_packets_total counter");
+        registry
+            .register(Box::new(capture_ring_dropped.clone()))
+            .expect("failed to register capture_ring_dropped_packets_total counter");
+        server::register_build_info(&registry, &build_info!(), Some("localpacketdump"))
+            .expect("failed to register build_info gauge");
+        let control_token = network::var("CONTROL_TOKEN")
+            .ok()
+            .filter(|token| !token.is_empty());
+        if control_token.is_none() {
+            info!("CONTROL_TOKEN not set, control API disabled");
+        }
+        let transition = Transition::new(&registry);
+        let tunnels = Tunnels::new(&registry);
+        let deny = DenyList::new(&registry);
+        let sampler = AdaptiveSampler::new(&registry);
+        #[cfg(feature = "flow-export")]
+        let flow_export = FlowExporter::from_env(&registry).map(Arc::new);
+        #[cfg(not(feature = "flow-export"))]
+        if network::var("FLOW_EXPORT_COLLECTOR").is_ok_and(|v| !v.trim().is_empty()) {
+            error!("FLOW_EXPORT_COLLECTOR is ignored: built without the flow-export feature");
+        }
+        let flow_store = FlowStore::from_env(&registry).map(Arc::new);
+        let reverse_dns = ReverseDns::from_env(&registry).map(Arc::new);
 
         Self {
             download_bytes_gauge: Arc::new(download_bytes_gauge),
             upload_bytes_gauge: Arc::new(upload_bytes_gauge),
-            window_download_bytes: Arc::new(DashMap::new()),
-            window_upload_bytes: Arc::new(DashMap::new()),
+            download_bytes_counter,
+            upload_bytes_counter,
+            download_packets_gauge: Arc::new(download_packets_gauge),
+            upload_packets_gauge: Arc::new(upload_packets_gauge),
+            window_download: Arc::new(DashMap::new()),
+            window_upload: Arc::new(DashMap::new()),
+            packet_sizes,
+            packet_size_handles: Arc::new(DashMap::new()),
             known_metrics: Arc::new(DashMap::new()),
+            aggregation,
+            idle,
+            idle_series_evicted,
+            perspective,
+            protocol_labels,
+            vlan_labels,
+            dscp_labels,
+            geoip,
+            alignment,
+            window_started: Arc::new(Mutex::new(Instant::now())),
+            last_window: Arc::new(RwLock::new(None)),
+            window_sequence_gauge,
+            window_closed_gauge,
+            live: Arc::new(LiveStream::from_env()),
             registry,
-            local_cidrs: Arc::new(local_cidrs),
-            status: Arc::new(tokio::sync::RwLock::new(None)),
-            status_url,
+            local_cidrs: Arc::new(ArcSwap::from_pointee(local_cidrs)),
+            deny: Arc::new(deny),
+            status: Arc::new(ArcSwapOption::empty()),
+            status_url: Arc::new(ArcSwap::from_pointee(status_url)),
+            status_tracker: Arc::new(status_tracker),
+            http,
+            remote_write,
+            metric_filter,
+            amplification: Arc::new(amplification),
+            flows: Arc::new(flows),
+            tcp_quality: Arc::new(tcp_quality),
+            multicast: Arc::new(multicast),
+            inventory,
+            top,
+            anomaly,
+            crosscheck,
+            fingerprints,
+            #[cfg(feature = "flow-export")]
+            flow_export,
+            flow_store,
+            bursts: Arc::new(bursts),
+            segments: Arc::new(segments),
+            protocol_policy: Arc::new(protocol_policy),
+            devices: Arc::new(devices),
+            transition: Arc::new(transition),
+            tunnels: Arc::new(tunnels),
+            sampler: Arc::new(sampler),
+            reverse_dns,
+            capture_dropped,
+            capture_ring_dropped,
+            capture_paused: Arc::new(AtomicBool::new(false)),
+            capture_paused_gauge,
+            health: Arc::new(Health::from_env()),
+            control_token,
         }
     }
 
     async fn fetch_status(&self) {
-        match reqwest::get(&self.status_url).await {
-            Ok(response) => match response.json::<StatusResponse>().await {
+        let status_url = self.status_url.load_full();
+        let request = self
+            .http
+            .get(status_url.as_str())
+            .header(SCHEMA_VERSION_HEADER, STATUS_SCHEMA_VERSION.to_string());
+        let body = match self.http.send(request).await {
+            Ok(response) => response.bytes().await,
+            Err(e) => Err(e),
+        };
+        match body {
+            Ok(body) => match StatusResponse::from_json(&body) {
                 Ok(status) => {
+                    if status.is_newer_than_supported() {
+                        warn!(
+                            "Status API speaks schema version {}, reading it as {}",
+                            status.schema_version, STATUS_SCHEMA_VERSION
+                        );
+                    }
                     info!(
                         "Fetched status: config={:?}, mappings={:?}",
                         status.config, status.mappings
                     );
-                    *self.status.write().await = Some(status);
+                    if let Some(previous) = self.status.load().as_ref() {
+                        self.status_tracker.record_changes(previous, &status);
+                    }
+                    self.status_tracker.succeeded(&body);
+                    self.status.store(Some(Arc::new(status)));
                 }
                 Err(e) => {
                     warn!("Failed to parse status response: {}", e);
+                    self.status_tracker.failed("parse");
                 }
             },
             Err(e) => {
-                warn!("Failed to fetch status from {}: {}", self.status_url, e);
+                warn!("Failed to fetch status from {}: {}", status_url, e);
+                self.status_tracker.failed("request");
             }
         }
     }
 
-    async fn get_interface_for_ip(&self, local_ip: &str) -> String {
-        let status_guard = self.status.read().await;
-        if let Some(status) = status_guard.as_ref() {
-            // Check if local_ip is in mappings
-            if let Some(wan_name) = status.mappings.get(local_ip) {
-                // wan_name is either "wan0" or "wan1"
-                match wan_name.as_str() {
-                    "wan0" => return status.config.wan0.clone(),
-                    "wan1" => return status.config.wan1.clone(),
-                    _ => {}
-                }
-            }
-            // Not in mappings, so it's wan0
-            return status.config.wan0.clone();
+    fn get_interface_for_ip(&self, local_ip: &str) -> String {
+        if let Some(status) = self.status.load().as_ref() {
+            // Devices not in mappings use wan0
+            return status.interface_for(local_ip).to_string();
         }
         // Fallback if status is not available
         "unknown".to_string()
     }
 
     // Check if an IP address is in local CIDR range
-    fn is_local_ip(&self, ip_str: &str) -> bool {
-        if let Ok(ip) = IpAddr::from_str(ip_str) {
-            for network in self.local_cidrs.iter() {
-                if network.contains(ip) {
-                    return true;
+    fn is_local_ip(&self, ip: IpAddr) -> bool {
+        self.local_cidrs.load().contains(ip)
+    }
+
+    // Account a packet handed over by the capture thread
+    fn record(&self, packet: &CapturedPacket) {
+        if self.deny.denies(packet.src, packet.dst) {
+            return;
+        }
+        let src_ip = self.transition.label(packet.src);
+        let dst_ip = self.transition.label(packet.dst);
+        let direction = (self.is_local_ip(packet.src), self.is_local_ip(packet.dst));
+
+        // Flows are compared per WAN role, which the status mapping gives for local devices
+        if let (Some(crosscheck), Some(status)) = (&self.crosscheck, &*self.status.load()) {
+            match direction {
+                (false, true) => {
+                    let wan = status.wan_for(&dst_ip);
+                    crosscheck.record(Side::Lan, wan, packet.src, packet, true)
+                }
+                (true, false) => {
+                    let wan = status.wan_for(&src_ip);
+                    crosscheck.record(Side::Lan, wan, packet.dst, packet, false)
+                }
+                _ => {}
+            }
+        }
+
+        // The local device's interface is looked up once for everything accounted below
+        let interface = match direction {
+            (false, true) => Some(self.get_interface_for_ip(&dst_ip)),
+            (true, false) => Some(self.get_interface_for_ip(&src_ip)),
+            _ => None,
+        };
+        if let Some(interface) = &interface {
+            match self.protocol_policy.action(packet.protocol) {
+                (ProtocolAction::Include, _) => {
+                    self.record_packet(&src_ip, &dst_ip, direction, interface, packet)
+                }
+                (ProtocolAction::Exclude, _) => {}
+                (ProtocolAction::Separate, protocol) => {
+                    self.protocol_policy
+                        .record(interface, protocol, direction.1, packet.bytes)
                 }
             }
+            self.record_tcp_quality(&src_ip, &dst_ip, direction, interface, packet);
+        }
+        self.record_transport(&src_ip, &dst_ip, direction, packet);
+
+        match &packet.transport {
+            // Only local devices' memberships are tracked
+            Transport::Membership(changes) if direction.0 => {
+                self.multicast.record_report(&src_ip, changes)
+            }
+            Transport::Membership(_) => {}
+            _ if packet.dst.is_multicast() => {
+                self.multicast.record_traffic(packet.dst, packet.bytes)
+            }
+            _ => {}
+        }
+
+        #[cfg(feature = "flow-export")]
+        if let Some(exporter) = &self.flow_export {
+            match direction {
+                (true, false) => exporter.record(packet, true),
+                (false, true) => exporter.record(packet, false),
+                _ => {}
+            }
         }
-        false
     }
 
-    // Process a packet and record bytes based on direction
+    // Account a packet seen at a WAN capture point; it is only compared with the LAN side.
+    // The interface's own addresses are local there, as NAT has rewritten the LAN ones
+    fn record_capture_point(
+        &self,
+        role: &str,
+        interface: &NetworkInterface,
+        packet: &CapturedPacket,
+    ) {
+        let Some(crosscheck) = &self.crosscheck else {
+            return;
+        };
+        if self.deny.denies(packet.src, packet.dst) {
+            return;
+        }
+        let is_local =
+            |ip: IpAddr| self.is_local_ip(ip) || interface.ips.iter().any(|net| net.ip() == ip);
+        match (is_local(packet.src), is_local(packet.dst)) {
+            (false, true) => crosscheck.record(Side::Wan, role, packet.src, packet, true),
+            (true, false) => crosscheck.record(Side::Wan, role, packet.dst, packet, false),
+            _ => {}
+        }
+    }
+
+    // Record bytes based on direction
     // Download: remote source -> local destination
     // Upload: local source -> remote destination
-    async fn record_packet(&self, src_ip: &str, dst_ip: &str, bytes: u64) {
-        let src_is_local = self.is_local_ip(src_ip);
-        let dst_is_local = self.is_local_ip(dst_ip);
+    fn record_packet(
+        &self,
+        src_ip: &str,
+        dst_ip: &str,
+        direction: (bool, bool),
+        interface: &str,
+        packet: &CapturedPacket,
+    ) {
+        let (bytes, packets) = (packet.bytes, packet.packets);
+        let protocol = self.protocol_labels.then(|| protocol_name(packet.protocol));
+        let vlan = self.vlan_labels.then(|| {
+            packet
+                .vlan
+                .map(|tags| tags.label())
+                .unwrap_or_else(|| "untagged".to_string())
+        });
+        let dscp = self.dscp_labels.then(|| dscp_class(packet.dscp));
+        let optional: Vec<&str> = [protocol, vlan.as_deref(), dscp.as_deref()]
+            .into_iter()
+            .flatten()
+            .collect();
 
-        match (src_is_local, dst_is_local) {
+        match direction {
             // Download: remote -> local
             (false, true) => {
-                let interface = self.get_interface_for_ip(dst_ip).await;
-                let key = (src_ip.to_string(), interface);
-                self.window_download_bytes
-                    .entry(key.clone())
-                    .and_modify(|v| *v += bytes)
-                    .or_insert(bytes);
-                self.known_metrics.insert(key, ());
+                self.bursts.record(interface, true, bytes);
+                self.observe_size(interface, true, bytes, packets);
+                self.devices.record(dst_ip, interface, true, bytes);
+                if let Some(inventory) = &self.inventory {
+                    inventory.record(src_ip, interface, bytes, true);
+                }
+                if let Some(top) = &self.top {
+                    top.record(src_ip, interface, bytes, true);
+                }
+                if let Some(anomaly) = &self.anomaly {
+                    anomaly.record(src_ip, bytes, true);
+                }
+                if let Some(reverse_dns) = &self.reverse_dns {
+                    reverse_dns.note(src_ip);
+                }
+                let key = self.perspective.label_values(
+                    &self.remote_label(src_ip),
+                    dst_ip,
+                    interface.to_string(),
+                    &optional,
+                    self.geoip.as_ref().map(|geoip| geoip.labels(src_ip)),
+                );
+                self.segments.record(dst_ip, true, bytes);
+                // Counters and known_metrics are updated once per key when the window closes
+                let mut window = self.window_download.entry(key).or_default();
+                window.0 += bytes;
+                window.1 += packets;
             }
             // Upload: local -> remote
             (true, false) => {
-                let interface = self.get_interface_for_ip(src_ip).await;
-                let key = (dst_ip.to_string(), interface);
-                self.window_upload_bytes
-                    .entry(key.clone())
-                    .and_modify(|v| *v += bytes)
-                    .or_insert(bytes);
-                self.known_metrics.insert(key, ());
+                self.bursts.record(interface, false, bytes);
+                self.observe_size(interface, false, bytes, packets);
+                self.devices.record(src_ip, interface, false, bytes);
+                if let Some(inventory) = &self.inventory {
+                    inventory.record(dst_ip, interface, bytes, false);
+                }
+                if let Some(top) = &self.top {
+                    top.record(dst_ip, interface, bytes, false);
+                }
+                if let Some(anomaly) = &self.anomaly {
+                    anomaly.record(dst_ip, bytes, false);
+                }
+                if let (Some(fingerprints), Some(syn)) = (&self.fingerprints, &packet.syn) {
+                    fingerprints.record(src_ip, interface, syn);
+                }
+                if let Some(reverse_dns) = &self.reverse_dns {
+                    reverse_dns.note(dst_ip);
+                }
+                let key = self.perspective.label_values(
+                    &self.remote_label(dst_ip),
+                    src_ip,
+                    interface.to_string(),
+                    &optional,
+                    self.geoip.as_ref().map(|geoip| geoip.labels(dst_ip)),
+                );
+                self.segments.record(src_ip, false, bytes);
+                // Counters and known_metrics are updated once per key when the window closes
+                let mut window = self.window_upload.entry(key).or_default();
+                window.0 += bytes;
+                window.1 += packets;
             }
             // Local -> Local or Remote -> Remote: ignore
             _ => {}
         }
     }
 
+    // Only the remote side of the labels is bucketed
+    fn remote_label(&self, remote_ip: &str) -> String {
+        if self.aggregation.enabled() {
+            self.aggregation.bucket(remote_ip)
+        } else {
+            remote_ip.to_string()
+        }
+    }
+
+    fn record_tcp_quality(
+        &self,
+        src_ip: &str,
+        dst_ip: &str,
+        direction: (bool, bool),
+        interface: &str,
+        packet: &CapturedPacket,
+    ) {
+        if !self.tcp_quality.enabled() {
+            return;
+        }
+        let Transport::Tcp {
+            src_port,
+            dst_port,
+            flags,
+            seq,
+            payload_len,
+        } = packet.transport
+        else {
+            return;
+        };
+        let remote_ip = match direction {
+            (false, true) => src_ip,
+            (true, false) => dst_ip,
+            _ => return,
+        };
+        self.tcp_quality.record(tcp_quality::Segment {
+            flow: (packet.src, src_port, packet.dst, dst_port),
+            remote: &self.remote_label(remote_ip),
+            interface,
+            flags,
+            seq,
+            payload_len,
+            packets: packet.packets,
+        });
+    }
+
+    // Packets are observed at their own size, also when sampling scaled bytes up.
+    // The (download, upload) histograms are kept per interface, so a packet costs a map
+    // lookup rather than a label lookup
+    fn observe_size(&self, interface: &str, download: bool, bytes: u64, packets: u64) {
+        if let Some(histogram) = &self.packet_sizes {
+            let size = bytes.checked_div(packets).unwrap_or(bytes);
+            let handles = match self.packet_size_handles.get(interface) {
+                Some(handles) => handles,
+                None => self
+                    .packet_size_handles
+                    .entry(interface.to_string())
+                    .or_insert_with(|| {
+                        ["download", "upload"]
+                            .map(|direction| histogram.with_label_values(&[interface, direction]))
+                    })
+                    .downgrade(),
+            };
+            let histogram = &handles[usize::from(!download)];
+            for _ in 0..packets {
+                histogram.observe(size as f64);
+            }
+        }
+    }
+
+    // Use the transport header for flow tracking and amplification detection
+    fn record_transport(
+        &self,
+        src_ip: &str,
+        dst_ip: &str,
+        direction: (bool, bool),
+        packet: &CapturedPacket,
+    ) {
+        match packet.transport {
+            Transport::Udp { src_port, dst_port } => match direction {
+                (false, true) => {
+                    self.amplification
+                        .record_inbound(src_ip, src_port, dst_ip, packet.bytes);
+                    self.flows
+                        .record_udp(dst_ip, dst_port, src_ip, src_port, false);
+                }
+                (true, false) => {
+                    self.amplification.record_outbound(src_ip, dst_ip, dst_port);
+                    self.flows
+                        .record_udp(src_ip, src_port, dst_ip, dst_port, true);
+                }
+                _ => {}
+            },
+            Transport::Tcp { flags, .. } => {
+                if direction == (true, false) {
+                    self.flows
+                        .record_tcp_outbound(src_ip, flags, packet.packets);
+                }
+            }
+            Transport::Membership(_) | Transport::Other => {}
+        }
+    }
+
     // Compute bytes from the last second window, update gauges, then reset the window
     fn publish_bytes_and_reset(&self) {
+        // Scrape-driven windows have arbitrary length; report them as bytes per second
+        let elapsed = {
+            let mut started = self.window_started.lock().unwrap();
+            let elapsed = started.elapsed();
+            *started = Instant::now();
+            elapsed
+        };
+        let scale = if self.alignment == WindowAlignment::Scrape {
+            1.0 / elapsed.as_secs_f64().max(0.001)
+        } else {
+            1.0
+        };
+
         // Collect keys present in this window
-        let mut current_download_keys: HashSet<(String, String)> = HashSet::new();
-        let mut current_upload_keys: HashSet<(String, String)> = HashSet::new();
+        let mut current_download_keys: HashSet<Vec<String>> = HashSet::new();
+        let mut current_upload_keys: HashSet<Vec<String>> = HashSet::new();
+        // (download, upload) per key for the snapshot
+        let mut window_bytes: BTreeMap<Vec<String>, (u64, u64)> = BTreeMap::new();
+        let label_names = self.aggregation.relabel(self.perspective.label_names(
+            self.protocol_labels,
+            self.vlan_labels,
+            self.dscp_labels,
+            self.geoip.is_some(),
+        ));
+        let mut records = self
+            .flow_store
+            .as_ref()
+            .map(|_| persist::Records::new(&label_names));
 
-        // Update download_bytes gauge
-        for entry in self.window_download_bytes.iter() {
-            let (remote_ip, interface) = entry.key();
-            let bytes = *entry.value() as i64;
+        // Windows are drained per shard rather than cleared afterwards, so packets recorded
+        // meanwhile count towards the next window instead of being lost to the counters.
+        // Update download_bytes_total and the download_bytes / download_packets gauges
+        self.window_download.retain(|key, &mut (bytes, packets)| {
+            let labels: Vec<&str> = key.iter().map(String::as_str).collect();
+            self.download_bytes_counter
+                .with_label_values(&labels)
+                .inc_by(bytes);
+            let bytes = (bytes as f64 * scale) as i64;
             self.download_bytes_gauge
-                .with_label_values(&[remote_ip, interface])
+                .with_label_values(&labels)
                 .set(bytes);
-            current_download_keys.insert((remote_ip.clone(), interface.clone()));
-        }
+            self.download_packets_gauge
+                .with_label_values(&labels)
+                .set((packets as f64 * scale) as i64);
+            self.known_metrics.insert(key.clone(), IdleAge::default());
+            current_download_keys.insert(key.clone());
+            window_bytes.entry(key.clone()).or_default().0 = bytes as u64;
+            if let Some(records) = &mut records {
+                let packets = (packets as f64 * scale) as u64;
+                records.push(key, "download", bytes as u64, packets);
+            }
+            false
+        });
 
-        // Update upload_bytes gauge
-        for entry in self.window_upload_bytes.iter() {
-            let (remote_ip, interface) = entry.key();
-            let bytes = *entry.value() as i64;
+        // Update upload_bytes_total and the upload_bytes / upload_packets gauges
+        self.window_upload.retain(|key, &mut (bytes, packets)| {
+            let labels: Vec<&str> = key.iter().map(String::as_str).collect();
+            self.upload_bytes_counter
+                .with_label_values(&labels)
+                .inc_by(bytes);
+            let bytes = (bytes as f64 * scale) as i64;
             self.upload_bytes_gauge
-                .with_label_values(&[remote_ip, interface])
+                .with_label_values(&labels)
                 .set(bytes);
-            current_upload_keys.insert((remote_ip.clone(), interface.clone()));
-        }
+            self.upload_packets_gauge
+                .with_label_values(&labels)
+                .set((packets as f64 * scale) as i64);
+            self.known_metrics.insert(key.clone(), IdleAge::default());
+            current_upload_keys.insert(key.clone());
+            window_bytes.entry(key.clone()).or_default().1 = bytes as u64;
+            if let Some(records) = &mut records {
+                let packets = (packets as f64 * scale) as u64;
+                records.push(key, "upload", bytes as u64, packets);
+            }
+            false
+        });
 
-        // For known (remote_ip, interface) pairs not seen in this window, set 0
-        for entry in self.known_metrics.iter() {
-            let key = entry.key();
-            if !current_download_keys.contains(key) {
-                self.download_bytes_gauge
-                    .with_label_values(&[&key.0, &key.1])
-                    .set(0);
+        // For known label sets not seen in this window, set 0 or drop them per IDLE_POLICY
+        let paused = self.capture_paused.load(Ordering::Relaxed);
+        let mut expired: Vec<Vec<String>> = Vec::new();
+        for mut entry in self.known_metrics.iter_mut() {
+            let key = entry.key().clone();
+            let labels: Vec<&str> = key.iter().map(String::as_str).collect();
+            let seen_download = current_download_keys.contains(&key);
+            let seen_upload = current_upload_keys.contains(&key);
+
+            if seen_download || seen_upload {
+                *entry.value_mut() = IdleAge::default();
+            } else if !paused {
+                // Series do not age while capture is paused
+                if self.idle.age(entry.value_mut(), elapsed) {
+                    expired.push(key);
+                    continue;
+                }
             }
-            if !current_upload_keys.contains(key) {
-                self.upload_bytes_gauge
-                    .with_label_values(&[&key.0, &key.1])
+
+            if !seen_download {
+                self.download_bytes_gauge.with_label_values(&labels).set(0);
+                self.download_packets_gauge
+                    .with_label_values(&labels)
                     .set(0);
             }
+            if !seen_upload {
+                self.upload_bytes_gauge.with_label_values(&labels).set(0);
+                self.upload_packets_gauge.with_label_values(&labels).set(0);
+            }
         }
 
-        // Reset window
-        self.window_download_bytes.clear();
-        self.window_upload_bytes.clear();
-    }
+        self.idle_series_evicted.inc_by(expired.len() as u64);
+        for key in expired {
+            let labels: Vec<&str> = key.iter().map(String::as_str).collect();
+            // A series may exist in only one of the gauges
+            let _ = self.download_bytes_gauge.remove_label_values(&labels);
+            let _ = self.upload_bytes_gauge.remove_label_values(&labels);
+            // Counters restart from 0 if the series comes back, which rate() treats as a reset
+            let _ = self.download_bytes_counter.remove_label_values(&labels);
+            let _ = self.upload_bytes_counter.remove_label_values(&labels);
+            let _ = self.download_packets_gauge.remove_label_values(&labels);
+            let _ = self.upload_packets_gauge.remove_label_values(&labels);
+            self.known_metrics.remove(&key);
+        }
+
+        self.segments.publish_and_reset(scale);
+        self.protocol_policy.publish_and_reset(scale);
+        self.devices.publish_and_reset(scale);
+        self.bursts.publish_and_reset();
+        if let Some(top) = &self.top {
+            top.publish_and_reset(elapsed);
+        }
+        if let Some(anomaly) = &self.anomaly {
+            anomaly.evaluate_and_reset(elapsed);
+        }
+        if let Some(crosscheck) = &self.crosscheck {
+            crosscheck.publish_and_reset(scale);
+        }
 
-    fn encode_metrics(&self) -> String {
-        let encoder = TextEncoder::new();
-        let metric_families = self.registry.gather();
-        let mut buffer = vec![];
-        encoder
-            .encode(&metric_families, &mut buffer)
-            .expect("failed to encode metrics");
-        String::from_utf8(buffer).expect("metrics contained invalid UTF-8")
+        let entries = window_bytes
+            .into_iter()
+            .map(|(key, (download_bytes, upload_bytes))| WindowEntry {
+                labels: label_names.iter().copied().zip(key).collect(),
+                download_bytes,
+                upload_bytes,
+            })
+            .collect();
+        let mut last_window = self.last_window.write().unwrap();
+        let sequence = last_window.as_ref().map_or(1, |w| w.sequence + 1);
+        let timestamp_ms = chrono::Utc::now().timestamp_millis();
+        if let (Some(store), Some(records)) = (&self.flow_store, records) {
+            store.append(timestamp_ms, records.into_inner());
+        }
+        let snapshot = WindowSnapshot {
+            schema_version: WINDOW_SCHEMA_VERSION,
+            sequence,
+            timestamp_ms,
+            duration_ms: elapsed.as_millis() as u64,
+            entries,
+        };
+        self.window_sequence_gauge.set(sequence as i64);
+        self.window_closed_gauge
+            .set(snapshot.timestamp_ms as f64 / 1000.0);
+        self.live.publish(|| snapshot.clone());
+        *last_window = Some(snapshot);
     }
 }
 
@@ -259,114 +1453,1152 @@ impl TrafficMetrics {
 async fn main() {
     tracing_subscriber::fmt::init();
 
-    let registry = Arc::new(Registry::new());
-    let interface_name = env::var("INTERFACE_NAME").unwrap_or_else(|_| "eth2".to_string());
-
-    let metrics = TrafficMetrics::new(registry.clone());
-    let metrics_clone = metrics.clone();
-    let metrics_clone_for_tick = metrics.clone();
-    let metrics_clone_for_status = metrics.clone();
-    let interface_name_clone = interface_name.clone();
+    if let Err(e) = shared_config::init(shared_config::LOCALPACKETDUMP) {
+        error!("{}", e);
+        std::process::exit(2);
+    }
+    if std::env::var_os("CONFIG_FILE").is_some() {
+        warn!("CONFIG_FILE is no longer read; move its settings into the --config file");
+    }
 
-    // Fetch status initially
-    metrics.fetch_status().await;
+    // --simulate replaces the status API and the capture with synthetic inputs
+    let scenario = shared_sim::enabled().then(|| Arc::new(shared_sim::Scenario::from_env()));
 
-    // Status更新タスク (10秒ごと)
-    task::spawn(async move {
-        let mut interval = tokio::time::interval(Duration::from_secs(10));
-        loop {
-            interval.tick().await;
-            metrics_clone_for_status.fetch_status().await;
+    let watchdog = Arc::new(WatchdogGate::from_env());
+    let mut networks = Vec::new();
+    for (index, name) in network::from_env().into_iter().enumerate() {
+        if let Some(name) = &name {
+            info!("Starting network {}", name);
         }
-    });
+        networks.push(start_network(index, name, scenario.clone(), watchdog.clone()).await);
+    }
+    let networks = Arc::new(networks);
 
-    // パケット監視タスクを起動
-    task::spawn(async move {
-        monitor_interface(metrics_clone, &interface_name_clone).await;
-    });
+    // Prometheus メトリクスエンドポイント
+    // Without NETWORKS there is a single network and the root serves it as before
+    let mut app = network_routes()
+        .with_state(networks[0].metrics.clone())
+        .merge(
+            Router::new()
+                .route("/metrics", get(all_metrics_handler))
+                .route("/buildinfo", get(buildinfo_handler))
+                .route("/healthz", get(healthz_handler))
+                .route("/readyz", get(readyz_handler))
+                .route("/reload", axum::routing::post(reload_handler))
+                .with_state(networks.clone()),
+        );
+    #[cfg(feature = "profiling")]
+    {
+        use traffic_scan_core::profiling;
+        app = app
+            .route(profiling::PROFILE_PATH, get(profile_handler))
+            .route(profiling::HEAP_PATH, get(heap_handler));
+    }
+    for (index, network) in networks.iter().enumerate() {
+        if let Some(name) = &network.name {
+            app = app
+                .nest(
+                    &format!("/networks/{}", name),
+                    network_routes()
+                        .route("/metrics", get(metrics_handler))
+                        .with_state(network.metrics.clone()),
+                )
+                .route(
+                    &format!("/networks/{}/reload", name),
+                    axum::routing::post(network_reload_handler)
+                        .with_state((networks.clone(), index)),
+                );
+        }
+    }
+
+    let port: u16 = shared_config::var("LISTEN_PORT")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(59122);
+    // METRICS_TLS_* half set, unreadable or without the tls feature: refuse to fall back to
+    // plain HTTP
+    #[cfg(feature = "tls")]
+    let tls = match traffic_scan_core::tls::acceptor_from_env() {
+        Ok(tls) => tls,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    #[cfg(not(feature = "tls"))]
+    if let Err(e) = traffic_scan_core::server::reject_tls_env() {
+        error!("{}", e);
+        std::process::exit(2);
+    }
+    let auth = traffic_scan_core::MetricsAuth::from_env();
+    if auth.is_enabled() {
+        info!("Metrics server requires authentication");
+    }
+    let app = router::with_auth(app, auth, serve::skips_auth);
+
+    let listener = tokio::net::TcpListener::bind(("0.0.0.0", port))
+        .await
+        .unwrap();
+
+    #[cfg(feature = "tls")]
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    #[cfg(not(feature = "tls"))]
+    let scheme = "http";
+    info!(
+        "Metrics server listening on {}://0.0.0.0:{}/metrics",
+        scheme, port
+    );
+
+    // Type=notify: ready once every capture has opened its interface or failed to; the
+    // publish loops pet the watchdog
+    task::spawn(notify_systemd(networks.clone()));
+
+    // Keep serving while the final window is published, so the last scrape sees it
+    let (stop_serving, serving_stopped) = tokio::sync::oneshot::channel::<()>();
+    let stopped = async move {
+        let _ = serving_stopped.await;
+    };
+    let server = task::spawn(async move {
+        #[cfg(feature = "tls")]
+        if let Some(acceptor) = tls {
+            router::serve_tls(listener, app, acceptor, stopped).await;
+            return Ok(());
+        }
+        axum::serve(listener, app)
+            .with_graceful_shutdown(stopped)
+            .await
+    });
+
+    wait_for_shutdown(&networks).await;
+    info!("Shutting down");
+    traffic_scan_core::systemd::stopping();
+    for network in networks.iter() {
+        network.shutdown().await;
+    }
+    // Stream clients got the final window and never hang up on their own
+    for network in networks.iter() {
+        network.metrics.live.close();
+    }
+    let grace = shared_config::var("SHUTDOWN_GRACE_SECS")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(SHUTDOWN_GRACE);
+    if !grace.is_zero() {
+        info!("Serving the final window for {:?}", grace);
+        tokio::time::sleep(grace).await;
+    }
+    let _ = stop_serving.send(());
+    match tokio::time::timeout(SHUTDOWN_DRAIN_TIMEOUT, server).await {
+        Ok(Ok(Ok(()))) => {}
+        Ok(Ok(Err(e))) => error!("Metrics server failed: {}", e),
+        Ok(Err(e)) => error!("Metrics server task failed: {}", e),
+        Err(_) => warn!(
+            "HTTP connections not closed within {:?}, exiting",
+            SHUTDOWN_DRAIN_TIMEOUT
+        ),
+    }
+}
+
+// Resolves on SIGTERM or Ctrl-C; SIGHUP reloads the settings meanwhile
+async fn wait_for_shutdown(networks: &[NetworkRuntime]) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("failed to handle SIGTERM");
+        let mut hangup = signal(SignalKind::hangup()).expect("failed to handle SIGHUP");
+        loop {
+            tokio::select! {
+                _ = terminate.recv() => return,
+                _ = tokio::signal::ctrl_c() => return,
+                _ = hangup.recv() => {
+                    info!("SIGHUP received, reloading settings");
+                    if let Err(e) = reload(networks).await {
+                        error!("{}", e);
+                    }
+                }
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = networks;
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+// Re-read the --config file and apply it to the given networks
+async fn reload(networks: &[NetworkRuntime]) -> Result<(), String> {
+    shared_config::reload()?;
+    for network in networks {
+        network.reload().await;
+    }
+    Ok(())
+}
+
+// Endpoints of one network, served at the root for the first network and under
+// /networks/<name> for every named one
+fn network_routes() -> Router<TrafficMetrics> {
+    Router::new()
+        .route("/window.json", get(window_handler))
+        .route("/stream", get(stream_handler))
+        .route("/remotes", get(remotes_handler))
+        .route("/top", get(top_handler))
+        .route("/targets", get(targets_handler))
+        .route("/devices", get(devices_handler))
+        .route(
+            "/control/capture",
+            axum::routing::post(capture_control_handler),
+        )
+}
+
+// A running network, with what reload and shutdown need to restart or stop its capture
+struct NetworkRuntime {
+    // Position in NETWORKS and name, to read its settings again
+    index: usize,
+    name: Option<String>,
+    // Synthetic traffic instead of a capture (--simulate)
+    simulated: bool,
+    metrics: TrafficMetrics,
+    capture: Mutex<RunningCapture>,
+}
+
+struct RunningCapture {
+    settings: CaptureSettings,
+    // Set to end the current capture threads
+    stop: Arc<AtomicBool>,
+    // Kept to start capture threads on reload; dropped on shutdown so the queue can drain
+    packets: Option<Sender<CapturedPacket>>,
+    processing: Option<std::thread::JoinHandle<()>>,
+}
+
+// Set up one network and start its capture and background tasks; settings are read with
+// the network's NETWORK_<NAME>_ overrides
+async fn start_network(
+    index: usize,
+    name: Option<String>,
+    scenario: Option<Arc<shared_sim::Scenario>>,
+    watchdog: Arc<WatchdogGate>,
+) -> NetworkRuntime {
+    let metrics = network::scoped(index, name.as_deref(), || {
+        let registry = match &name {
+            Some(name) => Registry::new_custom(
+                None,
+                Some(HashMap::from([(LABEL_NETWORK.to_string(), name.clone())])),
+            )
+            .expect("failed to create network registry"),
+            None => Registry::new(),
+        };
+        TrafficMetrics::new(Arc::new(registry))
+    });
+    watchdog.register(metrics.health.clone());
+    let metrics_clone_for_processing = metrics.clone();
+    let metrics_clone_for_tick = metrics.clone();
+    let metrics_clone_for_status = metrics.clone();
+
+    if let Some(scenario) = &scenario {
+        simulate::load_status(&metrics, scenario);
+    } else {
+        // Start from the cached status so packets keep their WAN while the API is down;
+        // without one, wait for the first fetch
+        let fetched = match metrics.status_tracker.load_cache() {
+            Some(status) => {
+                metrics.status.store(Some(Arc::new(status)));
+                false
+            }
+            None => {
+                metrics.fetch_status().await;
+                true
+            }
+        };
+
+        // Status更新タスク (10秒ごと、失敗時はバックオフして再試行)
+        task::spawn(async move {
+            let status = &metrics_clone_for_status;
+            if fetched {
+                tokio::time::sleep(status.status_tracker.next_delay()).await;
+            }
+            loop {
+                status.fetch_status().await;
+                tokio::time::sleep(status.status_tracker.next_delay()).await;
+            }
+        });
+    }
+
+    // パケットのキャプチャと集計は非同期ランタイムの外の専用スレッドで行う
+    let (settings, queue_size) = network::scoped(index, name.as_deref(), || {
+        let queue_size = network::var("CAPTURE_QUEUE_SIZE")
+            .ok()
+            .and_then(|v| v.trim().parse::<usize>().ok())
+            .unwrap_or(65536);
+        (CaptureSettings::from_env(), queue_size)
+    });
+    let (packet_tx, packet_rx) = crossbeam_channel::bounded(queue_size);
+    let stop = Arc::new(AtomicBool::new(false));
+    let simulated = scenario.is_some();
+    match scenario {
+        Some(scenario) => {
+            let metrics = metrics.clone();
+            let stop = stop.clone();
+            let packet_tx = packet_tx.clone();
+            std::thread::Builder::new()
+                .name("simulate".to_string())
+                .spawn(move || simulate::run_generator(&metrics, &scenario, &stop, &packet_tx))
+                .expect("failed to spawn simulation thread");
+        }
+        None => network::scoped(index, name.as_deref(), || {
+            spawn_capture(
+                metrics.clone(),
+                settings.clone(),
+                stop.clone(),
+                packet_tx.clone(),
+            )
+        }),
+    }
+    let processing = std::thread::Builder::new()
+        .name("packet-processing".to_string())
+        .spawn(move || process_packets(&metrics_clone_for_processing, &packet_rx))
+        .expect("failed to spawn packet processing thread");
+    #[cfg(feature = "flow-export")]
+    if let Some(exporter) = metrics.flow_export.clone() {
+        std::thread::Builder::new()
+            .name("flow-export".to_string())
+            .spawn(move || exporter.run())
+            .expect("failed to spawn flow export thread");
+    }
 
     // 1秒ごとにバイト数を公開するタスク
     task::spawn(async move {
+        let alignment = metrics_clone_for_tick.alignment;
         let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(1));
         loop {
-            interval.tick().await;
-            metrics_clone_for_tick.publish_bytes_and_reset();
+            if alignment == WindowAlignment::WallClock {
+                // Re-align on every tick so the boundary follows the wall clock
+                tokio::time::sleep(until_next_second()).await;
+            } else {
+                interval.tick().await;
+            }
+            if alignment != WindowAlignment::Scrape {
+                metrics_clone_for_tick.publish_bytes_and_reset();
+            }
+            if let Some(remote_write) = &metrics_clone_for_tick.remote_write {
+                remote_write.collect();
+            }
+            metrics_clone_for_tick.sampler.adjust();
+            metrics_clone_for_tick.amplification.evaluate_and_reset();
+            metrics_clone_for_tick.flows.publish_and_reset();
+            metrics_clone_for_tick.tcp_quality.publish_and_reset();
+            metrics_clone_for_tick.multicast.publish_and_reset();
+            if let Some(inventory) = &metrics_clone_for_tick.inventory {
+                inventory.tick();
+            }
+            metrics_clone_for_tick.health.published();
+            watchdog.pet();
         }
     });
 
-    // Prometheus メトリクスエンドポイント
-    let app = Router::new()
-        .route("/metrics", get(metrics_handler))
-        .with_state(metrics.clone());
+    // Save the remote inventory every minute
+    if let Some(inventory) = metrics.inventory.clone() {
+        task::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let inventory = Arc::clone(&inventory);
+                match task::spawn_blocking(move || inventory.save()).await {
+                    Ok(Err(e)) => error!("Failed to save remote inventory: {}", e),
+                    Err(e) => error!("Remote inventory save task failed: {}", e),
+                    Ok(Ok(())) => {}
+                }
+            }
+        });
+    }
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:59122")
-        .await
-        .unwrap();
+    // Pick up updated GeoIP databases
+    if let Some(geoip) = metrics.geoip.clone() {
+        task::spawn(async move {
+            let mut interval = tokio::time::interval(geoip.reload_interval());
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let geoip = Arc::clone(&geoip);
+                if let Err(e) = task::spawn_blocking(move || geoip.reload()).await {
+                    error!("GeoIP reload task failed: {}", e);
+                }
+            }
+        });
+    }
+
+    if let Some(reverse_dns) = metrics.reverse_dns.clone() {
+        task::spawn(async move { reverse_dns.run().await });
+    }
 
-    info!("Metrics server listening on http://0.0.0.0:59122/metrics");
+    if let Some(remote_write) = metrics.remote_write.clone() {
+        task::spawn(remote_write.run());
+    }
 
-    axum::serve(listener, app).await.unwrap();
+    NetworkRuntime {
+        index,
+        name,
+        simulated,
+        metrics,
+        capture: Mutex::new(RunningCapture {
+            settings,
+            stop,
+            packets: Some(packet_tx),
+            processing: Some(processing),
+        }),
+    }
+}
+
+impl NetworkRuntime {
+    // Apply changed LOCAL_CIDRS, STATUS_URL and capture settings; the registry and the
+    // published series are kept
+    async fn reload(&self) {
+        let (local_cidrs, status_url, settings) =
+            network::scoped(self.index, self.name.as_deref(), || {
+                (
+                    local_cidrs_from_env(),
+                    status_url_from_env(),
+                    CaptureSettings::from_env(),
+                )
+            });
+        if **self.metrics.local_cidrs.load() != local_cidrs {
+            info!("Reload: applying {} local CIDRs", local_cidrs.len());
+            self.metrics.local_cidrs.store(Arc::new(local_cidrs));
+        }
+        if self.simulated {
+            return;
+        }
+        if **self.metrics.status_url.load() != status_url {
+            info!("Reload: status URL is now {}", status_url);
+            self.metrics.status_url.store(Arc::new(status_url));
+            self.metrics.fetch_status().await;
+        }
+
+        let mut capture = self.capture.lock().unwrap();
+        if capture.settings == settings {
+            return;
+        }
+        let Some(packets) = capture.packets.clone() else {
+            return;
+        };
+        info!(
+            "Reload: restarting capture on {} ({:?})",
+            settings.interface_name, settings.tuning
+        );
+        capture.stop.store(true, Ordering::Relaxed);
+        let stop = Arc::new(AtomicBool::new(false));
+        network::scoped(self.index, self.name.as_deref(), || {
+            spawn_capture(
+                self.metrics.clone(),
+                settings.clone(),
+                stop.clone(),
+                packets,
+            )
+        });
+        capture.settings = settings;
+        capture.stop = stop;
+    }
+
+    // Stop capturing, let the processing thread drain the queue, then publish what was
+    // counted since the last window and save the remote inventory
+    async fn shutdown(&self) {
+        let processing = {
+            let mut capture = self.capture.lock().unwrap();
+            capture.stop.store(true, Ordering::Relaxed);
+            capture.packets = None;
+            capture.processing.take()
+        };
+        if let Some(processing) = processing {
+            let drained = tokio::time::timeout(
+                SHUTDOWN_DRAIN_TIMEOUT,
+                task::spawn_blocking(move || processing.join()),
+            )
+            .await;
+            if drained.is_err() {
+                warn!(
+                    "Packet queue not drained within {:?}, publishing what was counted",
+                    SHUTDOWN_DRAIN_TIMEOUT
+                );
+            }
+        }
+        self.metrics.publish_bytes_and_reset();
+        info!("Published the final window");
+        if let Some(remote_write) = &self.metrics.remote_write {
+            remote_write.collect();
+            remote_write.flush().await;
+        }
+        if let Some(store) = self.metrics.flow_store.clone() {
+            if let Err(e) = task::spawn_blocking(move || store.close()).await {
+                error!("Flow persistence close task failed: {}", e);
+            }
+        }
+
+        if let Some(inventory) = self.metrics.inventory.clone() {
+            match task::spawn_blocking(move || inventory.save()).await {
+                Ok(Err(e)) => error!("Failed to save remote inventory: {}", e),
+                Err(e) => error!("Remote inventory save task failed: {}", e),
+                Ok(Ok(())) => {}
+            }
+        }
+    }
+}
+
+// With WINDOW_ALIGNMENT=scrape, the scrape itself closes the window
+fn scrape_closes_window(metrics: &TrafficMetrics, params: &HashMap<String, String>) {
+    let snapshot = params
+        .get("snapshot")
+        .is_some_and(|v| v == "true" || v == "1");
+    if snapshot && metrics.alignment == WindowAlignment::Scrape {
+        metrics.publish_bytes_and_reset();
+    }
 }
 
 async fn metrics_handler(
     axum::extract::State(metrics): axum::extract::State<TrafficMetrics>,
+    axum::extract::Query(params): axum::extract::Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    scrape_closes_window(&metrics, &params);
+    let mut families = metrics.registry.gather();
+    metrics.metric_filter.apply(&mut families);
+    server::encode_text(&families)
+}
+
+// Every network in one exposition; their series are told apart by the network label
+async fn all_metrics_handler(
+    axum::extract::State(networks): axum::extract::State<Arc<Vec<NetworkRuntime>>>,
+    axum::extract::Query(params): axum::extract::Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let mut families: BTreeMap<String, MetricFamily> = BTreeMap::new();
+    for network in networks.iter() {
+        scrape_closes_window(&network.metrics, &params);
+        let mut gathered = network.metrics.registry.gather();
+        network.metrics.metric_filter.apply(&mut gathered);
+        for mut family in gathered {
+            match families.get_mut(family.get_name()) {
+                Some(known) => {
+                    for metric in family.take_metric() {
+                        known.mut_metric().push(metric);
+                    }
+                }
+                None => {
+                    families.insert(family.get_name().to_string(), family);
+                }
+            }
+        }
+    }
+    let families: Vec<MetricFamily> = families.into_values().collect();
+    server::encode_text(&families)
+}
+
+async fn buildinfo_handler() -> axum::Json<BuildInfo> {
+    axum::Json(build_info!())
+}
+
+// CPU profile in pprof's protobuf format; ?seconds= (default 30) and ?frequency= (Hz)
+#[cfg(feature = "profiling")]
+async fn profile_handler(uri: axum::http::Uri) -> axum::response::Response {
+    use traffic_scan_core::profiling::{self, ProfileRequest};
+
+    let request = ProfileRequest::from_query(uri.query());
+    info!(
+        "Collecting a {:?} CPU profile at {} Hz",
+        request.duration, request.frequency
+    );
+    match task::spawn_blocking(move || profiling::cpu_profile(request)).await {
+        Ok(Ok(profile)) => (
+            [(axum::http::header::CONTENT_TYPE, "application/octet-stream")],
+            profile,
+        )
+            .into_response(),
+        Ok(Err(e)) => (axum::http::StatusCode::SERVICE_UNAVAILABLE, e).into_response(),
+        Err(e) => (
+            axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+            format!("CPU profile task failed: {}", e),
+        )
+            .into_response(),
+    }
+}
+
+#[cfg(feature = "profiling")]
+async fn heap_handler() -> axum::response::Response {
+    match traffic_scan_core::profiling::heap_stats() {
+        Ok(stats) => axum::Json(stats).into_response(),
+        Err(e) => (axum::http::StatusCode::SERVICE_UNAVAILABLE, e).into_response(),
+    }
+}
+
+async fn notify_systemd(networks: Arc<Vec<NetworkRuntime>>) {
+    // Capture threads leave Starting right after opening (or failing to open) the interface
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+    while networks
+        .iter()
+        .any(|network| network.metrics.health.starting())
+        && tokio::time::Instant::now() < deadline
+    {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    traffic_scan_core::systemd::ready();
+}
+
+// The systemd watchdog, petted from the 1-second publish loops. With NETWORKS every
+// network's loop pets it, so each checks all networks and a stuck one stops the petting.
+// Packet age is left to /healthz so a quiet link does not get the service restarted.
+struct WatchdogGate {
+    watchdog: Mutex<traffic_scan_core::systemd::Watchdog>,
+    networks: Mutex<Vec<Arc<Health>>>,
+    petting: AtomicBool,
+}
+
+impl WatchdogGate {
+    fn from_env() -> Self {
+        Self {
+            watchdog: Mutex::new(traffic_scan_core::systemd::Watchdog::from_env()),
+            networks: Mutex::new(Vec::new()),
+            petting: AtomicBool::new(true),
+        }
+    }
+
+    fn register(&self, health: Arc<Health>) {
+        self.networks.lock().unwrap().push(health);
+    }
+
+    fn pet(&self) {
+        let ok = self
+            .networks
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|health| health.watchdog_ok());
+        if ok {
+            self.watchdog.lock().unwrap().pet();
+        } else if self.petting.load(Ordering::Relaxed) {
+            warn!("Capture or publish loop stuck, no longer petting the systemd watchdog");
+        }
+        self.petting.store(ok, Ordering::Relaxed);
+    }
+}
+
+// 503 when a capture is failing or has gone quiet on any network
+async fn healthz_handler(
+    axum::extract::State(networks): axum::extract::State<Arc<Vec<NetworkRuntime>>>,
+) -> impl IntoResponse {
+    health_response(&networks, |report| report.live)
+}
+
+// 503 also while the status API is failing
+async fn readyz_handler(
+    axum::extract::State(networks): axum::extract::State<Arc<Vec<NetworkRuntime>>>,
+) -> impl IntoResponse {
+    health_response(&networks, |report| report.ready)
+}
+
+fn health_response(
+    networks: &[NetworkRuntime],
+    ok: impl Fn(&health::HealthReport) -> bool,
+) -> axum::response::Response {
+    let reports: Vec<health::HealthReport> = networks.iter().map(network_health).collect();
+    let code = if reports.iter().all(ok) {
+        axum::http::StatusCode::OK
+    } else {
+        axum::http::StatusCode::SERVICE_UNAVAILABLE
+    };
+    (code, axum::Json(reports)).into_response()
+}
+
+fn network_health(network: &NetworkRuntime) -> health::HealthReport {
+    let metrics = &network.metrics;
+    metrics.health.report(
+        network.name.as_deref(),
+        metrics.status.load().is_some(),
+        metrics.status_tracker.consecutive_failures(),
+    )
+}
+
+async fn remotes_handler(
+    axum::extract::State(metrics): axum::extract::State<TrafficMetrics>,
+    axum::extract::Query(query): axum::extract::Query<inventory::RemotesQuery>,
+) -> impl IntoResponse {
+    let Some(inventory) = &metrics.inventory else {
+        return (
+            axum::http::StatusCode::NOT_FOUND,
+            "remote inventory is disabled (REMOTE_INVENTORY not set)",
+        )
+            .into_response();
+    };
+    match inventory.query(&query) {
+        Ok(page) => axum::Json(page).into_response(),
+        Err(e) => (axum::http::StatusCode::BAD_REQUEST, e).into_response(),
+    }
+}
+
+async fn top_handler(
+    axum::extract::State(metrics): axum::extract::State<TrafficMetrics>,
+    axum::extract::Query(query): axum::extract::Query<top::TopQuery>,
+) -> impl IntoResponse {
+    match &metrics.top {
+        Some(top) => axum::Json(top.query(&query)).into_response(),
+        None => (
+            axum::http::StatusCode::NOT_FOUND,
+            "top talkers are disabled (TOP_MAX_WINDOWS=0)",
+        )
+            .into_response(),
+    }
+}
+
+async fn targets_handler(
+    axum::extract::State(metrics): axum::extract::State<TrafficMetrics>,
+    axum::extract::Query(query): axum::extract::Query<targets::TargetsQuery>,
+) -> impl IntoResponse {
+    match metrics.last_window.read().unwrap().as_ref() {
+        Some(window) => axum::Json(targets::from_window(window, &query)).into_response(),
+        None => (
+            axum::http::StatusCode::SERVICE_UNAVAILABLE,
+            "no window has been published yet",
+        )
+            .into_response(),
+    }
+}
+
+async fn devices_handler(
+    axum::extract::State(metrics): axum::extract::State<TrafficMetrics>,
+    axum::extract::Query(query): axum::extract::Query<fingerprint::DevicesQuery>,
+) -> impl IntoResponse {
+    match &metrics.fingerprints {
+        Some(fingerprints) => axum::Json(fingerprints.query(&query)).into_response(),
+        None => (
+            axum::http::StatusCode::NOT_FOUND,
+            "OS fingerprinting is disabled (OS_FINGERPRINT not set)",
+        )
+            .into_response(),
+    }
+}
+
+// Layout of /window.json and /stream to return: 400 for a version that is not a number,
+// 406 for one older than any layout served
+fn negotiate_window_version(
+    requested: Option<&str>,
+) -> Result<u32, (axum::http::StatusCode, String)> {
+    use shared_schema::SchemaError;
+
+    shared_schema::negotiate(requested, WINDOW_SCHEMA_VERSION).map_err(|e| match e {
+        SchemaError::Malformed(_) => (axum::http::StatusCode::BAD_REQUEST, e.to_string()),
+        _ => (axum::http::StatusCode::NOT_ACCEPTABLE, e.to_string()),
+    })
+}
+
+async fn window_handler(
+    axum::extract::State(metrics): axum::extract::State<TrafficMetrics>,
+    axum::extract::Query(params): axum::extract::Query<HashMap<String, String>>,
+    headers: axum::http::HeaderMap,
 ) -> impl IntoResponse {
-    metrics.encode_metrics()
+    // Consumers state the newest layout they understand, by query or header
+    let requested = params
+        .get(SCHEMA_VERSION_QUERY)
+        .map(String::as_str)
+        .or_else(|| {
+            headers
+                .get(SCHEMA_VERSION_HEADER)
+                .and_then(|v| v.to_str().ok())
+        });
+    let version = match negotiate_window_version(requested) {
+        Ok(version) => version,
+        Err(rejection) => return rejection.into_response(),
+    };
+    match metrics.last_window.read().unwrap().clone() {
+        Some(snapshot) => axum::Json(WindowSnapshot {
+            schema_version: version,
+            ..snapshot
+        })
+        .into_response(),
+        None => (
+            axum::http::StatusCode::SERVICE_UNAVAILABLE,
+            "no window has been published yet",
+        )
+            .into_response(),
+    }
 }
 
-async fn monitor_interface(metrics: TrafficMetrics, interface_name: &str) {
-    loop {
+async fn stream_handler(
+    axum::extract::State(metrics): axum::extract::State<TrafficMetrics>,
+    axum::extract::Query(query): axum::extract::Query<stream::StreamQuery>,
+    headers: axum::http::HeaderMap,
+) -> impl IntoResponse {
+    use axum::response::sse::{Event, KeepAlive, Sse};
+    use tokio::sync::broadcast::error::RecvError;
+
+    // Same payload as /window.json, so the same negotiation applies
+    let requested = query.schema_version.as_deref().or_else(|| {
+        headers
+            .get(SCHEMA_VERSION_HEADER)
+            .and_then(|v| v.to_str().ok())
+    });
+    let version = match negotiate_window_version(requested) {
+        Ok(version) => version,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let filter = match StreamFilter::parse(&query) {
+        Ok(filter) => filter,
+        Err(e) => return (axum::http::StatusCode::BAD_REQUEST, e).into_response(),
+    };
+    let Some((slot, receiver)) = metrics.live.subscribe() else {
+        return (
+            axum::http::StatusCode::SERVICE_UNAVAILABLE,
+            "too many stream clients (STREAM_MAX_CLIENTS)",
+        )
+            .into_response();
+    };
+
+    let events = futures_util::stream::unfold(
+        (receiver, filter, slot),
+        move |(mut receiver, filter, mut slot)| async move {
+            loop {
+                // Windows already sent go out first, the final one included
+                let received = tokio::select! {
+                    biased;
+                    received = receiver.recv() => received,
+                    () = slot.closed() => return None,
+                };
+                match received {
+                    Ok(window) => {
+                        let filtered = WindowSnapshot {
+                            schema_version: version,
+                            entries: window
+                                .entries
+                                .iter()
+                                .filter(|entry| filter.matches(&entry.labels))
+                                .cloned()
+                                .collect(),
+                            ..(*window).clone()
+                        };
+                        let event = Event::default().event("window").json_data(&filtered);
+                        return Some((event, (receiver, filter, slot)));
+                    }
+                    // A slow client skips windows rather than delaying the others
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("Stream client fell behind, skipped {} windows", skipped)
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        },
+    );
+    Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+// Same as SIGHUP. Every network is reloaded, so the token must be the CONTROL_TOKEN of
+// each of them; /networks/<name>/reload reloads a single network with its own token
+async fn reload_handler(
+    axum::extract::State(networks): axum::extract::State<Arc<Vec<NetworkRuntime>>>,
+    headers: axum::http::HeaderMap,
+) -> impl IntoResponse {
+    for network in networks.iter() {
+        if let Err(rejection) = authorize(&network.metrics, &headers) {
+            return rejection.into_response();
+        }
+    }
+    info!("Reloading settings through control API");
+    match reload(&networks).await {
+        Ok(()) => axum::http::StatusCode::NO_CONTENT.into_response(),
+        Err(e) => {
+            error!("{}", e);
+            (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e).into_response()
+        }
+    }
+}
+
+// Reload one named network, authorized with its own CONTROL_TOKEN
+async fn network_reload_handler(
+    axum::extract::State((networks, index)): axum::extract::State<(
+        Arc<Vec<NetworkRuntime>>,
+        usize,
+    )>,
+    headers: axum::http::HeaderMap,
+) -> impl IntoResponse {
+    let network = &networks[index];
+    if let Err(rejection) = authorize(&network.metrics, &headers) {
+        return rejection.into_response();
+    }
+    info!(
+        "Reloading settings of network {} through control API",
+        network.name.as_deref().unwrap_or_default()
+    );
+    match reload(std::slice::from_ref(network)).await {
+        Ok(()) => axum::http::StatusCode::NO_CONTENT.into_response(),
+        Err(e) => {
+            error!("{}", e);
+            (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e).into_response()
+        }
+    }
+}
+
+// Bearer token check shared by the control endpoints
+fn authorize(
+    metrics: &TrafficMetrics,
+    headers: &axum::http::HeaderMap,
+) -> Result<(), (axum::http::StatusCode, &'static str)> {
+    let Some(token) = metrics.control_token.as_deref() else {
+        return Err((
+            axum::http::StatusCode::FORBIDDEN,
+            "control API is disabled (CONTROL_TOKEN not set)",
+        ));
+    };
+    let authorized = headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .is_some_and(|provided| constant_time_eq(provided.as_bytes(), token.as_bytes()));
+    if !authorized {
+        return Err((axum::http::StatusCode::UNAUTHORIZED, ""));
+    }
+    Ok(())
+}
+
+async fn capture_control_handler(
+    axum::extract::State(metrics): axum::extract::State<TrafficMetrics>,
+    headers: axum::http::HeaderMap,
+    axum::Json(control): axum::Json<CaptureControl>,
+) -> impl IntoResponse {
+    if let Err(rejection) = authorize(&metrics, &headers) {
+        return rejection.into_response();
+    }
+
+    let paused = matches!(control.action, CaptureAction::Pause);
+    if metrics.capture_paused.swap(paused, Ordering::Relaxed) != paused {
+        info!(
+            "Packet capture {} through control API",
+            if paused { "paused" } else { "resumed" }
+        );
+    }
+    metrics.capture_paused_gauge.set(paused as i64);
+    axum::Json(CaptureState { paused }).into_response()
+}
+
+fn process_packets(metrics: &TrafficMetrics, packets: &Receiver<CapturedPacket>) {
+    for packet in packets {
+        metrics.record(&packet);
+    }
+}
+
+// Start the capture threads: TPACKET_V3 ring workers on Linux, otherwise a pnet channel,
+// plus one pnet channel per WAN capture point
+fn spawn_capture(
+    metrics: TrafficMetrics,
+    settings: CaptureSettings,
+    stop: Arc<AtomicBool>,
+    packets: Sender<CapturedPacket>,
+) {
+    let tuning = settings.tuning;
+    for point in metrics.crosscheck.iter().flat_map(|c| c.points()) {
+        let point_settings = CaptureSettings {
+            interface_name: point.interface_name.clone(),
+            tuning: CaptureTuning {
+                cpu: None,
+                ..tuning
+            },
+            filter: None,
+        };
+        let metrics = metrics.clone();
+        let stop = stop.clone();
+        let role = point.role.clone();
+        let health = metrics.health.register(&role, &point.interface_name, None);
+        std::thread::Builder::new()
+            .name(format!("capture-{}", role))
+            .spawn(move || {
+                monitor_interface(
+                    &metrics,
+                    &point_settings,
+                    &stop,
+                    &health,
+                    |interface, packet| {
+                        metrics.record_capture_point(&role, interface, &packet);
+                        true
+                    },
+                )
+            })
+            .expect("failed to spawn capture point thread");
+    }
+    #[cfg(target_os = "linux")]
+    if tuning.backend == CaptureBackend::Auto {
+        match ring::probe(tuning) {
+            Ok(()) => {
+                let config = ring::RingConfig::from_env(tuning.workers);
+                for worker in 0..tuning.workers {
+                    let metrics = metrics.clone();
+                    let settings = settings.clone();
+                    let stop = stop.clone();
+                    let packets = packets.clone();
+                    let health =
+                        metrics
+                            .health
+                            .register("lan", &settings.interface_name, Some(worker));
+                    std::thread::Builder::new()
+                        .name(format!("capture-{}", worker))
+                        .spawn(move || {
+                            let gave_up = ring::run_worker(
+                                &metrics, &settings, worker, config, &stop, &health, &packets,
+                            );
+                            if !gave_up {
+                                return;
+                            }
+                            // The first worker carries on with pnet; a single pnet channel
+                            // cannot be shared, so the others stop
+                            if worker > 0 {
+                                health.set(CaptureStatus::Closed);
+                                return;
+                            }
+                            warn!(
+                                "Capture ring unusable on {}, using pnet",
+                                settings.interface_name
+                            );
+                            monitor_interface(&metrics, &settings, &stop, &health, |_, packet| {
+                                queue_packet(&metrics, packet, &packets)
+                            })
+                        })
+                        .expect("failed to spawn capture thread");
+                }
+                return;
+            }
+            Err(e) => warn!("TPACKET_V3 capture ring unavailable, using pnet: {}", e),
+        }
+    }
+
+    let health = metrics
+        .health
+        .register("lan", &settings.interface_name, None);
+    std::thread::Builder::new()
+        .name("capture".to_string())
+        .spawn(move || {
+            monitor_interface(&metrics, &settings, &stop, &health, |_, packet| {
+                queue_packet(&metrics, packet, &packets)
+            })
+        })
+        .expect("failed to spawn capture thread");
+}
+
+// Queue a parsed packet for processing; false once the processing thread is gone
+fn queue_packet(
+    metrics: &TrafficMetrics,
+    mut packet: CapturedPacket,
+    packets: &Sender<CapturedPacket>,
+) -> bool {
+    // Membership reports are rare and a skipped one would lose a join
+    let exempt = matches!(packet.transport, Transport::Membership(_))
+        || metrics
+            .amplification
+            .is_request(&packet, metrics.is_local_ip(packet.src));
+    if !exempt {
+        match metrics.sampler.admit() {
+            Some(scale) => {
+                packet.bytes *= scale;
+                packet.packets = scale;
+            }
+            None => return true,
+        }
+    }
+    match packets.try_send(packet) {
+        Ok(()) => true,
+        Err(TrySendError::Full(_)) => {
+            metrics.capture_dropped.inc();
+            true
+        }
+        Err(TrySendError::Disconnected(_)) => false,
+    }
+}
+
+// Runs until `stop` is set by a reload or shutdown, or `deliver` returns false
+fn monitor_interface(
+    metrics: &TrafficMetrics,
+    settings: &CaptureSettings,
+    stop: &AtomicBool,
+    health: &CaptureHealth,
+    mut deliver: impl FnMut(&NetworkInterface, CapturedPacket) -> bool,
+) {
+    let interface_name = settings.interface_name.as_str();
+    let tuning = settings.tuning;
+    // Runs on its own OS thread, so pinning only affects capture
+    if let Some(cpu) = tuning.cpu {
+        match pin_current_thread(cpu) {
+            Ok(()) => info!("Pinned capture thread to CPU {}", cpu),
+            Err(e) => warn!("Failed to pin capture thread to CPU {}: {}", cpu, e),
+        }
+    }
+
+    while !stop.load(Ordering::Relaxed) {
+        // Keep the socket closed while paused so the kernel stops copying packets to us
+        if metrics.capture_paused.load(Ordering::Relaxed) {
+            health.set(CaptureStatus::Paused);
+            std::thread::sleep(std::time::Duration::from_secs(1));
+            continue;
+        }
+
         match get_interface_by_name(interface_name) {
             Some(interface) => {
                 info!("Monitoring interface: {}", interface_name);
-                let (_tx, mut rx) = match datalink::channel(&interface, Default::default()) {
-                    Ok(datalink::Channel::Ethernet(tx, rx)) => (tx, rx),
-                    Ok(_) => {
-                        info!("Unsupported channel type for {}", interface_name);
-                        tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
-                        continue;
-                    }
-                    Err(e) => {
-                        error!("Error creating channel for {}: {}", interface_name, e);
-                        tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
-                        continue;
+                // Wake up periodically so a pause takes effect on an idle link
+                let read_timeout = std::time::Duration::from_secs(1);
+                // GRO-coalesced frames exceed the 4 KiB default and would be cut short,
+                // skewing the comparison with the ring workers at WAN capture points
+                let read_buffer_size = 65536;
+                let mut rx = match open_own_socket(settings, read_buffer_size, read_timeout) {
+                    Some(rx) => rx,
+                    None => {
+                        let config = datalink::Config {
+                            read_timeout: Some(read_timeout),
+                            read_buffer_size,
+                            ..Default::default()
+                        };
+                        match datalink::channel(&interface, config) {
+                            Ok(datalink::Channel::Ethernet(_tx, rx)) => rx,
+                            Ok(_) => {
+                                info!("Unsupported channel type for {}", interface_name);
+                                health.failed("unsupported channel type");
+                                std::thread::sleep(std::time::Duration::from_secs(5));
+                                continue;
+                            }
+                            Err(e) => {
+                                error!("Error creating channel for {}: {}", interface_name, e);
+                                health.failed(&e);
+                                std::thread::sleep(std::time::Duration::from_secs(5));
+                                continue;
+                            }
+                        }
                     }
                 };
+                health.set(CaptureStatus::Open);
 
                 loop {
+                    if stop.load(Ordering::Relaxed) {
+                        info!("Closing capture on {}", interface_name);
+                        health.set(CaptureStatus::Closed);
+                        return;
+                    }
+                    if metrics.capture_paused.load(Ordering::Relaxed) {
+                        info!("Closing capture on {} while paused", interface_name);
+                        break;
+                    }
+                    health.polled();
                     match rx.next() {
-                        Ok(packet) => {
-                            // Parse Ethernet frame first
-                            if let Some(eth) = EthernetPacket::new(packet) {
-                                match eth.get_ethertype() {
-                                    EtherTypes::Ipv4 => {
-                                        if let Some(ipv4) = Ipv4Packet::new(eth.payload()) {
-                                            let src_ip = ipv4.get_source().to_string();
-                                            let dst_ip = ipv4.get_destination().to_string();
-                                            let packet_len = ipv4.packet().len() as u64;
-
-                                            metrics
-                                                .record_packet(&src_ip, &dst_ip, packet_len)
-                                                .await;
-                                        }
-                                    }
-                                    EtherTypes::Ipv6 => {
-                                        if let Some(ipv6) = Ipv6Packet::new(eth.payload()) {
-                                            let src_ip = ipv6.get_source().to_string();
-                                            let dst_ip = ipv6.get_destination().to_string();
-                                            let packet_len = ipv6.packet().len() as u64;
-
-                                            metrics
-                                                .record_packet(&src_ip, &dst_ip, packet_len)
-                                                .await;
-                                        }
-                                    }
-                                    _ => {}
-                                }
+                        Ok(frame) => {
+                            health.frame();
+                            let Some(packet) =
+                                capture::parse_frame(frame, &metrics.transition, &metrics.tunnels)
+                            else {
+                                continue;
+                            };
+                            if !deliver(&interface, packet) {
+                                error!("Packet processing thread stopped, ending capture");
+                                health.failed("packet processing thread stopped");
+                                return;
                             }
                         }
+                        Err(e) if e.kind() == std::io::ErrorKind::TimedOut => continue,
                         Err(e) => {
                             error!("Error receiving packet: {}", e);
+                            health.failed(&e);
                             break;
                         }
                     }
@@ -374,10 +2606,51 @@ async fn monitor_interface(metrics: TrafficMetrics, interface_name: &str) {
             }
             None => {
                 error!("Interface {} not found, retrying...", interface_name);
-                tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+                health.failed("interface not found");
+                std::thread::sleep(std::time::Duration::from_secs(5));
             }
         }
     }
+    health.set(CaptureStatus::Closed);
+}
+
+// pnet opens its own socket and cannot apply SO_BUSY_POLL or a filter to it, so with either
+// set frames are read from a socket opened here. None when neither is set or it failed.
+#[cfg(target_os = "linux")]
+fn open_own_socket(
+    settings: &CaptureSettings,
+    read_buffer_size: usize,
+    read_timeout: std::time::Duration,
+) -> Option<Box<dyn datalink::DataLinkReceiver>> {
+    if settings.tuning.busy_poll_usecs.is_none() && settings.filter.is_none() {
+        return None;
+    }
+    match ring::PacketSocket::open(
+        &settings.interface_name,
+        settings.tuning,
+        settings.filter.as_deref(),
+        read_buffer_size,
+        read_timeout,
+    ) {
+        Ok(socket) => Some(Box::new(socket)),
+        // Fall back to an unfiltered socket rather than not capturing at all
+        Err(e) => {
+            warn!("Failed to set up capture socket options: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_own_socket(
+    settings: &CaptureSettings,
+    _read_buffer_size: usize,
+    _read_timeout: std::time::Duration,
+) -> Option<Box<dyn datalink::DataLinkReceiver>> {
+    if settings.tuning.busy_poll_usecs.is_some() || settings.filter.is_some() {
+        warn!("SO_BUSY_POLL and capture filters are only supported on Linux");
+    }
+    None
 }
 
 fn get_interface_by_name(name: &str) -> Option<NetworkInterface> {
@@ -385,3 +2658,72 @@ fn get_interface_by_name(name: &str) -> Option<NetworkInterface> {
         .into_iter()
         .find(|interface| interface.name == name)
 }
+
+#[cfg(target_os = "linux")]
+fn pin_current_thread(cpu: usize) -> std::io::Result<()> {
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    unsafe { libc::CPU_SET(cpu, &mut set) };
+
+    let ret = unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_current_thread(_cpu: usize) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "CPU pinning is only supported on Linux",
+    ))
+}
+
+// Open an AF_PACKET socket with SO_BUSY_POLL and/or a BPF filter set; the ring and
+// PacketSocket bind it to the interface
+#[cfg(target_os = "linux")]
+fn open_capture_socket(
+    busy_poll_usecs: Option<u32>,
+    capture_filter: Option<&[BpfInstruction]>,
+) -> std::io::Result<i32> {
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_PACKET,
+            libc::SOCK_RAW,
+            (libc::ETH_P_ALL as u16).to_be() as libc::c_int,
+        )
+    };
+    if fd == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    if let Some(usecs) = busy_poll_usecs {
+        let value = usecs as libc::c_int;
+        let ret = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_BUSY_POLL,
+                &value as *const _ as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            let err = std::io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(err);
+        }
+        info!("Enabled SO_BUSY_POLL ({}us) on capture socket", usecs);
+    }
+
+    if let Some(program) = capture_filter {
+        if let Err(e) = filter::attach(fd, program) {
+            unsafe { libc::close(fd) };
+            return Err(e);
+        }
+        info!("Attached capture filter to capture socket");
+    }
+
+    Ok(fd)
+}
@@ -0,0 +1,175 @@
+// Multicast group membership from IGMP / MLD
+//
+// Local devices announce the groups they want with IGMP (IPv4) and MLD (IPv6) reports.
+// Tracking those reports shows which devices watch which IPTV channels, and comparing
+// the member count with the traffic arriving for the group tells a missing stream
+// (members but no bytes) from a stream nobody asked for (bytes but no members).
+// Link-local groups (224.0.0.0/24, ff01::/16, ff02::/16) are protocol chatter every
+// device joins and are ignored.
+
+use crate::capture::GroupChange;
+use crate::network;
+use dashmap::DashMap;
+use prometheus::{IntGaugeVec, Registry};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+// RFC 3376 group membership interval with the default robustness and query interval
+const DEFAULT_MEMBERSHIP_TIMEOUT_SECS: u64 = 260;
+
+pub struct MulticastTracker {
+    // Last report per (group, local_ip); a membership without reports times out
+    memberships: DashMap<(String, String), Instant>,
+    membership_timeout: Duration,
+    // Bytes sent to each group in the current 1-second window
+    window_group_bytes: DashMap<String, u64>,
+    // Every group that has had members or traffic, published as 0 when idle
+    known_groups: DashMap<String, ()>,
+    members_gauge: IntGaugeVec,
+    membership_gauge: IntGaugeVec,
+    group_bytes_gauge: IntGaugeVec,
+}
+
+impl MulticastTracker {
+    pub fn new(registry: &Registry) -> Self {
+        let membership_timeout = Duration::from_secs(
+            network::var("MULTICAST_MEMBERSHIP_TIMEOUT_SECS")
+                .ok()
+                .and_then(|v| v.trim().parse::<u64>().ok())
+                .unwrap_or(DEFAULT_MEMBERSHIP_TIMEOUT_SECS),
+        );
+        info!("Multicast membership timeout: {:?}", membership_timeout);
+
+        let members_gauge = IntGaugeVec::new(
+            prometheus::Opts::new(
+                "multicast_group_members",
+                "Local devices currently joined to a multicast group",
+            )
+            .const_label("job", "localpacketdump"),
+            &["group"],
+        )
+        .expect("failed to create multicast_group_members gauge");
+        let membership_gauge = IntGaugeVec::new(
+            prometheus::Opts::new(
+                "multicast_membership",
+                "Multicast group joined by a local device (always 1)",
+            )
+            .const_label("job", "localpacketdump"),
+            &["group", "local_ip"],
+        )
+        .expect("failed to create multicast_membership gauge");
+        let group_bytes_gauge = IntGaugeVec::new(
+            prometheus::Opts::new(
+                "multicast_group_bytes",
+                "Bytes sent to a multicast group over the last second",
+            )
+            .const_label("job", "localpacketdump"),
+            &["group"],
+        )
+        .expect("failed to create multicast_group_bytes gauge");
+        for gauge in [&members_gauge, &membership_gauge, &group_bytes_gauge] {
+            registry
+                .register(Box::new(gauge.clone()))
+                .expect("failed to register multicast gauge");
+        }
+
+        Self {
+            memberships: DashMap::new(),
+            membership_timeout,
+            window_group_bytes: DashMap::new(),
+            known_groups: DashMap::new(),
+            members_gauge,
+            membership_gauge,
+            group_bytes_gauge,
+        }
+    }
+
+    // IGMP / MLD report sent by a local device
+    pub fn record_report(&self, local_ip: &str, changes: &[GroupChange]) {
+        for change in changes.iter().filter(|c| !is_link_local(c.group)) {
+            let key = (change.group.to_string(), local_ip.to_string());
+            if change.joined {
+                if self.memberships.insert(key, Instant::now()).is_none() {
+                    info!("{} joined multicast group {}", local_ip, change.group);
+                }
+            } else if self.memberships.remove(&key).is_some() {
+                info!("{} left multicast group {}", local_ip, change.group);
+                let _ = self.membership_gauge.remove_label_values(&[&key.0, &key.1]);
+            }
+        }
+    }
+
+    // Packet addressed to a multicast group
+    pub fn record_traffic(&self, group: IpAddr, bytes: u64) {
+        if is_link_local(group) {
+            return;
+        }
+        self.window_group_bytes
+            .entry(group.to_string())
+            .and_modify(|v| *v += bytes)
+            .or_insert(bytes);
+    }
+
+    // Publish the last 1-second window, then reset it
+    pub fn publish_and_reset(&self) {
+        let timeout = self.membership_timeout;
+        self.memberships.retain(|(group, local_ip), reported| {
+            let alive = reported.elapsed() < timeout;
+            if !alive {
+                info!(
+                    "Multicast membership of {} in {} timed out",
+                    local_ip, group
+                );
+                let _ = self
+                    .membership_gauge
+                    .remove_label_values(&[group, local_ip]);
+            }
+            alive
+        });
+
+        let mut members: HashMap<String, i64> = HashMap::new();
+        for entry in self.memberships.iter() {
+            let (group, local_ip) = entry.key();
+            self.membership_gauge
+                .with_label_values(&[group, local_ip])
+                .set(1);
+            *members.entry(group.clone()).or_insert(0) += 1;
+        }
+
+        let mut current_bytes: HashSet<String> = HashSet::new();
+        for entry in self.window_group_bytes.iter() {
+            self.group_bytes_gauge
+                .with_label_values(&[entry.key()])
+                .set(*entry.value() as i64);
+            current_bytes.insert(entry.key().clone());
+        }
+        for group in members.keys().chain(current_bytes.iter()) {
+            self.known_groups.insert(group.clone(), ());
+        }
+
+        for entry in self.known_groups.iter() {
+            let group = entry.key();
+            self.members_gauge
+                .with_label_values(&[group])
+                .set(members.get(group).copied().unwrap_or(0));
+            if !current_bytes.contains(group) {
+                self.group_bytes_gauge.with_label_values(&[group]).set(0);
+            }
+        }
+
+        self.window_group_bytes.clear();
+    }
+}
+
+fn is_link_local(group: IpAddr) -> bool {
+    match group {
+        IpAddr::V4(v4) => {
+            let octets = v4.octets();
+            octets[0] == 224 && octets[1] == 0 && octets[2] == 0
+        }
+        // Interface-local (1) and link-local (2) scopes
+        IpAddr::V6(v6) => v6.segments()[0] & 0x000f <= 2,
+    }
+}
@@ -0,0 +1,88 @@
+// Several networks monitored by one process (NETWORKS)
+//
+// A router with VRFs or VLAN sub-interfaces would otherwise need one process per LAN.
+// With NETWORKS=lan,guest every named network gets its own capture, windows and
+// endpoints, and all of its metrics carry a network label. Any setting can be given per
+// network as NETWORK_<NAME>_<VAR> (NETWORK_GUEST_INTERFACE_NAME); unset ones fall back to
+// the plain <VAR>, and both can also come from the --config file. Settings are read
+// while a network is being set up or reloaded, so the lookup follows the network of the
+// current thread; the HTTP client and remote_write are handed `var` for the same reason.
+// The exceptions are the settings of the one HTTP server and of the process itself,
+// which are only read plain: NETWORKS, LISTEN_PORT, METRICS_AUTH_*, METRICS_TLS_*,
+// SHUTDOWN_GRACE_SECS and SIMULATE*.
+
+use std::cell::RefCell;
+use std::env;
+use tracing::warn;
+
+thread_local! {
+    // (position in NETWORKS, name) of the network being set up on this thread
+    static CURRENT: RefCell<Option<(usize, String)>> = const { RefCell::new(None) };
+}
+
+// Names from NETWORKS in order, or a single unnamed network when it is unset
+pub fn from_env() -> Vec<Option<String>> {
+    let mut names: Vec<Option<String>> = Vec::new();
+    for name in shared_config::var("NETWORKS")
+        .unwrap_or_default()
+        .split(',')
+    {
+        let name = name.trim();
+        if name.is_empty() {
+            continue;
+        }
+        // The name ends up in a label, a URL path and variable names
+        if !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            warn!("Ignoring network {:?}: use letters, digits, _ and -", name);
+            continue;
+        }
+        if names.iter().flatten().any(|known| known == name) {
+            warn!("Ignoring duplicate network {}", name);
+            continue;
+        }
+        names.push(Some(name.to_string()));
+    }
+    if names.is_empty() {
+        names.push(None);
+    }
+    names
+}
+
+// Run f with settings read for the given network
+pub fn scoped<R>(index: usize, name: Option<&str>, f: impl FnOnce() -> R) -> R {
+    let previous =
+        CURRENT.with(|current| current.replace(name.map(|name| (index, name.to_string()))));
+    let result = f();
+    CURRENT.with(|current| *current.borrow_mut() = previous);
+    result
+}
+
+// Name of the network being set up, None without NETWORKS
+pub fn current() -> Option<String> {
+    CURRENT.with(|current| current.borrow().as_ref().map(|(_, name)| name.clone()))
+}
+
+// Position of the network being set up in NETWORKS (0 without NETWORKS)
+pub fn index() -> usize {
+    CURRENT.with(|current| current.borrow().as_ref().map_or(0, |(index, _)| *index))
+}
+
+// shared_config::var, preferring NETWORK_<NAME>_<VAR> for the network being set up
+pub fn var(name: &str) -> Result<String, env::VarError> {
+    if let Some(network) = current() {
+        let prefix: String = network
+            .chars()
+            .map(|c| match c {
+                '-' => '_',
+                c => c.to_ascii_uppercase(),
+            })
+            .collect();
+        if let Ok(value) = shared_config::var(&format!("NETWORK_{}_{}", prefix, name)) {
+            return Ok(value);
+        }
+    }
+    shared_config::var(name)
+}
@@ -0,0 +1,566 @@
+// Per-second flow records on disk (PERSIST_FORMAT)
+//
+// Prometheus keeps per-IP series only for its retention and downsampling drops them
+// first. With PERSIST_FORMAT=sqlite or parquet, every published window is appended as
+// (timestamp_ms, remote_ip, local_ip, interface, direction, bytes, packets) rows to files
+// under PERSIST_DIR, for offline per-device analysis. A new file is started once the
+// current one reaches PERSIST_ROTATE_BYTES or PERSIST_ROTATE_SECS, and files older than
+// PERSIST_RETENTION_HOURS are deleted. Writing happens on its own thread; when it falls
+// behind, windows are dropped rather than stalling the publish loop.
+//
+// Each format needs its cargo feature (`sqlite`, `parquet`). A Parquet file is only
+// readable once closed, so a crash loses the file being written.
+
+use crate::network;
+use crossbeam_channel::{Receiver, Sender, TrySendError};
+use prometheus::{IntCounterVec, Registry};
+use shared_schema::{LABEL_INTERFACE, LABEL_LOCAL_IP, LABEL_REMOTE_IP, LABEL_REMOTE_PREFIX};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{error, info, warn};
+
+// Windows waiting for the writer thread
+const QUEUE_WINDOWS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Sqlite,
+    Parquet,
+}
+
+impl Format {
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Sqlite => "sqlite",
+            Format::Parquet => "parquet",
+        }
+    }
+}
+
+// Only read by the writers of the enabled features
+#[cfg_attr(not(any(feature = "sqlite", feature = "parquet")), allow(dead_code))]
+#[derive(Debug, Clone)]
+pub struct Record {
+    // None with PERSPECTIVE=local, and local_ip None with PERSPECTIVE=remote
+    pub remote_ip: Option<String>,
+    pub local_ip: Option<String>,
+    pub interface: String,
+    // "download" or "upload"
+    pub direction: &'static str,
+    pub bytes: u64,
+    pub packets: u64,
+}
+
+// Builds a window's records from the label values of its series
+pub struct Records {
+    remote: Option<usize>,
+    local: Option<usize>,
+    interface: Option<usize>,
+    records: Vec<Record>,
+}
+
+impl Records {
+    // `label_names` in the order of the series' label values
+    pub fn new(label_names: &[&str]) -> Self {
+        let position = |names: &[&str]| label_names.iter().position(|name| names.contains(name));
+        Self {
+            // remote_prefix with AGGREGATE_PREFIX_V4 / V6
+            remote: position(&[LABEL_REMOTE_IP, LABEL_REMOTE_PREFIX]),
+            local: position(&[LABEL_LOCAL_IP]),
+            interface: position(&[LABEL_INTERFACE]),
+            records: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, labels: &[String], direction: &'static str, bytes: u64, packets: u64) {
+        let value = |index: Option<usize>| index.and_then(|i| labels.get(i)).cloned();
+        let Some(interface) = value(self.interface) else {
+            return;
+        };
+        self.records.push(Record {
+            remote_ip: value(self.remote),
+            local_ip: value(self.local),
+            interface,
+            direction,
+            bytes,
+            packets,
+        });
+    }
+
+    pub fn into_inner(self) -> Vec<Record> {
+        self.records
+    }
+}
+
+struct Window {
+    timestamp_ms: i64,
+    records: Vec<Record>,
+}
+
+// One open file
+trait Writer: Send {
+    fn append(&mut self, timestamp_ms: i64, records: &[Record]) -> io::Result<()>;
+    fn close(self: Box<Self>) -> io::Result<()>;
+}
+
+struct Settings {
+    format: Format,
+    dir: PathBuf,
+    // File names start with this, so networks of the same process keep separate files
+    prefix: String,
+    rotate_bytes: u64,
+    rotate_age: Duration,
+    // None when PERSIST_RETENTION_HOURS is 0
+    retention: Option<Duration>,
+}
+
+pub struct FlowStore {
+    // Taken on shutdown so the writer thread closes its file
+    windows: Mutex<Option<Sender<Window>>>,
+    writer: Mutex<Option<std::thread::JoinHandle<()>>>,
+    rows: IntCounterVec,
+}
+
+impl FlowStore {
+    // None unless PERSIST_FORMAT is set
+    pub fn from_env(registry: &Registry) -> Option<Self> {
+        let format = match network::var("PERSIST_FORMAT")
+            .map(|v| v.trim().to_ascii_lowercase())
+            .as_deref()
+        {
+            Err(_) | Ok("") | Ok("none") => return None,
+            Ok("sqlite") => Format::Sqlite,
+            Ok("parquet") => Format::Parquet,
+            Ok(other) => {
+                error!("Unknown PERSIST_FORMAT {}, not persisting flows", other);
+                return None;
+            }
+        };
+        if !supported(format) {
+            error!(
+                "PERSIST_FORMAT={} needs the `{}` feature, not persisting flows",
+                format.extension(),
+                format.extension()
+            );
+            return None;
+        }
+        let number = |name: &str, default: u64| {
+            network::var(name)
+                .ok()
+                .and_then(|v| v.trim().parse::<u64>().ok())
+                .unwrap_or(default)
+        };
+        let retention_hours = number("PERSIST_RETENTION_HOURS", 168);
+        let settings = Settings {
+            format,
+            dir: PathBuf::from(network::var("PERSIST_DIR").unwrap_or_else(|_| "flows".into())),
+            prefix: match network::current() {
+                Some(name) => format!("flows-{}-", name),
+                None => "flows-".to_string(),
+            },
+            rotate_bytes: number("PERSIST_ROTATE_BYTES", 64 * 1024 * 1024).max(1),
+            rotate_age: Duration::from_secs(number("PERSIST_ROTATE_SECS", 3600).max(1)),
+            retention: (retention_hours > 0).then(|| Duration::from_secs(retention_hours * 3600)),
+        };
+        if let Err(e) = std::fs::create_dir_all(&settings.dir) {
+            error!(
+                "Failed to create PERSIST_DIR {}: {}",
+                settings.dir.display(),
+                e
+            );
+            return None;
+        }
+        info!(
+            "Persisting flow records as {:?} under {}",
+            format,
+            settings.dir.display()
+        );
+
+        let rows = IntCounterVec::new(
+            prometheus::Opts::new(
+                "persist_rows_total",
+                "Flow records written to PERSIST_DIR, or dropped because the writer fell behind or failed",
+            )
+            .const_label("job", "localpacketdump"),
+            &["result"],
+        )
+        .expect("failed to create persist_rows_total counter");
+        registry
+            .register(Box::new(rows.clone()))
+            .expect("failed to register persist_rows_total counter");
+
+        let (tx, rx) = crossbeam_channel::bounded(QUEUE_WINDOWS);
+        let thread_rows = rows.clone();
+        let writer = std::thread::Builder::new()
+            .name("flow-persist".to_string())
+            .spawn(move || run_writer(&settings, &rx, &thread_rows))
+            .expect("failed to spawn flow persistence thread");
+        Some(Self {
+            windows: Mutex::new(Some(tx)),
+            writer: Mutex::new(Some(writer)),
+            rows,
+        })
+    }
+
+    // Called from the publish loop with the closed window
+    pub fn append(&self, timestamp_ms: i64, records: Vec<Record>) {
+        if records.is_empty() {
+            return;
+        }
+        let windows = self.windows.lock().unwrap();
+        let Some(windows) = windows.as_ref() else {
+            return;
+        };
+        let count = records.len() as u64;
+        match windows.try_send(Window {
+            timestamp_ms,
+            records,
+        }) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+                self.rows.with_label_values(&["dropped"]).inc_by(count);
+            }
+        }
+    }
+
+    // Write what is queued and close the current file; called once on shutdown
+    pub fn close(&self) {
+        drop(self.windows.lock().unwrap().take());
+        if let Some(writer) = self.writer.lock().unwrap().take() {
+            if writer.join().is_err() {
+                error!("Flow persistence thread panicked");
+            }
+        }
+    }
+}
+
+fn run_writer(settings: &Settings, windows: &Receiver<Window>, rows: &IntCounterVec) {
+    remove_expired(settings);
+    let mut current: Option<(Box<dyn Writer>, PathBuf, Instant)> = None;
+    for window in windows.iter() {
+        let rotate = current.as_ref().is_some_and(|(_, path, opened)| {
+            opened.elapsed() >= settings.rotate_age
+                || std::fs::metadata(path).is_ok_and(|m| m.len() >= settings.rotate_bytes)
+        });
+        if rotate {
+            if let Some((writer, path, _)) = current.take() {
+                close(writer, &path);
+            }
+            remove_expired(settings);
+        }
+        if current.is_none() {
+            let path = settings.dir.join(format!(
+                "{}{}.{}",
+                settings.prefix,
+                chrono::Utc::now().format("%Y%m%dT%H%M%SZ"),
+                settings.format.extension()
+            ));
+            match open(settings.format, &path) {
+                Ok(writer) => {
+                    info!("Writing flow records to {}", path.display());
+                    current = Some((writer, path, Instant::now()));
+                }
+                Err(e) => error!("Failed to open {}: {}", path.display(), e),
+            }
+        }
+        let count = window.records.len() as u64;
+        let written = match &mut current {
+            Some((writer, path, _)) => match writer.append(window.timestamp_ms, &window.records) {
+                Ok(()) => true,
+                Err(e) => {
+                    error!("Failed to write flow records to {}: {}", path.display(), e);
+                    false
+                }
+            },
+            None => false,
+        };
+        let result = if written { "written" } else { "dropped" };
+        rows.with_label_values(&[result]).inc_by(count);
+    }
+    if let Some((writer, path, _)) = current.take() {
+        close(writer, &path);
+    }
+}
+
+fn close(writer: Box<dyn Writer>, path: &Path) {
+    if let Err(e) = writer.close() {
+        error!("Failed to close {}: {}", path.display(), e);
+    }
+}
+
+// Delete this network's files last modified before PERSIST_RETENTION_HOURS
+fn remove_expired(settings: &Settings) {
+    let Some(retention) = settings.retention else {
+        return;
+    };
+    let Some(oldest) = SystemTime::now().checked_sub(retention) else {
+        return;
+    };
+    let entries = match std::fs::read_dir(&settings.dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("Failed to list {}: {}", settings.dir.display(), e);
+            return;
+        }
+    };
+    let extension = format!(".{}", settings.format.extension());
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        // Another network's prefix is longer, but starts with "flows-" too
+        let Some(stamp) = name
+            .strip_prefix(settings.prefix.as_str())
+            .and_then(|rest| rest.strip_suffix(extension.as_str()))
+        else {
+            continue;
+        };
+        if !stamp.starts_with(|c: char| c.is_ascii_digit()) {
+            continue;
+        }
+        let expired = entry
+            .metadata()
+            .and_then(|m| m.modified())
+            .is_ok_and(|modified| modified < oldest);
+        if expired {
+            match std::fs::remove_file(entry.path()) {
+                Ok(()) => info!("Removed expired flow records {}", entry.path().display()),
+                Err(e) => warn!("Failed to remove {}: {}", entry.path().display(), e),
+            }
+        }
+    }
+}
+
+fn supported(format: Format) -> bool {
+    match format {
+        Format::Sqlite => cfg!(feature = "sqlite"),
+        Format::Parquet => cfg!(feature = "parquet"),
+    }
+}
+
+fn open(format: Format, path: &Path) -> io::Result<Box<dyn Writer>> {
+    match format {
+        #[cfg(feature = "sqlite")]
+        Format::Sqlite => Ok(Box::new(sqlite_writer::SqliteWriter::open(path)?)),
+        #[cfg(feature = "parquet")]
+        Format::Parquet => Ok(Box::new(parquet_writer::ParquetWriter::open(path)?)),
+        #[allow(unreachable_patterns)]
+        _ => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "cannot write {}: built without the `{}` feature",
+                path.display(),
+                format.extension()
+            ),
+        )),
+    }
+}
+
+#[cfg(feature = "sqlite")]
+mod sqlite_writer {
+    use super::{Record, Writer};
+    use rusqlite::Connection;
+    use std::io;
+    use std::path::Path;
+
+    pub struct SqliteWriter {
+        connection: Connection,
+    }
+
+    impl SqliteWriter {
+        pub fn open(path: &Path) -> io::Result<Self> {
+            let connection = Connection::open(path).map_err(io::Error::other)?;
+            connection
+                .execute_batch(
+                    "CREATE TABLE IF NOT EXISTS flows (
+                        timestamp_ms INTEGER NOT NULL,
+                        remote_ip TEXT,
+                        local_ip TEXT,
+                        interface TEXT NOT NULL,
+                        direction TEXT NOT NULL,
+                        bytes INTEGER NOT NULL,
+                        packets INTEGER NOT NULL
+                    );",
+                )
+                .map_err(io::Error::other)?;
+            Ok(Self { connection })
+        }
+    }
+
+    impl Writer for SqliteWriter {
+        // One transaction per window
+        fn append(&mut self, timestamp_ms: i64, records: &[Record]) -> io::Result<()> {
+            let transaction = self.connection.transaction().map_err(io::Error::other)?;
+            {
+                let mut insert = transaction
+                    .prepare_cached(
+                        "INSERT INTO flows (timestamp_ms, remote_ip, local_ip, interface, direction, bytes, packets)
+                         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                    )
+                    .map_err(io::Error::other)?;
+                for record in records {
+                    insert
+                        .execute(rusqlite::params![
+                            timestamp_ms,
+                            record.remote_ip,
+                            record.local_ip,
+                            record.interface,
+                            record.direction,
+                            record.bytes as i64,
+                            record.packets as i64,
+                        ])
+                        .map_err(io::Error::other)?;
+                }
+            }
+            transaction.commit().map_err(io::Error::other)
+        }
+
+        fn close(self: Box<Self>) -> io::Result<()> {
+            self.connection
+                .close()
+                .map_err(|(_, e)| io::Error::other(e))
+        }
+    }
+}
+
+#[cfg(feature = "parquet")]
+mod parquet_writer {
+    use super::{Record, Writer};
+    use parquet::basic::Compression;
+    use parquet::data_type::{ByteArray, ByteArrayType, Int64Type};
+    use parquet::file::properties::WriterProperties;
+    use parquet::file::writer::SerializedFileWriter;
+    use parquet::schema::parser::parse_message_type;
+    use std::fs::File;
+    use std::io;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    // Rows buffered before they are written out as a row group
+    const ROW_GROUP_ROWS: usize = 65536;
+
+    const SCHEMA: &str = "message flows {
+        REQUIRED INT64 timestamp_ms (TIMESTAMP(MILLIS, true));
+        OPTIONAL BYTE_ARRAY remote_ip (UTF8);
+        OPTIONAL BYTE_ARRAY local_ip (UTF8);
+        REQUIRED BYTE_ARRAY interface (UTF8);
+        REQUIRED BYTE_ARRAY direction (UTF8);
+        REQUIRED INT64 bytes;
+        REQUIRED INT64 packets;
+    }";
+
+    pub struct ParquetWriter {
+        file: SerializedFileWriter<File>,
+        rows: Vec<(i64, Record)>,
+    }
+
+    impl ParquetWriter {
+        pub fn open(path: &Path) -> io::Result<Self> {
+            let schema = Arc::new(parse_message_type(SCHEMA).map_err(io::Error::other)?);
+            let properties = Arc::new(
+                WriterProperties::builder()
+                    .set_compression(Compression::SNAPPY)
+                    .build(),
+            );
+            let file = SerializedFileWriter::new(File::create(path)?, schema, properties)
+                .map_err(io::Error::other)?;
+            Ok(Self {
+                file,
+                rows: Vec::new(),
+            })
+        }
+
+        fn write_row_group(&mut self) -> parquet::errors::Result<()> {
+            if self.rows.is_empty() {
+                return Ok(());
+            }
+            let rows = std::mem::take(&mut self.rows);
+            let strings = |field: fn(&Record) -> &str| -> Vec<ByteArray> {
+                rows.iter()
+                    .map(|(_, record)| ByteArray::from(field(record)))
+                    .collect()
+            };
+            let numbers = |field: fn(i64, &Record) -> i64| -> Vec<i64> {
+                rows.iter()
+                    .map(|(timestamp_ms, record)| field(*timestamp_ms, record))
+                    .collect()
+            };
+            // Values of the rows that have one, and definition levels (1 present, 0 null)
+            let optional = |field: fn(&Record) -> Option<&str>| -> (Vec<ByteArray>, Vec<i16>) {
+                let values = rows
+                    .iter()
+                    .filter_map(|(_, record)| field(record).map(ByteArray::from))
+                    .collect();
+                let levels = rows
+                    .iter()
+                    .map(|(_, record)| field(record).is_some() as i16)
+                    .collect();
+                (values, levels)
+            };
+
+            let mut row_group = self.file.next_row_group()?;
+            let mut index = 0;
+            while let Some(mut column) = row_group.next_column()? {
+                match index {
+                    0 => column.typed::<Int64Type>().write_batch(
+                        &numbers(|timestamp_ms, _| timestamp_ms),
+                        None,
+                        None,
+                    )?,
+                    1 | 2 => {
+                        let (values, levels) = if index == 1 {
+                            optional(|r| r.remote_ip.as_deref())
+                        } else {
+                            optional(|r| r.local_ip.as_deref())
+                        };
+                        column
+                            .typed::<ByteArrayType>()
+                            .write_batch(&values, Some(&levels), None)?
+                    }
+                    3 => column.typed::<ByteArrayType>().write_batch(
+                        &strings(|r| &r.interface),
+                        None,
+                        None,
+                    )?,
+                    4 => column.typed::<ByteArrayType>().write_batch(
+                        &strings(|r| r.direction),
+                        None,
+                        None,
+                    )?,
+                    5 => column.typed::<Int64Type>().write_batch(
+                        &numbers(|_, r| r.bytes as i64),
+                        None,
+                        None,
+                    )?,
+                    _ => column.typed::<Int64Type>().write_batch(
+                        &numbers(|_, r| r.packets as i64),
+                        None,
+                        None,
+                    )?,
+                };
+                column.close()?;
+                index += 1;
+            }
+            row_group.close()?;
+            Ok(())
+        }
+    }
+
+    impl Writer for ParquetWriter {
+        fn append(&mut self, timestamp_ms: i64, records: &[Record]) -> io::Result<()> {
+            self.rows
+                .extend(records.iter().map(|record| (timestamp_ms, record.clone())));
+            if self.rows.len() >= ROW_GROUP_ROWS {
+                self.write_row_group().map_err(io::Error::other)?;
+            }
+            Ok(())
+        }
+
+        fn close(mut self: Box<Self>) -> io::Result<()> {
+            self.write_row_group().map_err(io::Error::other)?;
+            self.file.close().map_err(io::Error::other)?;
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,142 @@
+// Handling of ICMP and other non-TCP/UDP bytes
+//
+// Pings, tunnels and routing protocols otherwise land in the same gauges as the traffic
+// users generate, so a remote that is only pinged shows up with bandwidth. Each class
+// can be kept in the byte figures (include, the default), left out of them (exclude) or
+// moved into gauges of its own (separate). TCP and UDP are always included.
+
+use crate::network;
+use dashmap::DashMap;
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use prometheus::{IntGaugeVec, Registry};
+use shared_schema::{LABEL_INTERFACE, LABEL_PROTOCOL};
+use tracing::{error, info};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolAction {
+    // Accounted like TCP and UDP
+    Include,
+    // Not accounted in any byte figure
+    Exclude,
+    // Accounted in separate_download_bytes / separate_upload_bytes only
+    Separate,
+}
+
+impl ProtocolAction {
+    fn from_env(name: &str) -> Self {
+        match network::var(name) {
+            Ok(v) => match v.trim().to_ascii_lowercase().as_str() {
+                "" | "include" => ProtocolAction::Include,
+                "exclude" => ProtocolAction::Exclude,
+                "separate" => ProtocolAction::Separate,
+                other => {
+                    error!("Unknown {} {}, falling back to include", name, other);
+                    ProtocolAction::Include
+                }
+            },
+            Err(_) => ProtocolAction::Include,
+        }
+    }
+}
+
+pub struct ProtocolPolicy {
+    // ICMP_BYTES / GRE_BYTES / ESP_BYTES / OTHER_PROTOCOL_BYTES
+    icmp: ProtocolAction,
+    gre: ProtocolAction,
+    esp: ProtocolAction,
+    other: ProtocolAction,
+    // (download, upload) bytes in the current window per [interface, protocol]
+    window_bytes: DashMap<[String; 2], (u64, u64)>,
+    download_gauge: IntGaugeVec,
+    upload_gauge: IntGaugeVec,
+}
+
+impl ProtocolPolicy {
+    pub fn new(registry: &Registry) -> Self {
+        let icmp = ProtocolAction::from_env("ICMP_BYTES");
+        let gre = ProtocolAction::from_env("GRE_BYTES");
+        let esp = ProtocolAction::from_env("ESP_BYTES");
+        let other = ProtocolAction::from_env("OTHER_PROTOCOL_BYTES");
+        info!(
+            "Non-TCP/UDP bytes: icmp {:?}, gre {:?}, esp {:?}, other {:?}",
+            icmp, gre, esp, other
+        );
+
+        let download_gauge = IntGaugeVec::new(
+            prometheus::Opts::new(
+                "separate_download_bytes",
+                "Download bytes of separately accounted protocols over the last second",
+            )
+            .const_label("job", "localpacketdump"),
+            &[LABEL_INTERFACE, LABEL_PROTOCOL],
+        )
+        .expect("failed to create separate_download_bytes gauge");
+        let upload_gauge = IntGaugeVec::new(
+            prometheus::Opts::new(
+                "separate_upload_bytes",
+                "Upload bytes of separately accounted protocols over the last second",
+            )
+            .const_label("job", "localpacketdump"),
+            &[LABEL_INTERFACE, LABEL_PROTOCOL],
+        )
+        .expect("failed to create separate_upload_bytes gauge");
+        if [icmp, gre, esp, other].contains(&ProtocolAction::Separate) {
+            registry
+                .register(Box::new(download_gauge.clone()))
+                .expect("failed to register separate_download_bytes gauge");
+            registry
+                .register(Box::new(upload_gauge.clone()))
+                .expect("failed to register separate_upload_bytes gauge");
+        }
+
+        Self {
+            icmp,
+            gre,
+            esp,
+            other,
+            window_bytes: DashMap::new(),
+            download_gauge,
+            upload_gauge,
+        }
+    }
+
+    // What to do with a packet, and the protocol label it gets when separated
+    pub fn action(&self, protocol: IpNextHeaderProtocol) -> (ProtocolAction, &'static str) {
+        match protocol {
+            IpNextHeaderProtocols::Tcp | IpNextHeaderProtocols::Udp => {
+                (ProtocolAction::Include, "")
+            }
+            IpNextHeaderProtocols::Icmp | IpNextHeaderProtocols::Icmpv6 => (self.icmp, "icmp"),
+            IpNextHeaderProtocols::Gre => (self.gre, "gre"),
+            IpNextHeaderProtocols::Esp => (self.esp, "esp"),
+            _ => (self.other, "other"),
+        }
+    }
+
+    pub fn record(&self, interface: &str, protocol: &str, download: bool, bytes: u64) {
+        let mut entry = self
+            .window_bytes
+            .entry([interface.to_string(), protocol.to_string()])
+            .or_default();
+        if download {
+            entry.0 += bytes;
+        } else {
+            entry.1 += bytes;
+        }
+    }
+
+    // Publish the window; series seen before read 0 while idle
+    pub fn publish_and_reset(&self, scale: f64) {
+        for mut entry in self.window_bytes.iter_mut() {
+            let (download, upload) = *entry.value();
+            let labels = [entry.key()[0].as_str(), entry.key()[1].as_str()];
+            self.download_gauge
+                .with_label_values(&labels)
+                .set((download as f64 * scale) as i64);
+            self.upload_gauge
+                .with_label_values(&labels)
+                .set((upload as f64 * scale) as i64);
+            *entry.value_mut() = (0, 0);
+        }
+    }
+}
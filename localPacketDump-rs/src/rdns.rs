@@ -0,0 +1,247 @@
+// Reverse-DNS names for remote IPs
+//
+// Publishes remote_host_info{remote_ip, remote_host} = 1 so dashboards can join names
+// onto the byte gauges instead of showing bare IPs. The processing thread only reads
+// the cache and hands unknown or expired IPs to a bounded queue; a single task resolves
+// them one at a time, at most REVERSE_DNS_RATE per second. When the queue is full the
+// IP is simply tried again on a later packet, so capture never waits on DNS.
+
+use crate::network;
+use dashmap::DashMap;
+use prometheus::{IntGaugeVec, Registry};
+use shared_schema::LABEL_REMOTE_IP;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+// IPs waiting for a lookup at most
+const QUEUE_SIZE: usize = 1024;
+// How often entries that went unused since they expired are dropped
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+struct CacheEntry {
+    // None when the IP has no PTR record or the lookup failed
+    host: Option<String>,
+    expires: Instant,
+    // Handed to the lookup task and not stored back yet
+    queued: bool,
+}
+
+pub struct ReverseDns {
+    // Lifetime of a resolved name (REVERSE_DNS_TTL_SECS)
+    ttl: Duration,
+    // Lifetime of a failed lookup (REVERSE_DNS_NEGATIVE_TTL_SECS)
+    negative_ttl: Duration,
+    // Lookups per second at most (REVERSE_DNS_RATE)
+    rate: f64,
+    // IPs cached at most (REVERSE_DNS_MAX_ENTRIES)
+    max_entries: usize,
+    cache: DashMap<String, CacheEntry>,
+    queue: mpsc::Sender<String>,
+    // Taken by run()
+    receiver: Mutex<Option<mpsc::Receiver<String>>>,
+    info_gauge: IntGaugeVec,
+    entries_gauge: IntGaugeVec,
+}
+
+impl ReverseDns {
+    // None unless REVERSE_DNS is set
+    pub fn from_env(registry: &Registry) -> Option<Self> {
+        let enabled = matches!(
+            network::var("REVERSE_DNS")
+                .map(|v| v.trim().to_ascii_lowercase())
+                .as_deref(),
+            Ok("1") | Ok("true")
+        );
+        info!("Reverse DNS: {}", enabled);
+        if !enabled {
+            return None;
+        }
+        let secs = |name: &str, default: u64| {
+            Duration::from_secs(
+                network::var(name)
+                    .ok()
+                    .and_then(|v| v.trim().parse::<u64>().ok())
+                    .unwrap_or(default),
+            )
+        };
+        let ttl = secs("REVERSE_DNS_TTL_SECS", 3600);
+        let negative_ttl = secs("REVERSE_DNS_NEGATIVE_TTL_SECS", 300);
+        let rate = network::var("REVERSE_DNS_RATE")
+            .ok()
+            .and_then(|v| v.trim().parse::<f64>().ok())
+            .filter(|rate| *rate > 0.0)
+            .unwrap_or(10.0);
+        let max_entries = network::var("REVERSE_DNS_MAX_ENTRIES")
+            .ok()
+            .and_then(|v| v.trim().parse::<usize>().ok())
+            .unwrap_or(10000);
+        info!(
+            "Reverse DNS: {} lookups/s, TTL {:?} ({:?} when not found), up to {} IPs",
+            rate, ttl, negative_ttl, max_entries
+        );
+
+        let info_gauge = IntGaugeVec::new(
+            prometheus::Opts::new(
+                "remote_host_info",
+                "Reverse DNS name of a remote IP (always 1)",
+            )
+            .const_label("job", "localpacketdump"),
+            &[LABEL_REMOTE_IP, "remote_host"],
+        )
+        .expect("failed to create remote_host_info gauge");
+        let entries_gauge = IntGaugeVec::new(
+            prometheus::Opts::new(
+                "reverse_dns_cache_entries",
+                "Remote IPs in the reverse DNS cache by lookup result",
+            )
+            .const_label("job", "localpacketdump"),
+            &["result"],
+        )
+        .expect("failed to create reverse_dns_cache_entries gauge");
+        registry
+            .register(Box::new(info_gauge.clone()))
+            .expect("failed to register remote_host_info gauge");
+        registry
+            .register(Box::new(entries_gauge.clone()))
+            .expect("failed to register reverse_dns_cache_entries gauge");
+
+        let (queue, receiver) = mpsc::channel(QUEUE_SIZE);
+        Some(Self {
+            ttl,
+            negative_ttl,
+            rate,
+            max_entries,
+            cache: DashMap::new(),
+            queue,
+            receiver: Mutex::new(Some(receiver)),
+            info_gauge,
+            entries_gauge,
+        })
+    }
+
+    // Called for every accounted packet; never blocks
+    pub fn note(&self, remote_ip: &str) {
+        match self.cache.get_mut(remote_ip) {
+            Some(mut entry) => {
+                if entry.queued || entry.expires > Instant::now() {
+                    return;
+                }
+                // Keep the old name published until the new lookup is stored
+                entry.queued = self.queue.try_send(remote_ip.to_string()).is_ok();
+            }
+            None => {
+                if self.cache.len() >= self.max_entries {
+                    return;
+                }
+                // Inserted before queueing so the lookup result cannot be overwritten
+                self.cache.insert(
+                    remote_ip.to_string(),
+                    CacheEntry {
+                        host: None,
+                        expires: Instant::now(),
+                        queued: true,
+                    },
+                );
+                if self.queue.try_send(remote_ip.to_string()).is_err() {
+                    self.cache.remove(remote_ip);
+                }
+            }
+        }
+    }
+
+    // Resolve queued IPs and sweep the cache until the queue is closed
+    pub async fn run(&self) {
+        let Some(mut receiver) = self.receiver.lock().unwrap().take() else {
+            return;
+        };
+        let mut limiter = tokio::time::interval(Duration::from_secs_f64(1.0 / self.rate));
+        limiter.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut sweep = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            tokio::select! {
+                remote_ip = receiver.recv() => {
+                    let Some(remote_ip) = remote_ip else {
+                        return;
+                    };
+                    limiter.tick().await;
+                    let host = lookup(&remote_ip).await;
+                    self.store(remote_ip, host);
+                }
+                _ = sweep.tick() => self.sweep(),
+            }
+        }
+    }
+
+    fn store(&self, remote_ip: String, host: Option<String>) {
+        debug!("Reverse DNS {} -> {:?}", remote_ip, host);
+        let ttl = if host.is_some() {
+            self.ttl
+        } else {
+            self.negative_ttl
+        };
+        if let Some(previous) = self.cache.get(&remote_ip).and_then(|e| e.host.clone()) {
+            if host.as_ref() != Some(&previous) {
+                let _ = self
+                    .info_gauge
+                    .remove_label_values(&[remote_ip.as_str(), previous.as_str()]);
+            }
+        }
+        if let Some(host) = &host {
+            self.info_gauge
+                .with_label_values(&[remote_ip.as_str(), host.as_str()])
+                .set(1);
+        }
+        self.cache.insert(
+            remote_ip,
+            CacheEntry {
+                host,
+                expires: Instant::now() + ttl,
+                queued: false,
+            },
+        );
+    }
+
+    // Forget IPs not seen again within a TTL after their entry expired
+    fn sweep(&self) {
+        let now = Instant::now();
+        self.cache.retain(|remote_ip, entry| {
+            if entry.queued || entry.expires + self.ttl > now {
+                return true;
+            }
+            if let Some(host) = &entry.host {
+                let _ = self
+                    .info_gauge
+                    .remove_label_values(&[remote_ip.as_str(), host.as_str()]);
+            }
+            false
+        });
+        let resolved = self.cache.iter().filter(|e| e.host.is_some()).count();
+        self.entries_gauge
+            .with_label_values(&["resolved"])
+            .set(resolved as i64);
+        self.entries_gauge
+            .with_label_values(&["not_found"])
+            .set((self.cache.len() - resolved) as i64);
+    }
+}
+
+// PTR lookup through the system resolver; None when there is no name
+async fn lookup(remote_ip: &str) -> Option<String> {
+    let ip: IpAddr = remote_ip.parse().ok()?;
+    match tokio::task::spawn_blocking(move || dns_lookup::lookup_addr(&ip)).await {
+        // getnameinfo falls back to the numeric address when there is no PTR record
+        Ok(Ok(host)) if host != ip.to_string() => Some(host),
+        Ok(Ok(_)) => None,
+        Ok(Err(e)) => {
+            debug!("Reverse DNS lookup for {} failed: {}", ip, e);
+            None
+        }
+        Err(e) => {
+            warn!("Reverse DNS lookup task failed: {}", e);
+            None
+        }
+    }
+}
@@ -0,0 +1,505 @@
+// Linux fast path: PACKET_MMAP with TPACKET_V3 blocks
+//
+// The kernel fills fixed-size blocks in a ring shared with user space and hands over a
+// whole block at a time, so a burst costs one wakeup instead of one recvfrom per frame.
+// With CAPTURE_WORKERS > 1 every worker opens its own ring and joins a PACKET_FANOUT
+// group, and the kernel spreads flows across the workers by hash.
+// The pnet backend cannot take a socket from us, so when BUSY_POLL_USECS or a capture filter
+// is set it reads frames from a plain socket opened here (PacketSocket) instead.
+
+use crate::capture::{CapturedPacket, VlanTags};
+use crate::filter::BpfInstruction;
+use crate::health::{CaptureHealth, CaptureStatus};
+use crate::network;
+use crate::{CaptureSettings, CaptureTuning, TrafficMetrics};
+use crossbeam_channel::Sender;
+use std::ffi::CString;
+use std::io;
+use std::sync::atomic::{fence, AtomicBool, Ordering};
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+// Frame slot size the kernel uses for its bookkeeping; V3 packs frames of any size
+const FRAME_SIZE: u32 = 2048;
+// enum tpacket_versions in linux/if_packet.h
+const TPACKET_V3: libc::c_int = 2;
+// How long poll() waits, so a pause takes effect on an idle link
+const POLL_TIMEOUT_MS: libc::c_int = 1000;
+// Consecutive failures to open the ring before the capture falls back to pnet
+const OPEN_ATTEMPTS: u32 = 3;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RingConfig {
+    // Size of one block in bytes, a multiple of the page size (RING_BLOCK_SIZE)
+    block_size: u32,
+    // Number of blocks per worker (RING_BLOCK_COUNT)
+    block_count: u32,
+    // Hand a partially filled block to user space after this many milliseconds
+    // (RING_BLOCK_TIMEOUT_MS)
+    block_timeout_ms: u32,
+    // PACKET_FANOUT group shared by the workers of this process and network (a group cannot
+    // span interfaces); a single worker needs none
+    fanout_group: Option<u16>,
+}
+
+impl RingConfig {
+    pub fn from_env(workers: usize) -> Self {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(4096) as u32;
+        let mut block_size = env_u32("RING_BLOCK_SIZE", 1 << 20);
+        if block_size < FRAME_SIZE.max(page_size) || !block_size.is_multiple_of(page_size) {
+            error!(
+                "RING_BLOCK_SIZE {} must be a multiple of the page size ({}), using 1048576",
+                block_size, page_size
+            );
+            block_size = 1 << 20;
+        }
+        Self {
+            block_size,
+            block_count: env_u32("RING_BLOCK_COUNT", 32).max(1),
+            block_timeout_ms: env_u32("RING_BLOCK_TIMEOUT_MS", 10).max(1),
+            fanout_group: (workers > 1)
+                .then(|| (std::process::id() as u16).wrapping_add(network::index() as u16)),
+        }
+    }
+}
+
+fn env_u32(name: &str, default: u32) -> u32 {
+    match network::var(name) {
+        Ok(v) => v.trim().parse::<u32>().unwrap_or_else(|e| {
+            error!("Failed to parse {} {}: {}", name, v, e);
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+struct Ring {
+    fd: libc::c_int,
+    map: *mut u8,
+    map_len: usize,
+    config: RingConfig,
+    // Index of the next block to hand back to the kernel
+    current: u32,
+}
+
+impl Ring {
+    fn open(
+        ifindex: libc::c_int,
+        config: RingConfig,
+        tuning: CaptureTuning,
+        capture_filter: Option<&[BpfInstruction]>,
+    ) -> io::Result<Self> {
+        let fd = crate::open_capture_socket(tuning.busy_poll_usecs, capture_filter)?;
+        // Closes the socket if any later step fails
+        let mut ring = Self {
+            fd,
+            map: std::ptr::null_mut(),
+            map_len: 0,
+            config,
+            current: 0,
+        };
+
+        setsockopt(fd, libc::PACKET_VERSION, &(TPACKET_V3))?;
+        let req = libc::tpacket_req3 {
+            tp_block_size: config.block_size,
+            tp_block_nr: config.block_count,
+            tp_frame_size: FRAME_SIZE,
+            tp_frame_nr: config.block_size / FRAME_SIZE * config.block_count,
+            tp_retire_blk_tov: config.block_timeout_ms,
+            tp_sizeof_priv: 0,
+            tp_feature_req_word: 0,
+        };
+        setsockopt(fd, libc::PACKET_RX_RING, &req)?;
+
+        let map_len = config.block_size as usize * config.block_count as usize;
+        let map = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                map_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        ring.map = map as *mut u8;
+        ring.map_len = map_len;
+
+        bind_promiscuous(fd, ifindex)?;
+
+        if let Some(group) = config.fanout_group {
+            let mode = libc::PACKET_FANOUT_HASH | libc::PACKET_FANOUT_FLAG_DEFRAG;
+            let value = (group as u32 | (mode << 16)) as libc::c_int;
+            setsockopt(fd, libc::PACKET_FANOUT, &value)?;
+        }
+
+        Ok(ring)
+    }
+
+    // Hand every frame of the next filled block to `f`; waits up to POLL_TIMEOUT_MS when
+    // the kernel has not released one yet
+    fn next_block(&mut self, mut f: impl FnMut(&[u8], Option<u16>)) -> io::Result<()> {
+        let block = unsafe {
+            self.map
+                .add(self.current as usize * self.config.block_size as usize)
+                as *mut libc::tpacket_block_desc
+        };
+        let status = unsafe { std::ptr::addr_of_mut!((*block).hdr.bh1.block_status) };
+        if unsafe { std::ptr::read_volatile(status) } & libc::TP_STATUS_USER == 0 {
+            let mut pfd = libc::pollfd {
+                fd: self.fd,
+                events: libc::POLLIN | libc::POLLERR,
+                revents: 0,
+            };
+            if unsafe { libc::poll(&mut pfd, 1, POLL_TIMEOUT_MS) } == -1 {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+            return Ok(());
+        }
+        fence(Ordering::Acquire);
+
+        unsafe {
+            let header = &(*block).hdr.bh1;
+            let mut offset = header.offset_to_first_pkt as usize;
+            for _ in 0..header.num_pkts {
+                let packet = (block as *const u8).add(offset) as *const libc::tpacket3_hdr;
+                let frame = std::slice::from_raw_parts(
+                    (packet as *const u8).add((*packet).tp_mac as usize),
+                    (*packet).tp_snaplen as usize,
+                );
+                // Tag the NIC removed with VLAN offload enabled
+                let vlan = ((*packet).tp_status & libc::TP_STATUS_VLAN_VALID != 0)
+                    .then(|| (*packet).hv1.tp_vlan_tci as u16 & 0x0fff);
+                f(frame, vlan);
+                offset += (*packet).tp_next_offset as usize;
+            }
+        }
+
+        fence(Ordering::Release);
+        unsafe { std::ptr::write_volatile(status, libc::TP_STATUS_KERNEL) };
+        self.current = (self.current + 1) % self.config.block_count;
+        Ok(())
+    }
+
+    // Packets the kernel dropped since the last call
+    fn take_kernel_drops(&self) -> u32 {
+        let mut stats: libc::tpacket_stats_v3 = unsafe { std::mem::zeroed() };
+        let mut len = std::mem::size_of::<libc::tpacket_stats_v3>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                self.fd,
+                libc::SOL_PACKET,
+                libc::PACKET_STATISTICS,
+                &mut stats as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret == 0 {
+            stats.tp_drops
+        } else {
+            0
+        }
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.map.is_null() {
+                libc::munmap(self.map as *mut libc::c_void, self.map_len);
+            }
+            libc::close(self.fd);
+        }
+    }
+}
+
+// A socket read one frame per recv, for the pnet backend with BUSY_POLL_USECS or a filter
+pub struct PacketSocket {
+    fd: libc::c_int,
+    buf: Vec<u8>,
+}
+
+impl PacketSocket {
+    // `read_timeout` lets the capture loop notice a pause on an idle link
+    pub fn open(
+        interface_name: &str,
+        tuning: CaptureTuning,
+        capture_filter: Option<&[BpfInstruction]>,
+        buffer_size: usize,
+        read_timeout: Duration,
+    ) -> io::Result<Self> {
+        let ifindex = interface_index(interface_name)?;
+        let fd = crate::open_capture_socket(tuning.busy_poll_usecs, capture_filter)?;
+        // Closes the socket if any later step fails
+        let socket = Self {
+            fd,
+            buf: vec![0; buffer_size],
+        };
+        bind_promiscuous(fd, ifindex)?;
+        let timeout = libc::timeval {
+            tv_sec: read_timeout.as_secs() as libc::time_t,
+            tv_usec: read_timeout.subsec_micros() as libc::suseconds_t,
+        };
+        let ret = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_RCVTIMEO,
+                &timeout as *const _ as *const libc::c_void,
+                std::mem::size_of::<libc::timeval>() as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(socket)
+    }
+}
+
+impl pnet::datalink::DataLinkReceiver for PacketSocket {
+    fn next(&mut self) -> io::Result<&[u8]> {
+        let n = unsafe {
+            libc::recv(
+                self.fd,
+                self.buf.as_mut_ptr() as *mut libc::c_void,
+                self.buf.len(),
+                0,
+            )
+        };
+        if n == -1 {
+            let err = io::Error::last_os_error();
+            // An expired SO_RCVTIMEO, reported like pnet's read_timeout
+            if err.kind() == io::ErrorKind::WouldBlock {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, err));
+            }
+            return Err(err);
+        }
+        Ok(&self.buf[..n as usize])
+    }
+}
+
+impl Drop for PacketSocket {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+// Bind a capture socket to the interface and put the interface in promiscuous mode,
+// same as pnet's default, so traffic of other LAN devices is seen on a mirror port
+fn bind_promiscuous(fd: libc::c_int, ifindex: libc::c_int) -> io::Result<()> {
+    let mut addr: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+    addr.sll_family = libc::AF_PACKET as u16;
+    addr.sll_protocol = (libc::ETH_P_ALL as u16).to_be();
+    addr.sll_ifindex = ifindex;
+    let ret = unsafe {
+        libc::bind(
+            fd,
+            &addr as *const _ as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut mreq: libc::packet_mreq = unsafe { std::mem::zeroed() };
+    mreq.mr_ifindex = ifindex;
+    mreq.mr_type = libc::PACKET_MR_PROMISC as u16;
+    setsockopt(fd, libc::PACKET_ADD_MEMBERSHIP, &mreq)
+}
+
+fn setsockopt<T>(fd: libc::c_int, option: libc::c_int, value: &T) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_PACKET,
+            option,
+            value as *const T as *const libc::c_void,
+            std::mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+fn interface_index(name: &str) -> io::Result<libc::c_int> {
+    let name = CString::new(name)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Interface name contains NUL"))?;
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if index == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(index as libc::c_int)
+}
+
+// Check that the kernel supports TPACKET_V3 rings before committing to this backend
+pub fn probe(tuning: CaptureTuning) -> io::Result<()> {
+    let fd = crate::open_capture_socket(tuning.busy_poll_usecs, None)?;
+    let result = setsockopt(fd, libc::PACKET_VERSION, &(TPACKET_V3));
+    unsafe { libc::close(fd) };
+    result
+}
+
+// Runs until `stop` is set by a reload or shutdown. Returns true when the ring could not be
+// opened OPEN_ATTEMPTS times in a row and the caller should capture with pnet instead.
+pub fn run_worker(
+    metrics: &TrafficMetrics,
+    settings: &CaptureSettings,
+    worker: usize,
+    config: RingConfig,
+    stop: &AtomicBool,
+    health: &CaptureHealth,
+    packets: &Sender<CapturedPacket>,
+) -> bool {
+    let interface_name = settings.interface_name.as_str();
+    let tuning = settings.tuning;
+    // Spread the workers over consecutive cores starting at CAPTURE_CPU
+    if let Some(cpu) = tuning.cpu {
+        match crate::pin_current_thread(cpu + worker) {
+            Ok(()) => info!("Pinned capture worker {} to CPU {}", worker, cpu + worker),
+            Err(e) => warn!(
+                "Failed to pin capture worker {} to CPU {}: {}",
+                worker,
+                cpu + worker,
+                e
+            ),
+        }
+    }
+
+    let mut failures = 0;
+    while !stop.load(Ordering::Relaxed) {
+        // Keep the ring unmapped while paused so the kernel stops copying packets to us
+        if metrics.capture_paused.load(Ordering::Relaxed) {
+            health.set(CaptureStatus::Paused);
+            std::thread::sleep(Duration::from_secs(1));
+            continue;
+        }
+
+        let ring = interface_index(interface_name)
+            .and_then(|ifindex| Ring::open(ifindex, config, tuning, settings.filter.as_deref()));
+        let mut ring = match ring {
+            Ok(ring) => ring,
+            Err(e) => {
+                failures += 1;
+                if failures >= OPEN_ATTEMPTS {
+                    error!(
+                        "Failed to open capture ring on {} (worker {}) {} times, giving up: {}",
+                        interface_name, worker, failures, e
+                    );
+                    return true;
+                }
+                error!(
+                    "Failed to open capture ring on {} (worker {}), retrying: {}",
+                    interface_name, worker, e
+                );
+                health.failed(&e);
+                std::thread::sleep(Duration::from_secs(5));
+                continue;
+            }
+        };
+        failures = 0;
+        info!(
+            "Monitoring interface: {} (ring worker {}, {} x {} bytes)",
+            interface_name, worker, config.block_count, config.block_size
+        );
+        health.set(CaptureStatus::Open);
+
+        loop {
+            if stop.load(Ordering::Relaxed) {
+                info!(
+                    "Closing capture ring on {} (worker {})",
+                    interface_name, worker
+                );
+                health.set(CaptureStatus::Closed);
+                return false;
+            }
+            if metrics.capture_paused.load(Ordering::Relaxed) {
+                info!("Closing capture ring on {} while paused", interface_name);
+                break;
+            }
+            let mut disconnected = false;
+            let mut frames = false;
+            health.polled();
+            let result = ring.next_block(|frame, vlan_tci| {
+                frames = true;
+                if !disconnected && !forward_frame(metrics, frame, vlan_tci, packets) {
+                    disconnected = true;
+                }
+            });
+            if frames {
+                health.frame();
+            }
+            if disconnected {
+                error!("Packet processing thread stopped, ending capture");
+                health.failed("packet processing thread stopped");
+                return false;
+            }
+            metrics
+                .capture_ring_dropped
+                .inc_by(ring.take_kernel_drops() as u64);
+            if let Err(e) = result {
+                error!("Error reading capture ring: {}", e);
+                health.failed(&e);
+                break;
+            }
+        }
+    }
+    health.set(CaptureStatus::Closed);
+    false
+}
+
+// Parse a frame and queue it for processing; false once the processing thread is gone
+fn forward_frame(
+    metrics: &TrafficMetrics,
+    frame: &[u8],
+    vlan_tci: Option<u16>,
+    packets: &Sender<CapturedPacket>,
+) -> bool {
+    let Some(mut packet) =
+        crate::capture::parse_frame(frame, &metrics.transition, &metrics.tunnels)
+    else {
+        return true;
+    };
+    packet.vlan = offloaded_tags(vlan_tci, packet.vlan);
+    crate::queue_packet(metrics, packet, packets)
+}
+
+// The tag the NIC stripped is the outer one; any tag left in the frame is inner
+fn offloaded_tags(vlan_tci: Option<u16>, in_frame: Option<VlanTags>) -> Option<VlanTags> {
+    match vlan_tci {
+        Some(outer) => Some(VlanTags {
+            outer,
+            inner: in_frame.map(|tags| tags.outer),
+        }),
+        None => in_frame,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn label(vlan_tci: Option<u16>, in_frame: Option<VlanTags>) -> Option<String> {
+        offloaded_tags(vlan_tci, in_frame).map(|tags| tags.label())
+    }
+
+    #[test]
+    fn offloaded_tag_is_outer() {
+        let tag = |outer, inner| Some(VlanTags { outer, inner });
+        assert_eq!(label(None, None), None);
+        assert_eq!(label(Some(100), None), Some("100".to_string()));
+        // QinQ with the outer tag offloaded: the tag left in the frame is the inner one
+        assert_eq!(label(Some(100), tag(20, None)), Some("100.20".to_string()));
+        // Without offload the frame's own tags are kept as parsed
+        assert_eq!(label(None, tag(100, Some(20))), Some("100.20".to_string()));
+    }
+}
@@ -0,0 +1,160 @@
+// Automatic sampling under load
+//
+// When the packet rate handed over by the capture threads exceeds
+// SAMPLING_THRESHOLD_PPS, only 1 in N packets is queued and its bytes are multiplied
+// by N, so a flood costs accuracy instead of silently overflowing the queue. N is a
+// power of two chosen every second from the offered rate, and steps back down once
+// the rate falls below 80% of what the lower ratio can take.
+
+use crate::network;
+use prometheus::{IntCounter, IntGauge, Registry};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use tracing::{error, info, warn};
+
+// Share of the threshold the rate must fall under before sampling is relaxed
+const RELAX_FACTOR: f64 = 0.8;
+
+pub struct AdaptiveSampler {
+    // Offered packets per second above which sampling starts; None disables it
+    threshold_pps: Option<u64>,
+    // Highest 1-in-N ratio (SAMPLING_MAX_RATIO)
+    max_ratio: u32,
+    ratio: AtomicU32,
+    // Packets offered since the last adjust()
+    offered: AtomicU64,
+    // Sequence used to pick every Nth packet
+    sequence: AtomicU64,
+    ratio_gauge: IntGauge,
+    offered_gauge: IntGauge,
+    skipped: IntCounter,
+}
+
+impl AdaptiveSampler {
+    pub fn new(registry: &Registry) -> Self {
+        let threshold_pps = network::var("SAMPLING_THRESHOLD_PPS").ok().and_then(|v| {
+            match v.trim().parse::<u64>() {
+                Ok(0) => None,
+                Ok(pps) => Some(pps),
+                Err(e) => {
+                    error!("Failed to parse SAMPLING_THRESHOLD_PPS {}: {}", v, e);
+                    None
+                }
+            }
+        });
+        let max_ratio = network::var("SAMPLING_MAX_RATIO")
+            .ok()
+            .and_then(|v| v.trim().parse::<u32>().ok())
+            .unwrap_or(64)
+            .max(1);
+        match threshold_pps {
+            Some(pps) => info!("Sampling above {} packets/s, up to 1 in {}", pps, max_ratio),
+            None => info!("Automatic sampling disabled"),
+        }
+
+        let ratio_gauge = IntGauge::with_opts(
+            prometheus::Opts::new(
+                "sampling_ratio",
+                "1 in N packets accounted (1 when every packet is accounted)",
+            )
+            .const_label("job", "localpacketdump"),
+        )
+        .expect("failed to create sampling_ratio gauge");
+        ratio_gauge.set(1);
+        let offered_gauge = IntGauge::with_opts(
+            prometheus::Opts::new(
+                "capture_packets_per_second",
+                "Packets handed over by the capture threads over the last second",
+            )
+            .const_label("job", "localpacketdump"),
+        )
+        .expect("failed to create capture_packets_per_second gauge");
+        let skipped = IntCounter::with_opts(
+            prometheus::Opts::new(
+                "sampling_skipped_packets_total",
+                "Packets left out by sampling, accounted through the scaled ones",
+            )
+            .const_label("job", "localpacketdump"),
+        )
+        .expect("failed to create sampling_skipped_packets_total counter");
+        registry
+            .register(Box::new(ratio_gauge.clone()))
+            .expect("failed to register sampling_ratio gauge");
+        registry
+            .register(Box::new(offered_gauge.clone()))
+            .expect("failed to register capture_packets_per_second gauge");
+        registry
+            .register(Box::new(skipped.clone()))
+            .expect("failed to register sampling_skipped_packets_total counter");
+
+        Self {
+            threshold_pps,
+            max_ratio,
+            ratio: AtomicU32::new(1),
+            offered: AtomicU64::new(0),
+            sequence: AtomicU64::new(0),
+            ratio_gauge,
+            offered_gauge,
+            skipped,
+        }
+    }
+
+    // Some(scale) when the packet is to be accounted with its bytes multiplied by scale
+    pub fn admit(&self) -> Option<u64> {
+        self.offered.fetch_add(1, Ordering::Relaxed);
+        let ratio = u64::from(self.ratio.load(Ordering::Relaxed));
+        if ratio == 1 {
+            return Some(1);
+        }
+        if self
+            .sequence
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(ratio)
+        {
+            Some(ratio)
+        } else {
+            self.skipped.inc();
+            None
+        }
+    }
+
+    // Called once per second: pick the ratio for the next second from the offered rate
+    pub fn adjust(&self) {
+        let pps = self.offered.swap(0, Ordering::Relaxed);
+        self.offered_gauge.set(pps as i64);
+        let Some(threshold) = self.threshold_pps else {
+            return;
+        };
+        let current = self.ratio.load(Ordering::Relaxed);
+        let needed = |capacity: f64| {
+            ((pps as f64 / capacity).ceil() as u32)
+                .max(1)
+                .next_power_of_two()
+                .min(self.max_ratio)
+        };
+        let escalate = needed(threshold as f64);
+        let relax = needed(threshold as f64 * RELAX_FACTOR);
+        let ratio = if escalate > current {
+            escalate
+        } else if relax < current {
+            relax
+        } else {
+            current
+        };
+        if ratio == current {
+            return;
+        }
+        if ratio > current {
+            warn!(
+                "{} packets/s offered, sampling 1 in {} (was 1 in {})",
+                pps, ratio, current
+            );
+        } else {
+            info!(
+                "{} packets/s offered, sampling 1 in {} (was 1 in {})",
+                pps, ratio, current
+            );
+        }
+        self.ratio.store(ratio, Ordering::Relaxed);
+        self.ratio_gauge.set(i64::from(ratio));
+    }
+}
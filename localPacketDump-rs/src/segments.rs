@@ -0,0 +1,134 @@
+// Per-segment traffic accounting
+//
+// Local CIDRs can be tagged with names (e.g. iot, guest, trusted, usually one per
+// SSID or VLAN) so the traffic of a whole network segment is visible at a glance
+// without summing per-device series. Devices in no named segment count as "other".
+
+use crate::network;
+use dashmap::DashMap;
+use ipnetwork::IpNetwork;
+use prometheus::{IntGaugeVec, Registry};
+use shared_cidr::PrefixTable;
+use std::net::IpAddr;
+use std::str::FromStr;
+use tracing::{error, info};
+
+const OTHER_SEGMENT: &str = "other";
+
+pub struct Segments {
+    // network -> name from SEGMENTS, e.g. "iot=10.40.1.0/24,guest=10.40.2.0/24"
+    networks: PrefixTable<String>,
+    // (download, upload) bytes in the current window per segment
+    window_bytes: DashMap<String, (u64, u64)>,
+    download_gauge: IntGaugeVec,
+    upload_gauge: IntGaugeVec,
+}
+
+impl Segments {
+    pub fn new(registry: &Registry) -> Self {
+        let networks: PrefixTable<String> = network::var("SEGMENTS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .filter_map(|entry| {
+                let Some((name, cidr)) = entry.split_once('=') else {
+                    error!("Failed to parse segment {}: expected NAME=CIDR", entry);
+                    return None;
+                };
+                match IpNetwork::from_str(cidr.trim()) {
+                    Ok(net) => Some((net, name.trim().to_string())),
+                    Err(e) => {
+                        error!("Failed to parse CIDR of segment {}: {}", name, e);
+                        None
+                    }
+                }
+            })
+            .collect();
+        for (net, name) in networks.iter() {
+            info!("Configured segment {}: {}", name, net);
+        }
+
+        let download_gauge = IntGaugeVec::new(
+            prometheus::Opts::new(
+                "segment_download_bytes",
+                "Download bytes per local network segment over the last second",
+            )
+            .const_label("job", "localpacketdump"),
+            &["segment"],
+        )
+        .expect("failed to create segment_download_bytes gauge");
+        let upload_gauge = IntGaugeVec::new(
+            prometheus::Opts::new(
+                "segment_upload_bytes",
+                "Upload bytes per local network segment over the last second",
+            )
+            .const_label("job", "localpacketdump"),
+            &["segment"],
+        )
+        .expect("failed to create segment_upload_bytes gauge");
+        registry
+            .register(Box::new(download_gauge.clone()))
+            .expect("failed to register segment_download_bytes gauge");
+        registry
+            .register(Box::new(upload_gauge.clone()))
+            .expect("failed to register segment_upload_bytes gauge");
+
+        Self {
+            networks,
+            window_bytes: DashMap::new(),
+            download_gauge,
+            upload_gauge,
+        }
+    }
+
+    // Most specific named segment containing the device, or "other"
+    fn segment_of(&self, local_ip: &str) -> &str {
+        let Ok(ip) = IpAddr::from_str(local_ip) else {
+            return OTHER_SEGMENT;
+        };
+        self.networks
+            .lookup(ip)
+            .map_or(OTHER_SEGMENT, |(_, name)| name.as_str())
+    }
+
+    pub fn record(&self, local_ip: &str, download: bool, bytes: u64) {
+        if self.networks.is_empty() {
+            return;
+        }
+        let mut entry = self
+            .window_bytes
+            .entry(self.segment_of(local_ip).to_string())
+            .or_default();
+        if download {
+            entry.0 += bytes;
+        } else {
+            entry.1 += bytes;
+        }
+    }
+
+    // Publish the window; every configured segment is always exported, idle ones as 0
+    pub fn publish_and_reset(&self, scale: f64) {
+        if self.networks.is_empty() {
+            return;
+        }
+        let names = self
+            .networks
+            .iter()
+            .map(|(_, name)| name.as_str())
+            .chain(std::iter::once(OTHER_SEGMENT));
+        for name in names {
+            let (download, upload) = self
+                .window_bytes
+                .get(name)
+                .map_or((0, 0), |entry| *entry.value());
+            self.download_gauge
+                .with_label_values(&[name])
+                .set((download as f64 * scale) as i64);
+            self.upload_gauge
+                .with_label_values(&[name])
+                .set((upload as f64 * scale) as i64);
+        }
+        self.window_bytes.clear();
+    }
+}
@@ -0,0 +1,20 @@
+// Paths the metrics credentials do not cover
+//
+// METRICS_AUTH_TOKEN / METRICS_AUTH_BASIC and METRICS_TLS_CERT / METRICS_TLS_KEY are applied
+// by traffic_scan_core::router, the same settings the other exporters read. The control
+// endpoints keep their own CONTROL_TOKEN, and the health endpoints stay open for probes that
+// cannot send credentials.
+
+pub fn skips_auth(path: &str) -> bool {
+    is_control_path(path) || path == "/healthz" || path == "/readyz"
+}
+
+// /reload, /control/* and /networks/<name>/control/*
+fn is_control_path(path: &str) -> bool {
+    if path == "/reload" || path.starts_with("/control/") {
+        return true;
+    }
+    path.strip_prefix("/networks/")
+        .and_then(|rest| rest.split_once('/'))
+        .is_some_and(|(_, rest)| rest.starts_with("control/"))
+}
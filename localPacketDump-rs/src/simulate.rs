@@ -0,0 +1,118 @@
+// Synthetic traffic for --simulate.
+//
+// Replaces the capture threads and the status API with shared_sim's scenario so the
+// exporter runs without root or a real interface. Every second each device/remote pair
+// sends the sampled download and upload bytes as TCP 443 segments through the same queue
+// the capture threads use, so everything downstream of parsing is exercised as usual.
+
+use crate::capture::{CapturedPacket, Transport};
+use crate::{queue_packet, TrafficMetrics};
+use crossbeam_channel::Sender;
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::tcp::TcpFlags;
+use shared_schema::StatusResponse;
+use shared_sim::Scenario;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+// Segment size the sampled bytes are split into
+const SEGMENT_BYTES: u64 = 1448;
+// Upper bound on segments per pair and direction in one second
+const MAX_SEGMENTS: u64 = 2000;
+// IPv4 + TCP headers of a generated segment
+const HEADER_BYTES: u64 = 40;
+
+// Install the scenario's device mappings in place of the status API
+pub fn load_status(metrics: &TrafficMetrics, scenario: &Scenario) {
+    let status = StatusResponse::from_json(scenario.status_json().as_bytes())
+        .expect("simulated status must parse");
+    info!(
+        "Simulated status: config={:?}, mappings={:?}",
+        status.config, status.mappings
+    );
+    metrics.status.store(Some(Arc::new(status)));
+}
+
+// Runs until `stop` is set on shutdown
+pub fn run_generator(
+    metrics: &TrafficMetrics,
+    scenario: &Scenario,
+    stop: &AtomicBool,
+    packets: &Sender<CapturedPacket>,
+) {
+    info!("Simulating traffic: {}", scenario.summary());
+    let mut first = true;
+    // Next sequence number per pair and direction, so segments never look retransmitted
+    let mut sequences = vec![[0u32; 2]; scenario.pairs.len()];
+    while !stop.load(Ordering::Relaxed) {
+        let started = Instant::now();
+        if !metrics.capture_paused.load(Ordering::Relaxed) {
+            for (i, pair) in scenario.pairs.iter().enumerate() {
+                let traffic = scenario.sample_traffic();
+                let local_port = 40000 + i as u16;
+                let flags = if first {
+                    TcpFlags::SYN | TcpFlags::ACK
+                } else {
+                    TcpFlags::ACK | TcpFlags::PSH
+                };
+                let directions = [
+                    (
+                        pair.remote_ip,
+                        pair.local_ip,
+                        443,
+                        local_port,
+                        traffic.download_bytes,
+                    ),
+                    (
+                        pair.local_ip,
+                        pair.remote_ip,
+                        local_port,
+                        443,
+                        traffic.upload_bytes,
+                    ),
+                ];
+                for (direction, (src, dst, src_port, dst_port, bytes)) in
+                    directions.into_iter().enumerate()
+                {
+                    for size in segments(bytes) {
+                        let payload_len = size.saturating_sub(HEADER_BYTES) as u32;
+                        let seq = &mut sequences[i][direction];
+                        let packet = CapturedPacket {
+                            src,
+                            dst,
+                            protocol: IpNextHeaderProtocols::Tcp,
+                            transport: Transport::Tcp {
+                                src_port,
+                                dst_port,
+                                flags,
+                                seq: *seq,
+                                payload_len,
+                            },
+                            vlan: None,
+                            dscp: 0,
+                            syn: None,
+                            bytes: size,
+                            packets: 1,
+                        };
+                        *seq = seq.wrapping_add(payload_len);
+                        if !queue_packet(metrics, packet, packets) {
+                            return;
+                        }
+                    }
+                }
+            }
+            first = false;
+        }
+        std::thread::sleep(Duration::from_secs(1).saturating_sub(started.elapsed()));
+    }
+}
+
+// Split a byte count into at most MAX_SEGMENTS nearly equal segment sizes
+fn segments(bytes: u64) -> impl Iterator<Item = u64> {
+    let count = bytes.div_ceil(SEGMENT_BYTES).min(MAX_SEGMENTS);
+    let size = bytes.checked_div(count).unwrap_or(0);
+    let remainder = bytes.checked_rem(count).unwrap_or(0);
+    (0..count).map(move |i| size + u64::from(i < remainder))
+}
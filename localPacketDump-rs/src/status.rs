@@ -0,0 +1,193 @@
+// Status API bookkeeping: disk cache, refresh backoff and mapping changes
+//
+// Without a status response every packet is attributed to interface "unknown", so a
+// status service that is down at startup used to blank out the per-WAN figures until it
+// came back. The last response is kept on disk (STATUS_CACHE_FILE) and loaded on boot.
+// Failed refreshes are retried with exponential backoff and jitter instead of waiting
+// for the next regular refresh, and devices moving between wan0 and wan1 are counted.
+
+use crate::network;
+use prometheus::{IntCounterVec, Registry};
+use shared_schema::StatusResponse;
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeSet;
+use std::hash::BuildHasher;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::{info, warn};
+
+// Regular refresh interval while the status API answers
+const REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+pub struct StatusTracker {
+    // None when STATUS_CACHE_FILE is set to an empty value
+    cache_path: Option<PathBuf>,
+    // Body last written to the cache, to skip rewriting an unchanged file
+    cached_body: Mutex<Option<Vec<u8>>>,
+    // First retry delay after a failure, doubled per consecutive failure up to retry_max
+    retry_initial: Duration,
+    retry_max: Duration,
+    consecutive_failures: Mutex<u32>,
+    fetch_failures: IntCounterVec,
+    mapping_changes: IntCounterVec,
+}
+
+impl StatusTracker {
+    pub fn from_env(registry: &Registry) -> Self {
+        // One file per network, like the remote inventory
+        let cache_path = match network::var("STATUS_CACHE_FILE") {
+            Ok(path) if path.trim().is_empty() => None,
+            Ok(path) => Some(PathBuf::from(path.trim())),
+            Err(_) => Some(PathBuf::from(match network::current() {
+                Some(name) => format!("status_cache-{}.json", name),
+                None => "status_cache.json".to_string(),
+            })),
+        };
+        let retry_initial = Duration::from_millis(
+            network::var("STATUS_RETRY_INITIAL_MS")
+                .ok()
+                .and_then(|v| v.trim().parse().ok())
+                .unwrap_or(1000),
+        );
+        let retry_max = Duration::from_secs(
+            network::var("STATUS_RETRY_MAX_SECS")
+                .ok()
+                .and_then(|v| v.trim().parse().ok())
+                .unwrap_or(60),
+        );
+
+        let fetch_failures = IntCounterVec::new(
+            prometheus::Opts::new(
+                "status_fetch_failures_total",
+                "Failed status API fetches by reason (request, parse)",
+            )
+            .const_label("job", "localpacketdump"),
+            &["reason"],
+        )
+        .expect("failed to create status_fetch_failures_total counter");
+        let mapping_changes = IntCounterVec::new(
+            prometheus::Opts::new(
+                "status_mapping_changes_total",
+                "Local devices moved between WANs by the status API",
+            )
+            .const_label("job", "localpacketdump"),
+            &["local_ip", "from", "to"],
+        )
+        .expect("failed to create status_mapping_changes_total counter");
+        registry
+            .register(Box::new(fetch_failures.clone()))
+            .expect("failed to register status_fetch_failures_total counter");
+        registry
+            .register(Box::new(mapping_changes.clone()))
+            .expect("failed to register status_mapping_changes_total counter");
+
+        Self {
+            cache_path,
+            cached_body: Mutex::new(None),
+            retry_initial,
+            retry_max,
+            consecutive_failures: Mutex::new(0),
+            fetch_failures,
+            mapping_changes,
+        }
+    }
+
+    // Status saved by a previous run, if any
+    pub fn load_cache(&self) -> Option<StatusResponse> {
+        let path = self.cache_path.as_ref()?;
+        let body = std::fs::read(path).ok()?;
+        match StatusResponse::from_json(&body) {
+            Ok(status) => {
+                info!(
+                    "Loaded cached status from {}: config={:?}, mappings={:?}",
+                    path.display(),
+                    status.config,
+                    status.mappings
+                );
+                *self.cached_body.lock().unwrap() = Some(body);
+                Some(status)
+            }
+            Err(e) => {
+                warn!("Ignoring cached status {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    // A response was fetched and parsed: reset the backoff and cache the body
+    pub fn succeeded(&self, body: &[u8]) {
+        *self.consecutive_failures.lock().unwrap() = 0;
+        let Some(path) = &self.cache_path else {
+            return;
+        };
+        let mut cached_body = self.cached_body.lock().unwrap();
+        if cached_body.as_deref() == Some(body) {
+            return;
+        }
+        let tmp = path.with_extension("tmp");
+        match std::fs::write(&tmp, body).and_then(|_| std::fs::rename(&tmp, path)) {
+            Ok(()) => *cached_body = Some(body.to_vec()),
+            Err(e) => warn!("Failed to save status cache {}: {}", path.display(), e),
+        }
+    }
+
+    pub fn failed(&self, reason: &str) {
+        self.fetch_failures.with_label_values(&[reason]).inc();
+        *self.consecutive_failures.lock().unwrap() += 1;
+    }
+
+    // Fetches failed in a row since the last success
+    pub fn consecutive_failures(&self) -> u32 {
+        *self.consecutive_failures.lock().unwrap()
+    }
+
+    // Wait before the next fetch: the regular interval, or the backoff after failures
+    pub fn next_delay(&self) -> Duration {
+        let failures = *self.consecutive_failures.lock().unwrap();
+        if failures == 0 {
+            return REFRESH_INTERVAL;
+        }
+        let backoff = self
+            .retry_initial
+            .saturating_mul(1 << (failures - 1).min(16))
+            .min(self.retry_max);
+        // Somewhere in [backoff / 2, backoff) so networks and routers do not retry in step
+        let jitter = RandomState::new().hash_one(failures) % 1000;
+        backoff / 2 + backoff / 2 * jitter as u32 / 1000
+    }
+
+    // Count devices whose WAN differs between two responses (unmapped devices use wan0)
+    pub fn record_changes(&self, previous: &StatusResponse, current: &StatusResponse) {
+        let wan = |status: &StatusResponse, local_ip: &str| match status
+            .mappings
+            .get(local_ip)
+            .map(String::as_str)
+        {
+            Some("wan1") => "wan1",
+            _ => "wan0",
+        };
+        let local_ips: BTreeSet<&String> = previous
+            .mappings
+            .keys()
+            .chain(current.mappings.keys())
+            .collect();
+        for local_ip in local_ips {
+            let (from, to) = (wan(previous, local_ip), wan(current, local_ip));
+            if from != to {
+                info!("Status: {} moved from {} to {}", local_ip, from, to);
+                self.mapping_changes
+                    .with_label_values(&[local_ip, from, to])
+                    .inc();
+            }
+        }
+        if previous.config.wan0 != current.config.wan0
+            || previous.config.wan1 != current.config.wan1
+        {
+            info!(
+                "Status: WAN interfaces changed from {:?} to {:?}",
+                previous.config, current.config
+            );
+        }
+    }
+}
@@ -0,0 +1,157 @@
+// Live window stream over Server-Sent Events, served at /stream
+//
+// Dashboards that want every window without polling /window.json subscribe once and get
+// each published window as a JSON event. Every client can narrow the entries down to
+// some interfaces or address ranges; the filter is applied per client so one busy
+// dashboard does not change what another sees. Clients that fall behind skip windows
+// instead of holding back publishing. On shutdown the streams are closed after the final
+// window, since clients never hang up on their own.
+
+use crate::network;
+use serde::Deserialize;
+use shared_cidr::IpSet;
+use shared_schema::{LABEL_INTERFACE, LABEL_LOCAL_IP, LABEL_REMOTE_IP, LABEL_REMOTE_PREFIX};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::{broadcast, watch};
+use tracing::info;
+
+// Windows buffered per client before it starts skipping
+const BUFFERED_WINDOWS: usize = 16;
+
+// Query parameters of /stream
+#[derive(Debug, Default, Deserialize)]
+pub struct StreamQuery {
+    // Comma-separated interfaces to keep
+    pub interface: Option<String>,
+    // Comma-separated CIDRs; an entry is kept when its remote or local address is inside one
+    pub cidr: Option<String>,
+    // Newest window layout the client understands, as for /window.json
+    pub schema_version: Option<String>,
+}
+
+pub struct StreamFilter {
+    interfaces: Vec<String>,
+    cidrs: IpSet,
+}
+
+impl StreamFilter {
+    pub fn parse(query: &StreamQuery) -> Result<Self, String> {
+        let split = |value: &Option<String>| -> Vec<String> {
+            value
+                .iter()
+                .flat_map(|v| v.split(','))
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+                .map(str::to_string)
+                .collect()
+        };
+        let cidrs = split(&query.cidr)
+            .iter()
+            .map(|cidr| {
+                cidr.parse::<ipnetwork::IpNetwork>()
+                    .map_err(|e| format!("invalid cidr {}: {}", cidr, e))
+            })
+            .collect::<Result<Vec<ipnetwork::IpNetwork>, _>>()?
+            .into_iter()
+            .collect();
+        Ok(Self {
+            interfaces: split(&query.interface),
+            cidrs,
+        })
+    }
+
+    // Labels of a window entry, as in /window.json
+    pub fn matches(&self, labels: &BTreeMap<&'static str, String>) -> bool {
+        if !self.interfaces.is_empty()
+            && !labels
+                .get(LABEL_INTERFACE)
+                .is_some_and(|interface| self.interfaces.contains(interface))
+        {
+            return false;
+        }
+        if self.cidrs.is_empty() {
+            return true;
+        }
+        [LABEL_REMOTE_IP, LABEL_LOCAL_IP, LABEL_REMOTE_PREFIX]
+            .iter()
+            .filter_map(|name| labels.get(name))
+            // A remote prefix is matched by its network address
+            .filter_map(|value| value.split('/').next()?.parse::<IpAddr>().ok())
+            .any(|ip| self.cidrs.contains(ip))
+    }
+}
+
+pub struct LiveStream<T> {
+    sender: broadcast::Sender<Arc<T>>,
+    // Connected clients at most (STREAM_MAX_CLIENTS)
+    max_clients: usize,
+    clients: Arc<AtomicUsize>,
+    // Set once on shutdown to end every stream
+    closed: watch::Sender<bool>,
+}
+
+// Held by a connected client; frees its slot when the connection ends
+pub struct ClientSlot {
+    clients: Arc<AtomicUsize>,
+    closed: watch::Receiver<bool>,
+}
+
+impl ClientSlot {
+    // Resolves once the stream is closed, right away for a client connecting after that
+    pub async fn closed(&mut self) {
+        let _ = self.closed.wait_for(|closed| *closed).await;
+    }
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        self.clients.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl<T> LiveStream<T> {
+    pub fn from_env() -> Self {
+        let max_clients = network::var("STREAM_MAX_CLIENTS")
+            .ok()
+            .and_then(|v| v.trim().parse::<usize>().ok())
+            .unwrap_or(16);
+        info!("Live stream: up to {} clients", max_clients);
+        let (sender, _) = broadcast::channel(BUFFERED_WINDOWS);
+        Self {
+            sender,
+            max_clients,
+            clients: Arc::new(AtomicUsize::new(0)),
+            closed: watch::channel(false).0,
+        }
+    }
+
+    // Cheap when nobody is connected: the value is only built when there is a client
+    pub fn publish(&self, value: impl FnOnce() -> T) {
+        if self.sender.receiver_count() > 0 {
+            let _ = self.sender.send(Arc::new(value()));
+        }
+    }
+
+    // None when STREAM_MAX_CLIENTS clients are already connected
+    pub fn subscribe(&self) -> Option<(ClientSlot, broadcast::Receiver<Arc<T>>)> {
+        let taken = self
+            .clients
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |clients| {
+                (clients < self.max_clients).then_some(clients + 1)
+            });
+        taken.ok()?;
+        let slot = ClientSlot {
+            clients: self.clients.clone(),
+            closed: self.closed.subscribe(),
+        };
+        Some((slot, self.sender.subscribe()))
+    }
+
+    // End every client's stream so a graceful shutdown does not wait on them
+    pub fn close(&self) {
+        self.closed.send_replace(true);
+    }
+}
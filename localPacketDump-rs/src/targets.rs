@@ -0,0 +1,81 @@
+// Active probe targets of the last window, served at /targets
+//
+// icmp-traffic-scan only needs to know which remotes were active on which interface, and
+// reading that back from Prometheus adds a dependency and a scrape interval of latency.
+// /targets reduces the last published window to one entry per (remote_ip, interface) with
+// the window's per-second bytes, summing over the other labels (local_ip, protocol, ...).
+// While remotes are aggregated into prefixes (remote_prefix) there is nothing to probe.
+
+use crate::WindowSnapshot;
+use serde::{Deserialize, Serialize};
+use shared_schema::{LABEL_INTERFACE, LABEL_REMOTE_IP};
+use std::collections::BTreeMap;
+
+// Query parameters of /targets
+#[derive(Debug, Default, Deserialize)]
+pub struct TargetsQuery {
+    // Only remotes with more download or upload bytes than this (default 0)
+    pub min_bytes: Option<u64>,
+    // Only traffic over this interface
+    pub interface: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Target {
+    pub remote_ip: String,
+    pub interface: String,
+    pub download_bytes: u64,
+    pub upload_bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TargetsPage {
+    // Same sequence and close time as /window.json
+    pub sequence: u64,
+    pub timestamp_ms: i64,
+    pub duration_ms: u64,
+    pub targets: Vec<Target>,
+}
+
+pub fn from_window(window: &WindowSnapshot, query: &TargetsQuery) -> TargetsPage {
+    let min_bytes = query.min_bytes.unwrap_or(0);
+    let mut bytes: BTreeMap<(&str, &str), (u64, u64)> = BTreeMap::new();
+    for entry in &window.entries {
+        let (Some(remote_ip), Some(interface)) = (
+            entry.labels.get(LABEL_REMOTE_IP),
+            entry.labels.get(LABEL_INTERFACE),
+        ) else {
+            continue;
+        };
+        if query
+            .interface
+            .as_deref()
+            .is_some_and(|wanted| wanted != interface)
+        {
+            continue;
+        }
+        let sum = bytes
+            .entry((remote_ip.as_str(), interface.as_str()))
+            .or_default();
+        sum.0 += entry.download_bytes;
+        sum.1 += entry.upload_bytes;
+    }
+    let targets = bytes
+        .into_iter()
+        .filter(|(_, (download, upload))| *download > min_bytes || *upload > min_bytes)
+        .map(
+            |((remote_ip, interface), (download_bytes, upload_bytes))| Target {
+                remote_ip: remote_ip.to_string(),
+                interface: interface.to_string(),
+                download_bytes,
+                upload_bytes,
+            },
+        )
+        .collect();
+    TargetsPage {
+        sequence: window.sequence,
+        timestamp_ms: window.timestamp_ms,
+        duration_ms: window.duration_ms,
+        targets,
+    }
+}
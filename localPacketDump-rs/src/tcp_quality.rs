@@ -0,0 +1,216 @@
+// TCP connection quality per remote
+//
+// Counts SYNs, RSTs and retransmitted segments per (remote, interface) so throughput
+// drops can be correlated with loss straight from the capture box. Retransmissions are
+// a heuristic: each flow direction remembers the highest sequence number it has sent,
+// and a segment whose data ends at or before it is counted as a retransmission.
+// Segments of at most one byte without SYN/FIN are ignored so keepalives are not counted.
+// Idle label sets follow IDLE_POLICY / IDLE_TTL_SECS like the byte gauges.
+
+use crate::idle::{IdleSeries, IdleSettings};
+use crate::network;
+use dashmap::DashMap;
+use pnet::packet::tcp::TcpFlags;
+use prometheus::{IntCounter, IntGaugeVec, Registry};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+// Flows silent this long are forgotten
+const FLOW_TIMEOUT: Duration = Duration::from_secs(120);
+
+// (src, src_port, dst, dst_port), one entry per direction
+type FlowKey = (IpAddr, u16, IpAddr, u16);
+
+struct FlowState {
+    // Sequence number following the highest byte sent so far
+    next_seq: u32,
+    last_seen: Instant,
+}
+
+#[derive(Default, Clone, Copy)]
+struct Counts {
+    syn: u64,
+    rst: u64,
+    retransmits: u64,
+}
+
+// One TCP segment between a local device and a remote
+pub struct Segment<'a> {
+    pub flow: FlowKey,
+    pub remote: &'a str,
+    pub interface: &'a str,
+    pub flags: u8,
+    pub seq: u32,
+    pub payload_len: u32,
+    // Packets the segment stands for when sampled
+    pub packets: u64,
+}
+
+pub struct TcpQuality {
+    enabled: bool,
+    // Flows tracked at most (TCP_FLOW_TABLE_SIZE)
+    max_flows: usize,
+    flows: DashMap<FlowKey, FlowState>,
+    // Counts in the current window per [remote, interface]
+    window: DashMap<[String; 2], Counts>,
+    // Published label sets and how long each has been idle
+    idle: IdleSeries<[String; 2]>,
+    syn_gauge: IntGaugeVec,
+    rst_gauge: IntGaugeVec,
+    retransmit_gauge: IntGaugeVec,
+    table_full: IntCounter,
+}
+
+impl TcpQuality {
+    // label_names: the remote label (remote_ip or remote_prefix) and interface
+    pub fn new(registry: &Registry, label_names: &[&str], idle: IdleSettings) -> Self {
+        let enabled = matches!(
+            network::var("TCP_QUALITY_METRICS")
+                .map(|v| v.trim().to_ascii_lowercase())
+                .as_deref(),
+            Ok("1") | Ok("true")
+        );
+        let max_flows = network::var("TCP_FLOW_TABLE_SIZE")
+            .ok()
+            .and_then(|v| v.trim().parse::<usize>().ok())
+            .unwrap_or(65536);
+        info!("TCP quality metrics: {}", enabled);
+
+        let gauge = |name: &str, help: &str| {
+            IntGaugeVec::new(
+                prometheus::Opts::new(name, help).const_label("job", "localpacketdump"),
+                label_names,
+            )
+            .unwrap_or_else(|e| panic!("failed to create {} gauge: {}", name, e))
+        };
+        let syn_gauge = gauge(
+            "tcp_syn_packets",
+            "TCP SYNs per remote over the last second",
+        );
+        let rst_gauge = gauge(
+            "tcp_rst_packets",
+            "TCP RSTs per remote over the last second",
+        );
+        let retransmit_gauge = gauge(
+            "tcp_retransmitted_segments",
+            "TCP segments resending already sent data per remote over the last second",
+        );
+        let table_full = IntCounter::with_opts(
+            prometheus::Opts::new(
+                "tcp_flow_table_full_total",
+                "TCP flows not tracked for retransmissions because the flow table was full",
+            )
+            .const_label("job", "localpacketdump"),
+        )
+        .expect("failed to create tcp_flow_table_full_total counter");
+        if enabled {
+            for gauge in [&syn_gauge, &rst_gauge, &retransmit_gauge] {
+                registry
+                    .register(Box::new(gauge.clone()))
+                    .expect("failed to register TCP quality gauge");
+            }
+            registry
+                .register(Box::new(table_full.clone()))
+                .expect("failed to register tcp_flow_table_full_total counter");
+        }
+
+        Self {
+            enabled,
+            max_flows,
+            flows: DashMap::new(),
+            window: DashMap::new(),
+            idle: IdleSeries::new(idle),
+            syn_gauge,
+            rst_gauge,
+            retransmit_gauge,
+            table_full,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn record(&self, segment: Segment) {
+        let syn = segment.flags & TcpFlags::SYN != 0;
+        let fin = segment.flags & TcpFlags::FIN != 0;
+        let rst = segment.flags & TcpFlags::RST != 0;
+        let retransmit = !rst && self.track(&segment, syn, fin);
+        if !(syn || rst || retransmit) {
+            return;
+        }
+        let mut counts = self
+            .window
+            .entry([segment.remote.to_string(), segment.interface.to_string()])
+            .or_default();
+        counts.syn += u64::from(syn) * segment.packets;
+        counts.rst += u64::from(rst) * segment.packets;
+        counts.retransmits += u64::from(retransmit) * segment.packets;
+    }
+
+    // Advance the flow's highest sequence number; true when the segment resends data
+    fn track(&self, segment: &Segment, syn: bool, fin: bool) -> bool {
+        // SYN and FIN each take one sequence number
+        let len = segment.payload_len + u32::from(syn) + u32::from(fin);
+        if len == 0 || (len == 1 && !syn && !fin) {
+            return false;
+        }
+        let end = segment.seq.wrapping_add(len);
+        let now = Instant::now();
+        if let Some(mut flow) = self.flows.get_mut(&segment.flow) {
+            flow.last_seen = now;
+            // Compared as a signed distance so sequence wraparound is handled
+            if end.wrapping_sub(flow.next_seq) as i32 <= 0 {
+                return true;
+            }
+            flow.next_seq = end;
+            return false;
+        }
+        if self.flows.len() >= self.max_flows {
+            self.table_full.inc();
+            return false;
+        }
+        self.flows.insert(
+            segment.flow,
+            FlowState {
+                next_seq: end,
+                last_seen: now,
+            },
+        );
+        false
+    }
+
+    // Publish the window; idle label sets read 0 until IDLE_POLICY / IDLE_TTL_SECS removes them
+    pub fn publish_and_reset(&self) {
+        if !self.enabled {
+            return;
+        }
+        let gauges = [&self.syn_gauge, &self.rst_gauge, &self.retransmit_gauge];
+        for entry in self.window.iter() {
+            let counts = *entry.value();
+            let labels = [entry.key()[0].as_str(), entry.key()[1].as_str()];
+            let values = [counts.syn, counts.rst, counts.retransmits];
+            for (gauge, value) in gauges.iter().zip(values) {
+                gauge.with_label_values(&labels).set(value as i64);
+            }
+            self.idle.seen(entry.key());
+        }
+        self.idle.sweep(
+            |key| self.window.contains_key(key),
+            |key| {
+                for gauge in gauges {
+                    gauge.with_label_values(&[&key[0], &key[1]]).set(0);
+                }
+            },
+            |key| {
+                for gauge in gauges {
+                    let _ = gauge.remove_label_values(&[&key[0], &key[1]]);
+                }
+            },
+        );
+        self.window.clear();
+        self.flows
+            .retain(|_, flow| flow.last_seen.elapsed() < FLOW_TIMEOUT);
+    }
+}
@@ -0,0 +1,225 @@
+// Top talkers over the last few windows, served at /top
+//
+// Lightweight UIs and scripts often only want "who is using the link right now"; /top
+// answers that as JSON without a Prometheus query layer. Bytes per remote and interface
+// are summed per window and the last TOP_MAX_WINDOWS windows are kept, so a request can
+// look back over any of them. Remotes are plain addresses even when the byte gauges are
+// aggregated into prefixes.
+//
+// With TOP_K_METRICS set, the download side of the newest window is also exported as
+// topk_download_bytes{rank, remote_ip}. The gauge is rebuilt every window so it never holds
+// more than K series, and low-retention setups can drop the per-remote gauges entirely.
+
+use crate::network;
+use dashmap::DashMap;
+use prometheus::{IntGaugeVec, Registry};
+use serde::{Deserialize, Serialize};
+use shared_schema::LABEL_REMOTE_IP;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::info;
+
+// Remotes per page at most
+const MAX_N: usize = 100;
+// Remotes tracked per window at most; traffic to further remotes is not counted
+const MAX_REMOTES_PER_WINDOW: usize = 65536;
+
+// (interface, remote IP)
+type Key = (String, String);
+
+struct Window {
+    timestamp_ms: i64,
+    duration: Duration,
+    // (download, upload) bytes
+    bytes: HashMap<Key, (u64, u64)>,
+}
+
+// Query parameters of /top
+#[derive(Debug, Default, Deserialize)]
+pub struct TopQuery {
+    // Remotes per list (default 10, at most 100)
+    pub n: Option<usize>,
+    // Windows to look back over (default 1, at most TOP_MAX_WINDOWS)
+    pub windows: Option<usize>,
+    // Only traffic over this interface
+    pub interface: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TopEntry {
+    pub remote_ip: String,
+    pub download_bytes: u64,
+    pub upload_bytes: u64,
+    pub interfaces: BTreeSet<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TopPage {
+    // When the newest window was closed (Unix epoch milliseconds)
+    pub timestamp_ms: i64,
+    // Windows summed, fewer than requested shortly after startup
+    pub windows: usize,
+    // Summed length of those windows; bytes are totals over it
+    pub duration_ms: u64,
+    // Most downloaded from first
+    pub download: Vec<TopEntry>,
+    // Most uploaded to first
+    pub upload: Vec<TopEntry>,
+}
+
+pub struct TopTalkers {
+    // Windows kept for lookback (TOP_MAX_WINDOWS)
+    max_windows: usize,
+    current: DashMap<Key, (u64, u64)>,
+    // Newest last
+    history: Mutex<VecDeque<Window>>,
+    // K and topk_download_bytes (TOP_K_METRICS, 0 disables)
+    topk: Option<(usize, IntGaugeVec)>,
+}
+
+impl TopTalkers {
+    // None when TOP_MAX_WINDOWS is 0
+    pub fn from_env(registry: &Registry) -> Option<Self> {
+        let max_windows = network::var("TOP_MAX_WINDOWS")
+            .ok()
+            .and_then(|v| v.trim().parse::<usize>().ok())
+            .unwrap_or(60);
+        info!("Top talkers: up to {} windows", max_windows);
+        if max_windows == 0 {
+            return None;
+        }
+        let k = network::var("TOP_K_METRICS")
+            .ok()
+            .and_then(|v| v.trim().parse::<usize>().ok())
+            .unwrap_or(0)
+            .min(MAX_N);
+        let topk = (k > 0).then(|| {
+            info!("Exporting top {} remotes as topk_download_bytes", k);
+            let gauge = IntGaugeVec::new(
+                prometheus::Opts::new(
+                    "topk_download_bytes",
+                    "Download bytes over the last second of the current top-K remotes",
+                )
+                .const_label("job", "localpacketdump"),
+                &["rank", LABEL_REMOTE_IP],
+            )
+            .expect("failed to create topk_download_bytes gauge");
+            registry
+                .register(Box::new(gauge.clone()))
+                .expect("failed to register topk_download_bytes gauge");
+            (k, gauge)
+        });
+        Some(Self {
+            max_windows,
+            current: DashMap::new(),
+            history: Mutex::new(VecDeque::with_capacity(max_windows)),
+            topk,
+        })
+    }
+
+    pub fn record(&self, remote_ip: &str, interface: &str, bytes: u64, download: bool) {
+        let key = (interface.to_string(), remote_ip.to_string());
+        if self.current.len() >= MAX_REMOTES_PER_WINDOW && !self.current.contains_key(&key) {
+            return;
+        }
+        let mut entry = self.current.entry(key).or_default();
+        if download {
+            entry.0 += bytes;
+        } else {
+            entry.1 += bytes;
+        }
+    }
+
+    // Close the current window; called whenever the byte gauges are published
+    pub fn publish_and_reset(&self, duration: Duration) {
+        let bytes: HashMap<Key, (u64, u64)> = self
+            .current
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        self.current.clear();
+        let mut history = self.history.lock().unwrap();
+        if history.len() >= self.max_windows {
+            history.pop_front();
+        }
+        history.push_back(Window {
+            timestamp_ms: chrono::Utc::now().timestamp_millis(),
+            duration,
+            bytes,
+        });
+        drop(history);
+
+        if let Some((k, gauge)) = &self.topk {
+            let page = self.query(&TopQuery {
+                n: Some(*k),
+                ..TopQuery::default()
+            });
+            // Remotes that left the top K disappear instead of lingering at their last value
+            gauge.reset();
+            let seconds = duration.as_secs_f64();
+            for (rank, entry) in page.download.iter().enumerate() {
+                let rate = if seconds > 0.0 {
+                    (entry.download_bytes as f64 / seconds).round() as i64
+                } else {
+                    entry.download_bytes as i64
+                };
+                gauge
+                    .with_label_values(&[&(rank + 1).to_string(), &entry.remote_ip])
+                    .set(rate);
+            }
+        }
+    }
+
+    pub fn query(&self, query: &TopQuery) -> TopPage {
+        let n = query.n.unwrap_or(10).min(MAX_N);
+        let lookback = query.windows.unwrap_or(1).clamp(1, self.max_windows);
+
+        let history = self.history.lock().unwrap();
+        let windows: Vec<&Window> = history.iter().rev().take(lookback).collect();
+        let mut totals: HashMap<&str, TopEntry> = HashMap::new();
+        for window in &windows {
+            for ((interface, remote_ip), (download, upload)) in &window.bytes {
+                if query
+                    .interface
+                    .as_ref()
+                    .is_some_and(|wanted| wanted != interface)
+                {
+                    continue;
+                }
+                let entry = totals.entry(remote_ip).or_insert_with(|| TopEntry {
+                    remote_ip: remote_ip.clone(),
+                    download_bytes: 0,
+                    upload_bytes: 0,
+                    interfaces: BTreeSet::new(),
+                });
+                entry.download_bytes += download;
+                entry.upload_bytes += upload;
+                if !entry.interfaces.contains(interface) {
+                    entry.interfaces.insert(interface.clone());
+                }
+            }
+        }
+
+        let top = |bytes: fn(&TopEntry) -> u64| {
+            let mut entries: Vec<&TopEntry> =
+                totals.values().filter(|entry| bytes(entry) > 0).collect();
+            entries.sort_by(|a, b| {
+                bytes(b)
+                    .cmp(&bytes(a))
+                    .then_with(|| a.remote_ip.cmp(&b.remote_ip))
+            });
+            entries.into_iter().take(n).cloned().collect()
+        };
+        TopPage {
+            timestamp_ms: windows.first().map_or(0, |window| window.timestamp_ms),
+            windows: windows.len(),
+            duration_ms: windows
+                .iter()
+                .map(|window| window.duration.as_millis() as u64)
+                .sum(),
+            download: top(|entry| entry.download_bytes),
+            upload: top(|entry| entry.upload_bytes),
+        }
+    }
+}
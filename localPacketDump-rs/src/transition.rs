@@ -0,0 +1,142 @@
+// IPv6 transition mechanisms (NAT64 / DS-Lite)
+//
+// Behind NAT64, an IPv4-only remote shows up as an IPv6 address with the IPv4 address
+// embedded after a NAT64 prefix (RFC 6052, 64:ff9b::/96 by default). Without translation
+// the same remote host is labeled under two identities on dual-stack networks.
+// DS-Lite carries IPv4 inside IPv6 (next header 4); the outer addresses are only the
+// B4 / AFTR tunnel endpoints, so the inner IPv4 packet is what should be accounted.
+
+use crate::network;
+use ipnetwork::Ipv6Network;
+use prometheus::{IntCounter, Registry};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use tracing::{error, info};
+
+// Prefix lengths defined by RFC 6052
+const NAT64_PREFIX_LENGTHS: [u8; 6] = [32, 40, 48, 56, 64, 96];
+
+pub struct Transition {
+    // NAT64 prefixes to recognize (NAT64_PREFIXES)
+    nat64_prefixes: Vec<Ipv6Network>,
+    // Label NAT64 remotes with their embedded IPv4 address (NAT64_TRANSLATE)
+    nat64_translate: bool,
+    // Account DS-Lite traffic by its inner IPv4 packet (DSLITE_DECAPSULATE)
+    pub dslite_decapsulate: bool,
+    nat64_packets: IntCounter,
+    dslite_packets: IntCounter,
+}
+
+impl Transition {
+    pub fn new(registry: &Registry) -> Self {
+        let nat64_prefixes: Vec<Ipv6Network> = network::var("NAT64_PREFIXES")
+            .unwrap_or_else(|_| "64:ff9b::/96".to_string())
+            .split(',')
+            .map(str::trim)
+            .filter(|prefix| !prefix.is_empty())
+            .filter_map(|prefix| match Ipv6Network::from_str(prefix) {
+                Ok(net) if NAT64_PREFIX_LENGTHS.contains(&net.prefix()) => Some(net),
+                Ok(net) => {
+                    error!(
+                        "NAT64 prefix {} must be /32, /40, /48, /56, /64 or /96",
+                        net
+                    );
+                    None
+                }
+                Err(e) => {
+                    error!("Failed to parse NAT64 prefix {}: {}", prefix, e);
+                    None
+                }
+            })
+            .collect();
+        let nat64_translate = is_enabled("NAT64_TRANSLATE");
+        let dslite_decapsulate = is_enabled("DSLITE_DECAPSULATE");
+
+        info!(
+            "NAT64 prefixes {:?} (translate: {}), DS-Lite decapsulation: {}",
+            nat64_prefixes, nat64_translate, dslite_decapsulate
+        );
+
+        let nat64_packets = IntCounter::with_opts(
+            prometheus::Opts::new(
+                "nat64_packets_total",
+                "Packets whose remote address is inside a NAT64 prefix",
+            )
+            .const_label("job", "localpacketdump"),
+        )
+        .expect("failed to create nat64_packets_total counter");
+        let dslite_packets = IntCounter::with_opts(
+            prometheus::Opts::new(
+                "dslite_packets_total",
+                "IPv4-in-IPv6 (DS-Lite) packets seen on the interface",
+            )
+            .const_label("job", "localpacketdump"),
+        )
+        .expect("failed to create dslite_packets_total counter");
+        registry
+            .register(Box::new(nat64_packets.clone()))
+            .expect("failed to register nat64_packets_total counter");
+        registry
+            .register(Box::new(dslite_packets.clone()))
+            .expect("failed to register dslite_packets_total counter");
+
+        Self {
+            nat64_prefixes,
+            nat64_translate,
+            dslite_decapsulate,
+            nat64_packets,
+            dslite_packets,
+        }
+    }
+
+    fn label_ipv6(&self, addr: Ipv6Addr) -> String {
+        let Some(prefix) = self.nat64_prefixes.iter().find(|net| net.contains(addr)) else {
+            return addr.to_string();
+        };
+        self.nat64_packets.inc();
+        if self.nat64_translate {
+            extract_ipv4(addr, prefix.prefix()).to_string()
+        } else {
+            addr.to_string()
+        }
+    }
+
+    // Label for an address; NAT64 addresses become their IPv4 form when translating
+    pub fn label(&self, addr: IpAddr) -> String {
+        match addr {
+            IpAddr::V4(addr) => addr.to_string(),
+            IpAddr::V6(addr) => self.label_ipv6(addr),
+        }
+    }
+
+    pub fn count_dslite(&self) {
+        self.dslite_packets.inc();
+    }
+}
+
+fn is_enabled(name: &str) -> bool {
+    matches!(
+        network::var(name)
+            .map(|v| v.trim().to_ascii_lowercase())
+            .as_deref(),
+        Ok("1") | Ok("true")
+    )
+}
+
+// RFC 6052 section 2.2: the IPv4 address follows the prefix, skipping bits 64..71
+fn extract_ipv4(addr: Ipv6Addr, prefix_len: u8) -> Ipv4Addr {
+    let octets = addr.octets();
+    let mut embedded = [0u8; 4];
+    let mut filled = 0;
+    for (index, octet) in octets.iter().enumerate().skip(prefix_len as usize / 8) {
+        if index == 8 {
+            continue;
+        }
+        embedded[filled] = *octet;
+        filled += 1;
+        if filled == embedded.len() {
+            break;
+        }
+    }
+    Ipv4Addr::from(embedded)
+}
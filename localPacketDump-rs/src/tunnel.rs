@@ -0,0 +1,236 @@
+// GRE / VXLAN decapsulation
+//
+// Overlay traffic routed through this box is otherwise accounted to the tunnel endpoints
+// only. When enabled, the inner packet's addresses and size are accounted instead.
+// VXLAN has no protocol number of its own, so it is recognized by UDP destination port.
+
+use crate::network;
+use pnet::packet::ethernet::{EtherType, EtherTypes};
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::udp::UdpPacket;
+use pnet::packet::Packet;
+use prometheus::{IntCounterVec, Registry};
+use tracing::{error, info};
+
+// GRE protocol type for Ethernet carried in GRE (NVGRE, gretap)
+const TRANSPARENT_ETHERNET_BRIDGING: EtherType = EtherType(0x6558);
+// VXLAN header flag marking a valid VNI (RFC 7348)
+const VXLAN_FLAG_VNI: u8 = 0x08;
+const VXLAN_HEADER_LEN: usize = 8;
+
+// Payload found inside a tunnel
+pub enum Inner<'a> {
+    // IP packet of the given ethertype
+    Ip(EtherType, &'a [u8]),
+    // Ethernet frame (VXLAN, gretap)
+    Ethernet(&'a [u8]),
+}
+
+pub struct Tunnels {
+    // Decapsulate GRE (GRE_DECAPSULATE)
+    gre: bool,
+    // UDP destination ports carrying VXLAN (VXLAN_PORTS, empty disables)
+    vxlan_ports: Vec<u16>,
+    packets: IntCounterVec,
+}
+
+impl Tunnels {
+    pub fn new(registry: &Registry) -> Self {
+        let gre = matches!(
+            network::var("GRE_DECAPSULATE")
+                .map(|v| v.trim().to_ascii_lowercase())
+                .as_deref(),
+            Ok("1") | Ok("true")
+        );
+        let vxlan_ports: Vec<u16> = network::var("VXLAN_PORTS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|port| !port.is_empty())
+            .filter_map(|port| match port.parse::<u16>() {
+                Ok(port) => Some(port),
+                Err(e) => {
+                    error!("Failed to parse VXLAN port {}: {}", port, e);
+                    None
+                }
+            })
+            .collect();
+
+        info!("GRE decapsulation: {}, VXLAN ports: {:?}", gre, vxlan_ports);
+
+        let packets = IntCounterVec::new(
+            prometheus::Opts::new(
+                "tunnel_packets_total",
+                "Tunneled packets accounted by their inner packet",
+            )
+            .const_label("job", "localpacketdump"),
+            &["type"],
+        )
+        .expect("failed to create tunnel_packets_total counter");
+        registry
+            .register(Box::new(packets.clone()))
+            .expect("failed to register tunnel_packets_total counter");
+
+        Self {
+            gre,
+            vxlan_ports,
+            packets,
+        }
+    }
+
+    // Inner payload of a GRE or VXLAN packet, or None if it is not a tunnel to decapsulate
+    pub fn decapsulate<'a>(
+        &self,
+        protocol: IpNextHeaderProtocol,
+        payload: &'a [u8],
+    ) -> Option<Inner<'a>> {
+        match protocol {
+            IpNextHeaderProtocols::Gre if self.gre => {
+                let inner = parse_gre(payload)?;
+                self.packets.with_label_values(&["gre"]).inc();
+                Some(inner)
+            }
+            IpNextHeaderProtocols::Udp if !self.vxlan_ports.is_empty() => {
+                let udp = UdpPacket::new(payload)?;
+                if !self.vxlan_ports.contains(&udp.get_destination()) {
+                    return None;
+                }
+                let vxlan = udp.payload();
+                if vxlan.len() <= VXLAN_HEADER_LEN || vxlan[0] & VXLAN_FLAG_VNI == 0 {
+                    return None;
+                }
+                self.packets.with_label_values(&["vxlan"]).inc();
+                // The inner frame starts after the UDP and VXLAN headers
+                let offset = payload.len() - vxlan.len() + VXLAN_HEADER_LEN;
+                Some(Inner::Ethernet(&payload[offset..]))
+            }
+            _ => None,
+        }
+    }
+}
+
+// RFC 2784 / 2890 GRE header: flags and version, protocol type, then optional
+// checksum, key and sequence number fields
+fn parse_gre(data: &[u8]) -> Option<Inner<'_>> {
+    if data.len() < 4 {
+        return None;
+    }
+    let flags = u16::from_be_bytes([data[0], data[1]]);
+    // Version 1 is PPTP's enhanced GRE, which carries PPP rather than IP
+    if flags & 0x0007 != 0 {
+        return None;
+    }
+    let protocol = EtherType(u16::from_be_bytes([data[2], data[3]]));
+    let mut offset = 4;
+    for present in [0x8000, 0x2000, 0x1000] {
+        if flags & present != 0 {
+            offset += 4;
+        }
+    }
+    let inner = data.get(offset..)?;
+    match protocol {
+        EtherTypes::Ipv4 | EtherTypes::Ipv6 => Some(Inner::Ip(protocol, inner)),
+        TRANSPARENT_ETHERNET_BRIDGING => Some(Inner::Ethernet(inner)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_ports(vxlan_ports: Vec<u16>) -> Tunnels {
+        Tunnels {
+            gre: true,
+            vxlan_ports,
+            packets: IntCounterVec::new(prometheus::Opts::new("packets", "packets"), &["type"])
+                .unwrap(),
+        }
+    }
+
+    fn gre(flags: u16, protocol: EtherType, fields: &[u8], inner: &[u8]) -> Vec<u8> {
+        [
+            &flags.to_be_bytes()[..],
+            &protocol.0.to_be_bytes(),
+            fields,
+            inner,
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn gre_optional_fields() {
+        let ip = [0x45, 0x00, 0x00, 0x14];
+        let key_and_sequence = [0, 0, 0, 42, 0, 0, 0, 7];
+        // K and S bits: key and sequence number before the payload
+        let packet = gre(0x3000, EtherTypes::Ipv4, &key_and_sequence, &ip);
+        let Some(Inner::Ip(ethertype, inner)) = parse_gre(&packet) else {
+            panic!("no inner IP packet");
+        };
+        assert_eq!(ethertype, EtherTypes::Ipv4);
+        assert_eq!(inner, ip);
+
+        // C bit as well: checksum and reserved word first
+        let fields = [[0u8; 4].as_slice(), &key_and_sequence].concat();
+        let packet = gre(0xb000, EtherTypes::Ipv6, &fields, &[0x60]);
+        let Some(Inner::Ip(ethertype, inner)) = parse_gre(&packet) else {
+            panic!("no inner IP packet");
+        };
+        assert_eq!(ethertype, EtherTypes::Ipv6);
+        assert_eq!(inner, [0x60]);
+    }
+
+    #[test]
+    fn gre_ethernet_and_rejected() {
+        let frame = [0xff; 14];
+        let packet = gre(0x2000, TRANSPARENT_ETHERNET_BRIDGING, &[0, 0, 0, 1], &frame);
+        let Some(Inner::Ethernet(inner)) = parse_gre(&packet) else {
+            panic!("no inner frame");
+        };
+        assert_eq!(inner, frame);
+
+        // Enhanced GRE (version 1), other protocols, and fields cut short
+        assert!(parse_gre(&gre(0x3001, EtherTypes::Ipv4, &[0; 8], &[0x45])).is_none());
+        assert!(parse_gre(&gre(0x0000, EtherTypes::Arp, &[], &[0])).is_none());
+        assert!(parse_gre(&gre(0x3000, EtherTypes::Ipv4, &[0; 6], &[])).is_none());
+        assert!(parse_gre(&[0x00, 0x00, 0x08]).is_none());
+    }
+
+    fn vxlan(port: u16, flags: u8, frame: &[u8]) -> Vec<u8> {
+        let len = (8 + VXLAN_HEADER_LEN + frame.len()) as u16;
+        let udp = [
+            &49152u16.to_be_bytes()[..],
+            &port.to_be_bytes(),
+            &len.to_be_bytes(),
+            &[0, 0],
+        ]
+        .concat();
+        let header = [flags, 0, 0, 0, 0, 0x10, 0x00, 0];
+        [udp.as_slice(), &header, frame].concat()
+    }
+
+    #[test]
+    fn vxlan_inner_frame() {
+        let tunnels = with_ports(vec![4789]);
+        let frame = [0xaa; 14];
+        let packet = vxlan(4789, VXLAN_FLAG_VNI, &frame);
+        let Some(Inner::Ethernet(inner)) = tunnels.decapsulate(IpNextHeaderProtocols::Udp, &packet)
+        else {
+            panic!("no inner frame");
+        };
+        assert_eq!(inner, frame);
+
+        // Another port, no valid VNI, or a VXLAN header with nothing after it
+        let ignored = |packet: &[u8]| {
+            tunnels
+                .decapsulate(IpNextHeaderProtocols::Udp, packet)
+                .is_none()
+        };
+        assert!(ignored(&vxlan(4790, VXLAN_FLAG_VNI, &frame)));
+        assert!(ignored(&vxlan(4789, 0, &frame)));
+        assert!(ignored(&vxlan(4789, VXLAN_FLAG_VNI, &[])));
+        assert!(with_ports(Vec::new())
+            .decapsulate(IpNextHeaderProtocols::Udp, &packet)
+            .is_none());
+    }
+}
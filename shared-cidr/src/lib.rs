@@ -0,0 +1,222 @@
+// CIDR の集合に対する最長一致の検索
+//
+// ローカル（LOCAL_CIDRS）、除外（DENY_CIDRS / MEASUREMENT_CIDRS）、名前付きのセグメント（SEGMENTS）の判定は
+// どれも CIDR の一覧を先頭から調べていたため、パケットごとの処理が CIDR の数に比例していた。
+// アドレスのビットをたどる二分木（トライ）にまとめ、CIDR が数十個あってもアドレス長ぶんの手順で引けるようにする。
+
+use ipnetwork::IpNetwork;
+use std::net::IpAddr;
+
+// IPv4 と IPv6 の根（nodes の 0 と 1）
+const V4_ROOT: u32 = 0;
+const V6_ROOT: u32 = 1;
+// 子が無いことを表す番号（根は子にならないので 0 を使う）
+const NO_CHILD: u32 = 0;
+
+// CIDR -> 値 の表。包含関係にある CIDR はより長いプレフィックスが優先する
+#[derive(Debug, Clone)]
+pub struct PrefixTable<T> {
+    nodes: Vec<Node>,
+    // 登録順の (CIDR, 値)
+    entries: Vec<(IpNetwork, T)>,
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    children: [u32; 2],
+    // このノードで終わる CIDR の entries の番号
+    entry: Option<u32>,
+}
+
+impl Node {
+    fn new() -> Self {
+        Self {
+            children: [NO_CHILD; 2],
+            entry: None,
+        }
+    }
+}
+
+// 値を持たない CIDR の集合
+pub type IpSet = PrefixTable<()>;
+
+impl<T> Default for PrefixTable<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// 同じ CIDR を同じ順に登録した表を等しいとみなす（再読み込みで変わったかの判定用）
+impl<T: PartialEq> PartialEq for PrefixTable<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.entries == other.entries
+    }
+}
+
+impl<T> PrefixTable<T> {
+    pub fn new() -> Self {
+        Self {
+            nodes: vec![Node::new(), Node::new()],
+            entries: Vec::new(),
+        }
+    }
+
+    // 同じ CIDR を再び登録した場合は値を置き換える
+    pub fn insert(&mut self, network: IpNetwork, value: T) {
+        let (mut node, bits) = root_and_bits(network.ip());
+        for depth in 0..u32::from(network.prefix()) {
+            let bit = bit_at(bits, depth);
+            let child = self.nodes[node as usize].children[bit];
+            node = if child == NO_CHILD {
+                let index = self.nodes.len() as u32;
+                self.nodes.push(Node::new());
+                self.nodes[node as usize].children[bit] = index;
+                index
+            } else {
+                child
+            };
+        }
+        match self.nodes[node as usize].entry {
+            Some(index) => self.entries[index as usize] = (network, value),
+            None => {
+                self.nodes[node as usize].entry = Some(self.entries.len() as u32);
+                self.entries.push((network, value));
+            }
+        }
+    }
+
+    // アドレスを含む CIDR のうちプレフィックスが最も長いもの
+    pub fn lookup(&self, ip: IpAddr) -> Option<(&IpNetwork, &T)> {
+        let (mut node, bits) = root_and_bits(ip);
+        let width = if ip.is_ipv4() { 32 } else { 128 };
+        let mut best = self.nodes[node as usize].entry;
+        for depth in 0..width {
+            let child = self.nodes[node as usize].children[bit_at(bits, depth)];
+            if child == NO_CHILD {
+                break;
+            }
+            node = child;
+            if let Some(entry) = self.nodes[node as usize].entry {
+                best = Some(entry);
+            }
+        }
+        best.map(|index| {
+            let (network, value) = &self.entries[index as usize];
+            (network, value)
+        })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.lookup(ip).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // 登録順の (CIDR, 値)
+    pub fn iter(&self) -> impl Iterator<Item = (&IpNetwork, &T)> {
+        self.entries.iter().map(|(network, value)| (network, value))
+    }
+}
+
+impl<T> FromIterator<(IpNetwork, T)> for PrefixTable<T> {
+    fn from_iter<I: IntoIterator<Item = (IpNetwork, T)>>(iter: I) -> Self {
+        let mut table = Self::new();
+        for (network, value) in iter {
+            table.insert(network, value);
+        }
+        table
+    }
+}
+
+impl FromIterator<IpNetwork> for IpSet {
+    fn from_iter<I: IntoIterator<Item = IpNetwork>>(iter: I) -> Self {
+        iter.into_iter().map(|network| (network, ())).collect()
+    }
+}
+
+// 根のノードと、先頭のビットから並べたアドレス
+fn root_and_bits(ip: IpAddr) -> (u32, u128) {
+    match ip {
+        IpAddr::V4(v4) => (V4_ROOT, u128::from(u32::from(v4)) << 96),
+        IpAddr::V6(v6) => (V6_ROOT, u128::from(v6)),
+    }
+}
+
+fn bit_at(bits: u128, depth: u32) -> usize {
+    ((bits >> (127 - depth)) & 1) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn net(s: &str) -> IpNetwork {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        // 登録順に関係なく、より長いプレフィックスが優先する
+        let table: PrefixTable<&str> = [
+            (net("10.1.2.0/24"), "lab"),
+            (net("10.0.0.0/8"), "corp"),
+            (net("10.1.0.0/16"), "site"),
+        ]
+        .into_iter()
+        .collect();
+        let value = |s: &str| table.lookup(ip(s)).map(|(_, v)| *v);
+        assert_eq!(value("10.1.2.3"), Some("lab"));
+        assert_eq!(value("10.1.3.3"), Some("site"));
+        assert_eq!(value("10.2.0.1"), Some("corp"));
+        assert_eq!(value("192.168.0.1"), None);
+        assert_eq!(table.lookup(ip("10.1.2.3")).unwrap().0, &net("10.1.2.0/24"));
+    }
+
+    #[test]
+    fn default_routes_and_host_prefixes() {
+        let table: PrefixTable<u8> = [
+            (net("0.0.0.0/0"), 0),
+            (net("::/0"), 6),
+            (net("192.0.2.1/32"), 32),
+            (net("2001:db8::1/128"), 128),
+        ]
+        .into_iter()
+        .collect();
+        let value = |s: &str| table.lookup(ip(s)).map(|(_, v)| *v);
+        assert_eq!(value("192.0.2.1"), Some(32));
+        assert_eq!(value("192.0.2.2"), Some(0));
+        assert_eq!(value("2001:db8::1"), Some(128));
+        assert_eq!(value("2001:db8::2"), Some(6));
+    }
+
+    #[test]
+    fn families_are_separate() {
+        // ::/0 は IPv4 のアドレスを含まない
+        let set: IpSet = [net("::/0")].into_iter().collect();
+        assert!(set.contains(ip("::1")));
+        assert!(!set.contains(ip("127.0.0.1")));
+        // IPv4 射影アドレスも IPv6 として扱う
+        let set: IpSet = [net("0.0.0.0/0")].into_iter().collect();
+        assert!(!set.contains(ip("::ffff:127.0.0.1")));
+    }
+
+    #[test]
+    fn reinsert_replaces_value() {
+        let mut table = PrefixTable::new();
+        table.insert(net("10.0.0.0/8"), 1);
+        table.insert(net("10.0.0.0/8"), 2);
+        assert_eq!(table.len(), 1);
+        assert_eq!(table.lookup(ip("10.0.0.1")).map(|(_, v)| *v), Some(2));
+        assert!(PrefixTable::<()>::new().is_empty());
+    }
+}
@@ -0,0 +1,320 @@
+// 全コンポーネント共通の設定ファイル（--config traffic-scan.toml）
+//
+// 設定は環境変数（localPacketDump-rs / icmp-traffic-scan / throughput-dump）と CLI フラグ
+// （tcp-traffic-scan）に分かれていたため、1 つの TOML / YAML ファイルにまとめて書けるようにする。
+// トップレベルのキーは全コンポーネント共通、コンポーネント名のテーブルはそのコンポーネントだけの設定で、
+// 共通のキーより優先する。キーは環境変数の名前を小文字にしたもので、入れ子のテーブルは `_` でつなぐ
+// （`[localpacketdump.network.guest] interface_name` → `NETWORK_GUEST_INTERFACE_NAME`）。
+// 環境変数が設定されていればファイルより優先する。
+// ファイルは起動時に読み、reload を呼ぶと読み直す（localPacketDump-rs の SIGHUP / POST /reload）。
+
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::env;
+use std::path::Path;
+use std::sync::RwLock;
+
+// コンポーネント名（設定ファイルのテーブル名）
+pub const LOCALPACKETDUMP: &str = "localpacketdump";
+pub const ICMP_TRAFFIC_SCAN: &str = "icmp-traffic-scan";
+pub const THROUGHPUT_DUMP: &str = "throughput-dump";
+pub const TCP_TRAFFIC_SCAN: &str = "tcp-traffic-scan";
+const COMPONENTS: [&str; 4] = [
+    LOCALPACKETDUMP,
+    ICMP_TRAFFIC_SCAN,
+    THROUGHPUT_DUMP,
+    TCP_TRAFFIC_SCAN,
+];
+
+// init / reload で最後に読み込めた設定
+static LOADED: RwLock<Option<Loaded>> = RwLock::new(None);
+
+struct Loaded {
+    path: String,
+    component: String,
+    settings: Settings,
+}
+
+// 1 つのコンポーネントから見た設定
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    // 小文字のキー -> 値（配列は要素ごと、スカラーは 1 要素）
+    entries: BTreeMap<String, Vec<String>>,
+}
+
+impl Settings {
+    // 拡張子が .yaml / .yml なら YAML、それ以外は TOML として読む
+    pub fn load(path: &Path, component: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        let yaml = matches!(
+            path.extension().and_then(|e| e.to_str()),
+            Some("yaml") | Some("yml")
+        );
+        let document: Value = if yaml {
+            serde_yaml::from_str(&text).map_err(|e| e.to_string())
+        } else {
+            toml::from_str(&text).map_err(|e| e.to_string())
+        }
+        .map_err(|e| format!("failed to parse {}: {}", path.display(), e))?;
+        Self::from_document(&document, component)
+            .map_err(|e| format!("invalid {}: {}", path.display(), e))
+    }
+
+    fn from_document(document: &Value, component: &str) -> Result<Self, String> {
+        let Value::Object(root) = document else {
+            return Err("the top level must be a table".to_string());
+        };
+        let mut settings = Self::default();
+        // 共通のキーを先に入れ、コンポーネントのテーブルで上書きする
+        for (key, value) in root {
+            if !COMPONENTS.contains(&key.as_str()) {
+                settings.flatten(key, value)?;
+            }
+        }
+        match root.get(component) {
+            Some(Value::Object(table)) => {
+                for (key, value) in table {
+                    settings.flatten(key, value)?;
+                }
+            }
+            Some(_) => return Err(format!("{} must be a table", component)),
+            None => {}
+        }
+        Ok(settings)
+    }
+
+    fn flatten(&mut self, key: &str, value: &Value) -> Result<(), String> {
+        let key = key.to_ascii_lowercase().replace('-', "_");
+        let values = match value {
+            Value::Object(table) => {
+                for (inner, value) in table {
+                    self.flatten(&format!("{}_{}", key, inner), value)?;
+                }
+                return Ok(());
+            }
+            Value::Array(items) => items
+                .iter()
+                .map(|item| scalar(item).ok_or_else(|| format!("{}: nested values in a list", key)))
+                .collect::<Result<Vec<_>, _>>()?,
+            value => vec![scalar(value).ok_or_else(|| format!("{}: unsupported value", key))?],
+        };
+        self.entries.insert(key, values);
+        Ok(())
+    }
+
+    // キー（小文字）の値。配列は要素ごと
+    pub fn get(&self, key: &str) -> Option<&[String]> {
+        self.entries.get(key).map(Vec::as_slice)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[String])> {
+        self.entries
+            .iter()
+            .map(|(key, values)| (key.as_str(), values.as_slice()))
+    }
+
+    // 環境変数の名前で引いた値。配列はカンマ区切りにする
+    pub fn env_value(&self, name: &str) -> Option<String> {
+        self.get(&name.to_ascii_lowercase())
+            .map(|values| values.join(","))
+    }
+}
+
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        // 空の値（YAML の `key:`）
+        Value::Null => Some(String::new()),
+        Value::Array(_) | Value::Object(_) => None,
+    }
+}
+
+// 引数の `--config PATH` / `--config=PATH`
+pub fn path_from_args() -> Option<String> {
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next();
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(path.to_string());
+        }
+    }
+    None
+}
+
+// `--config` が指定されていればそのコンポーネントの設定を読み込む。main の最初に 1 回呼ぶ
+pub fn init(component: &str) -> Result<Option<Settings>, String> {
+    let Some(path) = path_from_args() else {
+        return Ok(None);
+    };
+    let settings = Settings::load(Path::new(&path), component)?;
+    *LOADED.write().unwrap() = Some(Loaded {
+        path,
+        component: component.to_string(),
+        settings: settings.clone(),
+    });
+    Ok(Some(settings))
+}
+
+// init で読んだファイルを読み直す。読めなければ前の設定のまま。`--config` が無ければ何もしない
+pub fn reload() -> Result<(), String> {
+    let Some((path, component)) = LOADED
+        .read()
+        .unwrap()
+        .as_ref()
+        .map(|loaded| (loaded.path.clone(), loaded.component.clone()))
+    else {
+        return Ok(());
+    };
+    let settings = Settings::load(Path::new(&path), &component)?;
+    if let Some(loaded) = LOADED.write().unwrap().as_mut() {
+        loaded.settings = settings;
+    }
+    Ok(())
+}
+
+// env::var と同じだが、環境変数が無ければ設定ファイルの値を返す
+pub fn var(name: &str) -> Result<String, env::VarError> {
+    match env::var(name) {
+        Err(env::VarError::NotPresent) => LOADED
+            .read()
+            .unwrap()
+            .as_ref()
+            .and_then(|loaded| loaded.settings.env_value(name))
+            .ok_or(env::VarError::NotPresent),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_toml(text: &str, component: &str) -> Result<Settings, String> {
+        let document: Value = toml::from_str(text).unwrap();
+        Settings::from_document(&document, component)
+    }
+
+    fn from_yaml(text: &str, component: &str) -> Result<Settings, String> {
+        let document: Value = serde_yaml::from_str(text).unwrap();
+        Settings::from_document(&document, component)
+    }
+
+    #[test]
+    fn nested_tables_and_keys() {
+        let settings = from_toml(
+            r#"
+            [localpacketdump.network.guest]
+            interface_name = "eth3"
+            [localpacketdump]
+            Status-URL = "http://router/status"
+            "#,
+            LOCALPACKETDUMP,
+        )
+        .unwrap();
+        assert_eq!(
+            settings
+                .env_value("NETWORK_GUEST_INTERFACE_NAME")
+                .as_deref(),
+            Some("eth3")
+        );
+        // 大文字と `-` は環境変数の名前にそろえる
+        assert_eq!(
+            settings.env_value("STATUS_URL").as_deref(),
+            Some("http://router/status")
+        );
+    }
+
+    #[test]
+    fn arrays_and_scalars() {
+        let settings = from_toml(
+            r#"
+            local_cidrs = ["10.40.0.0/20", "10.50.0.0/24"]
+            port = 59122
+            simulate = true
+            "#,
+            LOCALPACKETDUMP,
+        )
+        .unwrap();
+        assert_eq!(
+            settings.get("local_cidrs").unwrap(),
+            ["10.40.0.0/20", "10.50.0.0/24"]
+        );
+        assert_eq!(
+            settings.env_value("LOCAL_CIDRS").as_deref(),
+            Some("10.40.0.0/20,10.50.0.0/24")
+        );
+        assert_eq!(settings.env_value("PORT").as_deref(), Some("59122"));
+        assert_eq!(settings.env_value("SIMULATE").as_deref(), Some("true"));
+        assert_eq!(settings.env_value("MISSING"), None);
+    }
+
+    #[test]
+    fn component_table_wins_and_others_are_ignored() {
+        let text = r#"
+            prometheus_url = "http://shared:9090"
+            interval = "1"
+            [throughput-dump]
+            prometheus_url = "http://local:9090"
+            [icmp-traffic-scan]
+            interval = "5"
+            ping_timeout_ms = "500"
+        "#;
+        let settings = from_toml(text, THROUGHPUT_DUMP).unwrap();
+        assert_eq!(
+            settings.env_value("PROMETHEUS_URL").as_deref(),
+            Some("http://local:9090")
+        );
+        assert_eq!(settings.env_value("INTERVAL").as_deref(), Some("1"));
+        assert_eq!(settings.env_value("PING_TIMEOUT_MS"), None);
+        // ほかのコンポーネントのテーブルは共通のキーとして展開しない
+        assert!(settings.iter().all(|(key, _)| !key.contains("icmp")));
+
+        let settings = from_toml(text, ICMP_TRAFFIC_SCAN).unwrap();
+        assert_eq!(settings.env_value("INTERVAL").as_deref(), Some("5"));
+        assert_eq!(
+            settings.env_value("PROMETHEUS_URL").as_deref(),
+            Some("http://shared:9090")
+        );
+    }
+
+    #[test]
+    fn yaml_empty_value() {
+        let settings = from_yaml(
+            "localpacketdump:\n  status_url:\n  local_cidrs: [10.40.0.0/20]\n",
+            LOCALPACKETDUMP,
+        )
+        .unwrap();
+        assert_eq!(settings.env_value("STATUS_URL").as_deref(), Some(""));
+        assert_eq!(
+            settings.env_value("LOCAL_CIDRS").as_deref(),
+            Some("10.40.0.0/20")
+        );
+    }
+
+    #[test]
+    fn invalid_documents() {
+        assert_eq!(
+            from_yaml("- a\n- b\n", LOCALPACKETDUMP).unwrap_err(),
+            "the top level must be a table"
+        );
+        assert_eq!(
+            from_toml("localpacketdump = 1", LOCALPACKETDUMP).unwrap_err(),
+            "localpacketdump must be a table"
+        );
+        // 使わないコンポーネントのテーブルの形は問わない
+        assert!(from_toml("localpacketdump = 1", TCP_TRAFFIC_SCAN).is_ok());
+        assert_eq!(
+            from_toml("servers = [[\"a\"], [\"b\"]]", TCP_TRAFFIC_SCAN).unwrap_err(),
+            "servers: nested values in a list"
+        );
+        assert_eq!(
+            from_toml("servers = [{ host = \"a\" }]", TCP_TRAFFIC_SCAN).unwrap_err(),
+            "servers: nested values in a list"
+        );
+    }
+}
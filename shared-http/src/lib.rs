@@ -0,0 +1,258 @@
+// 各コンポーネント共通の HTTP クライアント
+//
+// ステータス API の取得、Prometheus へのクエリ、Webhook などで使う reqwest のラッパー。
+// タイムアウト、リトライ（429 / 5xx / 接続エラー）、コネクションプール、プロキシ、
+// ホストごとのレート制限をまとめて扱う。設定は環境変数（shared_config::var）か、渡された Lookup で読む。
+
+use log::{error, warn};
+use reqwest::{Client, Proxy, RequestBuilder, Response, StatusCode};
+use std::collections::HashMap;
+use std::env;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+// Retry-After が長すぎても定期取得を止めないよう待ち時間に上限を設ける
+const MAX_RETRY_WAIT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    // リクエスト全体のタイムアウト
+    pub timeout: Duration,
+    pub connect_timeout: Duration,
+    // 失敗時の再試行回数（0 で再試行しない）
+    pub retries: u32,
+    // 再試行の初回待ち時間（以降は倍々）
+    pub retry_backoff: Duration,
+    // ホストごとの 1 秒あたりの最大リクエスト数（0 で無制限）
+    pub rate_limit_per_host: f64,
+    // すべてのリクエストを通すプロキシ（未設定なら HTTP_PROXY などの標準の環境変数に従う）
+    pub proxy: Option<String>,
+    // 追加で信頼する CA 証明書（PEM ファイルのパス）。自己署名の Prometheus などに使う
+    pub ca_cert: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(10),
+            connect_timeout: Duration::from_secs(3),
+            retries: 2,
+            retry_backoff: Duration::from_millis(200),
+            rate_limit_per_host: 0.0,
+            proxy: None,
+            ca_cert: None,
+        }
+    }
+}
+
+// 設定の読み方（shared_config::var か、localPacketDump-rs のネットワークごとの上書き付きのもの）
+pub trait Lookup: Fn(&str) -> Result<String, env::VarError> {}
+
+impl<F: Fn(&str) -> Result<String, env::VarError>> Lookup for F {}
+
+fn parse<T: std::str::FromStr>(var: &impl Lookup, name: &str) -> Option<T> {
+    var(name).ok().and_then(|v| v.trim().parse().ok())
+}
+
+// 負数・inf・巨大な値は from_secs_f64 が panic するので、有限で 0 以上のものだけ受け付ける
+fn secs(var: &impl Lookup, name: &str) -> Option<Duration> {
+    parse::<f64>(var, name)
+        .filter(|v| v.is_finite() && *v >= 0.0)
+        .and_then(|v| Duration::try_from_secs_f64(v).ok())
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Self::from_lookup(shared_config::var)
+    }
+
+    pub fn from_lookup(var: impl Lookup) -> Self {
+        let default = Self::default();
+        Self {
+            timeout: secs(&var, "HTTP_TIMEOUT_SECS").unwrap_or(default.timeout),
+            connect_timeout: secs(&var, "HTTP_CONNECT_TIMEOUT_SECS")
+                .unwrap_or(default.connect_timeout),
+            retries: parse(&var, "HTTP_RETRIES").unwrap_or(default.retries),
+            retry_backoff: parse(&var, "HTTP_RETRY_BACKOFF_MS")
+                .map(Duration::from_millis)
+                .unwrap_or(default.retry_backoff),
+            rate_limit_per_host: parse(&var, "HTTP_RATE_LIMIT_PER_HOST")
+                .unwrap_or(default.rate_limit_per_host),
+            proxy: var("HTTP_CLIENT_PROXY").ok().filter(|v| !v.is_empty()),
+            ca_cert: var("HTTP_CA_CERT").ok().filter(|v| !v.is_empty()),
+        }
+    }
+}
+
+pub struct HttpClient {
+    inner: Client,
+    config: Config,
+    // ホストごとに次のリクエストを送ってよい時刻
+    next_allowed: Mutex<HashMap<String, Instant>>,
+}
+
+impl HttpClient {
+    pub fn new(config: Config) -> Self {
+        let mut builder = Client::builder()
+            .timeout(config.timeout)
+            .connect_timeout(config.connect_timeout)
+            .pool_max_idle_per_host(4)
+            .pool_idle_timeout(Duration::from_secs(90))
+            .tcp_keepalive(Duration::from_secs(60));
+
+        if let Some(proxy) = &config.proxy {
+            match Proxy::all(proxy) {
+                Ok(proxy) => builder = builder.proxy(proxy),
+                Err(e) => error!("Invalid HTTP_CLIENT_PROXY {}: {}", proxy, e),
+            }
+        }
+
+        if let Some(path) = &config.ca_cert {
+            builder = add_ca_cert(builder, path);
+        }
+
+        let inner = builder.build().unwrap_or_else(|e| {
+            error!("Failed to build HTTP client, using defaults: {}", e);
+            Client::new()
+        });
+
+        Self {
+            inner,
+            config,
+            next_allowed: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn from_env() -> Self {
+        Self::new(Config::from_env())
+    }
+
+    pub fn from_lookup(var: impl Lookup) -> Self {
+        Self::new(Config::from_lookup(var))
+    }
+
+    pub fn get(&self, url: &str) -> RequestBuilder {
+        self.inner.get(url)
+    }
+
+    pub fn post(&self, url: &str) -> RequestBuilder {
+        self.inner.post(url)
+    }
+
+    // レート制限と再試行を適用して送信する。再試行し尽くした 429 / 5xx はそのまま返す
+    pub async fn send(&self, request: RequestBuilder) -> reqwest::Result<Response> {
+        let request = request.build()?;
+        let host = request.url().host_str().unwrap_or_default().to_string();
+        let idempotent = is_idempotent(request.method());
+        let mut backoff = self.config.retry_backoff;
+        let mut attempt = 0;
+
+        loop {
+            self.wait_for_slot(&host).await;
+
+            // ストリーミングのボディは複製できないので 1 回だけ送る
+            let Some(current) = request.try_clone() else {
+                return self.inner.execute(request).await;
+            };
+            let can_retry = attempt < self.config.retries;
+            attempt += 1;
+
+            match self.inner.execute(current).await {
+                Ok(response) if can_retry && is_retryable(response.status()) => {
+                    let wait = retry_after(&response)
+                        .unwrap_or(backoff)
+                        .min(MAX_RETRY_WAIT);
+                    warn!(
+                        "{} returned {}, retrying in {:?}",
+                        request.url(),
+                        response.status(),
+                        wait
+                    );
+                    tokio::time::sleep(wait).await;
+                }
+                // タイムアウトはサーバ側で処理済みかもしれないので、冪等なメソッドだけ再送する
+                Err(e) if can_retry && (e.is_connect() || (idempotent && e.is_timeout())) => {
+                    let wait = backoff.min(MAX_RETRY_WAIT);
+                    warn!(
+                        "Request to {} failed, retrying in {:?}: {}",
+                        request.url(),
+                        wait,
+                        e
+                    );
+                    tokio::time::sleep(wait).await;
+                }
+                result => return result,
+            }
+            backoff = backoff.saturating_mul(2);
+        }
+    }
+
+    async fn wait_for_slot(&self, host: &str) {
+        if self.config.rate_limit_per_host <= 0.0 {
+            return;
+        }
+        let interval = Duration::from_secs_f64(1.0 / self.config.rate_limit_per_host);
+        let wait = {
+            let mut next_allowed = self.next_allowed.lock().await;
+            let now = Instant::now();
+            let slot = next_allowed
+                .get(host)
+                .copied()
+                .filter(|next| *next > now)
+                .unwrap_or(now);
+            next_allowed.insert(host.to_string(), slot + interval);
+            slot - now
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(feature = "tls")]
+fn add_ca_cert(builder: reqwest::ClientBuilder, path: &str) -> reqwest::ClientBuilder {
+    let cert = std::fs::read(path)
+        .map_err(|e| e.to_string())
+        .and_then(|pem| reqwest::Certificate::from_pem(&pem).map_err(|e| e.to_string()));
+    match cert {
+        Ok(cert) => builder.add_root_certificate(cert),
+        Err(e) => {
+            error!("Failed to load CA certificate {}: {}", path, e);
+            builder
+        }
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+fn add_ca_cert(builder: reqwest::ClientBuilder, path: &str) -> reqwest::ClientBuilder {
+    error!(
+        "CA certificate {} ignored: built without the tls feature",
+        path
+    );
+    builder
+}
+
+fn is_idempotent(method: &reqwest::Method) -> bool {
+    use reqwest::Method;
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::PUT | Method::DELETE | Method::TRACE
+    )
+}
+
+fn is_retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+// Retry-After（秒数のみ対応）
+fn retry_after(response: &Response) -> Option<Duration> {
+    response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .map(Duration::from_secs)
+}
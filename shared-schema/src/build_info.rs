@@ -0,0 +1,121 @@
+// ビルド情報（/buildinfo と build_info メトリクス）
+//
+// 4 つのコンポーネントはルーター上で個別に更新されるため、どのビルドが動いているかを
+// Prometheus で横断して確認できるようにする。各クレートの build.rs で emit() を呼んで
+// 環境変数として埋め込み、本体では build_info!() で取り出す。
+
+use serde::Serialize;
+use std::env;
+use std::process::Command;
+
+pub const METRIC_NAME: &str = "build_info";
+pub const METRIC_HELP: &str = "Build information of the running binary (value is always 1)";
+pub const LABEL_NAMES: [&str; 5] = ["component", "version", "git_hash", "features", "rustc"];
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildInfo {
+    // Cargo のパッケージ名
+    pub component: &'static str,
+    pub version: &'static str,
+    // ビルドしたコミット（git が無い環境では "unknown"）
+    pub git_hash: &'static str,
+    // 有効な Cargo の feature（カンマ区切り、ソート済み）
+    pub features: &'static str,
+    pub rustc: &'static str,
+}
+
+impl BuildInfo {
+    // LABEL_NAMES の順のラベル値
+    pub fn label_values(&self) -> [&'static str; 5] {
+        [
+            self.component,
+            self.version,
+            self.git_hash,
+            self.features,
+            self.rustc,
+        ]
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    // prometheus クレートを使わないコンポーネント向けのテキスト形式
+    pub fn prometheus_text(&self) -> String {
+        let labels: Vec<String> = LABEL_NAMES
+            .iter()
+            .zip(self.label_values())
+            .map(|(name, value)| {
+                format!(
+                    "{}=\"{}\"",
+                    name,
+                    value.replace('\\', "\\\\").replace('"', "\\\"")
+                )
+            })
+            .collect();
+        format!(
+            "# HELP {name} {help}\n# TYPE {name} gauge\n{name}{{{labels}}} 1\n",
+            name = METRIC_NAME,
+            help = METRIC_HELP,
+            labels = labels.join(",")
+        )
+    }
+}
+
+// 呼び出したクレートのビルド情報（build.rs で emit() を呼んでおくこと）
+#[macro_export]
+macro_rules! build_info {
+    () => {
+        $crate::BuildInfo {
+            component: env!("CARGO_PKG_NAME"),
+            version: env!("CARGO_PKG_VERSION"),
+            git_hash: env!("BUILD_GIT_HASH"),
+            features: env!("BUILD_FEATURES"),
+            rustc: env!("BUILD_RUSTC_VERSION"),
+        }
+    };
+}
+
+// build.rs から呼び、BUILD_GIT_HASH / BUILD_FEATURES / BUILD_RUSTC_VERSION を埋め込む。
+// .git の無い環境（ソースの tarball など）では環境変数 BUILD_GIT_HASH で指定できる
+pub fn emit() {
+    println!("cargo:rerun-if-env-changed=BUILD_GIT_HASH");
+    let git_hash = env::var("BUILD_GIT_HASH")
+        .ok()
+        .filter(|v| !v.is_empty())
+        .or_else(|| command_output("git", &["rev-parse", "--short=12", "HEAD"]))
+        .unwrap_or_else(|| "unknown".to_string());
+
+    // コミットやブランチの切り替えで再ビルドする
+    if let Some(git_dir) = command_output("git", &["rev-parse", "--absolute-git-dir"]) {
+        println!("cargo:rerun-if-changed={}/HEAD", git_dir);
+        if let Some(reference) = command_output("git", &["symbolic-ref", "-q", "HEAD"]) {
+            println!("cargo:rerun-if-changed={}/{}", git_dir, reference);
+        }
+    }
+
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(name, _)| {
+            name.strip_prefix("CARGO_FEATURE_")
+                .map(|feature| feature.to_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version =
+        command_output(&rustc, &["--version"]).unwrap_or_else(|| "unknown".to_string());
+
+    println!("cargo:rustc-env=BUILD_GIT_HASH={}", git_hash);
+    println!("cargo:rustc-env=BUILD_FEATURES={}", features.join(","));
+    println!("cargo:rustc-env=BUILD_RUSTC_VERSION={}", rustc_version);
+}
+
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let text = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (!text.is_empty()).then_some(text)
+}
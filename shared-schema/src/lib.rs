@@ -0,0 +1,157 @@
+// コンポーネント間で受け渡すデータ形式のバージョン
+//
+// ルーター上で各ツールを個別に更新できるよう、ステータス API のレスポンス、
+// /window.json のスナップショット、download_bytes / upload_bytes のラベル名に
+// バージョンを付ける。取得側は対応する最大バージョンを SCHEMA_VERSION_HEADER で伝え、
+// 提供側はそれ以下で返せる最新の形式を schema_version に入れて返す。
+
+pub mod build_info;
+pub mod pipeline;
+pub mod schedule;
+
+pub use build_info::BuildInfo;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+
+// 取得側が対応する最大バージョンを伝えるリクエストヘッダー / クエリ名
+pub const SCHEMA_VERSION_HEADER: &str = "X-Schema-Version";
+pub const SCHEMA_VERSION_QUERY: &str = "schema_version";
+
+// ステータス API（schema_version の無いレスポンスは 1 とみなす）
+pub const STATUS_SCHEMA_VERSION: u32 = 1;
+// localPacketDump-rs の /window.json
+pub const WINDOW_SCHEMA_VERSION: u32 = 1;
+// download_bytes / upload_bytes のラベル名（remote_ip / local_ip / interface）
+pub const LABELS_SCHEMA_VERSION: u32 = 1;
+
+pub const LABEL_REMOTE_IP: &str = "remote_ip";
+// AGGREGATE_PREFIX_V4 / V6 を設定したとき remote_ip の代わりに付く（"203.0.113.0/24"）
+pub const LABEL_REMOTE_PREFIX: &str = "remote_prefix";
+pub const LABEL_LOCAL_IP: &str = "local_ip";
+pub const LABEL_INTERFACE: &str = "interface";
+// PROTOCOL_LABELS を有効にしたときだけ付く（tcp / udp / icmp / other）
+pub const LABEL_PROTOCOL: &str = "protocol";
+// VLAN_LABELS を有効にしたときだけ付く（"100"、QinQ は "外側.内側"、タグなしは "untagged"）
+pub const LABEL_VLAN: &str = "vlan";
+// DSCP_LABELS を有効にしたときだけ付く（"EF" / "AF41" / "CS6" / "BE"、名前の無いものは "DSCP13"）
+pub const LABEL_DSCP_CLASS: &str = "dscp_class";
+// GEOIP_COUNTRY_DB / GEOIP_ASN_DB を設定したときだけ付く（"JP" / "2516"、不明なら "unknown"）
+pub const LABEL_COUNTRY: &str = "country";
+pub const LABEL_ASN: &str = "asn";
+// localPacketDump-rs で NETWORKS を設定したときだけすべてのメトリクスに付く（"lan" / "guest"）
+pub const LABEL_NETWORK: &str = "network";
+
+#[derive(Debug)]
+pub enum SchemaError {
+    // 対応していないバージョン
+    Unsupported { found: u32, supported: u32 },
+    // 数値として読めない要求
+    Malformed(String),
+    Invalid(serde_json::Error),
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchemaError::Unsupported { found, supported } => write!(
+                f,
+                "unsupported schema version {} (supported up to {})",
+                found, supported
+            ),
+            SchemaError::Malformed(requested) => {
+                write!(f, "malformed schema version '{}'", requested)
+            }
+            SchemaError::Invalid(e) => write!(f, "invalid payload: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SchemaError {}
+
+// 取得側が要求したバージョン（未指定なら最新）に対して返すバージョンを決める。
+// 要求以下で提供できる最新を返す。1 未満の要求は未対応、数値でない要求は不正とする
+pub fn negotiate(requested: Option<&str>, current: u32) -> Result<u32, SchemaError> {
+    let Some(requested) = requested else {
+        return Ok(current);
+    };
+    match requested.trim().parse::<u32>() {
+        Ok(version) if version >= 1 => Ok(version.min(current)),
+        Ok(version) => Err(SchemaError::Unsupported {
+            found: version,
+            supported: current,
+        }),
+        Err(_) => Err(SchemaError::Malformed(requested.to_string())),
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct StatusConfig {
+    #[serde(default)]
+    pub lan: String,
+    pub wan0: String,
+    pub wan1: String,
+}
+
+// NextRouter ステータス API のレスポンス
+#[derive(Debug, Deserialize, Clone)]
+pub struct StatusResponse {
+    #[serde(default = "legacy_version")]
+    pub schema_version: u32,
+    pub config: StatusConfig,
+    // ローカル端末 IP -> "wan0" / "wan1"
+    pub mappings: HashMap<String, String>,
+}
+
+fn legacy_version() -> u32 {
+    1
+}
+
+impl StatusResponse {
+    // 新しいバージョンはフィールドの追加のみで互換とし、知っているフィールドだけ読む。
+    // 読めない場合は未対応バージョンとして返す
+    pub fn from_json(body: &[u8]) -> Result<Self, SchemaError> {
+        match serde_json::from_slice::<Self>(body) {
+            Ok(status) => Ok(status),
+            Err(e) => {
+                let found = serde_json::from_slice::<VersionOnly>(body)
+                    .map(|v| v.schema_version)
+                    .unwrap_or(STATUS_SCHEMA_VERSION);
+                if found > STATUS_SCHEMA_VERSION {
+                    Err(SchemaError::Unsupported {
+                        found,
+                        supported: STATUS_SCHEMA_VERSION,
+                    })
+                } else {
+                    Err(SchemaError::Invalid(e))
+                }
+            }
+        }
+    }
+
+    pub fn is_newer_than_supported(&self) -> bool {
+        self.schema_version > STATUS_SCHEMA_VERSION
+    }
+
+    // マッピングに無い端末は wan0 を使う
+    pub fn interface_for(&self, local_ip: &str) -> &str {
+        match self.wan_for(local_ip) {
+            "wan1" => &self.config.wan1,
+            _ => &self.config.wan0,
+        }
+    }
+
+    // 端末が使う WAN（"wan0" / "wan1"）
+    pub fn wan_for(&self, local_ip: &str) -> &'static str {
+        match self.mappings.get(local_ip).map(String::as_str) {
+            Some("wan1") => "wan1",
+            _ => "wan0",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct VersionOnly {
+    #[serde(default = "legacy_version")]
+    schema_version: u32,
+}
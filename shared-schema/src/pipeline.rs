@@ -0,0 +1,38 @@
+// パイプラインの遅延（キャプチャ → ping → 計算）
+//
+// localPacketDump-rs はウィンドウを確定するたびに番号と確定時刻をメトリクスで公開し、
+// Prometheus 経由でバイト数と一緒に下流へ渡す。下流のツールは使ったデータの確定時刻から
+// 自分が動いた時刻までの遅延をヒストグラムに記録し、遅延の悪化を測れるようにする。
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// localPacketDump-rs が公開するウィンドウの番号（/window.json の sequence と同じ）
+pub const WINDOW_SEQUENCE_METRIC: &str = "window_sequence";
+// ウィンドウを確定した時刻（Unix エポック秒）
+pub const WINDOW_CLOSED_METRIC: &str = "window_closed_timestamp_seconds";
+
+// 下流のツールが記録するヒストグラム（stage ラベルで段階を区別する）
+pub const LAG_METRIC: &str = "pipeline_lag_seconds";
+pub const LAG_HELP: &str =
+    "Seconds from the localPacketDump-rs window closing to this stage acting on its data";
+// 下流のツールが使ったウィンドウの番号
+pub const INPUT_SEQUENCE_METRIC: &str = "pipeline_input_window_sequence";
+pub const INPUT_SEQUENCE_HELP: &str =
+    "Sequence of the localPacketDump-rs window this stage last acted on";
+
+// icmp-traffic-scan が ping を始めた時点
+pub const STAGE_PROBE: &str = "probe";
+// throughput-dump がスループットを計算した時点
+pub const STAGE_COMPUTE: &str = "compute";
+
+// スクレイプ間隔 1 秒の構成で、数秒の悪化と止まった状態を見分けられる幅
+pub const LAG_BUCKETS: [f64; 10] = [0.25, 0.5, 1.0, 1.5, 2.0, 3.0, 5.0, 10.0, 30.0, 60.0];
+
+// ウィンドウの確定時刻（Unix エポック秒）から今までの秒数。時計のずれで負にはしない
+pub fn lag_seconds(closed_timestamp_seconds: f64) -> f64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or(0.0);
+    (now - closed_timestamp_seconds).max(0.0)
+}
@@ -0,0 +1,39 @@
+// 測定の周期の位相
+//
+// icmp-traffic-scan の ping と tcp-traffic-scan の接続・実転送が毎秒同じ瞬間に始まると、
+// 互いの通信が相手の RTT やスループットに混ざる。各ツールは周期の開始を壁時計の周期の境界に
+// そろえたうえでツールごとの位相だけずらす。同じホストの時計を見ていれば、プロセス間で
+// やり取りしなくても開始の瞬間が重ならない。
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Schedule {
+    period: Duration,
+    // 境界からのずれ（period 以上なら period で割った余り）
+    phase: Duration,
+}
+
+impl Schedule {
+    pub fn new(period: Duration, phase: Duration) -> Self {
+        Self { period, phase }
+    }
+
+    // 次の開始時刻（Unix エポックから phase + n * period）までの時間。ちょうど境界なら 0
+    pub fn until_next(&self) -> Duration {
+        let period = self.period.as_nanos();
+        if period == 0 {
+            return Duration::ZERO;
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let phase = self.phase.as_nanos() % period;
+        let since_start = (now + period - phase) % period;
+        if since_start == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_nanos((period - since_start) as u64)
+    }
+}
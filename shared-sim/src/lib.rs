@@ -0,0 +1,410 @@
+// シミュレーションモード（--simulate）
+//
+// root 権限や実際の通信が無いノート PC でもパイプライン全体を動かせるよう、
+// 各コンポーネントの入力（キャプチャしたパケット、ステータス API、Prometheus の応答、ping の結果）を
+// 合成した値に置き換える。端末・リモート・WAN の組み合わせは環境変数から決まり、
+// 同じ設定なら各コンポーネントで同じ組み合わせになる。
+
+use serde_json::{json, Value};
+use std::env;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// 引数に --simulate があるか、SIMULATE=true のときに有効
+pub fn enabled() -> bool {
+    env::args().skip(1).any(|arg| arg == "--simulate")
+        || shared_config::var("SIMULATE").is_ok_and(|v| v == "true" || v == "1")
+}
+
+// 値の分布（負の値は 0 に切り上げる）
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Distribution {
+    Constant(f64),
+    // 一様分布 [min, max)
+    Uniform { min: f64, max: f64 },
+    Normal { mean: f64, stddev: f64 },
+    // 中央値と log の標準偏差で指定する対数正規分布（通信量のように裾の長い値向け）
+    LogNormal { median: f64, sigma: f64 },
+}
+
+impl Distribution {
+    // "constant:V" / "uniform:MIN,MAX" / "normal:MEAN,STDDEV" / "lognormal:MEDIAN,SIGMA"。
+    // 数値だけの場合は constant として扱う
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        let (kind, params) = s.split_once(':').unwrap_or(("constant", s));
+        let params: Vec<f64> = params
+            .split(',')
+            .map(|p| p.trim().parse::<f64>())
+            .collect::<Result<_, _>>()
+            .map_err(|_| format!("invalid distribution parameters in '{}'", s))?;
+        let distribution = match (kind, params.as_slice()) {
+            ("constant", [value]) => Distribution::Constant(*value),
+            ("uniform", [min, max]) if min <= max => Distribution::Uniform {
+                min: *min,
+                max: *max,
+            },
+            ("normal", [mean, stddev]) if *stddev >= 0.0 => Distribution::Normal {
+                mean: *mean,
+                stddev: *stddev,
+            },
+            ("lognormal", [median, sigma]) if *median > 0.0 && *sigma >= 0.0 => {
+                Distribution::LogNormal {
+                    median: *median,
+                    sigma: *sigma,
+                }
+            }
+            _ => return Err(format!("invalid distribution '{}'", s)),
+        };
+        Ok(distribution)
+    }
+
+    // 環境変数から読む（未設定や不正な値なら default）
+    pub fn from_env(name: &str, default: Distribution) -> Self {
+        match shared_config::var(name) {
+            Ok(value) => Self::parse(&value).unwrap_or_else(|e| {
+                eprintln!("Ignoring {}: {}", name, e);
+                default
+            }),
+            Err(_) => default,
+        }
+    }
+
+    pub fn sample(&self, rng: &mut fastrand::Rng) -> f64 {
+        let value = match *self {
+            Distribution::Constant(value) => value,
+            Distribution::Uniform { min, max } => min + (max - min) * rng.f64(),
+            Distribution::Normal { mean, stddev } => mean + stddev * standard_normal(rng),
+            Distribution::LogNormal { median, sigma } => {
+                median * (sigma * standard_normal(rng)).exp()
+            }
+        };
+        value.max(0.0)
+    }
+
+    // 分布のおおよその中心（ロスした周期の代わりに使う）
+    pub fn mean(&self) -> f64 {
+        match *self {
+            Distribution::Constant(value) => value,
+            Distribution::Uniform { min, max } => (min + max) / 2.0,
+            Distribution::Normal { mean, .. } => mean,
+            Distribution::LogNormal { median, .. } => median,
+        }
+    }
+}
+
+impl fmt::Display for Distribution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Distribution::Constant(value) => write!(f, "constant:{}", value),
+            Distribution::Uniform { min, max } => write!(f, "uniform:{},{}", min, max),
+            Distribution::Normal { mean, stddev } => write!(f, "normal:{},{}", mean, stddev),
+            Distribution::LogNormal { median, sigma } => {
+                write!(f, "lognormal:{},{}", median, sigma)
+            }
+        }
+    }
+}
+
+// Box-Muller 法
+fn standard_normal(rng: &mut fastrand::Rng) -> f64 {
+    let u1 = 1.0 - rng.f64();
+    let u2 = rng.f64();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+}
+
+// 端末とリモートの組み合わせ（リモートごとに 1 つ）
+#[derive(Debug, Clone)]
+pub struct Pair {
+    pub local_ip: IpAddr,
+    pub remote_ip: IpAddr,
+    // 端末のマッピングから決まる WAN のインターフェース名
+    pub interface: String,
+}
+
+// 1 秒分の合成された通信量
+#[derive(Debug, Clone, Copy)]
+pub struct Traffic {
+    pub download_bytes: u64,
+    pub upload_bytes: u64,
+}
+
+pub struct Scenario {
+    // wan0 / wan1 のインターフェース名
+    pub interfaces: Vec<String>,
+    pub pairs: Vec<Pair>,
+    // 1 秒あたりの download + upload のバイト数
+    pub bytes: Distribution,
+    // 通信量のうち upload の割合
+    pub upload_ratio: f64,
+    // ping の RTT（ミリ秒）
+    pub rtt_ms: Distribution,
+    // ping が応答しない確率
+    pub loss: f64,
+    // Prometheus のサンプルの経過秒数（throughput-dump の input_age_seconds 用）
+    pub sample_age_secs: Distribution,
+    rng: Mutex<fastrand::Rng>,
+}
+
+impl Scenario {
+    pub fn from_env() -> Self {
+        let devices = env_parse("SIMULATE_DEVICES", 8usize).clamp(1, 240);
+        let remotes = env_parse("SIMULATE_REMOTES", 20usize).clamp(1, 500);
+        let mut interfaces: Vec<String> = shared_config::var("SIMULATE_INTERFACES")
+            .unwrap_or_else(|_| "eth0,eth1".to_string())
+            .split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .take(2)
+            .collect();
+        if interfaces.is_empty() {
+            interfaces.push("eth0".to_string());
+        }
+
+        // 端末は LOCAL_CIDRS の既定（10.40.0.0/20）に含まれるアドレス、
+        // リモートは文書用のアドレス（TEST-NET-2 / TEST-NET-3）を使う
+        let pairs = (0..remotes)
+            .map(|i| {
+                let device = i % devices;
+                let local_ip = IpAddr::V4(Ipv4Addr::new(10, 40, 0, 10 + device as u8));
+                let remote_ip = if i < 250 {
+                    IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1 + i as u8))
+                } else {
+                    IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1 + (i - 250) as u8))
+                };
+                Pair {
+                    local_ip,
+                    remote_ip,
+                    interface: interfaces[device % interfaces.len()].clone(),
+                }
+            })
+            .collect();
+
+        let rng = match shared_config::var("SIMULATE_SEED")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+        {
+            Some(seed) => fastrand::Rng::with_seed(seed),
+            None => fastrand::Rng::new(),
+        };
+
+        Self {
+            interfaces,
+            pairs,
+            bytes: Distribution::from_env(
+                "SIMULATE_BYTES",
+                Distribution::LogNormal {
+                    median: 20_000.0,
+                    sigma: 1.5,
+                },
+            ),
+            upload_ratio: env_parse("SIMULATE_UPLOAD_RATIO", 0.1f64).clamp(0.0, 1.0),
+            rtt_ms: Distribution::from_env(
+                "SIMULATE_RTT_MS",
+                Distribution::Normal {
+                    mean: 25.0,
+                    stddev: 5.0,
+                },
+            ),
+            loss: env_parse("SIMULATE_LOSS", 0.01f64).clamp(0.0, 1.0),
+            sample_age_secs: Distribution::from_env(
+                "SIMULATE_SAMPLE_AGE_SECS",
+                Distribution::Uniform { min: 0.0, max: 2.0 },
+            ),
+            rng: Mutex::new(rng),
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "{} remotes over {:?}, bytes={}, rtt_ms={}, loss={}",
+            self.pairs.len(),
+            self.interfaces,
+            self.bytes,
+            self.rtt_ms,
+            self.loss
+        )
+    }
+
+    // シナリオの乱数で任意の分布から取り出す（コンポーネント固有の値用）
+    pub fn sample(&self, distribution: &Distribution) -> f64 {
+        distribution.sample(&mut self.rng.lock().unwrap())
+    }
+
+    // ping 1 回分の RTT（ミリ秒）。ロスした場合は None
+    pub fn sample_rtt(&self) -> Option<f64> {
+        let mut rng = self.rng.lock().unwrap();
+        if rng.f64() < self.loss {
+            return None;
+        }
+        Some(self.rtt_ms.sample(&mut rng))
+    }
+
+    pub fn sample_traffic(&self) -> Traffic {
+        let mut rng = self.rng.lock().unwrap();
+        let total = self.bytes.sample(&mut rng);
+        let upload = total * self.upload_ratio;
+        Traffic {
+            download_bytes: (total - upload) as u64,
+            upload_bytes: upload as u64,
+        }
+    }
+
+    // NextRouter ステータス API のレスポンス（端末を wan0 / wan1 に交互に割り当てる）
+    pub fn status_json(&self) -> String {
+        let mut mappings = serde_json::Map::new();
+        for pair in &self.pairs {
+            let wan = if pair.interface == self.interfaces[0] {
+                "wan0"
+            } else {
+                "wan1"
+            };
+            mappings.insert(pair.local_ip.to_string(), json!(wan));
+        }
+        json!({
+            "schema_version": 1,
+            "config": {
+                "lan": "lan0",
+                "wan0": self.interfaces[0],
+                "wan1": self.interfaces.get(1).unwrap_or(&self.interfaces[0]),
+            },
+            "mappings": mappings,
+        })
+        .to_string()
+    }
+
+    // Prometheus の /api/v1/query の応答。クエリに含まれるメトリクス名
+    // （rtt_icmp_dump / download_bytes / upload_bytes / icmp_loss_ratio）の系列を返し、名前を含まない
+    // セレクター（icmp-traffic-scan のジョブ指定など）には download_bytes と upload_bytes を返す。
+    // timestamp(...) にはサンプル時刻を値として返す。window_sequence / window_closed_timestamp_seconds
+    // （名前を含まないセレクターにも含める）は、サンプルの経過秒数だけ前に確定したウィンドウとして返す
+    pub fn prometheus_query(&self, query: &str) -> String {
+        const NAMES: [&str; 4] = [
+            "rtt_icmp_dump",
+            "download_bytes",
+            "upload_bytes",
+            "icmp_loss_ratio",
+        ];
+        const WINDOW_NAMES: [&str; 2] = ["window_sequence", "window_closed_timestamp_seconds"];
+        let now = unix_now();
+        let timestamps = query.trim_start().starts_with("timestamp(");
+        let mut names: Vec<&str> = NAMES.into_iter().filter(|n| query.contains(n)).collect();
+        let mut window_names: Vec<&str> = WINDOW_NAMES
+            .into_iter()
+            .filter(|n| query.contains(n))
+            .collect();
+        if names.is_empty() && window_names.is_empty() {
+            names = vec!["download_bytes", "upload_bytes"];
+            window_names = WINDOW_NAMES.to_vec();
+        }
+
+        let mut result = Vec::new();
+        if !timestamps && !window_names.is_empty() {
+            let closed = {
+                let mut rng = self.rng.lock().unwrap();
+                now - self.sample_age_secs.sample(&mut rng)
+            };
+            for name in window_names {
+                let value = match name {
+                    "window_sequence" => closed.floor(),
+                    _ => closed,
+                };
+                result.push(json!({
+                    "metric": { "__name__": name, "job": "localpacketdump-rs" },
+                    "value": [now, value.to_string()],
+                }));
+            }
+        }
+        for pair in &self.pairs {
+            let traffic = self.sample_traffic();
+            for name in &names {
+                let value = if timestamps {
+                    let mut rng = self.rng.lock().unwrap();
+                    now - self.sample_age_secs.sample(&mut rng)
+                } else {
+                    match *name {
+                        "download_bytes" => traffic.download_bytes as f64,
+                        "upload_bytes" => traffic.upload_bytes as f64,
+                        "icmp_loss_ratio" => self.loss,
+                        // ロスした周期も前回の値が残っている想定で系列は返す
+                        _ => self.sample_rtt().unwrap_or_else(|| self.rtt_ms.mean()),
+                    }
+                };
+                let mut metric = json!({
+                    "__name__": name,
+                    "job": if matches!(*name, "rtt_icmp_dump" | "icmp_loss_ratio") { "icmp-traffic-scan" } else { "localpacketdump-rs" },
+                    "remote_ip": pair.remote_ip.to_string(),
+                    "interface": pair.interface,
+                });
+                if *name == "rtt_icmp_dump" {
+                    metric["data_type"] = json!("download");
+                } else if *name != "icmp_loss_ratio" {
+                    // localPacketDump-rs の PERSPECTIVE=both と同じく端末ごとの系列にする
+                    metric["local_ip"] = json!(pair.local_ip.to_string());
+                }
+                if timestamps {
+                    // timestamp() はメトリクス名を落とす
+                    metric.as_object_mut().unwrap().remove("__name__");
+                }
+                result.push(json!({ "metric": metric, "value": [now, value.to_string()] }));
+            }
+        }
+
+        json!({
+            "status": "success",
+            "data": { "resultType": "vector", "result": Value::Array(result) },
+        })
+        .to_string()
+    }
+
+    // /api/v1/query_range の応答。step ごとに prometheus_query と同じ値を合成し、系列ごとにまとめる
+    pub fn prometheus_query_range(&self, query: &str, start: f64, end: f64, step: f64) -> String {
+        let step = if step > 0.0 { step } else { 1.0 };
+        let mut series: Vec<(Value, Vec<Value>)> = Vec::new();
+        let mut at = start;
+        while at <= end {
+            let instant: Value =
+                serde_json::from_str(&self.prometheus_query(query)).unwrap_or(Value::Null);
+            let results = instant["data"]["result"]
+                .as_array()
+                .cloned()
+                .unwrap_or_default();
+            for result in results {
+                let point = json!([at, result["value"][1].clone()]);
+                match series
+                    .iter_mut()
+                    .find(|(metric, _)| *metric == result["metric"])
+                {
+                    Some((_, values)) => values.push(point),
+                    None => series.push((result["metric"].clone(), vec![point])),
+                }
+            }
+            at += step;
+        }
+        let result: Vec<Value> = series
+            .into_iter()
+            .map(|(metric, values)| json!({ "metric": metric, "values": values }))
+            .collect();
+        json!({
+            "status": "success",
+            "data": { "resultType": "matrix", "result": result },
+        })
+        .to_string()
+    }
+}
+
+fn unix_now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or(0.0)
+}
+
+fn env_parse<T: std::str::FromStr>(name: &str, default: T) -> T {
+    shared_config::var(name)
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(default)
+}
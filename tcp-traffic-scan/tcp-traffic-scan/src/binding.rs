@@ -0,0 +1,235 @@
+// How measurement sockets follow the routing path of an interface.
+//
+// Binding to the interface by name (SO_BINDTODEVICE) is the default. On routers using
+// policy routing, production traffic is steered by VRFs or fwmark rules instead, so the
+// socket can be bound to the VRF device, bound by interface index, and marked.
+// macOS has no SO_BINDTODEVICE; sockets are bound with IP_BOUND_IF / IPV6_BOUND_IF there,
+// and to the interface's address on other BSDs or when that fails.
+
+use socket2::Socket;
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+#[derive(Debug, Clone)]
+pub struct Binding {
+    pub interface: String,
+    // Bind to this VRF device instead, so route lookups use the VRF's table
+    pub vrf: Option<String>,
+    // Bind by interface index (SO_BINDTOIFINDEX) instead of by name
+    pub by_index: bool,
+    // SO_MARK value so fwmark-based ip rules select the same table as marked traffic
+    pub fwmark: Option<u32>,
+}
+
+impl Binding {
+    // `ipv6` is the address family of the socket, needed where binding is per family
+    pub fn apply(&self, socket: &Socket, ipv6: bool) -> io::Result<()> {
+        let device = self.vrf.as_deref().unwrap_or(&self.interface);
+        if self.by_index {
+            bind_to_ifindex(socket, device)?;
+        } else {
+            bind_to_device(socket, device, ipv6)?;
+        }
+        if let Some(mark) = self.fwmark {
+            set_mark(socket, mark)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn bind_to_device(socket: &Socket, device: &str, _ipv6: bool) -> io::Result<()> {
+    // Requires CAP_NET_RAW or root privileges
+    let name = std::ffi::CString::new(device)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Interface name contains NUL"))?;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            name.as_ptr() as *const libc::c_void,
+            name.as_bytes_with_nul().len() as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+// IP_BOUND_IF scopes route lookups to the interface like SO_BINDTODEVICE and needs no privileges
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn bind_to_device(socket: &Socket, device: &str, ipv6: bool) -> io::Result<()> {
+    let (level, option) = if ipv6 {
+        (libc::IPPROTO_IPV6, libc::IPV6_BOUND_IF)
+    } else {
+        (libc::IPPROTO_IP, libc::IP_BOUND_IF)
+    };
+    let index = if_index(device)? as libc::c_int;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            option,
+            &index as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        return Ok(());
+    }
+    let e = io::Error::last_os_error();
+    bind_to_address(socket, device, ipv6)
+        .map_err(|fallback| io::Error::new(fallback.kind(), format!("{}; {}", e, fallback)))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "ios")))]
+fn bind_to_device(socket: &Socket, device: &str, ipv6: bool) -> io::Result<()> {
+    bind_to_address(socket, device, ipv6)
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn if_index(device: &str) -> io::Result<libc::c_uint> {
+    let name = std::ffi::CString::new(device)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Interface name contains NUL"))?;
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if index == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(index)
+}
+
+// Bind to an address of the interface, so replies come back to it and, with the scoped or
+// source-based routing of the host, the connection leaves through it
+#[cfg(not(target_os = "linux"))]
+fn bind_to_address(socket: &Socket, device: &str, ipv6: bool) -> io::Result<()> {
+    let addr = interface_address(device, ipv6)?;
+    socket.bind(&std::net::SocketAddr::new(addr, 0).into())
+}
+
+// First address of the interface in the family; link-local IPv6 addresses are skipped
+// because they need a scope and cannot reach the internet
+#[cfg(not(target_os = "linux"))]
+fn interface_address(device: &str, ipv6: bool) -> io::Result<std::net::IpAddr> {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    let mut addrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut addrs) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut found = None;
+    let mut cursor = addrs;
+    while let Some(ifa) = unsafe { cursor.as_ref() } {
+        cursor = ifa.ifa_next;
+        if ifa.ifa_addr.is_null() {
+            continue;
+        }
+        let name = unsafe { std::ffi::CStr::from_ptr(ifa.ifa_name) };
+        if name.to_bytes() != device.as_bytes() {
+            continue;
+        }
+        let family = libc::c_int::from(unsafe { (*ifa.ifa_addr).sa_family });
+        let addr = match family {
+            libc::AF_INET if !ipv6 => {
+                let sin = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in) };
+                IpAddr::V4(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)))
+            }
+            libc::AF_INET6 if ipv6 => {
+                let sin6 = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in6) };
+                let addr = Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+                if addr.segments()[0] & 0xffc0 == 0xfe80 {
+                    continue;
+                }
+                IpAddr::V6(addr)
+            }
+            _ => continue,
+        };
+        found = Some(addr);
+        break;
+    }
+    unsafe { libc::freeifaddrs(addrs) };
+    found.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "no {} address on {}",
+                if ipv6 { "IPv6" } else { "IPv4" },
+                device
+            ),
+        )
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn bind_to_ifindex(socket: &Socket, device: &str) -> io::Result<()> {
+    let name = std::ffi::CString::new(device)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Interface name contains NUL"))?;
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if index == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    setsockopt_int(socket, libc::SO_BINDTOIFINDEX, index as libc::c_int)
+}
+
+#[cfg(target_os = "linux")]
+fn set_mark(socket: &Socket, mark: u32) -> io::Result<()> {
+    // Requires CAP_NET_ADMIN
+    setsockopt_int(socket, libc::SO_MARK, mark as libc::c_int)
+}
+
+#[cfg(target_os = "linux")]
+fn setsockopt_int(socket: &Socket, option: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            option,
+            &value as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_to_ifindex(_socket: &Socket, _device: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "SO_BINDTOIFINDEX is only supported on Linux",
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_mark(_socket: &Socket, _mark: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "SO_MARK is only supported on Linux",
+    ))
+}
+
+pub fn parse_fwmark(s: &str) -> Result<(String, u32), String> {
+    let (interface, mark) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected IFACE=MARK, got '{}'", s))?;
+    let parsed = match mark.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => mark.parse::<u32>(),
+    };
+    let mark = parsed.map_err(|_| format!("invalid fwmark '{}' for {}", mark, interface))?;
+    Ok((interface.to_string(), mark))
+}
+
+pub fn parse_vrf(s: &str) -> Result<(String, String), String> {
+    let (interface, vrf) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected IFACE=VRF, got '{}'", s))?;
+    if vrf.is_empty() {
+        return Err(format!("empty VRF name for {}", interface));
+    }
+    Ok((interface.to_string(), vrf.to_string()))
+}
@@ -0,0 +1,72 @@
+// Build information endpoint.
+//
+// The other components expose build_info on their metrics servers. This tool has no
+// metrics server, so --buildinfo-listen starts a minimal one serving only /metrics
+// (build_info) and /buildinfo, letting Prometheus see version skew here as well.
+// It stays on std rather than traffic-scan-core's MetricsServer: the measurements use
+// blocking sockets and this tool has no async runtime to pull in for three endpoints.
+// /summary returns the --summary-json document for the cycles so far, so a long run can
+// be checked without stopping it.
+
+use crate::history::{History, Thresholds};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Bind and answer requests on a background thread
+pub fn serve(
+    addr: SocketAddr,
+    history: Arc<Mutex<History>>,
+    thresholds: Thresholds,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    std::thread::Builder::new()
+        .name("buildinfo".to_string())
+        .spawn(move || {
+            for stream in listener.incoming().flatten() {
+                if let Err(e) = handle(stream, &history, &thresholds) {
+                    eprintln!("Error serving build info: {}", e);
+                }
+            }
+        })?;
+    Ok(())
+}
+
+fn handle(stream: TcpStream, history: &Mutex<History>, thresholds: &Thresholds) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Drain the headers so the client sees a clean close
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let info = shared_schema::build_info!();
+    let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+    let (status, content_type, body) = match path {
+        "/buildinfo" => ("200 OK", "application/json", info.to_json()),
+        "/metrics" => (
+            "200 OK",
+            "text/plain; version=0.0.4",
+            info.prometheus_text(),
+        ),
+        "/summary" => {
+            let (summary, _) = history.lock().unwrap().json_summary(thresholds);
+            ("200 OK", "application/json", summary.to_string())
+        }
+        _ => ("404 Not Found", "text/plain", "not found\n".to_string()),
+    };
+
+    let mut stream = &stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
@@ -0,0 +1,169 @@
+// Target auto-discovery from localPacketDump-rs.
+//
+// Fixed server lists drift away from what the network actually talks to. This pulls the
+// last traffic window from localPacketDump-rs (/window.json) and picks the busiest remote
+// IPs per interface, so the measured set follows real traffic. Only plain HTTP is
+// supported since the API is local.
+
+use serde_json::Value;
+use shared_schema::{
+    LABEL_INTERFACE, LABEL_REMOTE_IP, SCHEMA_VERSION_HEADER, WINDOW_SCHEMA_VERSION,
+};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+pub struct Discovery {
+    url: String,
+    // Busiest remotes kept per interface
+    top: usize,
+    // Port the discovered remotes are measured on
+    port: u16,
+    // Interface label in the window for each measured interface, when the names differ
+    labels: Vec<(String, String)>,
+}
+
+impl Discovery {
+    pub fn new(url: String, top: usize, port: u16, labels: Vec<(String, String)>) -> Self {
+        Self {
+            url,
+            top,
+            port,
+            labels,
+        }
+    }
+
+    // Fetch the last window and return "ip:port" targets per measured interface
+    pub fn fetch(&self, interfaces: &[String]) -> io::Result<HashMap<String, Vec<String>>> {
+        let body = http_get(&self.url)?;
+        let window: Value = serde_json::from_slice(&body)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let entries = window["entries"]
+            .as_array()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "window has no entries"))?;
+
+        // Interface label -> remote IP -> bytes in the window
+        let mut bytes: HashMap<&str, HashMap<IpAddr, u64>> = HashMap::new();
+        for entry in entries {
+            let (Some(remote_ip), Some(label)) = (
+                entry[LABEL_REMOTE_IP]
+                    .as_str()
+                    .and_then(|ip| ip.parse::<IpAddr>().ok()),
+                entry[LABEL_INTERFACE].as_str(),
+            ) else {
+                continue;
+            };
+            let total = entry["download_bytes"].as_u64().unwrap_or(0)
+                + entry["upload_bytes"].as_u64().unwrap_or(0);
+            *bytes
+                .entry(label)
+                .or_default()
+                .entry(remote_ip)
+                .or_insert(0) += total;
+        }
+
+        let mut targets = HashMap::new();
+        for interface in interfaces {
+            let label = self
+                .labels
+                .iter()
+                .find(|(i, _)| i == interface)
+                .map_or(interface.as_str(), |(_, label)| label.as_str());
+            let mut remotes: Vec<(IpAddr, u64)> = bytes
+                .get(label)
+                .map(|remotes| remotes.iter().map(|(ip, b)| (*ip, *b)).collect())
+                .unwrap_or_default();
+            remotes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+            targets.insert(
+                interface.clone(),
+                remotes
+                    .into_iter()
+                    .take(self.top)
+                    .map(|(ip, _)| SocketAddr::new(ip, self.port).to_string())
+                    .collect(),
+            );
+        }
+        Ok(targets)
+    }
+}
+
+pub fn parse_label(s: &str) -> Result<(String, String), String> {
+    let (interface, label) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected IFACE=LABEL, got '{}'", s))?;
+    if label.is_empty() {
+        return Err(format!("empty interface label for {}", interface));
+    }
+    Ok((interface.to_string(), label.to_string()))
+}
+
+// Minimal HTTP/1.1 GET returning the body of a 200 response
+fn http_get(url: &str) -> io::Result<Vec<u8>> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| invalid(format!("only http:// URLs are supported: {}", url)))?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let addr_str = if authority
+        .rsplit_once(':')
+        .is_some_and(|(_, port)| !port.ends_with(']'))
+    {
+        authority.to_string()
+    } else {
+        format!("{}:80", authority)
+    };
+    let addr = addr_str
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| invalid(format!("could not resolve {}", authority)))?;
+
+    let mut stream = TcpStream::connect_timeout(&addr, Duration::from_secs(2))?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\n{}: {}\r\nConnection: close\r\n\r\n",
+        path, authority, SCHEMA_VERSION_HEADER, WINDOW_SCHEMA_VERSION
+    );
+    stream.write_all(request.as_bytes())?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+
+    let header_end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "truncated HTTP response"))?;
+    let headers = String::from_utf8_lossy(&response[..header_end]).to_ascii_lowercase();
+    let body = response[header_end + 4..].to_vec();
+
+    let status = headers.lines().next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(io::Error::other(format!("{} returned '{}'", url, status)));
+    }
+    if headers.contains("transfer-encoding: chunked") {
+        return decode_chunked(&body);
+    }
+    Ok(body)
+}
+
+fn decode_chunked(mut data: &[u8]) -> io::Result<Vec<u8>> {
+    let bad = || io::Error::new(io::ErrorKind::InvalidData, "malformed chunked body");
+    let mut body = Vec::new();
+    loop {
+        let line_end = data.windows(2).position(|w| w == b"\r\n").ok_or_else(bad)?;
+        let size_str = std::str::from_utf8(&data[..line_end]).map_err(|_| bad())?;
+        let size = usize::from_str_radix(size_str.split(';').next().unwrap_or("").trim(), 16)
+            .map_err(|_| bad())?;
+        data = &data[line_end + 2..];
+        if size == 0 {
+            return Ok(body);
+        }
+        if data.len() < size + 2 {
+            return Err(bad());
+        }
+        body.extend_from_slice(&data[..size]);
+        data = &data[size + 2..];
+    }
+}
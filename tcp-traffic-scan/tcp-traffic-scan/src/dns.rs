@@ -0,0 +1,145 @@
+// Minimal DNS stub resolver used to resolve targets through a specific resolver and interface.
+//
+// The system resolver always follows the default route, so DNS-based geo steering would be
+// measured from the wrong WAN. This sends a single A (or AAAA) query over UDP from a socket
+// bound to the measured interface.
+
+use crate::binding::Binding;
+use socket2::{Domain, Socket, Type};
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+/// Resolve `host` by querying `resolver` from a socket bound like the measurement sockets.
+/// Tries an A record first and falls back to AAAA.
+pub fn resolve_via(host: &str, resolver: IpAddr, binding: &Binding) -> io::Result<IpAddr> {
+    match query(host, TYPE_A, resolver, binding)? {
+        Some(addr) => Ok(addr),
+        None => query(host, TYPE_AAAA, resolver, binding)?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} has no A/AAAA record at {}", host, resolver),
+            )
+        }),
+    }
+}
+
+fn query(
+    host: &str,
+    qtype: u16,
+    resolver: IpAddr,
+    binding: &Binding,
+) -> io::Result<Option<IpAddr>> {
+    let domain = if resolver.is_ipv4() {
+        Domain::IPV4
+    } else {
+        Domain::IPV6
+    };
+    let socket = Socket::new(domain, Type::DGRAM, None)?;
+    if let Err(e) = binding.apply(&socket, resolver.is_ipv6()) {
+        eprintln!(
+            "Warning: Failed to bind DNS query to device '{}'. Error: {}",
+            binding.interface, e
+        );
+    }
+    socket.set_read_timeout(Some(Duration::from_secs(2)))?;
+    socket.connect(&SocketAddr::new(resolver, 53).into())?;
+
+    // Not security sensitive; only used to match the response to the query
+    let id = (SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0)
+        & 0xffff) as u16;
+    socket.send(&build_query(id, host, qtype)?)?;
+
+    let mut buf = [0u8; 1500];
+    let len = (&socket).read(&mut buf)?;
+
+    parse_response(&buf[..len], id, qtype)
+}
+
+fn build_query(id: u16, host: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    let mut packet = Vec::with_capacity(512);
+    packet.extend_from_slice(&id.to_be_bytes());
+    packet.extend_from_slice(&0x0100u16.to_be_bytes()); // recursion desired
+    packet.extend_from_slice(&1u16.to_be_bytes()); // QDCOUNT
+    packet.extend_from_slice(&[0; 6]); // ANCOUNT, NSCOUNT, ARCOUNT
+
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid host name '{}'", host),
+            ));
+        }
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend_from_slice(&qtype.to_be_bytes());
+    packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(packet)
+}
+
+fn parse_response(packet: &[u8], id: u16, qtype: u16) -> io::Result<Option<IpAddr>> {
+    let malformed = || io::Error::new(io::ErrorKind::InvalidData, "malformed DNS response");
+
+    if packet.len() < 12 || u16::from_be_bytes([packet[0], packet[1]]) != id {
+        return Err(malformed());
+    }
+    let rcode = packet[3] & 0x0f;
+    if rcode != 0 {
+        return Err(io::Error::other(format!("DNS error rcode {}", rcode)));
+    }
+    let qdcount = u16::from_be_bytes([packet[4], packet[5]]);
+    let ancount = u16::from_be_bytes([packet[6], packet[7]]);
+
+    let mut pos = 12;
+    for _ in 0..qdcount {
+        pos = skip_name(packet, pos).ok_or_else(malformed)? + 4;
+    }
+
+    for _ in 0..ancount {
+        pos = skip_name(packet, pos).ok_or_else(malformed)?;
+        let header = packet.get(pos..pos + 10).ok_or_else(malformed)?;
+        let rtype = u16::from_be_bytes([header[0], header[1]]);
+        let rdlength = u16::from_be_bytes([header[8], header[9]]) as usize;
+        pos += 10;
+        let rdata = packet.get(pos..pos + rdlength).ok_or_else(malformed)?;
+        pos += rdlength;
+
+        // CNAME records are skipped; the resolver appends the final A/AAAA
+        match (rtype, rdlength) {
+            (TYPE_A, 4) if qtype == TYPE_A => {
+                return Ok(Some(IpAddr::V4(Ipv4Addr::new(
+                    rdata[0], rdata[1], rdata[2], rdata[3],
+                ))));
+            }
+            (TYPE_AAAA, 16) if qtype == TYPE_AAAA => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(rdata);
+                return Ok(Some(IpAddr::V6(Ipv6Addr::from(octets))));
+            }
+            _ => {}
+        }
+    }
+
+    Ok(None)
+}
+
+// Returns the position right after the (possibly compressed) name starting at `pos`
+fn skip_name(packet: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *packet.get(pos)?;
+        match len {
+            0 => return Some(pos + 1),
+            l if l & 0xc0 == 0xc0 => return Some(pos + 2),
+            l => pos += 1 + l as usize,
+        }
+    }
+}
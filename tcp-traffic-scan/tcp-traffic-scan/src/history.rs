@@ -0,0 +1,259 @@
+// In-memory history of the last N measurement cycles, used for the --summary report
+// and the machine-readable exit summary (--summary-json).
+
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, VecDeque};
+
+// Relative change between the older and newer half of the window reported as a trend
+const TREND_THRESHOLD: f64 = 0.10;
+
+struct Sample {
+    interface: String,
+    target: String,
+    // Both None when the measurement failed
+    rtt_ms: Option<f64>,
+    throughput_mbps: Option<f64>,
+}
+
+// Pass/fail limits for the exit summary; a target/interface failing any of them fails the run
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Thresholds {
+    pub min_availability_pct: Option<f64>,
+    pub max_median_rtt_ms: Option<f64>,
+    pub min_throughput_mbps: Option<f64>,
+}
+
+impl Thresholds {
+    // Reasons the samples of one target/interface fail, empty when they pass
+    fn check(&self, stats: &Stats) -> Vec<String> {
+        let mut failures = Vec::new();
+        if let Some(min) = self.min_availability_pct {
+            if stats.availability_pct < min {
+                failures.push(format!(
+                    "availability {:.1}% below {}%",
+                    stats.availability_pct, min
+                ));
+            }
+        }
+        if let Some(max) = self.max_median_rtt_ms {
+            match stats.median_rtt_ms {
+                Some(rtt) if rtt > max => {
+                    failures.push(format!("median RTT {:.1}ms above {}ms", rtt, max))
+                }
+                Some(_) => {}
+                None => failures.push("no successful measurement".to_string()),
+            }
+        }
+        if let Some(min) = self.min_throughput_mbps {
+            match stats.median_throughput_mbps {
+                Some(mbps) if mbps < min => failures.push(format!(
+                    "median throughput {:.0}Mbps below {}Mbps",
+                    mbps, min
+                )),
+                Some(_) => {}
+                None => failures.push("no successful measurement".to_string()),
+            }
+        }
+        failures.dedup();
+        failures
+    }
+}
+
+// Figures of one target on one interface over the kept cycles
+struct Stats {
+    samples: usize,
+    availability_pct: f64,
+    median_rtt_ms: Option<f64>,
+    avg_throughput_mbps: Option<f64>,
+    median_throughput_mbps: Option<f64>,
+    trend: String,
+}
+
+impl Stats {
+    fn new(samples: &[&Sample]) -> Self {
+        let rtts: Vec<f64> = samples.iter().filter_map(|s| s.rtt_ms).collect();
+        let throughputs: Vec<f64> = samples.iter().filter_map(|s| s.throughput_mbps).collect();
+        // --mode handshake records no throughput, so a sample with either figure counts
+        let reached = samples
+            .iter()
+            .filter(|s| s.rtt_ms.is_some() || s.throughput_mbps.is_some())
+            .count();
+        Self {
+            samples: samples.len(),
+            availability_pct: reached as f64 / samples.len() as f64 * 100.0,
+            median_rtt_ms: median(&rtts),
+            avg_throughput_mbps: mean(&throughputs),
+            median_throughput_mbps: median(&throughputs),
+            trend: trend(&throughputs),
+        }
+    }
+}
+
+pub struct History {
+    capacity: usize,
+    cycles: VecDeque<Vec<Sample>>,
+    current: Vec<Sample>,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            cycles: VecDeque::new(),
+            current: Vec::new(),
+        }
+    }
+
+    pub fn record(
+        &mut self,
+        interface: &str,
+        target: &str,
+        rtt_ms: Option<f64>,
+        throughput_mbps: Option<f64>,
+    ) {
+        self.current.push(Sample {
+            interface: interface.to_string(),
+            target: target.to_string(),
+            rtt_ms,
+            throughput_mbps,
+        });
+    }
+
+    // Close the current cycle, dropping the oldest one once the ring is full
+    pub fn finish_cycle(&mut self) {
+        if self.cycles.len() == self.capacity {
+            self.cycles.pop_front();
+        }
+        self.cycles.push_back(std::mem::take(&mut self.current));
+    }
+
+    // target -> interface -> samples in chronological order
+    fn by_target(&self) -> BTreeMap<&str, BTreeMap<&str, Vec<&Sample>>> {
+        let mut by_target: BTreeMap<&str, BTreeMap<&str, Vec<&Sample>>> = BTreeMap::new();
+        for sample in self.cycles.iter().flatten() {
+            by_target
+                .entry(&sample.target)
+                .or_default()
+                .entry(&sample.interface)
+                .or_default()
+                .push(sample);
+        }
+        by_target
+    }
+
+    pub fn print_summary(&self) {
+        println!(
+            "========== Summary (last {} cycles) ==========",
+            self.cycles.len()
+        );
+
+        for (target, interfaces) in &self.by_target() {
+            println!("{}:", target);
+            let mut averages = Vec::new();
+
+            for (interface, samples) in interfaces {
+                let stats = Stats::new(samples);
+                match stats.avg_throughput_mbps {
+                    Some(avg) => {
+                        println!(
+                            "  {}: avail {:.1}% avg {:.0}Mbps trend {}",
+                            interface, stats.availability_pct, avg, stats.trend
+                        );
+                        averages.push((*interface, avg));
+                    }
+                    None => println!(
+                        "  {}: avail {:.1}% avg N/A",
+                        interface, stats.availability_pct
+                    ),
+                }
+            }
+
+            let best = averages.iter().max_by(|a, b| a.1.total_cmp(&b.1));
+            let worst = averages.iter().min_by(|a, b| a.1.total_cmp(&b.1));
+            if let (Some(best), Some(worst)) = (best, worst) {
+                println!("  best: {}, worst: {}", best.0, worst.0);
+            }
+        }
+    }
+
+    // Summary as JSON and whether every target/interface met the thresholds
+    pub fn json_summary(&self, thresholds: &Thresholds) -> (Value, bool) {
+        let mut results = Vec::new();
+        let mut pass = true;
+        for (target, interfaces) in &self.by_target() {
+            for (interface, samples) in interfaces {
+                let stats = Stats::new(samples);
+                let failures = thresholds.check(&stats);
+                pass &= failures.is_empty();
+                results.push(json!({
+                    "target": target,
+                    "interface": interface,
+                    "samples": stats.samples,
+                    "availability_pct": stats.availability_pct,
+                    "median_rtt_ms": stats.median_rtt_ms,
+                    "avg_throughput_mbps": stats.avg_throughput_mbps,
+                    "median_throughput_mbps": stats.median_throughput_mbps,
+                    "trend": stats.trend,
+                    "pass": failures.is_empty(),
+                    "failures": failures,
+                }));
+            }
+        }
+        let summary = json!({
+            "cycles": self.cycles.len(),
+            "thresholds": {
+                "min_availability_pct": thresholds.min_availability_pct,
+                "max_median_rtt_ms": thresholds.max_median_rtt_ms,
+                "min_throughput_mbps": thresholds.min_throughput_mbps,
+            },
+            "pass": pass,
+            "results": results,
+        });
+        (summary, pass)
+    }
+}
+
+fn mean(values: &[f64]) -> Option<f64> {
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.iter().sum::<f64>() / values.len() as f64)
+    }
+}
+
+fn median(values: &[f64]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let mid = sorted.len() / 2;
+    Some(if sorted.len().is_multiple_of(2) {
+        (sorted[mid - 1] + sorted[mid]) / 2.0
+    } else {
+        sorted[mid]
+    })
+}
+
+// Compare the older half of the samples with the newer half
+fn trend(values: &[f64]) -> String {
+    if values.len() < 4 {
+        return "n/a".to_string();
+    }
+    let (older, newer) = values.split_at(values.len() / 2);
+    let (Some(older), Some(newer)) = (mean(older), mean(newer)) else {
+        return "n/a".to_string();
+    };
+    if older <= 0.0 {
+        return "n/a".to_string();
+    }
+
+    let change = (newer - older) / older;
+    if change > TREND_THRESHOLD {
+        format!("up (+{:.0}%)", change * 100.0)
+    } else if change < -TREND_THRESHOLD {
+        format!("down ({:.0}%)", change * 100.0)
+    } else {
+        "flat".to_string()
+    }
+}
@@ -0,0 +1,403 @@
+// HTTPS measurement modes (--mode handshake, --mode download-probe).
+//
+// The default estimate divides the receive window by the handshake RTT, which says little
+// about what a WAN can actually carry or where its latency comes from. The handshake mode
+// times the TCP connect, the TLS handshake and the first byte of an HTTP response
+// separately; the download probe fetches a fixed number of bytes from a URL and reports
+// the goodput. Both run over sockets bound like every other measurement.
+
+use crate::binding::Binding;
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{ClientConfig, ClientConnection, OwnedTrustAnchor, RootCertStore, ServerName};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+// Response headers larger than this are treated as a broken server
+pub const MAX_HEADER_BYTES: usize = 16 * 1024;
+
+// TLS client configuration shared by all measurements. With `insecure` the certificate is
+// not verified, for targets given by IP address or with self-signed certificates.
+pub fn client_config(insecure: bool) -> Arc<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            anchor.subject,
+            anchor.spki,
+            anchor.name_constraints,
+        )
+    }));
+    let mut config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    if insecure {
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(AcceptAnyCertificate));
+    }
+    Arc::new(config)
+}
+
+struct AcceptAnyCertificate;
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+// URL of the download probe (https:// or, for testing, plain http://)
+#[derive(Debug, Clone)]
+pub struct ProbeUrl {
+    pub tls: bool,
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl ProbeUrl {
+    // "host:port" (or "[v6]:port") for resolving, like a -s/--server entry
+    pub fn server(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+impl std::fmt::Display for ProbeUrl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let scheme = if self.tls { "https" } else { "http" };
+        write!(f, "{}://{}{}", scheme, self.server(), self.path)
+    }
+}
+
+pub fn parse_url(s: &str) -> Result<ProbeUrl, String> {
+    let (tls, rest) = if let Some(rest) = s.strip_prefix("https://") {
+        (true, rest)
+    } else if let Some(rest) = s.strip_prefix("http://") {
+        (false, rest)
+    } else {
+        return Err(format!("expected an https:// URL, got '{}'", s));
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => (&rest[..slash], &rest[slash..]),
+        None => (rest, "/"),
+    };
+    // [2001:db8::1]:8443 for IPv6 literals
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(bracketed) => {
+            let (host, rest) = bracketed
+                .split_once(']')
+                .ok_or_else(|| format!("missing ']' in '{}'", s))?;
+            (host, rest.strip_prefix(':'))
+        }
+        None => match authority.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        },
+    };
+    let port = match port {
+        Some(port) => port
+            .parse()
+            .map_err(|_| format!("invalid port '{}' in '{}'", port, s))?,
+        None if tls => 443,
+        None => 80,
+    };
+    if host.is_empty() {
+        return Err(format!("missing host in '{}'", s));
+    }
+    Ok(ProbeUrl {
+        tls,
+        host: host.to_string(),
+        port,
+        path: path.to_string(),
+    })
+}
+
+// --probe-url URL for every interface, or IFACE=URL for one of them
+pub fn parse_probe_url(s: &str) -> Result<(Option<String>, ProbeUrl), String> {
+    match s.split_once('=') {
+        Some((interface, url)) if !interface.contains(':') && !interface.contains('/') => {
+            Ok((Some(interface.to_string()), parse_url(url)?))
+        }
+        _ => Ok((None, parse_url(s)?)),
+    }
+}
+
+// Time of each phase of an HTTPS request (--mode handshake)
+pub struct Timings {
+    pub connect: Duration,
+    pub tls: Duration,
+    // From sending the request to the first byte of the response
+    pub ttfb: Duration,
+}
+
+impl Timings {
+    pub fn format(&self, addr: SocketAddr) -> String {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        format!(
+            "{}:connect={:.1}ms,tls={:.1}ms,ttfb={:.1}ms",
+            addr.ip(),
+            ms(self.connect),
+            ms(self.tls),
+            ms(self.ttfb)
+        )
+    }
+}
+
+// Result of one download probe
+pub struct Download {
+    pub connect: Duration,
+    pub bytes: u64,
+    // From sending the request to the last byte counted
+    pub elapsed: Duration,
+}
+
+impl Download {
+    pub fn mbps(&self) -> f64 {
+        if self.elapsed.as_secs_f64() > 0.0 {
+            self.bytes as f64 * 8.0 / self.elapsed.as_secs_f64() / 1_000_000.0
+        } else {
+            0.0
+        }
+    }
+
+    pub fn format(&self, addr: SocketAddr) -> String {
+        format!(
+            "{}:bytes={},time={:.2}s,goodput={:.0}Mbps",
+            addr.ip(),
+            self.bytes,
+            self.elapsed.as_secs_f64(),
+            self.mbps()
+        )
+    }
+}
+
+// An HTTP or HTTPS connection; the TLS handshake is done by the time it is returned
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<rustls::StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl Stream {
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(stream) => stream,
+            Stream::Tls(stream) => &stream.sock,
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+fn remaining(until: Instant) -> io::Result<Duration> {
+    let remaining = until.saturating_duration_since(Instant::now());
+    if remaining.is_zero() {
+        return Err(io::Error::new(io::ErrorKind::TimedOut, "timed out"));
+    }
+    Ok(remaining)
+}
+
+// Connect on the interface and, with `tls`, complete the handshake for `host`.
+// Returns the stream with the connect and handshake times.
+fn open(
+    binding: &Binding,
+    addr: SocketAddr,
+    host: &str,
+    tls: Option<&Arc<ClientConfig>>,
+    until: Instant,
+) -> io::Result<(Stream, Duration, Duration)> {
+    let (socket, connect) = crate::connect_on_interface(binding, addr, remaining(until)?)?;
+    let mut tcp = TcpStream::from(socket);
+    tcp.set_read_timeout(Some(remaining(until)?))?;
+    tcp.set_write_timeout(Some(remaining(until)?))?;
+    let Some(config) = tls else {
+        return Ok((Stream::Plain(tcp), connect, Duration::ZERO));
+    };
+
+    let name = ServerName::try_from(host)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+    let mut conn = ClientConnection::new(Arc::clone(config), name)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    let start = Instant::now();
+    while conn.is_handshaking() {
+        conn.complete_io(&mut tcp)?;
+    }
+    let handshake = start.elapsed();
+    Ok((
+        Stream::Tls(Box::new(rustls::StreamOwned::new(conn, tcp))),
+        connect,
+        handshake,
+    ))
+}
+
+// Host header value; IPv6 literals are bracketed
+fn host_header(host: &str, port: u16, default_port: u16) -> String {
+    let host = if host.contains(':') {
+        format!("[{}]", host)
+    } else {
+        host.to_string()
+    };
+    if port == default_port {
+        host
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+// Time the TCP connect, the TLS handshake and the first response byte of GET `path`
+pub fn handshake(
+    binding: &Binding,
+    addr: SocketAddr,
+    host: &str,
+    path: &str,
+    config: &Arc<ClientConfig>,
+    until: Instant,
+) -> io::Result<Timings> {
+    let (mut stream, connect, tls) = open(binding, addr, host, Some(config), until)?;
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path,
+        host_header(host, addr.port(), 443)
+    );
+    let start = Instant::now();
+    stream.write_all(request.as_bytes())?;
+    let mut buf = [0u8; 1];
+    if stream.read(&mut buf)? == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "connection closed before the response",
+        ));
+    }
+    Ok(Timings {
+        connect,
+        tls,
+        ttfb: start.elapsed(),
+    })
+}
+
+// Fetch the first `bytes` bytes of `url` (a Range request) and time the body.
+// A stall of `stall_timeout` ends the probe with the bytes received so far.
+pub fn download(
+    binding: &Binding,
+    addr: SocketAddr,
+    url: &ProbeUrl,
+    bytes: u64,
+    config: &Arc<ClientConfig>,
+    stall_timeout: Duration,
+) -> io::Result<Download> {
+    let (mut stream, connect, _) = open(
+        binding,
+        addr,
+        &url.host,
+        url.tls.then_some(config),
+        Instant::now() + stall_timeout,
+    )?;
+    stream.tcp().set_read_timeout(Some(stall_timeout))?;
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nRange: bytes=0-{}\r\nConnection: close\r\n\r\n",
+        url.path,
+        host_header(&url.host, url.port, if url.tls { 443 } else { 80 }),
+        bytes.saturating_sub(1)
+    );
+    let start = Instant::now();
+    stream.write_all(request.as_bytes())?;
+
+    // Status line and headers; body bytes read along with them are counted
+    let mut head = Vec::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut received = loop {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed before the response headers",
+            ));
+        }
+        head.extend_from_slice(&buf[..n]);
+        if let Some(end) = head.windows(4).position(|w| w == b"\r\n\r\n") {
+            check_status(&head[..end])?;
+            break (head.len() - end - 4) as u64;
+        }
+        if head.len() > MAX_HEADER_BYTES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "response headers too large",
+            ));
+        }
+    };
+
+    while received < bytes {
+        match stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => received += n as u64,
+            // A stall ends the probe; what arrived until then still counts
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                break
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    let elapsed = start.elapsed();
+    if received == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "server sent no data",
+        ));
+    }
+    Ok(Download {
+        connect,
+        bytes: received.min(bytes),
+        elapsed,
+    })
+}
+
+// 200 (Range ignored) and 206 carry the body
+pub fn check_status(head: &[u8]) -> io::Result<()> {
+    let head = String::from_utf8_lossy(head);
+    let status_line = head.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1) {
+        Some("200") | Some("206") => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected response: {}", status_line),
+        )),
+    }
+}
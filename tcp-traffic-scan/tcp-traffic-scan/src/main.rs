@@ -1,22 +1,52 @@
-use clap::Parser;
-use libc;
+mod binding;
+mod buildinfo;
+mod discover;
+mod dns;
+mod history;
+mod https;
+mod nat_timeout;
+mod output;
+mod pmtud;
+mod pool;
+mod reflector;
+mod simulate;
+mod transfer;
+
+use binding::Binding;
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches, Parser, ValueEnum};
+use shared_schema::schedule::Schedule;
 use socket2::{Domain, Socket, Type};
-#[cfg(target_os = "linux")]
-use std::ffi::CString;
+use std::collections::HashMap;
+use std::ffi::OsString;
 use std::io;
 use std::io::Write;
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
 use std::os::unix::io::AsRawFd;
-#[cfg(not(target_os = "linux"))]
-use std::sync::Once;
 use std::sync::{
     atomic::{AtomicBool, Ordering},
-    Arc,
+    Arc, Mutex,
 };
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Handshake timeout of NAT timeout discovery
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(
+    author,
+    version,
+    long_version = concat!(
+        env!("CARGO_PKG_VERSION"),
+        " (",
+        env!("BUILD_GIT_HASH"),
+        ", ",
+        env!("BUILD_RUSTC_VERSION"),
+        ")"
+    ),
+    about,
+    long_about = None
+)]
 struct Args {
     /// Network interfaces to use (can specify multiple)
     #[arg(short, long, action = clap::ArgAction::Append)]
@@ -25,21 +55,480 @@ struct Args {
     /// Server IP addresses to measure
     #[arg(short, long, action = clap::ArgAction::Append)]
     server: Vec<String>,
+
+    /// Link capacity per interface in Mbps, e.g. eth0=1000 (enables BDP report)
+    #[arg(short, long, action = clap::ArgAction::Append, value_parser = parse_capacity)]
+    capacity: Vec<(String, f64)>,
+
+    /// Report negotiated TCP options (timestamps, SACK, window scaling, ECN) per target (Linux only)
+    #[arg(long)]
+    probe_options: bool,
+
+    /// Also time a request on the already-connected socket and report it against the cold connect
+    #[arg(long)]
+    compare_reuse: bool,
+
+    /// Check that MSS- and MTU+1-sized writes with DF set are delivered per target (Linux only)
+    #[arg(long)]
+    pmtud: bool,
+
+    /// Seconds each write of the PMTUD check may take to be acknowledged
+    #[arg(long, value_name = "SECS", default_value_t = 3.0, value_parser = parse_interval)]
+    pmtud_timeout: f64,
+
+    /// DNS resolver to use per interface, e.g. eth0=1.1.1.1 (queried through that interface)
+    #[arg(short, long, action = clap::ArgAction::Append, value_parser = parse_resolver)]
+    resolver: Vec<(String, IpAddr)>,
+
+    /// Print trends, best/worst interface per target and availability when the run ends
+    #[arg(long)]
+    summary: bool,
+
+    /// Number of recent cycles kept for the summary (raised to --count when that is larger)
+    #[arg(long, default_value_t = 60)]
+    history: usize,
+
+    /// Stop after this many measurement cycles instead of running until Ctrl+C
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    count: Option<u64>,
+
+    /// Write a JSON summary of the kept cycles when the run ends ("-" for stdout)
+    #[arg(long, value_name = "PATH")]
+    summary_json: Option<String>,
+
+    /// Fail (exit code 1) if a target/interface was reachable in less than this percent of cycles
+    #[arg(long, value_name = "PCT")]
+    min_availability: Option<f64>,
+
+    /// Fail (exit code 1) if a target/interface's median RTT exceeds this many milliseconds
+    #[arg(long, value_name = "MS")]
+    max_median_rtt: Option<f64>,
+
+    /// Fail (exit code 1) if a target/interface's median throughput estimate is below this many Mbps
+    /// (not with --mode handshake)
+    #[arg(long, value_name = "MBPS")]
+    min_throughput: Option<f64>,
+
+    /// Real-transfer mode: download over HTTP from each target for this many seconds
+    #[arg(long, value_name = "SECS", value_parser = parse_interval)]
+    transfer: Option<f64>,
+
+    /// Path requested in real-transfer mode (point it at a large file)
+    #[arg(long, default_value = "/")]
+    transfer_path: String,
+
+    /// Real-transfer mode: upload to each target with a chunked HTTP POST for this many seconds
+    #[arg(long, value_name = "SECS", value_parser = parse_interval)]
+    upload: Option<f64>,
+
+    /// Path the chunked POST is sent to in real-transfer mode
+    #[arg(long, default_value = "/")]
+    upload_path: String,
+
+    /// Seconds of slow start excluded from the steady-state goodput in real-transfer mode
+    #[arg(long, value_name = "SECS", default_value_t = 2.0, value_parser = parse_non_negative)]
+    warmup: f64,
+
+    /// Parallel streams per target in real-transfer mode (like iperf -P)
+    #[arg(short = 'P', long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    parallel: u32,
+
+    /// Bind sockets by interface index (SO_BINDTOIFINDEX) instead of by name (Linux only)
+    #[arg(long)]
+    bind_ifindex: bool,
+
+    /// Bind sockets of an interface to its VRF device, e.g. eth0=vrf-wan0 (Linux only)
+    #[arg(long, action = clap::ArgAction::Append, value_parser = binding::parse_vrf)]
+    vrf: Vec<(String, String)>,
+
+    /// fwmark set on sockets of an interface, e.g. eth0=0x100 (Linux only, needs CAP_NET_ADMIN)
+    #[arg(long, action = clap::ArgAction::Append, value_parser = binding::parse_fwmark)]
+    fwmark: Vec<(String, u32)>,
+
+    /// Interface/server pairs measured at the same time
+    #[arg(long, value_name = "N", default_value_t = 16, value_parser = clap::value_parser!(u64).range(1..))]
+    concurrency: u64,
+
+    /// Seconds one measurement (handshake, reuse request) may take before it counts as an error;
+    /// in download-probe mode, how long the download may stall
+    #[arg(long, value_name = "SECS", default_value_t = 5.0, value_parser = parse_interval)]
+    timeout: f64,
+
+    /// What each target is measured with
+    #[arg(long, value_enum, default_value_t = Mode::Estimate)]
+    mode: Mode,
+
+    /// URL fetched in download-probe mode, for every interface or per interface as IFACE=URL
+    #[arg(long, value_name = "URL", action = clap::ArgAction::Append, value_parser = https::parse_probe_url)]
+    probe_url: Vec<(Option<String>, https::ProbeUrl)>,
+
+    /// Bytes fetched per interface in download-probe mode
+    #[arg(long, value_name = "N", default_value_t = 10_000_000, value_parser = clap::value_parser!(u64).range(1..))]
+    probe_bytes: u64,
+
+    /// Do not verify TLS certificates in handshake and download-probe mode
+    #[arg(long)]
+    insecure: bool,
+
+    /// How results are printed: bars, or one JSON object / CSV row per target and cycle
+    #[arg(long, value_enum, default_value_t = Output::Text)]
+    output: Output,
+
+    /// Seconds between the start of consecutive measurement cycles
+    #[arg(long, value_name = "SECS", default_value_t = 1.0, value_parser = parse_interval)]
+    interval: f64,
+
+    /// Start cycles on wall-clock multiples of the interval shifted by this many seconds,
+    /// e.g. 0.5 to stay clear of icmp-traffic-scan pings at PROBE_PHASE_MS=0; below --interval
+    #[arg(long, value_name = "SECS", value_parser = parse_non_negative)]
+    phase: Option<f64>,
+
+    /// End each cycle at the interval: targets not reached in time are reported as SKIPPED
+    #[arg(long)]
+    deadline: bool,
+
+    /// Also measure the busiest remotes from localPacketDump-rs, e.g. http://localhost:59122/window.json
+    #[arg(long, value_name = "URL")]
+    discover: Option<String>,
+
+    /// Number of busiest remotes measured per interface in discovery mode
+    #[arg(long, default_value_t = 5)]
+    discover_top: usize,
+
+    /// Port the discovered remotes are measured on
+    #[arg(long, default_value_t = 443)]
+    discover_port: u16,
+
+    /// Interface label used by localPacketDump-rs for an interface, e.g. eth0=wan0
+    #[arg(long, action = clap::ArgAction::Append, value_parser = discover::parse_label)]
+    discover_label: Vec<(String, String)>,
+
+    /// Serve /buildinfo and a build_info metric on this address, e.g. 0.0.0.0:59125
+    #[arg(long, value_name = "ADDR")]
+    buildinfo_listen: Option<SocketAddr>,
+
+    /// Run the built-in reflector on this address instead of measuring, e.g. 0.0.0.0:8080
+    #[arg(long, value_name = "ADDR")]
+    reflect: Option<SocketAddr>,
+
+    /// Shared token of the reflector; required with --reflect and sent with --transfer/--upload/--nat-timeout
+    #[arg(long, value_name = "TOKEN")]
+    reflect_token: Option<String>,
+
+    /// Connections the reflector serves at once
+    #[arg(long, value_name = "N", default_value_t = 64)]
+    reflect_max_connections: usize,
+
+    /// Longest the reflector serves a single download or upload
+    #[arg(long, value_name = "SECS", default_value_t = 60)]
+    reflect_max_secs: u64,
+
+    /// Report simulated handshakes instead of connecting (see SIMULATE_* variables)
+    #[arg(long)]
+    simulate: bool,
+
+    /// Discover after how long idle connections are dropped on each interface, then exit (uses the first server)
+    #[arg(long)]
+    nat_timeout: bool,
+
+    /// Shortest idle time tried in NAT timeout discovery
+    #[arg(long, value_name = "SECS", default_value_t = 15, value_parser = clap::value_parser!(u64).range(1..))]
+    nat_timeout_min: u64,
+
+    /// Longest idle time tried in NAT timeout discovery
+    #[arg(long, value_name = "SECS", default_value_t = 1800, value_parser = clap::value_parser!(u64).range(1..))]
+    nat_timeout_max: u64,
+
+    /// Idle times tried between the last kept and the first dropped connection (0 to skip)
+    #[arg(long, default_value_t = 6)]
+    nat_timeout_steps: u32,
+
+    /// Read defaults from the tcp-traffic-scan table of a shared TOML/YAML file (flags win)
+    #[arg(long, value_name = "PATH")]
+    config: Option<String>,
+}
+
+// Command line merged with --config, then checked across flags
+fn parse_args() -> Args {
+    let args = merged_args();
+    if let Err(e) = check_args(&args) {
+        e.exit();
+    }
+    args
+}
+
+// Checks that involve more than one flag, reported like clap's own errors
+fn check_args(args: &Args) -> Result<(), clap::Error> {
+    if args.phase.is_some_and(|phase| phase >= args.interval) {
+        return Err(Args::command().error(
+            clap::error::ErrorKind::ValueValidation,
+            format!("--phase must be below --interval ({}s)", args.interval),
+        ));
+    }
+    Ok(())
+}
+
+// Each setting of --config named after a flag is added as that flag unless the flag was
+// given on the command line. Settings for other components are ignored.
+fn merged_args() -> Args {
+    let command = Args::command();
+    let matches = command.clone().get_matches();
+    let settings = match shared_config::init(shared_config::TCP_TRAFFIC_SCAN) {
+        Ok(Some(settings)) => settings,
+        Ok(None) => return Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit()),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    let mut argv: Vec<OsString> = std::env::args_os().collect();
+    for (key, values) in settings.iter() {
+        let Some(arg) = command.get_arguments().find(|arg| arg.get_id() == key) else {
+            continue;
+        };
+        let Some(long) = arg.get_long() else {
+            continue;
+        };
+        if key == "config" || matches.value_source(key) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+        let flag = format!("--{}", long);
+        for value in values {
+            if arg.get_action().takes_values() {
+                argv.push(flag.clone().into());
+                argv.push(value.into());
+            } else if value == "true" {
+                argv.push(flag.clone().into());
+            }
+        }
+    }
+    Args::try_parse_from(argv).unwrap_or_else(|e| e.exit())
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// Receive window divided by the TCP handshake RTT
+    Estimate,
+    /// TCP connect, TLS handshake and HTTP time to first byte, each timed separately
+    Handshake,
+    /// Goodput of fetching --probe-bytes from --probe-url over HTTPS
+    DownloadProbe,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum Output {
+    Text,
+    Json,
+    Csv,
+}
+
+fn parse_interval(s: &str) -> Result<f64, String> {
+    let secs: f64 = s.parse().map_err(|_| format!("invalid interval '{}'", s))?;
+    if !(secs > 0.0 && secs.is_finite()) {
+        return Err("seconds must be positive and finite".to_string());
+    }
+    Ok(secs)
+}
+
+// Seconds that may be zero
+fn parse_non_negative(s: &str) -> Result<f64, String> {
+    let secs: f64 = s.parse().map_err(|_| format!("invalid seconds '{}'", s))?;
+    if !(secs >= 0.0 && secs.is_finite()) {
+        return Err("seconds must be finite and not negative".to_string());
+    }
+    Ok(secs)
+}
+
+fn parse_resolver(s: &str) -> Result<(String, IpAddr), String> {
+    let (interface, resolver) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected IFACE=RESOLVER_IP, got '{}'", s))?;
+    let resolver: IpAddr = resolver
+        .parse()
+        .map_err(|_| format!("invalid resolver '{}' for {}", resolver, interface))?;
+    Ok((interface.to_string(), resolver))
+}
+
+// Result of a single connect measurement
+struct Measurement {
+    rtt: Duration,
+    window_size: u32,
+    // Negotiated TCP options, when the platform exposes TCP_INFO
+    options: Option<TcpOptions>,
+    // Round trip of a request on the kept-alive connection (--compare-reuse)
+    warm_rtt: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TcpOptions {
+    timestamps: bool,
+    sack: bool,
+    window_scaling: bool,
+    ecn: bool,
+}
+
+impl std::fmt::Display for TcpOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let flag = |enabled: bool, name: &str| {
+            if enabled {
+                name.to_string()
+            } else {
+                format!("-{}", name)
+            }
+        };
+        write!(
+            f,
+            "{},{},{},{}",
+            flag(self.timestamps, "ts"),
+            flag(self.sack, "sack"),
+            flag(self.window_scaling, "ws"),
+            flag(self.ecn, "ecn")
+        )
+    }
+}
+
+fn parse_capacity(s: &str) -> Result<(String, f64), String> {
+    let (interface, mbps) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected IFACE=MBPS, got '{}'", s))?;
+    let mbps: f64 = mbps
+        .parse()
+        .map_err(|_| format!("invalid capacity '{}' for {}", mbps, interface))?;
+    if mbps <= 0.0 {
+        return Err(format!("capacity for {} must be positive", interface));
+    }
+    Ok((interface.to_string(), mbps))
 }
 
 fn main() {
-    let args = Args::parse();
+    let mut args = parse_args();
+
+    // The reflector runs at the far end and needs neither interfaces nor servers
+    if let Some(addr) = args.reflect {
+        // Unlike the measurement output, the reflector's connections are logged through tracing
+        tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::INFO)
+            .with_writer(std::io::stderr)
+            .init();
+        let Some(token) = args.reflect_token.filter(|token| !token.is_empty()) else {
+            eprintln!("--reflect needs --reflect-token; clients send it with --reflect-token");
+            std::process::exit(2);
+        };
+        let config = reflector::Config {
+            token,
+            max_connections: args.reflect_max_connections,
+            max_duration: Duration::from_secs(args.reflect_max_secs.max(1)),
+        };
+        if let Err(e) = reflector::serve(addr, config) {
+            eprintln!("Failed to run the reflector on {}: {}", addr, e);
+            std::process::exit(2);
+        }
+        return;
+    }
 
     if args.interface.is_empty() {
         eprintln!("No interfaces specified. Use -i/--interface to add interfaces.");
         std::process::exit(2);
     }
 
-    if args.server.is_empty() {
-        eprintln!("No servers specified. Use -s/--server to add targets.");
+    if args.mode == Mode::DownloadProbe {
+        if let Some(interface) = args
+            .interface
+            .iter()
+            .find(|interface| probe_url_for(&args, interface).is_none())
+        {
+            eprintln!(
+                "No probe URL for {}. Use --probe-url URL or --probe-url {}=URL.",
+                interface, interface
+            );
+            std::process::exit(2);
+        }
+    } else if args.server.is_empty() && args.discover.is_none() {
+        eprintln!("No servers specified. Use -s/--server or --discover to add targets.");
+        std::process::exit(2);
+    }
+
+    // Handshake mode measures no throughput, so the threshold would fail every target
+    if args.mode == Mode::Handshake && args.min_throughput.is_some() && !args.simulate {
+        eprintln!(
+            "--min-throughput cannot be used with --mode handshake, which measures no throughput"
+        );
         std::process::exit(2);
     }
 
+    if args.probe_options {
+        warn_if_ecn_not_requested();
+    }
+
+    let thresholds = history::Thresholds {
+        min_availability_pct: args.min_availability,
+        max_median_rtt_ms: args.max_median_rtt,
+        min_throughput_mbps: args.min_throughput,
+    };
+    // With --count the run is bounded, so keep every cycle and let the exit summary and the
+    // thresholds cover the whole run rather than only the last --history cycles
+    let history_cycles = match args.count {
+        Some(count) => args
+            .history
+            .max(usize::try_from(count).unwrap_or(usize::MAX)),
+        None => args.history,
+    };
+    let history = Arc::new(Mutex::new(history::History::new(history_cycles)));
+
+    if let Some(addr) = args.buildinfo_listen {
+        if let Err(e) = buildinfo::serve(addr, Arc::clone(&history), thresholds) {
+            eprintln!("Failed to serve build info on {}: {}", addr, e);
+            std::process::exit(2);
+        }
+    }
+
+    // The records carry only the measurement itself; the extra reports need the bars
+    if args.output != Output::Text {
+        let text_only = [
+            ("--compare-reuse", std::mem::take(&mut args.compare_reuse)),
+            ("--transfer", args.transfer.take().is_some()),
+            ("--upload", args.upload.take().is_some()),
+            ("--pmtud", std::mem::take(&mut args.pmtud)),
+            ("--probe-options", std::mem::take(&mut args.probe_options)),
+            (
+                "-c/--capacity",
+                !std::mem::take(&mut args.capacity).is_empty(),
+            ),
+        ];
+        for (flag, _) in text_only.iter().filter(|(_, set)| *set) {
+            eprintln!("Warning: {} is ignored with --output json/csv", flag);
+        }
+    }
+
+    let simulation = args.simulate.then(simulate::Simulation::from_env);
+    if let Some(simulation) = &simulation {
+        // Kept off stdout so it does not mix with JSON or CSV records
+        if args.output == Output::Text {
+            println!("Simulating measurements: {}", simulation.describe());
+        } else {
+            eprintln!("Simulating measurements: {}", simulation.describe());
+        }
+        if args.transfer.take().is_some() {
+            eprintln!("Warning: --transfer is not simulated and is ignored with --simulate");
+        }
+        if args.upload.take().is_some() {
+            eprintln!("Warning: --upload is not simulated and is ignored with --simulate");
+        }
+        if std::mem::take(&mut args.pmtud) {
+            eprintln!("Warning: --pmtud is not simulated and is ignored with --simulate");
+        }
+        if args.mode != Mode::Estimate {
+            eprintln!("Warning: --mode is not simulated, the estimate is reported instead");
+            if args.mode == Mode::DownloadProbe && args.server.is_empty() {
+                eprintln!("No servers specified. Use -s/--server to add simulated targets.");
+                std::process::exit(2);
+            }
+            args.mode = Mode::Estimate;
+        }
+    }
+    let tls = https::client_config(args.insecure);
+
     // Ctrl+C handling
     let running = Arc::new(AtomicBool::new(true));
     {
@@ -49,86 +538,845 @@ fn main() {
         });
     }
 
-    // Main loop until Ctrl+C
-    let sleep_duration = Duration::from_secs_f64(1.0);
+    if args.nat_timeout {
+        if simulation.is_some() {
+            eprintln!("--nat-timeout is not simulated and cannot be combined with --simulate");
+            std::process::exit(2);
+        }
+        if args.nat_timeout_max < args.nat_timeout_min {
+            eprintln!("--nat-timeout-max must not be below --nat-timeout-min");
+            std::process::exit(2);
+        }
+        run_nat_timeout(&args, &running);
+        return;
+    }
+
+    let discovery = args.discover.clone().map(|url| {
+        discover::Discovery::new(
+            url,
+            args.discover_top,
+            args.discover_port,
+            args.discover_label.clone(),
+        )
+    });
+    // Last successfully discovered targets per interface, kept when a fetch fails
+    let mut discovered: HashMap<String, Vec<String>> = HashMap::new();
+
+    // Main loop until Ctrl+C or --count cycles
+    let sleep_duration = Duration::from_secs_f64(args.interval);
+    let schedule = args
+        .phase
+        .map(|phase| Schedule::new(sleep_duration, Duration::from_secs_f64(phase)));
+    let mut cycles = 0;
+    if args.output == Output::Csv {
+        println!("{}", output::CSV_HEADER);
+    }
     while running.load(Ordering::SeqCst) {
-        println!("==================================");
+        if let Some(schedule) = &schedule {
+            wait_until(Instant::now() + schedule.until_next(), &running);
+            if !running.load(Ordering::SeqCst) {
+                break;
+            }
+        }
+        if args.output == Output::Text {
+            println!("==================================");
+        }
+        let cycle_start = Instant::now();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or(0.0);
+        // With --deadline, the cycle must end by the time the next one is due
+        let deadline = args.deadline.then(|| cycle_start + sleep_duration);
 
-        for interface in &args.interface {
-            let mut results = Vec::new();
+        if let Some(discovery) = &discovery {
+            match discovery.fetch(&args.interface) {
+                Ok(targets) => discovered = targets,
+                Err(e) => eprintln!("Error discovering targets: {}", e),
+            }
+        }
 
-            for server_str in &args.server {
-                match resolve_server_address(server_str) {
-                    Ok(server_addr) => match measure_throughput(interface, server_addr) {
-                        Ok((rtt, window_size)) => {
-                            let throughput_bps = if rtt.as_secs_f64() > 0.0 {
-                                (window_size as f64 * 8.0) / rtt.as_secs_f64()
-                            } else {
-                                0.0
-                            };
-                            let throughput_mbps = throughput_bps / 1_000_000.0;
-                            results.push(format!(
-                                "{}:{:.0}Mbps",
-                                server_addr.ip(),
-                                throughput_mbps
-                            ));
-                        }
-                        Err(e) => {
-                            eprintln!(
-                                "Error measuring {} on {}: {}",
-                                server_addr.ip(),
-                                interface,
-                                e
-                            );
-                            results.push(format!("{}:ERR", server_addr.ip()));
-                        }
-                    },
-                    Err(e) => {
-                        eprintln!("Error resolving server address for {}: {}", server_str, e);
-                        results.push(format!("{}:N/A", server_str));
+        // Every interface/server pair of the cycle, measured --concurrency at a time.
+        // The download probe fetches its one URL per interface.
+        let pairs: Vec<(&str, String)> = args
+            .interface
+            .iter()
+            .flat_map(|interface| {
+                if args.mode == Mode::DownloadProbe {
+                    let url = probe_url_for(&args, interface).map(|url| url.to_string());
+                    return url
+                        .into_iter()
+                        .map(move |url| (interface.as_str(), url))
+                        .collect();
+                }
+                let mut servers = args.server.clone();
+                for target in discovered.get(interface).into_iter().flatten() {
+                    if !servers.contains(target) {
+                        servers.push(target.clone());
                     }
                 }
+                servers
+                    .into_iter()
+                    .map(|server| (interface.as_str(), server))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        let measured = pool::run(
+            &pairs,
+            args.concurrency as usize,
+            |(interface, server_str)| {
+                measure_pair(
+                    &args,
+                    simulation.as_ref(),
+                    &tls,
+                    interface,
+                    server_str,
+                    deadline,
+                )
+            },
+        );
+
+        // Real transfers share the link, so each interface moves data to one target at a time
+        let transfers: Vec<Transfers> = std::thread::scope(|scope| {
+            let handles: Vec<_> = args
+                .interface
+                .iter()
+                .map(|interface| {
+                    let targets: Vec<(&str, SocketAddr)> = pairs
+                        .iter()
+                        .zip(&measured)
+                        .filter(|((i, _), _)| i == interface)
+                        .filter_map(|((_, server_str), pair)| {
+                            Some((server_str.as_str(), pair.transfer_target?))
+                        })
+                        .collect();
+                    let args = &args;
+                    scope.spawn(move || run_transfers(args, interface, &targets, deadline))
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap_or_default())
+                .collect()
+        });
 
-                // Small delay between servers to stagger measurements
-                std::thread::sleep(Duration::from_millis(100));
+        for (interface, transfers) in args.interface.iter().zip(transfers) {
+            let mut results = Vec::new();
+            let mut measurements = Vec::new();
+            let mut option_results = Vec::new();
+            let mut reuse_results = Vec::new();
+            let mut pmtud_results = Vec::new();
+            for ((_, server_str), pair) in pairs
+                .iter()
+                .zip(&measured)
+                .filter(|((i, _), _)| i == interface)
+            {
+                if let Some((rtt_ms, throughput_mbps)) = pair.history {
+                    history
+                        .lock()
+                        .unwrap()
+                        .record(interface, server_str, rtt_ms, throughput_mbps);
+                }
+                match args.output {
+                    Output::Text => {}
+                    Output::Json => println!(
+                        "{}",
+                        output::json_line(timestamp, interface, server_str, &pair.figures)
+                    ),
+                    Output::Csv => println!(
+                        "{}",
+                        output::csv_line(timestamp, interface, server_str, &pair.figures)
+                    ),
+                }
+                results.push(pair.result.clone());
+                measurements.extend(pair.measurement);
+                option_results.extend(pair.options.clone());
+                reuse_results.extend(pair.reuse.clone());
+                pmtud_results.extend(pair.pmtud.clone());
+            }
+
+            if args.output != Output::Text {
+                continue;
             }
 
             // Print interface results in bar format
             println!("{}: |{}|", interface, results.join("|"));
 
-            // Delay between interfaces to stagger measurements
-            std::thread::sleep(Duration::from_millis(200));
+            if args.compare_reuse {
+                println!("{} reuse: |{}|", interface, reuse_results.join("|"));
+            }
+
+            if args.transfer.is_some() {
+                println!("{} transfer: |{}|", interface, transfers.download.join("|"));
+                if args.parallel > 1 {
+                    println!(
+                        "{} streams: |{}|",
+                        interface,
+                        transfers.download_streams.join("|")
+                    );
+                }
+            }
+
+            if args.upload.is_some() {
+                println!("{} upload: |{}|", interface, transfers.upload.join("|"));
+                if args.parallel > 1 {
+                    println!(
+                        "{} upload streams: |{}|",
+                        interface,
+                        transfers.upload_streams.join("|")
+                    );
+                }
+            }
+
+            if args.pmtud {
+                println!("{} pmtud: |{}|", interface, pmtud_results.join("|"));
+            }
+
+            if args.probe_options {
+                if option_results.is_empty() {
+                    println!("{} options: unavailable", interface);
+                } else {
+                    println!("{} options: |{}|", interface, option_results.join("|"));
+                }
+            }
+
+            if let Some((_, capacity_mbps)) = args.capacity.iter().find(|(i, _)| i == interface) {
+                print_bdp_report(interface, *capacity_mbps, &measurements);
+            }
         }
 
+        history.lock().unwrap().finish_cycle();
         let _ = std::io::stdout().flush();
+        cycles += 1;
+        if args.count.is_some_and(|count| cycles >= count) {
+            break;
+        }
 
-        // Sleep until next iteration or exit if Ctrl+C was pressed
-        let start_sleep = Instant::now();
-        while running.load(Ordering::SeqCst) {
-            let elapsed = start_sleep.elapsed();
-            if elapsed >= sleep_duration {
-                break;
-            }
-            std::thread::sleep(Duration::from_millis(50));
+        // With --phase the next cycle waits for its slot at the top of the loop
+        if schedule.is_some() {
+            continue;
         }
+        // Sleep until next iteration or exit if Ctrl+C was pressed. With --deadline the
+        // next cycle starts one interval after this one did, keeping the output cadence steady
+        let next_cycle = match deadline {
+            Some(deadline) => deadline,
+            None => Instant::now() + sleep_duration,
+        };
+        wait_until(next_cycle, &running);
+    }
+
+    let history = history.lock().unwrap();
+    if args.summary {
+        history.print_summary();
     }
+
+    let (summary, pass) = history.json_summary(&thresholds);
+    if let Some(path) = &args.summary_json {
+        let json = serde_json::to_string_pretty(&summary).unwrap_or_default();
+        let written = match path.as_str() {
+            "-" => writeln!(std::io::stdout(), "{}", json),
+            path => std::fs::write(path, json + "\n"),
+        };
+        if let Err(e) = written {
+            eprintln!("Failed to write the summary to {}: {}", path, e);
+            std::process::exit(2);
+        }
+    }
+    if !pass {
+        eprintln!("Link quality below the thresholds, see the summary");
+        std::process::exit(1);
+    }
+}
+
+fn resolver_for(args: &Args, interface: &str) -> Option<IpAddr> {
+    args.resolver
+        .iter()
+        .find(|(i, _)| i == interface)
+        .map(|(_, resolver)| *resolver)
 }
 
-fn resolve_server_address(server_str: &str) -> io::Result<SocketAddr> {
-    // Append a default port if not specified, required by ToSocketAddrs
-    let addr_with_port = if server_str.contains(':') {
-        server_str.to_string()
+// --probe-url given for the interface, else the one given for every interface
+fn probe_url_for<'a>(args: &'a Args, interface: &str) -> Option<&'a https::ProbeUrl> {
+    let url = |wanted: Option<&str>| {
+        args.probe_url
+            .iter()
+            .find(|(i, _)| i.as_deref() == wanted)
+            .map(|(_, url)| url)
+    };
+    url(Some(interface)).or_else(|| url(None))
+}
+
+fn binding_for(args: &Args, interface: &str) -> Binding {
+    Binding {
+        interface: interface.to_string(),
+        vrf: args
+            .vrf
+            .iter()
+            .find(|(i, _)| i == interface)
+            .map(|(_, vrf)| vrf.clone()),
+        by_index: args.bind_ifindex,
+        fwmark: args
+            .fwmark
+            .iter()
+            .find(|(i, _)| i == interface)
+            .map(|(_, mark)| *mark),
+    }
+}
+
+// What one interface/server pair produced in a cycle, printed once every pair is done
+#[derive(Default)]
+struct PairResult {
+    // Entry of the interface's result bar
+    result: String,
+    // (RTT in ms, throughput in Mbps) for the history, both None when the target failed;
+    // None when the target was skipped. The HTTPS modes record the TCP connect time as RTT.
+    history: Option<(Option<f64>, Option<f64>)>,
+    // (RTT, receive window) for the BDP report
+    measurement: Option<(Duration, u32)>,
+    options: Option<String>,
+    reuse: Option<String>,
+    pmtud: Option<String>,
+    // Address the real transfers go to, when the handshake succeeded
+    transfer_target: Option<SocketAddr>,
+    // Record of --output json / csv
+    figures: output::Figures,
+}
+
+// Measure one target on one interface within --timeout and the cycle deadline
+fn measure_pair(
+    args: &Args,
+    simulation: Option<&simulate::Simulation>,
+    tls: &Arc<rustls::ClientConfig>,
+    interface: &str,
+    server_str: &str,
+    deadline: Option<Instant>,
+) -> PairResult {
+    let start = Instant::now();
+    let timeout = Duration::from_secs_f64(args.timeout);
+    // The measurement ends at --timeout or the cycle deadline, whichever comes first
+    let until = match deadline {
+        Some(deadline) if deadline <= start => {
+            return PairResult {
+                result: format!("{}:SKIPPED", server_str),
+                ..Default::default()
+            };
+        }
+        Some(deadline) => deadline.min(start + timeout),
+        None => start + timeout,
+    };
+    let binding = binding_for(args, interface);
+    let probe_url = match args.mode {
+        Mode::DownloadProbe => probe_url_for(args, interface),
+        _ => None,
+    };
+    let target = probe_url.map(|url| url.server());
+    let target = target.as_deref().unwrap_or(server_str);
+    // Simulated runs resolve through the system resolver, without binding
+    let resolved = match simulation {
+        Some(_) => resolve_server_address(target, &binding, None),
+        None => resolve_server_address(target, &binding, resolver_for(args, interface)),
+    };
+    let server_addr = match resolved {
+        Ok(server_addr) => server_addr,
+        Err(e) => {
+            eprintln!("Error resolving server address for {}: {}", server_str, e);
+            return PairResult {
+                result: format!("{}:N/A", server_str),
+                history: Some((None, None)),
+                figures: output::Figures {
+                    status: output::Status::Unresolved,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+        }
+    };
+
+    match (args.mode, probe_url) {
+        (Mode::Handshake, _) => {
+            return measure_handshake(tls, &binding, server_addr, server_str, until)
+        }
+        (Mode::DownloadProbe, Some(url)) => {
+            return measure_download(args, tls, &binding, server_addr, url)
+        }
+        _ => {}
+    }
+
+    let measured = match simulation {
+        Some(simulation) => simulation.measure(
+            args.compare_reuse,
+            until.saturating_duration_since(Instant::now()),
+        ),
+        None => measure_throughput(&binding, server_addr, args.compare_reuse, until),
+    };
+    let Measurement {
+        rtt,
+        window_size,
+        options,
+        warm_rtt,
+    } = match measured {
+        Ok(measurement) => measurement,
+        Err(e) => {
+            eprintln!(
+                "Error measuring {} on {}: {}",
+                server_addr.ip(),
+                interface,
+                e
+            );
+            return PairResult {
+                result: format!("{}:ERR", server_addr.ip()),
+                history: Some((None, None)),
+                figures: output::Figures {
+                    status: output::Status::Error,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+        }
+    };
+
+    let throughput_bps = if rtt.as_secs_f64() > 0.0 {
+        (window_size as f64 * 8.0) / rtt.as_secs_f64()
     } else {
-        format!("{}:443", server_str) // Default to port 443 for resolution
+        0.0
+    };
+    let throughput_mbps = throughput_bps / 1_000_000.0;
+    PairResult {
+        result: format!("{}:{:.0}Mbps", server_addr.ip(), throughput_mbps),
+        history: Some((Some(rtt.as_secs_f64() * 1000.0), Some(throughput_mbps))),
+        measurement: Some((rtt, window_size)),
+        options: options.map(|options| format!("{}:{}", server_addr.ip(), options)),
+        reuse: args
+            .compare_reuse
+            .then(|| format_reuse(server_addr, rtt, warm_rtt)),
+        pmtud: args.pmtud.then(|| {
+            check_pmtud(
+                &binding,
+                server_addr,
+                Duration::from_secs_f64(args.pmtud_timeout),
+                deadline,
+            )
+        }),
+        transfer_target: Some(server_addr),
+        figures: output::Figures {
+            status: output::Status::Ok,
+            rtt_ms: Some(rtt.as_secs_f64() * 1000.0),
+            window: Some(window_size),
+            throughput_mbps: Some(throughput_mbps),
+            ..Default::default()
+        },
+    }
+}
+
+// Time connect, TLS handshake and first response byte of one target (--mode handshake)
+fn measure_handshake(
+    tls: &Arc<rustls::ClientConfig>,
+    binding: &Binding,
+    server_addr: SocketAddr,
+    server_str: &str,
+    until: Instant,
+) -> PairResult {
+    match https::handshake(binding, server_addr, host_of(server_str), "/", tls, until) {
+        Ok(timings) => PairResult {
+            result: timings.format(server_addr),
+            history: Some((Some(timings.connect.as_secs_f64() * 1000.0), None)),
+            transfer_target: Some(server_addr),
+            figures: output::Figures {
+                status: output::Status::Ok,
+                rtt_ms: Some(timings.connect.as_secs_f64() * 1000.0),
+                tls_ms: Some(timings.tls.as_secs_f64() * 1000.0),
+                ttfb_ms: Some(timings.ttfb.as_secs_f64() * 1000.0),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+        Err(e) => {
+            eprintln!(
+                "Error measuring the HTTPS handshake to {} on {}: {}",
+                server_addr.ip(),
+                binding.interface,
+                e
+            );
+            PairResult {
+                result: format!("{}:ERR", server_addr.ip()),
+                history: Some((None, None)),
+                figures: output::Figures {
+                    status: output::Status::Error,
+                    ..Default::default()
+                },
+                ..Default::default()
+            }
+        }
+    }
+}
+
+// Fetch --probe-bytes from the interface's probe URL (--mode download-probe). The download is
+// not cut short by --timeout or the cycle deadline, only by stalling for --timeout.
+fn measure_download(
+    args: &Args,
+    tls: &Arc<rustls::ClientConfig>,
+    binding: &Binding,
+    server_addr: SocketAddr,
+    url: &https::ProbeUrl,
+) -> PairResult {
+    let stall_timeout = Duration::from_secs_f64(args.timeout);
+    match https::download(
+        binding,
+        server_addr,
+        url,
+        args.probe_bytes,
+        tls,
+        stall_timeout,
+    ) {
+        Ok(download) => PairResult {
+            result: download.format(server_addr),
+            history: Some((
+                Some(download.connect.as_secs_f64() * 1000.0),
+                Some(download.mbps()),
+            )),
+            figures: output::Figures {
+                status: output::Status::Ok,
+                rtt_ms: Some(download.connect.as_secs_f64() * 1000.0),
+                throughput_mbps: Some(download.mbps()),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+        Err(e) => {
+            eprintln!("Error downloading {} on {}: {}", url, binding.interface, e);
+            PairResult {
+                result: format!("{}:ERR", server_addr.ip()),
+                history: Some((None, None)),
+                figures: output::Figures {
+                    status: output::Status::Error,
+                    ..Default::default()
+                },
+                ..Default::default()
+            }
+        }
+    }
+}
+
+// Result bars of the real transfers on one interface
+#[derive(Default)]
+struct Transfers {
+    download: Vec<String>,
+    download_streams: Vec<String>,
+    upload: Vec<String>,
+    upload_streams: Vec<String>,
+}
+
+// Download from and upload to each reachable target of an interface in turn (real-transfer mode)
+fn run_transfers(
+    args: &Args,
+    interface: &str,
+    targets: &[(&str, SocketAddr)],
+    deadline: Option<Instant>,
+) -> Transfers {
+    let mut transfers = Transfers::default();
+    let binding = binding_for(args, interface);
+    let connect_timeout = Duration::from_secs_f64(args.timeout);
+    let warmup = Duration::from_secs_f64(args.warmup);
+    // A transfer that would overrun the deadline is not started
+    let late = |secs: f64| {
+        deadline.is_some_and(|deadline| Instant::now() + Duration::from_secs_f64(secs) > deadline)
     };
+    for (server_str, server_addr) in targets {
+        let server_addr = *server_addr;
+        if args.transfer.is_some_and(late) {
+            transfers
+                .download
+                .push(format!("{}:SKIPPED", server_addr.ip()));
+        } else if let Some(secs) = args.transfer {
+            let streams = measure_transfer(
+                &binding,
+                server_addr,
+                server_str,
+                args.parallel,
+                connect_timeout,
+                |socket, host| {
+                    transfer::run(
+                        socket,
+                        host,
+                        &args.transfer_path,
+                        args.reflect_token.as_deref(),
+                        Duration::from_secs_f64(secs),
+                        warmup,
+                    )
+                },
+            );
+            for e in streams.iter().filter_map(|r| r.as_ref().err()) {
+                eprintln!(
+                    "Error transferring from {} on {}: {}",
+                    server_addr.ip(),
+                    interface,
+                    e
+                );
+            }
+            let total = transfer::aggregate(&streams);
+            transfers
+                .download
+                .push(transfer::format_result(server_addr, &total));
+            if args.parallel > 1 {
+                transfers
+                    .download_streams
+                    .push(transfer::format_streams(server_addr, &streams));
+            }
+        }
+        if args.upload.is_some_and(late) {
+            transfers
+                .upload
+                .push(format!("{}:SKIPPED", server_addr.ip()));
+        } else if let Some(secs) = args.upload {
+            let streams = measure_transfer(
+                &binding,
+                server_addr,
+                server_str,
+                args.parallel,
+                connect_timeout,
+                |socket, host| {
+                    transfer::upload(
+                        socket,
+                        host,
+                        &args.upload_path,
+                        args.reflect_token.as_deref(),
+                        Duration::from_secs_f64(secs),
+                        warmup,
+                    )
+                },
+            );
+            for e in streams.iter().filter_map(|r| r.as_ref().err()) {
+                eprintln!(
+                    "Error uploading to {} on {}: {}",
+                    server_addr.ip(),
+                    interface,
+                    e
+                );
+            }
+            let total = transfer::aggregate(&streams);
+            transfers
+                .upload
+                .push(transfer::format_result(server_addr, &total));
+            if args.parallel > 1 {
+                transfers
+                    .upload_streams
+                    .push(transfer::format_streams(server_addr, &streams));
+            }
+        }
+    }
+    transfers
+}
+
+// Discover the idle timeout of every interface at once against the first server
+fn run_nat_timeout(args: &Args, running: &AtomicBool) {
+    let Some(server_str) = args.server.first() else {
+        eprintln!("--nat-timeout needs a server given with -s/--server");
+        std::process::exit(2);
+    };
+    let config = nat_timeout::Config {
+        min: Duration::from_secs(args.nat_timeout_min),
+        max: Duration::from_secs(args.nat_timeout_max),
+        refine_steps: args.nat_timeout_steps,
+        token: args
+            .reflect_token
+            .as_deref()
+            .filter(|token| !token.is_empty()),
+    };
+    let results: Vec<(String, String)> = std::thread::scope(|scope| {
+        let handles: Vec<_> = args
+            .interface
+            .iter()
+            .map(|interface| {
+                scope.spawn(move || {
+                    let binding = binding_for(args, interface);
+                    let resolver = resolver_for(args, interface);
+                    let result = match resolve_server_address(server_str, &binding, resolver) {
+                        Ok(addr) => {
+                            nat_timeout::discover(&binding, addr, config, running).to_string()
+                        }
+                        Err(e) => format!("error resolving {}: {}", server_str, e),
+                    };
+                    (interface.clone(), result)
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .zip(&args.interface)
+            .map(|(handle, interface)| {
+                handle
+                    .join()
+                    .unwrap_or_else(|_| (interface.clone(), "discovery panicked".to_string()))
+            })
+            .collect()
+    });
+    for (interface, result) in results {
+        println!("{} nat-timeout: {}", interface, result);
+    }
+}
+
+// Sleep until `until`, waking early if Ctrl+C was pressed
+fn wait_until(until: Instant, running: &AtomicBool) {
+    while running.load(Ordering::SeqCst) {
+        let now = Instant::now();
+        if now >= until {
+            break;
+        }
+        std::thread::sleep((until - now).min(Duration::from_millis(50)));
+    }
+}
+
+// Compare the bandwidth-delay product against the observed receive window.
+// Uses the largest RTT seen on the interface, since that path needs the biggest window.
+fn print_bdp_report(interface: &str, capacity_mbps: f64, measurements: &[(Duration, u32)]) {
+    let Some((rtt, window_size)) = measurements.iter().max_by_key(|(rtt, _)| *rtt) else {
+        return;
+    };
+
+    let bdp_bytes = (capacity_mbps * 1_000_000.0 / 8.0 * rtt.as_secs_f64()).ceil() as u64;
+    let window_limited = u64::from(*window_size) < bdp_bytes;
+
+    println!(
+        "{}: BDP {} bytes ({:.0}Mbps x {:.1}ms), window {} bytes -> {}",
+        interface,
+        bdp_bytes,
+        capacity_mbps,
+        rtt.as_secs_f64() * 1000.0,
+        window_size,
+        if window_limited {
+            "window-limited"
+        } else {
+            "ok"
+        }
+    );
+
+    if window_limited {
+        // Linux reserves roughly half of the buffer for bookkeeping, so suggest twice the BDP
+        let suggested = (bdp_bytes * 2).next_power_of_two();
+        println!(
+            "{}: suggest sysctl -w net.core.rmem_max={} net.ipv4.tcp_rmem=\"4096 131072 {}\"",
+            interface, suggested, suggested
+        );
+    }
+}
+
+fn resolve_server_address(
+    server_str: &str,
+    binding: &Binding,
+    resolver: Option<IpAddr>,
+) -> io::Result<SocketAddr> {
+    if let Ok(addr) = server_str.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    // Default to port 443
+    let (host, port) = split_server(server_str)?;
+    let port = port.unwrap_or(443);
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, port));
+    }
+    if let Some(resolver) = resolver {
+        let ip = dns::resolve_via(host, resolver, binding)?;
+        return Ok(SocketAddr::new(ip, port));
+    }
 
-    addr_with_port
+    (host, port)
         .to_socket_addrs()?
         .next()
         .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Could not resolve address"))
 }
 
-fn measure_throughput(interface: &str, addr: SocketAddr) -> io::Result<(Duration, u32)> {
+// Split a -s/--server entry into host and port. Accepts host, host:port, a bare IP
+// address and [v6]:port; an IPv6 address with a port has to be bracketed, since
+// 2001:db8::1:443 could be either.
+fn split_server(server_str: &str) -> io::Result<(&str, Option<u16>)> {
+    if server_str.parse::<IpAddr>().is_ok() {
+        return Ok((server_str, None));
+    }
+    let invalid = |reason: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} in '{}'", reason, server_str),
+        )
+    };
+    let (host, port) = match server_str.strip_prefix('[') {
+        Some(bracketed) => {
+            let (host, rest) = bracketed
+                .split_once(']')
+                .ok_or_else(|| invalid("missing ']'"))?;
+            if rest.is_empty() {
+                (host, None)
+            } else {
+                let port = rest
+                    .strip_prefix(':')
+                    .ok_or_else(|| invalid("expected ':' after ']'"))?;
+                (host, Some(port))
+            }
+        }
+        None => match server_str.rsplit_once(':') {
+            Some((host, _)) if host.contains(':') => {
+                return Err(invalid(
+                    "IPv6 address with a port must be written as [addr]:port",
+                ))
+            }
+            Some((host, port)) => (host, Some(port)),
+            None => (server_str, None),
+        },
+    };
+    let port = port
+        .map(|port| {
+            port.parse::<u16>()
+                .map_err(|_| invalid("invalid port number"))
+        })
+        .transpose()?;
+    Ok((host, port))
+}
+
+// One target of the PMTUD check, shortened to the cycle deadline
+fn check_pmtud(
+    binding: &Binding,
+    addr: SocketAddr,
+    timeout: Duration,
+    deadline: Option<Instant>,
+) -> String {
+    let timeout = match deadline {
+        Some(deadline) => timeout.min(deadline.saturating_duration_since(Instant::now())),
+        None => timeout,
+    };
+    if timeout.is_zero() {
+        return format!("{}:SKIPPED", addr.ip());
+    }
+    match pmtud::probe(binding, addr, timeout) {
+        Ok(result) => result.to_string(),
+        Err(e) => {
+            eprintln!("Error checking PMTUD to {}: {}", addr.ip(), e);
+            format!("{}:ERR", addr.ip())
+        }
+    }
+}
+
+fn format_reuse(addr: SocketAddr, cold: Duration, warm: Option<Duration>) -> String {
+    let cold_ms = cold.as_secs_f64() * 1000.0;
+    match warm {
+        Some(warm) => {
+            let warm_ms = warm.as_secs_f64() * 1000.0;
+            format!(
+                "{}:cold={:.1}ms,warm={:.1}ms,delta={:.1}ms",
+                addr.ip(),
+                cold_ms,
+                warm_ms,
+                cold_ms - warm_ms
+            )
+        }
+        None => format!("{}:cold={:.1}ms,warm=ERR", addr.ip(), cold_ms),
+    }
+}
+
+// Open a TCP connection bound to the interface and return it with the handshake time
+fn connect_on_interface(
+    binding: &Binding,
+    addr: SocketAddr,
+    timeout: Duration,
+) -> io::Result<(Socket, Duration)> {
     let domain = if addr.is_ipv4() {
         Domain::IPV4
     } else {
@@ -137,18 +1385,78 @@ fn measure_throughput(interface: &str, addr: SocketAddr) -> io::Result<(Duration
 
     let socket = Socket::new(domain, Type::STREAM, None)?;
 
-    // Bind the socket to the specified interface (Linux-only)
-    if let Err(e) = bind_socket_to_interface(&socket, interface) {
+    // Bind the socket to the specified interface
+    if let Err(e) = binding.apply(&socket, addr.is_ipv6()) {
         eprintln!(
             "Warning: Failed to bind to device '{}'. This might require root privileges. Error: {}",
-            interface, e
+            binding.interface, e
         );
         // Continue without binding, the OS will choose the interface.
     }
 
     let start = Instant::now();
-    socket.connect_timeout(&addr.into(), Duration::from_secs(5))?;
-    let rtt = start.elapsed();
+    socket.connect_timeout(&addr.into(), timeout)?;
+    Ok((socket, start.elapsed()))
+}
+
+// Host of a -s/--server entry without the port or brackets, for the Host header and TLS
+// server name
+fn host_of(server_str: &str) -> &str {
+    split_server(server_str)
+        .map(|(host, _)| host)
+        .unwrap_or(server_str)
+}
+
+// Run `transfer` on `streams` fresh connections to the target at once (real-transfer mode).
+// All connections are opened before any transfer starts so the streams overlap.
+fn measure_transfer(
+    binding: &Binding,
+    addr: SocketAddr,
+    server_str: &str,
+    streams: u32,
+    connect_timeout: Duration,
+    transfer: impl Fn(&Socket, &str) -> io::Result<transfer::TransferResult> + Sync,
+) -> Vec<io::Result<transfer::TransferResult>> {
+    let host = host_of(server_str);
+
+    let sockets: Vec<io::Result<Socket>> = (0..streams)
+        .map(|_| connect_on_interface(binding, addr, connect_timeout).map(|(socket, _)| socket))
+        .collect();
+
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = sockets
+            .into_iter()
+            .map(|socket| {
+                let transfer = &transfer;
+                scope.spawn(move || transfer(&socket?, host))
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|_| Err(io::Error::other("transfer thread panicked")))
+            })
+            .collect()
+    })
+}
+
+// Handshake and, with --compare-reuse, a request on the same connection, both finished by `until`
+fn measure_throughput(
+    binding: &Binding,
+    addr: SocketAddr,
+    compare_reuse: bool,
+    until: Instant,
+) -> io::Result<Measurement> {
+    let connect_timeout = until.saturating_duration_since(Instant::now());
+    if connect_timeout.is_zero() {
+        return Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "no time left to connect",
+        ));
+    }
+    let (socket, rtt) = connect_on_interface(binding, addr, connect_timeout)?;
 
     let fd = socket.as_raw_fd();
     // On most platforms (including macOS and Linux), SO_RCVBUF is an int
@@ -178,46 +1486,185 @@ fn measure_throughput(interface: &str, addr: SocketAddr) -> io::Result<(Duration
     #[cfg(not(target_os = "linux"))]
     let actual_window_size = window_size as u32;
 
-    Ok((rtt, actual_window_size))
+    let options = read_tcp_options(&socket);
+
+    let warm_rtt = if compare_reuse {
+        match measure_warm_rtt(&socket, addr, until) {
+            Ok(warm_rtt) => Some(warm_rtt),
+            Err(e) => {
+                eprintln!("Warning: Warm request to {} failed: {}", addr.ip(), e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    Ok(Measurement {
+        rtt,
+        window_size: actual_window_size,
+        options,
+        warm_rtt,
+    })
+}
+
+// Send a small request on the established connection and time the first response byte.
+// An HTTP server answers the HEAD, a TLS server answers with an alert or closes the
+// connection; either way the reply arrives one round trip later, without a handshake.
+fn measure_warm_rtt(socket: &Socket, addr: SocketAddr, until: Instant) -> io::Result<Duration> {
+    use std::io::Read;
+
+    let timeout = until.saturating_duration_since(Instant::now());
+    if timeout.is_zero() {
+        return Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "no time left for the request",
+        ));
+    }
+    socket.set_read_timeout(Some(timeout))?;
+    let request = format!("HEAD / HTTP/1.1\r\nHost: {}\r\n\r\n", addr.ip());
+
+    let mut stream = socket;
+    let start = Instant::now();
+    stream.write_all(request.as_bytes())?;
+
+    let mut buf = [0u8; 1];
+    match stream.read(&mut buf) {
+        Ok(_) => Ok(start.elapsed()),
+        // A reset still means the server answered
+        Err(e) if e.kind() == io::ErrorKind::ConnectionReset => Ok(start.elapsed()),
+        Err(e) => Err(e),
+    }
 }
 
+// Read the options negotiated during the handshake from TCP_INFO
 #[cfg(target_os = "linux")]
-fn bind_socket_to_interface(socket: &Socket, interface: &str) -> io::Result<()> {
-    // Use libc directly to set SO_BINDTODEVICE, since socket2 may not expose bind_device on all versions.
-    // Requires CAP_NET_RAW or root privileges on Linux.
-    let fd = socket.as_raw_fd();
-    let ifname = CString::new(interface)
-        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Interface name contains NUL"))?;
+fn read_tcp_options(socket: &Socket) -> Option<TcpOptions> {
+    // Bits of tcpi_options, see include/uapi/linux/tcp.h
+    const TCPI_OPT_TIMESTAMPS: u8 = 1;
+    const TCPI_OPT_SACK: u8 = 2;
+    const TCPI_OPT_WSCALE: u8 = 4;
+    const TCPI_OPT_ECN: u8 = 8;
 
-    let ret = unsafe {
-        libc::setsockopt(
-            fd,
-            libc::SOL_SOCKET,
-            libc::SO_BINDTODEVICE,
-            ifname.as_ptr() as *const libc::c_void,
-            ifname.as_bytes_with_nul().len() as libc::socklen_t,
+    let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+    let mut optlen = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+
+    let result = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut _ as *mut libc::c_void,
+            &mut optlen,
         )
     };
 
-    if ret == 0 {
-        Ok(())
-    } else {
-        Err(io::Error::last_os_error())
+    if result != 0 {
+        return None;
     }
+
+    Some(TcpOptions {
+        timestamps: info.tcpi_options & TCPI_OPT_TIMESTAMPS != 0,
+        sack: info.tcpi_options & TCPI_OPT_SACK != 0,
+        window_scaling: info.tcpi_options & TCPI_OPT_WSCALE != 0,
+        ecn: info.tcpi_options & TCPI_OPT_ECN != 0,
+    })
 }
 
 #[cfg(not(target_os = "linux"))]
-fn bind_socket_to_interface(_socket: &Socket, interface: &str) -> io::Result<()> {
-    // SO_BINDTODEVICE is not supported on non-Linux platforms.
-    // We can print a warning to the user.
-    // SO_BINDTODEVICE is not supported on non-Linux platforms.
-    // Print a one-time warning to the user to avoid spamming in the loop.
-    static ONCE: Once = Once::new();
-    ONCE.call_once(|| {
-            eprintln!(
-                "Warning: Binding to a specific interface ('{}') is only supported on Linux. This option will be ignored.",
-                interface
-            );
-        });
-    Ok(())
+fn read_tcp_options(_socket: &Socket) -> Option<TcpOptions> {
+    None
+}
+
+// Linux only requests ECN on outgoing connections when net.ipv4.tcp_ecn=1
+fn warn_if_ecn_not_requested() {
+    #[cfg(target_os = "linux")]
+    match std::fs::read_to_string("/proc/sys/net/ipv4/tcp_ecn") {
+        Ok(value) if value.trim() == "1" => {}
+        Ok(value) => eprintln!(
+            "Warning: net.ipv4.tcp_ecn={} does not request ECN on outgoing connections; \
+             set it to 1 to probe ECN negotiation.",
+            value.trim()
+        ),
+        Err(e) => eprintln!("Warning: Failed to read net.ipv4.tcp_ecn: {}", e),
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    eprintln!("Warning: TCP option probing is only supported on Linux.");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(server: &str) -> Option<(&str, Option<u16>)> {
+        split_server(server).ok()
+    }
+
+    #[test]
+    fn host_and_port() {
+        assert_eq!(split("example.com"), Some(("example.com", None)));
+        assert_eq!(split("example.com:8080"), Some(("example.com", Some(8080))));
+        assert_eq!(split("192.0.2.1"), Some(("192.0.2.1", None)));
+        assert_eq!(split("192.0.2.1:443"), Some(("192.0.2.1", Some(443))));
+    }
+
+    #[test]
+    fn ipv6_needs_brackets_for_a_port() {
+        assert_eq!(split("2001:db8::1"), Some(("2001:db8::1", None)));
+        assert_eq!(split("[2001:db8::1]"), Some(("2001:db8::1", None)));
+        assert_eq!(split("[2001:db8::1]:443"), Some(("2001:db8::1", Some(443))));
+        // Not an address, and ambiguous as host:port
+        assert_eq!(split("2001:db8::1::443"), None);
+        assert_eq!(split("fe80::1%eth0:443"), None);
+    }
+
+    #[test]
+    fn malformed() {
+        assert_eq!(split("[2001:db8::1"), None);
+        assert_eq!(split("[2001:db8::1]443"), None);
+        assert_eq!(split("example.com:http"), None);
+        assert_eq!(split("example.com:70000"), None);
+    }
+
+    #[test]
+    fn seconds() {
+        assert_eq!(parse_interval("2.5"), Ok(2.5));
+        assert_eq!(parse_non_negative("0"), Ok(0.0));
+        assert_eq!(parse_non_negative("1.5"), Ok(1.5));
+        for invalid in ["-1", "inf", "NaN", "x", ""] {
+            assert!(parse_interval(invalid).is_err(), "{}", invalid);
+            assert!(parse_non_negative(invalid).is_err(), "{}", invalid);
+        }
+        assert!(parse_interval("0").is_err());
+    }
+
+    #[test]
+    fn transfer_durations() {
+        let parse = |args: &[&str]| Args::try_parse_from([&["rtt-traffic-scan"], args].concat());
+        let args = parse(&["--transfer", "10", "--warmup", "0"]).unwrap();
+        assert_eq!((args.transfer, args.warmup), (Some(10.0), 0.0));
+        assert!(parse(&["--transfer=-1"]).is_err());
+        assert!(parse(&["--transfer", "0"]).is_err());
+        assert!(parse(&["--warmup=-1"]).is_err());
+        assert!(parse(&["--warmup", "inf"]).is_err());
+        assert_eq!(parse(&["--upload", "5"]).unwrap().upload, Some(5.0));
+        assert!(parse(&["--upload=-1"]).is_err());
+        assert!(parse(&["--upload", "NaN"]).is_err());
+    }
+
+    #[test]
+    fn phase_within_the_interval() {
+        let check = |args: &[&str]| {
+            let args = Args::try_parse_from([&["rtt-traffic-scan"], args].concat())?;
+            check_args(&args)
+        };
+        assert!(check(&["--phase", "0"]).is_ok());
+        assert!(check(&["--phase", "0.5"]).is_ok());
+        assert!(check(&["--interval", "10", "--phase", "9.5"]).is_ok());
+        assert!(check(&["--phase", "1"]).is_err());
+        assert!(check(&["--interval", "2", "--phase", "3"]).is_err());
+        assert!(check(&["--phase", "inf"]).is_err());
+        assert!(check(&["--phase=-0.5"]).is_err());
+    }
 }
@@ -0,0 +1,305 @@
+// NAT / middlebox idle timeout discovery (--nat-timeout).
+//
+// One connection per candidate idle time is opened at once and left silent. When its
+// idle time is up, a small request is sent: a reply of any kind means the mapping was
+// still there, while silence or a reset means the NAT or a middlebox dropped the idle
+// connection. The candidates double from --nat-timeout-min to --nat-timeout-max, then
+// the bracket between the last kept and first dropped connection is split into
+// --nat-timeout-steps further candidates. Servers closing idle connections themselves
+// (as HTTP servers do) cap what can be learned; that is reported separately. With
+// --reflect-token, each connection first sends a HEAD carrying the token, which makes the
+// built-in reflector keep it open while it idles.
+
+use crate::binding::Binding;
+use crate::CONNECT_TIMEOUT;
+use socket2::Socket;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+// How long a reply to the probe request is awaited
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+// Longest reply header read after authorizing
+const MAX_REPLY_HEADER: usize = 16 * 1024;
+// Ctrl+C is checked at least this often while connections are idle
+const POLL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy)]
+pub struct Config<'a> {
+    pub min: Duration,
+    pub max: Duration,
+    pub refine_steps: u32,
+    // Token of the built-in reflector, sent before the connection idles
+    pub token: Option<&'a str>,
+}
+
+#[derive(Debug)]
+enum Probe {
+    // The reply came back: the mapping survived the idle time
+    Kept,
+    // No reply, or a reset from the NAT / the server not knowing the connection any more
+    Dropped,
+    // The server closed the connection while idle, after this long
+    ServerClosed(Duration),
+    // Ctrl+C, or the connection could not be opened
+    Aborted,
+}
+
+#[derive(Debug, Default)]
+pub struct Outcome {
+    // Longest idle time a connection survived
+    pub kept: Option<Duration>,
+    // Shortest idle time after which a connection was dropped
+    pub dropped: Option<Duration>,
+    // Shortest idle time after which the server closed a connection
+    pub server_closed: Option<Duration>,
+}
+
+impl Outcome {
+    fn add(&mut self, idle: Duration, probe: &Probe) {
+        match probe {
+            Probe::Kept => self.kept = self.kept.max(Some(idle)),
+            Probe::Dropped => {
+                self.dropped = Some(self.dropped.map_or(idle, |dropped| dropped.min(idle)))
+            }
+            Probe::ServerClosed(after) => {
+                self.server_closed = Some(
+                    self.server_closed
+                        .map_or(*after, |closed| closed.min(*after)),
+                )
+            }
+            Probe::Aborted => {}
+        }
+    }
+}
+
+impl std::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let secs = |d: Duration| d.as_secs();
+        match (self.kept, self.dropped) {
+            (Some(kept), Some(dropped)) if kept < dropped => {
+                write!(f, "idle timeout {}-{}s", secs(kept), secs(dropped))?
+            }
+            (None, Some(dropped)) => write!(f, "idle timeout under {}s", secs(dropped))?,
+            (Some(kept), None) => write!(f, "no drop up to {}s", secs(kept))?,
+            // Drops at or below a kept idle time: the path is not consistent
+            (Some(kept), Some(dropped)) => write!(
+                f,
+                "inconsistent (kept {}s, dropped {}s)",
+                secs(kept),
+                secs(dropped)
+            )?,
+            (None, None) => write!(f, "no result")?,
+        }
+        if let Some(closed) = self.server_closed {
+            write!(
+                f,
+                ", server closes idle connections after {}s",
+                secs(closed)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+pub fn discover(
+    binding: &Binding,
+    addr: SocketAddr,
+    config: Config<'_>,
+    running: &AtomicBool,
+) -> Outcome {
+    let mut outcome = Outcome::default();
+
+    let mut candidates = Vec::new();
+    let mut idle = config.min;
+    while idle < config.max {
+        candidates.push(idle);
+        idle *= 2;
+    }
+    candidates.push(config.max);
+    run_round(
+        binding,
+        addr,
+        &candidates,
+        config.token,
+        running,
+        &mut outcome,
+    );
+
+    // Split the bracket between the last kept and the first dropped idle time
+    let Some(upper) = outcome.dropped else {
+        return outcome;
+    };
+    let lower = outcome.kept.unwrap_or(Duration::ZERO);
+    if config.refine_steps == 0 || lower >= upper {
+        return outcome;
+    }
+    let step = (upper - lower) / (config.refine_steps + 1);
+    if step < Duration::from_secs(1) {
+        return outcome;
+    }
+    let candidates: Vec<Duration> = (1..=config.refine_steps)
+        .map(|i| lower + step * i)
+        .collect();
+    if running.load(Ordering::SeqCst) {
+        run_round(
+            binding,
+            addr,
+            &candidates,
+            config.token,
+            running,
+            &mut outcome,
+        );
+    }
+    outcome
+}
+
+// Hold one connection per idle time in parallel and probe each when its time is up
+fn run_round(
+    binding: &Binding,
+    addr: SocketAddr,
+    idles: &[Duration],
+    token: Option<&str>,
+    running: &AtomicBool,
+    outcome: &mut Outcome,
+) {
+    println!(
+        "{}: holding {} idle connections to {} ({})",
+        binding.interface,
+        idles.len(),
+        addr,
+        idles
+            .iter()
+            .map(|idle| format!("{}s", idle.as_secs()))
+            .collect::<Vec<_>>()
+            .join(",")
+    );
+    let probes: Vec<Probe> = std::thread::scope(|scope| {
+        let handles: Vec<_> = idles
+            .iter()
+            .map(|&idle| scope.spawn(move || hold(binding, addr, idle, token, running)))
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap_or(Probe::Aborted))
+            .collect()
+    });
+    for (&idle, probe) in idles.iter().zip(&probes) {
+        println!(
+            "{}: {}s idle -> {:?}",
+            binding.interface,
+            idle.as_secs(),
+            probe
+        );
+        outcome.add(idle, probe);
+    }
+}
+
+fn hold(
+    binding: &Binding,
+    addr: SocketAddr,
+    idle: Duration,
+    token: Option<&str>,
+    running: &AtomicBool,
+) -> Probe {
+    let socket = match crate::connect_on_interface(binding, addr, CONNECT_TIMEOUT) {
+        Ok((socket, _)) => socket,
+        Err(e) => {
+            eprintln!(
+                "Error connecting to {} on {}: {}",
+                addr, binding.interface, e
+            );
+            return Probe::Aborted;
+        }
+    };
+    if let Some(token) = token {
+        if let Err(e) = authorize(&socket, addr, token) {
+            eprintln!(
+                "Error authorizing the connection to {} on {}: {}",
+                addr, binding.interface, e
+            );
+            return Probe::Aborted;
+        }
+    }
+    match wait_idle(&socket, idle, running) {
+        Ok(None) => {}
+        Ok(Some(probe)) => return probe,
+        // A reset while idle: some NATs notify the ends when they expire a mapping
+        Err(_) => return Probe::Dropped,
+    }
+    match send_probe(&socket, addr) {
+        Ok(true) => Probe::Kept,
+        Ok(false) | Err(_) => Probe::Dropped,
+    }
+}
+
+// Send a HEAD with the reflector's token and read its reply, so the reflector keeps the
+// connection open instead of expecting the request within its header timeout
+fn authorize(socket: &Socket, addr: SocketAddr, token: &str) -> io::Result<()> {
+    let request = format!(
+        "HEAD / HTTP/1.1\r\nHost: {}\r\nAuthorization: Bearer {}\r\n\r\n",
+        addr.ip(),
+        token
+    );
+    let mut stream = socket;
+    stream.write_all(request.as_bytes())?;
+    socket.set_read_timeout(Some(REPLY_TIMEOUT))?;
+    // The reply to a HEAD ends with its headers
+    let mut reply = Vec::new();
+    let mut buf = [0u8; 1];
+    while !reply.ends_with(b"\r\n\r\n") {
+        if stream.read(&mut buf)? == 0 || reply.len() >= MAX_REPLY_HEADER {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        reply.push(buf[0]);
+    }
+    if !reply.starts_with(b"HTTP/1.1 200") {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "the server refused the token",
+        ));
+    }
+    Ok(())
+}
+
+// Stay silent for `idle`; Some if the connection ended early or Ctrl+C was pressed
+fn wait_idle(socket: &Socket, idle: Duration, running: &AtomicBool) -> io::Result<Option<Probe>> {
+    let start = Instant::now();
+    let mut buf = [0u8; 1];
+    let mut stream = socket;
+    loop {
+        if !running.load(Ordering::SeqCst) {
+            return Ok(Some(Probe::Aborted));
+        }
+        let remaining = idle.saturating_sub(start.elapsed());
+        if remaining.is_zero() {
+            return Ok(None);
+        }
+        socket.set_read_timeout(Some(remaining.min(POLL)))?;
+        match stream.read(&mut buf) {
+            // Data or a close from the server before our request: the server gave up
+            Ok(_) => return Ok(Some(Probe::ServerClosed(start.elapsed()))),
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// True when anything comes back for the request, even a close or an error page
+fn send_probe(socket: &Socket, addr: SocketAddr) -> io::Result<bool> {
+    let request = format!("HEAD / HTTP/1.1\r\nHost: {}\r\n\r\n", addr.ip());
+    let mut stream = socket;
+    stream.write_all(request.as_bytes())?;
+    socket.set_read_timeout(Some(REPLY_TIMEOUT))?;
+    let mut buf = [0u8; 1];
+    match stream.read(&mut buf) {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+            Ok(false)
+        }
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,90 @@
+// Machine-readable results (--output json / --output csv).
+//
+// The bars are meant to be read in a terminal. With --output json every interface/target
+// pair of a cycle is written as one JSON object per line, with --output csv as one row under
+// a header printed once, so the results can be piped into other tools.
+
+use serde_json::json;
+
+// How a pair ended in a cycle
+#[derive(Debug, Default, Clone, Copy)]
+pub enum Status {
+    // Not measured before the cycle deadline
+    #[default]
+    Skipped,
+    // The target's address could not be resolved
+    Unresolved,
+    Error,
+    Ok,
+}
+
+impl Status {
+    fn as_str(self) -> &'static str {
+        match self {
+            Status::Skipped => "skipped",
+            Status::Unresolved => "unresolved",
+            Status::Error => "error",
+            Status::Ok => "ok",
+        }
+    }
+}
+
+// Figures of one pair; what a mode does not measure stays None
+#[derive(Debug, Default, Clone)]
+pub struct Figures {
+    pub status: Status,
+    // Handshake RTT, or the TCP connect time in the HTTPS modes
+    pub rtt_ms: Option<f64>,
+    // Receive window in bytes (--mode estimate)
+    pub window: Option<u32>,
+    // Window/RTT estimate, or the goodput in download-probe mode
+    pub throughput_mbps: Option<f64>,
+    // TLS handshake and time to first byte (--mode handshake)
+    pub tls_ms: Option<f64>,
+    pub ttfb_ms: Option<f64>,
+}
+
+pub const CSV_HEADER: &str =
+    "timestamp,interface,server,status,rtt_ms,window,throughput_mbps,tls_ms,ttfb_ms";
+
+// `timestamp` is the start of the cycle in Unix epoch seconds
+pub fn json_line(timestamp: f64, interface: &str, server: &str, figures: &Figures) -> String {
+    json!({
+        "timestamp": timestamp,
+        "interface": interface,
+        "server": server,
+        "status": figures.status.as_str(),
+        "rtt_ms": figures.rtt_ms,
+        "window": figures.window,
+        "throughput_mbps": figures.throughput_mbps,
+        "tls_ms": figures.tls_ms,
+        "ttfb_ms": figures.ttfb_ms,
+    })
+    .to_string()
+}
+
+// Figures a mode does not measure are left empty
+pub fn csv_line(timestamp: f64, interface: &str, server: &str, figures: &Figures) -> String {
+    let number = |v: Option<f64>| v.map(|v| format!("{:.3}", v)).unwrap_or_default();
+    [
+        format!("{:.3}", timestamp),
+        csv_field(interface),
+        csv_field(server),
+        figures.status.as_str().to_string(),
+        number(figures.rtt_ms),
+        figures.window.map(|w| w.to_string()).unwrap_or_default(),
+        number(figures.throughput_mbps),
+        number(figures.tls_ms),
+        number(figures.ttfb_ms),
+    ]
+    .join(",")
+}
+
+// Quote fields with separators or quotes, e.g. probe URLs with a query string
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
@@ -0,0 +1,217 @@
+// Path MTU discovery check (--pmtud, Linux only).
+//
+// Tunneled WANs (PPPoE, DS-Lite, WireGuard) carry less than the interface MTU, and
+// when the ICMP "fragmentation needed" messages are filtered somewhere, full-sized
+// segments silently vanish while handshakes and small requests still work. On a fresh
+// connection with DF set (IP_PMTUDISC_DO), a small POST header is sent first, then an
+// MSS-sized write (one full-sized segment) and an MTU+1-sized write (more than one
+// packet can carry). Each write must be acknowledged in time: with working PMTUD the
+// kernel learns the smaller path MTU and resends, with broken PMTUD it never gets an
+// ACK. Servers that reject the request (TLS ports) may reset before the check is done,
+// which is reported as inconclusive; the built-in reflector or an HTTP server reads it.
+
+use crate::binding::Binding;
+use socket2::Socket;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy)]
+enum Outcome {
+    // Every write was acknowledged
+    Delivered,
+    // This write was not acknowledged in time
+    Lost(&'static str),
+    // The server reset the connection before this write was acknowledged
+    Reset(&'static str),
+}
+
+#[derive(Debug)]
+pub struct PmtudResult {
+    addr: SocketAddr,
+    mss: usize,
+    // Path MTU the kernel assumed when the check started, and after it
+    mtu: usize,
+    path_mtu: usize,
+    outcome: Outcome,
+}
+
+impl PmtudResult {
+    // None when the server reset the connection first
+    pub fn ok(&self) -> Option<bool> {
+        match self.outcome {
+            Outcome::Delivered => Some(true),
+            Outcome::Lost(_) => Some(false),
+            Outcome::Reset(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for PmtudResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.ok() {
+            Some(ok) => write!(f, "{}:pmtud_ok={}", self.addr.ip(), ok)?,
+            None => write!(f, "{}:pmtud_ok=n/a", self.addr.ip())?,
+        }
+        write!(f, ",mss={},mtu={}", self.mss, self.mtu)?;
+        // The kernel learned a smaller path MTU from an ICMP message
+        if self.path_mtu < self.mtu {
+            write!(f, ",pmtu={}", self.path_mtu)?;
+        }
+        match self.outcome {
+            Outcome::Delivered => Ok(()),
+            Outcome::Lost(write) => write!(f, ",lost={}", write),
+            Outcome::Reset(write) => write!(f, ",reset={}", write),
+        }
+    }
+}
+
+// Run the check on a new connection; `timeout` bounds the wait for each write's ACK
+#[cfg(target_os = "linux")]
+pub fn probe(binding: &Binding, addr: SocketAddr, timeout: Duration) -> io::Result<PmtudResult> {
+    let (socket, _) = crate::connect_on_interface(binding, addr, timeout)?;
+    socket.set_nodelay(true)?;
+    let (level, mtu_discover, do_not_fragment, mtu_option) = if addr.is_ipv4() {
+        (
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_DO,
+            libc::IP_MTU,
+        )
+    } else {
+        (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_DO,
+            libc::IPV6_MTU,
+        )
+    };
+    set_int(&socket, level, mtu_discover, do_not_fragment)?;
+    let mss = get_int(&socket, libc::IPPROTO_TCP, libc::TCP_MAXSEG)? as usize;
+    let mtu = get_int(&socket, level, mtu_option)? as usize;
+
+    let header = format!(
+        "POST / HTTP/1.1\r\nHost: {}\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\n\r\n",
+        addr.ip(),
+        mss + mtu + 1
+    );
+    let mut outcome = Outcome::Delivered;
+    for (write, len) in [("header", 0), ("mss", mss), ("mtu+1", mtu + 1)] {
+        let sent = if len == 0 {
+            send_all(&socket, header.as_bytes())
+        } else {
+            send_all(&socket, &vec![0u8; len])
+        };
+        let acked = sent.and_then(|()| wait_acked(&socket, timeout));
+        match acked {
+            Ok(true) => {}
+            Ok(false) => {
+                outcome = Outcome::Lost(write);
+                break;
+            }
+            Err(_) => {
+                outcome = Outcome::Reset(write);
+                break;
+            }
+        }
+    }
+    let path_mtu = get_int(&socket, level, mtu_option).map_or(mtu, |mtu| mtu as usize);
+    Ok(PmtudResult {
+        addr,
+        mss,
+        mtu,
+        path_mtu,
+        outcome,
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn probe(_binding: &Binding, _addr: SocketAddr, _timeout: Duration) -> io::Result<PmtudResult> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "the PMTUD check is only supported on Linux",
+    ))
+}
+
+// Without SIGPIPE when the server has already reset the connection
+#[cfg(target_os = "linux")]
+fn send_all(socket: &Socket, mut buf: &[u8]) -> io::Result<()> {
+    while !buf.is_empty() {
+        let sent = socket.send_with_flags(buf, libc::MSG_NOSIGNAL)?;
+        buf = &buf[sent..];
+    }
+    Ok(())
+}
+
+// true once the send queue is empty, i.e. every byte written was acknowledged
+#[cfg(target_os = "linux")]
+fn wait_acked(socket: &Socket, timeout: Duration) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        // A reset also empties the send queue, so look for it first
+        if let Some(e) = socket.take_error()? {
+            return Err(e);
+        }
+        let mut queued: libc::c_int = 0;
+        if unsafe { libc::ioctl(socket.as_raw_fd(), libc::TIOCOUTQ, &mut queued) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if queued == 0 {
+            return Ok(true);
+        }
+        if Instant::now() >= deadline {
+            return Ok(false);
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn get_int(socket: &Socket, level: libc::c_int, name: libc::c_int) -> io::Result<libc::c_int> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &mut value as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret == 0 {
+        Ok(value)
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_int(
+    socket: &Socket,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
@@ -0,0 +1,30 @@
+// Bounded pool of scoped threads for the measurements of one cycle (--concurrency).
+//
+// Measuring every interface/server pair one after another makes a cycle as long as the sum of
+// its handshakes, and the results of the first and last pair are seconds apart. Every pair is
+// a job instead; up to --concurrency of them run at once, so the cycle takes about as long as
+// its slowest pair and all pairs are measured within the same second.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+// Run `job` on every item with at most `limit` running at once; results keep the item order
+pub fn run<T: Sync, R: Send>(items: &[T], limit: usize, job: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    let next = AtomicUsize::new(0);
+    let results: Vec<Mutex<Option<R>>> = items.iter().map(|_| Mutex::new(None)).collect();
+    std::thread::scope(|scope| {
+        for _ in 0..limit.clamp(1, items.len().max(1)) {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(item) = items.get(index) else {
+                    break;
+                };
+                *results[index].lock().unwrap() = Some(job(item));
+            });
+        }
+    });
+    results
+        .into_iter()
+        .map(|result| result.into_inner().unwrap().expect("every job runs once"))
+        .collect()
+}
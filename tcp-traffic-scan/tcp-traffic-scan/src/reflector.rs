@@ -0,0 +1,426 @@
+// Built-in reflector for real-transfer mode (--reflect).
+//
+// Run on a host at the far end of the link to get a target for --transfer and
+// --upload without a web server: POST / PUT bodies are read and discarded, and any
+// other request is answered with zeros until the client closes the connection or
+// --reflect-max-secs is up. A HEAD carrying the token keeps the connection open, and it
+// may then idle without a limit before its next request, so it also serves as a
+// --nat-timeout target.
+//
+// Transfers need the --reflect-token the reflector was started with, sent as
+// "Authorization: Bearer <token>"; anything else gets a 401, so the reflector cannot be
+// used to flood a link by whoever finds it. HEAD and small bodies (the --pmtud probe) are
+// answered without it, but such connections are closed after one request. Until a request
+// carried the token, everything it sends has to arrive within HEADER_TIMEOUT of connecting,
+// however slowly it trickles in, and its headers have to fit in HEADER_LIMIT bytes. At most --reflect-max-connections are served at once, further
+// connections get a 503.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+const DOWNLOAD_CHUNK: usize = 64 * 1024;
+// Bodies up to this size are read without the token, so the PMTUD probe is not reset
+// (MSS + MTU + 1 bytes, about 128 KiB on loopback)
+const UNAUTHORIZED_BODY_LIMIT: u64 = 128 * 1024;
+// Request line and headers together; longer requests are dropped
+const HEADER_LIMIT: u64 = 16 * 1024;
+// Time from connecting a connection has to send its request before it carried the token
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Config {
+    pub token: String,
+    pub max_connections: usize,
+    // Longest a single download or upload is served
+    pub max_duration: Duration,
+}
+
+// Releases a connection slot when the connection's thread ends
+struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Accept connections until the process is stopped, one thread each
+pub fn serve(addr: SocketAddr, config: Config) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    info!(
+        "Reflector listening on {} (at most {} connections, {}s per transfer)",
+        addr,
+        config.max_connections,
+        config.max_duration.as_secs()
+    );
+    let config = Arc::new(config);
+    let active = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("Error accepting a connection: {}", e);
+                continue;
+            }
+        };
+        let peer = stream
+            .peer_addr()
+            .map_or_else(|_| "unknown".to_string(), |peer| peer.to_string());
+        if active.fetch_add(1, Ordering::SeqCst) >= config.max_connections {
+            active.fetch_sub(1, Ordering::SeqCst);
+            warn!("Refusing {}: too many connections", peer);
+            let _ = (&stream).write_all(
+                b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            );
+            continue;
+        }
+        let slot = Slot(Arc::clone(&active));
+        let config = Arc::clone(&config);
+        let spawned = std::thread::Builder::new()
+            .name("reflector".to_string())
+            .spawn(move || {
+                let _slot = slot;
+                if let Err(e) = handle(stream, &peer, &config) {
+                    warn!("Error serving {}: {}", peer, e);
+                }
+            });
+        if let Err(e) = spawned {
+            error!("Error starting a reflector thread: {}", e);
+        }
+    }
+    Ok(())
+}
+
+struct Request {
+    method: String,
+    chunked: bool,
+    content_length: Option<u64>,
+    authorized: bool,
+}
+
+fn handle(stream: TcpStream, peer: &str, config: &Config) -> io::Result<()> {
+    // Until a request carried the token the connection has to send it promptly
+    let mut reader = BufReader::new(Timed {
+        stream: &stream,
+        until: Some(Instant::now() + HEADER_TIMEOUT),
+    });
+    while let Some(request) = read_request(&mut reader, config)? {
+        // The token lifts the limit for the transfer and the requests after it
+        if request.authorized {
+            reader.get_mut().until = None;
+        }
+        if !respond(&stream, &mut reader, peer, config, &request)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// Reads from the connection that fail once `until` has passed, however slowly the bytes
+// arrive; a read timeout alone only bounds the wait for each read
+struct Timed<'a> {
+    stream: &'a TcpStream,
+    until: Option<Instant>,
+}
+
+impl Read for Timed<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(until) = self.until else {
+            return self.stream.read(buf);
+        };
+        let late = || io::Error::new(io::ErrorKind::TimedOut, "request not received in time");
+        let left = until.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(late());
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        match self.stream.read(buf) {
+            // The read timeout (WouldBlock on Unix) ran out with the time left
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                Err(late())
+            }
+            result => result,
+        }
+    }
+}
+
+// The request line and headers; None once the client closed the connection
+fn read_request(reader: &mut impl BufRead, config: &Config) -> io::Result<Option<Request>> {
+    let mut head = reader.take(HEADER_LIMIT);
+    let mut request_line = String::new();
+    if head.read_line(&mut request_line)? == 0 {
+        return Ok(None);
+    }
+    let mut request = Request {
+        method: request_line
+            .split_whitespace()
+            .next()
+            .unwrap_or("")
+            .to_string(),
+        chunked: false,
+        content_length: None,
+        authorized: false,
+    };
+    let mut line = String::new();
+    loop {
+        if head.limit() == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request headers too large",
+            ));
+        }
+        line.clear();
+        if head.read_line(&mut line)? <= 2 {
+            break;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("transfer-encoding") {
+            request.chunked = value.to_ascii_lowercase().contains("chunked");
+        } else if name.eq_ignore_ascii_case("content-length") {
+            request.content_length = value.parse().ok();
+        } else if name.eq_ignore_ascii_case("authorization") {
+            request.authorized = value
+                .strip_prefix("Bearer ")
+                .is_some_and(|token| constant_time_eq(token.as_bytes(), config.token.as_bytes()));
+        }
+    }
+    Ok(Some(request))
+}
+
+// Answer one request; true if the connection stays open for the next one
+fn respond(
+    stream: &TcpStream,
+    reader: &mut BufReader<Timed>,
+    peer: &str,
+    config: &Config,
+    request: &Request,
+) -> io::Result<bool> {
+    let Request {
+        method,
+        chunked,
+        content_length,
+        authorized,
+    } = request;
+    let start = Instant::now();
+    let mut stream = stream;
+    if !authorized && method != "HEAD" {
+        // Drain a small body first; closing with unread data would reset the connection
+        if !chunked && content_length.unwrap_or(0) <= UNAUTHORIZED_BODY_LIMIT {
+            io::copy(
+                &mut reader.take(content_length.unwrap_or(0)),
+                &mut io::sink(),
+            )?;
+        }
+        warn!("Refusing {}: missing or wrong token", peer);
+        write!(
+            stream,
+            "HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        )?;
+        return Ok(false);
+    }
+    if method == "HEAD" {
+        if !authorized {
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nConnection: close\r\n\r\n"
+            )?;
+            return Ok(false);
+        }
+        // An authorized connection may idle without a limit before its next request
+        stream.set_read_timeout(None)?;
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: 0\r\n\r\n"
+        )?;
+        return Ok(true);
+    }
+    stream.set_read_timeout(Some(config.max_duration))?;
+    stream.set_write_timeout(Some(config.max_duration))?;
+    let until = start + config.max_duration;
+    if method == "POST" || method == "PUT" {
+        let mut reader = Deadline {
+            inner: reader,
+            until,
+        };
+        let (received, complete) = if *chunked {
+            discard_chunked(&mut reader)?
+        } else {
+            let expected = content_length.unwrap_or(0);
+            let received = io::copy(&mut (&mut reader).take(expected), &mut io::sink())?;
+            (received, received == expected)
+        };
+        report(peer, "received", received, start);
+        // Uploads from --upload stop mid-body and close, nobody reads the reply then
+        if complete {
+            let body = format!("received {} bytes\n", received);
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )?;
+        }
+    } else {
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nConnection: close\r\n\r\n"
+        )?;
+        let chunk = vec![0u8; DOWNLOAD_CHUNK];
+        let mut sent: u64 = 0;
+        // Ends with an error once the client closes the connection
+        while Instant::now() < until && stream.write_all(&chunk).is_ok() {
+            sent += chunk.len() as u64;
+        }
+        report(peer, "sent", sent, start);
+    }
+    Ok(false)
+}
+
+// Reads end (as if the client had closed) once the transfer has run for its limit
+struct Deadline<R> {
+    inner: R,
+    until: Instant,
+}
+
+impl<R: Read> Read for Deadline<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if Instant::now() >= self.until {
+            return Ok(0);
+        }
+        match self.inner.read(buf) {
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                Ok(0)
+            }
+            result => result,
+        }
+    }
+}
+
+impl<R: BufRead> BufRead for Deadline<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if Instant::now() >= self.until {
+            return Ok(&[]);
+        }
+        match self.inner.fill_buf() {
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                Ok(&[])
+            }
+            result => result,
+        }
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.inner.consume(amount)
+    }
+}
+
+// Read a chunked body to the end; the bool is false when the client closed mid-body
+fn discard_chunked(reader: &mut impl BufRead) -> io::Result<(u64, bool)> {
+    let mut received = 0;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok((received, false));
+        }
+        // Chunk extensions after ';' are ignored
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = u64::from_str_radix(size, 16)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid chunk size"))?;
+        if size == 0 {
+            // Trailers up to the empty line
+            loop {
+                line.clear();
+                if reader.read_line(&mut line)? <= 2 {
+                    return Ok((received, true));
+                }
+            }
+        }
+        let copied = io::copy(&mut reader.take(size), &mut io::sink())?;
+        received += copied;
+        if copied < size {
+            return Ok((received, false));
+        }
+        line.clear();
+        reader.read_line(&mut line)?;
+    }
+}
+
+fn report(peer: &str, verb: &str, bytes: u64, start: Instant) {
+    let elapsed = start.elapsed().as_secs_f64();
+    let mbps = if elapsed > 0.0 {
+        bytes as f64 * 8.0 / elapsed / 1_000_000.0
+    } else {
+        0.0
+    };
+    info!(
+        "{}: {} {} bytes in {:.1}s ({:.0}Mbps)",
+        peer, verb, bytes, elapsed, mbps
+    );
+}
+
+// Compare every byte of equal-length tokens, so the time taken does not tell how much matched.
+// Kept identical to traffic_scan_core::auth::constant_time_eq: this tool has no async runtime
+// and does not depend on traffic-scan-core (see buildinfo.rs), so change both together.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slow_headers_are_cut_off_at_the_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let start = Instant::now();
+            let config = Config {
+                token: "token".to_string(),
+                max_connections: 1,
+                max_duration: Duration::from_secs(60),
+            };
+            let result = handle(stream, "test", &config);
+            (result, start.elapsed())
+        });
+
+        // One byte every 1.5s, each well within a read timeout of HEADER_TIMEOUT
+        let mut client = TcpStream::connect(addr).unwrap();
+        let request = b"GET / HTTP/1.1\r\nX-Slow: aaaaaaaaaaaaaaaa\r\n\r\n";
+        for byte in request {
+            if client.write_all(&[*byte]).is_err() || server.is_finished() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(1500));
+        }
+
+        let (result, elapsed) = server.join().unwrap();
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert!(
+            elapsed >= HEADER_TIMEOUT && elapsed < HEADER_TIMEOUT + Duration::from_secs(2),
+            "closed after {:?}",
+            elapsed
+        );
+        // Closed without an answer
+        let mut reply = Vec::new();
+        let _ = client.read_to_end(&mut reply);
+        assert!(reply.is_empty());
+    }
+}
@@ -0,0 +1,68 @@
+// Simulated measurements for --simulate.
+//
+// Handshakes are replaced by RTTs drawn from shared_sim's scenario (SIMULATE_RTT_MS,
+// SIMULATE_LOSS), so the loop, deadlines, history and summary can be exercised without
+// binding to interfaces or sending traffic. A lost handshake waits out the connect timeout
+// like a real one.
+
+use crate::Measurement;
+use shared_sim::{Distribution, Scenario};
+use std::io;
+use std::time::Duration;
+
+pub struct Simulation {
+    scenario: Scenario,
+    // Receive window reported for each connection (SIMULATE_WINDOW_BYTES)
+    window_bytes: Distribution,
+}
+
+impl Simulation {
+    pub fn from_env() -> Self {
+        Self {
+            scenario: Scenario::from_env(),
+            window_bytes: Distribution::from_env(
+                "SIMULATE_WINDOW_BYTES",
+                Distribution::Constant(131072.0),
+            ),
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "{}, window_bytes={}",
+            self.scenario.summary(),
+            self.window_bytes
+        )
+    }
+
+    pub fn measure(
+        &self,
+        compare_reuse: bool,
+        connect_timeout: Duration,
+    ) -> io::Result<Measurement> {
+        let rtt = match self.scenario.sample_rtt() {
+            Some(rtt_ms) => Duration::from_secs_f64(rtt_ms / 1000.0),
+            None => connect_timeout,
+        };
+        if rtt >= connect_timeout {
+            std::thread::sleep(connect_timeout);
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "simulated handshake timed out",
+            ));
+        }
+        std::thread::sleep(rtt);
+
+        let warm_rtt = compare_reuse
+            .then(|| self.scenario.sample_rtt())
+            .flatten()
+            .map(|rtt_ms| Duration::from_secs_f64(rtt_ms / 1000.0));
+
+        Ok(Measurement {
+            rtt,
+            window_size: self.scenario.sample(&self.window_bytes) as u32,
+            options: None,
+            warm_rtt,
+        })
+    }
+}
@@ -0,0 +1,250 @@
+// Real-transfer mode: download or upload over HTTP for a fixed time and report goodput.
+//
+// The first seconds of a transfer are spent in slow start, so a short test
+// understates what the link can carry. Bytes received during the warm-up are
+// reported in the overall figure but excluded from the steady-state one.
+//
+// Downloads count only the response body, and fail unless the server answers 200.
+// Uploads count the bytes the server has acknowledged rather than the bytes written,
+// since the send buffer would otherwise be counted as delivered.
+
+use crate::https;
+use socket2::Socket;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
+
+pub struct TransferResult {
+    // Goodput over the whole transfer, in Mbps
+    pub overall_mbps: f64,
+    // Goodput after the warm-up, in Mbps; None if the transfer ended before it
+    pub steady_mbps: Option<f64>,
+}
+
+fn mbps(bytes: u64, elapsed: Duration) -> f64 {
+    if elapsed.as_secs_f64() > 0.0 {
+        bytes as f64 * 8.0 / elapsed.as_secs_f64() / 1_000_000.0
+    } else {
+        0.0
+    }
+}
+
+// "Authorization" header carrying the reflector's token, if any
+fn authorization(token: Option<&str>) -> String {
+    token
+        .map(|token| format!("Authorization: Bearer {}\r\n", token))
+        .unwrap_or_default()
+}
+
+// Request `path` from `host` on an already-connected socket and read for `duration`
+pub fn run(
+    socket: &Socket,
+    host: &str,
+    path: &str,
+    token: Option<&str>,
+    duration: Duration,
+    warmup: Duration,
+) -> io::Result<TransferResult> {
+    socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\n{}Connection: close\r\n\r\n",
+        path,
+        host,
+        authorization(token)
+    );
+
+    let mut stream = socket;
+    stream.write_all(request.as_bytes())?;
+
+    let start = Instant::now();
+    let mut total_bytes: u64 = 0;
+    // Bytes and time at the first read past the warm-up
+    let mut warm: Option<(u64, Instant)> = None;
+    let mut buf = vec![0u8; 64 * 1024];
+    // Status line and headers until they are complete
+    let mut head: Option<Vec<u8>> = Some(Vec::new());
+
+    while start.elapsed() < duration {
+        let mut n = match stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                continue
+            }
+            Err(e) => return Err(e),
+        };
+        if let Some(received) = &mut head {
+            received.extend_from_slice(&buf[..n]);
+            let Some(end) = received.windows(4).position(|w| w == b"\r\n\r\n") else {
+                if received.len() > https::MAX_HEADER_BYTES {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "response headers too large",
+                    ));
+                }
+                continue;
+            };
+            https::check_status(&received[..end])?;
+            // Body bytes that arrived with the headers
+            n = received.len() - end - 4;
+            head = None;
+        }
+        if warm.is_none() && start.elapsed() >= warmup {
+            warm = Some((total_bytes, Instant::now()));
+        }
+        total_bytes += n as u64;
+    }
+
+    let end = Instant::now();
+    if head.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "no complete response headers",
+        ));
+    }
+    if total_bytes == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "server sent no data",
+        ));
+    }
+
+    Ok(TransferResult {
+        overall_mbps: mbps(total_bytes, end - start),
+        steady_mbps: warm
+            .map(|(warm_bytes, warm_at)| mbps(total_bytes - warm_bytes, end - warm_at)),
+    })
+}
+
+// Payload bytes per chunk of an upload
+const UPLOAD_CHUNK: usize = 64 * 1024;
+
+// POST a chunked body of zeros to `path` on an already-connected socket for `duration`
+pub fn upload(
+    socket: &Socket,
+    host: &str,
+    path: &str,
+    token: Option<&str>,
+    duration: Duration,
+    warmup: Duration,
+) -> io::Result<TransferResult> {
+    socket.set_write_timeout(Some(Duration::from_secs(1)))?;
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/octet-stream\r\nTransfer-Encoding: chunked\r\n{}Connection: close\r\n\r\n",
+        path,
+        host,
+        authorization(token)
+    );
+
+    let mut stream = socket;
+    stream.write_all(request.as_bytes())?;
+    // Written cyclically; the chunk framing is counted as payload (9 bytes in 64 KiB)
+    let mut chunk = format!("{:x}\r\n", UPLOAD_CHUNK).into_bytes();
+    chunk.resize(chunk.len() + UPLOAD_CHUNK, 0);
+    chunk.extend_from_slice(b"\r\n");
+
+    let start = Instant::now();
+    // Body bytes handed to the kernel
+    let mut written: u64 = 0;
+    let mut offset = 0;
+    // Acknowledged bytes and time at the first write past the warm-up
+    let mut warm: Option<(u64, Instant)> = None;
+
+    while start.elapsed() < duration {
+        match stream.write(&chunk[offset..]) {
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+            Ok(n) => {
+                written += n as u64;
+                offset = (offset + n) % chunk.len();
+            }
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+            }
+            Err(e) => return Err(e),
+        }
+        if warm.is_none() && start.elapsed() >= warmup {
+            warm = Some((acknowledged(socket, written)?, Instant::now()));
+        }
+    }
+
+    let end = Instant::now();
+    let total_bytes = acknowledged(socket, written)?;
+    if total_bytes == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "server acknowledged no data",
+        ));
+    }
+
+    Ok(TransferResult {
+        overall_mbps: mbps(total_bytes, end - start),
+        steady_mbps: warm
+            .map(|(warm_bytes, warm_at)| mbps(total_bytes - warm_bytes, end - warm_at)),
+    })
+}
+
+// Written bytes minus those still in the send queue (unsent or unacknowledged)
+#[cfg(target_os = "linux")]
+fn acknowledged(socket: &Socket, written: u64) -> io::Result<u64> {
+    let mut queued: libc::c_int = 0;
+    // SIOCOUTQ shares its number with TIOCOUTQ
+    if unsafe { libc::ioctl(socket.as_raw_fd(), libc::TIOCOUTQ, &mut queued) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(written.saturating_sub(queued as u64))
+}
+
+// Without SIOCOUTQ the send buffer is counted as delivered
+#[cfg(not(target_os = "linux"))]
+fn acknowledged(_socket: &Socket, written: u64) -> io::Result<u64> {
+    Ok(written)
+}
+
+// Sum the goodput of parallel streams; the steady-state figure needs every
+// successful stream to have passed the warm-up
+pub fn aggregate(streams: &[io::Result<TransferResult>]) -> io::Result<TransferResult> {
+    let ok: Vec<&TransferResult> = streams.iter().filter_map(|r| r.as_ref().ok()).collect();
+    if ok.is_empty() {
+        return Err(io::Error::other("all streams failed"));
+    }
+    Ok(TransferResult {
+        overall_mbps: ok.iter().map(|r| r.overall_mbps).sum(),
+        steady_mbps: ok.iter().map(|r| r.steady_mbps).sum(),
+    })
+}
+
+// Per-stream goodput, e.g. "1.1.1.1:#1=120Mbps,#2=118Mbps,#3=ERR"
+pub fn format_streams(addr: SocketAddr, streams: &[io::Result<TransferResult>]) -> String {
+    let parts: Vec<String> = streams
+        .iter()
+        .enumerate()
+        .map(|(i, result)| match result {
+            Ok(result) => format!("#{}={:.0}Mbps", i + 1, result.overall_mbps),
+            Err(_) => format!("#{}=ERR", i + 1),
+        })
+        .collect();
+    format!("{}:{}", addr.ip(), parts.join(","))
+}
+
+pub fn format_result(addr: SocketAddr, result: &io::Result<TransferResult>) -> String {
+    match result {
+        Ok(TransferResult {
+            overall_mbps,
+            steady_mbps: Some(steady_mbps),
+        }) => format!(
+            "{}:overall={:.0}Mbps,steady={:.0}Mbps",
+            addr.ip(),
+            overall_mbps,
+            steady_mbps
+        ),
+        Ok(TransferResult {
+            overall_mbps,
+            steady_mbps: None,
+        }) => format!("{}:overall={:.0}Mbps,steady=N/A", addr.ip(), overall_mbps),
+        Err(_) => format!("{}:ERR", addr.ip()),
+    }
+}
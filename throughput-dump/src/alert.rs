@@ -0,0 +1,375 @@
+// WAN 品質の劣化を検知して Webhook / コマンドを実行するアラート
+//
+// Alertmanager が止まっていても復旧処理を動かせるよう、計算結果（ThroughputReport）に対して
+// ALERT_RULES_FILE の条件をこのプロセス内で評価する。条件が for_secs 秒続いたら firing、
+// 条件を満たさなくなったら resolved として、ルールごとの webhook / exec に通知する。
+
+use anyhow::{Context, Result};
+use log::{error, info, warn};
+use prometheus::{GaugeVec, Opts, Registry};
+use serde::{Deserialize, Serialize};
+use shared_http::HttpClient;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::sink::ThroughputReport;
+
+// exec のコマンドがこれ以上かかったら打ち切る
+const EXEC_TIMEOUT: Duration = Duration::from_secs(60);
+// 現れなくなった系列を 0 として評価し続ける最短の期間
+const MIN_RETENTION_MS: i64 = 300_000;
+
+// 評価対象の系列
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Metric {
+    // インターフェースごとの合計（interface）
+    ThroughputdumpTotal,
+    // リモートごと（interface, remote_ip）
+    Throughputdump,
+    // 端末ごと（local_ip, interface）
+    DeviceThroughput,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Condition {
+    // 直近 window_secs 秒の平均から percent % 以上下がった
+    Drop { percent: f64, window_secs: u64 },
+    // 値がこれを下回った
+    Below { value: f64 },
+    // 値がこれを上回った
+    Above { value: f64 },
+}
+
+#[derive(Debug, Deserialize)]
+struct Rule {
+    name: String,
+    metric: Metric,
+    // 完全一致で絞り込むラベル（省略したラベルはすべての値）
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+    condition: Condition,
+    // 条件がこの秒数続いたら firing にする
+    #[serde(default)]
+    for_secs: u64,
+    // 通知を JSON で POST する URL
+    webhook: Option<String>,
+    // 実行するコマンドと引数（通知の内容は環境変数で渡す）
+    #[serde(default)]
+    exec: Vec<String>,
+    // 条件を満たさなくなったときも通知する
+    #[serde(default = "default_true")]
+    notify_resolved: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+// (ルールの添字, 系列のラベル)
+type SeriesKey = (usize, BTreeMap<String, String>);
+
+// 系列ごとの評価の状態
+#[derive(Default)]
+struct SeriesState {
+    // Drop の平均に使う (時刻ミリ秒, 値)
+    history: VecDeque<(i64, f64)>,
+    first_seen_ms: i64,
+    // 最後に計算結果に現れた時刻
+    last_seen_ms: i64,
+    // 条件を満たし始めた時刻
+    pending_since_ms: Option<i64>,
+    firing: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Notification {
+    rule: String,
+    // firing / resolved
+    state: &'static str,
+    metric: Metric,
+    labels: BTreeMap<String, String>,
+    value: f64,
+    threshold: f64,
+    timestamp_ms: i64,
+}
+
+pub struct AlertRules {
+    rules: Vec<Rule>,
+    states: Mutex<HashMap<SeriesKey, SeriesState>>,
+    http: Arc<HttpClient>,
+    firing_gauge: GaugeVec,
+}
+
+impl AlertRules {
+    // ALERT_RULES_FILE が無ければ None
+    pub fn from_env(registry: &Registry) -> Option<Self> {
+        let path = shared_config::var("ALERT_RULES_FILE").ok()?;
+        let rules = match load_rules(&path) {
+            Ok(rules) => rules,
+            Err(e) => {
+                error!("Alerting disabled: {:#}", e);
+                return None;
+            }
+        };
+        for rule in &rules {
+            info!(
+                "Alert rule {}: {:?} {:?} {:?} for {}s",
+                rule.name, rule.metric, rule.labels, rule.condition, rule.for_secs
+            );
+            if rule.webhook.is_none() && rule.exec.is_empty() {
+                warn!("Alert rule {} has neither webhook nor exec", rule.name);
+            }
+        }
+
+        let firing_gauge = GaugeVec::new(
+            Opts::new(
+                "throughputdump_alert_firing",
+                "Series currently firing per alert rule",
+            )
+            .const_label("job", "throughputdump"),
+            &["rule"],
+        )
+        .unwrap();
+        for rule in &rules {
+            firing_gauge.with_label_values(&[&rule.name]).set(0.0);
+        }
+        registry.register(Box::new(firing_gauge.clone())).unwrap();
+
+        Some(Self {
+            rules,
+            states: Mutex::new(HashMap::new()),
+            http: Arc::new(HttpClient::from_env()),
+            firing_gauge,
+        })
+    }
+
+    // 1 周期分の計算結果で全ルールを評価する
+    pub fn evaluate(&self, report: &ThroughputReport) {
+        let now_ms = report.timestamp_ms;
+        let mut states = self.states.lock().unwrap();
+        for (index, rule) in self.rules.iter().enumerate() {
+            // 値と、計算結果に現れたかどうか
+            let mut current: HashMap<BTreeMap<String, String>, (f64, bool)> =
+                series(report, rule.metric)
+                    .into_iter()
+                    .filter(|(labels, _)| {
+                        rule.labels
+                            .iter()
+                            .all(|(name, value)| labels.get(name) == Some(value))
+                    })
+                    .map(|(labels, value)| (labels, (value, true)))
+                    .collect();
+            // 前回まであって今回無い系列は通信が止まったものとして 0 で評価する
+            for (key, state) in states.iter() {
+                if key.0 == index
+                    && !current.contains_key(&key.1)
+                    && state.firing_or_recent(now_ms, rule)
+                {
+                    current.insert(key.1.clone(), (0.0, false));
+                }
+            }
+
+            for (labels, (value, present)) in current {
+                let state = states
+                    .entry((index, labels.clone()))
+                    .or_insert_with(|| SeriesState {
+                        first_seen_ms: now_ms,
+                        ..Default::default()
+                    });
+                if present {
+                    state.last_seen_ms = now_ms;
+                }
+                let Some(threshold) = rule.threshold(state, now_ms, value) else {
+                    continue;
+                };
+                let active = match rule.condition {
+                    Condition::Above { .. } => value > threshold,
+                    Condition::Drop { .. } | Condition::Below { .. } => value < threshold,
+                };
+                let notification = |state: &'static str| Notification {
+                    rule: rule.name.clone(),
+                    state,
+                    metric: rule.metric,
+                    labels: labels.clone(),
+                    value,
+                    threshold,
+                    timestamp_ms: now_ms,
+                };
+                if active {
+                    let since = *state.pending_since_ms.get_or_insert(now_ms);
+                    if !state.firing && now_ms - since >= rule.for_secs as i64 * 1000 {
+                        state.firing = true;
+                        warn!(
+                            "Alert {} firing for {:?}: {} (threshold {})",
+                            rule.name, labels, value, threshold
+                        );
+                        self.notify(rule, notification("firing"));
+                    }
+                } else {
+                    state.pending_since_ms = None;
+                    if state.firing {
+                        state.firing = false;
+                        info!(
+                            "Alert {} resolved for {:?}: {} (threshold {})",
+                            rule.name, labels, value, threshold
+                        );
+                        if rule.notify_resolved {
+                            self.notify(rule, notification("resolved"));
+                        }
+                    }
+                }
+            }
+
+            // 長く現れない系列の状態は捨てる
+            states.retain(|key, state| key.0 != index || state.firing_or_recent(now_ms, rule));
+            let firing = states
+                .iter()
+                .filter(|(key, state)| key.0 == index && state.firing)
+                .count();
+            self.firing_gauge
+                .with_label_values(&[&rule.name])
+                .set(firing as f64);
+        }
+    }
+
+    fn notify(&self, rule: &Rule, notification: Notification) {
+        if let Some(url) = rule.webhook.clone() {
+            let http = self.http.clone();
+            let notification = notification.clone();
+            tokio::spawn(async move {
+                if let Err(e) = http.send(http.post(&url).json(&notification)).await {
+                    warn!(
+                        "Failed to send alert {} to {}: {}",
+                        notification.rule, url, e
+                    );
+                }
+            });
+        }
+        if let Some((program, args)) = rule.exec.split_first() {
+            let mut command = tokio::process::Command::new(program);
+            command
+                .args(args)
+                .env("ALERT_RULE", &notification.rule)
+                .env("ALERT_STATE", notification.state)
+                .env("ALERT_VALUE", notification.value.to_string())
+                .env("ALERT_THRESHOLD", notification.threshold.to_string())
+                .env(
+                    "ALERT_JSON",
+                    serde_json::to_string(&notification).unwrap_or_default(),
+                )
+                .kill_on_drop(true);
+            for (name, value) in &notification.labels {
+                command.env(format!("ALERT_LABEL_{}", name.to_ascii_uppercase()), value);
+            }
+            let program = program.clone();
+            tokio::spawn(async move {
+                let status = match command.spawn() {
+                    Ok(mut child) => tokio::time::timeout(EXEC_TIMEOUT, child.wait()).await,
+                    Err(e) => {
+                        warn!("Failed to run alert command {}: {}", program, e);
+                        return;
+                    }
+                };
+                match status {
+                    Ok(Ok(status)) if status.success() => {}
+                    Ok(Ok(status)) => warn!("Alert command {} exited with {}", program, status),
+                    Ok(Err(e)) => warn!("Failed to wait for alert command {}: {}", program, e),
+                    Err(_) => warn!(
+                        "Alert command {} did not finish in {:?}, killed",
+                        program, EXEC_TIMEOUT
+                    ),
+                }
+            });
+        }
+    }
+}
+
+impl Rule {
+    // 今回の閾値。Drop は平均の窓がたまるまで None（評価しない）
+    fn threshold(&self, state: &mut SeriesState, now_ms: i64, value: f64) -> Option<f64> {
+        match self.condition {
+            Condition::Below { value } | Condition::Above { value } => Some(value),
+            Condition::Drop {
+                percent,
+                window_secs,
+            } => {
+                let window_ms = window_secs as i64 * 1000;
+                state.history.push_back((now_ms, value));
+                while state
+                    .history
+                    .front()
+                    .is_some_and(|(at, _)| now_ms - at > window_ms)
+                {
+                    state.history.pop_front();
+                }
+                if now_ms - state.first_seen_ms < window_ms {
+                    return None;
+                }
+                let average = state.history.iter().map(|(_, value)| value).sum::<f64>()
+                    / state.history.len() as f64;
+                Some(average * (1.0 - percent / 100.0))
+            }
+        }
+    }
+
+    // 現れなくなった系列の状態を残す期間（平均の窓と for_secs のうち長い方、最短 5 分）
+    fn retention_ms(&self) -> i64 {
+        let window_secs = match self.condition {
+            Condition::Drop { window_secs, .. } => window_secs,
+            _ => 0,
+        };
+        (window_secs.max(self.for_secs) as i64 * 1000).max(MIN_RETENTION_MS)
+    }
+}
+
+impl SeriesState {
+    fn firing_or_recent(&self, now_ms: i64, rule: &Rule) -> bool {
+        self.firing || now_ms - self.last_seen_ms <= rule.retention_ms()
+    }
+}
+
+fn load_rules(path: &str) -> Result<Vec<Rule>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read alert rules {}", path))?;
+    serde_json::from_str(&text).with_context(|| format!("Failed to parse alert rules {}", path))
+}
+
+// 評価対象の系列のラベルと値
+fn series(report: &ThroughputReport, metric: Metric) -> Vec<(BTreeMap<String, String>, f64)> {
+    let labels = |pairs: &[(&str, &str)]| {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<BTreeMap<_, _>>()
+    };
+    match metric {
+        Metric::ThroughputdumpTotal => report
+            .interfaces
+            .iter()
+            .map(|i| (labels(&[("interface", &i.interface)]), i.throughput))
+            .collect(),
+        Metric::Throughputdump => report
+            .remotes
+            .iter()
+            .map(|r| {
+                (
+                    labels(&[("interface", &r.interface), ("remote_ip", &r.remote_ip)]),
+                    r.throughput,
+                )
+            })
+            .collect(),
+        Metric::DeviceThroughput => report
+            .devices
+            .iter()
+            .map(|d| {
+                (
+                    labels(&[("local_ip", &d.local_ip), ("interface", &d.interface)]),
+                    d.throughput,
+                )
+            })
+            .collect(),
+    }
+}
@@ -0,0 +1,160 @@
+// /compare で返すインターフェース（WAN）の比較
+//
+// 判定エンジンがなぜ片方の回線を選ぶのかを目視で確認できるよう、直近の計算結果を
+// リモート IP ごと・全体でインターフェースを順位付けし、元になった RTT とバイト数と一緒に返す。
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+lazy_static! {
+    // 直近の計算周期の比較結果
+    static ref LAST_COMPARISON: RwLock<Option<Comparison>> = RwLock::new(None);
+}
+
+// 1 つのリモート IP への 1 つのインターフェースの入力と計算結果
+#[derive(Debug, Serialize)]
+pub struct LinkInput {
+    pub interface: String,
+    pub rtt: f64,
+    pub download_bytes: f64,
+    pub upload_bytes: f64,
+    // RTT が 0 以下、またはバイト数が MIN_BYTES 以下で計算しなかった場合は None
+    pub throughput: Option<f64>,
+    pub input_age_seconds: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+struct RemoteComparison {
+    remote_ip: String,
+    // 1 位のインターフェース
+    preferred: String,
+    // 順位順
+    links: Vec<LinkInput>,
+}
+
+#[derive(Debug, Serialize)]
+struct InterfaceSummary {
+    interface: String,
+    // throughputdump_total と同じ値
+    throughput: f64,
+    // スループットを計算したリモートの数
+    remotes: usize,
+    // 複数のインターフェースで計算できたリモートのうち 1 位になった数
+    wins: usize,
+    // 有効な RTT の中央値
+    median_rtt: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+struct Comparison {
+    timestamp_ms: i64,
+    // スループットの合計が大きい順
+    interfaces: Vec<InterfaceSummary>,
+    // 1 位のスループットが大きい順
+    remotes: Vec<RemoteComparison>,
+}
+
+// スループットの大きい順、計算できなかったものは RTT の小さい順で後ろに並べる
+fn rank(a: &LinkInput, b: &LinkInput) -> Ordering {
+    match (a.throughput, b.throughput) {
+        (Some(a), Some(b)) => b.total_cmp(&a),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => {
+            let rtt = |link: &LinkInput| {
+                if link.rtt > 0.0 {
+                    link.rtt
+                } else {
+                    f64::INFINITY
+                }
+            };
+            rtt(a).total_cmp(&rtt(b))
+        }
+    }
+    .then_with(|| a.interface.cmp(&b.interface))
+}
+
+fn median(mut values: Vec<f64>) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(f64::total_cmp);
+    let middle = values.len() / 2;
+    Some(if values.len().is_multiple_of(2) {
+        (values[middle - 1] + values[middle]) / 2.0
+    } else {
+        values[middle]
+    })
+}
+
+// 1 周期分の (remote_ip, 入力) から比較結果を作って置き換える
+pub fn publish(timestamp_ms: i64, links: Vec<(String, LinkInput)>) {
+    let mut by_remote: BTreeMap<String, Vec<LinkInput>> = BTreeMap::new();
+    for (remote_ip, link) in links {
+        by_remote.entry(remote_ip).or_default().push(link);
+    }
+
+    let mut summaries: BTreeMap<String, InterfaceSummary> = BTreeMap::new();
+    let mut rtts: BTreeMap<String, Vec<f64>> = BTreeMap::new();
+    let mut remotes = Vec::with_capacity(by_remote.len());
+    for (remote_ip, mut links) in by_remote {
+        links.sort_by(rank);
+        let calculated = links.iter().filter(|l| l.throughput.is_some()).count();
+        for (position, link) in links.iter().enumerate() {
+            let summary =
+                summaries
+                    .entry(link.interface.clone())
+                    .or_insert_with(|| InterfaceSummary {
+                        interface: link.interface.clone(),
+                        throughput: 0.0,
+                        remotes: 0,
+                        wins: 0,
+                        median_rtt: None,
+                    });
+            if let Some(throughput) = link.throughput {
+                summary.throughput += throughput;
+                summary.remotes += 1;
+                if position == 0 && calculated >= 2 {
+                    summary.wins += 1;
+                }
+            }
+            if link.rtt > 0.0 {
+                rtts.entry(link.interface.clone())
+                    .or_default()
+                    .push(link.rtt);
+            }
+        }
+        remotes.push(RemoteComparison {
+            remote_ip,
+            preferred: links[0].interface.clone(),
+            links,
+        });
+    }
+    for (interface, values) in rtts {
+        if let Some(summary) = summaries.get_mut(&interface) {
+            summary.median_rtt = median(values);
+        }
+    }
+
+    let mut interfaces: Vec<InterfaceSummary> = summaries.into_values().collect();
+    interfaces.sort_by(|a, b| b.throughput.total_cmp(&a.throughput));
+    let best = |remote: &RemoteComparison| remote.links[0].throughput.unwrap_or(0.0);
+    remotes.sort_by(|a, b| best(b).total_cmp(&best(a)));
+
+    *LAST_COMPARISON.write().unwrap() = Some(Comparison {
+        timestamp_ms,
+        interfaces,
+        remotes,
+    });
+}
+
+// まだ 1 度も計算していなければ None
+pub fn to_json() -> Option<String> {
+    let comparison = LAST_COMPARISON.read().unwrap();
+    comparison
+        .as_ref()
+        .map(|c| serde_json::to_string(c).unwrap_or_default())
+}
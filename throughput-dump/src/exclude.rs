@@ -0,0 +1,66 @@
+// 計測用の通信の除外
+//
+// icmp-traffic-scan の ping、tcp-traffic-scan の接続、Prometheus のスクレイプなど、
+// このツール群自身の通信も download_bytes / upload_bytes に含まれるため、そのままでは
+// 算出するスループットを押し上げてしまう。リモート IP と L4 プロトコルのルールで
+// 計測用とみなした系列を入力から除く。ポートは localPacketDump-rs のラベルに無いため、
+// ポートで分ける場合は localPacketDump-rs の CAPTURE_FILTER を使う。
+
+use ipnetwork::IpNetwork;
+use log::{error, info};
+use shared_cidr::IpSet;
+use shared_schema::{LABEL_PROTOCOL, LABEL_REMOTE_IP};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+pub struct MeasurementFilter {
+    // 計測用の通信の相手（Prometheus サーバー、tcp-traffic-scan のターゲットなど）
+    cidrs: IpSet,
+    // 計測用とみなすプロトコル（localPacketDump-rs の PROTOCOL_LABELS が有効な場合のみ効く）。
+    // 利用者の ICMP もまとめて落ちるため既定では空
+    protocols: Vec<String>,
+}
+
+impl MeasurementFilter {
+    pub fn from_env() -> Self {
+        let cidrs: IpSet = shared_config::var("MEASUREMENT_CIDRS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|cidr| !cidr.is_empty())
+            .filter_map(|cidr| match IpNetwork::from_str(cidr) {
+                Ok(net) => Some(net),
+                Err(e) => {
+                    error!("Failed to parse measurement CIDR {}: {}", cidr, e);
+                    None
+                }
+            })
+            .collect();
+        let protocols: Vec<String> = shared_config::var("MEASUREMENT_PROTOCOLS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|protocol| protocol.trim().to_ascii_lowercase())
+            .filter(|protocol| !protocol.is_empty())
+            .collect();
+        let networks: Vec<&IpNetwork> = cidrs.iter().map(|(net, _)| net).collect();
+        info!(
+            "Excluding measurement traffic to {:?} and protocols {:?}",
+            networks, protocols
+        );
+        Self { cidrs, protocols }
+    }
+
+    // download_bytes / upload_bytes の系列が計測用の通信か
+    pub fn is_measurement(&self, labels: &HashMap<String, String>) -> bool {
+        if let Some(protocol) = labels.get(LABEL_PROTOCOL) {
+            if self.protocols.iter().any(|p| p == protocol) {
+                return true;
+            }
+        }
+        labels
+            .get(LABEL_REMOTE_IP)
+            .and_then(|ip| ip.parse::<IpAddr>().ok())
+            .is_some_and(|ip| self.cidrs.contains(ip))
+    }
+}
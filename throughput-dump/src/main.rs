@@ -1,164 +1,855 @@
+mod alert;
+mod compare;
+mod exclude;
+mod range;
+mod score;
+mod sink;
+
+use alert::AlertRules;
 use anyhow::{Context, Result};
+use compare::LinkInput;
+use exclude::MeasurementFilter;
 use lazy_static::lazy_static;
-use log::{error, info, warn};
-use prometheus::{Encoder, Gauge, Opts, Registry, TextEncoder};
-use reqwest::Client;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use log::{debug, error, info, warn};
+use prometheus::core::Collector;
+use prometheus::proto::{LabelPair, MetricFamily, MetricType};
+use prometheus::{Gauge, GaugeVec, HistogramOpts, HistogramVec, Opts, Registry};
+use range::{RangeConfig, Stat, ThroughputStats};
+use score::{Formula, InputQueries, Variables};
+use shared_http::HttpClient;
+use shared_schema::{
+    build_info, pipeline, StatusResponse, LABEL_INTERFACE, LABEL_LOCAL_IP, LABEL_REMOTE_IP,
+    SCHEMA_VERSION_HEADER, STATUS_SCHEMA_VERSION,
+};
+use shared_sim::Scenario;
+use sink::{DeviceThroughput, InterfaceThroughput, OutputSink, RemoteThroughput, ThroughputReport};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
+use traffic_scan_core::server::{self, StatusCode};
+use traffic_scan_core::{
+    DeviceKey, MetricsServer, PrometheusClient, RemoteWrite, Sample, SeriesKey,
+};
+
+// icmp-traffic-scan の rtt_icmp_dump で、測定のきっかけになった通信の向き（download / upload）
+const LABEL_DATA_TYPE: &str = "data_type";
+// 入力のバイト数と window_closed_timestamp_seconds を突き合わせるスクレイプ先のラベル
+const LABEL_INSTANCE: &str = "instance";
+
+// 同じ interface + remote_ip に複数の RTT 系列（data_type, probe_type など）がある場合の集約方法
+#[derive(Debug, Clone, Copy)]
+enum RttMethod {
+    Min,
+    Avg,
+    Max,
+}
 
-// Prometheusのクエリレスポンス構造
-#[derive(Debug, Deserialize)]
-struct PrometheusResponse {
-    status: String,
-    data: PrometheusData,
+// RTT 系列の選び方と集約方法。data_type を 1 つに絞ってから残りを集約する
+#[derive(Debug, Clone)]
+struct RttAggregation {
+    method: RttMethod,
+    // RTT_DATA_TYPE を設定したときだけ、その data_type の系列を使う
+    data_type: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
-struct PrometheusData {
-    #[serde(rename = "resultType")]
-    result_type: String,
-    result: Vec<PrometheusResult>,
+impl RttAggregation {
+    fn from_env() -> Self {
+        let method = match shared_config::var("RTT_AGGREGATION").as_deref() {
+            Ok("avg") => RttMethod::Avg,
+            Ok("max") => RttMethod::Max,
+            Ok("min") | Err(_) => RttMethod::Min,
+            Ok(other) => {
+                warn!("Unknown RTT_AGGREGATION={}, using min", other);
+                RttMethod::Min
+            }
+        };
+        let data_type = shared_config::var("RTT_DATA_TYPE")
+            .ok()
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty());
+        Self { method, data_type }
+    }
+
+    // data_type ラベルの無い系列は RTT_DATA_TYPE を設定したときは使わない
+    fn accepts(&self, sample: &Sample) -> bool {
+        self.data_type
+            .as_deref()
+            .is_none_or(|data_type| sample.label(LABEL_DATA_TYPE) == Some(data_type))
+    }
+
+    fn aggregate(&self, values: &[f64]) -> f64 {
+        match self.method {
+            RttMethod::Min => values.iter().copied().fold(f64::INFINITY, f64::min),
+            RttMethod::Avg => values.iter().sum::<f64>() / values.len() as f64,
+            RttMethod::Max => values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+        }
+    }
 }
 
-#[derive(Debug, Deserialize, Clone)]
-struct PrometheusResult {
-    metric: HashMap<String, String>,
-    value: (f64, String),
+// 直近の計算に現れなかった interface + remote_ip・インターフェース・端末の系列の扱い
+#[derive(Debug, Clone, Copy)]
+enum StaleSeries {
+    // レジストリから外す
+    Remove,
+    // 0 にして残す
+    Zero,
 }
 
-// スループットメトリクスのキー
-#[derive(Debug, Clone, Hash, Eq, PartialEq)]
-struct MetricKey {
-    interface: String,
-    remote_ip: String,
+impl StaleSeries {
+    fn from_env() -> Self {
+        match shared_config::var("STALE_SERIES").as_deref() {
+            Ok("zero") => StaleSeries::Zero,
+            Ok("remove") | Err(_) => StaleSeries::Remove,
+            Ok(other) => {
+                warn!("Unknown STALE_SERIES={}, using remove", other);
+                StaleSeries::Remove
+            }
+        }
+    }
 }
 
+// local_ip ラベルが無く device_throughput を計算しなかったことを一度だけ警告する
+static DEVICE_THROUGHPUT_SKIPPED: AtomicBool = AtomicBool::new(false);
+
 lazy_static! {
     static ref REGISTRY: Registry = Registry::new();
-    static ref THROUGHPUT_GAUGES: Arc<Mutex<HashMap<MetricKey, Gauge>>> =
+    static ref THROUGHPUT_GAUGES: Arc<Mutex<HashMap<SeriesKey, Gauge>>> =
         Arc::new(Mutex::new(HashMap::new()));
     static ref THROUGHPUT_TOTAL_GAUGES: Arc<Mutex<HashMap<String, Gauge>>> =
         Arc::new(Mutex::new(HashMap::new()));
+    static ref DEVICE_THROUGHPUT_GAUGES: Arc<Mutex<HashMap<DeviceKey, Gauge>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+    static ref EXCLUDED_BYTES_GAUGES: Arc<Mutex<HashMap<String, Gauge>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+    static ref INPUT_AGE_GAUGES: Arc<Mutex<HashMap<SeriesKey, Gauge>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+    // 範囲から求めた throughputdump_avg / throughputdump_p50 / throughputdump_p95
+    static ref RANGE_STAT_GAUGES: Arc<Mutex<HashMap<(SeriesKey, &'static str), Gauge>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+    // 系列ごとの元データのクエリ評価時刻（ミリ秒）
+    static ref SAMPLE_TIMESTAMPS: Mutex<HashMap<String, i64>> = Mutex::new(HashMap::new());
+    // 使ったウィンドウの確定から計算するまでの遅延
+    static ref PIPELINE_LAG: HistogramVec = {
+        let histogram = HistogramVec::new(
+            HistogramOpts::new(pipeline::LAG_METRIC, pipeline::LAG_HELP)
+                .const_label("job", "throughputdump")
+                .buckets(pipeline::LAG_BUCKETS.to_vec()),
+            &["stage"],
+        )
+        .unwrap();
+        REGISTRY.register(Box::new(histogram.clone())).unwrap();
+        histogram
+    };
+    static ref INPUT_SEQUENCE: GaugeVec = {
+        let gauge = GaugeVec::new(
+            Opts::new(pipeline::INPUT_SEQUENCE_METRIC, pipeline::INPUT_SEQUENCE_HELP)
+                .const_label("job", "throughputdump"),
+            &["stage"],
+        )
+        .unwrap();
+        REGISTRY.register(Box::new(gauge.clone())).unwrap();
+        gauge
+    };
+}
+
+fn now_ms() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+// 系列を識別する文字列（メトリクス名 + ソート済みラベル）
+fn series_id(name: &str, labels: &[LabelPair]) -> String {
+    let mut id = name.to_string();
+    for label in labels {
+        id.push_str(&format!(",{}={}", label.get_name(), label.get_value()));
+    }
+    id
+}
+
+// Gauge に値を設定し、元データのクエリ評価時刻を記録する
+fn set_gauge(gauge: &Gauge, value: f64, timestamp_ms: i64) {
+    gauge.set(value);
+    if let Some(desc) = gauge.desc().first() {
+        SAMPLE_TIMESTAMPS.lock().unwrap().insert(
+            series_id(&desc.fq_name, &desc.const_label_pairs),
+            timestamp_ms,
+        );
+    }
+}
+
+// Gauge をレジストリから外し、記録したクエリ評価時刻も消す
+fn unregister_gauge(gauge: &Gauge) {
+    if let Err(e) = REGISTRY.unregister(Box::new(gauge.clone())) {
+        warn!("Failed to unregister stale gauge: {}", e);
+    }
+    if let Some(desc) = gauge.desc().first() {
+        SAMPLE_TIMESTAMPS
+            .lock()
+            .unwrap()
+            .remove(&series_id(&desc.fq_name, &desc.const_label_pairs));
+    }
+}
+
+// keep が false のキーの Gauge を外すか 0 にする
+fn prune_gauges<K: Eq + std::hash::Hash>(
+    gauges: &mut HashMap<K, Gauge>,
+    keep: impl Fn(&K) -> bool,
+    stale: StaleSeries,
+    timestamp_ms: i64,
+) {
+    match stale {
+        StaleSeries::Remove => gauges.retain(|key, gauge| {
+            if keep(key) {
+                return true;
+            }
+            unregister_gauge(gauge);
+            false
+        }),
+        StaleSeries::Zero => {
+            for (_, gauge) in gauges.iter().filter(|(key, _)| !keep(key)) {
+                set_gauge(gauge, 0.0, timestamp_ms);
+            }
+        }
+    }
+}
+
+// 収集したメトリクスにクエリ評価時刻を付与する
+fn attach_timestamps(metric_families: &mut [MetricFamily]) {
+    let timestamps = SAMPLE_TIMESTAMPS.lock().unwrap();
+    for family in metric_families.iter_mut() {
+        let name = family.get_name().to_string();
+        for metric in family.mut_metric().iter_mut() {
+            if let Some(timestamp_ms) = timestamps.get(&series_id(&name, metric.get_label())) {
+                metric.set_timestamp_ms(*timestamp_ms);
+            }
+        }
+    }
+}
+
+// OpenMetrics テキスト形式でエンコード（タイムスタンプは秒）
+fn encode_openmetrics(metric_families: &[MetricFamily]) -> String {
+    let mut out = String::new();
+    for family in metric_families {
+        let name = family.get_name();
+        // Counter のファミリー名は _total を除き、サンプルにだけ付ける
+        let (family_name, type_name) = match family.get_field_type() {
+            MetricType::COUNTER => (name.strip_suffix("_total").unwrap_or(name), "counter"),
+            MetricType::GAUGE => (name, "gauge"),
+            MetricType::HISTOGRAM => (name, "histogram"),
+            MetricType::SUMMARY => (name, "summary"),
+            MetricType::UNTYPED => (name, "unknown"),
+        };
+        out.push_str(&format!("# TYPE {} {}\n", family_name, type_name));
+        out.push_str(&format!(
+            "# HELP {} {}\n",
+            family_name,
+            escape_openmetrics(family.get_help())
+        ));
+        for metric in family.get_metric() {
+            let labels: Vec<String> = metric
+                .get_label()
+                .iter()
+                .map(|l| format!("{}=\"{}\"", l.get_name(), escape_openmetrics(l.get_value())))
+                .collect();
+            let timestamp = if metric.get_timestamp_ms() != 0 {
+                format!(" {:.3}", metric.get_timestamp_ms() as f64 / 1000.0)
+            } else {
+                String::new()
+            };
+            let mut push = |suffix: &str, extra: Option<String>, value: String| {
+                let labels: Vec<&str> = labels
+                    .iter()
+                    .map(String::as_str)
+                    .chain(extra.as_deref())
+                    .collect();
+                out.push_str(family_name);
+                out.push_str(suffix);
+                if !labels.is_empty() {
+                    out.push_str(&format!("{{{}}}", labels.join(",")));
+                }
+                out.push_str(&format!(" {}{}\n", value, timestamp));
+            };
+            match family.get_field_type() {
+                MetricType::COUNTER => push(
+                    "_total",
+                    None,
+                    openmetrics_float(metric.get_counter().get_value()),
+                ),
+                MetricType::GAUGE => {
+                    push("", None, openmetrics_float(metric.get_gauge().get_value()))
+                }
+                MetricType::UNTYPED => push(
+                    "",
+                    None,
+                    openmetrics_float(metric.get_untyped().get_value()),
+                ),
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    for bucket in histogram.get_bucket() {
+                        let le = format!("le=\"{}\"", openmetrics_float(bucket.get_upper_bound()));
+                        push(
+                            "_bucket",
+                            Some(le),
+                            bucket.get_cumulative_count().to_string(),
+                        );
+                    }
+                    let count = histogram.get_sample_count().to_string();
+                    push("_bucket", Some("le=\"+Inf\"".to_string()), count.clone());
+                    push("_count", None, count);
+                    push("_sum", None, openmetrics_float(histogram.get_sample_sum()));
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    for quantile in summary.get_quantile() {
+                        let label = format!(
+                            "quantile=\"{}\"",
+                            openmetrics_float(quantile.get_quantile())
+                        );
+                        push("", Some(label), openmetrics_float(quantile.get_value()));
+                    }
+                    push("_count", None, summary.get_sample_count().to_string());
+                    push("_sum", None, openmetrics_float(summary.get_sample_sum()));
+                }
+            }
+        }
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+// HELP とラベル値のエスケープ
+fn escape_openmetrics(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// le / quantile は 1.0 のように小数点付きで書く
+fn openmetrics_float(v: f64) -> String {
+    if v.is_nan() {
+        "NaN".to_string()
+    } else if v.is_infinite() {
+        if v > 0.0 { "+Inf" } else { "-Inf" }.to_string()
+    } else {
+        format!("{:?}", v)
+    }
+}
+
+// interface + remote_ip ごとにまとめた 1 時刻分の入力
+#[derive(Default)]
+struct Inputs {
+    rtt: HashMap<SeriesKey, f64>,
+    download: HashMap<SeriesKey, f64>,
+    upload: HashMap<SeriesKey, f64>,
+    // LOSS_QUERY のロス率（式が loss を使うときだけ）
+    loss: HashMap<SeriesKey, f64>,
+    // local_ip ラベル付き（localPacketDump-rs の PERSPECTIVE=both）の場合は端末ごとにも集計（download, upload）
+    device_bytes: HashMap<(DeviceKey, String), (f64, f64)>,
+    // local_ip ラベルの無い通信量があった（PERSPECTIVE=both 以外）
+    bytes_without_local_ip: bool,
+    // インターフェースごとの除いた計測用の通信量
+    excluded_bytes: HashMap<String, f64>,
+}
+
+// 取得した入力（範囲クエリのときは最後の時刻の値）
+struct FetchedInputs {
+    // 元データのクエリ評価時刻
+    timestamp_ms: i64,
+    rtt: Vec<Sample>,
+    download: Vec<Sample>,
+    upload: Vec<Sample>,
+    loss: Vec<Sample>,
+    // 範囲から求めた interface + remote_ip ごとのスループット（範囲クエリでなければ空）
+    stats: HashMap<SeriesKey, ThroughputStats>,
+}
+
+impl Inputs {
+    // interface + remote_ip の式の変数
+    fn variables(&self, key: &SeriesKey, rtt: f64) -> Variables {
+        Variables {
+            download: self.download.get(key).copied().unwrap_or(0.0),
+            upload: self.upload.get(key).copied().unwrap_or(0.0),
+            rtt,
+            loss: self.loss.get(key).copied().unwrap_or(0.0),
+        }
+    }
 }
 
 struct ThroughputCalculator {
-    prometheus_url: String,
-    client: Client,
+    prometheus: PrometheusClient,
+    status_url: String,
+    client: Arc<HttpClient>,
+    // ステータス API から取得した最新のマッピング
+    status: tokio::sync::RwLock<Option<StatusResponse>>,
+    // 計算結果の出力先（stdout JSON / ファイル / MQTT）
+    sinks: Vec<Box<dyn OutputSink>>,
+    // download + upload がこのバイト数以下のキーは計算しない
+    min_bytes: f64,
+    rtt_aggregation: RttAggregation,
+    // 入力の PromQL（RTT_QUERY / DOWNLOAD_QUERY / UPLOAD_QUERY / LOSS_QUERY）
+    queries: InputQueries,
+    // スループットの計算式（THROUGHPUT_FORMULA）
+    formula: Formula,
+    // RANGE_LOOKBACK_SECS の範囲クエリによる平滑化（0 なら無効）
+    range: Option<RangeConfig>,
+    // 計測用の通信として入力から除く系列
+    measurement_filter: MeasurementFilter,
+    // 直近の計算に現れなかった系列の扱い（STALE_SERIES）
+    stale_series: StaleSeries,
+    // --simulate のとき、Prometheus とステータス API の応答をこのシナリオから合成する
+    simulation: Option<Arc<Scenario>>,
+    // ALERT_RULES_FILE の条件で Webhook / コマンドを実行する
+    alerts: Option<AlertRules>,
 }
 
 impl ThroughputCalculator {
-    fn new(prometheus_url: String) -> Self {
+    fn new(
+        prometheus_url: String,
+        status_url: String,
+        sinks: Vec<Box<dyn OutputSink>>,
+        min_bytes: f64,
+        rtt_aggregation: RttAggregation,
+        measurement_filter: MeasurementFilter,
+        simulation: Option<Arc<Scenario>>,
+    ) -> Self {
+        let client = Arc::new(HttpClient::from_env());
+        let mut prometheus = PrometheusClient::new(&prometheus_url, Arc::clone(&client))
+            .with_env_auth()
+            .with_env_retry();
+        if let Some(scenario) = &simulation {
+            prometheus = prometheus.simulated(Arc::clone(scenario));
+        }
         Self {
-            prometheus_url,
-            client: Client::new(),
+            prometheus,
+            status_url,
+            client,
+            status: tokio::sync::RwLock::new(None),
+            sinks,
+            min_bytes,
+            rtt_aggregation,
+            queries: InputQueries::from_env(),
+            formula: Formula::from_env(),
+            range: RangeConfig::from_env(),
+            measurement_filter,
+            stale_series: StaleSeries::from_env(),
+            simulation,
+            alerts: AlertRules::from_env(&REGISTRY),
         }
     }
 
-    // Prometheusからメトリクスを取得
-    async fn query_prometheus(&self, query: &str) -> Result<Vec<PrometheusResult>> {
-        let url = format!("{}/api/v1/query", self.prometheus_url);
-        let response = self
-            .client
-            .get(&url)
-            .query(&[("query", query)])
-            .send()
-            .await
-            .context("Failed to send request to Prometheus")?;
-
-        let prom_response: PrometheusResponse = response
-            .json()
-            .await
-            .context("Failed to parse Prometheus response")?;
-
-        if prom_response.status != "success" {
-            anyhow::bail!("Prometheus query failed: {:?}", prom_response.status);
-        }
-
-        Ok(prom_response.data.result)
-    }
-
-    // メトリクスを取得して計算
-    async fn calculate_throughput(&self) -> Result<()> {
-        info!("Fetching metrics from Prometheus...");
+    // ステータス API からマッピングを取得
+    async fn fetch_status(&self) {
+        let result = async {
+            if let Some(scenario) = &self.simulation {
+                return Ok(StatusResponse::from_json(
+                    scenario.status_json().as_bytes(),
+                )?);
+            }
+            let request = self
+                .client
+                .get(&self.status_url)
+                .header(SCHEMA_VERSION_HEADER, STATUS_SCHEMA_VERSION.to_string());
+            let body = self.client.send(request).await?.bytes().await?;
+            Ok::<_, anyhow::Error>(StatusResponse::from_json(&body)?)
+        }
+        .await;
 
-        // 各メトリクスを取得
-        let rtt_results = self
-            .query_prometheus("rtt_icmp_dump")
-            .await
-            .context("Failed to query rtt_icmp_dump")?;
-        let download_results = self
-            .query_prometheus("download_bytes")
-            .await
-            .context("Failed to query download_bytes")?;
-        let upload_results = self
-            .query_prometheus("upload_bytes")
-            .await
-            .context("Failed to query upload_bytes")?;
+        match result {
+            Ok(status) => {
+                if status.is_newer_than_supported() {
+                    warn!(
+                        "Status API speaks schema version {}, reading it as {}",
+                        status.schema_version, STATUS_SCHEMA_VERSION
+                    );
+                }
+                info!("Fetched status with {} mappings", status.mappings.len());
+                *self.status.write().await = Some(status);
+            }
+            Err(e) => warn!("Failed to fetch status from {}: {}", self.status_url, e),
+        }
+    }
 
-        info!(
-            "Fetched {} RTT, {} download, {} upload metrics",
-            rtt_results.len(),
-            download_results.len(),
-            upload_results.len()
-        );
+    // Prometheusからメトリクスを取得
+    async fn query_prometheus(&self, query: &str) -> Result<Vec<Sample>> {
+        Ok(self.prometheus.query(query).await?)
+    }
 
-        // メトリクスをinterface+remote_ipでグループ化
-        let mut rtt_map: HashMap<MetricKey, f64> = HashMap::new();
-        let mut download_map: HashMap<MetricKey, f64> = HashMap::new();
-        let mut upload_map: HashMap<MetricKey, f64> = HashMap::new();
+    // 入力の古さ（秒）を interface + remote_ip ごとに取得する（最も古いものを採る）。
+    // timestamp() はスクレイプ時刻なので、止まったウィンドウを公開し続けている localPacketDump-rs でも
+    // 新しく見える。バイト数は同じ instance の window_closed_timestamp_seconds からの経過秒数、
+    // RTT は time() - timestamp() を使う
+    async fn query_input_ages(&self, fetched: &FetchedInputs) -> Result<HashMap<SeriesKey, f64>> {
+        let rtt_ages = self
+            .query_prometheus(&format!("time() - timestamp({})", self.queries.rtt))
+            .await?;
+        let window_ages: HashMap<String, f64> = self
+            .query_prometheus(&format!("time() - {}", pipeline::WINDOW_CLOSED_METRIC))
+            .await?
+            .into_iter()
+            .filter_map(|result| Some((result.labels.get(LABEL_INSTANCE)?.clone(), result.value)))
+            .collect();
 
-        for result in rtt_results {
+        let byte_ages = fetched
+            .download
+            .iter()
+            .chain(&fetched.upload)
+            .filter_map(|result| {
+                let instance = result.labels.get(LABEL_INSTANCE)?;
+                Some((result, *window_ages.get(instance)?))
+            });
+        let mut ages: HashMap<SeriesKey, f64> = HashMap::new();
+        for (result, age) in rtt_ages
+            .iter()
+            .map(|result| (result, result.value))
+            .chain(byte_ages)
+        {
             if let (Some(interface), Some(remote_ip)) = (
-                result.metric.get("interface"),
-                result.metric.get("remote_ip"),
+                result.labels.get(LABEL_INTERFACE),
+                result.labels.get(LABEL_REMOTE_IP),
             ) {
-                let key = MetricKey {
+                let key = SeriesKey {
                     interface: interface.clone(),
                     remote_ip: remote_ip.clone(),
                 };
-                let value: f64 = result.value.1.parse().unwrap_or(0.0);
-                rtt_map.insert(key, value);
+                let oldest = ages.entry(key).or_insert(0.0);
+                *oldest = oldest.max(age.max(0.0));
             }
         }
+        Ok(ages)
+    }
 
-        for result in download_results {
+    // バイト数の元になった localPacketDump-rs のウィンドウの確定時刻と番号を記録する
+    // （複数のインスタンスがあればそれぞれの遅延を記録し、番号は最大を採る）
+    async fn observe_input_windows(&self) -> Result<()> {
+        let results = self
+            .query_prometheus(&format!(
+                r#"{{__name__=~"{}|{}"}}"#,
+                pipeline::WINDOW_SEQUENCE_METRIC,
+                pipeline::WINDOW_CLOSED_METRIC
+            ))
+            .await?;
+        let mut sequence: Option<f64> = None;
+        for result in results {
+            let value = result.value;
+            match result.name() {
+                Some(pipeline::WINDOW_CLOSED_METRIC) => PIPELINE_LAG
+                    .with_label_values(&[pipeline::STAGE_COMPUTE])
+                    .observe(pipeline::lag_seconds(value)),
+                Some(pipeline::WINDOW_SEQUENCE_METRIC) => {
+                    sequence = Some(sequence.map_or(value, |s| s.max(value)))
+                }
+                _ => {}
+            }
+        }
+        if let Some(sequence) = sequence {
+            INPUT_SEQUENCE
+                .with_label_values(&[pipeline::STAGE_COMPUTE])
+                .set(sequence);
+        }
+        Ok(())
+    }
+
+    // 入力のクエリ（式が loss を使うときは LOSS_QUERY も）
+    fn input_queries(&self) -> Vec<&str> {
+        let mut queries = vec![
+            self.queries.rtt.as_str(),
+            self.queries.download.as_str(),
+            self.queries.upload.as_str(),
+        ];
+        if self.formula.uses_loss() {
+            queries.push(&self.queries.loss);
+        }
+        queries
+    }
+
+    // 入力のメトリクスを取得する。範囲クエリのときは最後の時刻の値と、範囲から求めた
+    // interface + remote_ip ごとのスループットの統計を返す
+    async fn fetch_inputs(&self) -> Result<FetchedInputs> {
+        let Some(range) = &self.range else {
+            let mut fetched = Vec::with_capacity(4);
+            for query in self.input_queries() {
+                fetched.push(
+                    self.query_prometheus(query)
+                        .await
+                        .with_context(|| format!("Failed to query {}", query))?,
+                );
+            }
+            // LOSS_QUERY を問い合わせなかった場合は空
+            let mut fetched = fetched.into_iter();
+            let rtt = fetched.next().unwrap_or_default();
+            let download = fetched.next().unwrap_or_default();
+            let upload = fetched.next().unwrap_or_default();
+            let loss = fetched.next().unwrap_or_default();
+            // 元データのクエリ評価時刻（スクレイプが遅れても値が時間的にずれないよう付与する）
+            let timestamp_ms = rtt
+                .first()
+                .map(|result| (result.timestamp * 1000.0) as i64)
+                .unwrap_or_else(now_ms);
+            return Ok(FetchedInputs {
+                timestamp_ms,
+                rtt,
+                download,
+                upload,
+                loss,
+                stats: HashMap::new(),
+            });
+        };
+
+        let end = now_ms() as f64 / 1000.0;
+        let start = end - range.lookback_secs;
+        let mut fetched = Vec::with_capacity(4);
+        for query in self.input_queries() {
+            let series = self
+                .prometheus
+                .query_range(query, start, end, range.step_secs)
+                .await
+                .with_context(|| {
+                    format!("Failed to query {} over {}s", query, range.lookback_secs)
+                })?;
+            fetched.push(range::by_timestamp(&series));
+        }
+        let mut fetched = fetched.into_iter();
+        let mut rtt = fetched.next().unwrap_or_default();
+        let mut download = fetched.next().unwrap_or_default();
+        let mut upload = fetched.next().unwrap_or_default();
+        let mut loss = fetched.next().unwrap_or_default();
+
+        // 各時刻で瞬時ベクトルと同じ計算をする（RTT の無い時刻は数えない、通信量が閾値以下なら 0）
+        let mut values: HashMap<SeriesKey, Vec<f64>> = HashMap::new();
+        for (timestamp_ms, rtt_at) in &rtt {
+            let inputs = self.group_inputs(
+                rtt_at,
+                download
+                    .get(timestamp_ms)
+                    .map(Vec::as_slice)
+                    .unwrap_or_default(),
+                upload
+                    .get(timestamp_ms)
+                    .map(Vec::as_slice)
+                    .unwrap_or_default(),
+                loss.get(timestamp_ms)
+                    .map(Vec::as_slice)
+                    .unwrap_or_default(),
+            );
+            for (key, rtt) in &inputs.rtt {
+                if *rtt <= 0.0 {
+                    continue;
+                }
+                let variables = inputs.variables(key, *rtt);
+                let throughput = if variables.download + variables.upload <= self.min_bytes {
+                    0.0
+                } else {
+                    match self.formula.eval(&variables) {
+                        Some(throughput) => throughput,
+                        None => continue,
+                    }
+                };
+                values.entry(key.clone()).or_default().push(throughput);
+            }
+        }
+        let stats = values
+            .into_iter()
+            .filter_map(|(key, values)| Some((key, ThroughputStats::from_values(values)?)))
+            .collect();
+
+        // 最後の時刻の値は瞬時ベクトルのクエリ結果の代わりに使う
+        let timestamp_ms = rtt.keys().next_back().copied().unwrap_or_else(now_ms);
+        Ok(FetchedInputs {
+            timestamp_ms,
+            rtt: rtt.remove(&timestamp_ms).unwrap_or_default(),
+            download: download.remove(&timestamp_ms).unwrap_or_default(),
+            upload: upload.remove(&timestamp_ms).unwrap_or_default(),
+            loss: loss.remove(&timestamp_ms).unwrap_or_default(),
+            stats,
+        })
+    }
+
+    // 1 時刻分のクエリ結果を interface + remote_ip ごとにまとめる
+    fn group_inputs(
+        &self,
+        rtt_results: &[Sample],
+        download_results: &[Sample],
+        upload_results: &[Sample],
+        loss_results: &[Sample],
+    ) -> Inputs {
+        let mut inputs = Inputs::default();
+        let mut rtt_values: HashMap<SeriesKey, Vec<f64>> = HashMap::new();
+
+        for result in rtt_results
+            .iter()
+            .filter(|r| self.rtt_aggregation.accepts(r))
+        {
             if let (Some(interface), Some(remote_ip)) = (
-                result.metric.get("interface"),
-                result.metric.get("remote_ip"),
+                result.labels.get(LABEL_INTERFACE),
+                result.labels.get(LABEL_REMOTE_IP),
             ) {
-                let key = MetricKey {
+                let key = SeriesKey {
                     interface: interface.clone(),
                     remote_ip: remote_ip.clone(),
                 };
-                let value: f64 = result.value.1.parse().unwrap_or(0.0);
-                download_map.insert(key, value);
+                let value = result.value;
+                rtt_values.entry(key).or_default().push(value);
+            }
+        }
+
+        // interface + remote_ip 以外のラベルで分かれた系列を集約する（0 以下は計測失敗なので除く）
+        inputs.rtt = rtt_values
+            .into_iter()
+            .map(|(key, values)| {
+                let valid: Vec<f64> = values.into_iter().filter(|v| *v > 0.0).collect();
+                let rtt = if valid.is_empty() {
+                    0.0
+                } else {
+                    self.rtt_aggregation.aggregate(&valid)
+                };
+                (key, rtt)
+            })
+            .collect();
+
+        for (upload, results) in [(false, download_results), (true, upload_results)] {
+            for result in results {
+                if let (Some(interface), Some(remote_ip)) = (
+                    result.labels.get(LABEL_INTERFACE),
+                    result.labels.get(LABEL_REMOTE_IP),
+                ) {
+                    let key = SeriesKey {
+                        interface: interface.clone(),
+                        remote_ip: remote_ip.clone(),
+                    };
+                    let value = result.value;
+                    if self.measurement_filter.is_measurement(&result.labels) {
+                        *inputs
+                            .excluded_bytes
+                            .entry(interface.clone())
+                            .or_insert(0.0) += value;
+                        continue;
+                    }
+                    // 同じ interface + remote_ip でも端末ごとに系列が分かれるので合算する
+                    let map = if upload {
+                        &mut inputs.upload
+                    } else {
+                        &mut inputs.download
+                    };
+                    *map.entry(key).or_insert(0.0) += value;
+
+                    if let Some(local_ip) = result.labels.get(LABEL_LOCAL_IP) {
+                        let device = DeviceKey {
+                            local_ip: local_ip.clone(),
+                            interface: interface.clone(),
+                        };
+                        let bytes = inputs
+                            .device_bytes
+                            .entry((device, remote_ip.clone()))
+                            .or_insert((0.0, 0.0));
+                        if upload {
+                            bytes.1 += value;
+                        } else {
+                            bytes.0 += value;
+                        }
+                    } else {
+                        inputs.bytes_without_local_ip = true;
+                    }
+                }
             }
         }
 
-        for result in upload_results {
+        // 系列が分かれていれば最も悪いロス率を採る
+        for result in loss_results {
             if let (Some(interface), Some(remote_ip)) = (
-                result.metric.get("interface"),
-                result.metric.get("remote_ip"),
+                result.labels.get(LABEL_INTERFACE),
+                result.labels.get(LABEL_REMOTE_IP),
             ) {
-                let key = MetricKey {
+                let key = SeriesKey {
                     interface: interface.clone(),
                     remote_ip: remote_ip.clone(),
                 };
-                let value: f64 = result.value.1.parse().unwrap_or(0.0);
-                upload_map.insert(key, value);
+                let loss = inputs.loss.entry(key).or_insert(0.0);
+                *loss = loss.max(result.value);
             }
         }
+        inputs
+    }
+
+    // メトリクスを取得して計算
+    async fn calculate_throughput(&self) -> Result<()> {
+        info!("Fetching metrics from Prometheus...");
+
+        let fetched = self.fetch_inputs().await?;
+
+        // 古さが分からなくてもスループットの計算は続ける
+        let input_ages = match self.query_input_ages(&fetched).await {
+            Ok(ages) => ages,
+            Err(e) => {
+                warn!("Failed to query input sample timestamps: {:#}", e);
+                HashMap::new()
+            }
+        };
+
+        // 遅延が分からなくても計算は続ける
+        if let Err(e) = self.observe_input_windows().await {
+            warn!("Failed to query input window timestamps: {:#}", e);
+        }
+
+        let status = self.status.read().await.clone();
+
+        let eval_timestamp_ms = fetched.timestamp_ms;
+
+        info!(
+            "Fetched {} RTT, {} download, {} upload metrics",
+            fetched.rtt.len(),
+            fetched.download.len(),
+            fetched.upload.len()
+        );
 
-        // スループット計算: (download_bytes + upload_bytes) / rtt_icmp_dump
+        // メトリクスをinterface+remote_ipでグループ化
+        let Inputs {
+            rtt: rtt_map,
+            download: download_map,
+            upload: upload_map,
+            loss: loss_map,
+            device_bytes,
+            bytes_without_local_ip,
+            excluded_bytes,
+        } = self.group_inputs(
+            &fetched.rtt,
+            &fetched.download,
+            &fetched.upload,
+            &fetched.loss,
+        );
+        let stat = self.range.as_ref().map_or(Stat::Latest, |range| range.stat);
+        publish_range_stats(&fetched.stats, eval_timestamp_ms);
+
+        let mut report = ThroughputReport {
+            timestamp_ms: eval_timestamp_ms,
+            ..Default::default()
+        };
+
+        // スループット計算: THROUGHPUT_FORMULA（既定は (download_bytes + upload_bytes) / rtt_icmp_dump）
         let mut gauges = THROUGHPUT_GAUGES.lock().unwrap();
         let mut interface_totals: HashMap<String, f64> = HashMap::new();
+        // /compare 用に、計算しなかったものも含めた入力
+        let mut links: Vec<(String, LinkInput)> = Vec::with_capacity(rtt_map.len());
+        // 今回 throughputdump を設定した（0 に戻したものを含む）キー
+        let mut published: HashSet<SeriesKey> = HashSet::new();
 
         for (key, rtt) in &rtt_map {
             // 同じキーのdownloadとuploadを取得
             let download = download_map.get(key).copied().unwrap_or(0.0);
             let upload = upload_map.get(key).copied().unwrap_or(0.0);
+            links.push((
+                key.remote_ip.clone(),
+                LinkInput {
+                    interface: key.interface.clone(),
+                    rtt: *rtt,
+                    download_bytes: download,
+                    upload_bytes: upload,
+                    throughput: None,
+                    input_age_seconds: input_ages.get(key).copied(),
+                },
+            ));
 
             // RTTが0の場合はスキップ
             if *rtt <= 0.0 {
@@ -171,11 +862,46 @@ impl ThroughputCalculator {
 
             // スループット計算
             let total_bytes = download + upload;
-            let throughput = total_bytes / rtt;
+
+            // データ量が閾値以下のリモートはスキップ（既に公開中の Gauge は 0 に戻す）
+            if total_bytes <= self.min_bytes {
+                debug!(
+                    "Skipping interface={}, remote_ip={}: {} bytes <= {}",
+                    key.interface, key.remote_ip, total_bytes, self.min_bytes
+                );
+                if let Some(gauge) = gauges.get(key) {
+                    set_gauge(gauge, 0.0, eval_timestamp_ms);
+                    set_input_age(key, input_ages.get(key).copied(), eval_timestamp_ms);
+                    published.insert(key.clone());
+                }
+                continue;
+            }
+
+            // THROUGHPUT_STAT が avg / p50 / p95 なら範囲から求めた値を使う
+            let stats = fetched.stats.get(key).copied();
+            let variables = Variables {
+                download,
+                upload,
+                rtt: *rtt,
+                loss: loss_map.get(key).copied().unwrap_or(0.0),
+            };
+            let Some(throughput) = stats
+                .and_then(|stats| stats.get(stat))
+                .or_else(|| self.formula.eval(&variables))
+            else {
+                warn!(
+                    "Skipping interface={}, remote_ip={}: {} is not finite for {:?}",
+                    key.interface, key.remote_ip, self.formula, variables
+                );
+                continue;
+            };
+            if let Some((_, link)) = links.last_mut() {
+                link.throughput = Some(throughput);
+            }
 
             info!(
-                "Calculated throughput for interface={}, remote_ip={}: ({} + {}) / {} = {}",
-                key.interface, key.remote_ip, download, upload, rtt, throughput
+                "Calculated throughput for interface={}, remote_ip={}: {} with {:?} = {}",
+                key.interface, key.remote_ip, self.formula, variables, throughput
             );
 
             // Gaugeを取得または作成
@@ -194,7 +920,20 @@ impl ThroughputCalculator {
                 gauge
             });
 
-            gauge.set(throughput);
+            set_gauge(gauge, throughput, eval_timestamp_ms);
+            published.insert(key.clone());
+            let input_age = input_ages.get(key).copied();
+            set_input_age(key, input_age, eval_timestamp_ms);
+            report.remotes.push(RemoteThroughput {
+                interface: key.interface.clone(),
+                remote_ip: key.remote_ip.clone(),
+                download_bytes: download,
+                upload_bytes: upload,
+                rtt: *rtt,
+                throughput,
+                input_age_seconds: input_age,
+                range: stats,
+            });
 
             // interfaceごとのトータルに加算
             *interface_totals.entry(key.interface.clone()).or_insert(0.0) += throughput;
@@ -222,38 +961,296 @@ impl ThroughputCalculator {
                 gauge
             });
 
-            gauge.set(*total_throughput);
+            set_gauge(gauge, *total_throughput, eval_timestamp_ms);
+            report.interfaces.push(InterfaceThroughput {
+                interface: interface.clone(),
+                throughput: *total_throughput,
+            });
+        }
+        drop(gauges);
+        drop(total_gauges);
+
+        // 除いた計測用の通信量（前回あって今回無いインターフェースは 0 に戻す）
+        let mut excluded_gauges = EXCLUDED_BYTES_GAUGES.lock().unwrap();
+        for (interface, gauge) in excluded_gauges.iter() {
+            if !excluded_bytes.contains_key(interface) {
+                set_gauge(gauge, 0.0, eval_timestamp_ms);
+            }
+        }
+        for (interface, bytes) in &excluded_bytes {
+            let gauge = excluded_gauges.entry(interface.clone()).or_insert_with(|| {
+                let gauge = Gauge::with_opts(
+                    Opts::new(
+                        "throughputdump_excluded_bytes",
+                        "Bytes of measurement traffic excluded from the throughput inputs",
+                    )
+                    .const_label("interface", interface)
+                    .const_label("job", "throughputdump"),
+                )
+                .unwrap();
+                REGISTRY.register(Box::new(gauge.clone())).unwrap();
+                gauge
+            });
+            set_gauge(gauge, *bytes, eval_timestamp_ms);
+        }
+        drop(excluded_gauges);
+        compare::publish(eval_timestamp_ms, links);
+
+        report.devices = update_device_throughput(
+            status.as_ref(),
+            &self.formula,
+            &rtt_map,
+            &loss_map,
+            &device_bytes,
+            bytes_without_local_ip,
+            eval_timestamp_ms,
+        );
+        self.prune_stale_series(&report, &published, &fetched.stats);
+
+        for sink in &self.sinks {
+            if let Err(e) = sink.write(&report) {
+                warn!("Failed to write to {} sink: {:#}", sink.name(), e);
+            }
+        }
+        if let Some(alerts) = &self.alerts {
+            alerts.evaluate(&report);
         }
 
         Ok(())
     }
+
+    // 消えた interface + remote_ip・インターフェース・端末の系列を外す（STALE_SERIES=zero なら 0 にする）
+    fn prune_stale_series(
+        &self,
+        report: &ThroughputReport,
+        published: &HashSet<SeriesKey>,
+        stats: &HashMap<SeriesKey, ThroughputStats>,
+    ) {
+        let (stale, timestamp_ms) = (self.stale_series, report.timestamp_ms);
+        prune_gauges(
+            &mut THROUGHPUT_GAUGES.lock().unwrap(),
+            |key| published.contains(key),
+            stale,
+            timestamp_ms,
+        );
+        // 古さが 0 では新しい入力に見えるので、STALE_SERIES=zero でも外す
+        prune_gauges(
+            &mut INPUT_AGE_GAUGES.lock().unwrap(),
+            |key| published.contains(key),
+            StaleSeries::Remove,
+            timestamp_ms,
+        );
+        prune_gauges(
+            &mut RANGE_STAT_GAUGES.lock().unwrap(),
+            |(key, _)| stats.contains_key(key),
+            stale,
+            timestamp_ms,
+        );
+        prune_gauges(
+            &mut THROUGHPUT_TOTAL_GAUGES.lock().unwrap(),
+            |interface| report.interfaces.iter().any(|i| &i.interface == interface),
+            stale,
+            timestamp_ms,
+        );
+        prune_gauges(
+            &mut DEVICE_THROUGHPUT_GAUGES.lock().unwrap(),
+            |device| {
+                report
+                    .devices
+                    .iter()
+                    .any(|d| d.local_ip == device.local_ip && d.interface == device.interface)
+            },
+            stale,
+            timestamp_ms,
+        );
+    }
 }
 
-// HTTPサーバーでメトリクスを公開
-async fn serve_metrics() -> Result<()> {
-    use hyper::service::{make_service_fn, service_fn};
-    use hyper::{Body, Request, Response, Server};
-
-    let make_svc = make_service_fn(|_conn| async {
-        Ok::<_, hyper::Error>(service_fn(|_req: Request<Body>| async {
-            let encoder = TextEncoder::new();
-            let metric_families = REGISTRY.gather();
-            let mut buffer = vec![];
-            encoder.encode(&metric_families, &mut buffer).unwrap();
-
-            Response::builder()
-                .status(200)
-                .header("Content-Type", encoder.format_type())
-                .body(Body::from(buffer))
-        }))
+// throughputdump の系列に対応する入力の古さ（秒）を設定する（不明なら NaN）
+fn set_input_age(key: &SeriesKey, age: Option<f64>, eval_timestamp_ms: i64) {
+    let mut age_gauges = INPUT_AGE_GAUGES.lock().unwrap();
+    let gauge = age_gauges.entry(key.clone()).or_insert_with(|| {
+        let gauge = Gauge::with_opts(
+            Opts::new(
+                "input_age_seconds",
+                "Age of the oldest RTT sample or byte window behind the throughputdump series",
+            )
+            .const_label("interface", &key.interface)
+            .const_label("remote_ip", &key.remote_ip)
+            .const_label("job", "throughputdump"),
+        )
+        .unwrap();
+        REGISTRY.register(Box::new(gauge.clone())).unwrap();
+        gauge
     });
+    set_gauge(gauge, age.unwrap_or(f64::NAN), eval_timestamp_ms);
+}
+
+// 範囲から求めたスループットの平均・中央値・p95 を interface + remote_ip ごとに設定する
+fn publish_range_stats(stats: &HashMap<SeriesKey, ThroughputStats>, eval_timestamp_ms: i64) {
+    let mut stat_gauges = RANGE_STAT_GAUGES.lock().unwrap();
+    for (key, stats) in stats {
+        for (name, help, value) in [
+            (
+                "throughputdump_avg",
+                "Average throughput over the range lookback",
+                stats.avg,
+            ),
+            (
+                "throughputdump_p50",
+                "Median throughput over the range lookback",
+                stats.p50,
+            ),
+            (
+                "throughputdump_p95",
+                "95th percentile throughput over the range lookback",
+                stats.p95,
+            ),
+        ] {
+            let gauge = stat_gauges.entry((key.clone(), name)).or_insert_with(|| {
+                let gauge = Gauge::with_opts(
+                    Opts::new(name, help)
+                        .const_label("interface", &key.interface)
+                        .const_label("remote_ip", &key.remote_ip)
+                        .const_label("job", "throughputdump"),
+                )
+                .unwrap();
+                REGISTRY.register(Box::new(gauge.clone())).unwrap();
+                gauge
+            });
+            set_gauge(gauge, value, eval_timestamp_ms);
+        }
+    }
+}
+
+// 端末ごとのスループット: 端末が通信した各リモートについて THROUGHPUT_FORMULA で求めた値の合計
+fn update_device_throughput(
+    status: Option<&StatusResponse>,
+    formula: &Formula,
+    rtt_map: &HashMap<SeriesKey, f64>,
+    loss_map: &HashMap<SeriesKey, f64>,
+    device_bytes: &HashMap<(DeviceKey, String), (f64, f64)>,
+    bytes_without_local_ip: bool,
+    eval_timestamp_ms: i64,
+) -> Vec<DeviceThroughput> {
+    // 通信量に local_ip ラベルが無ければ端末ごとには求められない。常に 0 を公開しないよう計算しない
+    if device_bytes.is_empty() && bytes_without_local_ip {
+        if !DEVICE_THROUGHPUT_SKIPPED.swap(true, Ordering::Relaxed) {
+            warn!(
+                "download_bytes / upload_bytes have no {} label; device_throughput needs localPacketDump-rs with PERSPECTIVE=both and is not published",
+                LABEL_LOCAL_IP
+            );
+        }
+        return Vec::new();
+    }
+    let mut device_totals: HashMap<DeviceKey, f64> = HashMap::new();
+
+    // マッピングにある端末は通信が無くても 0 を公開する
+    if let Some(status) = status {
+        for local_ip in status.mappings.keys() {
+            device_totals.insert(
+                DeviceKey {
+                    local_ip: local_ip.clone(),
+                    interface: status.interface_for(local_ip).to_string(),
+                },
+                0.0,
+            );
+        }
+    }
 
-    let addr = ([0, 0, 0, 0], 59124).into();
-    let server = Server::bind(&addr).serve(make_svc);
+    for ((device, remote_ip), (download, upload)) in device_bytes {
+        let key = SeriesKey {
+            interface: device.interface.clone(),
+            remote_ip: remote_ip.clone(),
+        };
+        let rtt = match rtt_map.get(&key) {
+            Some(rtt) if *rtt > 0.0 => *rtt,
+            _ => continue,
+        };
+        let variables = Variables {
+            download: *download,
+            upload: *upload,
+            rtt,
+            loss: loss_map.get(&key).copied().unwrap_or(0.0),
+        };
+        if let Some(throughput) = formula.eval(&variables) {
+            *device_totals.entry(device.clone()).or_insert(0.0) += throughput;
+        }
+    }
 
-    info!("Metrics server listening on http://localhost:59124/metrics");
+    let mut device_gauges = DEVICE_THROUGHPUT_GAUGES.lock().unwrap();
+    for (device, throughput) in &device_totals {
+        let gauge = device_gauges.entry(device.clone()).or_insert_with(|| {
+            let gauge = Gauge::with_opts(
+                Opts::new(
+                    "device_throughput",
+                    "Calculated throughput per local device based on bytes and RTT",
+                )
+                .const_label("local_ip", &device.local_ip)
+                .const_label("interface", &device.interface)
+                .const_label("job", "throughputdump"),
+            )
+            .unwrap();
+            REGISTRY.register(Box::new(gauge.clone())).unwrap();
+            gauge
+        });
 
-    server.await.context("Server error")?;
+        set_gauge(gauge, *throughput, eval_timestamp_ms);
+    }
+
+    device_totals
+        .into_iter()
+        .map(|(device, throughput)| DeviceThroughput {
+            local_ip: device.local_ip,
+            interface: device.interface,
+            throughput,
+        })
+        .collect()
+}
+
+// HTTPサーバーでメトリクスを公開
+async fn serve_metrics(port: u16) -> Result<()> {
+    MetricsServer::new(([0, 0, 0, 0], port), REGISTRY.clone())
+        // METRICS_AUTH_* / METRICS_TLS_* が設定されていれば認証と HTTPS を有効にする
+        .with_env_security()
+        .map_err(anyhow::Error::msg)?
+        // /compare は直近の計算結果でのインターフェースの比較を JSON で返す
+        .route("/compare", |_| match compare::to_json() {
+            Some(json) => server::json_response(json),
+            None => server::error_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "no throughput has been calculated yet",
+            ),
+        })
+        // /buildinfo は動いているビルドの情報を JSON で返す
+        .build_info(build_info!())
+        .metrics(|req| {
+            let mut metric_families = REGISTRY.gather();
+            // METRICS_ALLOW / METRICS_DENY などで公開しないものを落とす
+            traffic_scan_core::filter::apply(&mut metric_families);
+            attach_timestamps(&mut metric_families);
+
+            // Accept ヘッダで OpenMetrics が要求された場合はその形式で返す
+            let wants_openmetrics = req
+                .headers()
+                .get(hyper::header::ACCEPT)
+                .and_then(|v| v.to_str().ok())
+                .is_some_and(|v| v.contains("application/openmetrics-text"));
+
+            if wants_openmetrics {
+                return server::Response::builder()
+                    .header(
+                        "Content-Type",
+                        "application/openmetrics-text; version=1.0.0; charset=utf-8",
+                    )
+                    .body(server::Body::from(encode_openmetrics(&metric_families)))
+                    .unwrap();
+            }
+            server::text_response(server::encode_text(&metric_families))
+        })
+        .serve()
+        .await
+        .context("Server error")?;
 
     Ok(())
 }
@@ -262,28 +1259,104 @@ async fn serve_metrics() -> Result<()> {
 async fn main() -> Result<()> {
     env_logger::init();
 
-    let prometheus_url =
-        std::env::var("PROMETHEUS_URL").unwrap_or_else(|_| "http://localhost:9090".to_string());
+    // --config traffic-scan.toml（環境変数が優先）
+    if let Err(e) = shared_config::init(shared_config::THROUGHPUT_DUMP) {
+        error!("{}", e);
+        std::process::exit(2);
+    }
+
+    let prometheus_url = shared_config::var("PROMETHEUS_URL")
+        .unwrap_or_else(|_| "http://localhost:9090".to_string());
+
+    let status_url = shared_config::var("STATUS_URL")
+        .unwrap_or_else(|_| "http://localhost:32599/status".to_string());
 
     info!("Starting throughput-dump");
+    // 動いているビルドのバージョンなど（値は常に 1）
+    server::register_build_info(&REGISTRY, &build_info!(), Some("throughputdump"))?;
+
     info!("Prometheus URL: {}", prometheus_url);
+    info!("Status URL: {}", status_url);
 
-    let calculator = Arc::new(ThroughputCalculator::new(prometheus_url));
+    // icmp-traffic-scan と同じく 100 バイト以下の通信は無視する
+    let min_bytes: f64 = shared_config::var("MIN_BYTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(100.0);
+    info!("Minimum bytes: {}", min_bytes);
+
+    let listen_port: u16 = shared_config::var("LISTEN_PORT")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(59124);
+    // 0 では tokio::time::interval が panic するので 1 秒以上にする
+    let calculate_interval = Duration::from_secs(
+        shared_config::var("CALCULATE_INTERVAL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(1)
+            .max(1),
+    );
+
+    let rtt_aggregation = RttAggregation::from_env();
+    info!("RTT aggregation: {:?}", rtt_aggregation);
+
+    let simulation = shared_sim::enabled().then(|| Arc::new(Scenario::from_env()));
+    if let Some(scenario) = &simulation {
+        info!(
+            "Simulating Prometheus and status API: {}",
+            scenario.summary()
+        );
+    }
+
+    let sinks = sink::sinks_from_env();
+    let calculator = Arc::new(ThroughputCalculator::new(
+        prometheus_url,
+        status_url,
+        sinks,
+        min_bytes,
+        rtt_aggregation,
+        MeasurementFilter::from_env(),
+        simulation,
+    ));
+
+    // ステータス（端末マッピング）更新タスク (10秒ごと)
+    let calculator_for_status = calculator.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(10));
+        loop {
+            interval.tick().await;
+            calculator_for_status.fetch_status().await;
+        }
+    });
+
+    // REMOTE_WRITE_URL があれば計算のたびに値を溜めて Prometheus へ送る
+    let remote_write =
+        RemoteWrite::from_env(&REGISTRY, Arc::new(HttpClient::from_env())).map(Arc::new);
+    if let Some(remote_write) = &remote_write {
+        tokio::spawn(Arc::clone(remote_write).run());
+    }
 
     // メトリクス更新タスク
+    // 計算が止まったら systemd の watchdog で再起動させる（WatchdogSec= を設定したときだけ）
     let calculator_clone = calculator.clone();
     tokio::spawn(async move {
-        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        let mut interval = tokio::time::interval(calculate_interval);
+        let mut watchdog = traffic_scan_core::systemd::Watchdog::from_env();
         loop {
             interval.tick().await;
             if let Err(e) = calculator_clone.calculate_throughput().await {
                 error!("Error calculating throughput: {}", e);
             }
+            if let Some(remote_write) = &remote_write {
+                remote_write.collect();
+            }
+            watchdog.pet();
         }
     });
 
     // メトリクスサーバー起動
-    serve_metrics().await?;
+    serve_metrics(listen_port).await?;
 
     Ok(())
 }
@@ -0,0 +1,120 @@
+// 直近 RANGE_LOOKBACK_SECS の範囲クエリによるスループットの平滑化
+//
+// 瞬時ベクトルのクエリでは 1 回のスクレイプの取りこぼしや 1 周期だけの RTT の跳ねがそのまま
+// スループットに出て、WAN の選択がそのたびに揺れる。/api/v1/query_range で直近の
+// 入力（RTT_QUERY など）を RANGE_STEP_SECS ごとに取り、各時刻の THROUGHPUT_FORMULA の値から
+// interface + remote_ip ごとの平均・中央値・p95 を求める。
+// THROUGHPUT_STAT で throughputdump と合計・出力先・アラートに使う値をそのいずれかに切り替えられる。
+
+use log::{info, warn};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use traffic_scan_core::{Sample, Series};
+
+// throughputdump などに使う値
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stat {
+    // 最後の時刻の値（従来どおり）
+    Latest,
+    Avg,
+    P50,
+    P95,
+}
+
+#[derive(Debug, Clone)]
+pub struct RangeConfig {
+    // 何秒前から取るか
+    pub lookback_secs: f64,
+    // 評価の間隔（秒）
+    pub step_secs: f64,
+    pub stat: Stat,
+}
+
+impl RangeConfig {
+    // RANGE_LOOKBACK_SECS=0 なら無効（瞬時ベクトルのクエリだけを使う）
+    pub fn from_env() -> Option<Self> {
+        let lookback_secs: f64 = shared_config::var("RANGE_LOOKBACK_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(60.0);
+        if lookback_secs <= 0.0 {
+            return None;
+        }
+        let step_secs: f64 = shared_config::var("RANGE_STEP_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|step: &f64| *step > 0.0)
+            .unwrap_or(1.0);
+        let stat = match shared_config::var("THROUGHPUT_STAT").as_deref() {
+            Ok("avg") => Stat::Avg,
+            Ok("p50") => Stat::P50,
+            Ok("p95") => Stat::P95,
+            Ok("latest") | Err(_) => Stat::Latest,
+            Ok(other) => {
+                warn!("Unknown THROUGHPUT_STAT={}, using latest", other);
+                Stat::Latest
+            }
+        };
+        info!(
+            "Smoothing throughput over {}s in {}s steps (throughputdump uses {:?})",
+            lookback_secs, step_secs, stat
+        );
+        Some(Self {
+            lookback_secs,
+            step_secs,
+            stat,
+        })
+    }
+}
+
+// interface + remote_ip ごとの直近の範囲のスループット
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ThroughputStats {
+    pub avg: f64,
+    pub p50: f64,
+    pub p95: f64,
+    // 計算できた時刻の数
+    pub samples: usize,
+}
+
+impl ThroughputStats {
+    pub fn from_values(mut values: Vec<f64>) -> Option<Self> {
+        if values.is_empty() {
+            return None;
+        }
+        values.sort_by(|a, b| a.total_cmp(b));
+        Some(Self {
+            avg: values.iter().sum::<f64>() / values.len() as f64,
+            p50: percentile(&values, 0.5),
+            p95: percentile(&values, 0.95),
+            samples: values.len(),
+        })
+    }
+
+    // Stat::Latest は None（最後の時刻の値をそのまま使う）
+    pub fn get(&self, stat: Stat) -> Option<f64> {
+        match stat {
+            Stat::Latest => None,
+            Stat::Avg => Some(self.avg),
+            Stat::P50 => Some(self.p50),
+            Stat::P95 => Some(self.p95),
+        }
+    }
+}
+
+// ソート済みの値から nearest-rank 方式でパーセンタイルを求める
+fn percentile(sorted: &[f64], q: f64) -> f64 {
+    let rank = (q * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+// 範囲のクエリ結果を評価時刻（ミリ秒）ごとの瞬時ベクトルに並べ替える
+pub fn by_timestamp(series: &[Series]) -> BTreeMap<i64, Vec<Sample>> {
+    let mut at: BTreeMap<i64, Vec<Sample>> = BTreeMap::new();
+    for sample in series.iter().flat_map(Series::samples) {
+        at.entry((sample.timestamp * 1000.0).round() as i64)
+            .or_default()
+            .push(sample);
+    }
+    at
+}
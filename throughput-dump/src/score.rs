@@ -0,0 +1,367 @@
+// 入力のクエリとスループットの計算式（RTT_QUERY など / THROUGHPUT_FORMULA）
+//
+// 入力のメトリクス名と (download + upload) / rtt の式が固定だと、WAN の選択の重み付けを変えるたびに
+// 再ビルドが要る。入力はそれぞれ PromQL で差し替えられ、式は用意した戦略の名前か
+// download / upload / bytes / rtt / loss の四則演算とべき乗（^）で書ける。
+//
+//   bytes_per_rtt   (download + upload) / rtt（既定）
+//   bytes_per_rtt2  (download + upload) / rtt ^ 2（RTT の差を強く効かせる）
+//   loss_penalized  (download + upload) / rtt * (1 - loss)（icmp-traffic-scan のロス率で割り引く）
+
+use log::{info, warn};
+use std::fmt;
+
+// 入力の PromQL
+#[derive(Debug, Clone)]
+pub struct InputQueries {
+    pub rtt: String,
+    pub download: String,
+    pub upload: String,
+    // 式が loss を使うときだけ問い合わせる
+    pub loss: String,
+}
+
+impl InputQueries {
+    pub fn from_env() -> Self {
+        let query = |name: &str, default: &str| {
+            shared_config::var(name)
+                .ok()
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+                .unwrap_or_else(|| default.to_string())
+        };
+        let queries = Self {
+            rtt: query("RTT_QUERY", "rtt_icmp_dump"),
+            download: query("DOWNLOAD_QUERY", "download_bytes"),
+            upload: query("UPLOAD_QUERY", "upload_bytes"),
+            loss: query("LOSS_QUERY", "icmp_loss_ratio"),
+        };
+        info!(
+            "Input queries: rtt={}, download={}, upload={}",
+            queries.rtt, queries.download, queries.upload
+        );
+        queries
+    }
+}
+
+// 式の変数の値（1 つの interface + remote_ip、または端末）
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Variables {
+    pub download: f64,
+    pub upload: f64,
+    pub rtt: f64,
+    // 0〜1（LOSS_QUERY に系列が無ければ 0）
+    pub loss: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Var {
+    Download,
+    Upload,
+    Bytes,
+    Rtt,
+    Loss,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Number(f64),
+    Var(Var),
+    Neg(Box<Expr>),
+    Binary(char, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    fn eval(&self, v: &Variables) -> f64 {
+        match self {
+            Expr::Number(n) => *n,
+            Expr::Var(Var::Download) => v.download,
+            Expr::Var(Var::Upload) => v.upload,
+            Expr::Var(Var::Bytes) => v.download + v.upload,
+            Expr::Var(Var::Rtt) => v.rtt,
+            Expr::Var(Var::Loss) => v.loss,
+            Expr::Neg(e) => -e.eval(v),
+            Expr::Binary(op, a, b) => {
+                let (a, b) = (a.eval(v), b.eval(v));
+                match op {
+                    '+' => a + b,
+                    '-' => a - b,
+                    '*' => a * b,
+                    '/' => a / b,
+                    _ => a.powf(b),
+                }
+            }
+        }
+    }
+
+    fn uses(&self, var: Var) -> bool {
+        match self {
+            Expr::Number(_) => false,
+            Expr::Var(v) => *v == var,
+            Expr::Neg(e) => e.uses(var),
+            Expr::Binary(_, a, b) => a.uses(var) || b.uses(var),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Formula {
+    source: String,
+    expr: Expr,
+}
+
+impl fmt::Display for Formula {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+const DEFAULT_FORMULA: &str = "(download + upload) / rtt";
+
+impl Formula {
+    // 解釈できなければ警告して既定の式を使う
+    pub fn from_env() -> Self {
+        let value = shared_config::var("THROUGHPUT_FORMULA").unwrap_or_default();
+        let formula = match Self::parse(&value) {
+            Ok(formula) => formula,
+            Err(e) => {
+                warn!(
+                    "Invalid THROUGHPUT_FORMULA={} ({}), using {}",
+                    value, e, DEFAULT_FORMULA
+                );
+                Self::parse(DEFAULT_FORMULA).unwrap()
+            }
+        };
+        info!("Throughput formula: {}", formula);
+        formula
+    }
+
+    // 戦略の名前か式（空なら既定）
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let source = match value.trim() {
+            "" | "bytes_per_rtt" => DEFAULT_FORMULA,
+            "bytes_per_rtt2" => "(download + upload) / rtt ^ 2",
+            "loss_penalized" => "(download + upload) / rtt * (1 - loss)",
+            expression => expression,
+        };
+        let mut parser = Parser {
+            chars: source.chars().collect(),
+            pos: 0,
+        };
+        let expr = parser.expression()?;
+        parser.skip_whitespace();
+        if let Some(c) = parser.peek() {
+            return Err(format!("unexpected '{}' at {}", c, parser.pos));
+        }
+        Ok(Self {
+            source: source.to_string(),
+            expr,
+        })
+    }
+
+    pub fn uses_loss(&self) -> bool {
+        self.expr.uses(Var::Loss)
+    }
+
+    // 有限の値にならなければ None
+    pub fn eval(&self, variables: &Variables) -> Option<f64> {
+        Some(self.expr.eval(variables)).filter(|value| value.is_finite())
+    }
+}
+
+// expression := term (('+' | '-') term)*
+// term       := unary (('*' | '/') unary)*
+// unary      := '-' unary | power
+// power      := atom ('^' unary)?
+// atom       := number | variable | '(' expression ')'
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    // 空白を飛ばし、次が c なら読み進める
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expression(&mut self) -> Result<Expr, String> {
+        let mut expr = self.term()?;
+        loop {
+            let op = if self.eat('+') {
+                '+'
+            } else if self.eat('-') {
+                '-'
+            } else {
+                return Ok(expr);
+            };
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.term()?));
+        }
+    }
+
+    fn term(&mut self) -> Result<Expr, String> {
+        let mut expr = self.unary()?;
+        loop {
+            let op = if self.eat('*') {
+                '*'
+            } else if self.eat('/') {
+                '/'
+            } else {
+                return Ok(expr);
+            };
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.eat('-') {
+            return Ok(Expr::Neg(Box::new(self.unary()?)));
+        }
+        self.power()
+    }
+
+    fn power(&mut self) -> Result<Expr, String> {
+        let base = self.atom()?;
+        if self.eat('^') {
+            return Ok(Expr::Binary('^', Box::new(base), Box::new(self.unary()?)));
+        }
+        Ok(base)
+    }
+
+    fn atom(&mut self) -> Result<Expr, String> {
+        if self.eat('(') {
+            let expr = self.expression()?;
+            if !self.eat(')') {
+                return Err(format!("missing ')' at {}", self.pos));
+            }
+            return Ok(expr);
+        }
+        self.skip_whitespace();
+        let start = self.pos;
+        match self.peek() {
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                while self.peek().is_some_and(|c| c.is_ascii_digit() || c == '.') {
+                    self.pos += 1;
+                }
+                let number: String = self.chars[start..self.pos].iter().collect();
+                number
+                    .parse()
+                    .map(Expr::Number)
+                    .map_err(|_| format!("invalid number '{}'", number))
+            }
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+                while self
+                    .peek()
+                    .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_')
+                {
+                    self.pos += 1;
+                }
+                let name: String = self.chars[start..self.pos].iter().collect();
+                let var = match name.as_str() {
+                    "download" => Var::Download,
+                    "upload" => Var::Upload,
+                    "bytes" => Var::Bytes,
+                    "rtt" => Var::Rtt,
+                    "loss" => Var::Loss,
+                    _ => {
+                        return Err(format!(
+                            "unknown variable '{}' (download, upload, bytes, rtt, loss)",
+                            name
+                        ))
+                    }
+                };
+                Ok(Expr::Var(var))
+            }
+            Some(c) => Err(format!("unexpected '{}' at {}", c, self.pos)),
+            None => Err("unexpected end of formula".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(formula: &str, variables: Variables) -> f64 {
+        Formula::parse(formula).unwrap().eval(&variables).unwrap()
+    }
+
+    fn constant(formula: &str) -> f64 {
+        eval(formula, Variables::default())
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(constant("1 + 2 * 3"), 7.0);
+        assert_eq!(constant("(1 + 2) * 3"), 9.0);
+        assert_eq!(constant("8 / 4 / 2"), 1.0);
+        assert_eq!(constant("10 - 4 - 3"), 3.0);
+        assert_eq!(constant("2 * 3 ^ 2"), 18.0);
+    }
+
+    #[test]
+    fn power_is_right_associative() {
+        assert_eq!(constant("2 ^ 3 ^ 2"), 512.0);
+        assert_eq!(constant("(2 ^ 3) ^ 2"), 64.0);
+        assert_eq!(constant("2 ^ -1"), 0.5);
+    }
+
+    #[test]
+    fn unary_minus() {
+        // ^ は単項マイナスより強い
+        assert_eq!(constant("-2 ^ 2"), -4.0);
+        assert_eq!(constant("(-2) ^ 2"), 4.0);
+        assert_eq!(constant("--3"), 3.0);
+        assert_eq!(constant("1 - -1"), 2.0);
+    }
+
+    #[test]
+    fn variables_and_strategies() {
+        let variables = Variables {
+            download: 300.0,
+            upload: 100.0,
+            rtt: 2.0,
+            loss: 0.5,
+        };
+        assert_eq!(eval("bytes_per_rtt", variables), 200.0);
+        assert_eq!(eval("", variables), 200.0);
+        assert_eq!(eval("bytes_per_rtt2", variables), 100.0);
+        assert_eq!(eval("loss_penalized", variables), 100.0);
+        assert_eq!(eval("bytes / rtt", variables), 200.0);
+        assert!(Formula::parse("loss_penalized").unwrap().uses_loss());
+        assert!(!Formula::parse("bytes_per_rtt").unwrap().uses_loss());
+    }
+
+    #[test]
+    fn non_finite_is_none() {
+        let formula = Formula::parse("bytes / rtt").unwrap();
+        assert_eq!(formula.eval(&Variables::default()), None);
+    }
+
+    #[test]
+    fn error_positions() {
+        let error = |formula: &str| Formula::parse(formula).unwrap_err();
+        assert_eq!(error("rtt $"), "unexpected '$' at 4");
+        assert_eq!(error("rtt * )"), "unexpected ')' at 6");
+        assert_eq!(error("(rtt + 1"), "missing ')' at 8");
+        assert_eq!(error("rtt +"), "unexpected end of formula");
+        assert_eq!(error("1.2.3"), "invalid number '1.2.3'");
+        assert!(error("latency").starts_with("unknown variable 'latency'"));
+    }
+}
@@ -0,0 +1,189 @@
+// calculate_throughput の計算結果の出力先
+//
+// Prometheus の Gauge に加えて、ルーターの判定エンジンなどへ直接結果を渡すための出力先。
+// OUTPUT_SINKS（カンマ区切り: stdout, file, mqtt）で有効にする。
+
+use crate::range::ThroughputStats;
+use anyhow::{Context, Result};
+use log::{error, info};
+#[cfg(feature = "mqtt")]
+use rumqttc::{AsyncClient, MqttOptions, QoS};
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::Mutex;
+#[cfg(feature = "mqtt")]
+use std::time::Duration;
+
+// 1 周期分の計算結果
+#[derive(Debug, Default, Serialize)]
+pub struct ThroughputReport {
+    pub timestamp_ms: i64,
+    pub remotes: Vec<RemoteThroughput>,
+    pub interfaces: Vec<InterfaceThroughput>,
+    pub devices: Vec<DeviceThroughput>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RemoteThroughput {
+    pub interface: String,
+    pub remote_ip: String,
+    pub download_bytes: f64,
+    pub upload_bytes: f64,
+    pub rtt: f64,
+    pub throughput: f64,
+    // 入力の RTT / バイト数のうち最も古いサンプルの経過秒数（取得できなければ None）
+    pub input_age_seconds: Option<f64>,
+    // RANGE_LOOKBACK_SECS の範囲から求めたスループット（範囲クエリでなければ None）
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub range: Option<ThroughputStats>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InterfaceThroughput {
+    pub interface: String,
+    pub throughput: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeviceThroughput {
+    pub local_ip: String,
+    pub interface: String,
+    pub throughput: f64,
+}
+
+pub trait OutputSink: Send + Sync {
+    fn name(&self) -> &str;
+    fn write(&self, report: &ThroughputReport) -> Result<()>;
+}
+
+// 1 行 1 JSON で標準出力へ書き出す
+pub struct StdoutJsonSink;
+
+impl OutputSink for StdoutJsonSink {
+    fn name(&self) -> &str {
+        "stdout"
+    }
+
+    fn write(&self, report: &ThroughputReport) -> Result<()> {
+        let line = serde_json::to_string(report)?;
+        let mut stdout = std::io::stdout().lock();
+        writeln!(stdout, "{}", line)?;
+        Ok(())
+    }
+}
+
+// JSON Lines 形式でファイルへ追記する
+pub struct FileSink {
+    file: Mutex<File>,
+}
+
+impl FileSink {
+    pub fn open(path: &str) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open output file {}", path))?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl OutputSink for FileSink {
+    fn name(&self) -> &str {
+        "file"
+    }
+
+    fn write(&self, report: &ThroughputReport) -> Result<()> {
+        let line = serde_json::to_string(report)?;
+        let mut file = self.file.lock().unwrap();
+        writeln!(file, "{}", line)?;
+        Ok(())
+    }
+}
+
+// MQTT ブローカーへ JSON を publish する
+#[cfg(feature = "mqtt")]
+pub struct MqttSink {
+    client: AsyncClient,
+    topic: String,
+}
+
+#[cfg(feature = "mqtt")]
+impl MqttSink {
+    // 接続の維持は別タスクのイベントループで行う（tokio ランタイム内で呼ぶこと）
+    pub fn connect(host: &str, port: u16, topic: String) -> Self {
+        let mut options = MqttOptions::new("throughput-dump", host, port);
+        options.set_keep_alive(Duration::from_secs(30));
+        let (client, mut eventloop) = AsyncClient::new(options, 16);
+
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = eventloop.poll().await {
+                    log::warn!("MQTT connection error: {}", e);
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                }
+            }
+        });
+
+        Self { client, topic }
+    }
+}
+
+#[cfg(feature = "mqtt")]
+impl OutputSink for MqttSink {
+    fn name(&self) -> &str {
+        "mqtt"
+    }
+
+    fn write(&self, report: &ThroughputReport) -> Result<()> {
+        let payload = serde_json::to_vec(report)?;
+        self.client
+            .try_publish(&self.topic, QoS::AtMostOnce, false, payload)
+            .context("Failed to publish to MQTT")?;
+        Ok(())
+    }
+}
+
+// 環境変数から出力先を構築する
+pub fn sinks_from_env() -> Vec<Box<dyn OutputSink>> {
+    let names = shared_config::var("OUTPUT_SINKS").unwrap_or_default();
+    let mut sinks: Vec<Box<dyn OutputSink>> = Vec::new();
+
+    for name in names.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+        match name {
+            "stdout" => sinks.push(Box::new(StdoutJsonSink)),
+            "file" => {
+                let path = shared_config::var("OUTPUT_FILE")
+                    .unwrap_or_else(|_| "throughput-dump.jsonl".to_string());
+                match FileSink::open(&path) {
+                    Ok(sink) => sinks.push(Box::new(sink)),
+                    Err(e) => error!("{:#}", e),
+                }
+            }
+            #[cfg(feature = "mqtt")]
+            "mqtt" => {
+                let host =
+                    shared_config::var("MQTT_HOST").unwrap_or_else(|_| "localhost".to_string());
+                let port = shared_config::var("MQTT_PORT")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(1883);
+                let topic = shared_config::var("MQTT_TOPIC")
+                    .unwrap_or_else(|_| "nextrouter/throughput".to_string());
+                sinks.push(Box::new(MqttSink::connect(&host, port, topic)));
+            }
+            #[cfg(not(feature = "mqtt"))]
+            "mqtt" => error!("Output sink mqtt is not available (built without the mqtt feature)"),
+            other => error!("Unknown output sink: {}", other),
+        }
+    }
+
+    for sink in &sinks {
+        info!("Output sink enabled: {}", sink.name());
+    }
+
+    sinks
+}
@@ -0,0 +1,133 @@
+// メトリクスサーバーの認証
+//
+// エクスポーターはルーティングされたセグメント越しにスクレイプされるため、
+// METRICS_AUTH_TOKEN（Bearer）か METRICS_AUTH_BASIC（user:password の Basic 認証）を設定すると
+// Authorization ヘッダの無いリクエストを拒否する。両方設定した場合はどちらでも通す。
+// 送る側（Prometheus へのクエリや remote_write）の資格情報は Credentials。
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use shared_http::{Config, HttpClient, Lookup};
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Default)]
+pub struct MetricsAuth {
+    bearer: Option<String>,
+    // "user:password"
+    basic: Option<String>,
+}
+
+impl MetricsAuth {
+    pub fn from_env() -> Self {
+        let var = |name| shared_config::var(name).ok().filter(|v| !v.is_empty());
+        Self {
+            bearer: var("METRICS_AUTH_TOKEN"),
+            basic: var("METRICS_AUTH_BASIC"),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.bearer.is_some() || self.basic.is_some()
+    }
+
+    // Authorization ヘッダの値が通るか（認証を設定していなければ常に通す）
+    pub fn allows(&self, authorization: Option<&str>) -> bool {
+        if !self.is_enabled() {
+            return true;
+        }
+        let Some(authorization) = authorization else {
+            return false;
+        };
+        if let (Some(expected), Some(token)) = (&self.bearer, authorization.strip_prefix("Bearer "))
+        {
+            if constant_time_eq(token.trim().as_bytes(), expected.as_bytes()) {
+                return true;
+            }
+        }
+        if let (Some(expected), Some(encoded)) = (&self.basic, authorization.strip_prefix("Basic "))
+        {
+            if let Ok(decoded) = STANDARD.decode(encoded.trim()) {
+                return constant_time_eq(&decoded, expected.as_bytes());
+            }
+        }
+        false
+    }
+
+    // 401 の WWW-Authenticate ヘッダ
+    pub fn challenge(&self) -> &'static str {
+        if self.basic.is_some() {
+            "Basic realm=\"metrics\""
+        } else {
+            "Bearer"
+        }
+    }
+}
+
+// 認証付きの送信先（リバースプロキシの背後の Prometheus など）に付ける資格情報
+#[derive(Debug, Clone)]
+pub(crate) enum Credentials {
+    Bearer(String),
+    Basic { username: String, password: String },
+}
+
+impl Credentials {
+    // {prefix}_BEARER_TOKEN か {prefix}_BASIC_AUTH（user:password）。両方あれば Bearer
+    pub(crate) fn from_env(prefix: &str) -> Option<Self> {
+        Self::from_lookup(prefix, &shared_config::var)
+    }
+
+    pub(crate) fn from_lookup(prefix: &str, lookup: &impl Lookup) -> Option<Self> {
+        let var = |name: String| lookup(&name).ok().filter(|v| !v.is_empty());
+        if let Some(token) = var(format!("{}_BEARER_TOKEN", prefix)) {
+            return Some(Credentials::Bearer(token));
+        }
+        let basic = var(format!("{}_BASIC_AUTH", prefix))?;
+        let (username, password) = basic.split_once(':').unwrap_or((&basic, ""));
+        Some(Credentials::Basic {
+            username: username.to_string(),
+            password: password.to_string(),
+        })
+    }
+
+    pub(crate) fn apply(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match self {
+            Credentials::Bearer(token) => request.bearer_auth(token),
+            Credentials::Basic { username, password } => {
+                request.basic_auth(username, Some(password))
+            }
+        }
+    }
+}
+
+// {prefix}_CA_CERT があれば、その CA を信頼する送信先専用のクライアントを作る
+// （他の宛先には信頼させない）。無ければ共有のクライアントをそのまま使う
+pub(crate) fn client_with_ca(prefix: &str, shared: Arc<HttpClient>) -> Arc<HttpClient> {
+    client_with_ca_from(prefix, shared, &shared_config::var)
+}
+
+pub(crate) fn client_with_ca_from(
+    prefix: &str,
+    shared: Arc<HttpClient>,
+    lookup: &impl Lookup,
+) -> Arc<HttpClient> {
+    match lookup(&format!("{}_CA_CERT", prefix))
+        .ok()
+        .filter(|v| !v.is_empty())
+    {
+        Some(ca_cert) => Arc::new(HttpClient::new(Config {
+            ca_cert: Some(ca_cert),
+            ..Config::from_lookup(lookup)
+        })),
+        None => shared,
+    }
+}
+
+// 一致するまでの時間から値を推測されないよう、長さが同じなら全バイトを比べる
+// （各バイナリの制御 API などのトークンの照合にも使う。traffic-scan-core に依存しない
+// tcp-traffic-scan の reflector に同じ実装があるので、変えるときは両方を変える）
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,210 @@
+// 公開するメトリクスの絞り込み
+//
+// サイトごとにスクレイプの量を減らせるよう（upload_bytes をまるごと捨てるなど）、
+// エンコードする前にメトリクスファミリーと系列を設定で落とす。パターンは `*`（任意の文字列）と
+// `?`（任意の 1 文字）のワイルドカードで、値全体と一致したものを対象にする。
+//
+// METRICS_ALLOW          残すファミリー名（設定したときだけ、一致しないファミリーを落とす）
+// METRICS_DENY           落とすファミリー名（METRICS_ALLOW より優先）
+// METRICS_ALLOW_LABELS   `label=pattern`。そのラベルを持つ系列は、いずれかのパターンに一致するものだけ残す
+// METRICS_DENY_LABELS    `label=pattern`。ラベルの値が一致した系列を落とす
+//
+// どれもカンマ区切り（設定ファイルでは配列でもよい）。メトリクスサーバーと remote_write の両方に効く。
+
+use log::{info, warn};
+use prometheus::proto::MetricFamily;
+use shared_http::Lookup;
+use std::sync::OnceLock;
+
+static GLOBAL: OnceLock<MetricFilter> = OnceLock::new();
+
+#[derive(Debug, Clone, Default)]
+pub struct MetricFilter {
+    allow: Vec<String>,
+    deny: Vec<String>,
+    // (ラベル名, 値のパターン)
+    allow_labels: Vec<(String, String)>,
+    deny_labels: Vec<(String, String)>,
+}
+
+impl MetricFilter {
+    pub fn from_env() -> Self {
+        Self::from_lookup(shared_config::var)
+    }
+
+    pub fn from_lookup(var: impl Lookup) -> Self {
+        let list = |name: &str| -> Vec<String> {
+            var(name)
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+                .map(str::to_string)
+                .collect()
+        };
+        let labels = |name: &str| -> Vec<(String, String)> {
+            list(name)
+                .into_iter()
+                .filter_map(|entry| match entry.split_once('=') {
+                    Some((label, pattern)) if !label.trim().is_empty() => {
+                        Some((label.trim().to_string(), pattern.trim().to_string()))
+                    }
+                    _ => {
+                        warn!("Ignoring {} entry {} (expected label=pattern)", name, entry);
+                        None
+                    }
+                })
+                .collect()
+        };
+        let filter = Self {
+            allow: list("METRICS_ALLOW"),
+            deny: list("METRICS_DENY"),
+            allow_labels: labels("METRICS_ALLOW_LABELS"),
+            deny_labels: labels("METRICS_DENY_LABELS"),
+        };
+        if filter.is_enabled() {
+            info!(
+                "Filtering exposed metrics: allow {:?}, deny {:?}, allow labels {:?}, deny labels {:?}",
+                filter.allow, filter.deny, filter.allow_labels, filter.deny_labels
+            );
+        }
+        filter
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !(self.allow.is_empty()
+            && self.deny.is_empty()
+            && self.allow_labels.is_empty()
+            && self.deny_labels.is_empty())
+    }
+
+    // ファミリー名が残るか
+    pub fn allows_family(&self, name: &str) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|p| matches(p, name)))
+            && !self.deny.iter().any(|p| matches(p, name))
+    }
+
+    // 系列のラベルの組が残るか
+    pub fn allows_labels<'a>(&self, labels: impl IntoIterator<Item = (&'a str, &'a str)>) -> bool {
+        for (name, value) in labels {
+            if self
+                .deny_labels
+                .iter()
+                .any(|(label, pattern)| label == name && matches(pattern, value))
+            {
+                return false;
+            }
+            let mut patterns = self
+                .allow_labels
+                .iter()
+                .filter(|(label, _)| label == name)
+                .peekable();
+            if patterns.peek().is_some() && !patterns.any(|(_, pattern)| matches(pattern, value)) {
+                return false;
+            }
+        }
+        true
+    }
+
+    // 落とした系列が最後の 1 つだったファミリーも落とす
+    pub fn apply(&self, families: &mut Vec<MetricFamily>) {
+        if !self.is_enabled() {
+            return;
+        }
+        families.retain(|family| self.allows_family(family.get_name()));
+        if self.allow_labels.is_empty() && self.deny_labels.is_empty() {
+            return;
+        }
+        for family in families.iter_mut() {
+            let metrics: Vec<_> = family
+                .take_metric()
+                .into_iter()
+                .filter(|metric| {
+                    self.allows_labels(
+                        metric
+                            .get_label()
+                            .iter()
+                            .map(|pair| (pair.get_name(), pair.get_value())),
+                    )
+                })
+                .collect();
+            family.set_metric(metrics.into());
+        }
+        families.retain(|family| !family.get_metric().is_empty());
+    }
+}
+
+// METRICS_* から読んだ、プロセスで共通の絞り込み（最初に使ったときに読む）
+pub fn global() -> &'static MetricFilter {
+    GLOBAL.get_or_init(MetricFilter::from_env)
+}
+
+// gather() したファミリーに global() の絞り込みをかける
+pub fn apply(families: &mut Vec<MetricFamily>) {
+    global().apply(families);
+}
+
+// `*` と `?` のワイルドカードで値全体と一致するか
+fn matches(pattern: &str, value: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let value: Vec<char> = value.chars().collect();
+    // 最後の `*` の位置と、そこから value のどこまでを `*` に割り当てたか
+    let (mut p, mut v) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while v < value.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, v));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == value[v] => {
+                p += 1;
+                v += 1;
+            }
+            _ => match star {
+                Some((star_p, star_v)) => {
+                    p = star_p + 1;
+                    v = star_v + 1;
+                    star = Some((star_p, star_v + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards_match_whole_value() {
+        assert!(matches("upload_bytes", "upload_bytes"));
+        assert!(!matches("upload", "upload_bytes"));
+        assert!(matches("upload_*", "upload_bytes"));
+        assert!(matches("*_bytes", "upload_bytes"));
+        assert!(!matches("*_bytes", "upload_bytes_total"));
+        assert!(matches("rtt_?cmp_dump", "rtt_icmp_dump"));
+        assert!(!matches("rtt_?cmp_dump", "rtt_cmp_dump"));
+    }
+
+    #[test]
+    fn star_matches_empty_and_backtracks() {
+        assert!(matches("*", ""));
+        assert!(matches("**", "abc"));
+        assert!(matches("a*", "a"));
+        assert!(!matches("", "a"));
+        assert!(!matches("?", ""));
+        // 最初の `*` の割り当てが短すぎても、やり直して一致する
+        assert!(matches("*ab*ab", "xabyabab"));
+        assert!(matches("a*b?d", "abbbcd"));
+        assert!(!matches("a*b?d", "abd"));
+    }
+
+    #[test]
+    fn non_ascii() {
+        assert!(matches("wan?", "wan一"));
+        assert!(matches("*回線", "光回線"));
+    }
+}
@@ -0,0 +1,53 @@
+// 系列を識別するラベルの組
+//
+// ラベル名は shared-schema の LABEL_* に合わせる。
+
+use shared_schema::{LABEL_INTERFACE, LABEL_LOCAL_IP, LABEL_REMOTE_IP};
+use std::collections::HashMap;
+
+// interface + remote_ip（リモートごとの通信量と RTT）
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SeriesKey {
+    pub interface: String,
+    pub remote_ip: String,
+}
+
+impl SeriesKey {
+    pub fn new(interface: impl Into<String>, remote_ip: impl Into<String>) -> Self {
+        Self {
+            interface: interface.into(),
+            remote_ip: remote_ip.into(),
+        }
+    }
+
+    // どちらかのラベルが無い系列は None
+    pub fn from_labels(labels: &HashMap<String, String>) -> Option<Self> {
+        Some(Self::new(
+            labels.get(LABEL_INTERFACE)?,
+            labels.get(LABEL_REMOTE_IP)?,
+        ))
+    }
+}
+
+// local_ip + interface（端末ごとの通信量）
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct DeviceKey {
+    pub local_ip: String,
+    pub interface: String,
+}
+
+impl DeviceKey {
+    pub fn new(local_ip: impl Into<String>, interface: impl Into<String>) -> Self {
+        Self {
+            local_ip: local_ip.into(),
+            interface: interface.into(),
+        }
+    }
+
+    pub fn from_labels(labels: &HashMap<String, String>) -> Option<Self> {
+        Some(Self::new(
+            labels.get(LABEL_LOCAL_IP)?,
+            labels.get(LABEL_INTERFACE)?,
+        ))
+    }
+}
@@ -0,0 +1,28 @@
+// 各バイナリで共通の土台
+//
+// Prometheus のレジストリを公開する HTTP サーバー、Prometheus の HTTP API のクライアント、
+// 系列を識別するラベルの組をコンポーネントごとに実装していたため、ここにまとめる。
+// MetricsServer を使うのは icmp-traffic-scan と throughput-dump で、axum のエンドポイントを持つ
+// localPacketDump-rs は自前の Router に router（axum フィーチャー）の認証と TLS をかける。
+// 非同期ランタイムを持たない tcp-traffic-scan は自前のサーバーのまま。
+
+pub mod auth;
+pub mod filter;
+pub mod labels;
+#[cfg(feature = "profiling")]
+pub mod profiling;
+pub mod query;
+pub mod remote_write;
+#[cfg(feature = "axum")]
+pub mod router;
+pub mod server;
+pub mod systemd;
+#[cfg(feature = "tls")]
+pub mod tls;
+
+pub use auth::MetricsAuth;
+pub use filter::MetricFilter;
+pub use labels::{DeviceKey, SeriesKey};
+pub use query::{PrometheusClient, QueryError, Sample, Series};
+pub use remote_write::RemoteWrite;
+pub use server::MetricsServer;
@@ -0,0 +1,111 @@
+// 動いているバイナリのプロファイル（profiling フィーチャー）
+//
+// ルーター上での性能の劣化を作り直さずに調べられるよう、リリースビルドのまま
+// /debug/pprof/profile で CPU プロファイル（pprof の protobuf、`go tool pprof` で読める）を、
+// /debug/heap で jemalloc のヒープの統計を JSON で返す。
+// 統計を取るため、このフィーチャーを有効にするとアロケーターが jemalloc になる。
+
+use serde::Serialize;
+use std::time::Duration;
+use tikv_jemalloc_ctl::{epoch, stats};
+
+#[global_allocator]
+static ALLOCATOR: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+pub const PROFILE_PATH: &str = "/debug/pprof/profile";
+pub const HEAP_PATH: &str = "/debug/heap";
+
+// ?seconds= の既定値と上限（Go の net/http/pprof に合わせる）
+const DEFAULT_SECONDS: u64 = 30;
+const MAX_SECONDS: u64 = 300;
+// サンプリングの頻度（Hz）。?frequency= で変えられる
+const DEFAULT_FREQUENCY: i32 = 99;
+const MAX_FREQUENCY: i32 = 1000;
+
+// /debug/pprof/profile のクエリ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProfileRequest {
+    pub duration: Duration,
+    pub frequency: i32,
+}
+
+impl ProfileRequest {
+    // クエリ文字列（seconds=10&frequency=199）を読む。読めない値は既定値のまま
+    pub fn from_query(query: Option<&str>) -> Self {
+        let mut seconds = DEFAULT_SECONDS;
+        let mut frequency = DEFAULT_FREQUENCY;
+        for pair in query.unwrap_or_default().split('&') {
+            let Some((name, value)) = pair.split_once('=') else {
+                continue;
+            };
+            match name {
+                "seconds" => seconds = value.parse().unwrap_or(seconds),
+                "frequency" => frequency = value.parse().unwrap_or(frequency),
+                _ => {}
+            }
+        }
+        Self {
+            duration: Duration::from_secs(seconds.clamp(1, MAX_SECONDS)),
+            frequency: frequency.clamp(1, MAX_FREQUENCY),
+        }
+    }
+}
+
+// 指定の時間 CPU をサンプリングして pprof の protobuf を返す。SIGPROF のタイマーはプロセスで共有するので、
+// 取っている最中に頼まれるとエラーになる。その間スレッドを止めるので
+// 非同期のランタイムからは spawn_blocking で呼ぶ
+pub fn cpu_profile(request: ProfileRequest) -> Result<Vec<u8>, String> {
+    use pprof::protos::Message;
+
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(request.frequency)
+        // シグナルハンドラーの中でスタックを辿ると libc や pthread の中でデッドロックすることがある
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+        .map_err(|e| format!("failed to start the CPU profiler: {}", e))?;
+    std::thread::sleep(request.duration);
+    let profile = guard
+        .report()
+        .build()
+        .and_then(|report| report.pprof())
+        .map_err(|e| format!("failed to build the CPU profile: {}", e))?;
+    Ok(profile.encode_to_vec())
+}
+
+// jemalloc の統計（バイト）
+#[derive(Debug, Clone, Serialize)]
+pub struct HeapStats {
+    // アプリケーションが確保している量
+    pub allocated: u64,
+    // 確保済みの領域を含むページ
+    pub active: u64,
+    // jemalloc 自身の管理領域
+    pub metadata: u64,
+    // 物理メモリに載っているページ
+    pub resident: u64,
+    pub mapped: u64,
+    // OS に返さず保持している仮想メモリ
+    pub retained: u64,
+}
+
+pub fn heap_stats() -> Result<HeapStats, String> {
+    // 統計は epoch を進めたときに更新される
+    epoch::advance().map_err(|e| format!("failed to refresh jemalloc stats: {}", e))?;
+    let read = |name: &str, value: tikv_jemalloc_ctl::Result<usize>| {
+        value
+            .map(|v| v as u64)
+            .map_err(|e| format!("failed to read jemalloc stats.{}: {}", name, e))
+    };
+    Ok(HeapStats {
+        allocated: read("allocated", stats::allocated::read())?,
+        active: read("active", stats::active::read())?,
+        metadata: read("metadata", stats::metadata::read())?,
+        resident: read("resident", stats::resident::read())?,
+        mapped: read("mapped", stats::mapped::read())?,
+        retained: read("retained", stats::retained::read())?,
+    })
+}
+
+pub fn heap_stats_json() -> Result<String, String> {
+    heap_stats().map(|stats| serde_json::to_string(&stats).expect("failed to encode heap stats"))
+}
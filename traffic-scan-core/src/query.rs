@@ -0,0 +1,357 @@
+// Prometheus の HTTP API（/api/v1/query と /api/v1/query_range）のクライアント
+//
+// 瞬時ベクトルと範囲のクエリを扱う。--simulate のときは shared-sim のシナリオが合成した応答を同じ形で解釈する。
+// ベース URL はカンマ区切りで複数書け、書いた順に試す。送信できなかった・応答が API の形式ではなかった
+// エンドポイントは ENDPOINT_COOLDOWN の間は後回しにし、すべて失敗したら with_env_retry の回数だけ待って繰り返す。
+
+use crate::auth::{self, Credentials};
+use crate::labels::SeriesKey;
+use log::warn;
+use serde::Deserialize;
+use shared_http::HttpClient;
+use shared_sim::Scenario;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// 失敗したエンドポイントを後回しにする時間
+const ENDPOINT_COOLDOWN: Duration = Duration::from_secs(30);
+
+// クエリ結果の 1 系列
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub labels: HashMap<String, String>,
+    // クエリの評価時刻（Unix エポック秒）
+    pub timestamp: f64,
+    pub value: f64,
+}
+
+impl Sample {
+    // メトリクス名（__name__）
+    pub fn name(&self) -> Option<&str> {
+        self.label("__name__")
+    }
+
+    pub fn label(&self, name: &str) -> Option<&str> {
+        self.labels.get(name).map(String::as_str)
+    }
+
+    pub fn series_key(&self) -> Option<SeriesKey> {
+        SeriesKey::from_labels(&self.labels)
+    }
+}
+
+// 範囲のクエリ結果の 1 系列
+#[derive(Debug, Clone)]
+pub struct Series {
+    pub labels: HashMap<String, String>,
+    // (評価時刻（Unix エポック秒）, 値) を時刻順に
+    pub values: Vec<(f64, f64)>,
+}
+
+impl Series {
+    pub fn label(&self, name: &str) -> Option<&str> {
+        self.labels.get(name).map(String::as_str)
+    }
+
+    // 各時刻の値を瞬時ベクトルのクエリ結果と同じ形で返す
+    pub fn samples(&self) -> impl Iterator<Item = Sample> + '_ {
+        self.values.iter().map(|(timestamp, value)| Sample {
+            labels: self.labels.clone(),
+            timestamp: *timestamp,
+            value: *value,
+        })
+    }
+
+    // 最後の時刻の値
+    pub fn last(&self) -> Option<Sample> {
+        self.samples().last()
+    }
+}
+
+#[derive(Debug)]
+pub enum QueryError {
+    // 送信できなかった
+    Request(String),
+    // 応答が API の形式ではない
+    Parse(String),
+    // status が success ではない
+    Failed(String),
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryError::Request(e) => write!(f, "failed to send request to Prometheus: {}", e),
+            QueryError::Parse(e) => write!(f, "failed to parse Prometheus response: {}", e),
+            QueryError::Failed(e) => write!(f, "Prometheus query failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+#[derive(Debug, Deserialize)]
+struct Response {
+    status: String,
+    #[serde(default)]
+    error: Option<String>,
+    #[serde(default)]
+    data: Option<Data>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Data {
+    result: Vec<RawSample>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawSample {
+    metric: HashMap<String, String>,
+    // 瞬時ベクトル
+    #[serde(default)]
+    value: Option<(f64, String)>,
+    // 範囲ベクトル
+    #[serde(default)]
+    values: Vec<(f64, String)>,
+}
+
+struct Endpoint {
+    // {ベース URL}/api/v1/query
+    query_url: String,
+    // {ベース URL}/api/v1/query_range
+    range_url: String,
+    // 直近に失敗した時刻
+    failed_at: Mutex<Option<Instant>>,
+}
+
+impl Endpoint {
+    fn cooling_down(&self) -> bool {
+        self.failed_at
+            .lock()
+            .unwrap()
+            .is_some_and(|failed_at| failed_at.elapsed() < ENDPOINT_COOLDOWN)
+    }
+}
+
+pub struct PrometheusClient {
+    // 試す順
+    endpoints: Vec<Endpoint>,
+    http: Arc<HttpClient>,
+    credentials: Option<Credentials>,
+    simulation: Option<Arc<Scenario>>,
+    // すべてのエンドポイントが失敗したときに繰り返す回数（0 で繰り返さない）
+    retries: u32,
+    // 繰り返す前の初回の待ち時間（以降は倍々）
+    retry_backoff: Duration,
+}
+
+impl PrometheusClient {
+    // ベース URL は末尾の / の有無を問わない（http://localhost:9090）。
+    // カンマ区切りで複数書くと、先に書いたものから試す（http://prom-a:9090,http://prom-b:9090）
+    pub fn new(base_url: &str, http: Arc<HttpClient>) -> Self {
+        let mut base_urls: Vec<&str> = base_url
+            .split(',')
+            .map(|url| url.trim().trim_end_matches('/'))
+            .filter(|url| !url.is_empty())
+            .collect();
+        if base_urls.is_empty() {
+            base_urls.push(base_url.trim_end_matches('/'));
+        }
+        Self {
+            endpoints: base_urls
+                .into_iter()
+                .map(|base_url| Endpoint {
+                    query_url: format!("{}/api/v1/query", base_url),
+                    range_url: format!("{}/api/v1/query_range", base_url),
+                    failed_at: Mutex::new(None),
+                })
+                .collect(),
+            http,
+            credentials: None,
+            simulation: None,
+            retries: 0,
+            retry_backoff: Duration::ZERO,
+        }
+    }
+
+    // PROMETHEUS_BEARER_TOKEN / PROMETHEUS_BASIC_AUTH（user:password）を付けて送り、
+    // PROMETHEUS_CA_CERT があればその CA で HTTPS の証明書を検証する
+    pub fn with_env_auth(mut self) -> Self {
+        self.credentials = Credentials::from_env("PROMETHEUS");
+        self.http = auth::client_with_ca("PROMETHEUS", self.http);
+        self
+    }
+
+    // すべてのエンドポイントが失敗したとき、PROMETHEUS_QUERY_RETRIES（既定 2）回まで
+    // PROMETHEUS_RETRY_BACKOFF_MS（既定 500、以降は倍々）待って繰り返す。クエリ自体のエラーは繰り返さない
+    pub fn with_env_retry(mut self) -> Self {
+        self.retries = shared_config::var("PROMETHEUS_QUERY_RETRIES")
+            .ok()
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(2);
+        self.retry_backoff = shared_config::var("PROMETHEUS_RETRY_BACKOFF_MS")
+            .ok()
+            .and_then(|v| v.trim().parse().ok())
+            .map(Duration::from_millis)
+            .unwrap_or(Duration::from_millis(500));
+        self
+    }
+
+    // Prometheus に問い合わせず、シナリオから合成した応答を返す
+    pub fn simulated(mut self, scenario: Arc<Scenario>) -> Self {
+        self.simulation = Some(scenario);
+        self
+    }
+
+    // 値を数値として読めない系列は除く
+    pub async fn query(&self, query: &str) -> Result<Vec<Sample>, QueryError> {
+        let results = match &self.simulation {
+            Some(scenario) => parse_response(&scenario.prometheus_query(query))?,
+            None => {
+                self.get(|endpoint| &endpoint.query_url, &[("query", query)])
+                    .await?
+            }
+        };
+        Ok(results
+            .into_iter()
+            .filter_map(|raw| {
+                let (timestamp, value) = raw.value?;
+                Some(Sample {
+                    value: value.parse().ok()?,
+                    timestamp,
+                    labels: raw.metric,
+                })
+            })
+            .collect())
+    }
+
+    // start から end まで step 秒ごとに評価する（時刻は Unix エポック秒）。数値として読めない点は除く
+    pub async fn query_range(
+        &self,
+        query: &str,
+        start: f64,
+        end: f64,
+        step: f64,
+    ) -> Result<Vec<Series>, QueryError> {
+        let results = match &self.simulation {
+            Some(scenario) => {
+                parse_response(&scenario.prometheus_query_range(query, start, end, step))?
+            }
+            None => {
+                let (start, end, step) = (start.to_string(), end.to_string(), step.to_string());
+                self.get(
+                    |endpoint| &endpoint.range_url,
+                    &[
+                        ("query", query),
+                        ("start", &start),
+                        ("end", &end),
+                        ("step", &step),
+                    ],
+                )
+                .await?
+            }
+        };
+        Ok(results
+            .into_iter()
+            .map(|raw| Series {
+                values: raw
+                    .values
+                    .into_iter()
+                    .filter_map(|(timestamp, value)| Some((timestamp, value.parse().ok()?)))
+                    .collect(),
+                labels: raw.metric,
+            })
+            .collect())
+    }
+
+    // エンドポイントを順に試し、すべて失敗したら待って繰り返す
+    async fn get(
+        &self,
+        url: impl Fn(&Endpoint) -> &String,
+        params: &[(&str, &str)],
+    ) -> Result<Vec<RawSample>, QueryError> {
+        let mut backoff = self.retry_backoff;
+        let mut attempt = 0;
+        loop {
+            match self.get_any(&url, params).await {
+                Err(e @ (QueryError::Request(_) | QueryError::Parse(_)))
+                    if attempt < self.retries =>
+                {
+                    attempt += 1;
+                    warn!(
+                        "All Prometheus endpoints failed, retrying in {:?} ({}/{}): {}",
+                        backoff, attempt, self.retries, e
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // 後回しにしていないエンドポイントを書いた順に試し、残りをその後に試す
+    async fn get_any(
+        &self,
+        url: &impl Fn(&Endpoint) -> &String,
+        params: &[(&str, &str)],
+    ) -> Result<Vec<RawSample>, QueryError> {
+        let (ready, cooling): (Vec<&Endpoint>, Vec<&Endpoint>) = self
+            .endpoints
+            .iter()
+            .partition(|endpoint| !endpoint.cooling_down());
+        let mut last_error = None;
+        for endpoint in ready.into_iter().chain(cooling) {
+            let result = self
+                .send(url(endpoint), params)
+                .await
+                .and_then(|body| parse_response(&body));
+            match result {
+                // クエリ自体のエラーは他のエンドポイントでも同じなのでそのまま返す
+                Ok(_) | Err(QueryError::Failed(_)) => {
+                    *endpoint.failed_at.lock().unwrap() = None;
+                    return result;
+                }
+                Err(e) => {
+                    if self.endpoints.len() > 1 {
+                        warn!("Prometheus at {} failed: {}", url(endpoint), e);
+                    }
+                    *endpoint.failed_at.lock().unwrap() = Some(Instant::now());
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| QueryError::Request("no endpoint".to_string())))
+    }
+
+    async fn send(&self, url: &str, params: &[(&str, &str)]) -> Result<String, QueryError> {
+        let mut request = self.http.get(url).query(params);
+        if let Some(credentials) = &self.credentials {
+            request = credentials.apply(request);
+        }
+        // エラーの応答も JSON で理由が返るので、ステータスコードでは判定しない
+        self.http
+            .send(request)
+            .await
+            .map_err(|e| QueryError::Request(e.to_string()))?
+            .text()
+            .await
+            .map_err(|e| QueryError::Request(e.to_string()))
+    }
+}
+
+fn parse_response(body: &str) -> Result<Vec<RawSample>, QueryError> {
+    let response: Response =
+        serde_json::from_str(body).map_err(|e| QueryError::Parse(e.to_string()))?;
+    if response.status != "success" {
+        return Err(QueryError::Failed(
+            response.error.unwrap_or(response.status),
+        ));
+    }
+    let data = response
+        .data
+        .ok_or_else(|| QueryError::Parse("missing data".to_string()))?;
+    Ok(data.result)
+}
@@ -0,0 +1,342 @@
+// Prometheus remote_write による送信
+//
+// NAT の内側などで Prometheus からスクレイプできないルーター向けに、レジストリの値を
+// remote_write（protobuf + snappy）で REMOTE_WRITE_URL へ POST する。
+// collect でウィンドウごとの値を時刻付きで溜め、REMOTE_WRITE_INTERVAL_SECS ごとに
+// REMOTE_WRITE_MAX_SAMPLES_PER_SEND 件ずつまとめて送る。送れなかった分は溜めたまま次に再送し、
+// REMOTE_WRITE_MAX_PENDING_SAMPLES を超えたら古いものから捨てる。
+
+use crate::auth::{self, Credentials};
+use crate::filter::MetricFilter;
+use log::{info, warn};
+use prometheus::proto::{MetricFamily, MetricType};
+use prometheus::{IntCounterVec, IntGauge, Opts, Registry};
+use shared_http::{HttpClient, Lookup};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// (ラベル（名前順、__name__ を含む）, タイムスタンプ（ミリ秒）, 値)
+type PendingSample = (Vec<(String, String)>, i64, f64);
+// 系列ごとの (タイムスタンプ, 値)
+type SeriesSamples<'a> = BTreeMap<&'a [(String, String)], Vec<(i64, f64)>>;
+
+pub struct RemoteWrite {
+    url: String,
+    http: Arc<HttpClient>,
+    credentials: Option<Credentials>,
+    registry: Registry,
+    // 送る前に落とす系列（METRICS_*）
+    filter: MetricFilter,
+    // REMOTE_WRITE_LABELS（instance=router1,site=home）。スクレイプなら Prometheus が付けるラベル
+    extra_labels: Vec<(String, String)>,
+    flush_interval: Duration,
+    max_samples_per_send: usize,
+    max_pending_samples: usize,
+    // 古いものが先頭
+    pending: Mutex<VecDeque<PendingSample>>,
+    samples: IntCounterVec,
+    pending_gauge: IntGauge,
+}
+
+enum SendError {
+    // 再送すれば通るかもしれない（接続エラー、429、5xx）
+    Retry(String),
+    // 再送しても通らない（429 以外の 4xx）
+    Rejected(String),
+}
+
+impl RemoteWrite {
+    // REMOTE_WRITE_URL が無ければ None
+    pub fn from_env(registry: &Registry, http: Arc<HttpClient>) -> Option<Self> {
+        Self::from_lookup(registry, http, shared_config::var)
+    }
+
+    // 設定を lookup で読む（localPacketDump-rs のネットワークごとの上書き）。
+    // 送る系列の絞り込み（METRICS_*）も lookup から読む
+    pub fn from_lookup(
+        registry: &Registry,
+        http: Arc<HttpClient>,
+        lookup: impl Lookup,
+    ) -> Option<Self> {
+        let var = |name| lookup(name).ok().filter(|v| !v.trim().is_empty());
+        let url = var("REMOTE_WRITE_URL")?;
+        let parse = |name, default| {
+            var(name)
+                .and_then(|v| v.trim().parse::<u64>().ok())
+                .unwrap_or(default)
+        };
+        let extra_labels = var("REMOTE_WRITE_LABELS")
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|pair| {
+                let (name, value) = pair.split_once('=')?;
+                Some((name.trim().to_string(), value.trim().to_string()))
+            })
+            .collect();
+
+        let samples = IntCounterVec::new(
+            Opts::new(
+                "remote_write_samples_total",
+                "Samples handed to remote_write by result (sent, dropped)",
+            ),
+            &["result"],
+        )
+        .expect("failed to create remote_write_samples_total counter");
+        let pending_gauge = IntGauge::new(
+            "remote_write_pending_samples",
+            "Samples waiting to be sent with remote_write",
+        )
+        .expect("failed to create remote_write_pending_samples gauge");
+        registry
+            .register(Box::new(samples.clone()))
+            .expect("failed to register remote_write_samples_total counter");
+        registry
+            .register(Box::new(pending_gauge.clone()))
+            .expect("failed to register remote_write_pending_samples gauge");
+
+        let remote_write = Self {
+            http: auth::client_with_ca_from("REMOTE_WRITE", http, &lookup),
+            credentials: Credentials::from_lookup("REMOTE_WRITE", &lookup),
+            filter: MetricFilter::from_lookup(&lookup),
+            registry: registry.clone(),
+            extra_labels,
+            flush_interval: Duration::from_secs(parse("REMOTE_WRITE_INTERVAL_SECS", 5).max(1)),
+            max_samples_per_send: parse("REMOTE_WRITE_MAX_SAMPLES_PER_SEND", 2000).max(1) as usize,
+            max_pending_samples: parse("REMOTE_WRITE_MAX_PENDING_SAMPLES", 500_000) as usize,
+            pending: Mutex::new(VecDeque::new()),
+            samples,
+            pending_gauge,
+            url,
+        };
+        info!(
+            "remote_write to {} every {:?}",
+            remote_write.url, remote_write.flush_interval
+        );
+        Some(remote_write)
+    }
+
+    // 今のレジストリの値を溜める。ウィンドウを公開した直後に呼ぶ
+    pub fn collect(&self) {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_millis() as i64)
+            .unwrap_or_default();
+        let mut samples = Vec::new();
+        let mut families = self.registry.gather();
+        self.filter.apply(&mut families);
+        for family in families {
+            flatten(&family, now, &self.extra_labels, &mut samples);
+        }
+        let mut pending = self.pending.lock().unwrap();
+        pending.extend(samples);
+        self.trim(&mut pending);
+    }
+
+    // REMOTE_WRITE_INTERVAL_SECS ごとに送る
+    pub async fn run(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(self.flush_interval);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            self.flush().await;
+        }
+    }
+
+    // 溜まっている分を送る。失敗したら残りは次の機会に回す
+    pub async fn flush(&self) {
+        loop {
+            let batch: Vec<PendingSample> = {
+                let mut pending = self.pending.lock().unwrap();
+                let n = pending.len().min(self.max_samples_per_send);
+                pending.drain(..n).collect()
+            };
+            if batch.is_empty() {
+                return;
+            }
+            match self.send(&batch).await {
+                Ok(()) => {
+                    self.samples
+                        .with_label_values(&["sent"])
+                        .inc_by(batch.len() as u64);
+                }
+                Err(SendError::Rejected(e)) => {
+                    warn!("remote_write rejected {} samples: {}", batch.len(), e);
+                    self.samples
+                        .with_label_values(&["dropped"])
+                        .inc_by(batch.len() as u64);
+                }
+                Err(SendError::Retry(e)) => {
+                    warn!("remote_write failed, retrying later: {}", e);
+                    let mut pending = self.pending.lock().unwrap();
+                    for sample in batch.into_iter().rev() {
+                        pending.push_front(sample);
+                    }
+                    self.trim(&mut pending);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn trim(&self, pending: &mut VecDeque<PendingSample>) {
+        let excess = pending.len().saturating_sub(self.max_pending_samples);
+        if excess > 0 {
+            pending.drain(..excess);
+            self.samples
+                .with_label_values(&["dropped"])
+                .inc_by(excess as u64);
+        }
+        self.pending_gauge.set(pending.len() as i64);
+    }
+
+    async fn send(&self, batch: &[PendingSample]) -> Result<(), SendError> {
+        let body = snap::raw::Encoder::new()
+            .compress_vec(&encode_write_request(batch))
+            .map_err(|e| SendError::Rejected(e.to_string()))?;
+        let mut request = self
+            .http
+            .post(&self.url)
+            .header("Content-Encoding", "snappy")
+            .header("Content-Type", "application/x-protobuf")
+            .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+            .body(body);
+        if let Some(credentials) = &self.credentials {
+            request = credentials.apply(request);
+        }
+        // 429 / 5xx / 接続エラーは HttpClient が再試行してから返す
+        let response = self
+            .http
+            .send(request)
+            .await
+            .map_err(|e| SendError::Retry(e.to_string()))?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let message = format!("{}: {}", status, response.text().await.unwrap_or_default());
+        if status.is_client_error() && status.as_u16() != 429 {
+            Err(SendError::Rejected(message))
+        } else {
+            Err(SendError::Retry(message))
+        }
+    }
+}
+
+// テキスト形式と同じ系列に展開する（ヒストグラムは _bucket / _sum / _count）
+fn flatten(
+    family: &MetricFamily,
+    now: i64,
+    extra_labels: &[(String, String)],
+    out: &mut Vec<PendingSample>,
+) {
+    let name = family.get_name();
+    for metric in family.get_metric() {
+        let timestamp = match metric.get_timestamp_ms() {
+            0 => now,
+            timestamp => timestamp,
+        };
+        let mut base: Vec<(String, String)> = metric
+            .get_label()
+            .iter()
+            .map(|pair| (pair.get_name().to_string(), pair.get_value().to_string()))
+            .collect();
+        // メトリクス自身のラベルを優先する
+        for (label, value) in extra_labels {
+            if !base.iter().any(|(name, _)| name == label) {
+                base.push((label.clone(), value.clone()));
+            }
+        }
+        let mut push = |suffix: &str, extra: Option<(&str, String)>, value: f64| {
+            let mut labels = base.clone();
+            labels.push(("__name__".to_string(), format!("{}{}", name, suffix)));
+            if let Some((label, value)) = extra {
+                labels.push((label.to_string(), value));
+            }
+            labels.sort();
+            out.push((labels, timestamp, value));
+        };
+        match family.get_field_type() {
+            MetricType::COUNTER => push("", None, metric.get_counter().get_value()),
+            MetricType::GAUGE => push("", None, metric.get_gauge().get_value()),
+            MetricType::UNTYPED => push("", None, metric.get_untyped().get_value()),
+            MetricType::HISTOGRAM => {
+                let histogram = metric.get_histogram();
+                for bucket in histogram.get_bucket() {
+                    let le = bucket.get_upper_bound().to_string();
+                    push(
+                        "_bucket",
+                        Some(("le", le)),
+                        bucket.get_cumulative_count() as f64,
+                    );
+                }
+                let count = histogram.get_sample_count() as f64;
+                push("_bucket", Some(("le", "+Inf".to_string())), count);
+                push("_sum", None, histogram.get_sample_sum());
+                push("_count", None, count);
+            }
+            MetricType::SUMMARY => {
+                let summary = metric.get_summary();
+                for quantile in summary.get_quantile() {
+                    push(
+                        "",
+                        Some(("quantile", quantile.get_quantile().to_string())),
+                        quantile.get_value(),
+                    );
+                }
+                push("_sum", None, summary.get_sample_sum());
+                push("_count", None, summary.get_sample_count() as f64);
+            }
+        }
+    }
+}
+
+// prometheus.WriteRequest
+//   WriteRequest { repeated TimeSeries timeseries = 1; }
+//   TimeSeries   { repeated Label labels = 1; repeated Sample samples = 2; }
+//   Label        { string name = 1; string value = 2; }
+//   Sample       { double value = 1; int64 timestamp = 2; }
+fn encode_write_request(batch: &[PendingSample]) -> Vec<u8> {
+    // 同じ系列のサンプルは 1 つの TimeSeries に時刻順でまとめる
+    let mut series: SeriesSamples = BTreeMap::new();
+    for (labels, timestamp, value) in batch {
+        series.entry(labels).or_default().push((*timestamp, *value));
+    }
+
+    let mut request = Vec::new();
+    for (labels, mut samples) in series {
+        samples.sort_by_key(|(timestamp, _)| *timestamp);
+        let mut timeseries = Vec::new();
+        for (name, value) in labels {
+            let mut label = Vec::new();
+            put_bytes(&mut label, 1, name.as_bytes());
+            put_bytes(&mut label, 2, value.as_bytes());
+            put_bytes(&mut timeseries, 1, &label);
+        }
+        for (timestamp, value) in samples {
+            let mut sample = Vec::new();
+            put_varint(&mut sample, (1 << 3) | 1);
+            sample.extend_from_slice(&value.to_le_bytes());
+            put_varint(&mut sample, 2 << 3);
+            put_varint(&mut sample, timestamp as u64);
+            put_bytes(&mut timeseries, 2, &sample);
+        }
+        put_bytes(&mut request, 1, &timeseries);
+    }
+    request
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+// 長さ付きのフィールド（ワイヤタイプ 2）
+fn put_bytes(buf: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    put_varint(buf, ((field as u64) << 3) | 2);
+    put_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
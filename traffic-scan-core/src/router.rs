@@ -0,0 +1,89 @@
+// axum の Router で組んだサーバーの認証と TLS
+//
+// 自前のエンドポイントを持つ localPacketDump-rs の Router に、MetricsServer と同じ
+// METRICS_AUTH_* の認証と METRICS_TLS_* の HTTPS をかける。
+// 認証を掛けないパス（独自のトークンを持つ制御系やヘルスチェック）は呼び出し側が exempt で決める。
+
+use crate::auth::MetricsAuth;
+use axum::extract::{Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Router;
+use std::sync::Arc;
+
+struct Guard {
+    auth: MetricsAuth,
+    exempt: fn(&str) -> bool,
+}
+
+// 資格情報の無いリクエストを 401 で拒否する（認証を設定していなければ何もしない）
+pub fn with_auth(app: Router, auth: MetricsAuth, exempt: fn(&str) -> bool) -> Router {
+    if !auth.is_enabled() {
+        return app;
+    }
+    app.layer(axum::middleware::from_fn_with_state(
+        Arc::new(Guard { auth, exempt }),
+        require_auth,
+    ))
+}
+
+async fn require_auth(State(guard): State<Arc<Guard>>, request: Request, next: Next) -> Response {
+    if (guard.exempt)(request.uri().path()) {
+        return next.run(request).await;
+    }
+    let authorization = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok());
+    if !guard.auth.allows(authorization) {
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, guard.auth.challenge())],
+            "unauthorized",
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+// ハンドシェイク後の接続を 1 本ずつ hyper に渡す（WebSocket などのアップグレードも通す）。
+// shutdown が終わると受け付けをやめ、処理中のリクエストを返し終えた接続から閉じて、すべて閉じるまで待つ
+// （axum::serve の with_graceful_shutdown と同じ）
+#[cfg(feature = "tls")]
+pub async fn serve_tls(
+    listener: tokio::net::TcpListener,
+    app: Router,
+    acceptor: crate::tls::TlsAcceptor,
+    shutdown: impl std::future::Future<Output = ()>,
+) {
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use hyper_util::server::conn::auto;
+    use hyper_util::service::TowerToHyperService;
+    use log::debug;
+
+    // 接続ごとのタスクが受信側を持ち、すべて落ちたら閉じ終わり
+    let (closing, watch) = tokio::sync::watch::channel(());
+    crate::tls::accept_loop(listener, acceptor, shutdown, move |stream, peer| {
+        let service = TowerToHyperService::new(app.clone());
+        let mut closing = watch.clone();
+        async move {
+            let builder = auto::Builder::new(TokioExecutor::new());
+            let connection = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+            tokio::pin!(connection);
+            let result = tokio::select! {
+                result = connection.as_mut() => result,
+                _ = closing.changed() => {
+                    connection.as_mut().graceful_shutdown();
+                    connection.await
+                }
+            };
+            if let Err(e) = result {
+                debug!("Metrics connection from {} closed: {}", peer, e);
+            }
+        }
+    })
+    .await;
+    let _ = closing.send(());
+    closing.closed().await;
+}
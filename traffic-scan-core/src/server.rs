@@ -0,0 +1,300 @@
+// レジストリを公開するメトリクスサーバー
+//
+// 登録したパス以外（/metrics を含む）はレジストリをテキスト形式で返す（METRICS_ALLOW などで絞り込む）。
+// /buildinfo などの JSON のエンドポイントは route で足す。
+// with_env_security で METRICS_AUTH_* の認証と METRICS_TLS_* の TLS（tls フィーチャー）を有効にできる。
+// ポートを開けたら systemd に READY=1 を送る（Type=notify のとき）。
+// profiling フィーチャーでは /debug/pprof/profile と /debug/heap も返す（認証はメトリクスと同じ）。
+
+use crate::auth::MetricsAuth;
+use crate::filter;
+#[cfg(feature = "tls")]
+use crate::tls::{self, TlsAcceptor};
+use log::info;
+use prometheus::proto::MetricFamily;
+use prometheus::{Encoder, GaugeVec, Opts, Registry, TextEncoder};
+use shared_schema::build_info::{self, BuildInfo};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+pub use hyper::{Body, Request, Response, StatusCode};
+
+type Handler = Arc<dyn Fn(&Request<Body>) -> Response<Body> + Send + Sync>;
+
+pub struct MetricsServer {
+    addr: SocketAddr,
+    routes: HashMap<String, Handler>,
+    fallback: Handler,
+    auth: MetricsAuth,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsAcceptor>,
+}
+
+// 接続ごとに共有するルーティングと認証
+struct Routes {
+    routes: HashMap<String, Handler>,
+    fallback: Handler,
+    auth: MetricsAuth,
+}
+
+impl Routes {
+    fn handle(&self, req: &Request<Body>) -> Response<Body> {
+        let authorization = req
+            .headers()
+            .get(hyper::header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok());
+        if !self.auth.allows(authorization) {
+            return unauthorized(self.auth.challenge());
+        }
+        let handler = self.routes.get(req.uri().path()).unwrap_or(&self.fallback);
+        handler(req)
+    }
+
+    // CPU プロファイルは取り終えるまで待つので、ほかのエンドポイントと分けて非同期で返す
+    async fn respond(self: Arc<Self>, req: Request<Body>) -> Response<Body> {
+        #[cfg(feature = "profiling")]
+        if req.uri().path() == crate::profiling::PROFILE_PATH {
+            let authorization = req
+                .headers()
+                .get(hyper::header::AUTHORIZATION)
+                .and_then(|v| v.to_str().ok());
+            if !self.auth.allows(authorization) {
+                return unauthorized(self.auth.challenge());
+            }
+            return profile_response(req.uri().query()).await;
+        }
+        self.handle(&req)
+    }
+}
+
+#[cfg(feature = "profiling")]
+async fn profile_response(query: Option<&str>) -> Response<Body> {
+    use crate::profiling::{self, ProfileRequest};
+
+    let request = ProfileRequest::from_query(query);
+    info!(
+        "Collecting a {:?} CPU profile at {} Hz",
+        request.duration, request.frequency
+    );
+    match tokio::task::spawn_blocking(move || profiling::cpu_profile(request)).await {
+        Ok(Ok(profile)) => Response::builder()
+            .header("Content-Type", "application/octet-stream")
+            .header("Content-Disposition", "attachment; filename=\"profile.pb\"")
+            .body(Body::from(profile))
+            .unwrap(),
+        Ok(Err(e)) => error_response(StatusCode::SERVICE_UNAVAILABLE, &e),
+        Err(e) => error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("CPU profile task failed: {}", e),
+        ),
+    }
+}
+
+impl MetricsServer {
+    pub fn new(addr: impl Into<SocketAddr>, registry: Registry) -> Self {
+        let server = Self {
+            addr: addr.into(),
+            routes: HashMap::new(),
+            fallback: Arc::new(move |_| {
+                let mut families = registry.gather();
+                filter::apply(&mut families);
+                text_response(encode_text(&families))
+            }),
+            auth: MetricsAuth::default(),
+            #[cfg(feature = "tls")]
+            tls: None,
+        };
+        #[cfg(feature = "profiling")]
+        let server =
+            server.route(
+                crate::profiling::HEAP_PATH,
+                |_| match crate::profiling::heap_stats_json() {
+                    Ok(json) => json_response(json),
+                    Err(e) => error_response(StatusCode::SERVICE_UNAVAILABLE, &e),
+                },
+            );
+        server
+    }
+
+    pub fn auth(mut self, auth: MetricsAuth) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, acceptor: TlsAcceptor) -> Self {
+        self.tls = Some(acceptor);
+        self
+    }
+
+    // METRICS_AUTH_TOKEN / METRICS_AUTH_BASIC と METRICS_TLS_CERT / METRICS_TLS_KEY を読む
+    pub fn with_env_security(mut self) -> Result<Self, String> {
+        self.auth = MetricsAuth::from_env();
+        #[cfg(feature = "tls")]
+        {
+            self.tls = tls::acceptor_from_env()?;
+        }
+        #[cfg(not(feature = "tls"))]
+        reject_tls_env()?;
+        Ok(self)
+    }
+
+    pub fn route(
+        mut self,
+        path: &str,
+        handler: impl Fn(&Request<Body>) -> Response<Body> + Send + Sync + 'static,
+    ) -> Self {
+        self.routes.insert(path.to_string(), Arc::new(handler));
+        self
+    }
+
+    // メトリクスの応答を差し替える（タイムスタンプや OpenMetrics を付ける場合）
+    pub fn metrics(
+        mut self,
+        handler: impl Fn(&Request<Body>) -> Response<Body> + Send + Sync + 'static,
+    ) -> Self {
+        self.fallback = Arc::new(handler);
+        self
+    }
+
+    // /buildinfo で動いているビルドの情報を JSON で返す
+    pub fn build_info(self, info: BuildInfo) -> Self {
+        let json = info.to_json();
+        self.route("/buildinfo", move |_| json_response(json.clone()))
+    }
+
+    pub async fn serve(self) -> std::io::Result<()> {
+        use hyper::service::{make_service_fn, service_fn};
+
+        let routes = Arc::new(Routes {
+            routes: self.routes,
+            fallback: self.fallback,
+            auth: self.auth,
+        });
+        if routes.auth.is_enabled() {
+            info!("Metrics server requires authentication");
+        }
+
+        #[cfg(feature = "tls")]
+        if let Some(acceptor) = self.tls {
+            return serve_tls(self.addr, routes, acceptor).await;
+        }
+
+        let make_svc = make_service_fn(move |_conn| {
+            let routes = Arc::clone(&routes);
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let routes = Arc::clone(&routes);
+                    async move { Ok::<_, Infallible>(routes.respond(req).await) }
+                }))
+            }
+        });
+        let server = hyper::Server::try_bind(&self.addr)
+            .map_err(std::io::Error::other)?
+            .serve(make_svc);
+        info!("Metrics server listening on http://{}/metrics", self.addr);
+        crate::systemd::ready();
+        server.await.map_err(std::io::Error::other)
+    }
+}
+
+#[cfg(feature = "tls")]
+async fn serve_tls(
+    addr: SocketAddr,
+    routes: Arc<Routes>,
+    acceptor: TlsAcceptor,
+) -> std::io::Result<()> {
+    use hyper::service::service_fn;
+    use log::debug;
+
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!("Metrics server listening on https://{}/metrics", addr);
+    crate::systemd::ready();
+    // MetricsServer はプロセスが終わるまで受け付け続ける
+    let forever = std::future::pending();
+    tls::accept_loop(listener, acceptor, forever, move |stream, peer| {
+        let routes = Arc::clone(&routes);
+        async move {
+            let service = service_fn(move |req: Request<Body>| {
+                let routes = Arc::clone(&routes);
+                async move { Ok::<_, Infallible>(routes.respond(req).await) }
+            });
+            if let Err(e) = hyper::server::conn::Http::new()
+                .serve_connection(stream, service)
+                .await
+            {
+                debug!("Metrics connection from {} closed: {}", peer, e);
+            }
+        }
+    })
+    .await;
+    Ok(())
+}
+
+// tls フィーチャーなしのビルドで METRICS_TLS_* が設定されていればエラー（平文で公開し続けないよう起動を止める）
+#[cfg(not(feature = "tls"))]
+pub fn reject_tls_env() -> Result<(), String> {
+    let set = |name| shared_config::var(name).is_ok_and(|v| !v.is_empty());
+    if set("METRICS_TLS_CERT") || set("METRICS_TLS_KEY") {
+        return Err(
+            "METRICS_TLS_CERT / METRICS_TLS_KEY are set but this build has no tls feature"
+                .to_string(),
+        );
+    }
+    Ok(())
+}
+
+// Prometheus のテキスト形式
+pub fn encode_text(metric_families: &[MetricFamily]) -> String {
+    let mut buffer = vec![];
+    TextEncoder::new()
+        .encode(metric_families, &mut buffer)
+        .expect("failed to encode metrics");
+    String::from_utf8(buffer).expect("metrics contained invalid UTF-8")
+}
+
+pub fn text_response(body: String) -> Response<Body> {
+    Response::builder()
+        .header("Content-Type", TextEncoder::new().format_type())
+        .body(Body::from(body))
+        .unwrap()
+}
+
+pub fn json_response(body: String) -> Response<Body> {
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+fn unauthorized(challenge: &str) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header(hyper::header::WWW_AUTHENTICATE, challenge)
+        .body(Body::from("unauthorized"))
+        .unwrap()
+}
+
+pub fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(message.to_string()))
+        .unwrap()
+}
+
+// build_info メトリクス（値は常に 1）を登録する。job は const_label として付ける
+pub fn register_build_info(
+    registry: &Registry,
+    info: &BuildInfo,
+    job: Option<&str>,
+) -> prometheus::Result<()> {
+    let mut opts = Opts::new(build_info::METRIC_NAME, build_info::METRIC_HELP);
+    if let Some(job) = job {
+        opts = opts.const_label("job", job);
+    }
+    let gauge = GaugeVec::new(opts, &build_info::LABEL_NAMES)?;
+    gauge.with_label_values(&info.label_values()).set(1.0);
+    registry.register(Box::new(gauge))
+}
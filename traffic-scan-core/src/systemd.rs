@@ -0,0 +1,105 @@
+// systemd の Type=notify と watchdog
+//
+// NOTIFY_SOCKET（systemd が Type=notify のサービスに渡す）へ sd_notify のメッセージを送る。
+// 準備ができたら READY=1、WatchdogSec= を設定したサービスでは WATCHDOG_USEC の半分ごとに
+// WATCHDOG=1 を送る。systemd の外で動かしているときはどれも何もしない。
+
+use log::{info, warn};
+use std::time::{Duration, Instant};
+
+// NOTIFY_SOCKET へ 1 つのメッセージを送る。送れなければ false
+pub fn notify(state: &str) -> bool {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return false;
+    };
+    match send(&path, state) {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("Failed to notify systemd ({}): {}", state, e);
+            false
+        }
+    }
+}
+
+// 起動が終わったことを伝える
+pub fn ready() -> bool {
+    notify("READY=1")
+}
+
+pub fn stopping() -> bool {
+    notify("STOPPING=1")
+}
+
+pub fn watchdog() -> bool {
+    notify("WATCHDOG=1")
+}
+
+// WATCHDOG=1 を送る間隔（WATCHDOG_USEC の半分）。watchdog が無効か、他のプロセス宛てなら None
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.trim().parse().ok()?;
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.trim().parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+    }
+    (usec > 0).then(|| Duration::from_micros(usec / 2))
+}
+
+// 定期的に回るループから呼ぶ watchdog。前に送ってから間隔が空いたときだけ WATCHDOG=1 を送るので、
+// ループが止まると systemd がサービスを再起動する
+pub struct Watchdog {
+    interval: Option<Duration>,
+    last: Option<Instant>,
+}
+
+impl Watchdog {
+    pub fn from_env() -> Self {
+        let interval = watchdog_interval();
+        if let Some(interval) = interval {
+            info!("Petting the systemd watchdog every {:?}", interval);
+        }
+        Self {
+            interval,
+            last: None,
+        }
+    }
+
+    pub fn pet(&mut self) {
+        let Some(interval) = self.interval else {
+            return;
+        };
+        if self.last.is_none_or(|last| last.elapsed() >= interval) {
+            self.last = Some(Instant::now());
+            watchdog();
+        }
+    }
+}
+
+#[cfg(unix)]
+fn send(path: &std::ffi::OsStr, state: &str) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::UnixDatagram;
+
+    let socket = UnixDatagram::unbound()?;
+    // "@" で始まるのは Linux の抽象名前空間のソケット
+    match path.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &address)?;
+        }
+        _ => {
+            socket.send_to(state.as_bytes(), path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send(_path: &std::ffi::OsStr, _state: &str) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "sd_notify needs a Unix socket",
+    ))
+}
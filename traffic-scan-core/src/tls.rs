@@ -0,0 +1,98 @@
+// メトリクスサーバーの TLS
+//
+// METRICS_TLS_CERT（証明書チェーン）と METRICS_TLS_KEY（秘密鍵）の PEM ファイルを設定すると、
+// メトリクスサーバーは HTTPS だけを受け付ける。
+
+use log::{debug, warn};
+use std::fs::File;
+use std::future::Future;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::server::TlsStream;
+
+pub use tokio_rustls::TlsAcceptor;
+
+// 片方だけの設定や読めないファイルはエラー（平文で公開し続けないよう起動を止める）
+pub fn acceptor_from_env() -> Result<Option<TlsAcceptor>, String> {
+    let var = |name| shared_config::var(name).ok().filter(|v| !v.is_empty());
+    let (cert_path, key_path) = match (var("METRICS_TLS_CERT"), var("METRICS_TLS_KEY")) {
+        (None, None) => return Ok(None),
+        (Some(cert), Some(key)) => (cert, key),
+        _ => return Err("METRICS_TLS_CERT and METRICS_TLS_KEY must be set together".to_string()),
+    };
+
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut open(&cert_path)?)
+        .map_err(|e| format!("failed to parse {}: {}", cert_path, e))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err(format!("no certificate in {}", cert_path));
+    }
+    let mut key_reader = open(&key_path)?;
+    let key = loop {
+        match rustls_pemfile::read_one(&mut key_reader)
+            .map_err(|e| format!("failed to parse {}: {}", key_path, e))?
+        {
+            Some(
+                rustls_pemfile::Item::PKCS8Key(key)
+                | rustls_pemfile::Item::RSAKey(key)
+                | rustls_pemfile::Item::ECKey(key),
+            ) => break PrivateKey(key),
+            Some(_) => continue,
+            None => return Err(format!("no private key in {}", key_path)),
+        }
+    };
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("invalid certificate or key: {}", e))?;
+    Ok(Some(TlsAcceptor::from(Arc::new(config))))
+}
+
+// hyper 0.14 の Server も axum::serve も TLS を扱わないので、接続ごとのタスクでハンドシェイクし、
+// 済んだ接続を serve に渡す（MetricsServer と router で共有する）。shutdown が終わると受け付けをやめる
+pub async fn accept_loop<F, Fut>(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    shutdown: impl Future<Output = ()>,
+    serve: F,
+) where
+    F: Fn(TlsStream<TcpStream>, SocketAddr) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let serve = Arc::new(serve);
+    tokio::pin!(shutdown);
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            () = &mut shutdown => return,
+        };
+        let (stream, peer) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Failed to accept metrics connection: {}", e);
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let serve = Arc::clone(&serve);
+        tokio::spawn(async move {
+            match acceptor.accept(stream).await {
+                Ok(stream) => serve(stream, peer).await,
+                Err(e) => debug!("TLS handshake with {} failed: {}", peer, e),
+            }
+        });
+    }
+}
+
+fn open(path: &str) -> Result<BufReader<File>, String> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| format!("failed to open {}: {}", path, e))
+}
@@ -0,0 +1,129 @@
+// traffic-top: リモートごとの通信量を iftop のように端末に表示する
+//
+// localPacketDump-rs の /top を定期的に取得し、ダウンロード / アップロードの速度、
+// 使ったインターフェース（ステータス API の WAN の割り当て）、icmp-traffic-scan の RTT を
+// 並べ替えられる表で表示する。集計はすべて localPacketDump-rs 側で行うので、
+// ルーターの外からでも同じ値を見られる。
+
+mod source;
+mod table;
+mod ui;
+
+use clap::Parser;
+use crossterm::event::{self, Event, KeyEventKind};
+use ratatui::DefaultTerminal;
+use source::{Query, Snapshot, Source};
+use std::collections::BTreeSet;
+use std::time::Duration;
+use table::SortKey;
+use tokio::sync::watch;
+use ui::App;
+
+// キー入力を待つ間隔（画面の「updated」の表示もこの間隔で進む）
+const INPUT_POLL: Duration = Duration::from_millis(250);
+
+#[derive(Parser, Debug)]
+#[command(
+    version,
+    about = "Live per-remote traffic table fed by localPacketDump-rs /top"
+)]
+struct Args {
+    /// Base URL of localPacketDump-rs (add /networks/<name> to watch one of NETWORKS)
+    #[arg(long, env = "TOP_URL", default_value = "http://127.0.0.1:59122")]
+    url: String,
+
+    /// Prometheus to read icmp-traffic-scan's rtt_icmp_dump from (RTT column stays empty without it)
+    #[arg(long, env = "PROMETHEUS_URL")]
+    prometheus: Option<String>,
+
+    /// Seconds between refreshes
+    #[arg(long, value_name = "SECS", default_value_t = 1.0)]
+    interval: f64,
+
+    /// Windows summed per refresh (+/- change it while running)
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    windows: u64,
+
+    /// Upper bound for --windows, matching TOP_MAX_WINDOWS of localPacketDump-rs
+    #[arg(long, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    max_windows: u64,
+
+    /// Remotes fetched per direction (at most 100)
+    #[arg(short, default_value_t = 100)]
+    n: usize,
+
+    /// Only traffic over this interface (i cycles through interfaces while running)
+    #[arg(short, long)]
+    interface: Option<String>,
+
+    /// Initial sort column: download, upload, total, rtt or remote
+    #[arg(long, default_value = "download", value_parser = parse_sort)]
+    sort: SortKey,
+}
+
+fn parse_sort(value: &str) -> Result<SortKey, String> {
+    SortKey::parse(value).ok_or_else(|| {
+        format!(
+            "unknown sort column {} (expected download, upload, total, rtt or remote)",
+            value
+        )
+    })
+}
+
+fn main() -> std::io::Result<()> {
+    let args = Args::parse();
+    let interval = Duration::from_secs_f64(args.interval.max(0.1));
+    let max_windows = args.max_windows as usize;
+    let query = Query {
+        windows: (args.windows as usize).min(max_windows),
+        interface: args.interface.clone(),
+    };
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()?;
+    let (query_tx, query_rx) = watch::channel(query.clone());
+    let (snapshot_tx, snapshot_rx) = watch::channel(Snapshot::default());
+    let source = Source::new(&args.url, args.prometheus.as_deref(), args.n);
+    runtime.spawn(source.run(interval, query_rx, snapshot_tx));
+
+    let app = App {
+        sort: args.sort,
+        reverse: false,
+        query,
+        max_windows,
+        offset: 0,
+        visible: 0,
+        interfaces: args.interface.into_iter().collect::<BTreeSet<_>>(),
+        source: args.url,
+        quit: false,
+    };
+    // 端末を元に戻してから終わる（パニックしたときも ratatui のフックが戻す）
+    let mut terminal = ratatui::init();
+    let result = run(&mut terminal, app, &query_tx, &snapshot_rx);
+    ratatui::restore();
+    runtime.shutdown_background();
+    result
+}
+
+fn run(
+    terminal: &mut DefaultTerminal,
+    mut app: App,
+    query: &watch::Sender<Query>,
+    snapshots: &watch::Receiver<Snapshot>,
+) -> std::io::Result<()> {
+    while !app.quit {
+        let snapshot = snapshots.borrow().clone();
+        terminal.draw(|frame| app.draw(frame, &snapshot))?;
+        if !event::poll(INPUT_POLL)? {
+            continue;
+        }
+        if let Event::Key(key) = event::read()? {
+            if key.kind == KeyEventKind::Press && app.handle_key(key) {
+                query.send_replace(app.query.clone());
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,165 @@
+// 表示する値の取得
+//
+// 通信量は localPacketDump-rs の /top（キャプチャした通信をステータス API の WAN の割り当てで
+// インターフェースに振り分けたもの）から、RTT は --prometheus を指定したときだけ
+// icmp-traffic-scan の rtt_icmp_dump から取る。RTT が取れなくても通信量は表示し続ける。
+
+use serde::Deserialize;
+use shared_http::HttpClient;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use traffic_scan_core::{PrometheusClient, SeriesKey};
+
+// /top が 1 回に返すリモートの上限
+pub const MAX_N: usize = 100;
+
+// localPacketDump-rs の /top の応答（使うフィールドだけ）
+#[derive(Debug, Clone, Deserialize)]
+pub struct TopEntry {
+    pub remote_ip: String,
+    pub download_bytes: u64,
+    pub upload_bytes: u64,
+    #[serde(default)]
+    pub interfaces: BTreeSet<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TopPage {
+    pub windows: usize,
+    pub duration_ms: u64,
+    pub download: Vec<TopEntry>,
+    pub upload: Vec<TopEntry>,
+}
+
+// 画面の 1 回分
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    pub page: Option<TopPage>,
+    // (interface, remote_ip) -> RTT（ミリ秒）
+    pub rtt: HashMap<SeriesKey, f64>,
+    // 直近の取得の失敗（成功すれば消える）
+    pub error: Option<String>,
+    pub updated: Option<Instant>,
+}
+
+// 画面から変えられる取得の条件
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Query {
+    // 合計するウィンドウ数（/top の windows）
+    pub windows: usize,
+    // このインターフェースの通信だけ
+    pub interface: Option<String>,
+}
+
+pub struct Source {
+    http: Arc<HttpClient>,
+    top_url: String,
+    prometheus: Option<PrometheusClient>,
+    n: usize,
+}
+
+impl Source {
+    pub fn new(base_url: &str, prometheus_url: Option<&str>, n: usize) -> Self {
+        let http = Arc::new(HttpClient::from_env());
+        let prometheus =
+            prometheus_url.map(|url| PrometheusClient::new(url, Arc::clone(&http)).with_env_auth());
+        Self {
+            http,
+            top_url: format!("{}/top", base_url.trim_end_matches('/')),
+            prometheus,
+            n: n.clamp(1, MAX_N),
+        }
+    }
+
+    pub async fn fetch_top(&self, query: &Query) -> Result<TopPage, String> {
+        let mut params = vec![
+            ("n", self.n.to_string()),
+            ("windows", query.windows.to_string()),
+        ];
+        if let Some(interface) = &query.interface {
+            params.push(("interface", interface.clone()));
+        }
+        let request = self.http.get(&self.top_url).query(&params);
+        let response = self
+            .http
+            .send(request)
+            .await
+            .map_err(|e| format!("failed to fetch {}: {}", self.top_url, e))?;
+        let status = response.status();
+        let body = response
+            .bytes()
+            .await
+            .map_err(|e| format!("failed to read {}: {}", self.top_url, e))?;
+        if !status.is_success() {
+            return Err(format!(
+                "{} returned {}: {}",
+                self.top_url,
+                status,
+                String::from_utf8_lossy(&body).trim()
+            ));
+        }
+        serde_json::from_slice(&body).map_err(|e| format!("invalid /top response: {}", e))
+    }
+
+    // --prometheus を指定しなければ常に空
+    pub async fn fetch_rtt(&self) -> Result<HashMap<SeriesKey, f64>, String> {
+        let Some(prometheus) = &self.prometheus else {
+            return Ok(HashMap::new());
+        };
+        let samples = prometheus
+            .query("rtt_icmp_dump")
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut rtt: HashMap<SeriesKey, f64> = HashMap::new();
+        // 測定のきっかけの向き（data_type）ごとの系列は小さい方を使う
+        for sample in samples {
+            let Some(key) = sample.series_key() else {
+                continue;
+            };
+            if !sample.value.is_finite() || sample.value <= 0.0 {
+                continue;
+            }
+            rtt.entry(key)
+                .and_modify(|value| *value = value.min(sample.value))
+                .or_insert(sample.value);
+        }
+        Ok(rtt)
+    }
+
+    // interval ごとに取り直して snapshots に流す。query が変われば待たずに取り直す
+    pub async fn run(
+        self,
+        interval: Duration,
+        mut query: watch::Receiver<Query>,
+        snapshots: watch::Sender<Snapshot>,
+    ) {
+        loop {
+            let current = query.borrow_and_update().clone();
+            let (top, rtt) = tokio::join!(self.fetch_top(&current), self.fetch_rtt());
+            snapshots.send_modify(|snapshot| {
+                let mut errors = Vec::new();
+                match top {
+                    Ok(page) => snapshot.page = Some(page),
+                    Err(e) => errors.push(e),
+                }
+                match rtt {
+                    Ok(rtt) => snapshot.rtt = rtt,
+                    Err(e) => errors.push(e),
+                }
+                snapshot.error = (!errors.is_empty()).then(|| errors.join("; "));
+                snapshot.updated = Some(Instant::now());
+            });
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {}
+                changed = query.changed() => {
+                    // 画面が閉じられた
+                    if changed.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
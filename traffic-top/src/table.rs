@@ -0,0 +1,151 @@
+// 表の行と並べ替え
+//
+// /top はダウンロードとアップロードの上位を別々に返すので、リモートごとにまとめて 1 行にする。
+// バイト数はウィンドウの合計なので、長さで割って bps にする。
+
+use crate::source::Snapshot;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use traffic_scan_core::SeriesKey;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    Download,
+    Upload,
+    Total,
+    Rtt,
+    Remote,
+}
+
+impl SortKey {
+    pub const ALL: [SortKey; 5] = [
+        SortKey::Download,
+        SortKey::Upload,
+        SortKey::Total,
+        SortKey::Rtt,
+        SortKey::Remote,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SortKey::Download => "download",
+            SortKey::Upload => "upload",
+            SortKey::Total => "total",
+            SortKey::Rtt => "rtt",
+            SortKey::Remote => "remote",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|key| key.name() == name)
+    }
+
+    pub fn next(self) -> Self {
+        let index = Self::ALL.iter().position(|key| *key == self).unwrap_or(0);
+        Self::ALL[(index + 1) % Self::ALL.len()]
+    }
+
+    pub fn previous(self) -> Self {
+        let index = Self::ALL.iter().position(|key| *key == self).unwrap_or(0);
+        Self::ALL[(index + Self::ALL.len() - 1) % Self::ALL.len()]
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Row {
+    pub remote_ip: String,
+    // ウィンドウの間に使ったインターフェース（WAN を切り替えたリモートは複数）
+    pub interfaces: Vec<String>,
+    pub download_bps: f64,
+    pub upload_bps: f64,
+    // 使ったインターフェースの RTT のうち最小（ミリ秒）。測っていなければ None
+    pub rtt_ms: Option<f64>,
+}
+
+impl Row {
+    pub fn total_bps(&self) -> f64 {
+        self.download_bps + self.upload_bps
+    }
+}
+
+pub fn rows(snapshot: &Snapshot) -> Vec<Row> {
+    let Some(page) = &snapshot.page else {
+        return Vec::new();
+    };
+    let seconds = page.duration_ms as f64 / 1000.0;
+    let bps = |bytes: u64| {
+        if seconds > 0.0 {
+            bytes as f64 * 8.0 / seconds
+        } else {
+            0.0
+        }
+    };
+    let mut rows: HashMap<&str, Row> = HashMap::new();
+    for entry in page.download.iter().chain(&page.upload) {
+        rows.entry(&entry.remote_ip).or_insert_with(|| {
+            let interfaces: Vec<String> = entry.interfaces.iter().cloned().collect();
+            let rtt_ms = interfaces
+                .iter()
+                .filter_map(|interface| {
+                    snapshot.rtt.get(&SeriesKey::new(
+                        interface.as_str(),
+                        entry.remote_ip.as_str(),
+                    ))
+                })
+                .copied()
+                .reduce(f64::min);
+            Row {
+                remote_ip: entry.remote_ip.clone(),
+                interfaces,
+                download_bps: bps(entry.download_bytes),
+                upload_bps: bps(entry.upload_bytes),
+                rtt_ms,
+            }
+        });
+    }
+    rows.into_values().collect()
+}
+
+// 降順（Remote だけは昇順）が既定。reverse で逆にする。RTT の無い行は向きによらず最後
+pub fn sort(rows: &mut [Row], key: SortKey, reverse: bool) {
+    rows.sort_by(|a, b| {
+        let order = match key {
+            SortKey::Download => b.download_bps.total_cmp(&a.download_bps),
+            SortKey::Upload => b.upload_bps.total_cmp(&a.upload_bps),
+            SortKey::Total => b.total_bps().total_cmp(&a.total_bps()),
+            SortKey::Rtt => match (a.rtt_ms, b.rtt_ms) {
+                (Some(a), Some(b)) => b.total_cmp(&a),
+                (Some(_), None) => return Ordering::Less,
+                (None, Some(_)) => return Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            },
+            SortKey::Remote => compare_addresses(&a.remote_ip, &b.remote_ip),
+        };
+        let order = if reverse { order.reverse() } else { order };
+        order.then_with(|| compare_addresses(&a.remote_ip, &b.remote_ip))
+    });
+}
+
+// アドレスとして読めれば数値順（IPv4 が先）、読めなければ文字列順
+fn compare_addresses(a: &str, b: &str) -> Ordering {
+    match (a.parse::<std::net::IpAddr>(), b.parse::<std::net::IpAddr>()) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        _ => a.cmp(b),
+    }
+}
+
+// 8_400_000 -> "8.40 Mbps"
+pub fn format_bps(bps: f64) -> String {
+    const UNITS: [&str; 5] = ["bps", "Kbps", "Mbps", "Gbps", "Tbps"];
+    let mut value = bps;
+    let mut unit = 0;
+    while value >= 1000.0 && unit < UNITS.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{:.0} {}", value, UNITS[unit])
+    } else {
+        format!("{:.2} {}", value, UNITS[unit])
+    }
+}
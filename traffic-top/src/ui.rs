@@ -0,0 +1,188 @@
+// 画面の状態と描画
+//
+// 上の行に取得元と条件、中央にリモートごとの表、下の行にキーの説明と取得のエラーを出す。
+
+use crate::source::{Query, Snapshot};
+use crate::table::{self, Row, SortKey};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Cell, Paragraph, Row as TableRow, Table};
+use ratatui::Frame;
+use std::collections::BTreeSet;
+
+pub struct App {
+    pub sort: SortKey,
+    pub reverse: bool,
+    pub query: Query,
+    // /top の windows の上限（localPacketDump-rs の TOP_MAX_WINDOWS の既定値）
+    pub max_windows: usize,
+    // 表示を始める行
+    pub offset: usize,
+    // 前回の描画で表に入った行数（PageUp / PageDown の幅）
+    pub visible: usize,
+    // これまでの応答に出てきたインターフェース。絞り込むと他が応答に出なくなるので覚えておく
+    pub interfaces: BTreeSet<String>,
+    // 取得元（見出しに出す）
+    pub source: String,
+    pub quit: bool,
+}
+
+impl App {
+    // 取得の条件が変わったら true
+    pub fn handle_key(&mut self, key: KeyEvent) -> bool {
+        let before = self.query.clone();
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => self.quit = true,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => self.quit = true,
+            KeyCode::Right | KeyCode::Tab | KeyCode::Char('s') => self.sort = self.sort.next(),
+            KeyCode::Left | KeyCode::BackTab => self.sort = self.sort.previous(),
+            KeyCode::Char('d') => self.sort = SortKey::Download,
+            KeyCode::Char('u') => self.sort = SortKey::Upload,
+            KeyCode::Char('t') => self.sort = SortKey::Total,
+            KeyCode::Char('l') => self.sort = SortKey::Rtt,
+            KeyCode::Char('a') => self.sort = SortKey::Remote,
+            KeyCode::Char('r') => self.reverse = !self.reverse,
+            KeyCode::Char('i') => {
+                self.query.interface =
+                    next_interface(self.query.interface.as_deref(), &self.interfaces)
+            }
+            KeyCode::Char('+') | KeyCode::Char('=') => {
+                self.query.windows = (self.query.windows + 1).min(self.max_windows)
+            }
+            KeyCode::Char('-') => self.query.windows = self.query.windows.saturating_sub(1).max(1),
+            KeyCode::Down | KeyCode::Char('j') => self.offset += 1,
+            KeyCode::Up | KeyCode::Char('k') => self.offset = self.offset.saturating_sub(1),
+            KeyCode::PageDown => self.offset += self.visible.max(1),
+            KeyCode::PageUp => self.offset = self.offset.saturating_sub(self.visible.max(1)),
+            KeyCode::Home => self.offset = 0,
+            _ => {}
+        }
+        if self.query != before {
+            self.offset = 0;
+            return true;
+        }
+        false
+    }
+
+    pub fn draw(&mut self, frame: &mut Frame, snapshot: &Snapshot) {
+        let [header, body, footer] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Min(3),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        if let Some(page) = &snapshot.page {
+            for entry in page.download.iter().chain(&page.upload) {
+                self.interfaces.extend(entry.interfaces.iter().cloned());
+            }
+        }
+        let mut rows = table::rows(snapshot);
+        table::sort(&mut rows, self.sort, self.reverse);
+
+        frame.render_widget(Paragraph::new(self.header(snapshot, &rows)), header);
+
+        // 枠と見出しの 3 行を除いた分
+        let visible = (body.height as usize).saturating_sub(3);
+        self.visible = visible;
+        self.offset = self.offset.min(rows.len().saturating_sub(visible));
+        let widths = [
+            Constraint::Min(20),
+            Constraint::Length(14),
+            Constraint::Length(12),
+            Constraint::Length(12),
+            Constraint::Length(12),
+            Constraint::Length(9),
+        ];
+        let bold = Style::default().add_modifier(Modifier::BOLD);
+        let titles = [
+            ("remote", Some(SortKey::Remote)),
+            ("interface", None),
+            ("download", Some(SortKey::Download)),
+            ("upload", Some(SortKey::Upload)),
+            ("total", Some(SortKey::Total)),
+            ("rtt", Some(SortKey::Rtt)),
+        ];
+        let titles = titles.map(|(title, key)| {
+            if key == Some(self.sort) {
+                let arrow = if self.reverse { "▲" } else { "▼" };
+                Cell::from(format!("{}{}", title, arrow))
+                    .style(bold.add_modifier(Modifier::REVERSED))
+            } else {
+                Cell::from(title).style(bold)
+            }
+        });
+        let lines = rows.iter().skip(self.offset).take(visible).map(table_row);
+        let table = Table::new(lines, widths)
+            .header(TableRow::new(titles))
+            .block(Block::bordered().title(format!(" {} remotes ", rows.len())));
+        frame.render_widget(table, body);
+
+        let footer_text = match &snapshot.error {
+            Some(error) => Line::styled(
+                error.clone(),
+                Style::default().add_modifier(Modifier::BOLD | Modifier::REVERSED),
+            ),
+            None => Line::from(
+                "q quit  ←/→ sort  d/u/t/l/a download/upload/total/rtt/remote  r reverse  i interface  +/- windows  ↑/↓ scroll",
+            ),
+        };
+        frame.render_widget(Paragraph::new(footer_text), footer);
+    }
+
+    fn header(&self, snapshot: &Snapshot, rows: &[Row]) -> Line<'static> {
+        let interface = self.query.interface.as_deref().unwrap_or("all");
+        let (download, upload) = rows.iter().fold((0.0, 0.0), |(d, u), row| {
+            (d + row.download_bps, u + row.upload_bps)
+        });
+        let span = match &snapshot.page {
+            Some(page) => format!(
+                "{} window(s), {:.1}s",
+                page.windows,
+                page.duration_ms as f64 / 1000.0
+            ),
+            None => "waiting for /top".to_string(),
+        };
+        let age = snapshot
+            .updated
+            .map(|updated| format!("{:.0}s ago", updated.elapsed().as_secs_f64()))
+            .unwrap_or_else(|| "-".to_string());
+        Line::from(format!(
+            "{}  interface: {}  {}  ↓ {}  ↑ {}  updated {}",
+            self.source,
+            interface,
+            span,
+            table::format_bps(download),
+            table::format_bps(upload),
+            age
+        ))
+    }
+}
+
+fn table_row(row: &Row) -> TableRow<'static> {
+    TableRow::new([
+        Cell::from(row.remote_ip.clone()),
+        Cell::from(row.interfaces.join(",")),
+        Cell::from(table::format_bps(row.download_bps)),
+        Cell::from(table::format_bps(row.upload_bps)),
+        Cell::from(table::format_bps(row.total_bps())),
+        Cell::from(
+            row.rtt_ms
+                .map(|rtt| format!("{:.1} ms", rtt))
+                .unwrap_or_else(|| "-".to_string()),
+        ),
+    ])
+}
+
+// all → 見たことのあるインターフェース（名前順）→ all
+fn next_interface(current: Option<&str>, known: &BTreeSet<String>) -> Option<String> {
+    match current {
+        None => known.iter().next().cloned(),
+        Some(current) => known
+            .iter()
+            .find(|interface| interface.as_str() > current)
+            .cloned(),
+    }
+}